@@ -1,19 +1,19 @@
 //! Example of a user agent that sends a message to an assistant
-#![feature(lazy_cell)]
 
 use {
     anyhow::Result,
-    autogen_rs::agent::{
-        assistant::AssistantBuilder, user::UserAgentBuilder, Actor, Message, Sender,
+    autogen_rs::{
+        agent::{
+            assistant::AssistantBuilder,
+            directory::AgentDirectory,
+            system::{self, AgentSystem},
+            user::UserAgentBuilder,
+        },
+        chat::{initiate_chat, ChatOptions},
     },
-    dashmap::DashMap,
-    std::sync::LazyLock,
     tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt},
-    uuid::Uuid,
 };
 
-static AGENTS: LazyLock<DashMap<Uuid, Sender<Box<Message>>>> = LazyLock::new(DashMap::new);
-
 /// Invoking the example:
 /// ```zsh
 /// RUST_LOG=debug cargo run --example user_agent
@@ -29,21 +29,38 @@ async fn main() -> Result<()> {
         .with(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
-    let assistant = AssistantBuilder::new().with_name("assistant").build();
-    AGENTS.insert(assistant.id(), assistant.sender());
-
+    // owns the agents and shuts them down together
+    let system = AgentSystem::new();
+    let agents = AgentDirectory::new();
     let user_agent = UserAgentBuilder::new().with_name("user-agent").build();
-    AGENTS.insert(user_agent.id(), user_agent.sender());
+    agents.register_actor(&user_agent)?;
 
-    // start the conversation by sending a message to the user agent
-    user_agent.send(Message {
-        sender: assistant.sender(),
-        content: "What can I do for you?".to_string(),
-    })?;
+    // stream the assistant's replies to the user as they are generated
+    let assistant = AssistantBuilder::new()
+        .with_name("assistant")
+        .with_stream(user_agent.stream_sender())
+        .with_cancellation(system.token())
+        .build();
+    agents.register_actor(&assistant)?;
 
-    // TODO: this is a hack to keep the program running
-    tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+    // the assistant opens the conversation; it ends when the user types "exit"
+    // or hits Ctrl-C
+    let chat = initiate_chat(
+        &assistant,
+        &user_agent,
+        "What can I do for you?",
+        ChatOptions::new().with_termination_keyword("exit"),
+    );
+    tokio::select! {
+        outcome = chat => {
+            tracing::debug!(reason = %outcome.reason, agents = ?agents.agents(), "<conversation ended>");
+        }
+        signalled = system::signal() => signalled?,
+    }
 
-    tracing::debug!("<conversation ended>");
+    system.register(assistant);
+    system.register(user_agent);
+    let shutdown = system.shutdown().await;
+    tracing::debug!(?shutdown, "<agents stopped>");
     Ok(())
 }
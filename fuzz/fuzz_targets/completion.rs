@@ -0,0 +1,8 @@
+//! Parses arbitrary bytes as a chat completion response.
+#![no_main]
+
+use {autogen_rs::llm::openai, libfuzzer_sys::fuzz_target};
+
+fuzz_target!(|body: &[u8]| {
+    let _ = openai::parse_completion(body);
+});
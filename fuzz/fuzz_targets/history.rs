@@ -0,0 +1,12 @@
+//! Round-trips conversation histories parsed from arbitrary bytes.
+#![no_main]
+
+use {autogen_rs::llm::HistoryMessage, libfuzzer_sys::fuzz_target};
+
+fuzz_target!(|body: &[u8]| {
+    if let Ok(history) = serde_json::from_slice::<Vec<HistoryMessage>>(body) {
+        let json = serde_json::to_vec(&history).unwrap();
+        let parsed: Vec<HistoryMessage> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(parsed, history);
+    }
+});
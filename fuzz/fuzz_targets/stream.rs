@@ -0,0 +1,20 @@
+//! Parses arbitrary bytes as a streamed chat completion, split into chunks at
+//! arbitrary points.
+#![no_main]
+
+use {autogen_rs::llm::openai::StreamParser, libfuzzer_sys::fuzz_target};
+
+fuzz_target!(|input: (Vec<usize>, &[u8])| {
+    let (splits, mut body) = input;
+    let mut parser = StreamParser::new();
+    for split in splits {
+        let (chunk, rest) = body.split_at(split.min(body.len()));
+        if parser.push(chunk, |_| {}).is_err() {
+            return;
+        }
+        body = rest;
+    }
+    if parser.push(body, |_| {}).is_ok() {
+        let _ = parser.finish(|_| {});
+    }
+});
@@ -1,6 +1,8 @@
 //! Actor trait.
-#[allow(async_fn_in_trait)]
 
+use super::{Sender, Shutdown};
+
+#[allow(async_fn_in_trait)]
 pub trait Actor {
     type Message;
     type Error;
@@ -11,13 +13,16 @@ pub trait Actor {
     /// Returns the actor's name.
     fn name(&self) -> Option<&str>;
 
+    /// Returns a sender that can be used to send messages to the actor.
+    fn sender(&self) -> Sender<Box<Self::Message>>;
+
     /// Send a message to the actor.
-    fn send(&self, message: Self::Message) -> Result<(), Self::Error>;
+    async fn send(&self, message: Self::Message) -> Result<(), Self::Error>;
 
-    // Terminates the actor by closing its message channel and waiting for it
-    /// to finish processing remaining messages. Consumes the actor since it
-    /// can no longer process messages.
-    async fn terminate(self);
+    /// Terminates the actor by closing its message channel and waiting for it
+    /// to finish processing remaining messages. Reports whether it stopped
+    /// gracefully. Consumes the actor since it can no longer process messages.
+    async fn terminate(self) -> Shutdown;
 
     /// Aborts the actor's event loop immediately without waiting for it to
     /// finish.
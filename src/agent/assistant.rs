@@ -1,13 +1,51 @@
-//! The OpenAI backed agent. It is a wrapper around the OpenAI API.
-//!
-//! *Under development*
+//! The LLM backed agent. It replies using any [`LlmClient`], by default
+//! the OpenAI API.
 
+pub use crate::llm::{HistoryMessage, Role};
 use {
-    super::{Actor, Message, Sender},
-    crate::Agent,
+    super::{
+        builder::Hooks, mailbox, registry::Instance, state::FnHandler, Actor, Message, ReplyStream,
+        Sender, Shutdown, StreamEvent, SystemClock,
+    },
+    crate::{
+        artifact::Spill,
+        chat::{termination::TerminationCondition, ChatMessage},
+        cleanup::Resources,
+        context::ContextWindow,
+        flags::AgentFlags,
+        llm::{
+            self,
+            offline::Offline,
+            openai,
+            reprompt::{Reprompt, RepromptPolicy},
+            retry::{Retry, RetryPolicy},
+            usage::{Metered, UsageTracker},
+            Completion, CompletionRequest, Delta, LlmClient, Parameters, ResponseFormat,
+        },
+        tokenizer::TokenizerRegistry,
+        tools::{Tool, ToolRegistry},
+        Agent,
+    },
+    schemars::JsonSchema,
+    serde::de::DeserializeOwned,
+    std::{
+        collections::HashMap,
+        future::Future,
+        sync::{Arc, Mutex},
+    },
+    tokio_util::sync::CancellationToken,
     uuid::Uuid,
 };
 
+/// The model used when none is configured.
+pub const DEFAULT_MODEL: &str = "gpt-4";
+
+/// The most times the model can call tools before replying to a message.
+pub const MAX_TOOL_ROUNDS: usize = 10;
+
+/// The most times [`Assistant::ask_typed`] asks for a reply it can parse.
+pub const MAX_PARSE_ATTEMPTS: usize = 3;
+
 /// Errors that can occur when sending a message to a assistant.
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -16,6 +54,157 @@ pub enum Error {
 
     #[error("Io error: {0:?}")]
     IoError(#[from] std::io::Error),
+
+    #[error("unable to generate reply: {0}")]
+    LlmError(#[from] llm::Error),
+
+    #[error("model was still calling tools after {MAX_TOOL_ROUNDS} rounds")]
+    TooManyToolRounds,
+}
+
+impl Error {
+    /// Returns whether the conversation can go on after the error, e.g.
+    /// after the model failed, as opposed to when the sender can't be
+    /// replied to.
+    fn is_recoverable(&self) -> bool {
+        match self {
+            // the work the reply was for was aborted
+            Self::LlmError(llm::Error::Cancelled) => false,
+            Self::LlmError(_) | Self::TooManyToolRounds => true,
+            Self::SendError(_) | Self::IoError(_) => false,
+        }
+    }
+}
+
+/// Errors that can occur when asking an assistant for a typed reply.
+#[derive(thiserror::Error, Debug)]
+pub enum AskTypedError {
+    #[error("unable to send message: {0:?}")]
+    SendError(#[from] crate::agent::SendError<Box<Message>>),
+
+    #[error("the assistant stopped without replying")]
+    NoReply,
+
+    #[error("unable to parse the reply after {attempts} attempts: {error}")]
+    ParseError {
+        attempts: usize,
+        error: serde_json::Error,
+    },
+}
+
+/// A record of the assistant switching models mid-conversation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelSwitch {
+    /// The model used before the switch.
+    pub from: String,
+
+    /// The model used after the switch.
+    pub to: String,
+
+    /// The number of history messages preceding the switch.
+    pub at: usize,
+
+    /// Why the model was switched.
+    pub reason: Option<String>,
+
+    /// Whether the client the model is called through was switched too.
+    pub switched_backend: bool,
+}
+
+/// The tokens of one call to the model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TurnTokens {
+    /// The model called.
+    pub model: String,
+
+    /// The tokens of the whole history at the time of the call.
+    pub history_tokens: usize,
+
+    /// The tokens of the messages sent, after fitting them to the
+    /// [context window](Assistant::set_context_window).
+    pub prompt_tokens: usize,
+
+    /// The tokens of the model's reply, tool calls included.
+    pub reply_tokens: usize,
+}
+
+/// A snapshot of an assistant's conversation, to restore it from, e.g. after
+/// it was evicted from a [`Registry`](super::registry::Registry).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// The model replies are generated with.
+    pub model: String,
+
+    /// Every message the assistant received and sent.
+    pub history: Vec<HistoryMessage>,
+
+    /// Every model switch, in order.
+    pub switches: Vec<ModelSwitch>,
+}
+
+/// The state an assistant keeps across messages.
+#[derive(Debug)]
+struct State {
+    /// The model replies are generated with.
+    model: String,
+
+    /// The client the model is called through.
+    client: Arc<dyn LlmClient>,
+
+    /// Every message the assistant received and sent.
+    history: Vec<HistoryMessage>,
+
+    /// Every model switch, in order.
+    switches: Vec<ModelSwitch>,
+
+    /// The namespaces of the tools the model may call, or `None` for every
+    /// tool.
+    tool_namespaces: Option<Vec<String>>,
+
+    /// Ends the assistant's work on a message early.
+    termination: Option<Arc<dyn TerminationCondition>>,
+
+    /// Keeps long messages, tool results and replies out of the history.
+    spill: Option<Spill>,
+
+    /// Keeps what's sent to the model within its context window.
+    context_window: Option<ContextWindow>,
+
+    /// Counts the tokens of each call to the model.
+    tokenizers: TokenizerRegistry,
+
+    /// The tokens of every call to the model, in order.
+    turns: Vec<TurnTokens>,
+
+    /// Instructions sent ahead of the history with every call.
+    system_prompt: Option<String>,
+
+    /// How the model samples its replies.
+    parameters: Parameters,
+
+    /// The most replies in a row the assistant sends another agent without
+    /// a human stepping in, or `None` for no limit.
+    max_consecutive_auto_reply: Option<usize>,
+
+    /// The replies in a row sent to each agent, by id.
+    auto_replies: HashMap<Uuid, usize>,
+
+    /// Who's asked to reply instead once the limit is hit.
+    human_fallback: Option<Sender<Box<Message>>>,
+
+    /// Whether every message is handed to the human fallback, e.g. once an
+    /// escalation policy has no stronger model left.
+    handed_to_human: bool,
+
+    /// Whether the tools the model calls are logged instead of run.
+    log_tool_calls: bool,
+
+    /// Turns tool namespaces on and off at runtime.
+    flags: Option<AgentFlags>,
+
+    /// Whether the model failing is replied to with the error rather than
+    /// stopping the assistant.
+    error_replies: bool,
 }
 
 /// An LLM assistant.
@@ -31,32 +220,688 @@ pub enum Error {
 #[derive(Debug)]
 pub struct Assistant {
     pub agent: Agent<Box<Message>, Error>,
+
+    /// State shared with the assistant's event loop.
+    state: Arc<Mutex<State>>,
 }
 
 impl Assistant {
-    /// Create a new assistant.
-    pub fn spawn(id: Uuid, name: Option<String>) -> Self {
-        let agent = Agent::<Box<Message>, _>::spawn(id, name, move |sender, message| {
-            async move {
-                tracing::trace!(%id,  message = &message.content, "received message; pretending to call OpenAI API");
-                // TODO: call OpenAI API
-                // for now just echo the message back
-
-                message.sender.clone().send(Box::new(Message {
-                    sender,
-                    content: message.content,
-                }))?;
-                Ok(())
+    /// Create a new assistant that replies using the given model of `client`.
+    /// If a stream is given, the reply's tokens are sent to it as they are
+    /// generated, followed by a [`StreamEvent::Complete`] with the full reply.
+    /// Tokens are also sent to the stream of each message, if it has one. The
+    /// model's reasoning, if it exposes any, is sent along as
+    /// [`StreamEvent::Thought`]s, and is kept out of the reply and the
+    /// history.
+    ///
+    /// The model may call `tools` before replying. Each call is reported to
+    /// the sender as tool progress, and its result, or error, is added to the
+    /// history for the model to use.
+    ///
+    /// A [termination condition](Assistant::set_termination) is checked
+    /// against each message's exchange: the message, the model's replies and
+    /// the tools' results. If it's met by the message or while the model
+    /// works, the assistant doesn't reply; if it's met after calling tools,
+    /// the assistant stops calling tools and replies with what it has.
+    ///
+    /// With a [spill](Assistant::set_spill), long messages, tool results and
+    /// replies are stored as artifacts and referenced in the history instead.
+    /// Replies are still sent in full.
+    ///
+    /// With a [context window](Assistant::set_context_window), the history
+    /// is shortened before it's sent if it doesn't fit. The history itself is
+    /// kept in full. The tokens of each call are
+    /// [recorded](Assistant::turn_tokens).
+    ///
+    /// A [system prompt](Assistant::set_system_prompt) and
+    /// [parameters](Assistant::set_parameters) are sent with every call to
+    /// the model, followed by the system prompt of the message, if it has
+    /// one. System prompts aren't part of the history.
+    ///
+    /// With a [limit](Assistant::set_max_consecutive_auto_reply) on
+    /// consecutive auto-replies, the assistant stops replying to an agent
+    /// once it has replied to it that many times in a row, so two agents
+    /// can't keep each other going forever. With a
+    /// [human fallback](Assistant::set_human_fallback), the human is asked to
+    /// reply instead; the human's reply is sent in the assistant's place,
+    /// and an empty reply or `exit` ends the conversation. Either way the
+    /// count starts over. While the assistant is
+    /// [handed to a human](Assistant::set_handed_to_human), the human is
+    /// asked to reply to every message.
+    pub fn spawn(
+        id: Uuid,
+        name: Option<String>,
+        model: impl ToString,
+        client: Arc<dyn LlmClient>,
+        stream: Option<Sender<StreamEvent>>,
+        tools: ToolRegistry,
+    ) -> Self {
+        Self::spawn_with_cancellation(id, name, model, client, stream, tools, None)
+    }
+
+    /// Like [`Assistant::spawn`], but stops as soon as `cancellation` is
+    /// cancelled. See
+    /// [`AgentBuilder::with_cancellation`](super::AgentBuilder::with_cancellation).
+    fn spawn_with_cancellation(
+        id: Uuid,
+        name: Option<String>,
+        model: impl ToString,
+        client: Arc<dyn LlmClient>,
+        stream: Option<Sender<StreamEvent>>,
+        tools: ToolRegistry,
+        cancellation: Option<CancellationToken>,
+    ) -> Self {
+        let tools = Arc::new(tools);
+        let state = Arc::new(Mutex::new(State {
+            model: model.to_string(),
+            client,
+            history: Vec::new(),
+            switches: Vec::new(),
+            tool_namespaces: None,
+            termination: None,
+            spill: None,
+            context_window: None,
+            tokenizers: TokenizerRegistry::default(),
+            turns: Vec::new(),
+            system_prompt: None,
+            parameters: Parameters::default(),
+            max_consecutive_auto_reply: None,
+            auto_replies: HashMap::new(),
+            human_fallback: None,
+            handed_to_human: false,
+            log_tool_calls: false,
+            flags: None,
+            error_replies: true,
+        }));
+        let own_name = name.clone().unwrap_or_else(|| "assistant".to_string());
+
+        let hooks = Hooks {
+            cancellation,
+            ..Default::default()
+        };
+        let handler = {
+            let state = state.clone();
+            move |sender: Sender<Box<Message>>, message: Box<Message>| {
+                let state = state.clone();
+                let stream = stream.clone();
+                let tools = tools.clone();
+                let own_name = own_name.clone();
+                async move {
+                    let reply_to = message.sender.clone();
+                    let own_sender = sender.clone();
+                    let error_replies = state.lock().unwrap().error_replies;
+                    let conversation = message.conversation.clone();
+                    let replied: Result<(), Error> = in_conversation(conversation.as_deref(), async {
+                        let (tools, termination, spill, log_tool_calls) = {
+                            let mut state = state.lock().unwrap();
+                            let mut received = match message.role {
+                                // other assistants' replies are this assistant's input
+                                Role::Assistant => HistoryMessage::new(Role::User, &message.content),
+                                _ => message.to_history(),
+                            };
+                            if let Some(spill) = &state.spill {
+                                received.content = spill.apply(received.content);
+                            }
+                            state.history.push(received);
+                            let mut tools = match &state.tool_namespaces {
+                                Some(namespaces) => tools.select(namespaces),
+                                None => ToolRegistry::clone(&tools),
+                            };
+                            if let Some(flags) = &state.flags {
+                                let mut enabled = tools.namespaces();
+                                enabled.retain(|namespace| flags.is_enabled(namespace));
+                                tools = tools.select(&enabled);
+                            }
+                            (
+                                tools,
+                                state.termination.clone(),
+                                state.spill.clone(),
+                                state.log_tool_calls,
+                            )
+                        };
+                        let spill = |content: String| match &spill {
+                            Some(spill) => spill.apply(content),
+                            None => content,
+                        };
+                        let definitions = tools.definitions();
+
+                        let mut transcript = vec![ChatMessage {
+                            name: message.name.clone().unwrap_or_else(|| "user".to_string()),
+                            content: message.content.to_string(),
+                            thought: None,
+                        }];
+                        if let Some(termination) = &termination {
+                            termination.start();
+                            if let Some(reason) = termination.check(&transcript) {
+                                tracing::trace!(%id, %reason, "termination condition met; not replying");
+                                return Ok(());
+                            }
+                        }
+
+                        // only agents can keep a conversation going on their own
+                        let counterpart = message.sender.target().map(|target| target.id);
+                        let human_fallback = {
+                            let mut state = state.lock().unwrap();
+                            match (state.max_consecutive_auto_reply, counterpart) {
+                                _ if state.handed_to_human => Some(state.human_fallback.clone()),
+                                (Some(max), Some(counterpart)) => {
+                                    let human_fallback = state.human_fallback.clone();
+                                    let count = state.auto_replies.entry(counterpart).or_default();
+                                    if *count < max {
+                                        *count += 1;
+                                        None
+                                    } else {
+                                        *count = 0;
+                                        Some(human_fallback)
+                                    }
+                                }
+                                _ => None,
+                            }
+                        };
+                        if let Some(human_fallback) = human_fallback {
+                            let Some(human) = human_fallback else {
+                                tracing::trace!(%id, "no human to hand the message to; not replying");
+                                return Ok(());
+                            };
+                            tracing::trace!(%id, "asking a human to reply");
+                            let (reply_to, mut replies) = mailbox::channel(None);
+                            human
+                                .send(Box::new(Message {
+                                    name: message.name.clone(),
+                                    ..Message::new(reply_to, message.content.clone())
+                                }))
+                                .await?;
+                            let content = replies
+                                .recv()
+                                .await
+                                .map(|reply| reply.content.to_string())
+                                .unwrap_or_default();
+                            if content.is_empty() || content == "exit" {
+                                tracing::trace!(%id, "the human ended the conversation");
+                                return Ok(());
+                            }
+                            state
+                                .lock()
+                                .unwrap()
+                                .history
+                                .push(HistoryMessage::new(Role::Assistant, spill(content.clone())));
+                            message
+                                .sender
+                                .clone()
+                                .send(Box::new(
+                                    Message::new(sender, content).with_role(Role::Assistant),
+                                ))
+                                .await?;
+                            return Ok(());
+                        }
+
+                        let mut rounds = 0;
+                        let content = loop {
+                            let (model, client, history, context_window, tokenizers, parameters) = {
+                                let state = state.lock().unwrap();
+                                let prompts = state.system_prompt.iter().chain(&message.system_prompt);
+                                let history = prompts
+                                    .map(|prompt| HistoryMessage::new(Role::System, prompt))
+                                    .chain(state.history.iter().cloned())
+                                    .collect::<Vec<_>>();
+                                (
+                                    state.model.clone(),
+                                    state.client.clone(),
+                                    history,
+                                    state.context_window.clone(),
+                                    state.tokenizers.clone(),
+                                    state.parameters.clone(),
+                                )
+                            };
+                            tracing::trace!(%id, model, message = %message.content, "received message; calling model");
+                            let history_tokens = tokenizers.count_messages(&model, &history);
+                            let messages = match &context_window {
+                                Some(window) => window.fit(history, &model, &tokenizers).await,
+                                None => history,
+                            };
+                            let prompt_tokens = tokenizers.count_messages(&model, &messages);
+                            let request = CompletionRequest {
+                                model: model.clone(),
+                                messages,
+                                tools: definitions.clone(),
+                                parameters,
+                                response_format: message.response_format.clone(),
+                            };
+                            let completion = complete(&*client, request, &message, &stream);
+                            let completion = match &termination {
+                                Some(termination) => tokio::select! {
+                                    completion = completion => completion?,
+                                    reason = termination.triggered() => {
+                                        tracing::trace!(%id, %reason, "termination condition met; not replying");
+                                        return Ok(());
+                                    }
+                                },
+                                None => completion.await?,
+                            };
+                            #[cfg(feature = "metrics")]
+                            if let Some(usage) = completion.usage {
+                                crate::metrics::record_tokens(id, &own_name, &model, usage);
+                            }
+                            let reply_tokens = tokenizers.count_message(
+                                &model,
+                                &HistoryMessage {
+                                    tool_calls: completion.tool_calls.clone(),
+                                    ..HistoryMessage::new(Role::Assistant, &completion.content)
+                                },
+                            );
+                            tracing::trace!(%id, history_tokens, prompt_tokens, reply_tokens, "model replied");
+                            state.lock().unwrap().turns.push(TurnTokens {
+                                model,
+                                history_tokens,
+                                prompt_tokens,
+                                reply_tokens,
+                            });
+                            if completion.tool_calls.is_empty() {
+                                state.lock().unwrap().history.push(HistoryMessage::new(
+                                    Role::Assistant,
+                                    spill(completion.content.clone()),
+                                ));
+                                break completion.content;
+                            }
+
+                            rounds += 1;
+                            if rounds > MAX_TOOL_ROUNDS {
+                                return Err(Error::TooManyToolRounds);
+                            }
+                            let calls = completion.tool_calls.clone();
+                            transcript.push(ChatMessage {
+                                name: own_name.clone(),
+                                content: completion.content.clone(),
+                                thought: None,
+                            });
+                            state.lock().unwrap().history.push(HistoryMessage {
+                                tool_calls: completion.tool_calls,
+                                ..HistoryMessage::new(
+                                    Role::Assistant,
+                                    spill(completion.content.clone()),
+                                )
+                            });
+                            for call in calls {
+                                let tool = &call.function.name;
+                                #[cfg(feature = "metrics")]
+                                crate::metrics::increment_conversation(
+                                    crate::metrics::CONVERSATION_TOOL_CALLS,
+                                    1,
+                                );
+                                message.report_progress(tool, None, "running");
+                                let result = match log_tool_calls {
+                                    true => {
+                                        let arguments = &call.function.arguments;
+                                        tracing::info!(%id, tool, arguments, "not running tool call");
+                                        format!("[{tool} was not run]")
+                                    }
+                                    false => tools.call(&call).await.unwrap_or_else(|e| {
+                                        tracing::debug!(%id, tool, error = %e, "tool call failed");
+                                        format!("error: {e}")
+                                    }),
+                                };
+                                message.report_progress(tool, Some(100), "done");
+                                let result = spill(result);
+                                transcript.push(ChatMessage {
+                                    name: tool.clone(),
+                                    content: result.clone(),
+                                    thought: None,
+                                });
+                                state
+                                    .lock()
+                                    .unwrap()
+                                    .history
+                                    .push(HistoryMessage::tool_result(&call.id, result));
+                            }
+                            if let Some(reason) = termination
+                                .as_ref()
+                                .and_then(|termination| termination.check(&transcript))
+                            {
+                                tracing::trace!(%id, %reason, "termination condition met; replying");
+                                break completion.content;
+                            }
+                        };
+                        if let Some(stream) = &stream {
+                            let _ = stream.try_send(StreamEvent::Complete(content.clone()));
+                        }
+                        message
+                            .sender
+                            .clone()
+                            .send(Box::new(
+                                Message::new(sender, content).with_role(Role::Assistant),
+                            ))
+                            .await?;
+                        Ok(())
+                    })
+                    .await;
+                    match replied {
+                        Err(e) if error_replies && e.is_recoverable() => {
+                            tracing::debug!(%id, error = %e, "unable to reply; telling the sender");
+                            let content = format!("error: {e}");
+                            if let Some(stream) = &stream {
+                                let _ = stream.try_send(StreamEvent::Complete(content.clone()));
+                            }
+                            reply_to
+                                .send(Box::new(
+                                    Message::new(own_sender, content).with_role(Role::Assistant),
+                                ))
+                                .await?;
+                            Ok(())
+                        }
+                        replied => replied,
+                    }
+                }
             }
-        });
+        };
+        let agent = Agent::spawn_with(
+            id,
+            name,
+            None,
+            None,
+            hooks,
+            Arc::new(SystemClock),
+            FnHandler(handler),
+        );
 
-        Self { agent }
+        Self { agent, state }
     }
 
     /// Returns a sender that can be used to send messages to the assistant.
     pub fn sender(&self) -> Sender<Box<Message>> {
-        Sender(self.agent.sender.clone())
+        self.agent.sender()
+    }
+
+    /// Send `content` to the assistant and stream its reply as it's
+    /// generated. See [`Sender::ask_stream`].
+    pub async fn ask_stream(
+        &self,
+        content: impl ToString,
+    ) -> Result<ReplyStream, super::SendError<Box<Message>>> {
+        self.agent.sender().ask_stream(content).await
+    }
+
+    /// Send `content` to the assistant and parse its reply into `T`. The
+    /// reply is constrained to JSON matching `T`'s schema, and if it can't be
+    /// parsed anyway the assistant is told why and asked again, up to
+    /// [`MAX_PARSE_ATTEMPTS`] times.
+    ///
+    /// Usage:
+    /// ```no_run
+    /// # use autogen_rs::agent::assistant::AssistantBuilder;
+    /// #[derive(serde::Deserialize, schemars::JsonSchema)]
+    /// struct Capital {
+    ///     city: String,
+    ///     population: u64,
+    /// }
+    ///
+    /// # async fn example() -> anyhow::Result<()> {
+    /// let assistant = AssistantBuilder::new().build();
+    /// let capital = assistant
+    ///     .ask_typed::<Capital>("What's the capital of France?")
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn ask_typed<T>(&self, content: impl ToString) -> Result<T, AskTypedError>
+    where
+        T: DeserializeOwned + JsonSchema,
+    {
+        let format = ResponseFormat::of::<T>();
+        let (sender, mut replies) = mailbox::channel(None);
+        let mut content = content.to_string();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let message =
+                Message::new(sender.clone(), content).with_response_format(format.clone());
+            self.agent.sender().send(Box::new(message)).await?;
+            let reply = replies.recv().await.ok_or(AskTypedError::NoReply)?;
+            let error = match parse_json(&reply.content.to_string()) {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            tracing::debug!(attempts, %error, "unable to parse typed reply");
+            if attempts == MAX_PARSE_ATTEMPTS {
+                return Err(AskTypedError::ParseError { attempts, error });
+            }
+            content = format!(
+                "Your reply couldn't be parsed: {error}. Reply with only JSON matching the schema."
+            );
+        }
+    }
+
+    /// Returns the model the assistant currently replies with.
+    pub fn model(&self) -> String {
+        self.state.lock().unwrap().model.clone()
+    }
+
+    /// Switch the model used for subsequent replies. The conversation
+    /// history is kept and the switch is recorded. Messages already being
+    /// processed finish with the previous model.
+    pub fn switch_model(&self, model: impl ToString, reason: Option<String>) {
+        self.switch(None, model.to_string(), reason);
     }
+
+    /// Switch the model used for subsequent replies to `model` of `client`,
+    /// e.g. to a stronger model from another provider. `client` is called as
+    /// is, without the retries or metering the builder wraps its client in.
+    /// See [`Assistant::switch_model`].
+    pub fn switch_backend(
+        &self,
+        client: Arc<dyn LlmClient>,
+        model: impl ToString,
+        reason: Option<String>,
+    ) {
+        self.switch(Some(client), model.to_string(), reason);
+    }
+
+    fn switch(&self, client: Option<Arc<dyn LlmClient>>, to: String, reason: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        let from = std::mem::replace(&mut state.model, to.clone());
+        let switched_backend = client.is_some();
+        tracing::debug!(id = %self.agent.id, from, to, reason, switched_backend, "switching model");
+        if let Some(client) = client {
+            state.client = client;
+        }
+        let at = state.history.len();
+        state.switches.push(ModelSwitch {
+            from,
+            to,
+            at,
+            reason,
+            switched_backend,
+        });
+    }
+
+    /// Restrict the tools the model may call to those in `namespaces` and
+    /// those without a namespace, or allow every tool with `None`. Messages
+    /// already being processed keep the tools they started with.
+    pub fn set_tool_namespaces(&self, namespaces: Option<Vec<String>>) {
+        self.state.lock().unwrap().tool_namespaces = namespaces;
+    }
+
+    /// Offer the model only the tools in namespaces whose flag in `flags` is
+    /// on, e.g. [`WEB_ACCESS`](crate::flags::WEB_ACCESS) for the `"web"`
+    /// namespace, or every tool with `None`. Flags are checked for each
+    /// message.
+    pub fn set_flags(&self, flags: Option<AgentFlags>) {
+        self.state.lock().unwrap().flags = flags;
+    }
+
+    /// End the assistant's work on each message early when `termination` is
+    /// met, or never with `None`. See [`Assistant::spawn`].
+    pub fn set_termination(&self, termination: Option<Arc<dyn TerminationCondition>>) {
+        self.state.lock().unwrap().termination = termination;
+    }
+
+    /// Keep long messages, tool results and replies out of the history with
+    /// `spill`, or keep them in full with `None`. See [`Assistant::spawn`].
+    pub fn set_spill(&self, spill: Option<Spill>) {
+        self.state.lock().unwrap().spill = spill;
+    }
+
+    /// Keep what's sent to the model within `window`, or send the whole
+    /// history with `None`. See [`Assistant::spawn`].
+    pub fn set_context_window(&self, window: Option<ContextWindow>) {
+        self.state.lock().unwrap().context_window = window;
+    }
+
+    /// Send `prompt` ahead of the history with every call to the model, or
+    /// no system prompt with `None`.
+    pub fn set_system_prompt(&self, prompt: Option<String>) {
+        self.state.lock().unwrap().system_prompt = prompt;
+    }
+
+    /// Returns the system prompt sent with every call to the model.
+    pub fn system_prompt(&self) -> Option<String> {
+        self.state.lock().unwrap().system_prompt.clone()
+    }
+
+    /// Sample replies with `parameters`.
+    pub fn set_parameters(&self, parameters: Parameters) {
+        self.state.lock().unwrap().parameters = parameters;
+    }
+
+    /// Returns the parameters replies are sampled with.
+    pub fn parameters(&self) -> Parameters {
+        self.state.lock().unwrap().parameters.clone()
+    }
+
+    /// Stop replying to an agent after `max` replies to it in a row, or
+    /// never stop if `None`.
+    pub fn set_max_consecutive_auto_reply(&self, max: Option<usize>) {
+        self.state.lock().unwrap().max_consecutive_auto_reply = max;
+    }
+
+    /// Ask `human` to reply instead once the limit on consecutive
+    /// auto-replies is hit, or stop replying if `None`.
+    pub fn set_human_fallback(&self, human: Option<Sender<Box<Message>>>) {
+        self.state.lock().unwrap().human_fallback = human;
+    }
+
+    /// Hand every message to the [human fallback](Assistant::set_human_fallback)
+    /// to reply to in the assistant's place, e.g. once an
+    /// [escalation policy](super::escalation::EscalationPolicy) has no
+    /// stronger model left, or go back to replying with the model. Without a
+    /// human fallback, the assistant doesn't reply while handed to a human.
+    pub fn set_handed_to_human(&self, handed: bool) {
+        self.state.lock().unwrap().handed_to_human = handed;
+    }
+
+    /// Log the tools the model calls, and tell it they weren't run, instead
+    /// of running them, e.g. to develop a workflow without side effects.
+    pub fn set_log_tool_calls(&self, log: bool) {
+        self.state.lock().unwrap().log_tool_calls = log;
+    }
+
+    /// Reply with the error, e.g. `error: unable to generate reply: ...`,
+    /// when the model fails, so the conversation can recover, instead of
+    /// stopping the assistant. On by default.
+    pub fn set_error_replies(&self, error_replies: bool) {
+        self.state.lock().unwrap().error_replies = error_replies;
+    }
+
+    /// Forget how many replies in a row the assistant sent each agent.
+    pub fn reset_consecutive_auto_reply(&self) {
+        self.state.lock().unwrap().auto_replies.clear();
+    }
+
+    /// Count tokens with `tokenizers`.
+    pub fn set_tokenizers(&self, tokenizers: TokenizerRegistry) {
+        self.state.lock().unwrap().tokenizers = tokenizers;
+    }
+
+    /// Returns the tokens of every call to the model so far, in order.
+    pub fn turn_tokens(&self) -> Vec<TurnTokens> {
+        self.state.lock().unwrap().turns.clone()
+    }
+
+    /// Returns the namespaces of the tools the model may call, or `None` if
+    /// it may call every tool.
+    pub fn tool_namespaces(&self) -> Option<Vec<String>> {
+        self.state.lock().unwrap().tool_namespaces.clone()
+    }
+
+    /// Returns the assistant's conversation history.
+    pub fn history(&self) -> Vec<HistoryMessage> {
+        self.state.lock().unwrap().history.clone()
+    }
+
+    /// Returns every model switch, in order.
+    pub fn model_switches(&self) -> Vec<ModelSwitch> {
+        self.state.lock().unwrap().switches.clone()
+    }
+
+    /// Returns a snapshot of the assistant's conversation.
+    pub fn checkpoint(&self) -> Checkpoint {
+        let state = self.state.lock().unwrap();
+        Checkpoint {
+            model: state.model.clone(),
+            history: state.history.clone(),
+            switches: state.switches.clone(),
+        }
+    }
+
+    /// Continue the conversation from `checkpoint`, replacing the current
+    /// one.
+    pub fn restore(&self, checkpoint: Checkpoint) {
+        let mut state = self.state.lock().unwrap();
+        state.model = checkpoint.model;
+        state.history = checkpoint.history;
+        state.switches = checkpoint.switches;
+    }
+}
+
+/// Runs `future` as part of the conversation with `id`, if any, for
+/// metrics.
+async fn in_conversation<F: Future>(id: Option<&str>, future: F) -> F::Output {
+    #[cfg(feature = "metrics")]
+    return crate::metrics::in_conversation(id, future).await;
+    #[cfg(not(feature = "metrics"))]
+    {
+        let _ = id;
+        future.await
+    }
+}
+
+/// Parses a reply as JSON, ignoring a code fence around it, which models
+/// add even when asked for bare JSON.
+pub(crate) fn parse_json<T: DeserializeOwned>(reply: &str) -> Result<T, serde_json::Error> {
+    let reply = reply.trim();
+    let json = reply
+        .strip_prefix("```")
+        .and_then(|fenced| fenced.strip_suffix("```"))
+        .map_or(reply, |fenced| {
+            // skip the fence's language, e.g. `json`
+            fenced.split_once('\n').map_or(fenced, |(_, body)| body)
+        });
+    serde_json::from_str(json)
+}
+
+/// Ask the model for the next step in the conversation, streaming its output
+/// to `stream` and its reasoning to the sender of `message`.
+async fn complete(
+    client: &dyn LlmClient,
+    request: CompletionRequest,
+    message: &Message,
+    stream: &Option<Sender<StreamEvent>>,
+) -> Result<Completion, llm::Error> {
+    let streams = stream.iter().chain(&message.stream).collect::<Vec<_>>();
+    if streams.is_empty() {
+        return client.complete(request).await;
+    }
+    client
+        .stream(request, &mut |delta| {
+            let event = match delta {
+                Delta::Content(token) => StreamEvent::Token(token.to_string()),
+                Delta::Reasoning(thought) => StreamEvent::Thought(thought.to_string()),
+            };
+            // previews are best effort, so events are dropped if a stream is full or closed
+            for stream in &streams {
+                let _ = stream.try_send(event.clone());
+            }
+        })
+        .await
 }
 
 #[derive(Debug, Default)]
@@ -66,6 +911,90 @@ pub struct AssistantBuilder {
 
     /// A user-friendly name for the assistant.
     pub name: Option<String>,
+
+    /// The model replies are generated with.
+    pub model: Option<String>,
+
+    /// Instructions sent ahead of the history with every call to the model.
+    pub system_prompt: Option<String>,
+
+    /// How the model samples its replies.
+    pub parameters: Parameters,
+
+    /// The client replies are generated with. Defaults to an OpenAI client
+    /// configured with `api_key` and `base_url`, which retries transient
+    /// failures.
+    pub client: Option<Arc<dyn LlmClient>>,
+
+    /// How failed calls to the client are retried. Defaults to the default
+    /// policy for the default client, and to no retries for other clients.
+    pub retry: Option<RetryPolicy>,
+
+    /// Asks the model again when a reply is empty, a refusal or too short.
+    pub reprompt: Option<RepromptPolicy>,
+
+    /// The OpenAI API key. Falls back to the OPENAI_API_KEY environment
+    /// variable.
+    pub api_key: Option<String>,
+
+    /// The base URL of the OpenAI API. Falls back to the OPENAI_BASE_URL
+    /// environment variable, then to the public OpenAI API.
+    pub base_url: Option<String>,
+
+    /// Where to stream the tokens of replies as they are generated.
+    pub stream: Option<Sender<StreamEvent>>,
+
+    /// The tools the model can call.
+    pub tools: ToolRegistry,
+
+    /// The namespaces of the tools the model may call, or `None` for every
+    /// tool.
+    pub tool_namespaces: Option<Vec<String>>,
+
+    /// The conversation to continue from.
+    pub checkpoint: Option<Checkpoint>,
+
+    /// Ends the assistant's work on a message early.
+    pub termination: Option<Arc<dyn TerminationCondition>>,
+
+    /// Keeps long messages, tool results and replies out of the history.
+    pub spill: Option<Spill>,
+
+    /// Keeps what's sent to the model within its context window.
+    pub context_window: Option<ContextWindow>,
+
+    /// Counts tokens. Defaults to [`TokenizerRegistry::default`].
+    pub tokenizers: Option<TokenizerRegistry>,
+
+    /// Records the usage of every call to the model and holds calls to its
+    /// budget.
+    pub usage: Option<UsageTracker>,
+
+    /// The most replies in a row the assistant sends another agent.
+    pub max_consecutive_auto_reply: Option<usize>,
+
+    /// Who's asked to reply instead once the limit on consecutive
+    /// auto-replies is hit.
+    pub human_fallback: Option<Sender<Box<Message>>>,
+
+    /// Tracks calls to the model, so they're interrupted when the work
+    /// they're for is aborted.
+    pub resources: Option<Resources>,
+
+    /// Stops the assistant when cancelled.
+    pub cancellation: Option<CancellationToken>,
+
+    /// Whether the assistant replies with placeholders instead of calling a
+    /// model, and logs tool calls instead of running them. Assistants with
+    /// neither a client nor an API key are offline anyway.
+    pub offline: bool,
+
+    /// Turns tool namespaces on and off at runtime.
+    pub flags: Option<AgentFlags>,
+
+    /// Whether the model failing is replied to with the error rather than
+    /// stopping the assistant. Defaults to `true`.
+    pub error_replies: Option<bool>,
 }
 
 impl AssistantBuilder {
@@ -86,9 +1015,302 @@ impl AssistantBuilder {
         self
     }
 
+    /// Set the model replies are generated with.
+    pub fn with_model(mut self, model: impl ToString) -> Self {
+        self.model = Some(model.to_string());
+        self
+    }
+
+    /// Send `prompt` ahead of the history with every call to the model.
+    pub fn with_system_prompt(mut self, prompt: impl ToString) -> Self {
+        self.system_prompt = Some(prompt.to_string());
+        self
+    }
+
+    /// Set how random replies are, usually from 0 to 2.
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.parameters.temperature = Some(temperature);
+        self
+    }
+
+    /// Only sample replies from the most likely tokens making up `top_p`
+    /// of the probability mass.
+    pub fn with_top_p(mut self, top_p: f32) -> Self {
+        self.parameters.top_p = Some(top_p);
+        self
+    }
+
+    /// Limit replies to `max_tokens` tokens.
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.parameters.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// End replies when the model generates any of `sequences`.
+    pub fn with_stop_sequences<I, S>(mut self, sequences: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        self.parameters.stop_sequences = sequences.into_iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    /// Generate replies with `client`, e.g. to use a provider other than
+    /// OpenAI. The API key and base URL are ignored if a client is set.
+    pub fn with_client(mut self, client: Arc<dyn LlmClient>) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Set the OpenAI API key.
+    pub fn with_api_key(mut self, api_key: impl ToString) -> Self {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    /// Set the base URL of the OpenAI API, e.g. to use an OpenAI-compatible
+    /// server.
+    pub fn with_base_url(mut self, base_url: impl ToString) -> Self {
+        self.base_url = Some(base_url.to_string());
+        self
+    }
+
+    /// Stream the tokens of replies to `stream` as they are generated, e.g.
+    /// to a [`UserAgent`](super::user::UserAgent)'s
+    /// [`stream_sender`](super::user::UserAgent::stream_sender).
+    pub fn with_stream(mut self, stream: Sender<StreamEvent>) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    /// Let the model call `tool`.
+    pub fn with_tool(mut self, tool: impl Tool) -> Self {
+        self.tools.register(tool);
+        self
+    }
+
+    /// Let the model call the tools in `tools`, replacing any tools added so
+    /// far.
+    pub fn with_tools(mut self, tools: ToolRegistry) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    /// Only let the model call the tools in `namespaces`, and the tools
+    /// without a namespace. See [`Assistant::set_tool_namespaces`].
+    pub fn with_tool_namespaces<I, S>(mut self, namespaces: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        self.tool_namespaces = Some(namespaces.into_iter().map(|s| s.to_string()).collect());
+        self
+    }
+
+    /// End the assistant's work on each message early when `condition` is
+    /// met. See [`Assistant::spawn`].
+    pub fn with_termination(mut self, condition: impl TerminationCondition) -> Self {
+        self.termination = Some(Arc::new(condition));
+        self
+    }
+
+    /// Store long messages, tool results and replies with `spill` instead of
+    /// keeping them in the history, and let the model read them back with
+    /// the spill's [tool](Spill::tool).
+    pub fn with_spill(mut self, spill: Spill) -> Self {
+        self.spill = Some(spill);
+        self
+    }
+
+    /// Keep what's sent to the model within `window`. See
+    /// [`Assistant::set_context_window`].
+    pub fn with_context_window(mut self, window: ContextWindow) -> Self {
+        self.context_window = Some(window);
+        self
+    }
+
+    /// Count tokens with `tokenizers`, e.g. to use the model's own
+    /// tokenizer.
+    pub fn with_tokenizers(mut self, tokenizers: TokenizerRegistry) -> Self {
+        self.tokenizers = Some(tokenizers);
+        self
+    }
+
+    /// Record the usage and cost of every call to the model in `tracker`,
+    /// under the assistant's name, and hold calls to its budget.
+    pub fn with_usage(mut self, tracker: UsageTracker) -> Self {
+        self.usage = Some(tracker);
+        self
+    }
+
+    /// Retry calls to the client that fail transiently, e.g. on rate limits
+    /// or server errors, with `policy`.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Ask the model again, with instructions on what was wrong, when a
+    /// reply is empty, a refusal or too short by `policy`. See [`Reprompt`].
+    pub fn with_reprompt(mut self, policy: RepromptPolicy) -> Self {
+        self.reprompt = Some(policy);
+        self
+    }
+
+    /// Continue the conversation from `checkpoint`, including its model.
+    pub fn with_checkpoint(mut self, checkpoint: Checkpoint) -> Self {
+        self.checkpoint = Some(checkpoint);
+        self
+    }
+
+    /// Stop replying to an agent after `max` replies to it in a row, e.g. so
+    /// two assistants talking to each other don't loop forever.
+    pub fn with_max_consecutive_auto_reply(mut self, max: usize) -> Self {
+        self.max_consecutive_auto_reply = Some(max);
+        self
+    }
+
+    /// Ask `human`, e.g. a [user agent](super::user::UserAgent), to reply
+    /// instead once the limit on consecutive auto-replies is hit.
+    pub fn with_human_fallback(mut self, human: Sender<Box<Message>>) -> Self {
+        self.human_fallback = Some(human);
+        self
+    }
+
+    /// Track calls to the model in `resources`, interrupting them when the
+    /// resources are cancelled.
+    pub fn with_resources(mut self, resources: Resources) -> Self {
+        self.resources = Some(resources);
+        self
+    }
+
+    /// Reply with the error when the model fails, so the conversation can
+    /// recover, or stop the assistant if `false`. See
+    /// [`Assistant::set_error_replies`].
+    pub fn with_error_replies(mut self, error_replies: bool) -> Self {
+        self.error_replies = Some(error_replies);
+        self
+    }
+
+    /// Stop the assistant as soon as `cancellation` is cancelled, e.g. when
+    /// the user hits Ctrl-C, interrupting the call to the model it's waiting
+    /// on. Pass [`Resources::token`] to stop it along with the work it was
+    /// started for.
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Reply with [`Offline`] placeholders instead of calling a model, after
+    /// calling each tool once, and log tool calls instead of running them, so
+    /// the structure of a workflow can be developed without credentials or
+    /// cost.
+    pub fn with_offline(mut self) -> Self {
+        self.offline = true;
+        self
+    }
+
+    /// Offer the model only the tools in namespaces whose flag is on. See
+    /// [`Assistant::set_flags`].
+    pub fn with_flags(mut self, flags: AgentFlags) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
     /// Builds the assistant.
-    pub fn build(self) -> Assistant {
-        Assistant::spawn(self.id.unwrap_or_else(Uuid::new_v4), self.name)
+    pub fn build(mut self) -> Assistant {
+        if let Some(spill) = &self.spill {
+            self.tools.register(spill.tool());
+        }
+        let no_api_key = self.client.is_none()
+            && self.api_key.is_none()
+            && std::env::var(openai::API_KEY_ENV_VAR).is_err();
+        if no_api_key && !self.offline {
+            tracing::warn!(
+                "no client or OpenAI API key configured; replying offline with placeholders"
+            );
+        }
+        let offline = self.offline || no_api_key;
+        let client = match (self.client, self.retry) {
+            _ if offline => Arc::new(Offline::new().with_tool_calls()),
+            (Some(client), None) => client,
+            (client, retry) => {
+                let client = client
+                    .unwrap_or_else(|| Arc::new(openai::Client::new(self.api_key, self.base_url)));
+                Arc::new(Retry::new(client).with_policy(retry.unwrap_or_default()))
+            }
+        };
+        let client = match self.reprompt {
+            Some(policy) => Arc::new(Reprompt::new(client).with_policy(policy)),
+            None => client,
+        };
+        let client = match self.usage {
+            Some(tracker) => {
+                let agent = self.name.as_deref().unwrap_or("assistant");
+                let metered = Metered::new(client, tracker).with_agent(agent);
+                let metered = match &self.tokenizers {
+                    Some(tokenizers) => metered.with_tokenizers(tokenizers.clone()),
+                    None => metered,
+                };
+                Arc::new(metered)
+            }
+            None => client,
+        };
+        let client = match &self.resources {
+            Some(resources) => resources.client(client),
+            None => client,
+        };
+        let assistant = Assistant::spawn_with_cancellation(
+            self.id.unwrap_or_else(Uuid::new_v4),
+            self.name,
+            self.model.as_deref().unwrap_or(DEFAULT_MODEL),
+            client,
+            self.stream,
+            self.tools,
+            self.cancellation,
+        );
+        if let Some(checkpoint) = self.checkpoint {
+            assistant.restore(checkpoint);
+        }
+        assistant.set_tool_namespaces(self.tool_namespaces);
+        assistant.set_termination(self.termination);
+        assistant.set_spill(self.spill);
+        assistant.set_context_window(self.context_window);
+        assistant.set_system_prompt(self.system_prompt);
+        assistant.set_parameters(self.parameters);
+        assistant.set_max_consecutive_auto_reply(self.max_consecutive_auto_reply);
+        assistant.set_human_fallback(self.human_fallback);
+        assistant.set_log_tool_calls(offline);
+        assistant.set_flags(self.flags);
+        if let Some(error_replies) = self.error_replies {
+            assistant.set_error_replies(error_replies);
+        }
+        if let Some(tokenizers) = self.tokenizers {
+            assistant.set_tokenizers(tokenizers);
+        }
+        assistant
+    }
+}
+
+impl Instance for Assistant {
+    type Checkpoint = Checkpoint;
+    type Message = Box<Message>;
+
+    fn sender(&self) -> Sender<Box<Message>> {
+        self.agent.sender()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.agent.is_finished()
+    }
+
+    fn checkpoint(&self) -> Option<Checkpoint> {
+        Some(Assistant::checkpoint(self))
+    }
+
+    fn terminate(self) -> impl Future<Output = Shutdown> + Send {
+        self.agent.terminate()
     }
 }
 
@@ -105,16 +1327,700 @@ impl Actor for Assistant {
         self.agent.name.as_deref()
     }
 
-    async fn terminate(self) {
-        self.agent.terminate().await;
+    fn sender(&self) -> Sender<Box<Message>> {
+        self.agent.sender()
+    }
+
+    async fn terminate(self) -> Shutdown {
+        self.agent.terminate().await
     }
 
     fn abort(self) {
         self.agent.abort()
     }
 
-    fn send(&self, message: Self::Message) -> Result<(), Self::Error> {
-        self.agent.send(Box::new(message))?;
+    async fn send(&self, message: Self::Message) -> Result<(), Self::Error> {
+        self.agent.send(Box::new(message)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            agent::{registry::Registry, ManualClock, ToolProgress},
+            llm::LlmFuture,
+            tools::{self, FnTool},
+        },
+        anyhow::Result,
+        std::time::Duration,
+    };
+
+    /// A backend that replies with the last message reversed.
+    #[derive(Debug)]
+    struct Reverse;
+
+    impl LlmClient for Reverse {
+        fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                let last = request.messages.last().map(|m| m.content.as_str());
+                Ok(Completion {
+                    content: last.unwrap_or_default().chars().rev().collect(),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_replies() -> Result<()> {
+        /// Times out on "fail", and reverses everything else.
+        #[derive(Debug)]
+        struct Flaky;
+
+        impl LlmClient for Flaky {
+            fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+                Box::pin(async move {
+                    match request.messages.last().map(|m| m.content.as_str()) {
+                        Some("fail") => Err(llm::Error::Timeout(Duration::from_secs(1))),
+                        _ => Reverse.complete(request).await,
+                    }
+                })
+            }
+        }
+
+        async fn reply(assistant: &Assistant, content: &str) -> Result<Option<String>> {
+            let mut reply = assistant.ask_stream(content).await?;
+            while let Some(event) = reply.next().await {
+                if let StreamEvent::Complete(content) = event {
+                    return Ok(Some(content));
+                }
+            }
+            Ok(None)
+        }
+
+        let assistant = AssistantBuilder::new().with_client(Arc::new(Flaky)).build();
+        assert_eq!(
+            reply(&assistant, "fail").await?.as_deref(),
+            Some("error: unable to generate reply: no reply within 1s")
+        );
+        // the assistant carries on
+        assert_eq!(reply(&assistant, "abc").await?.as_deref(), Some("cba"));
+
+        assistant.set_error_replies(false);
+        assert_eq!(reply(&assistant, "fail").await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_custom_client() -> Result<()> {
+        let assistant = AssistantBuilder::new()
+            .with_client(Arc::new(Reverse))
+            .build();
+
+        let mut reply = assistant.ask_stream("hello").await?;
+        assert_eq!(
+            reply.next().await,
+            Some(StreamEvent::Token("olleh".to_string()))
+        );
+        assert_eq!(
+            reply.next().await,
+            Some(StreamEvent::Complete("olleh".to_string()))
+        );
+        assert_eq!(assistant.history().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_consecutive_auto_reply() -> Result<()> {
+        let build = |name| {
+            AssistantBuilder::new()
+                .with_name(name)
+                .with_client(Arc::new(Reverse))
+                .with_max_consecutive_auto_reply(2)
+                .build()
+        };
+        let (a, b) = (build("a"), build("b"));
+        b.sender()
+            .send(Box::new(Message::new(a.sender(), "hello")))
+            .await?;
+
+        // each replies twice, then b stops replying
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while b.history().len() < 5 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(a.history().len(), 4);
+        assert_eq!(b.history().len(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_human_fallback() -> Result<()> {
+        let (human, mut questions) = mailbox::channel(None);
+        let a = AssistantBuilder::new()
+            .with_client(Arc::new(Reverse))
+            .build();
+        let b = AssistantBuilder::new()
+            .with_client(Arc::new(Reverse))
+            .with_max_consecutive_auto_reply(1)
+            .with_human_fallback(human)
+            .build();
+        b.sender()
+            .send(Box::new(Message::new(a.sender(), "hello")))
+            .await?;
+
+        // b replies "olleh", a replies "hello", and the human replies for b
+        let question = questions.recv().await.unwrap();
+        assert_eq!(question.content.to_string(), "hello");
+        let (reply_to, _) = mailbox::channel(None);
+        question
+            .sender
+            .send(Box::new(Message::new(reply_to.clone(), "stop")))
+            .await?;
+
+        // a replies "pots", and the count started over, so b replies "stop"
+        // before asking again
+        let question = questions.recv().await.unwrap();
+        assert_eq!(question.content.to_string(), "pots");
+        assert!(b
+            .history()
+            .contains(&HistoryMessage::new(Role::Assistant, "stop")));
+        question
+            .sender
+            .send(Box::new(Message::new(reply_to, "exit")))
+            .await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(b.history().len(), 7);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ask_typed() -> Result<()> {
+        #[derive(Debug, PartialEq, serde::Deserialize, JsonSchema)]
+        struct Point {
+            x: i32,
+            y: i32,
+        }
+
+        /// Replies with broken JSON, then with JSON in a code fence once
+        /// told what's wrong.
+        #[derive(Debug)]
+        struct Sloppy;
+
+        impl LlmClient for Sloppy {
+            fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+                Box::pin(async move {
+                    assert_eq!(request.response_format.unwrap().name, "Point");
+                    let last = &request.messages.last().unwrap().content;
+                    let content = match last.starts_with("Your reply couldn't be parsed") {
+                        true => "```json\n{\"x\": 1, \"y\": 2}\n```",
+                        false => "{\"x\": 1,",
+                    };
+                    Ok(Completion {
+                        content: content.to_string(),
+                        ..Default::default()
+                    })
+                })
+            }
+        }
+
+        let assistant = AssistantBuilder::new()
+            .with_client(Arc::new(Sloppy))
+            .build();
+        let point = assistant.ask_typed::<Point>("where?").await?;
+        assert_eq!(point, Point { x: 1, y: 2 });
+        assert_eq!(assistant.history().len(), 4);
+
+        // a reply that never parses fails after the last attempt
+        let assistant = AssistantBuilder::new()
+            .with_client(Arc::new(Reverse))
+            .build();
+        assert!(matches!(
+            assistant.ask_typed::<Point>("where?").await,
+            Err(AskTypedError::ParseError {
+                attempts: MAX_PARSE_ATTEMPTS,
+                ..
+            })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_system_prompt_and_parameters() -> Result<()> {
+        /// Replies with the system prompt and the temperature it's asked
+        /// with.
+        #[derive(Debug)]
+        struct Echo;
+
+        impl LlmClient for Echo {
+            fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+                Box::pin(async move {
+                    let first = &request.messages[0];
+                    Ok(Completion {
+                        content: format!(
+                            "{:?} {} {:?} {:?}",
+                            first.role,
+                            first.content,
+                            request.parameters.temperature,
+                            request.parameters.stop_sequences
+                        ),
+                        ..Default::default()
+                    })
+                })
+            }
+        }
+
+        let assistant = AssistantBuilder::new()
+            .with_client(Arc::new(Echo))
+            .with_system_prompt("Be brief.")
+            .with_temperature(0.2)
+            .with_stop_sequences(["END"])
+            .build();
+        let mut reply = assistant.ask_stream("hello").await?;
+        let mut content = None;
+        while let Some(event) = reply.next().await {
+            if let StreamEvent::Complete(complete) = event {
+                content = Some(complete);
+            }
+        }
+        assert_eq!(
+            content.as_deref(),
+            Some(r#"System Be brief. Some(0.2) ["END"]"#)
+        );
+        // the prompt is sent, but isn't part of the history
+        assert_eq!(
+            assistant.history()[0],
+            HistoryMessage::new(Role::User, "hello")
+        );
+        assert_eq!(assistant.parameters().max_tokens, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_switch_model_keeps_history() -> Result<()> {
+        let assistant = AssistantBuilder::new()
+            .with_model("cheap")
+            .with_api_key("key")
+            .with_base_url(openai::mock::echo_server().await)
+            .build();
+        let (inbox, mut replies) = crate::agent::channel(None);
+
+        assistant
+            .send(Message::new(inbox.clone(), "first".to_string()))
+            .await?;
+        replies.recv().await;
+
+        assistant.switch_model("strong", Some("too many failures".to_string()));
+        assert_eq!(assistant.model(), "strong");
+
+        assistant
+            .send(Message::new(inbox, "second".to_string()))
+            .await?;
+        replies.recv().await;
+
+        assert_eq!(assistant.history().len(), 4);
+        assert_eq!(
+            assistant.model_switches(),
+            [ModelSwitch {
+                from: "cheap".to_string(),
+                to: "strong".to_string(),
+                at: 2,
+                reason: Some("too many failures".to_string()),
+                switched_backend: false,
+            }]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_switch_backend() -> Result<()> {
+        let assistant = AssistantBuilder::new()
+            .with_model("local")
+            .with_api_key("key")
+            .with_base_url(openai::mock::echo_server().await)
+            .build();
+        let (inbox, mut replies) = crate::agent::channel(None);
+
+        assistant
+            .send(Message::new(inbox.clone(), "first".to_string()))
+            .await?;
+        replies.recv().await;
+
+        assistant.switch_backend(Arc::new(Reverse), "remote", None);
+        assert_eq!(assistant.model(), "remote");
+
+        assistant
+            .send(Message::new(inbox, "second".to_string()))
+            .await?;
+        let reply = replies.recv().await.unwrap();
+        assert_eq!(reply.content.to_string(), "dnoces");
+
+        assert_eq!(assistant.history().len(), 4);
+        assert_eq!(
+            assistant.model_switches(),
+            [ModelSwitch {
+                from: "local".to_string(),
+                to: "remote".to_string(),
+                at: 2,
+                reason: None,
+                switched_backend: true,
+            }]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restore_after_eviction() -> Result<()> {
+        let base_url = openai::mock::echo_server().await;
+        let clock = ManualClock::new();
+        let registry = Registry::with_restore(move |name, checkpoint| {
+            let builder = AssistantBuilder::new()
+                .with_name(name)
+                .with_api_key("key")
+                .with_base_url(&base_url);
+            match checkpoint {
+                Some(checkpoint) => builder.with_checkpoint(checkpoint),
+                None => builder,
+            }
+            .build()
+        })
+        .with_idle_timeout(Duration::from_secs(60))
+        .with_clock(clock.clone());
+
+        let mut reply = registry.sender("alice").ask_stream("first").await?;
+        while reply.next().await.is_some() {}
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(registry.evict_idle(), ["alice"]);
+
+        let mut reply = registry.sender("alice").ask_stream("second").await?;
+        while reply.next().await.is_some() {}
+        let alice = registry.remove("alice").expect("alice is running");
+        let roles = alice
+            .history()
+            .iter()
+            .map(|message| message.role)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            roles,
+            [Role::User, Role::Assistant, Role::User, Role::Assistant]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_tokens() -> Result<()> {
+        let (stream, mut tokens) = crate::agent::channel(None);
+        let assistant = AssistantBuilder::new()
+            .with_api_key("key")
+            .with_base_url(openai::mock::echo_server().await)
+            .with_stream(stream)
+            .build();
+        let (inbox, mut replies) = crate::agent::channel(None);
+
+        assistant
+            .send(Message::new(inbox, "hello world".to_string()))
+            .await?;
+
+        assert_eq!(
+            replies.recv().await.map(|reply| reply.content.to_string()),
+            Some("hello world".to_string())
+        );
+        assert_eq!(
+            tokens.recv().await,
+            Some(StreamEvent::Token("hello ".to_string()))
+        );
+        assert_eq!(
+            tokens.recv().await,
+            Some(StreamEvent::Token("world".to_string()))
+        );
+        assert_eq!(
+            tokens.recv().await,
+            Some(StreamEvent::Complete("hello world".to_string()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ask_stream() -> Result<()> {
+        let assistant = AssistantBuilder::new()
+            .with_api_key("key")
+            .with_base_url(openai::mock::echo_server().await)
+            .build();
+
+        let mut reply = assistant.ask_stream("hello world").await?;
+        let mut events = Vec::new();
+        while let Some(event) = reply.next().await {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            [
+                StreamEvent::Token("hello ".to_string()),
+                StreamEvent::Token("world".to_string()),
+                StreamEvent::Complete("hello world".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls() -> Result<()> {
+        let assistant = AssistantBuilder::new()
+            .with_api_key("key")
+            .with_base_url(openai::mock::echo_server().await)
+            .with_tool(FnTool::new(
+                "shout",
+                "Shouts the text.",
+                serde_json::json!({ "type": "object" }),
+                |arguments| async move {
+                    arguments["text"]
+                        .as_str()
+                        .map(str::to_uppercase)
+                        .ok_or_else(|| tools::Error::InvalidArguments("no text".to_string()))
+                },
+            ))
+            .build();
+        let (inbox, mut replies) = crate::agent::channel(None);
+        let (stream, mut progress) = crate::agent::channel(None);
+
+        assistant
+            .send(
+                Message::new(inbox, r#"call shout {"text":"hi"}"#.to_string()).with_stream(stream),
+            )
+            .await?;
+
+        // the mock model echoes the tool's result
+        assert_eq!(
+            replies.recv().await.map(|reply| reply.content.to_string()),
+            Some("HI".to_string())
+        );
+        assert!(matches!(
+            progress.recv().await,
+            Some(StreamEvent::Progress(ToolProgress { tool, .. })) if tool == "shout"
+        ));
+        let roles = assistant
+            .history()
+            .iter()
+            .map(|message| message.role)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            roles,
+            [Role::User, Role::Assistant, Role::Tool, Role::Assistant]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_offline() -> Result<()> {
+        let assistant = AssistantBuilder::new()
+            .with_model("gpt-4o")
+            .with_offline()
+            .with_tool(FnTool::new(
+                "delete",
+                "Deletes everything.",
+                serde_json::json!({ "type": "object" }),
+                |_| async { panic!("tools aren't run offline") },
+            ))
+            .build();
+        let (inbox, mut replies) = crate::agent::channel(None);
+
+        assistant.send(Message::new(inbox, "clean up")).await?;
+
+        assert_eq!(
+            replies.recv().await.map(|reply| reply.content.to_string()),
+            Some("[offline reply from gpt-4o to: clean up]".to_string())
+        );
+        let history = assistant.history();
+        assert_eq!(history[1].tool_calls[0].function.name, "delete");
+        assert_eq!(history[2].content, "[delete was not run]");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flags() -> Result<()> {
+        use crate::flags::{Flags, Scope, WEB_ACCESS};
+
+        let tool = |name| {
+            FnTool::new(
+                name,
+                "Does something.",
+                serde_json::json!({ "type": "object" }),
+                |_| async { Ok(String::new()) },
+            )
+        };
+        let flags = Flags::new();
+        flags.set(Scope::Default, WEB_ACCESS, false);
+        // offline, the model calls every tool it's offered
+        let assistant = AssistantBuilder::new()
+            .with_name("researcher")
+            .with_offline()
+            .with_tools(
+                ToolRegistry::new()
+                    .with_tool_in(WEB_ACCESS, tool("search"))
+                    .with_tool_in("files", tool("read")),
+            )
+            .with_flags(flags.for_agent("researcher"))
+            .build();
+        let (inbox, mut replies) = crate::agent::channel(None);
+        let called = |assistant: &Assistant| {
+            let history = assistant.history();
+            let calling = history.iter().rev().find(|m| !m.tool_calls.is_empty());
+            calling.map_or(Vec::new(), |message| {
+                let calls = message.tool_calls.iter();
+                calls.map(|call| call.function.name.clone()).collect()
+            })
+        };
+
+        assistant
+            .send(Message::new(inbox.clone(), "look it up"))
+            .await?;
+        replies.recv().await;
+        assert_eq!(called(&assistant), ["read"]);
+
+        flags.set(Scope::Agent("researcher".to_string()), WEB_ACCESS, true);
+        assistant.send(Message::new(inbox, "look it up")).await?;
+        replies.recv().await;
+        assert_eq!(called(&assistant), ["read", "search"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_termination() -> Result<()> {
+        use crate::chat::termination::{Keyword, MaxMessages, TerminationCondition};
+
+        let assistant = AssistantBuilder::new()
+            .with_api_key("key")
+            .with_base_url(openai::mock::echo_server().await)
+            .with_tool(FnTool::new(
+                "shout",
+                "Shouts the text.",
+                serde_json::json!({ "type": "object" }),
+                |_| async { Ok("HI".to_string()) },
+            ))
+            // the message, the tool call and its result
+            .with_termination(Keyword::new("STOP").or(MaxMessages(3)))
+            .build();
+        let (inbox, mut replies) = crate::agent::channel(None);
+
+        assistant
+            .send(Message::new(inbox.clone(), "STOP".to_string()))
+            .await?;
+        assistant
+            .send(Message::new(
+                inbox,
+                r#"call shout {"text":"hi"}"#.to_string(),
+            ))
+            .await?;
+
+        // the first message isn't replied to, and the tool's result isn't
+        // sent back to the model
+        assert!(replies.recv().await.is_some());
+        let roles = assistant
+            .history()
+            .iter()
+            .map(|message| message.role)
+            .collect::<Vec<_>>();
+        assert_eq!(roles, [Role::User, Role::User, Role::Assistant, Role::Tool]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spill() -> Result<()> {
+        use crate::artifact::{MemoryStore, Spill};
+
+        let assistant = AssistantBuilder::new()
+            .with_client(Arc::new(Reverse))
+            .with_spill(Spill::new(MemoryStore::new()).with_threshold(20))
+            .build();
+        let (inbox, mut replies) = crate::agent::channel(None);
+
+        // the model sees a reference to the message, and the reply is sent
+        // in full
+        assistant.send(Message::new(inbox, "x".repeat(100))).await?;
+        let reply = replies.recv().await.map(|reply| reply.content.to_string());
+        let reply = reply.unwrap_or_default();
+        let history = assistant.history();
+        assert!(history[0]
+            .content
+            .starts_with("[100 characters stored as artifact "));
+        assert_eq!(reply.chars().count(), history[0].content.chars().count());
+        assert!(history[1].content.ends_with("..."));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_context_window() -> Result<()> {
+        use crate::context::{ContextWindow, SlidingWindow};
+
+        /// Replies with the number of messages it was sent.
+        #[derive(Debug)]
+        struct Count;
+
+        impl LlmClient for Count {
+            fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+                Box::pin(async move {
+                    Ok(Completion {
+                        content: request.messages.len().to_string(),
+                        ..Default::default()
+                    })
+                })
+            }
+        }
+
+        let assistant = AssistantBuilder::new()
+            .with_client(Arc::new(Count))
+            .with_context_window(ContextWindow::new(1000, SlidingWindow::new(3)))
+            .build();
+        let (inbox, mut replies) = crate::agent::channel(None);
+        let mut counts = Vec::new();
+        for content in ["one", "two", "three"] {
+            assistant.send(Message::new(inbox.clone(), content)).await?;
+            let reply = replies.recv().await.map(|reply| reply.content.to_string());
+            counts.push(reply.unwrap_or_default());
+        }
+        assert_eq!(counts, ["1", "3", "3"]);
+        assert_eq!(assistant.history().len(), 6);
+
+        let turns = assistant.turn_tokens();
+        assert_eq!(turns.len(), 3);
+        assert_eq!(turns[0].history_tokens, turns[0].prompt_tokens);
+        assert!(turns[2].history_tokens > turns[2].prompt_tokens);
+        assert_eq!(turns[2].reply_tokens, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_thoughts_kept_out_of_reply() -> Result<()> {
+        let assistant = AssistantBuilder::new()
+            .with_model(openai::mock::REASONING_MODEL)
+            .with_api_key("key")
+            .with_base_url(openai::mock::echo_server().await)
+            .build();
+        let (inbox, mut replies) = crate::agent::channel(None);
+        let (stream, mut thoughts) = crate::agent::channel(None);
+
+        assistant
+            .send(Message::new(inbox, "hello".to_string()).with_stream(stream))
+            .await?;
+
+        assert_eq!(
+            replies.recv().await.map(|reply| reply.content.to_string()),
+            Some("hello".to_string())
+        );
+        assert_eq!(
+            thoughts.recv().await,
+            Some(StreamEvent::Thought("thinking about hello".to_string()))
+        );
+        assert!(assistant
+            .history()
+            .iter()
+            .all(|message| !message.content.contains("thinking")));
         Ok(())
     }
 }
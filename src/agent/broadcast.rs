@@ -0,0 +1,162 @@
+//! Sending one message to many agents.
+
+use {
+    super::Sender,
+    std::sync::{Arc, Mutex},
+    uuid::Uuid,
+};
+
+/// Fans each message sent out to a set of senders, e.g. to announce
+/// something to every agent in a chat. Senders can be registered and
+/// unregistered at any time, and senders of agents that stopped are dropped
+/// as they're found. Clones share the same senders.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::agent::{AgentBuilder, Broadcast};
+/// # tokio_test::block_on(async {
+/// let everyone = Broadcast::new();
+/// for name in ["planner", "coder", "reviewer"] {
+///     let agent = AgentBuilder::new().with_name(name).spawn(
+///         move |_sender, announcement: String| async move {
+///             println!("{name} heard {announcement:?}");
+///             Ok::<_, std::io::Error>(())
+///         },
+///     );
+///     everyone.register(agent.sender());
+/// }
+/// let delivered = everyone.send("the task changed".to_string()).await;
+/// assert_eq!(delivered, 3);
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct Broadcast<M> {
+    senders: Arc<Mutex<Vec<Registered<M>>>>,
+}
+
+#[derive(Debug)]
+struct Registered<M> {
+    id: Uuid,
+    sender: Sender<M>,
+}
+
+// implemented by hand so that cloning a broadcast doesn't require `M: Clone`
+impl<M> Clone for Broadcast<M> {
+    fn clone(&self) -> Self {
+        Self {
+            senders: self.senders.clone(),
+        }
+    }
+}
+
+impl<M> Default for Broadcast<M> {
+    fn default() -> Self {
+        Self {
+            senders: Default::default(),
+        }
+    }
+}
+
+impl<M> Broadcast<M> {
+    /// Create a broadcast with no senders.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add `sender` to the senders messages are sent to. Returns its id, to
+    /// unregister it with.
+    pub fn register(&self, sender: Sender<M>) -> Uuid {
+        let id = Uuid::new_v4();
+        let mut senders = self.senders.lock().unwrap();
+        senders.retain(|registered| !registered.sender.is_closed());
+        senders.push(Registered { id, sender });
+        id
+    }
+
+    /// Remove a sender. Returns whether it was registered.
+    pub fn unregister(&self, id: Uuid) -> bool {
+        let mut senders = self.senders.lock().unwrap();
+        let before = senders.len();
+        senders.retain(|registered| registered.id != id);
+        senders.len() < before
+    }
+
+    /// Returns the number of senders whose agents are running.
+    pub fn len(&self) -> usize {
+        self.live().len()
+    }
+
+    /// Returns whether no senders' agents are running.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops the senders of agents that stopped, and returns the rest.
+    fn live(&self) -> Vec<Sender<M>> {
+        let mut senders = self.senders.lock().unwrap();
+        senders.retain(|registered| !registered.sender.is_closed());
+        senders
+            .iter()
+            .map(|registered| registered.sender.clone())
+            .collect()
+    }
+}
+
+impl<M: Clone> Broadcast<M> {
+    /// Send `message` to every sender. Returns the number it was delivered
+    /// to. Bounded, full mailboxes are waited on, like [`Sender::send`].
+    pub async fn send(&self, message: M) -> usize {
+        // don't hold the lock while waiting on mailboxes
+        let mut delivered = 0;
+        for sender in self.live() {
+            match sender.send(message.clone()).await {
+                Ok(()) => delivered += 1,
+                Err(e) => tracing::trace!(target = ?e.target, "broadcast receiver stopped"),
+            }
+        }
+        delivered
+    }
+
+    /// Send `message` to every sender without waiting. Returns the number
+    /// it was delivered to; full mailboxes are skipped, like
+    /// [`Sender::try_send`].
+    pub fn try_send(&self, message: M) -> usize {
+        self.live()
+            .iter()
+            .filter(|sender| sender.try_send(message.clone()).is_ok())
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::agent::channel, anyhow::Result};
+
+    #[tokio::test]
+    async fn test_broadcast() -> Result<()> {
+        let broadcast = Broadcast::new();
+        let (first, mut first_inbox) = channel(None);
+        let (full, mut full_inbox) = channel(Some(1));
+        let (stopped, stopped_inbox) = channel(None);
+        let (leaving, mut leaving_inbox) = channel(None);
+        broadcast.register(first);
+        broadcast.register(full.clone());
+        broadcast.register(stopped);
+        let leaving = broadcast.clone().register(leaving);
+
+        drop(stopped_inbox);
+        assert!(broadcast.unregister(leaving));
+        assert_eq!(broadcast.len(), 2);
+        assert_eq!(broadcast.send("hello").await, 2);
+        // the bounded mailbox is full now
+        assert_eq!(broadcast.try_send("again"), 1);
+
+        assert_eq!(first_inbox.recv().await, Some("hello"));
+        assert_eq!(first_inbox.recv().await, Some("again"));
+        assert_eq!(full_inbox.recv().await, Some("hello"));
+        assert_eq!(full_inbox.try_recv(), None);
+        assert_eq!(leaving_inbox.try_recv(), None);
+        drop(full);
+        Ok(())
+    }
+}
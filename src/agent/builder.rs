@@ -0,0 +1,493 @@
+//! A builder for agents with lifecycle hooks.
+
+use {
+    super::{
+        children::Children,
+        dead_letter::DeadLetters,
+        directory::AgentDirectory,
+        middleware::{Chain, Layered, Middleware},
+        priority::{self, Level, Priority},
+        state::FnHandler,
+        Agent, AgentFailure, AgentState, Clock, Sender, SystemClock,
+    },
+    std::{fmt, fmt::Debug, future::Future, pin::Pin, sync::Arc, time::Duration},
+    tokio_util::sync::CancellationToken,
+    uuid::Uuid,
+};
+
+/// A boxed future returned by lifecycle hooks.
+type HookFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A hook that runs when an agent starts or stops.
+type Hook<M> = Box<dyn Fn(AgentContext<M>) -> HookFuture + Send + Sync>;
+
+/// A hook that runs when an agent's handler returns an error.
+type ErrorHook<M, E> = Box<dyn Fn(AgentContext<M>, &E) -> HookFuture + Send + Sync>;
+
+/// A hook that runs when an agent's handler times out on a message.
+type TimeoutHook<M> = Box<dyn Fn(AgentContext<M>, Duration) -> HookFuture + Send + Sync>;
+
+/// A hook that runs when an agent's handler panics.
+type PanicHook<M> = Box<dyn Fn(AgentContext<M>, AgentFailure) -> HookFuture + Send + Sync>;
+
+/// What a lifecycle hook or a handler knows about its agent.
+#[derive(Debug)]
+pub struct AgentContext<M> {
+    /// Unique identifier for the agent.
+    pub id: Uuid,
+
+    /// A user-friendly name for the agent.
+    pub name: Option<String>,
+
+    /// A channel to send messages to the agent.
+    pub sender: Sender<M>,
+
+    /// The agent's clock. Handlers should use it for timing so tests can
+    /// control time.
+    pub clock: Arc<dyn Clock>,
+
+    /// Cancelled when the agent is. Handlers can pass it, or a child token,
+    /// on to work they start so it stops along with the agent.
+    pub cancellation: CancellationToken,
+
+    /// The agents spawned with [`AgentContext::spawn_child`].
+    pub(crate) children: Children,
+}
+
+// implemented by hand so that cloning a context doesn't require `M: Clone`
+impl<M> Clone for AgentContext<M> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            name: self.name.clone(),
+            sender: self.sender.clone(),
+            clock: self.clock.clone(),
+            cancellation: self.cancellation.clone(),
+            children: self.children.clone(),
+        }
+    }
+}
+
+impl<M> AgentContext<M> {
+    /// Spawn a child agent handling messages with `handler`. The child is
+    /// terminated when this agent stops: gracefully when it's terminated or
+    /// its handler fails, and at once when it's aborted or cancelled.
+    /// Children can spawn children of their own.
+    ///
+    /// Usage:
+    /// ```
+    /// # use autogen_rs::agent::{Agent, AgentContext, AgentState, Sender};
+    /// /// Hands each file to a worker of its own.
+    /// struct Indexer;
+    ///
+    /// impl AgentState<String> for Indexer {
+    ///     type Error = autogen_rs::agent::SendError<String>;
+    ///
+    ///     async fn handle(
+    ///         &mut self,
+    ///         context: &AgentContext<String>,
+    ///         file: String,
+    ///     ) -> Result<(), Self::Error> {
+    ///         let worker =
+    ///             context.spawn_child(None, |_sender: Sender<String>, file: String| async move {
+    ///                 println!("indexing {file}");
+    ///                 Ok::<_, std::io::Error>(())
+    ///             });
+    ///         worker.send(file).await
+    ///     }
+    /// }
+    ///
+    /// # tokio_test::block_on(async {
+    /// let indexer = Agent::spawn_with_state(uuid::Uuid::new_v4(), None, Indexer);
+    /// indexer.send("README.md".to_string()).await?;
+    /// // stops the workers too
+    /// indexer.terminate().await;
+    /// # anyhow::Ok(())
+    /// # });
+    /// ```
+    pub fn spawn_child<N, E, H, R>(&self, name: Option<String>, handler: H) -> Sender<N>
+    where
+        N: Debug + Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+        H: Fn(Sender<N>, N) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        self.spawn_child_with_state(name, FnHandler(handler))
+    }
+
+    /// Spawn a child agent whose messages are handled by `state`. See
+    /// [`AgentContext::spawn_child`].
+    pub fn spawn_child_with_state<N, S>(&self, name: Option<String>, state: S) -> Sender<N>
+    where
+        N: Debug + Send + 'static,
+        S: AgentState<N>,
+        S::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let child = Agent::spawn_with(
+            Uuid::new_v4(),
+            name,
+            None,
+            None,
+            Hooks {
+                cancellation: Some(self.cancellation.child_token()),
+                ..Default::default()
+            },
+            self.clock.clone(),
+            state,
+        );
+        tracing::trace!(parent = %self.id, child = %child.id, "spawned child");
+        let sender = child.sender();
+        self.children.adopt(child);
+        sender
+    }
+
+    /// Returns the number of children still running.
+    pub fn children(&self) -> usize {
+        self.children.len()
+    }
+}
+
+/// Hooks that run inside an agent's event loop.
+pub(crate) struct Hooks<M, E> {
+    /// Runs before the agent processes its first message.
+    pub(crate) on_start: Option<Hook<M>>,
+
+    /// Runs after the agent's event loop finishes, whether or not it failed.
+    pub(crate) on_stop: Option<Hook<M>>,
+
+    /// Runs when the handler returns an error, before the agent stops.
+    pub(crate) on_error: Option<ErrorHook<M, E>>,
+
+    /// Runs when the handler times out on a message, before the next one.
+    pub(crate) on_timeout: Option<TimeoutHook<M>>,
+
+    /// Runs when the handler panics.
+    pub(crate) on_panic: Option<PanicHook<M>>,
+
+    /// Whether the agent carries on with the next message after its handler
+    /// panics.
+    pub(crate) isolate_panics: bool,
+
+    /// How long the handler may take on each message before it's cancelled.
+    pub(crate) timeout: Option<Duration>,
+
+    /// Records the messages the agent doesn't handle.
+    pub(crate) dead_letters: Option<DeadLetters>,
+
+    /// Stops the agent when cancelled.
+    pub(crate) cancellation: Option<CancellationToken>,
+
+    /// Whether messages carry the span they're sent from to the handler.
+    pub(crate) propagate_traces: bool,
+}
+
+impl<M, E> Default for Hooks<M, E> {
+    fn default() -> Self {
+        Self {
+            on_start: None,
+            on_stop: None,
+            on_error: None,
+            on_timeout: None,
+            on_panic: None,
+            isolate_panics: false,
+            timeout: None,
+            dead_letters: None,
+            cancellation: None,
+            propagate_traces: false,
+        }
+    }
+}
+
+impl<M, E> Debug for Hooks<M, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_start", &self.on_start.is_some())
+            .field("on_stop", &self.on_stop.is_some())
+            .field("on_error", &self.on_error.is_some())
+            .field("on_timeout", &self.on_timeout.is_some())
+            .field("timeout", &self.timeout)
+            .field("dead_letters", &self.dead_letters.is_some())
+            .finish()
+    }
+}
+
+/// Builds an agent with an optional bounded mailbox and lifecycle hooks.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::agent::AgentBuilder;
+/// # tokio_test::block_on(async {
+/// let agent = AgentBuilder::new()
+///     .with_name("logger")
+///     .on_start(|context| async move { tracing::info!(name = context.name, "opening log") })
+///     .on_stop(|context| async move { tracing::info!(name = context.name, "flushing log") })
+///     .spawn(|_sender, line: String| async move {
+///         println!("{line}");
+///         Ok::<_, std::io::Error>(())
+///     });
+/// agent.send("hello".to_string()).await?;
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct AgentBuilder<M, E> {
+    /// Unique identifier for the agent.
+    pub id: Option<Uuid>,
+
+    /// A user-friendly name for the agent.
+    pub name: Option<String>,
+
+    /// The capacity of the agent's mailbox. Unbounded if not set.
+    pub capacity: Option<usize>,
+
+    /// Returns the level of each message, if the mailbox delivers them by
+    /// priority.
+    priority: Option<fn(&M) -> Level>,
+
+    /// How long a message waits in a priority mailbox before it's treated
+    /// as one level more urgent.
+    aging: Duration,
+
+    /// Hooks that run inside the agent's event loop.
+    hooks: Hooks<M, E>,
+
+    /// The clock the agent's grace period and timeouts are measured on.
+    clock: Arc<dyn Clock>,
+
+    /// The directory the agent registers with when it's spawned.
+    directory: Option<AgentDirectory<M>>,
+
+    /// Wraps the agent's handling of each message.
+    middleware: Chain<M, E>,
+}
+
+impl<M, E> Default for AgentBuilder<M, E> {
+    fn default() -> Self {
+        Self {
+            id: None,
+            name: None,
+            capacity: None,
+            priority: None,
+            aging: priority::DEFAULT_AGING,
+            hooks: Hooks::default(),
+            clock: Arc::new(SystemClock),
+            directory: None,
+            middleware: Chain::default(),
+        }
+    }
+}
+
+impl<M, E> AgentBuilder<M, E>
+where
+    M: Debug + Send + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    /// Create a new agent builder.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the id of the agent.
+    pub fn with_id(mut self, id: Uuid) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Set the name of the agent.
+    pub fn with_name(mut self, name: impl ToString) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Bound the agent's mailbox to `capacity` messages.
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "a bounded mailbox needs room for a message");
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Deliver the most urgent message waiting first, by
+    /// [`Priority::priority`] or the level it's sent with, instead of the
+    /// oldest. Control messages go ahead of all others, and messages age as
+    /// they wait so none starve.
+    pub fn with_priority_mailbox(mut self) -> Self
+    where
+        M: Priority,
+    {
+        self.priority = Some(M::priority);
+        self
+    }
+
+    /// Treat a message that waited `aging` in a priority mailbox as one level
+    /// more urgent, up to [`Level::High`]. Defaults to
+    /// [`priority::DEFAULT_AGING`].
+    pub fn with_priority_aging(mut self, aging: Duration) -> Self {
+        self.aging = aging;
+        self
+    }
+
+    /// Measure the agent's grace period and timeouts on `clock` instead of
+    /// the system clock, e.g. a [`ManualClock`](super::ManualClock) in tests.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Register the agent with `directory` when it's spawned. It's removed
+    /// once it stops.
+    pub fn with_directory(mut self, directory: AgentDirectory<M>) -> Self {
+        self.directory = Some(directory);
+        self
+    }
+
+    /// Record the messages the agent doesn't handle in `dead_letters`:
+    /// those sent once its mailbox is closed, those left in its mailbox when
+    /// its handler fails, and those its handler times out on.
+    pub fn with_dead_letters(mut self, dead_letters: DeadLetters) -> Self {
+        self.hooks.dead_letters = Some(dead_letters);
+        self
+    }
+
+    /// Cancel the handler when it takes longer than `timeout` on a message,
+    /// e.g. on a stuck call to a model, and carry on with the next message
+    /// instead of wedging the agent. Measured on the agent's clock.
+    pub fn with_message_timeout(mut self, timeout: Duration) -> Self {
+        self.hooks.timeout = Some(timeout);
+        self
+    }
+
+    /// Stop the agent as soon as `cancellation` is cancelled, e.g. when the
+    /// user hits Ctrl-C, along with the children it spawned. The message
+    /// being handled is dropped mid-way, interrupting the calls to models it
+    /// was waiting on, and the messages left in the mailbox are recorded as
+    /// dead letters. Child tokens cancel a whole tree of agents from one
+    /// token, e.g. [`Resources::token`](crate::cleanup::Resources::token).
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.hooks.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Run `hook` before the agent processes its first message.
+    pub fn on_start<F, R>(mut self, hook: F) -> Self
+    where
+        F: Fn(AgentContext<M>) -> R + Send + Sync + 'static,
+        R: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_start = Some(Box::new(move |context| Box::pin(hook(context))));
+        self
+    }
+
+    /// Run `hook` after the agent's event loop finishes.
+    pub fn on_stop<F, R>(mut self, hook: F) -> Self
+    where
+        F: Fn(AgentContext<M>) -> R + Send + Sync + 'static,
+        R: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_stop = Some(Box::new(move |context| Box::pin(hook(context))));
+        self
+    }
+
+    /// Run `hook` when the handler returns an error, before the agent stops.
+    pub fn on_error<F, R>(mut self, hook: F) -> Self
+    where
+        F: Fn(AgentContext<M>, &E) -> R + Send + Sync + 'static,
+        R: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_error = Some(Box::new(move |context, error| {
+            Box::pin(hook(context, error))
+        }));
+        self
+    }
+
+    /// Run `hook` with the timeout when the handler times out on a message,
+    /// before the agent carries on with the next one.
+    pub fn on_timeout<F, R>(mut self, hook: F) -> Self
+    where
+        F: Fn(AgentContext<M>, Duration) -> R + Send + Sync + 'static,
+        R: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_timeout = Some(Box::new(move |context, timeout| {
+            Box::pin(hook(context, timeout))
+        }));
+        self
+    }
+
+    /// Run `hook` with what the handler panicked with when it panics, e.g.
+    /// to report the failure to a supervisor.
+    pub fn on_panic<F, R>(mut self, hook: F) -> Self
+    where
+        F: Fn(AgentContext<M>, AgentFailure) -> R + Send + Sync + 'static,
+        R: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_panic = Some(Box::new(move |context, failure| {
+            Box::pin(hook(context, failure))
+        }));
+        self
+    }
+
+    /// Carry on with the next message when the handler panics, instead of
+    /// stopping the agent. The message the handler panicked on is recorded
+    /// as a dead letter. The handler's state may be left half-updated by
+    /// the panic.
+    pub fn with_panic_isolation(mut self) -> Self {
+        self.hooks.isolate_panics = true;
+        self
+    }
+
+    /// Carry the span each message is sent from to the handler, which
+    /// handles it in a `handle` span that follows from it. A conversation
+    /// between agents then reads as linked spans, e.g. once exported with
+    /// the `otel` feature's layer, instead of disconnected ones.
+    pub fn with_trace_propagation(mut self) -> Self {
+        self.hooks.propagate_traces = true;
+        self
+    }
+
+    /// Wrap the handling of each message in `middleware`. Middleware runs
+    /// in the order it's added, the first outermost, so it sees each message
+    /// before the middleware added after it and the outcome after.
+    pub fn with_middleware(mut self, middleware: impl Middleware<M, E>) -> Self {
+        self.middleware.0.push(Arc::new(middleware));
+        self
+    }
+
+    /// Spawns the agent.
+    pub fn spawn<H, R>(self, handler: H) -> Agent<M, E>
+    where
+        H: Fn(Sender<M>, M) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        self.spawn_with_state(FnHandler(handler))
+    }
+
+    /// Spawns the agent with its messages handled by `state`.
+    pub fn spawn_with_state<S>(self, state: S) -> Agent<M, E>
+    where
+        S: AgentState<M, Error = E>,
+    {
+        let agent = Agent::spawn_with(
+            self.id.unwrap_or_else(Uuid::new_v4),
+            self.name,
+            self.capacity,
+            self.priority.map(|priority_of| priority::Ordering {
+                priority_of,
+                aging: self.aging,
+            }),
+            self.hooks,
+            self.clock,
+            Layered {
+                chain: self.middleware,
+                state,
+            },
+        );
+        if let Some(directory) = self.directory {
+            // the agent still runs; it just can't be looked up
+            if let Err(e) = directory.register(agent.id, agent.name.clone(), agent.sender()) {
+                tracing::warn!(id = %agent.id, name = agent.name, error = %e, "unable to register agent");
+            }
+        }
+        agent
+    }
+}
@@ -0,0 +1,244 @@
+//! Publish/subscribe messaging between agents. Agents subscribe to topics on
+//! an [`EventBus`], and a message published to a topic goes to every
+//! subscriber, so publishers don't need to know who's interested.
+//!
+//! Subscriptions of agents that stopped are dropped the next time their
+//! topic is used.
+
+use {
+    super::Sender,
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    },
+    uuid::Uuid,
+};
+
+/// Topics and their subscribers. Clones share the same bus.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::agent::{bus::EventBus, AgentBuilder};
+/// # tokio_test::block_on(async {
+/// let bus = EventBus::new();
+/// for name in ["auditor", "notifier"] {
+///     let agent =
+///         AgentBuilder::new()
+///             .with_name(name)
+///             .spawn(move |_sender, event: String| async move {
+///                 println!("{name} saw {event}");
+///                 Ok::<_, std::io::Error>(())
+///             });
+///     bus.subscribe("deploys", agent.sender());
+/// }
+/// let delivered = bus.publish("deploys", "v1.2 is live".to_string()).await;
+/// assert_eq!(delivered, 2);
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct EventBus<M> {
+    topics: Arc<Mutex<HashMap<String, Vec<Subscriber<M>>>>>,
+}
+
+#[derive(Debug)]
+struct Subscriber<M> {
+    id: Uuid,
+    sender: Sender<M>,
+}
+
+// implemented by hand so that cloning a bus doesn't require `M: Clone`
+impl<M> Clone for EventBus<M> {
+    fn clone(&self) -> Self {
+        Self {
+            topics: self.topics.clone(),
+        }
+    }
+}
+
+impl<M> Default for EventBus<M> {
+    fn default() -> Self {
+        Self {
+            topics: Default::default(),
+        }
+    }
+}
+
+impl<M> EventBus<M> {
+    /// Create a bus with no topics.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns a handle to the topic named `name`.
+    pub fn topic(&self, name: impl ToString) -> Topic<M> {
+        Topic {
+            bus: self.clone(),
+            name: name.to_string(),
+        }
+    }
+
+    /// Subscribe `sender` to `topic`. Returns the subscription's id, to
+    /// unsubscribe with.
+    pub fn subscribe(&self, topic: impl ToString, sender: Sender<M>) -> Uuid {
+        let id = Uuid::new_v4();
+        let mut topics = self.topics.lock().unwrap();
+        let subscribers = topics.entry(topic.to_string()).or_default();
+        subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+        subscribers.push(Subscriber { id, sender });
+        id
+    }
+
+    /// Remove a subscription. Returns whether it was subscribed.
+    pub fn unsubscribe(&self, topic: &str, id: Uuid) -> bool {
+        let mut topics = self.topics.lock().unwrap();
+        let Some(subscribers) = topics.get_mut(topic) else {
+            return false;
+        };
+        let before = subscribers.len();
+        subscribers.retain(|subscriber| subscriber.id != id);
+        let removed = subscribers.len() < before;
+        if subscribers.is_empty() {
+            topics.remove(topic);
+        }
+        removed
+    }
+
+    /// Returns the number of running subscribers to `topic`.
+    pub fn subscribers(&self, topic: &str) -> usize {
+        let mut topics = self.topics.lock().unwrap();
+        prune(&mut topics, topic);
+        topics.get(topic).map_or(0, Vec::len)
+    }
+
+    /// Returns the topics with running subscribers.
+    pub fn topics(&self) -> Vec<String> {
+        let mut topics = self.topics.lock().unwrap();
+        topics.retain(|_, subscribers| {
+            subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+            !subscribers.is_empty()
+        });
+        topics.keys().cloned().collect()
+    }
+}
+
+impl<M: Clone> EventBus<M> {
+    /// Send `message` to every subscriber to `topic`. Returns the number of
+    /// subscribers it was delivered to. Subscribers with bounded, full
+    /// mailboxes are waited on, like [`Sender::send`].
+    pub async fn publish(&self, topic: &str, message: M) -> usize {
+        // don't hold the lock while waiting on mailboxes
+        let subscribers = {
+            let mut topics = self.topics.lock().unwrap();
+            prune(&mut topics, topic);
+            match topics.get(topic) {
+                Some(subscribers) => subscribers
+                    .iter()
+                    .map(|subscriber| subscriber.sender.clone())
+                    .collect::<Vec<_>>(),
+                None => return 0,
+            }
+        };
+
+        let mut delivered = 0;
+        for sender in subscribers {
+            match sender.send(message.clone()).await {
+                Ok(()) => delivered += 1,
+                Err(e) => tracing::trace!(topic, target = ?e.target, "subscriber stopped"),
+            }
+        }
+        tracing::trace!(topic, delivered, "published message");
+        delivered
+    }
+}
+
+/// Remove the stopped subscribers to `topic`, and the topic if none are left.
+fn prune<M>(topics: &mut HashMap<String, Vec<Subscriber<M>>>, topic: &str) {
+    if let Some(subscribers) = topics.get_mut(topic) {
+        subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+        if subscribers.is_empty() {
+            topics.remove(topic);
+        }
+    }
+}
+
+/// A topic on an [`EventBus`], for publishing to or subscribing to one topic
+/// without repeating its name.
+#[derive(Debug)]
+pub struct Topic<M> {
+    bus: EventBus<M>,
+    name: String,
+}
+
+// implemented by hand so that cloning a topic doesn't require `M: Clone`
+impl<M> Clone for Topic<M> {
+    fn clone(&self) -> Self {
+        Self {
+            bus: self.bus.clone(),
+            name: self.name.clone(),
+        }
+    }
+}
+
+impl<M> Topic<M> {
+    /// Returns the topic's name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Subscribe `sender` to the topic. Returns the subscription's id.
+    pub fn subscribe(&self, sender: Sender<M>) -> Uuid {
+        self.bus.subscribe(&self.name, sender)
+    }
+
+    /// Remove a subscription. Returns whether it was subscribed.
+    pub fn unsubscribe(&self, id: Uuid) -> bool {
+        self.bus.unsubscribe(&self.name, id)
+    }
+
+    /// Returns the number of running subscribers.
+    pub fn subscribers(&self) -> usize {
+        self.bus.subscribers(&self.name)
+    }
+}
+
+impl<M: Clone> Topic<M> {
+    /// Send `message` to every subscriber. Returns the number of subscribers
+    /// it was delivered to.
+    pub async fn publish(&self, message: M) -> usize {
+        self.bus.publish(&self.name, message).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::agent::channel, anyhow::Result};
+
+    #[tokio::test]
+    async fn test_publish_fans_out() -> Result<()> {
+        let bus = EventBus::new();
+        let alerts = bus.topic("alerts");
+        let (first, mut first_inbox) = channel(None);
+        let (second, mut second_inbox) = channel(Some(1));
+        let (stopped, stopped_inbox) = channel(None);
+        let (leaving, mut leaving_inbox) = channel(None);
+        alerts.subscribe(first);
+        alerts.subscribe(second);
+        alerts.subscribe(stopped);
+        let leaving = alerts.subscribe(leaving);
+        bus.subscribe("other", channel(None).0);
+
+        drop(stopped_inbox);
+        assert!(alerts.unsubscribe(leaving));
+        assert_eq!(alerts.publish("fire").await, 2);
+        assert_eq!(first_inbox.recv().await, Some("fire"));
+        assert_eq!(second_inbox.recv().await, Some("fire"));
+        assert_eq!(leaving_inbox.try_recv(), None);
+        assert_eq!(alerts.subscribers(), 2);
+
+        // the other topic's only subscriber is gone
+        assert_eq!(bus.topics(), ["alerts"]);
+        assert_eq!(bus.publish("nobody", "hello").await, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+//! The child agents an agent spawned.
+
+use {
+    super::{Agent, Shutdown},
+    std::{
+        fmt::Debug,
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+    },
+};
+
+/// A child agent, whatever its message and error types.
+trait Child: Debug + Send {
+    fn is_finished(&self) -> bool;
+
+    fn terminate(self: Box<Self>) -> Pin<Box<dyn Future<Output = Shutdown> + Send>>;
+
+    fn abort(self: Box<Self>);
+}
+
+impl<M, E> Child for Agent<M, E>
+where
+    M: Debug + Send + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    fn is_finished(&self) -> bool {
+        Agent::is_finished(self)
+    }
+
+    fn terminate(self: Box<Self>) -> Pin<Box<dyn Future<Output = Shutdown> + Send>> {
+        Box::pin(Agent::terminate(*self))
+    }
+
+    fn abort(self: Box<Self>) {
+        Agent::abort(*self)
+    }
+}
+
+/// The children of an agent, shared by its handle and its event loop.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Children(Arc<Mutex<Vec<Box<dyn Child>>>>);
+
+impl Children {
+    /// Track `child`, dropping children that already stopped.
+    pub(crate) fn adopt<M, E>(&self, child: Agent<M, E>)
+    where
+        M: Debug + Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let mut children = self.0.lock().unwrap();
+        children.retain(|child| !child.is_finished());
+        children.push(Box::new(child));
+    }
+
+    /// Returns the number of children still running.
+    pub(crate) fn len(&self) -> usize {
+        let mut children = self.0.lock().unwrap();
+        children.retain(|child| !child.is_finished());
+        children.len()
+    }
+
+    /// Terminate every child at once, each gracefully and then by force.
+    /// Reports a forced shutdown if any child had to be aborted.
+    pub(crate) async fn terminate(&self) -> Shutdown {
+        let children = std::mem::take(&mut *self.0.lock().unwrap());
+        let terminating = children
+            .into_iter()
+            .map(|child| tokio::spawn(child.terminate()))
+            .collect::<Vec<_>>();
+        let mut shutdown = Shutdown::Graceful;
+        for terminated in terminating {
+            if !matches!(terminated.await, Ok(Shutdown::Graceful)) {
+                shutdown = Shutdown::Forced;
+            }
+        }
+        shutdown
+    }
+
+    /// Abort every child immediately.
+    pub(crate) fn abort(&self) {
+        let children = std::mem::take(&mut *self.0.lock().unwrap());
+        for child in children {
+            child.abort();
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Time as seen by agents. Grace periods, timeouts and restart backoff all go
+//! through a [`Clock`], so tests can swap the [`SystemClock`] for a
+//! [`ManualClock`] and control time directly.
+
+use {
+    std::{
+        fmt::Debug,
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
+    tokio::sync::oneshot,
+};
+
+/// A future that completes once a [`Clock`]'s sleep is over.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// A source of time.
+pub trait Clock: Debug + Send + Sync + 'static {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Returns a future that completes once `duration` has passed.
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+/// The real clock, backed by tokio's timer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A clock that only moves when it's told to. Sleeps complete when
+/// [`ManualClock::advance`] moves the clock past their deadline.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::agent::{Clock, ManualClock}, std::time::Duration};
+/// # tokio_test::block_on(async {
+/// let clock = ManualClock::new();
+/// let sleep = tokio::spawn(clock.sleep(Duration::from_secs(60)));
+/// clock.advance(Duration::from_secs(60));
+/// sleep.await?;
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<ManualState>>);
+
+#[derive(Debug)]
+struct ManualState {
+    /// The time the clock was created at.
+    start: Instant,
+
+    /// How far the clock has been advanced.
+    elapsed: Duration,
+
+    /// Pending sleeps and when they end, relative to `start`.
+    sleepers: Vec<(Duration, oneshot::Sender<()>)>,
+}
+
+impl ManualClock {
+    /// Create a clock that starts at the current time.
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(ManualState {
+            start: Instant::now(),
+            elapsed: Duration::ZERO,
+            sleepers: Vec::new(),
+        })))
+    }
+
+    /// Move the clock forward, completing the sleeps that end by then.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.0.lock().unwrap();
+        state.elapsed += duration;
+        let elapsed = state.elapsed;
+        for (_, sleeper) in extract(&mut state.sleepers, |(deadline, _)| *deadline <= elapsed) {
+            let _ = sleeper.send(());
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        let state = self.0.lock().unwrap();
+        state.start + state.elapsed
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        if duration.is_zero() {
+            return Box::pin(std::future::ready(()));
+        }
+        let (sleeper, woken) = oneshot::channel();
+        let mut state = self.0.lock().unwrap();
+        let deadline = state.elapsed + duration;
+        state.sleepers.push((deadline, sleeper));
+        Box::pin(async move {
+            // a dropped clock will never advance, so its sleeps never end
+            if woken.await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        })
+    }
+}
+
+/// Removes and returns the items matching `predicate`.
+fn extract<T>(items: &mut Vec<T>, mut predicate: impl FnMut(&T) -> bool) -> Vec<T> {
+    let (matching, rest) = std::mem::take(items)
+        .into_iter()
+        .partition(|item| predicate(item));
+    *items = rest;
+    matching
+}
+
+/// Runs `future`, giving up once `duration` has passed on `clock`. The time
+/// starts when this is called, not when the returned future is first polled.
+/// Returns `None` if it timed out.
+pub(crate) fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> impl Future<Output = Option<F::Output>> {
+    let sleep = clock.sleep(duration);
+    async move {
+        tokio::select! {
+            biased;
+            output = future => Some(output),
+            () = sleep => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, anyhow::Result};
+
+    #[tokio::test]
+    async fn test_manual_clock() -> Result<()> {
+        let clock = ManualClock::new();
+        let start = clock.now();
+
+        let short = tokio::spawn(clock.sleep(Duration::from_secs(1)));
+        let long = tokio::spawn(clock.sleep(Duration::from_secs(10)));
+        clock.advance(Duration::from_secs(5));
+        short.await?;
+        assert!(!long.is_finished());
+        assert_eq!(clock.now() - start, Duration::from_secs(5));
+
+        clock.advance(Duration::from_secs(5));
+        long.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let clock = ManualClock::new();
+        assert_eq!(
+            timeout(&clock, Duration::from_secs(1), async { 42 }).await,
+            Some(42)
+        );
+
+        let pending = std::future::pending::<()>();
+        let timed_out = timeout(&clock, Duration::from_secs(1), pending);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(timed_out.await, None);
+    }
+}
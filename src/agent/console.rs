@@ -0,0 +1,138 @@
+//! Renders a user agent's side of the conversation on the terminal. While the
+//! user waits for a reply a spinner is shown; streamed tokens are previewed as
+//! they arrive and replaced by the complete message once it's delivered.
+
+use {
+    super::ToolProgress,
+    std::{
+        io::{IsTerminal, Write},
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+};
+
+/// The frames of the spinner shown while waiting for a reply.
+const SPINNER_FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+
+/// How often the spinner advances.
+const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The terminal a user agent renders to.
+#[derive(Debug, Clone)]
+pub(crate) struct Console {
+    /// State shared between the agent's handler and its renderer task.
+    state: Arc<Mutex<State>>,
+
+    /// Whether to draw spinners and previews. Disabled when stdout isn't a
+    /// terminal, since they can't be erased there.
+    interactive: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Whether the user is waiting for a reply.
+    waiting: bool,
+
+    /// The tokens previewed so far.
+    preview: String,
+
+    /// The latest progress report, shown next to the spinner.
+    status: Option<String>,
+
+    /// The spinner frame last drawn.
+    frame: usize,
+}
+
+impl Console {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Default::default(),
+            interactive: std::io::stdout().is_terminal(),
+        }
+    }
+
+    /// Show a spinner until the next token or message arrives.
+    pub(crate) fn wait(&self) {
+        self.state.lock().unwrap().waiting = true;
+    }
+
+    /// Preview a streamed token.
+    pub(crate) fn token(&self, token: &str) {
+        if !self.interactive {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let mut stdout = std::io::stdout().lock();
+        if state.preview.is_empty() && state.waiting {
+            // erase the spinner
+            let _ = write!(stdout, "\r \r");
+        }
+        let _ = write!(stdout, "{token}");
+        let _ = stdout.flush();
+        state.preview.push_str(token);
+    }
+
+    /// Show a progress report next to the spinner.
+    pub(crate) fn progress(&self, progress: &ToolProgress) {
+        let status = match progress.percent {
+            Some(percent) => format!("{} {percent}% {}", progress.tool, progress.status),
+            None => format!("{} {}", progress.tool, progress.status),
+        };
+        self.state.lock().unwrap().status = Some(status);
+    }
+
+    /// Print a complete message, replacing any preview of it.
+    pub(crate) fn message(&self, line: &str) {
+        let mut state = self.state.lock().unwrap();
+        let mut stdout = std::io::stdout().lock();
+        if self.interactive && (state.waiting || !state.preview.is_empty()) {
+            let _ = write!(stdout, "{}", erase(&state.preview));
+        }
+        state.waiting = false;
+        state.preview.clear();
+        state.status = None;
+        let _ = writeln!(stdout, "{line}");
+    }
+
+    /// Draw the spinner while waiting for the first token. Runs until the
+    /// task is aborted.
+    pub(crate) async fn spin(self) {
+        if !self.interactive {
+            return;
+        }
+        let mut interval = tokio::time::interval(SPINNER_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut state = self.state.lock().unwrap();
+            if state.waiting && state.preview.is_empty() {
+                state.frame = (state.frame + 1) % SPINNER_FRAMES.len();
+                let mut stdout = std::io::stdout().lock();
+                let _ = write!(stdout, "\r\x1b[2K{}", SPINNER_FRAMES[state.frame]);
+                if let Some(status) = &state.status {
+                    let _ = write!(stdout, " {status}");
+                }
+                let _ = stdout.flush();
+            }
+        }
+    }
+}
+
+/// Returns the escape sequence that erases `preview` (or a spinner, if the
+/// preview is empty) and leaves the cursor at the start of its first line.
+fn erase(preview: &str) -> String {
+    let mut sequence = "\x1b[1A".repeat(preview.matches('\n').count());
+    sequence.insert(0, '\r');
+    sequence.push_str("\r\x1b[J");
+    sequence
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_erase() {
+        assert_eq!(erase(""), "\r\r\x1b[J");
+        assert_eq!(erase("one\ntwo\nthree"), "\r\x1b[1A\x1b[1A\r\x1b[J");
+    }
+}
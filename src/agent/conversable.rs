@@ -0,0 +1,1028 @@
+//! An agent composed from reply functions, like AutoGen's
+//! `ConversableAgent`. Each message is offered to the agent's
+//! [reply functions](ReplyFn) in the order they were registered; the first
+//! whose [`Trigger`] matches and that produces a reply answers the message,
+//! and the others pass. The agent keeps a separate history with each peer,
+//! so a reply function sees only the conversation it's replying in.
+//!
+//! AutoGen's default order is built from the reply functions here: check
+//! for termination with a [`TerminationReply`], run the tools asked for
+//! with a [`ToolReply`], ask a model with an [`LlmReply`] and finally ask a
+//! person with a [`HumanReply`].
+//!
+//! [`ConversableAgentBuilder`] also configures the common hybrids directly,
+//! so an agent can be an assistant, a user proxy or both: a model with a
+//! system message, tools to run, a person to ask in a
+//! [`HumanInputMode`], a termination condition and a limit on consecutive
+//! auto-replies.
+
+use {
+    super::{message::Content, registry::Instance, Actor, Message, Sender, Shutdown},
+    crate::{
+        chat::{termination::TerminationCondition, ChatMessage},
+        llm::{self, CompletionRequest, HistoryMessage, LlmClient, Role},
+        tools::ToolRegistry,
+        Agent,
+    },
+    std::{
+        collections::HashMap,
+        fmt,
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+    },
+    uuid::Uuid,
+};
+
+/// Errors that can occur when a conversable agent replies to a message.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("unable to send message: {0:?}")]
+    SendError(#[from] crate::agent::SendError<Box<Message>>),
+
+    #[error("unable to generate reply: {0}")]
+    LlmError(#[from] llm::Error),
+
+    #[error("reply function failed: {0}")]
+    ReplyFailed(String),
+}
+
+/// What a reply function made of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// Offer the message to the next reply function.
+    Pass,
+
+    /// Answer the message with this reply.
+    Final(String),
+
+    /// Don't answer the message, nor offer it to the next reply functions,
+    /// e.g. because the conversation is over.
+    End,
+}
+
+impl From<Option<String>> for Reply {
+    fn from(reply: Option<String>) -> Self {
+        reply.map_or(Self::Pass, Self::Final)
+    }
+}
+
+/// A boxed future returned by [`ReplyFn::reply`], resolving to what the
+/// reply function made of the message.
+pub type ReplyFuture<'a> = Pin<Box<dyn Future<Output = Result<Reply, Error>> + Send + 'a>>;
+
+/// What a reply function sees of the message it's offered.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplyContext<'a> {
+    /// The name of the agent replying.
+    pub name: &'a str,
+
+    /// The message to reply to.
+    pub message: &'a Message,
+
+    /// The conversation with the message's sender so far, the message
+    /// included.
+    pub history: &'a [HistoryMessage],
+}
+
+/// A function that may reply to a message.
+///
+/// Closures taking a [`ReplyContext`] and returning a future of a [`Reply`],
+/// or of an optional reply where `None` passes, are reply functions. The
+/// future can't borrow the context, so closures take what they need from it
+/// before they return. Closures need the type of their argument spelled
+/// out, as in `|context: ReplyContext<'_>| ..`.
+pub trait ReplyFn: Send + Sync + 'static {
+    /// Returns what to make of the message in `context`.
+    fn reply<'a>(&'a self, context: ReplyContext<'a>) -> ReplyFuture<'a>;
+}
+
+impl<F, R, T> ReplyFn for F
+where
+    F: Fn(ReplyContext<'_>) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<T, Error>> + Send + 'static,
+    T: Into<Reply>,
+{
+    fn reply<'a>(&'a self, context: ReplyContext<'a>) -> ReplyFuture<'a> {
+        let reply = self(context);
+        Box::pin(async move { reply.await.map(Into::into) })
+    }
+}
+
+/// Which messages a reply function is offered.
+#[derive(Clone, Default)]
+pub enum Trigger {
+    /// Every message.
+    #[default]
+    Any,
+
+    /// Messages from the agent with this name.
+    Name(String),
+
+    /// Messages written in this role.
+    Role(Role),
+
+    /// Messages the function returns `true` for.
+    When(Arc<dyn Fn(&Message) -> bool + Send + Sync>),
+}
+
+impl Trigger {
+    /// Offer messages `matches` returns `true` for.
+    pub fn when(matches: impl Fn(&Message) -> bool + Send + Sync + 'static) -> Self {
+        Self::When(Arc::new(matches))
+    }
+
+    /// Returns whether `message` sets the trigger off.
+    pub fn matches(&self, message: &Message) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Name(name) => message.name.as_ref() == Some(name),
+            Self::Role(role) => message.role == *role,
+            Self::When(matches) => matches(message),
+        }
+    }
+}
+
+impl fmt::Debug for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => f.write_str("Any"),
+            Self::Name(name) => f.debug_tuple("Name").field(name).finish(),
+            Self::Role(role) => f.debug_tuple("Role").field(role).finish(),
+            Self::When(_) => f.write_str("When(..)"),
+        }
+    }
+}
+
+/// A registered reply function.
+#[derive(Clone)]
+struct Registered {
+    trigger: Trigger,
+    reply: Arc<dyn ReplyFn>,
+}
+
+impl fmt::Debug for Registered {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Registered")
+            .field("trigger", &self.trigger)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The state a conversable agent keeps across messages.
+#[derive(Debug, Default)]
+struct State {
+    /// The reply functions, in the order they're offered messages.
+    replies: Vec<Registered>,
+
+    /// The conversation with each peer, by the peer's id. Messages from
+    /// senders that aren't agents are kept under the nil id.
+    histories: HashMap<Uuid, Vec<HistoryMessage>>,
+}
+
+/// An agent that replies with the first of its reply functions to reply.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::agent::{conversable::{ConversableAgentBuilder, ReplyContext, Trigger}, StreamEvent};
+/// # tokio_test::block_on(async {
+/// let agent = ConversableAgentBuilder::new()
+///     .with_name("greeter")
+///     .with_reply(
+///         Trigger::when(|message| message.content.to_string() == "hi"),
+///         |_: ReplyContext<'_>| async { Ok(Some("hello!".to_string())) },
+///     )
+///     .build();
+///
+/// let mut reply = agent.sender().ask_stream("hi").await?;
+/// assert_eq!(
+///     reply.next().await,
+///     Some(StreamEvent::Complete("hello!".to_string()))
+/// );
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct ConversableAgent {
+    pub agent: Agent<Box<Message>, Error>,
+
+    /// State shared with the agent's event loop.
+    state: Arc<Mutex<State>>,
+}
+
+impl ConversableAgent {
+    /// Create a new conversable agent with no reply functions. Until some
+    /// are [registered](Self::register_reply), it doesn't reply.
+    pub fn spawn(id: Uuid, name: Option<String>) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let own_name = name.clone().unwrap_or_else(|| "agent".to_string());
+
+        let agent = Agent::<Box<Message>, _>::spawn(id, name, {
+            let state = state.clone();
+            move |sender, message| {
+                let state = state.clone();
+                let own_name = own_name.clone();
+                async move {
+                    let peer = peer(&message);
+                    let (replies, history) = {
+                        let mut state = state.lock().unwrap();
+                        let received = match message.role {
+                            // other agents' replies are this agent's input
+                            Role::Assistant => HistoryMessage::new(Role::User, &message.content),
+                            _ => message.to_history(),
+                        };
+                        let history = state.histories.entry(peer).or_default();
+                        history.push(received);
+                        let history = history.clone();
+                        (state.replies.clone(), history)
+                    };
+
+                    let context = ReplyContext {
+                        name: &own_name,
+                        message: &message,
+                        history: &history,
+                    };
+                    let mut reply = Reply::Pass;
+                    for registered in replies.iter().filter(|r| r.trigger.matches(&message)) {
+                        reply = registered.reply.reply(context).await?;
+                        if reply != Reply::Pass {
+                            break;
+                        }
+                    }
+                    let content = match reply {
+                        Reply::Final(content) => content,
+                        Reply::Pass => {
+                            tracing::trace!(%id, "no reply function replied");
+                            return Ok(());
+                        }
+                        Reply::End => {
+                            tracing::trace!(%id, "conversation ended; not replying");
+                            return Ok(());
+                        }
+                    };
+
+                    state
+                        .lock()
+                        .unwrap()
+                        .histories
+                        .entry(peer)
+                        .or_default()
+                        .push(HistoryMessage::new(Role::Assistant, &content));
+                    message
+                        .sender
+                        .clone()
+                        .send(Box::new(
+                            Message::new(sender, content).with_role(Role::Assistant),
+                        ))
+                        .await?;
+                    Ok(())
+                }
+            }
+        });
+
+        Self { agent, state }
+    }
+
+    /// Returns a sender that can be used to send messages to the agent.
+    pub fn sender(&self) -> Sender<Box<Message>> {
+        self.agent.sender()
+    }
+
+    /// Offer messages that set off `trigger` to `reply`, after the reply
+    /// functions registered before it.
+    pub fn register_reply(&self, trigger: Trigger, reply: impl ReplyFn) {
+        self.state.lock().unwrap().replies.push(Registered {
+            trigger,
+            reply: Arc::new(reply),
+        });
+    }
+
+    /// Offer messages that set off `trigger` to `reply`, at `position` among
+    /// the reply functions, e.g. 0 to offer them to it first. Positions past
+    /// the end register it last.
+    pub fn register_reply_at(&self, position: usize, trigger: Trigger, reply: impl ReplyFn) {
+        let mut state = self.state.lock().unwrap();
+        let position = position.min(state.replies.len());
+        state.replies.insert(
+            position,
+            Registered {
+                trigger,
+                reply: Arc::new(reply),
+            },
+        );
+    }
+
+    /// Returns the number of reply functions.
+    pub fn replies(&self) -> usize {
+        self.state.lock().unwrap().replies.len()
+    }
+
+    /// Returns the conversation with the agent with id `peer`, or with
+    /// senders that aren't agents if `peer` is nil.
+    pub fn history(&self, peer: Uuid) -> Vec<HistoryMessage> {
+        let state = self.state.lock().unwrap();
+        state.histories.get(&peer).cloned().unwrap_or_default()
+    }
+
+    /// Forget the conversations with every peer.
+    pub fn clear_history(&self) {
+        self.state.lock().unwrap().histories.clear();
+    }
+}
+
+/// A reply function that asks a model to continue the conversation.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::conversable::{ConversableAgentBuilder, LlmReply, Trigger}, llm::openai}, std::sync::Arc};
+/// # tokio_test::block_on(async {
+/// let client = Arc::new(openai::Client::new(None, None));
+/// let agent = ConversableAgentBuilder::new()
+///     .with_reply(Trigger::Any, LlmReply::new(client, "gpt-4").with_system_prompt("Be brief."))
+///     .build();
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct LlmReply {
+    client: Arc<dyn LlmClient>,
+    model: String,
+    system_prompt: Option<String>,
+}
+
+impl LlmReply {
+    /// Create a reply function that asks `model` of `client`.
+    pub fn new(client: Arc<dyn LlmClient>, model: impl ToString) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            system_prompt: None,
+        }
+    }
+
+    /// Send `prompt` ahead of the conversation with every call.
+    pub fn with_system_prompt(mut self, prompt: impl ToString) -> Self {
+        self.system_prompt = Some(prompt.to_string());
+        self
+    }
+}
+
+impl ReplyFn for LlmReply {
+    fn reply<'a>(&'a self, context: ReplyContext<'a>) -> ReplyFuture<'a> {
+        Box::pin(async move {
+            let prompts = self
+                .system_prompt
+                .iter()
+                .chain(&context.message.system_prompt);
+            let messages = prompts
+                .map(|prompt| HistoryMessage::new(Role::System, prompt))
+                .chain(context.history.iter().cloned())
+                .collect();
+            let request = CompletionRequest {
+                model: self.model.clone(),
+                messages,
+                response_format: context.message.response_format.clone(),
+                ..Default::default()
+            };
+            let completion = self.client.complete(request).await?;
+            Ok(Reply::Final(completion.content))
+        })
+    }
+}
+
+/// A reply function that ends the conversation once a
+/// [termination condition](TerminationCondition) is met, so the reply
+/// functions after it aren't offered the message.
+#[derive(Debug, Clone)]
+pub struct TerminationReply {
+    condition: Arc<dyn TerminationCondition>,
+}
+
+impl TerminationReply {
+    /// Create a reply function that ends the conversation when `condition`
+    /// is met by it.
+    pub fn new(condition: impl TerminationCondition) -> Self {
+        Self {
+            condition: Arc::new(condition),
+        }
+    }
+}
+
+impl ReplyFn for TerminationReply {
+    fn reply<'a>(&'a self, context: ReplyContext<'a>) -> ReplyFuture<'a> {
+        Box::pin(async move {
+            match terminated(&*self.condition, context) {
+                true => Ok(Reply::End),
+                false => Ok(Reply::Pass),
+            }
+        })
+    }
+}
+
+/// Returns whether the conversation in `context` meets `condition`.
+fn terminated(condition: &dyn TerminationCondition, context: ReplyContext<'_>) -> bool {
+    let peer = context.message.name.as_deref().unwrap_or("user");
+    let transcript = context
+        .history
+        .iter()
+        .map(|message| ChatMessage {
+            name: match message.role {
+                Role::Assistant => context.name.to_string(),
+                _ => peer.to_string(),
+            },
+            content: message.content.clone(),
+            thought: None,
+        })
+        .collect::<Vec<_>>();
+    condition.start();
+    let reason = condition.check(&transcript);
+    if let Some(reason) = &reason {
+        tracing::trace!(name = context.name, %reason, "termination condition met");
+    }
+    reason.is_some()
+}
+
+/// Returns the id of the agent that sent `message`, or nil if it wasn't an
+/// agent.
+fn peer(message: &Message) -> Uuid {
+    message.sender.target().map_or(Uuid::nil(), |peer| peer.id)
+}
+
+/// A reply function that runs the tools a message asks to call and replies
+/// with their results, one per line. Messages that don't call tools pass.
+#[derive(Debug, Clone)]
+pub struct ToolReply {
+    tools: ToolRegistry,
+}
+
+impl ToolReply {
+    /// Create a reply function that runs tools of `tools`.
+    pub fn new(tools: ToolRegistry) -> Self {
+        Self { tools }
+    }
+}
+
+impl ReplyFn for ToolReply {
+    fn reply<'a>(&'a self, context: ReplyContext<'a>) -> ReplyFuture<'a> {
+        Box::pin(async move {
+            let Content::ToolCalls(calls) = &context.message.content else {
+                return Ok(Reply::Pass);
+            };
+            let mut results = Vec::with_capacity(calls.len());
+            for call in calls {
+                let tool = &call.function.name;
+                // failures are reported so the caller can try again
+                let result = self.tools.call(call).await.unwrap_or_else(|e| {
+                    tracing::debug!(tool, error = %e, "tool call failed");
+                    format!("error: {e}")
+                });
+                results.push(format!("{tool}: {result}"));
+            }
+            Ok(Reply::Final(results.join("\n")))
+        })
+    }
+}
+
+/// A reply function that asks a person, through a
+/// [user agent](super::user::UserAgent) or anything that answers like one,
+/// how to reply. Entering nothing passes, e.g. to let a model reply, and
+/// entering `exit` ends the conversation.
+#[derive(Debug, Clone)]
+pub struct HumanReply {
+    human: Sender<Box<Message>>,
+}
+
+impl HumanReply {
+    /// Create a reply function that asks the agent of `human`.
+    pub fn new(human: Sender<Box<Message>>) -> Self {
+        Self { human }
+    }
+}
+
+impl ReplyFn for HumanReply {
+    fn reply<'a>(&'a self, context: ReplyContext<'a>) -> ReplyFuture<'a> {
+        Box::pin(async move {
+            Ok(
+                match ask_human(&self.human, context.message).await?.as_str() {
+                    "" => Reply::Pass,
+                    "exit" => Reply::End,
+                    answer => Reply::Final(answer.to_string()),
+                },
+            )
+        })
+    }
+}
+
+/// Asks `human` how to reply to `message`, and returns the trimmed answer.
+async fn ask_human(human: &Sender<Box<Message>>, message: &Message) -> Result<String, Error> {
+    let (sender, mut replies) = super::channel(None);
+    human
+        .send(Box::new(Message::new(sender, message.content.to_string())))
+        .await?;
+    let Some(answer) = replies.recv().await else {
+        return Err(Error::ReplyFailed("the human didn't answer".to_string()));
+    };
+    Ok(answer.content.to_string().trim().to_string())
+}
+
+/// When a conversable agent asks a person how to reply, like AutoGen's
+/// `human_input_mode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HumanInputMode {
+    /// Ask about every message. Entering nothing lets the agent reply on its
+    /// own, and entering `exit` ends the conversation.
+    Always,
+
+    /// Ask only once the conversation would end, because the termination
+    /// condition is met or the limit on consecutive auto-replies is hit.
+    /// Entering nothing or `exit` ends it.
+    Terminate,
+
+    /// Never ask; the conversation ends once it would.
+    #[default]
+    Never,
+}
+
+/// The first reply function of a [built](ConversableAgentBuilder::build)
+/// agent: ends the conversation once it's over, asks a person when the
+/// human input mode says to, and counts the consecutive auto-replies to each
+/// peer.
+#[derive(Debug)]
+struct Gate {
+    termination: Option<Arc<dyn TerminationCondition>>,
+    max_consecutive_auto_reply: Option<usize>,
+    human: Option<(Sender<Box<Message>>, HumanInputMode)>,
+
+    /// The auto-replies in a row to each peer, by the peer's id.
+    auto_replies: Mutex<HashMap<Uuid, usize>>,
+}
+
+impl ReplyFn for Gate {
+    fn reply<'a>(&'a self, context: ReplyContext<'a>) -> ReplyFuture<'a> {
+        Box::pin(async move {
+            let peer = peer(context.message);
+            let terminated = self
+                .termination
+                .as_ref()
+                .is_some_and(|condition| terminated(&**condition, context));
+            let limit_hit = self.max_consecutive_auto_reply.is_some_and(|max| {
+                let auto_replies = self.auto_replies.lock().unwrap();
+                auto_replies.get(&peer).is_some_and(|count| *count >= max)
+            });
+            let over = terminated || limit_hit;
+
+            let human = match &self.human {
+                Some((human, HumanInputMode::Always)) => Some(human),
+                Some((human, HumanInputMode::Terminate)) if over => Some(human),
+                _ => None,
+            };
+            if let Some(human) = human {
+                match ask_human(human, context.message).await?.as_str() {
+                    "exit" => return Ok(Reply::End),
+                    "" if over => return Ok(Reply::End),
+                    // reply on its own
+                    "" => {}
+                    answer => {
+                        self.auto_replies.lock().unwrap().remove(&peer);
+                        return Ok(Reply::Final(answer.to_string()));
+                    }
+                }
+            }
+            if over {
+                tracing::trace!(
+                    name = context.name,
+                    limit_hit,
+                    "conversation over; not replying"
+                );
+                return Ok(Reply::End);
+            }
+            *self.auto_replies.lock().unwrap().entry(peer).or_default() += 1;
+            Ok(Reply::Pass)
+        })
+    }
+}
+
+/// Builds [`ConversableAgent`]s.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::{conversable::{ConversableAgentBuilder, HumanInputMode}, user::UserAgentBuilder}, llm::openai, tools::ToolRegistry}, std::sync::Arc};
+/// # tokio_test::block_on(async {
+/// let user = UserAgentBuilder::new().with_name("user").build();
+/// // writes code, runs the tools it's asked to and checks in with the user
+/// // after 5 replies in a row
+/// let coder = ConversableAgentBuilder::new()
+///     .with_name("coder")
+///     .with_llm(Arc::new(openai::Client::new(None, None)), "gpt-4")
+///     .with_system_message("You write Rust.")
+///     .with_tools(ToolRegistry::new())
+///     .with_human_input(user.sender(), HumanInputMode::Terminate)
+///     .with_max_consecutive_auto_reply(5)
+///     .build();
+/// # });
+/// ```
+#[derive(Debug, Default)]
+pub struct ConversableAgentBuilder {
+    /// Unique identifier for the agent.
+    pub id: Option<Uuid>,
+
+    /// A user-friendly name for the agent.
+    pub name: Option<String>,
+
+    /// The reply functions, in the order they're offered messages.
+    replies: Vec<Registered>,
+
+    /// The model that replies, if any, as the client and the model's name.
+    pub llm: Option<(Arc<dyn LlmClient>, String)>,
+
+    /// Sent ahead of the conversation with every call to the model.
+    pub system_message: Option<String>,
+
+    /// The tools the agent runs when asked to.
+    pub tools: Option<ToolRegistry>,
+
+    /// Who's asked how to reply, and when.
+    pub human: Option<(Sender<Box<Message>>, HumanInputMode)>,
+
+    /// Ends the conversation once it's met.
+    pub termination: Option<Arc<dyn TerminationCondition>>,
+
+    /// The most replies in a row the agent sends another agent on its own.
+    pub max_consecutive_auto_reply: Option<usize>,
+}
+
+impl ConversableAgentBuilder {
+    /// Create a new agent builder.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the id of the agent.
+    pub fn with_id(mut self, id: Uuid) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Set the name of the agent.
+    pub fn with_name(mut self, name: impl ToString) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Offer messages that set off `trigger` to `reply`, after the reply
+    /// functions added before it.
+    pub fn with_reply(mut self, trigger: Trigger, reply: impl ReplyFn) -> Self {
+        self.replies.push(Registered {
+            trigger,
+            reply: Arc::new(reply),
+        });
+        self
+    }
+
+    /// Reply with `model` of `client` when no other reply function does.
+    pub fn with_llm(mut self, client: Arc<dyn LlmClient>, model: impl ToString) -> Self {
+        self.llm = Some((client, model.to_string()));
+        self
+    }
+
+    /// Send `message` ahead of the conversation with every call to the
+    /// model.
+    pub fn with_system_message(mut self, message: impl ToString) -> Self {
+        self.system_message = Some(message.to_string());
+        self
+    }
+
+    /// Run the tools of `tools` that messages ask to call, and reply with
+    /// their results.
+    pub fn with_tools(mut self, tools: ToolRegistry) -> Self {
+        self.tools = Some(tools);
+        self
+    }
+
+    /// Ask `human`, e.g. a [user agent](super::user::UserAgent), how to
+    /// reply when `mode` says to.
+    pub fn with_human_input(mut self, human: Sender<Box<Message>>, mode: HumanInputMode) -> Self {
+        self.human = Some((human, mode));
+        self
+    }
+
+    /// End the conversation once `condition` is met.
+    pub fn with_termination(mut self, condition: impl TerminationCondition) -> Self {
+        self.termination = Some(Arc::new(condition));
+        self
+    }
+
+    /// Stop replying on its own to an agent once it has replied to it
+    /// `max` times in a row, so two agents can't keep each other going
+    /// forever. The count starts over when a person replies instead.
+    pub fn with_max_consecutive_auto_reply(mut self, max: usize) -> Self {
+        self.max_consecutive_auto_reply = Some(max);
+        self
+    }
+
+    /// Builds the agent. Messages are offered first to a check that ends
+    /// the conversation once it's over and asks the person, if any, when
+    /// the human input mode says to, then to the reply functions added,
+    /// then to the tools and finally to the model.
+    pub fn build(self) -> ConversableAgent {
+        let agent = ConversableAgent::spawn(self.id.unwrap_or_else(Uuid::new_v4), self.name);
+        let gate = Gate {
+            termination: self.termination,
+            max_consecutive_auto_reply: self.max_consecutive_auto_reply,
+            human: self.human,
+            auto_replies: Default::default(),
+        };
+        let mut replies = Vec::new();
+        if gate.termination.is_some()
+            || gate.max_consecutive_auto_reply.is_some()
+            || gate.human.is_some()
+        {
+            replies.push(Registered {
+                trigger: Trigger::Any,
+                reply: Arc::new(gate),
+            });
+        }
+        replies.extend(self.replies);
+        if let Some(tools) = self.tools {
+            replies.push(Registered {
+                trigger: Trigger::Any,
+                reply: Arc::new(ToolReply::new(tools)),
+            });
+        }
+        if let Some((client, model)) = self.llm {
+            let llm = LlmReply::new(client, model);
+            let llm = match self.system_message {
+                Some(message) => llm.with_system_prompt(message),
+                None => llm,
+            };
+            replies.push(Registered {
+                trigger: Trigger::Any,
+                reply: Arc::new(llm),
+            });
+        }
+        agent.state.lock().unwrap().replies = replies;
+        agent
+    }
+}
+
+impl Instance for ConversableAgent {
+    type Checkpoint = ();
+    type Message = Box<Message>;
+
+    fn sender(&self) -> Sender<Box<Message>> {
+        self.agent.sender()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.agent.is_finished()
+    }
+
+    fn terminate(self) -> impl Future<Output = Shutdown> + Send {
+        self.agent.terminate()
+    }
+}
+
+impl Actor for ConversableAgent {
+    type Error = super::SendError<Box<Message>>;
+    type Message = Message;
+
+    fn id(&self) -> Uuid {
+        self.agent.id
+    }
+
+    /// Returns the agent's name
+    fn name(&self) -> Option<&str> {
+        self.agent.name.as_deref()
+    }
+
+    fn sender(&self) -> Sender<Box<Message>> {
+        self.agent.sender()
+    }
+
+    async fn terminate(self) -> Shutdown {
+        self.agent.terminate().await
+    }
+
+    fn abort(self) {
+        self.agent.abort()
+    }
+
+    async fn send(&self, message: Self::Message) -> Result<(), Self::Error> {
+        self.agent.send(Box::new(message)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            agent::{channel, StreamEvent},
+            llm::{Completion, LlmFuture},
+        },
+        anyhow::Result,
+    };
+
+    /// A backend that replies with the last message reversed.
+    #[derive(Debug)]
+    struct Reverse;
+
+    impl LlmClient for Reverse {
+        fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                let last = request.messages.last().map(|m| m.content.as_str());
+                Ok(Completion {
+                    content: last.unwrap_or_default().chars().rev().collect(),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    async fn ask(agent: &ConversableAgent, content: &str) -> Result<Option<String>> {
+        let mut reply = agent.sender().ask_stream(content).await?;
+        while let Some(event) = reply.next().await {
+            if let StreamEvent::Complete(content) = event {
+                return Ok(Some(content));
+            }
+        }
+        Ok(None)
+    }
+
+    #[tokio::test]
+    async fn test_replies_in_order() -> Result<()> {
+        let agent = ConversableAgentBuilder::new()
+            .with_reply(
+                Trigger::when(|message| message.content.to_string() == "hi"),
+                |_: ReplyContext<'_>| async { Ok(Some("hello!".to_string())) },
+            )
+            // passes on everything, so the model replies
+            .with_reply(Trigger::Any, |_: ReplyContext<'_>| async { Ok(None) })
+            .with_reply(Trigger::Any, LlmReply::new(Arc::new(Reverse), "model"))
+            .build();
+
+        assert_eq!(ask(&agent, "hi").await?.as_deref(), Some("hello!"));
+        assert_eq!(ask(&agent, "abc").await?.as_deref(), Some("cba"));
+
+        // functions registered later are offered messages last
+        agent.register_reply(Trigger::Any, |_: ReplyContext<'_>| async {
+            Ok(Some("never".to_string()))
+        });
+        assert_eq!(ask(&agent, "abc").await?.as_deref(), Some("cba"));
+        assert_eq!(agent.history(Uuid::nil()).len(), 6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reply_chain() -> Result<()> {
+        let echo = crate::tools::FnTool::new(
+            "echo",
+            "Echoes.",
+            serde_json::json!({ "type": "object" }),
+            |_| async { Ok("done".to_string()) },
+        );
+        let agent = ConversableAgentBuilder::new()
+            .with_reply(
+                Trigger::Any,
+                TerminationReply::new(crate::chat::termination::Keyword::new("TERMINATE")),
+            )
+            .with_reply(
+                Trigger::Any,
+                ToolReply::new(ToolRegistry::new().with_tool(echo)),
+            )
+            .with_reply(Trigger::Any, LlmReply::new(Arc::new(Reverse), "model"))
+            .build();
+
+        // a person who answers some questions and passes on the rest
+        let human = Agent::<Box<Message>, crate::agent::SendError<Box<Message>>>::spawn(
+            Uuid::new_v4(),
+            Some("human".to_string()),
+            |sender, message| async move {
+                let answer = match message.content.to_string().as_str() {
+                    "help" => "a person answered",
+                    "bye" => "exit",
+                    _ => "",
+                };
+                message
+                    .sender
+                    .send(Box::new(Message::new(sender, answer)))
+                    .await
+            },
+        );
+        agent.register_reply_at(0, Trigger::Any, HumanReply::new(human.sender()));
+        assert_eq!(agent.replies(), 4);
+
+        assert_eq!(ask(&agent, "abc").await?.as_deref(), Some("cba"));
+        assert_eq!(
+            ask(&agent, "help").await?.as_deref(),
+            Some("a person answered")
+        );
+        assert_eq!(ask(&agent, "bye").await?, None);
+        assert_eq!(ask(&agent, "TERMINATE").await?, None);
+
+        let (sender, mut replies) = channel(None);
+        let call = llm::ToolCall {
+            id: "call_0".to_string(),
+            kind: llm::ToolKind::Function,
+            function: llm::FunctionCall {
+                name: "echo".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+        agent
+            .sender()
+            .send(Box::new(Message::new(
+                sender,
+                Content::ToolCalls(vec![call]),
+            )))
+            .await?;
+        let reply = replies.recv().await.unwrap();
+        assert_eq!(reply.content.to_string(), "echo: done");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_human_input() -> Result<()> {
+        // answers unless asked to say goodbye
+        let human = Agent::<Box<Message>, crate::agent::SendError<Box<Message>>>::spawn(
+            Uuid::new_v4(),
+            Some("human".to_string()),
+            |sender, message| async move {
+                let answer = match message.content.to_string().as_str() {
+                    "bye" => "",
+                    _ => "a person answered",
+                };
+                message
+                    .sender
+                    .send(Box::new(Message::new(sender, answer)))
+                    .await
+            },
+        );
+        let agent = ConversableAgentBuilder::new()
+            .with_llm(Arc::new(Reverse), "model")
+            .with_human_input(human.sender(), HumanInputMode::Terminate)
+            .with_max_consecutive_auto_reply(2)
+            .with_termination(crate::chat::termination::Keyword::new("TERMINATE"))
+            .build();
+
+        for (message, expected) in [
+            ("abc", Some("cba")),
+            ("abc", Some("cba")),
+            // the limit is hit, so the person is asked
+            ("abc", Some("a person answered")),
+            ("abc", Some("cba")),
+            ("TERMINATE", Some("a person answered")),
+            ("abc", Some("cba")),
+            ("abc", Some("cba")),
+            ("bye", None),
+        ] {
+            assert_eq!(ask(&agent, message).await?.as_deref(), expected);
+        }
+
+        let agent = ConversableAgentBuilder::new()
+            .with_llm(Arc::new(Reverse), "model")
+            .with_max_consecutive_auto_reply(1)
+            .build();
+        assert_eq!(ask(&agent, "abc").await?.as_deref(), Some("cba"));
+        assert_eq!(ask(&agent, "abc").await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_history_per_peer() -> Result<()> {
+        let agent = ConversableAgentBuilder::new()
+            .with_reply(Trigger::Any, |context: ReplyContext<'_>| {
+                let turns = context.history.len().to_string();
+                async move { Ok(Some(turns)) }
+            })
+            .build();
+
+        // peers are agents, which forward the replies they get here
+        let (replies, mut received) = channel::<Box<Message>>(None);
+        let spawn_peer = |name: &str| {
+            let replies = replies.clone();
+            Agent::<Box<Message>, crate::agent::SendError<Box<Message>>>::spawn(
+                Uuid::new_v4(),
+                Some(name.to_string()),
+                move |_, message| {
+                    let replies = replies.clone();
+                    async move { replies.send(message).await }
+                },
+            )
+        };
+        let (a, b) = (spawn_peer("a"), spawn_peer("b"));
+
+        for (peer, expected) in [(&a, "1"), (&a, "3"), (&b, "1")] {
+            agent
+                .sender()
+                .send(Box::new(Message::new(peer.sender(), "hello")))
+                .await?;
+            let reply = received.recv().await.unwrap();
+            assert_eq!(reply.content.to_string(), expected);
+        }
+        assert_eq!(agent.history(a.id).len(), 4);
+        assert_eq!(agent.history(b.id).len(), 2);
+        Ok(())
+    }
+}
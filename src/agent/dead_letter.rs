@@ -0,0 +1,285 @@
+//! Messages that never got handled. An agent given [`DeadLetters`] records
+//! each message it couldn't accept, because its mailbox was closed, each
+//! message left in its mailbox when its handler failed, and each message its
+//! handler gave up on after the agent's message timeout, instead of the
+//! message silently disappearing with a [`SendError`](super::SendError).
+//! Dead letters can be listed or watched as they arrive, e.g. to debug a
+//! conversation that stalled.
+
+use {
+    super::{AgentRef, Message, SendFailure},
+    std::{
+        any::Any,
+        collections::VecDeque,
+        fmt::{self, Debug},
+        sync::{Arc, Mutex, OnceLock},
+        time::{Duration, SystemTime},
+    },
+    tokio::sync::broadcast,
+    uuid::Uuid,
+};
+
+/// The number of dead letters kept when no capacity is configured. The
+/// oldest are dropped first.
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+/// Records a message that wasn't handled, and why, for the agent it was for.
+pub(crate) type Recorder<M> = dyn Fn(&M, Option<&AgentRef>, Reason) + Send + Sync;
+
+/// Why a message wasn't handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reason {
+    /// The message couldn't be delivered.
+    Undeliverable(SendFailure),
+
+    /// The message was delivered, but the agent's handler failed with this
+    /// error before getting to it.
+    Unhandled(String),
+
+    /// The agent's handler was cancelled after taking longer than this on
+    /// the message.
+    TimedOut(Duration),
+
+    /// The agent was cancelled before getting to the message.
+    Cancelled,
+
+    /// The agent's handler panicked with this on the message.
+    Panicked(String),
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Undeliverable(failure) => write!(f, "undeliverable: {failure}"),
+            Self::Unhandled(error) => write!(f, "unhandled: {error}"),
+            Self::TimedOut(timeout) => write!(f, "timed out after {timeout:?}"),
+            Self::Cancelled => f.write_str("cancelled"),
+            Self::Panicked(panic) => write!(f, "panicked: {panic}"),
+        }
+    }
+}
+
+/// A message that wasn't handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    /// The message, as formatted with [`Debug`].
+    pub message: String,
+
+    /// The agent that sent the message, if it's a [`Message`] from an agent.
+    pub sender: Option<AgentRef>,
+
+    /// The agent the message was for, if known.
+    pub receiver: Option<AgentRef>,
+
+    /// Why the message wasn't handled.
+    pub reason: Reason,
+
+    /// When the message was recorded.
+    pub recorded_at: SystemTime,
+}
+
+/// Where agents record the messages they didn't handle. Clones share the
+/// same dead letters.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::agent::{dead_letter::DeadLetters, AgentBuilder, SendError};
+/// # tokio_test::block_on(async {
+/// let dead_letters = DeadLetters::new();
+/// let mut arrivals = dead_letters.subscribe();
+/// let agent = AgentBuilder::<String, SendError<String>>::new()
+///     .with_name("printer")
+///     .with_dead_letters(dead_letters.clone())
+///     .spawn(|_sender, line| async move {
+///         println!("{line}");
+///         Ok(())
+///     });
+/// let sender = agent.sender();
+/// agent.terminate().await;
+///
+/// assert!(sender.send("too late".to_string()).await.is_err());
+/// let letter = arrivals.recv().await?;
+/// assert_eq!(letter.message, "\"too late\"");
+/// assert_eq!(dead_letters.letters(), [letter]);
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct DeadLetters {
+    letters: Arc<Mutex<VecDeque<DeadLetter>>>,
+    capacity: usize,
+    arrivals: broadcast::Sender<DeadLetter>,
+}
+
+impl Default for DeadLetters {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+}
+
+impl DeadLetters {
+    /// Create an empty set of dead letters that keeps the latest
+    /// [`DEFAULT_CAPACITY`].
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Create an empty set of dead letters that keeps the latest `capacity`.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let (arrivals, _) = broadcast::channel(capacity.max(1));
+        Self {
+            letters: Default::default(),
+            capacity,
+            arrivals,
+        }
+    }
+
+    /// Returns the dead letters shared across the crate, for agents that
+    /// don't need their own.
+    pub fn global() -> &'static DeadLetters {
+        static GLOBAL: OnceLock<DeadLetters> = OnceLock::new();
+        GLOBAL.get_or_init(DeadLetters::new)
+    }
+
+    /// Record `letter`, dropping the oldest letter if full.
+    pub fn record(&self, letter: DeadLetter) {
+        tracing::debug!(receiver = ?letter.receiver, reason = %letter.reason, message = letter.message, "dead letter");
+        let mut letters = self.letters.lock().unwrap();
+        if letters.len() == self.capacity {
+            letters.pop_front();
+        }
+        if self.capacity > 0 {
+            letters.push_back(letter.clone());
+        }
+        // nobody may be watching
+        let _ = self.arrivals.send(letter);
+    }
+
+    /// Returns the dead letters kept, oldest first.
+    pub fn letters(&self) -> Vec<DeadLetter> {
+        self.letters.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Returns the dead letters kept for the agent with `id`, oldest first.
+    pub fn letters_for(&self, id: Uuid) -> Vec<DeadLetter> {
+        let letters = self.letters.lock().unwrap();
+        letters
+            .iter()
+            .filter(|letter| letter.receiver.as_ref().is_some_and(|r| r.id == id))
+            .cloned()
+            .collect()
+    }
+
+    /// Forget the dead letters kept so far.
+    pub fn clear(&self) {
+        self.letters.lock().unwrap().clear();
+    }
+
+    /// Returns a receiver of dead letters recorded from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<DeadLetter> {
+        self.arrivals.subscribe()
+    }
+
+    /// Returns a recorder of messages of type `M`.
+    pub(crate) fn recorder<M: Debug + 'static>(&self) -> Arc<Recorder<M>> {
+        let dead_letters = self.clone();
+        Arc::new(move |message: &M, receiver: Option<&AgentRef>, reason| {
+            dead_letters.record(DeadLetter::new(message, receiver, reason))
+        })
+    }
+}
+
+impl DeadLetter {
+    /// Returns the dead letter of `message` for `receiver`, recorded now.
+    pub(crate) fn new<M: Debug + 'static>(
+        message: &M,
+        receiver: Option<&AgentRef>,
+        reason: Reason,
+    ) -> Self {
+        Self {
+            message: format!("{message:?}"),
+            sender: sender_of(message),
+            receiver: receiver.cloned(),
+            reason,
+            recorded_at: SystemTime::now(),
+        }
+    }
+}
+
+/// Returns the agent that sent `message`, if it's a [`Message`].
+fn sender_of(message: &dyn Any) -> Option<AgentRef> {
+    let message = match message.downcast_ref::<Box<Message>>() {
+        Some(message) => message.as_ref(),
+        None => message.downcast_ref::<Message>()?,
+    };
+    message.sender.target().cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::agent::{AgentBuilder, SendError},
+        anyhow::Result,
+        std::io,
+    };
+
+    #[tokio::test]
+    async fn test_dead_letters() -> Result<()> {
+        let dead_letters = DeadLetters::with_capacity(2);
+        let (open, opened) = tokio::sync::oneshot::channel::<()>();
+        let opened = Mutex::new(Some(opened));
+        let failing = AgentBuilder::<Box<Message>, io::Error>::new()
+            .with_name("failing")
+            .with_dead_letters(dead_letters.clone())
+            .spawn(move |_, _| {
+                let opened = opened.lock().unwrap().take();
+                async move {
+                    if let Some(opened) = opened {
+                        let _ = opened.await;
+                    }
+                    Err(io::Error::other("broken"))
+                }
+            });
+        let peer = AgentBuilder::<Box<Message>, SendError<Box<Message>>>::new()
+            .with_name("peer")
+            .spawn(|_, _| async { Ok(()) });
+
+        // the first message fails the handler, and the second is left over
+        for content in ["first", "second"] {
+            failing
+                .send(Box::new(Message::new(peer.sender(), content)))
+                .await?;
+        }
+        let _ = open.send(());
+        let sender = failing.sender();
+        assert!(failing.join().await?.is_err());
+        assert!(sender
+            .send(Box::new(Message::new(peer.sender(), "third")))
+            .await
+            .is_err());
+
+        let letters = dead_letters.letters();
+        assert_eq!(letters.len(), 2);
+        assert!(letters[0].message.contains("second"));
+        assert_eq!(letters[0].reason, Reason::Unhandled("broken".to_string()));
+        assert_eq!(
+            letters[0].sender.as_ref().unwrap().name.as_deref(),
+            Some("peer")
+        );
+        assert!(letters[1].message.contains("third"));
+        assert_eq!(
+            letters[1].reason,
+            Reason::Undeliverable(SendFailure::Closed)
+        );
+        assert_eq!(
+            letters[1].receiver.as_ref().unwrap().name.as_deref(),
+            Some("failing")
+        );
+        assert_eq!(dead_letters.letters_for(peer.id), []);
+
+        dead_letters.clear();
+        assert!(dead_letters.letters().is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+//! A directory of running agents. Agents register with an [`AgentDirectory`]
+//! when they're spawned, and other parts of an application look them up by
+//! id or by name instead of passing senders around.
+//!
+//! An agent's entry is removed once its mailbox closes, i.e. when it's
+//! terminated, aborted or its handler fails, so lookups never return a
+//! stopped agent.
+
+use {
+    super::{Actor, AgentRef, Sender},
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    },
+    uuid::Uuid,
+};
+
+/// Errors that can occur when registering an agent.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum RegisterError {
+    #[error("an agent with id {0} is already registered")]
+    IdTaken(Uuid),
+
+    #[error("an agent named {0:?} is already registered")]
+    NameTaken(String),
+}
+
+/// Running agents, by id and by unique name. Clones share the same
+/// directory.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::agent::{directory::AgentDirectory, AgentBuilder};
+/// # tokio_test::block_on(async {
+/// let directory = AgentDirectory::new();
+/// let agent = AgentBuilder::new()
+///     .with_name("printer")
+///     .with_directory(directory.clone())
+///     .spawn(|_sender, line: String| async move {
+///         println!("{line}");
+///         Ok::<_, std::io::Error>(())
+///     });
+/// if let Some(printer) = directory.get_by_name("printer") {
+///     printer.send("hello".to_string()).await?;
+/// }
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct AgentDirectory<M> {
+    inner: Arc<Mutex<Inner<M>>>,
+}
+
+#[derive(Debug)]
+struct Inner<M> {
+    agents: HashMap<Uuid, Entry<M>>,
+
+    /// The ids of named agents.
+    names: HashMap<String, Uuid>,
+}
+
+#[derive(Debug)]
+struct Entry<M> {
+    name: Option<String>,
+    sender: Sender<M>,
+}
+
+// implemented by hand so that cloning a directory doesn't require `M: Clone`
+impl<M> Clone for AgentDirectory<M> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<M> Default for AgentDirectory<M> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                agents: HashMap::new(),
+                names: HashMap::new(),
+            })),
+        }
+    }
+}
+
+impl<M> Inner<M> {
+    /// Remove the agents whose mailboxes closed.
+    fn prune(&mut self) {
+        let names = &mut self.names;
+        self.agents.retain(|id, entry| {
+            let running = !entry.sender.is_closed();
+            if !running {
+                tracing::trace!(%id, name = entry.name, "deregistering stopped agent");
+                if let Some(name) = &entry.name {
+                    names.remove(name);
+                }
+            }
+            running
+        });
+    }
+}
+
+impl<M> AgentDirectory<M> {
+    /// Create an empty directory.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Register an agent. Fails if the id or the name is taken by a running
+    /// agent.
+    pub fn register(
+        &self,
+        id: Uuid,
+        name: Option<String>,
+        sender: Sender<M>,
+    ) -> Result<(), RegisterError> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.prune();
+        if inner.agents.contains_key(&id) {
+            return Err(RegisterError::IdTaken(id));
+        }
+        if let Some(name) = &name {
+            if inner.names.contains_key(name) {
+                return Err(RegisterError::NameTaken(name.clone()));
+            }
+            inner.names.insert(name.clone(), id);
+        }
+        inner.agents.insert(id, Entry { name, sender });
+        Ok(())
+    }
+
+    /// Remove an agent before it stops. Returns whether it was registered.
+    pub fn deregister(&self, id: Uuid) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entry) = inner.agents.remove(&id) else {
+            return false;
+        };
+        if let Some(name) = entry.name {
+            inner.names.remove(&name);
+        }
+        true
+    }
+
+    /// Returns a sender to the agent with `id`, if it's running.
+    pub fn get(&self, id: Uuid) -> Option<Sender<M>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.prune();
+        inner.agents.get(&id).map(|entry| entry.sender.clone())
+    }
+
+    /// Returns a sender to the agent named `name`, if it's running.
+    pub fn get_by_name(&self, name: &str) -> Option<Sender<M>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.prune();
+        let id = inner.names.get(name)?;
+        inner.agents.get(id).map(|entry| entry.sender.clone())
+    }
+
+    /// Returns the running agents, in no particular order.
+    pub fn agents(&self) -> Vec<AgentRef> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.prune();
+        inner
+            .agents
+            .iter()
+            .map(|(id, entry)| AgentRef {
+                id: *id,
+                name: entry.name.clone(),
+            })
+            .collect()
+    }
+
+    /// Returns the number of running agents.
+    pub fn len(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        inner.prune();
+        inner.agents.len()
+    }
+
+    /// Returns whether no agents are running.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> AgentDirectory<Box<T>> {
+    /// Register an actor, e.g. a [`UserAgent`](super::user::UserAgent), by
+    /// its id and name.
+    pub fn register_actor<A>(&self, actor: &A) -> Result<(), RegisterError>
+    where
+        A: Actor<Message = T>,
+    {
+        self.register(actor.id(), actor.name().map(String::from), actor.sender())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::agent::{Agent, AgentBuilder},
+        anyhow::Result,
+    };
+
+    fn spawn(directory: &AgentDirectory<String>, name: &str) -> Agent<String, std::io::Error> {
+        AgentBuilder::new()
+            .with_name(name)
+            .with_directory(directory.clone())
+            .spawn(|_sender, _message: String| async move { Ok(()) })
+    }
+
+    #[tokio::test]
+    async fn test_lookup_and_deregister() -> Result<()> {
+        let directory = AgentDirectory::new();
+        let first = spawn(&directory, "first");
+        let second = spawn(&directory, "second");
+
+        assert_eq!(directory.len(), 2);
+        assert!(directory.get(first.id).is_some());
+        assert_eq!(
+            directory.get_by_name("second").unwrap().target(),
+            Some(&AgentRef {
+                id: second.id,
+                name: Some("second".to_string()),
+            })
+        );
+        assert_eq!(
+            directory.register(Uuid::new_v4(), Some("first".to_string()), first.sender()),
+            Err(RegisterError::NameTaken("first".to_string()))
+        );
+
+        // terminated and aborted agents are removed, freeing their names
+        let id = first.id;
+        first.terminate().await;
+        assert!(directory.get(id).is_none());
+        second.abort();
+        while directory.get_by_name("second").is_some() {
+            tokio::task::yield_now().await;
+        }
+        assert!(directory.is_empty());
+        let first = spawn(&directory, "first");
+        assert_eq!(directory.agents()[0].id, first.id);
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+//! Automatic escalation between models. An [`EscalationPolicy`] watches
+//! signals about the quality of an assistant's replies and escalates to a
+//! stronger model, possibly of another backend, or to a human once there is
+//! no stronger model, when its thresholds are crossed.
+
+use {
+    super::assistant::Assistant,
+    crate::llm::LlmClient,
+    std::sync::Arc,
+};
+
+/// A signal about how a conversation is going.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    /// A reply was accepted. Resets the failure counters.
+    Success,
+
+    /// A reply failed validation.
+    ValidationFailure,
+
+    /// A judge scored a reply.
+    JudgeScore(f32),
+
+    /// A request had to be retried.
+    Retry,
+}
+
+/// Where a conversation was escalated to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Escalation {
+    /// Switch to a stronger model.
+    Model(String),
+
+    /// Hand the conversation to a human.
+    Human,
+}
+
+/// A model on an [`EscalationPolicy`]'s ladder.
+#[derive(Debug, Clone)]
+struct Rung {
+    model: String,
+
+    /// The client the model is called through, or `None` for the
+    /// assistant's current one.
+    client: Option<Arc<dyn LlmClient>>,
+}
+
+/// Escalates a conversation through a ladder of increasingly strong models.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::agent::{assistant::AssistantBuilder, escalation::{Escalation, EscalationPolicy, Signal}};
+/// # tokio_test::block_on(async {
+/// let assistant = AssistantBuilder::new().with_model("gpt-3.5-turbo").build();
+/// let mut policy = EscalationPolicy::new(["gpt-4"])
+///     .with_max_validation_failures(2)
+///     .with_human_fallback();
+///
+/// policy.observe_for(&assistant, Signal::ValidationFailure);
+/// let escalation = policy.observe_for(&assistant, Signal::ValidationFailure);
+/// assert_eq!(escalation, Some(Escalation::Model("gpt-4".to_string())));
+/// assert_eq!(assistant.model(), "gpt-4");
+///
+/// // past the top of the ladder, the assistant's human fallback replies
+/// policy.observe_for(&assistant, Signal::ValidationFailure);
+/// let escalation = policy.observe_for(&assistant, Signal::ValidationFailure);
+/// assert_eq!(escalation, Some(Escalation::Human));
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EscalationPolicy {
+    /// The models to escalate to, weakest first.
+    ladder: Vec<Rung>,
+
+    /// The index of the next model on the ladder.
+    next: usize,
+
+    /// Escalate to a human once the ladder is exhausted.
+    human_fallback: bool,
+
+    /// Escalate after this many validation failures in a row.
+    max_validation_failures: Option<usize>,
+
+    /// Escalate when a judge scores a reply below this.
+    min_judge_score: Option<f32>,
+
+    /// Escalate after this many retries in a row.
+    max_retries: Option<usize>,
+
+    /// Validation failures since the last success or escalation.
+    validation_failures: usize,
+
+    /// Retries since the last success or escalation.
+    retries: usize,
+}
+
+impl EscalationPolicy {
+    /// Create a policy that escalates through `ladder`, weakest model first.
+    /// The policy has no thresholds until they are configured.
+    pub fn new(ladder: impl IntoIterator<Item = impl ToString>) -> Self {
+        Self {
+            ladder: ladder
+                .into_iter()
+                .map(|model| Rung {
+                    model: model.to_string(),
+                    client: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Add `model` of `client` to the top of the ladder, e.g. a stronger
+    /// model from another provider. Escalating to it switches the
+    /// assistant's [backend](Assistant::switch_backend).
+    pub fn with_backend(mut self, client: Arc<dyn LlmClient>, model: impl ToString) -> Self {
+        self.ladder.push(Rung {
+            model: model.to_string(),
+            client: Some(client),
+        });
+        self
+    }
+
+    /// Escalate to a human once every model on the ladder has been tried,
+    /// handing the conversation to the assistant's
+    /// [human fallback](Assistant::set_human_fallback).
+    pub fn with_human_fallback(mut self) -> Self {
+        self.human_fallback = true;
+        self
+    }
+
+    /// Escalate after `max` validation failures in a row.
+    pub fn with_max_validation_failures(mut self, max: usize) -> Self {
+        self.max_validation_failures = Some(max);
+        self
+    }
+
+    /// Escalate when a judge scores a reply below `score`.
+    pub fn with_min_judge_score(mut self, score: f32) -> Self {
+        self.min_judge_score = Some(score);
+        self
+    }
+
+    /// Escalate after `max` retries in a row.
+    pub fn with_max_retries(mut self, max: usize) -> Self {
+        self.max_retries = Some(max);
+        self
+    }
+
+    /// Record a signal. Returns where the conversation should be escalated
+    /// to if a threshold was crossed, or `None` if it shouldn't be escalated
+    /// or there is nowhere left to escalate to.
+    pub fn observe(&mut self, signal: Signal) -> Option<Escalation> {
+        self.escalate(signal).map(|(escalation, _)| escalation)
+    }
+
+    /// Record a signal about `assistant`'s replies, switching its model, and
+    /// backend if the model has its own, if the policy escalates to a
+    /// stronger one, or [handing](Assistant::set_handed_to_human) the
+    /// conversation to its human fallback if the policy escalates to a human.
+    pub fn observe_for(&mut self, assistant: &Assistant, signal: Signal) -> Option<Escalation> {
+        let (escalation, client) = self.escalate(signal)?;
+        let reason = Some(format!("escalated after {signal:?}"));
+        match (&escalation, client) {
+            (Escalation::Model(model), Some(client)) => {
+                assistant.switch_backend(client, model, reason)
+            }
+            (Escalation::Model(model), None) => assistant.switch_model(model, reason),
+            (Escalation::Human, _) => assistant.set_handed_to_human(true),
+        }
+        Some(escalation)
+    }
+
+    /// Record a signal. Returns where the conversation should be escalated
+    /// to, with the client of the model escalated to, if it has its own.
+    fn escalate(&mut self, signal: Signal) -> Option<(Escalation, Option<Arc<dyn LlmClient>>)> {
+        let crossed = match signal {
+            Signal::Success => {
+                self.reset();
+                false
+            }
+            Signal::ValidationFailure => {
+                self.validation_failures += 1;
+                self.max_validation_failures
+                    .is_some_and(|max| self.validation_failures >= max)
+            }
+            Signal::JudgeScore(score) => self.min_judge_score.is_some_and(|min| score < min),
+            Signal::Retry => {
+                self.retries += 1;
+                self.max_retries.is_some_and(|max| self.retries >= max)
+            }
+        };
+        if !crossed {
+            return None;
+        }
+
+        self.reset();
+        let (escalation, client) = match self.ladder.get(self.next) {
+            Some(rung) => {
+                self.next += 1;
+                (Escalation::Model(rung.model.clone()), rung.client.clone())
+            }
+            None if self.human_fallback => (Escalation::Human, None),
+            None => return None,
+        };
+        tracing::debug!(?signal, ?escalation, "escalating");
+        Some((escalation, client))
+    }
+
+    fn reset(&mut self) {
+        self.validation_failures = 0;
+        self.retries = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            agent::{assistant::AssistantBuilder, mailbox, Message},
+            llm::{Completion, CompletionRequest, LlmFuture},
+        },
+        anyhow::Result,
+    };
+
+    /// Replies with its name.
+    #[derive(Debug)]
+    struct Named(&'static str);
+
+    impl LlmClient for Named {
+        fn complete(&self, _request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                Ok(Completion {
+                    content: self.0.to_string(),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    #[test]
+    fn test_escalates_through_ladder_then_human() {
+        let mut policy = EscalationPolicy::new(["b", "c"])
+            .with_max_retries(2)
+            .with_min_judge_score(0.5)
+            .with_human_fallback();
+
+        assert_eq!(policy.observe(Signal::Retry), None);
+        assert_eq!(
+            policy.observe(Signal::Retry),
+            Some(Escalation::Model("b".to_string()))
+        );
+        assert_eq!(policy.observe(Signal::JudgeScore(0.9)), None);
+        assert_eq!(
+            policy.observe(Signal::JudgeScore(0.1)),
+            Some(Escalation::Model("c".to_string()))
+        );
+        assert_eq!(
+            policy.observe(Signal::JudgeScore(0.1)),
+            Some(Escalation::Human)
+        );
+    }
+
+    #[test]
+    fn test_success_resets_counters() {
+        let mut policy = EscalationPolicy::new(["b"]).with_max_validation_failures(2);
+
+        assert_eq!(policy.observe(Signal::ValidationFailure), None);
+        assert_eq!(policy.observe(Signal::Success), None);
+        assert_eq!(policy.observe(Signal::ValidationFailure), None);
+        assert_eq!(
+            policy.observe(Signal::ValidationFailure),
+            Some(Escalation::Model("b".to_string()))
+        );
+        assert_eq!(policy.observe(Signal::ValidationFailure), None);
+        assert_eq!(
+            policy.observe(Signal::ValidationFailure),
+            None,
+            "there's nowhere left to escalate to without a human fallback"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_escalates_assistant() -> Result<()> {
+        let (human, mut questions) = mailbox::channel(None);
+        let assistant = AssistantBuilder::new()
+            .with_model("weak")
+            .with_client(Arc::new(Named("weak")))
+            .with_human_fallback(human)
+            .build();
+        let mut policy = EscalationPolicy::new(Vec::<String>::new())
+            .with_backend(Arc::new(Named("strong")), "strong")
+            .with_max_retries(1)
+            .with_human_fallback();
+        let (inbox, mut replies) = mailbox::channel(None);
+        let ask = |content: &'static str| {
+            let message = Box::new(Message::new(inbox.clone(), content));
+            let sender = assistant.sender();
+            async move { sender.send(message).await }
+        };
+
+        // the stronger model is called through its own client
+        assert_eq!(
+            policy.observe_for(&assistant, Signal::Retry),
+            Some(Escalation::Model("strong".to_string()))
+        );
+        ask("hello").await?;
+        assert_eq!(replies.recv().await.unwrap().content.to_string(), "strong");
+        assert!(assistant.model_switches()[0].switched_backend);
+
+        // then the human replies in the assistant's place
+        assert_eq!(
+            policy.observe_for(&assistant, Signal::Retry),
+            Some(Escalation::Human)
+        );
+        ask("hello again").await?;
+        let question = questions.recv().await.unwrap();
+        assert_eq!(question.content.to_string(), "hello again");
+        let (reply_to, _) = mailbox::channel(None);
+        question
+            .sender
+            .send(Box::new(Message::new(reply_to, "human")))
+            .await?;
+        assert_eq!(replies.recv().await.unwrap().content.to_string(), "human");
+        Ok(())
+    }
+}
@@ -0,0 +1,527 @@
+//! An agent's mailbox. Mailboxes are either unbounded or bounded; a bounded
+//! mailbox applies backpressure to senders when it's full. Either kind can
+//! deliver messages by [priority](super::priority) instead of in order.
+
+use {
+    super::{
+        clock::{self, Clock, SystemClock},
+        dead_letter::{Reason, Recorder},
+        priority::{self, Level, QueueReceiver, QueueSender},
+        AgentRef, AskError, ReplyTo, SendError, SendFailure,
+    },
+    std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+        time::Duration,
+    },
+    tokio::sync::{mpsc, oneshot},
+};
+
+/// Error returned by [`Sender::try_send`].
+#[derive(thiserror::Error, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TrySendError<M> {
+    /// The agent's mailbox is full.
+    #[error("agent mailbox is full: {0:?}")]
+    Full(M),
+
+    /// The agent has been terminated.
+    #[error("unable to send message to terminated agent: {0:?}")]
+    Closed(M),
+}
+
+/// Error returned by [`Sender::send_timeout`].
+#[derive(thiserror::Error, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SendTimeoutError<M> {
+    /// The agent's mailbox stayed full for the whole timeout.
+    #[error("timed out waiting for space in agent mailbox: {0:?}")]
+    Timeout(M),
+
+    /// The agent has been terminated.
+    #[error("unable to send message to terminated agent: {0:?}")]
+    Closed(M),
+}
+
+/// A message in a mailbox, and the span it was sent from if the mailbox
+/// propagates traces.
+#[derive(Debug)]
+pub(crate) struct Envelope<M> {
+    pub(crate) message: M,
+    pub(crate) span: Option<tracing::Span>,
+}
+
+impl<M> From<M> for Envelope<M> {
+    fn from(message: M) -> Self {
+        Self {
+            message,
+            span: None,
+        }
+    }
+}
+
+/// A channel to send messages to an agent.
+pub struct Sender<M> {
+    inner: Inner<M>,
+
+    /// The agent the mailbox belongs to, if any, for error context.
+    target: Option<Arc<Target>>,
+
+    /// Records the messages the mailbox refuses.
+    dead_letters: Option<Arc<Recorder<M>>>,
+
+    /// Whether messages carry the span they're sent from.
+    traced: bool,
+}
+
+impl<M> std::fmt::Debug for Sender<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sender")
+            .field("target", &self.target)
+            .field("dead_letters", &self.dead_letters.is_some())
+            .field("traced", &self.traced)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+enum Inner<M> {
+    /// An unbounded mailbox, and the number of messages in it, which tokio
+    /// doesn't track for unbounded channels.
+    Unbounded(mpsc::UnboundedSender<Envelope<M>>, Arc<AtomicUsize>),
+    /// A bounded mailbox, and the number of messages in it. Tokio only
+    /// tracks the slots taken, which include those reserved for messages
+    /// not sent yet.
+    Bounded(mpsc::Sender<Envelope<M>>, Arc<AtomicUsize>),
+    Priority(QueueSender<M>),
+}
+
+/// The agent a mailbox belongs to.
+#[derive(Debug)]
+struct Target {
+    agent: AgentRef,
+
+    /// Set once the agent is told to terminate, to tell a terminated agent
+    /// apart from one whose mailbox closed otherwise.
+    terminated: AtomicBool,
+}
+
+// implemented by hand so that cloning a sender doesn't require `M: Clone`
+impl<M> Clone for Sender<M> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: match &self.inner {
+                Inner::Unbounded(sender, queued) => {
+                    Inner::Unbounded(sender.clone(), queued.clone())
+                }
+                Inner::Bounded(sender, queued) => Inner::Bounded(sender.clone(), queued.clone()),
+                Inner::Priority(sender) => Inner::Priority(sender.clone()),
+            },
+            target: self.target.clone(),
+            dead_letters: self.dead_letters.clone(),
+            traced: self.traced,
+        }
+    }
+}
+
+impl<M> Sender<M> {
+    /// Send a message to the agent. If the agent's mailbox is bounded and
+    /// full, waits until there is space for the message.
+    pub async fn send(&self, message: M) -> Result<(), SendError<M>> {
+        let envelope = self.seal(message);
+        // map the tokio SendError to our own SendError
+        match &self.inner {
+            Inner::Unbounded(sender, queued) => {
+                send_unbounded(sender, queued, envelope).map_err(|m| self.error(m))
+            }
+            Inner::Bounded(sender, queued) => match sender.reserve().await {
+                Ok(permit) => {
+                    send_reserved(permit, queued, envelope);
+                    Ok(())
+                }
+                Err(_) => Err(self.error(envelope.message)),
+            },
+            Inner::Priority(sender) => sender.send(envelope, None).await.map_err(|m| self.error(m)),
+        }
+    }
+
+    /// Send a message to the agent at `level`, instead of the level it has,
+    /// if the agent has a priority mailbox. Other mailboxes deliver it in
+    /// order, like [`Sender::send`].
+    pub async fn send_with_priority(&self, message: M, level: Level) -> Result<(), SendError<M>> {
+        match &self.inner {
+            Inner::Priority(sender) => sender
+                .send(self.seal(message), Some(level))
+                .await
+                .map_err(|m| self.error(m)),
+            _ => self.send(message).await,
+        }
+    }
+
+    /// Returns the number of messages waiting in the mailbox. Senders still
+    /// waiting for space aren't counted.
+    pub fn len(&self) -> usize {
+        match &self.inner {
+            Inner::Unbounded(_, queued) | Inner::Bounded(_, queued) => {
+                queued.load(Ordering::Relaxed)
+            }
+            Inner::Priority(sender) => sender.len(),
+        }
+    }
+
+    /// Returns whether no messages are waiting in the mailbox.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the agent the mailbox belongs to, if any.
+    pub fn target(&self) -> Option<&AgentRef> {
+        self.target.as_ref().map(|target| &target.agent)
+    }
+
+    /// Returns whether the mailbox is closed, i.e. the agent stopped and
+    /// won't receive any more messages.
+    pub fn is_closed(&self) -> bool {
+        match &self.inner {
+            Inner::Unbounded(sender, _) => sender.is_closed(),
+            Inner::Bounded(sender, _) => sender.is_closed(),
+            Inner::Priority(sender) => sender.is_closed(),
+        }
+    }
+
+    /// Attribute the mailbox to `agent`.
+    pub(crate) fn with_target(self, agent: AgentRef) -> Self {
+        Self {
+            target: Some(Arc::new(Target {
+                agent,
+                terminated: AtomicBool::new(false),
+            })),
+            ..self
+        }
+    }
+
+    /// Have messages carry the span they're sent from.
+    pub(crate) fn with_tracing(self) -> Self {
+        Self {
+            traced: true,
+            ..self
+        }
+    }
+
+    /// Returns `message` in an envelope, with the current span if the
+    /// mailbox propagates traces.
+    fn seal(&self, message: M) -> Envelope<M> {
+        let span = self
+            .traced
+            .then(tracing::Span::current)
+            .filter(|span| !span.is_disabled());
+        Envelope { message, span }
+    }
+
+    /// Record the messages the mailbox refuses with `recorder`.
+    pub(crate) fn with_dead_letters(self, recorder: Arc<Recorder<M>>) -> Self {
+        Self {
+            dead_letters: Some(recorder),
+            ..self
+        }
+    }
+
+    /// Record `message` as a dead letter, if the mailbox keeps them.
+    pub(crate) fn dead_letter(&self, message: &M, reason: Reason) {
+        if let Some(record) = &self.dead_letters {
+            record(message, self.target(), reason);
+        }
+    }
+
+    /// Record that the agent was told to terminate.
+    pub(crate) fn mark_terminated(&self) {
+        if let Some(target) = &self.target {
+            target.terminated.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the error for a message the mailbox refused.
+    fn error(&self, message: M) -> SendError<M> {
+        let terminated = self
+            .target
+            .as_ref()
+            .is_some_and(|target| target.terminated.load(Ordering::Relaxed));
+        let reason = if terminated {
+            SendFailure::Terminated
+        } else {
+            SendFailure::Closed
+        };
+        self.dead_letter(&message, Reason::Undeliverable(reason));
+        SendError {
+            message,
+            target: self.target().cloned(),
+            reason,
+        }
+    }
+
+    /// Send a message to the agent without waiting. Fails if the agent's
+    /// mailbox is full.
+    pub fn try_send(&self, message: M) -> Result<(), TrySendError<M>> {
+        let envelope = self.seal(message);
+        match &self.inner {
+            Inner::Unbounded(sender, queued) => send_unbounded(sender, queued, envelope)
+                .map_err(|m| TrySendError::Closed(self.error(m).message)),
+            Inner::Bounded(sender, queued) => match sender.try_reserve() {
+                Ok(permit) => {
+                    send_reserved(permit, queued, envelope);
+                    Ok(())
+                }
+                Err(mpsc::error::TrySendError::Full(())) => {
+                    Err(TrySendError::Full(envelope.message))
+                }
+                Err(mpsc::error::TrySendError::Closed(())) => {
+                    Err(TrySendError::Closed(self.error(envelope.message).message))
+                }
+            },
+            Inner::Priority(sender) => sender.try_send(envelope, None).map_err(|e| match e {
+                (false, m) => TrySendError::Full(m),
+                (true, m) => TrySendError::Closed(self.error(m).message),
+            }),
+        }
+    }
+
+    /// Send a message to the agent, waiting at most `timeout` for space in
+    /// the agent's mailbox.
+    pub async fn send_timeout(
+        &self,
+        message: M,
+        timeout: Duration,
+    ) -> Result<(), SendTimeoutError<M>> {
+        self.send_timeout_on(&SystemClock, message, timeout).await
+    }
+
+    /// Like [`Sender::send_timeout`], but measures the timeout on `clock`.
+    pub(crate) async fn send_timeout_on(
+        &self,
+        clock: &dyn Clock,
+        message: M,
+        timeout: Duration,
+    ) -> Result<(), SendTimeoutError<M>> {
+        match &self.inner {
+            Inner::Unbounded(sender, queued) => send_unbounded(sender, queued, self.seal(message))
+                .map_err(|m| SendTimeoutError::Closed(self.error(m).message)),
+            // reserve a slot first so the message isn't lost if we time out
+            Inner::Bounded(sender, queued) => {
+                match clock::timeout(clock, timeout, sender.reserve()).await {
+                    Some(Ok(permit)) => {
+                        send_reserved(permit, queued, self.seal(message));
+                        Ok(())
+                    }
+                    Some(Err(_)) => Err(SendTimeoutError::Closed(self.error(message).message)),
+                    None => Err(SendTimeoutError::Timeout(message)),
+                }
+            }
+            Inner::Priority(sender) => match clock::timeout(clock, timeout, sender.reserve()).await
+            {
+                Some(Ok(())) => sender
+                    .send_reserved(self.seal(message), None)
+                    .map_err(|m| SendTimeoutError::Closed(self.error(m).message)),
+                Some(Err(_)) => Err(SendTimeoutError::Closed(self.error(message).message)),
+                None => Err(SendTimeoutError::Timeout(message)),
+            },
+        }
+    }
+
+    /// Send a request to the agent and wait for its reply. The request is
+    /// built from a [`ReplyTo`] handle that the agent uses to respond.
+    pub async fn ask<R, F>(&self, request: F) -> Result<R, AskError<M>>
+    where
+        F: FnOnce(ReplyTo<R>) -> M,
+    {
+        let (reply_to, reply) = oneshot::channel();
+        self.send(request(ReplyTo(reply_to))).await?;
+        reply.await.map_err(|_| AskError::NoReply)
+    }
+
+    /// Like [`Sender::ask`], but gives up if no reply is received within the
+    /// timeout.
+    pub async fn ask_timeout<R, F>(&self, request: F, timeout: Duration) -> Result<R, AskError<M>>
+    where
+        F: FnOnce(ReplyTo<R>) -> M,
+    {
+        self.ask_timeout_on(&SystemClock, request, timeout).await
+    }
+
+    /// Like [`Sender::ask_timeout`], but measures the timeout on `clock`.
+    pub(crate) async fn ask_timeout_on<R, F>(
+        &self,
+        clock: &dyn Clock,
+        request: F,
+        timeout: Duration,
+    ) -> Result<R, AskError<M>>
+    where
+        F: FnOnce(ReplyTo<R>) -> M,
+    {
+        clock::timeout(clock, timeout, self.ask(request))
+            .await
+            .unwrap_or(Err(AskError::Timeout))
+    }
+}
+
+/// Send `message` on an unbounded mailbox, counting it while it's queued.
+/// Returns the message if the mailbox is closed.
+fn send_unbounded<M>(
+    sender: &mpsc::UnboundedSender<Envelope<M>>,
+    queued: &AtomicUsize,
+    envelope: Envelope<M>,
+) -> Result<(), M> {
+    // count first, so the receiver never takes a message it wasn't told of
+    queued.fetch_add(1, Ordering::Relaxed);
+    sender.send(envelope).map_err(|e| {
+        queued.fetch_sub(1, Ordering::Relaxed);
+        e.0.message
+    })
+}
+
+/// Send `message` on a bounded mailbox with the slot reserved for it,
+/// counting it while it's queued.
+fn send_reserved<M>(
+    permit: mpsc::Permit<'_, Envelope<M>>,
+    queued: &AtomicUsize,
+    envelope: Envelope<M>,
+) {
+    // count first, so the receiver never takes a message it wasn't told of
+    queued.fetch_add(1, Ordering::Relaxed);
+    permit.send(envelope);
+}
+
+/// The receiving half of an agent's mailbox.
+#[derive(Debug)]
+pub(crate) enum Receiver<M> {
+    Unbounded(mpsc::UnboundedReceiver<Envelope<M>>, Arc<AtomicUsize>),
+    Bounded(mpsc::Receiver<Envelope<M>>, Arc<AtomicUsize>),
+    Priority(QueueReceiver<M>),
+}
+
+impl<M> Receiver<M> {
+    /// Receive the next message, or `None` once all senders are dropped.
+    pub(crate) async fn recv(&mut self) -> Option<M> {
+        std::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Receive the next message in its envelope, or `None` once all senders
+    /// are dropped.
+    pub(crate) async fn recv_envelope(&mut self) -> Option<Envelope<M>> {
+        std::future::poll_fn(|cx| self.poll_envelope(cx)).await
+    }
+
+    /// Poll for the next message, or `None` once all senders are dropped.
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<M>> {
+        self.poll_envelope(cx)
+            .map(|envelope| envelope.map(|envelope| envelope.message))
+    }
+
+    /// Poll for the next message in its envelope, or `None` once all
+    /// senders are dropped.
+    fn poll_envelope(&mut self, cx: &mut Context<'_>) -> Poll<Option<Envelope<M>>> {
+        match self {
+            Self::Unbounded(receiver, queued) => {
+                let received = receiver.poll_recv(cx);
+                if let Poll::Ready(Some(_)) = received {
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                }
+                received
+            }
+            Self::Bounded(receiver, queued) => {
+                let received = receiver.poll_recv(cx);
+                if let Poll::Ready(Some(_)) = received {
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                }
+                received
+            }
+            Self::Priority(receiver) => receiver.poll_recv(cx),
+        }
+    }
+
+    /// Close the mailbox. Further sends fail, but messages already in the
+    /// mailbox can still be received.
+    pub(crate) fn close(&mut self) {
+        match self {
+            Self::Unbounded(receiver, _) => receiver.close(),
+            Self::Bounded(receiver, _) => receiver.close(),
+            Self::Priority(receiver) => receiver.close(),
+        }
+    }
+
+    /// Receive the next message if one is waiting.
+    pub(crate) fn try_recv(&mut self) -> Option<M> {
+        match self {
+            Self::Unbounded(receiver, queued) => {
+                let received = receiver.try_recv().ok();
+                if received.is_some() {
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                }
+                received.map(|envelope| envelope.message)
+            }
+            Self::Bounded(receiver, queued) => {
+                let received = receiver.try_recv().ok();
+                if received.is_some() {
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                }
+                received.map(|envelope| envelope.message)
+            }
+            Self::Priority(receiver) => receiver.try_recv(),
+        }
+    }
+}
+
+/// Create a mailbox. The mailbox is bounded if a capacity is given.
+///
+/// Panics if the capacity is zero.
+pub(crate) fn channel<M>(capacity: Option<usize>) -> (Sender<M>, Receiver<M>) {
+    match capacity {
+        Some(capacity) => {
+            assert!(capacity > 0, "a bounded mailbox needs room for a message");
+            let (sender, receiver) = mpsc::channel(capacity);
+            let queued = Arc::new(AtomicUsize::new(0));
+            let sender = Sender {
+                inner: Inner::Bounded(sender, queued.clone()),
+                target: None,
+                dead_letters: None,
+                traced: false,
+            };
+            (sender, Receiver::Bounded(receiver, queued))
+        }
+        None => {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let queued = Arc::new(AtomicUsize::new(0));
+            let sender = Sender {
+                inner: Inner::Unbounded(sender, queued.clone()),
+                target: None,
+                dead_letters: None,
+                traced: false,
+            };
+            (sender, Receiver::Unbounded(receiver, queued))
+        }
+    }
+}
+
+/// Create a mailbox that delivers messages by priority, ordered by
+/// `ordering` and aged on `clock`. The mailbox is bounded if a capacity is
+/// given.
+///
+/// Panics if the capacity is zero.
+pub(crate) fn priority_channel<M>(
+    capacity: Option<usize>,
+    ordering: priority::Ordering<M>,
+    clock: Arc<dyn Clock>,
+) -> (Sender<M>, Receiver<M>) {
+    assert!(
+        capacity != Some(0),
+        "a bounded mailbox needs room for a message"
+    );
+    let (sender, receiver) = priority::queue(capacity, ordering, clock);
+    let sender = Sender {
+        inner: Inner::Priority(sender),
+        target: None,
+        dead_letters: None,
+        traced: false,
+    };
+    (sender, Receiver::Priority(receiver))
+}
@@ -0,0 +1,256 @@
+//! The messages agents exchange. A [`Message`] says who it's from, in what
+//! role, and carries [`Content`] that's richer than text: tool calls, tool
+//! results and images, so assistants can build proper chat completion
+//! requests and chats can render transcripts.
+
+use {
+    super::{Sender, StreamEvent, ToolProgress},
+    crate::llm::{HistoryMessage, ResponseFormat, Role, ToolCall},
+    std::{fmt, time::SystemTime},
+    uuid::Uuid,
+};
+
+/// What a message says.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Content {
+    /// Plain text.
+    Text(String),
+
+    /// Tools the sender asks to call.
+    ToolCalls(Vec<ToolCall>),
+
+    /// The result of a tool call.
+    ToolResult {
+        /// The call the result answers.
+        call_id: String,
+
+        /// What the tool returned.
+        result: String,
+    },
+
+    /// An image, by URL. Data URLs can carry the image itself.
+    Image {
+        /// Where the image is.
+        url: String,
+    },
+}
+
+impl Content {
+    /// Returns the text, if the content is text.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Self::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Content {
+    fn default() -> Self {
+        Self::Text(String::new())
+    }
+}
+
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<&str> for Content {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+/// Renders the content for people, e.g. in transcripts.
+impl fmt::Display for Content {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text(text) => f.write_str(text),
+            Self::ToolCalls(calls) => {
+                for (i, call) in calls.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(
+                        f,
+                        "call {}({})",
+                        call.function.name, call.function.arguments
+                    )?;
+                }
+                Ok(())
+            }
+            Self::ToolResult { result, .. } => f.write_str(result),
+            Self::Image { url } => write!(f, "[image: {url}]"),
+        }
+    }
+}
+
+/// Messages that can be sent to an actor.
+#[derive(Debug, Clone)]
+pub struct Message {
+    /// Unique identifier for the message.
+    pub id: Uuid,
+
+    /// The sender to reply to.
+    pub sender: Sender<Box<Message>>,
+
+    /// The role of whoever wrote the message.
+    pub role: Role,
+
+    /// The name of whoever wrote the message, if known.
+    pub name: Option<String>,
+
+    /// What the message says.
+    pub content: Content,
+
+    /// When the message was created.
+    pub timestamp: SystemTime,
+
+    /// Where to stream output while the message is being handled, if the
+    /// sender is listening.
+    pub stream: Option<Sender<StreamEvent>>,
+
+    /// The shape the reply's content must take, if constrained.
+    pub response_format: Option<ResponseFormat>,
+
+    /// Instructions for replying to this message, e.g. the recipient's role
+    /// in a chat. Assistants send them to the model after their own system
+    /// prompt, without adding them to the history.
+    pub system_prompt: Option<String>,
+
+    /// The conversation the message is part of, e.g. a chat's id, if any.
+    /// Metrics recorded while the message is handled are labeled with it.
+    pub conversation: Option<String>,
+}
+
+impl Message {
+    /// Create a user message that replies go to `sender`. The message is
+    /// named after the agent `sender` belongs to, if it has a name.
+    pub fn new(sender: Sender<Box<Message>>, content: impl Into<Content>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            name: sender.target().and_then(|target| target.name.clone()),
+            sender,
+            role: Role::User,
+            content: content.into(),
+            timestamp: SystemTime::now(),
+            stream: None,
+            response_format: None,
+            system_prompt: None,
+            conversation: None,
+        }
+    }
+
+    /// Set the role of whoever wrote the message.
+    pub fn with_role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// Set the name of whoever wrote the message.
+    pub fn with_name(mut self, name: impl ToString) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Stream output to `stream` while the message is handled.
+    pub fn with_stream(mut self, stream: Sender<StreamEvent>) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    /// Give the recipient `prompt` as instructions for replying.
+    pub fn with_system_prompt(mut self, prompt: impl ToString) -> Self {
+        self.system_prompt = Some(prompt.to_string());
+        self
+    }
+
+    /// Make the message part of the conversation with `id`.
+    pub fn with_conversation(mut self, id: impl ToString) -> Self {
+        self.conversation = Some(id.to_string());
+        self
+    }
+
+    /// Constrain the reply's content to `format`.
+    pub fn with_response_format(mut self, format: ResponseFormat) -> Self {
+        self.response_format = Some(format);
+        self
+    }
+
+    /// Returns the message as an entry in a model's conversation history.
+    /// Images are described in text, since history entries only hold text.
+    pub fn to_history(&self) -> HistoryMessage {
+        match &self.content {
+            Content::ToolCalls(calls) => HistoryMessage {
+                tool_calls: calls.clone(),
+                ..HistoryMessage::new(Role::Assistant, "")
+            },
+            Content::ToolResult { call_id, result } => HistoryMessage::tool_result(call_id, result),
+            content => HistoryMessage::new(self.role, content),
+        }
+    }
+
+    /// Report progress on a long-running tool to the sender, if it's
+    /// listening. Reports are best effort and dropped if the stream is full.
+    pub fn report_progress(&self, tool: impl ToString, percent: Option<u8>, status: impl ToString) {
+        if let Some(stream) = &self.stream {
+            let _ = stream.try_send(StreamEvent::Progress(ToolProgress {
+                tool: tool.to_string(),
+                percent: percent.map(|percent| percent.min(100)),
+                status: status.to_string(),
+            }));
+        }
+    }
+
+    /// Share a piece of reasoning with the sender, if it's listening.
+    /// Thoughts are kept apart from the reply, so they don't end up in other
+    /// agents' contexts unless the sender forwards them.
+    pub fn report_thought(&self, thought: impl ToString) {
+        if let Some(stream) = &self.stream {
+            let _ = stream.try_send(StreamEvent::Thought(thought.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::llm::{FunctionCall, ToolKind},
+    };
+
+    #[test]
+    fn test_to_history() {
+        let (sender, _receiver) = crate::agent::channel(None);
+        let call = ToolCall {
+            id: "call_0".to_string(),
+            kind: ToolKind::Function,
+            function: FunctionCall {
+                name: "add".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+
+        let message = Message::new(sender.clone(), "hello").with_role(Role::System);
+        assert_eq!(
+            message.to_history(),
+            HistoryMessage::new(Role::System, "hello")
+        );
+        let message = Message::new(sender.clone(), Content::ToolCalls(vec![call.clone()]));
+        assert_eq!(message.to_history().tool_calls, [call]);
+        assert_eq!(message.content.to_string(), "call add({})");
+        let message = Message::new(
+            sender,
+            Content::ToolResult {
+                call_id: "call_0".to_string(),
+                result: "3".to_string(),
+            },
+        );
+        assert_eq!(
+            message.to_history(),
+            HistoryMessage::tool_result("call_0", "3")
+        );
+    }
+}
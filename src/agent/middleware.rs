@@ -0,0 +1,229 @@
+//! Middleware around message handling. Middleware added to an
+//! [`AgentBuilder`](super::AgentBuilder) wraps the agent's handler, so
+//! cross-cutting concerns such as logging, redaction, metrics or content
+//! filtering are written once for any agent. Each middleware gets the message
+//! before the handler does and decides what happens next: pass it on, changed
+//! or not, through [`Next`]; handle it itself; or drop it. It sees how the
+//! rest of the chain went once [`Next::run`] returns.
+
+use {
+    super::{AgentContext, AgentState},
+    std::{fmt::Debug, future::Future, pin::Pin, sync::Arc},
+};
+
+/// A boxed future returned by [`Middleware::handle`] and [`Next::run`].
+pub type MiddlewareFuture<'a, E> = Pin<Box<dyn Future<Output = Result<(), E>> + Send + 'a>>;
+
+/// The handler behind the rest of a middleware chain.
+type Handler<'a, M, E> = Box<dyn FnOnce(M) -> MiddlewareFuture<'a, E> + Send + 'a>;
+
+/// Wraps an agent's handling of each message.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::agent::{AgentBuilder, AgentContext, Middleware, MiddlewareFuture, Next};
+/// /// Drops messages mentioning spam and redacts email addresses.
+/// struct Filter;
+///
+/// impl<E: Send + 'static> Middleware<String, E> for Filter {
+///     fn handle<'a>(
+///         &'a self,
+///         _context: &'a AgentContext<String>,
+///         message: String,
+///         next: Next<'a, String, E>,
+///     ) -> MiddlewareFuture<'a, E> {
+///         if message.contains("spam") {
+///             return Box::pin(async { Ok(()) });
+///         }
+///         let redacted = message
+///             .split(' ')
+///             .map(|word| if word.contains('@') { "[email]" } else { word })
+///             .collect::<Vec<_>>()
+///             .join(" ");
+///         next.run(redacted)
+///     }
+/// }
+///
+/// # tokio_test::block_on(async {
+/// let agent = AgentBuilder::new()
+///     .with_middleware(Filter)
+///     .spawn(|_sender, line: String| async move {
+///         println!("{line}");
+///         Ok::<_, std::io::Error>(())
+///     });
+/// agent.send("write to jane@example.com".to_string()).await?;
+/// # anyhow::Ok(())
+/// # });
+/// ```
+pub trait Middleware<M, E>: Send + Sync + 'static {
+    /// Handle `message`, usually by passing it on through `next`.
+    fn handle<'a>(
+        &'a self,
+        context: &'a AgentContext<M>,
+        message: M,
+        next: Next<'a, M, E>,
+    ) -> MiddlewareFuture<'a, E>;
+}
+
+/// The rest of a middleware chain: the middleware after the current one, then
+/// the agent's handler.
+pub struct Next<'a, M, E> {
+    middleware: &'a [Arc<dyn Middleware<M, E>>],
+    context: &'a AgentContext<M>,
+    handler: Handler<'a, M, E>,
+}
+
+impl<'a, M: 'static, E: 'static> Next<'a, M, E> {
+    /// Pass `message` on to the rest of the chain.
+    pub fn run(self, message: M) -> MiddlewareFuture<'a, E> {
+        match self.middleware.split_first() {
+            Some((first, rest)) => {
+                let next = Next {
+                    middleware: rest,
+                    context: self.context,
+                    handler: self.handler,
+                };
+                first.handle(self.context, message, next)
+            }
+            None => (self.handler)(message),
+        }
+    }
+}
+
+/// Logs each message an agent handles, and whether handling it failed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Logging;
+
+impl<M, E> Middleware<M, E> for Logging
+where
+    M: Debug + Send + 'static,
+    E: std::error::Error + Send + 'static,
+{
+    fn handle<'a>(
+        &'a self,
+        context: &'a AgentContext<M>,
+        message: M,
+        next: Next<'a, M, E>,
+    ) -> MiddlewareFuture<'a, E> {
+        Box::pin(async move {
+            let (id, name) = (context.id, context.name.as_deref());
+            tracing::info!(%id, name, ?message, "handling message");
+            let result = next.run(message).await;
+            if let Err(e) = &result {
+                tracing::warn!(%id, name, error = %e, "unable to handle message");
+            }
+            result
+        })
+    }
+}
+
+/// Middleware in the order it wraps a handler, outermost first.
+pub(crate) struct Chain<M, E>(pub(crate) Vec<Arc<dyn Middleware<M, E>>>);
+
+impl<M, E> Default for Chain<M, E> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<M, E> Debug for Chain<M, E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Chain({} middleware)", self.0.len())
+    }
+}
+
+/// Handles messages with `state`, through `chain`.
+pub(crate) struct Layered<M, E, S> {
+    pub(crate) chain: Chain<M, E>,
+    pub(crate) state: S,
+}
+
+impl<M, E, S> AgentState<M> for Layered<M, E, S>
+where
+    M: Send + 'static,
+    E: Send + 'static,
+    S: AgentState<M, Error = E>,
+{
+    type Error = E;
+
+    async fn handle(&mut self, context: &AgentContext<M>, message: M) -> Result<(), E> {
+        let state = &mut self.state;
+        let next = Next {
+            middleware: &self.chain.0,
+            context,
+            handler: Box::new(move |message| Box::pin(state.handle(context, message))),
+        };
+        next.run(message).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::agent::{AgentBuilder, SendError},
+        anyhow::Result,
+        std::sync::Mutex,
+    };
+
+    /// Drops messages that mention spam.
+    struct Filter;
+
+    impl<E: Send + 'static> Middleware<String, E> for Filter {
+        fn handle<'a>(
+            &'a self,
+            _context: &'a AgentContext<String>,
+            message: String,
+            next: Next<'a, String, E>,
+        ) -> MiddlewareFuture<'a, E> {
+            if message.contains("spam") {
+                return Box::pin(async { Ok(()) });
+            }
+            next.run(message)
+        }
+    }
+
+    /// Marks each message with a tag, and records the order it ran in.
+    struct Tag(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl<E: Send + 'static> Middleware<String, E> for Tag {
+        fn handle<'a>(
+            &'a self,
+            _context: &'a AgentContext<String>,
+            message: String,
+            next: Next<'a, String, E>,
+        ) -> MiddlewareFuture<'a, E> {
+            Box::pin(async move {
+                self.1.lock().unwrap().push(format!("before {}", self.0));
+                let result = next.run(format!("{message} [{}]", self.0)).await;
+                self.1.lock().unwrap().push(format!("after {}", self.0));
+                result
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware_chain() -> Result<()> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (handled, mut received) = crate::agent::channel::<String>(None);
+        let agent = AgentBuilder::<String, SendError<String>>::new()
+            .with_middleware(Logging)
+            .with_middleware(Filter)
+            .with_middleware(Tag("a", log.clone()))
+            .with_middleware(Tag("b", log.clone()))
+            .spawn(move |_, message| {
+                let handled = handled.clone();
+                async move { handled.send(message).await }
+            });
+
+        agent.send("spam".to_string()).await?;
+        agent.send("hello".to_string()).await?;
+        assert_eq!(received.recv().await.as_deref(), Some("hello [a] [b]"));
+        agent.terminate().await;
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["before a", "before b", "after b", "after a"]
+        );
+        Ok(())
+    }
+}
@@ -1,24 +1,270 @@
 //! Tools for creating tokio-based agents.
 
-use std::future::Future;
+use std::{future::Future, task::Poll};
 
 mod actor;
+mod broadcast;
+mod builder;
+mod children;
+mod clock;
+mod console;
+mod mailbox;
+mod message;
+mod middleware;
+mod schedule;
+mod state;
+mod stream;
 
-pub use actor::Actor;
+pub(crate) use mailbox::channel;
+pub use {
+    actor::Actor,
+    broadcast::Broadcast,
+    builder::{AgentBuilder, AgentContext},
+    clock::{Clock, ManualClock, Sleep, SystemClock},
+    mailbox::{SendTimeoutError, Sender, TrySendError},
+    message::{Content, Message},
+    middleware::{Logging, Middleware, MiddlewareFuture, Next},
+    schedule::Scheduled,
+    state::AgentState,
+    stream::ReplyStream,
+};
 pub mod assistant;
+pub mod bus;
+pub mod conversable;
+pub mod dead_letter;
+pub mod directory;
+pub mod escalation;
+pub mod pool;
+pub mod priority;
+pub mod registry;
+pub mod supervisor;
+pub mod system;
 pub mod user;
 
 use {
-    std::{fmt::Debug, time::Duration},
-    tokio::{sync::mpsc::UnboundedSender, task::JoinHandle},
+    builder::Hooks,
+    children::Children,
+    dead_letter::DeadLetter,
+    mailbox::Envelope,
+    state::FnHandler,
+    std::{
+        any::Any,
+        fmt::Debug,
+        panic::AssertUnwindSafe,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, OnceLock,
+        },
+        time::Duration,
+    },
+    tokio::{
+        sync::{oneshot, watch},
+        task::{JoinError, JoinHandle},
+    },
+    tracing::Instrument,
     uuid::Uuid,
 };
 
-/// Error returned when trying to send a message to an agent that has been
-/// terminated. Returns the message that couldn't be sent.
-#[derive(thiserror::Error, Debug, PartialEq, Eq, Clone, Copy)]
-#[error("unable to send message to terminated agent: {0:?}")]
-pub struct SendError<M>(pub M);
+/// Error returned when a message can't be delivered to an agent. Returns the
+/// message that couldn't be sent, along with which agent it was for and why
+/// it couldn't be delivered, so callers can log or retry sensibly.
+///
+/// A full mailbox only fails [`Sender::try_send`] and
+/// [`Sender::send_timeout`], which report it with their own errors.
+#[derive(thiserror::Error, Debug, PartialEq, Eq, Clone)]
+pub struct SendError<M> {
+    /// The message that couldn't be sent.
+    pub message: M,
+
+    /// The agent the message was for, if the mailbox belongs to an agent.
+    pub target: Option<AgentRef>,
+
+    /// Why the message couldn't be delivered.
+    pub reason: SendFailure,
+}
+
+impl<M> SendError<M> {
+    /// Create an error for a message sent to a closed mailbox that doesn't
+    /// belong to an agent.
+    pub fn new(message: M) -> Self {
+        Self {
+            message,
+            target: None,
+            reason: SendFailure::Closed,
+        }
+    }
+}
+
+impl<M: Debug> std::fmt::Display for SendError<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unable to send message")?;
+        if let Some(target) = &self.target {
+            write!(f, " to agent {target}")?;
+        }
+        write!(f, " ({}): {:?}", self.reason, self.message)
+    }
+}
+
+/// Why a message couldn't be delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendFailure {
+    /// The agent was terminated or aborted.
+    Terminated,
+
+    /// The mailbox was closed otherwise, e.g. because the agent's handler
+    /// failed or the receiver was dropped.
+    Closed,
+}
+
+impl std::fmt::Display for SendFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Terminated => "agent terminated",
+            Self::Closed => "mailbox closed",
+        })
+    }
+}
+
+/// Identifies an agent in errors and logs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentRef {
+    /// The agent's unique identifier.
+    pub id: Uuid,
+
+    /// The agent's name, if it has one.
+    pub name: Option<String>,
+}
+
+impl std::fmt::Display for AgentRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{name} ({})", self.id),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+/// Error returned when asking an agent for a reply.
+#[derive(thiserror::Error, Debug, PartialEq, Eq, Clone)]
+pub enum AskError<M> {
+    /// The request couldn't be delivered because the agent was terminated.
+    #[error("unable to send request: {0}")]
+    SendError(#[from] SendError<M>),
+
+    /// The agent dropped the reply handle without replying.
+    #[error("agent dropped the request without replying")]
+    NoReply,
+
+    /// The agent didn't reply within the allotted time.
+    #[error("timed out waiting for a reply")]
+    Timeout,
+}
+
+/// How an agent's event loop ended when it was terminated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    /// The agent handled the messages left in its mailbox and stopped within
+    /// the grace period.
+    Graceful,
+
+    /// The agent didn't stop within the grace period and was aborted.
+    Forced,
+}
+
+/// Whether an agent's event loop is still running, and how it ended if not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// The agent is handling messages.
+    Running,
+
+    /// The agent's mailbox was closed and it stopped.
+    Stopped,
+
+    /// The agent's handler returned an error or panicked, which stopped the
+    /// agent.
+    Failed(String),
+
+    /// The agent's cancellation token was cancelled, which stopped the agent.
+    Cancelled,
+
+    /// The agent was aborted.
+    Aborted,
+}
+
+/// A panic in an agent's handler, as reported to
+/// [`AgentBuilder::on_panic`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentFailure {
+    /// The agent whose handler panicked.
+    pub id: Uuid,
+
+    /// The agent's name.
+    pub name: Option<String>,
+
+    /// What the handler panicked with.
+    pub panic: String,
+
+    /// Whether the agent carries on with its next message, with
+    /// [panic isolation](AgentBuilder::with_panic_isolation).
+    pub recovered: bool,
+}
+
+/// Why handling a message stopped an agent.
+enum Stop<E> {
+    /// The handler returned an error.
+    Failed(E),
+
+    /// The handler panicked with this payload.
+    Panicked(Box<dyn Any + Send>),
+}
+
+impl<E: std::fmt::Display> Stop<E> {
+    fn describe(&self) -> String {
+        match self {
+            Self::Failed(e) => e.to_string(),
+            Self::Panicked(payload) => format!("panicked: {}", panic_message(&**payload)),
+        }
+    }
+}
+
+/// Extracts the message from a panic payload.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "handler panicked".to_string())
+}
+
+/// Polls `future`, catching its panics.
+async fn catch_unwind<F: Future>(future: F) -> Result<F::Output, Box<dyn Any + Send>> {
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(|cx| {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    })
+    .await
+}
+
+/// Where an agent is in its life, for schedulers and dashboards to watch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lifecycle {
+    /// The agent's start hook is running.
+    Starting,
+
+    /// The agent is taking messages.
+    Running,
+
+    /// The agent was told to terminate, and is handling the messages left
+    /// in its mailbox.
+    Draining,
+
+    /// The agent's event loop ended.
+    Stopped,
+}
 
 /// The AGENT_GRACE_PERIOD_SECONDS environment variable can be used to override
 /// the default grace period.
@@ -27,25 +273,55 @@ const GRACE_PERIOD_ENV_VAR: &str = "AGENT_GRACE_PERIOD_SECONDS";
 /// The amount of time to wait for an agent to terminate.
 const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(3);
 
-/// Messages that can be sent to an actor;.
-#[derive(Debug, Clone)]
-pub struct Message {
-    /// The sender to reply to.
-    pub sender: Sender<Box<Message>>,
+/// Returns how long a terminated agent has to finish its remaining messages.
+fn grace_period() -> Duration {
+    std::env::var(GRACE_PERIOD_ENV_VAR)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_GRACE_PERIOD)
+}
+
+/// Output streamed by an agent while it generates a reply. The reply itself
+/// is still delivered as a [`Message`] once it's complete.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    /// A piece of the reply being generated.
+    Token(String),
+
+    /// A piece of the reasoning behind the reply, e.g. from a model that
+    /// exposes its reasoning. It is not part of the reply.
+    Thought(String),
+
+    /// Progress on a long-running tool.
+    Progress(ToolProgress),
 
-    /// The content of the to prompt the user.
-    pub content: String,
+    /// The reply is complete. Carries the reply's full text.
+    Complete(String),
 }
 
-/// A channel to send messages to an agent.
-#[derive(Debug, Clone)]
-pub struct Sender<M>(UnboundedSender<M>);
+/// Progress on a long-running tool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolProgress {
+    /// The name of the tool.
+    pub tool: String,
+
+    /// How far along the tool is, from 0 to 100, if it knows.
+    pub percent: Option<u8>,
+
+    /// What the tool is doing, e.g. "installing dependencies".
+    pub status: String,
+}
 
-impl<M> Sender<M> {
-    /// Send a message to the agent.
-    pub fn send(&self, message: M) -> Result<(), SendError<M>> {
-        // map the tokio SendError to our own SendError
-        self.0.send(message).map_err(|m| SendError(m.0))
+/// A single-use handle for replying to a request made with [`Sender::ask`].
+#[derive(Debug)]
+pub struct ReplyTo<R>(oneshot::Sender<R>);
+
+impl<R> ReplyTo<R> {
+    /// Reply to the request. Returns the reply if the requester is no longer
+    /// waiting for it.
+    pub fn send(self, reply: R) -> Result<(), SendError<R>> {
+        self.0.send(reply).map_err(SendError::new)
     }
 }
 
@@ -59,7 +335,26 @@ pub struct Agent<M, E> {
     pub name: Option<String>,
 
     /// A channel to send messages to the agent.
-    sender: UnboundedSender<M>,
+    sender: Sender<M>,
+
+    /// Tells the agent's event loop to close its mailbox. The event loop keeps
+    /// senders of its own, so dropping ours isn't enough.
+    shutdown: oneshot::Sender<()>,
+
+    /// How the agent's event loop ended, once it has.
+    outcome: Arc<OnceLock<Status>>,
+
+    /// Where the agent is in its life.
+    lifecycle: watch::Receiver<Lifecycle>,
+
+    /// Whether the agent is handling a message.
+    busy: Arc<AtomicBool>,
+
+    /// The clock the agent's grace period and timeouts are measured on.
+    clock: Arc<dyn Clock>,
+
+    /// The agents spawned by the agent's handler.
+    children: Children,
 
     /// A handle to the agent's event loop.
     handle: JoinHandle<Result<(), E>>,
@@ -70,27 +365,195 @@ where
     M: Debug + Send + 'static,
     E: std::error::Error + Send + Sync + 'static,
 {
-    /// Create a new agent.
+    /// Create a new agent with an unbounded mailbox.
     pub fn spawn<H, R>(id: Uuid, name: Option<String>, handler: H) -> Self
     where
         H: Fn(Sender<M>, M) -> R + Send + Sync + 'static,
         R: Future<Output = Result<(), E>> + Send + 'static,
     {
-        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        Self::spawn_with(
+            id,
+            name,
+            None,
+            None,
+            Hooks::default(),
+            Arc::new(SystemClock),
+            FnHandler(handler),
+        )
+    }
+
+    /// Create a new agent whose mailbox holds at most `capacity` messages.
+    /// Senders wait for space when the mailbox is full, so a slow handler
+    /// applies backpressure to fast producers.
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn spawn_bounded<H, R>(id: Uuid, name: Option<String>, capacity: usize, handler: H) -> Self
+    where
+        H: Fn(Sender<M>, M) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        Self::spawn_with(
+            id,
+            name,
+            Some(capacity),
+            None,
+            Hooks::default(),
+            Arc::new(SystemClock),
+            FnHandler(handler),
+        )
+    }
+
+    /// Create a new agent with an unbounded mailbox whose messages are handled
+    /// by `state`. The agent's event loop owns the state.
+    pub fn spawn_with_state<S>(id: Uuid, name: Option<String>, state: S) -> Self
+    where
+        S: AgentState<M, Error = E>,
+    {
+        Self::spawn_with(
+            id,
+            name,
+            None,
+            None,
+            Hooks::default(),
+            Arc::new(SystemClock),
+            state,
+        )
+    }
+
+    fn spawn_with<S>(
+        id: Uuid,
+        name: Option<String>,
+        capacity: Option<usize>,
+        priority: Option<priority::Ordering<M>>,
+        hooks: Hooks<M, E>,
+        clock: Arc<dyn Clock>,
+        mut state: S,
+    ) -> Self
+    where
+        S: AgentState<M, Error = E>,
+    {
+        let (sender, mut receiver) = match priority {
+            Some(ordering) => mailbox::priority_channel(capacity, ordering, clock.clone()),
+            None => mailbox::channel(capacity),
+        };
+        let mut sender = sender.with_target(AgentRef {
+            id,
+            name: name.clone(),
+        });
+        if let Some(dead_letters) = &hooks.dead_letters {
+            sender = sender.with_dead_letters(dead_letters.recorder());
+        }
+        if hooks.propagate_traces {
+            sender = sender.with_tracing();
+        }
+        let (shutdown, mut shutdown_requested) = oneshot::channel();
+        let outcome = Arc::new(OnceLock::new());
+        let (lifecycle_sender, lifecycle) = watch::channel(Lifecycle::Starting);
+        let busy = Arc::new(AtomicBool::new(false));
+        let children = Children::default();
+        let cancellation = hooks.cancellation.clone().unwrap_or_default();
 
         let handle = {
             let name = name.clone();
             let sender = sender.clone();
+            let outcome = outcome.clone();
+            let busy = busy.clone();
+            let clock = clock.clone();
+            let children = children.clone();
             tokio::spawn(async move {
                 tracing::trace!(name, %id, "starting",);
+                let context = AgentContext {
+                    id,
+                    name: name.clone(),
+                    sender: sender.clone(),
+                    clock,
+                    cancellation: cancellation.clone(),
+                    children,
+                };
+                if let Some(on_start) = &hooks.on_start {
+                    on_start(context.clone()).await;
+                }
+                lifecycle_sender.send_replace(Lifecycle::Running);
 
-                while let Some(message) = receiver.recv().await {
-                    tracing::trace!(name, %id, ?message, "received message");
-                    handler(Sender(sender.clone()), message).await?;
+                let run = async {
+                    let mut closing = false;
+                    loop {
+                        tokio::select! {
+                            envelope = receiver.recv_envelope() => {
+                                let Some(Envelope { message, span }) = envelope else { break };
+                                tracing::trace!(name, %id, ?message, "received message");
+                                busy.store(true, Ordering::Relaxed);
+                                let handling = handle(&mut state, &context, message, &hooks);
+                                let handled = match span {
+                                    Some(sent) => {
+                                        let span = tracing::info_span!("handle", name, %id);
+                                        span.follows_from(&sent);
+                                        handling.instrument(span).await
+                                    }
+                                    None => handling.await,
+                                };
+                                busy.store(false, Ordering::Relaxed);
+                                handled?;
+                            }
+                            // a dropped handle leaves the agent running; only an explicit
+                            // shutdown closes the mailbox
+                            requested = &mut shutdown_requested, if !closing => {
+                                closing = true;
+                                if requested.is_ok() {
+                                    tracing::trace!(name, %id, "closing mailbox");
+                                    lifecycle_sender.send_replace(Lifecycle::Draining);
+                                    receiver.close();
+                                }
+                            }
+                        }
+                    }
+                    Ok(())
+                };
+                let (result, cancelled): (Result<(), Stop<E>>, _) = tokio::select! {
+                    result = run => (result, false),
+                    // drops the message being handled, and what it's waiting on
+                    () = cancellation.cancelled() => (Ok(()), true),
+                };
+
+                if cancelled {
+                    tracing::trace!(name, %id, "cancelled");
+                    sender.mark_terminated();
+                    receiver.close();
+                    while let Some(message) = receiver.try_recv() {
+                        sender.dead_letter(&message, dead_letter::Reason::Cancelled);
+                    }
+                }
+                if let Err(stop) = &result {
+                    // nothing will handle what's left in the mailbox
+                    receiver.close();
+                    let reason = stop.describe();
+                    while let Some(message) = receiver.try_recv() {
+                        sender
+                            .dead_letter(&message, dead_letter::Reason::Unhandled(reason.clone()));
+                    }
+                    if let (Stop::Failed(e), Some(on_error)) = (stop, &hooks.on_error) {
+                        on_error(context.clone(), e).await;
+                    }
+                }
+                // the children can't outlive their parent
+                context.children.terminate().await;
+                if let Some(on_stop) = &hooks.on_stop {
+                    on_stop(context).await;
                 }
 
                 tracing::trace!(name, %id, "stopping");
-                Ok(())
+                let _ = outcome.set(match &result {
+                    Ok(()) if cancelled => Status::Cancelled,
+                    Ok(()) => Status::Stopped,
+                    Err(stop) => Status::Failed(stop.describe()),
+                });
+                lifecycle_sender.send_replace(Lifecycle::Stopped);
+                match result {
+                    Ok(()) => Ok(()),
+                    Err(Stop::Failed(e)) => Err(e),
+                    // so joining the agent reports the panic
+                    Err(Stop::Panicked(payload)) => std::panic::resume_unwind(payload),
+                }
             })
         };
 
@@ -98,43 +561,248 @@ where
             id,
             name,
             sender,
+            shutdown,
+            outcome,
+            lifecycle,
+            busy,
+            clock,
+            children,
             handle,
         }
     }
 
-    /// Terminates the agent by closing its message channel and waiting for it
-    /// to finish processing remaining messages. Consumes the agent since it
-    /// can no longer process messages.
-    pub async fn terminate(self) {
-        drop(self.sender); // drop the sender to signal the agent to stop.
-        tokio::time::sleep(
-            std::env::var(GRACE_PERIOD_ENV_VAR)
-                .ok()
-                .and_then(|s| s.parse().ok())
-                .map(Duration::from_secs)
-                .unwrap_or(DEFAULT_GRACE_PERIOD),
-        )
-        .await;
-        self.handle.abort();
-        tracing::trace!(name = self.name, id = %self.id, "stopped (gracefully terminated)");
+    /// Wait for the agent's event loop to end and return the result it ended
+    /// with. The event loop keeps its own senders, so it runs until its
+    /// handler fails; use [`Agent::terminate`] to stop a healthy agent. Fails
+    /// if the agent panicked.
+    pub async fn join(self) -> Result<Result<(), E>, JoinError> {
+        self.handle.await
+    }
+
+    /// Returns whether the agent's event loop has ended.
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Returns whether the agent is running, and how it ended if not.
+    pub fn status(&self) -> Status {
+        match self.outcome.get() {
+            Some(status) => status.clone(),
+            None if self.handle.is_finished() => Status::Aborted,
+            None => Status::Running,
+        }
+    }
+
+    /// Returns where the agent is in its life.
+    pub fn lifecycle(&self) -> Lifecycle {
+        // an aborted event loop never says it stopped
+        if self.handle.is_finished() {
+            return Lifecycle::Stopped;
+        }
+        *self.lifecycle.borrow()
+    }
+
+    /// Returns a receiver that's told each time the agent moves on in its
+    /// life. If the agent is aborted, the receiver's sender is dropped
+    /// instead of it being told the agent stopped.
+    pub fn watch_lifecycle(&self) -> watch::Receiver<Lifecycle> {
+        self.lifecycle.clone()
+    }
+
+    /// Returns the number of messages waiting in the agent's mailbox, not
+    /// counting the one it's handling.
+    pub fn mailbox_len(&self) -> usize {
+        self.sender.len()
+    }
+
+    /// Returns whether the agent is running with nothing to do: it isn't
+    /// handling a message and none are waiting.
+    pub fn is_idle(&self) -> bool {
+        self.lifecycle() == Lifecycle::Running
+            && !self.busy.load(Ordering::Relaxed)
+            && self.sender.is_empty()
+    }
+
+    /// Terminates the agent by closing its mailbox and waiting up to the grace
+    /// period for it to finish processing remaining messages. Returns as soon
+    /// as the agent stops; an agent that's still busy when the grace period
+    /// ends is aborted. Consumes the agent since it can no longer process
+    /// messages.
+    pub async fn terminate(self) -> Shutdown {
+        let Self {
+            id,
+            name,
+            sender,
+            shutdown,
+            clock,
+            children,
+            mut handle,
+            ..
+        } = self;
+        sender.mark_terminated();
+        drop(sender);
+        // the event loop may already have stopped
+        let _ = shutdown.send(());
+
+        match clock::timeout(&*clock, grace_period(), &mut handle).await {
+            Some(_) => {
+                tracing::trace!(name, %id, "stopped (gracefully terminated)");
+                Shutdown::Graceful
+            }
+            None => {
+                handle.abort();
+                // the event loop didn't get to terminate them
+                children.abort();
+                tracing::trace!(name, %id, "stopped (forcefully terminated)");
+                Shutdown::Forced
+            }
+        }
     }
 
     /// Aborts the agent's event loop immediately without waiting for it to
     /// finish.
     pub fn abort(self) {
+        self.sender.mark_terminated();
         self.handle.abort();
+        self.children.abort();
         tracing::trace!(name = self.name, id = %self.id, "stopped (aborted)");
     }
 
-    /// Send a message to the agent.
-    pub fn send(&self, message: M) -> Result<(), SendError<M>> {
-        self.sender.send(message).map_err(|e| SendError(e.0))
+    /// Send a message to the agent. Waits for space if the agent's mailbox is
+    /// full.
+    pub async fn send(&self, message: M) -> Result<(), SendError<M>> {
+        self.sender.send(message).await
+    }
+
+    /// Send a message to the agent without waiting. See
+    /// [`Sender::try_send`].
+    pub fn try_send(&self, message: M) -> Result<(), TrySendError<M>> {
+        self.sender.try_send(message)
+    }
+
+    /// Send a message to the agent, waiting at most `timeout` for space in
+    /// its mailbox. See [`Sender::send_timeout`].
+    pub async fn send_timeout(
+        &self,
+        message: M,
+        timeout: Duration,
+    ) -> Result<(), SendTimeoutError<M>> {
+        self.sender
+            .send_timeout_on(&*self.clock, message, timeout)
+            .await
     }
 
     /// Returns a sender that can be used to send messages to the agent.
     pub fn sender(&self) -> Sender<M> {
-        Sender(self.sender.clone())
+        self.sender.clone()
+    }
+
+    /// Send a request to the agent and wait for its reply. See
+    /// [`Sender::ask`].
+    pub async fn ask<R, F>(&self, request: F) -> Result<R, AskError<M>>
+    where
+        F: FnOnce(ReplyTo<R>) -> M,
+    {
+        self.sender.ask(request).await
+    }
+
+    /// Send a request to the agent and wait at most `timeout` for its reply.
+    /// See [`Sender::ask_timeout`].
+    pub async fn ask_timeout<R, F>(&self, request: F, timeout: Duration) -> Result<R, AskError<M>>
+    where
+        F: FnOnce(ReplyTo<R>) -> M,
+    {
+        self.sender
+            .ask_timeout_on(&*self.clock, request, timeout)
+            .await
+    }
+}
+
+/// Has `state` handle `message`, catching its panics. With panic isolation
+/// the agent carries on after a panic; otherwise it stops.
+async fn handle<M, E, S>(
+    state: &mut S,
+    context: &AgentContext<M>,
+    message: M,
+    hooks: &Hooks<M, E>,
+) -> Result<(), Stop<E>>
+where
+    M: Debug + Send + 'static,
+    S: AgentState<M, Error = E>,
+{
+    // the handler takes the message, so describe it up front
+    let letter = (hooks.isolate_panics && hooks.dead_letters.is_some()).then(|| {
+        DeadLetter::new(
+            &message,
+            context.sender.target(),
+            dead_letter::Reason::Cancelled,
+        )
+    });
+    #[cfg(feature = "metrics")]
+    let handling =
+        crate::metrics::Handling::start(context.id, context.name.as_deref(), context.sender.len());
+    let handled = catch_unwind(handle_timed(state, context, message, hooks)).await;
+    #[cfg(feature = "metrics")]
+    handling.finish(matches!(handled, Ok(Ok(()))));
+    let payload = match handled {
+        Ok(handled) => return handled.map_err(Stop::Failed),
+        Err(payload) => payload,
+    };
+    let panic = panic_message(&*payload);
+    tracing::error!(name = context.name, id = %context.id, panic, recovered = hooks.isolate_panics, "handler panicked");
+    if let (Some(dead_letters), Some(letter)) = (&hooks.dead_letters, letter) {
+        dead_letters.record(DeadLetter {
+            reason: dead_letter::Reason::Panicked(panic.clone()),
+            ..letter
+        });
+    }
+    if let Some(on_panic) = &hooks.on_panic {
+        let failure = AgentFailure {
+            id: context.id,
+            name: context.name.clone(),
+            panic,
+            recovered: hooks.isolate_panics,
+        };
+        on_panic(context.clone(), failure).await;
+    }
+    match hooks.isolate_panics {
+        true => Ok(()),
+        false => Err(Stop::Panicked(payload)),
+    }
+}
+
+/// Has `state` handle `message`, cancelling it if it takes longer than the
+/// agent's message timeout.
+async fn handle_timed<M, E, S>(
+    state: &mut S,
+    context: &AgentContext<M>,
+    message: M,
+    hooks: &Hooks<M, E>,
+) -> Result<(), E>
+where
+    M: Debug + Send + 'static,
+    S: AgentState<M, Error = E>,
+{
+    let Some(timeout) = hooks.timeout else {
+        return state.handle(context, message).await;
+    };
+    // the handler takes the message, so describe it up front
+    let letter = hooks.dead_letters.as_ref().map(|_| {
+        let reason = dead_letter::Reason::TimedOut(timeout);
+        DeadLetter::new(&message, context.sender.target(), reason)
+    });
+    tokio::select! {
+        handled = state.handle(context, message) => return handled,
+        () = context.clock.sleep(timeout) => {}
     }
+    tracing::warn!(name = context.name, id = %context.id, ?timeout, "handler timed out; moving on to the next message");
+    if let (Some(dead_letters), Some(letter)) = (&hooks.dead_letters, letter) {
+        dead_letters.record(letter);
+    }
+    if let Some(on_timeout) = &hooks.on_timeout {
+        on_timeout(context.clone(), timeout).await;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -161,7 +829,7 @@ mod tests {
         );
 
         let message = "hello world";
-        agent.send(message)?;
+        agent.send(message).await?;
         assert_eq!(rx.recv().await, Some(message));
         Ok(())
     }
@@ -189,18 +857,220 @@ mod tests {
             move |_sender, message| {
                 let agent_1 = agent_1.clone();
                 async move {
-                    agent_1.send(message)?;
+                    agent_1.send(message).await?;
                     Result::<_, Error<&'static str>>::Ok(())
                 }
             },
         );
 
         let message = "hello world";
-        agent_2.send(message)?;
+        agent_2.send(message).await?;
         assert_eq!(rx.recv().await, Some(message));
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_ask() -> Result<()> {
+        let agent = Agent::spawn(
+            Uuid::new_v4(),
+            Some("1".to_string()),
+            move |_sender, (n, reply_to): (u32, ReplyTo<u32>)| async move {
+                reply_to.send(n * 2)?;
+                Result::<_, Error<_>>::Ok(())
+            },
+        );
+
+        assert_eq!(agent.ask(|reply_to| (21, reply_to)).await?, 42);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ask_timeout() -> Result<()> {
+        let agent = Agent::spawn(
+            Uuid::new_v4(),
+            Some("1".to_string()),
+            move |_sender, reply_to: ReplyTo<()>| async move {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                reply_to.send(())?;
+                Result::<_, Error<_>>::Ok(())
+            },
+        );
+
+        let result = agent
+            .ask_timeout(|reply_to| reply_to, Duration::from_millis(50))
+            .await;
+        assert!(matches!(result, Err(AskError::Timeout)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ask_no_reply() -> Result<()> {
+        let agent = Agent::spawn(
+            Uuid::new_v4(),
+            Some("1".to_string()),
+            move |_sender, reply_to: ReplyTo<()>| async move {
+                drop(reply_to);
+                Result::<_, Error<()>>::Ok(())
+            },
+        );
+
+        let result = agent.ask(|reply_to| reply_to).await;
+        assert!(matches!(result, Err(AskError::NoReply)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bounded_mailbox_backpressure() -> Result<()> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let release = std::sync::Arc::new(tokio::sync::Notify::new());
+
+        let agent = Agent::spawn_bounded(Uuid::new_v4(), Some("1".to_string()), 1, {
+            let release = release.clone();
+            move |_sender, message| {
+                let tx = tx.clone();
+                let release = release.clone();
+                async move {
+                    release.notified().await;
+                    tx.send(message)?;
+                    Result::<_, TokioSendError<_>>::Ok(())
+                }
+            }
+        });
+
+        // the first message is picked up by the handler, the second fills the
+        // mailbox
+        agent.send(1).await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        agent.send(2).await?;
+        assert_eq!(agent.mailbox_len(), 1);
+
+        assert_eq!(agent.try_send(3), Err(TrySendError::Full(3)));
+        assert_eq!(
+            agent.send_timeout(3, Duration::from_millis(50)).await,
+            Err(SendTimeoutError::Timeout(3))
+        );
+        assert_eq!(agent.mailbox_len(), 1);
+
+        release.notify_one();
+        assert_eq!(rx.recv().await, Some(1));
+        agent.send_timeout(3, Duration::from_millis(500)).await?;
+
+        release.notify_one();
+        release.notify_one();
+        assert_eq!(rx.recv().await, Some(2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mailbox_introspection() -> Result<()> {
+        let release = Arc::new(tokio::sync::Notify::new());
+        let agent = Agent::spawn(Uuid::new_v4(), Some("1".to_string()), {
+            let release = release.clone();
+            move |_sender, _message: u32| {
+                let release = release.clone();
+                async move {
+                    release.notified().await;
+                    Ok::<_, Error<u32>>(())
+                }
+            }
+        });
+        let mut lifecycle = agent.watch_lifecycle();
+        lifecycle
+            .wait_for(|lifecycle| *lifecycle == Lifecycle::Running)
+            .await?;
+        assert!(agent.is_idle());
+
+        // the first message is picked up by the handler, the rest wait
+        for message in 1..=3 {
+            agent.send(message).await?;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(agent.mailbox_len(), 2);
+        assert!(!agent.is_idle());
+
+        for _ in 1..=3 {
+            release.notify_one();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(agent.mailbox_len(), 0);
+        assert!(agent.is_idle());
+
+        agent.terminate().await;
+        assert_eq!(*lifecycle.borrow_and_update(), Lifecycle::Stopped);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_hooks() -> Result<()> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let agent = AgentBuilder::new()
+            .with_name("1")
+            .on_start({
+                let tx = tx.clone();
+                move |context| {
+                    let tx = tx.clone();
+                    async move {
+                        tx.send(format!("start {}", context.name.unwrap_or_default()))
+                            .ok();
+                    }
+                }
+            })
+            .on_error({
+                let tx = tx.clone();
+                move |_context, error: &Error<&'static str>| {
+                    let tx = tx.clone();
+                    let error = error.message;
+                    async move {
+                        tx.send(format!("error {error}")).ok();
+                    }
+                }
+            })
+            .on_stop(move |_context| {
+                let tx = tx.clone();
+                async move {
+                    tx.send("stop".to_string()).ok();
+                }
+            })
+            .spawn(|_sender, message| async move { Err(SendError::new(message)) });
+
+        agent.send("boom").await?;
+        assert_eq!(rx.recv().await.as_deref(), Some("start 1"));
+        assert_eq!(rx.recv().await.as_deref(), Some("error boom"));
+        assert_eq!(rx.recv().await.as_deref(), Some("stop"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stateful_handler() -> Result<()> {
+        /// Remembers every message and replies with all of them.
+        struct History(Vec<&'static str>);
+
+        impl AgentState<(&'static str, ReplyTo<Vec<&'static str>>)> for History {
+            type Error = Error<Vec<&'static str>>;
+
+            async fn handle(
+                &mut self,
+                _context: &AgentContext<(&'static str, ReplyTo<Vec<&'static str>>)>,
+                (message, reply_to): (&'static str, ReplyTo<Vec<&'static str>>),
+            ) -> Result<(), Self::Error> {
+                self.0.push(message);
+                reply_to.send(self.0.clone())
+            }
+        }
+
+        let agent = AgentBuilder::new()
+            .with_name("1")
+            .spawn_with_state(History(Vec::new()));
+
+        agent.ask(|reply_to| ("hello", reply_to)).await?;
+        assert_eq!(
+            agent.ask(|reply_to| ("world", reply_to)).await?,
+            ["hello", "world"]
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_terminate() -> Result<()> {
         let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
@@ -218,8 +1088,13 @@ mod tests {
         );
 
         let message = "hello world";
-        agent.send(message)?;
-        agent.terminate().await;
+        agent.send(message).await?;
+        let started = std::time::Instant::now();
+        assert_eq!(agent.terminate().await, Shutdown::Graceful);
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "testing that terminate doesn't wait out the grace period"
+        );
 
         assert_eq!(
             rx.recv().await,
@@ -251,8 +1126,8 @@ mod tests {
         );
 
         let message = "hello world";
-        agent.send(message)?;
-        agent.terminate().await;
+        agent.send(message).await?;
+        assert_eq!(agent.terminate().await, Shutdown::Forced);
 
         assert_eq!(
             rx.recv().await,
@@ -263,6 +1138,131 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_join_handler_error() -> Result<()> {
+        let agent = Agent::spawn(
+            Uuid::new_v4(),
+            Some("1".to_string()),
+            move |_sender, message: &'static str| async move { Err(SendError::new(message)) },
+        );
+        assert_eq!(agent.status(), Status::Running);
+
+        agent.send("fail").await?;
+        while !agent.is_finished() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            agent.status(),
+            Status::Failed(SendError::new("fail").to_string())
+        );
+        assert_eq!(agent.join().await?, Err(SendError::new("fail")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_error_context() -> Result<()> {
+        let id = Uuid::new_v4();
+        let agent = Agent::spawn(
+            id,
+            Some("failing".to_string()),
+            |_sender, message: u32| async move { Err(SendError::new(message)) },
+        );
+        let sender = agent.sender();
+        agent.send(1).await?;
+        while !agent.is_finished() {
+            tokio::task::yield_now().await;
+        }
+        let target = Some(AgentRef {
+            id,
+            name: Some("failing".to_string()),
+        });
+        assert_eq!(
+            sender.send(2).await,
+            Err(SendError {
+                message: 2,
+                target: target.clone(),
+                reason: SendFailure::Closed,
+            })
+        );
+
+        let agent = Agent::spawn(
+            id,
+            Some("failing".to_string()),
+            |_sender, message: u32| async move { Err(SendError::new(message)) },
+        );
+        let sender = agent.sender();
+        agent.terminate().await;
+        let error = sender.send(3).await.unwrap_err();
+        assert_eq!(error.reason, SendFailure::Terminated);
+        assert_eq!(error.target, target);
+        assert_eq!(
+            error.to_string(),
+            format!("unable to send message to agent failing ({id}) (agent terminated): 3")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_terminate_on_clock() -> Result<()> {
+        let clock = ManualClock::new();
+        let agent = AgentBuilder::new()
+            .with_clock(clock.clone())
+            .spawn(|_sender, ()| async {
+                std::future::pending::<()>().await;
+                Result::<_, Error<()>>::Ok(())
+            });
+        agent.send(()).await?;
+
+        let terminated = tokio::spawn(agent.terminate());
+        tokio::task::yield_now().await;
+        assert!(!terminated.is_finished());
+
+        clock.advance(DEFAULT_GRACE_PERIOD);
+        assert_eq!(terminated.await?, Shutdown::Forced);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_timeout() -> Result<()> {
+        let clock = ManualClock::new();
+        let dead_letters = dead_letter::DeadLetters::new();
+        let (timed_out, mut timeouts) = tokio::sync::mpsc::unbounded_channel();
+        let (handled, mut handled_messages) = tokio::sync::mpsc::unbounded_channel();
+        let agent = AgentBuilder::new()
+            .with_clock(clock.clone())
+            .with_dead_letters(dead_letters.clone())
+            .with_message_timeout(Duration::from_secs(30))
+            .on_timeout(move |_context, timeout| {
+                let _ = timed_out.send(timeout);
+                async {}
+            })
+            .spawn(move |_sender, message: &'static str| {
+                let handled = handled.clone();
+                async move {
+                    if message == "stuck" {
+                        std::future::pending::<()>().await;
+                    }
+                    let _ = handled.send(message);
+                    Result::<_, Error<&str>>::Ok(())
+                }
+            });
+        agent.send("stuck").await?;
+        agent.send("next").await?;
+        tokio::task::yield_now().await;
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(timeouts.recv().await, Some(Duration::from_secs(30)));
+        assert_eq!(handled_messages.recv().await, Some("next"));
+        let letters = dead_letters.letters_for(agent.id);
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].message, "\"stuck\"");
+        assert_eq!(
+            letters[0].reason,
+            dead_letter::Reason::TimedOut(Duration::from_secs(30))
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_abort() -> Result<()> {
         let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
@@ -280,7 +1280,7 @@ mod tests {
         );
 
         let message = "hello world";
-        agent.send(message)?;
+        agent.send(message).await?;
         tokio::time::sleep(Duration::from_millis(100)).await;
         agent.abort();
 
@@ -292,4 +1292,193 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_children() -> Result<()> {
+        /// Spawns a child for each request, and replies with its sender.
+        struct Parent;
+
+        impl AgentState<ReplyTo<Sender<u32>>> for Parent {
+            type Error = Error<Sender<u32>>;
+
+            async fn handle(
+                &mut self,
+                context: &AgentContext<ReplyTo<Sender<u32>>>,
+                reply_to: ReplyTo<Sender<u32>>,
+            ) -> Result<(), Self::Error> {
+                let child = context.spawn_child(None, |_sender, _message: u32| async {
+                    Result::<_, TokioSendError<u32>>::Ok(())
+                });
+                reply_to.send(child)
+            }
+        }
+
+        let closed = |children: Vec<Sender<u32>>| async move {
+            tokio::time::timeout(Duration::from_secs(1), async {
+                while !children.iter().all(Sender::is_closed) {
+                    tokio::task::yield_now().await;
+                }
+            })
+            .await
+        };
+
+        let parent = Agent::spawn_with_state(Uuid::new_v4(), None, Parent);
+        let children = vec![
+            parent.ask(|reply_to| reply_to).await?,
+            parent.ask(|reply_to| reply_to).await?,
+        ];
+        children[0].send(1).await?;
+        assert_eq!(parent.terminate().await, Shutdown::Graceful);
+        closed(children).await?;
+
+        let parent = Agent::spawn_with_state(Uuid::new_v4(), None, Parent);
+        let child = parent.ask(|reply_to| reply_to).await?;
+        parent.abort();
+        closed(vec![child]).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancellation() -> Result<()> {
+        /// Spawns a child, sends it back and then waits forever, like a
+        /// stuck call to a model.
+        struct Stuck(tokio::sync::mpsc::UnboundedSender<Sender<u32>>);
+
+        impl AgentState<u32> for Stuck {
+            type Error = Error<u32>;
+
+            async fn handle(
+                &mut self,
+                context: &AgentContext<u32>,
+                _message: u32,
+            ) -> Result<(), Self::Error> {
+                let child = context.spawn_child(None, |_sender, _message: u32| async {
+                    Result::<_, TokioSendError<u32>>::Ok(())
+                });
+                let _ = self.0.send(child);
+                std::future::pending().await
+            }
+        }
+
+        let token = tokio_util::sync::CancellationToken::new();
+        let dead_letters = dead_letter::DeadLetters::new();
+        let (children, mut spawned) = tokio::sync::mpsc::unbounded_channel();
+        let agent = AgentBuilder::new()
+            .with_cancellation(token.child_token())
+            .with_dead_letters(dead_letters.clone())
+            .spawn_with_state(Stuck(children));
+        agent.send(1).await?;
+        agent.send(2).await?;
+        let child = spawned.recv().await.unwrap();
+
+        token.cancel();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !agent.is_finished() || !child.is_closed() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await?;
+        assert_eq!(agent.status(), Status::Cancelled);
+        let letters = dead_letters.letters_for(agent.id);
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].reason, dead_letter::Reason::Cancelled);
+        assert!(agent.send(3).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_trace_propagation() -> Result<()> {
+        use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+        /// Keeps the names of each span and the span it follows from.
+        #[derive(Clone, Default)]
+        struct Links(Arc<std::sync::Mutex<Vec<(&'static str, &'static str)>>>);
+
+        impl<S: tracing::Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Links {
+            fn on_follows_from(
+                &self,
+                span: &tracing::Id,
+                follows: &tracing::Id,
+                ctx: Context<'_, S>,
+            ) {
+                let name = |id| ctx.span(id).map_or("", |span| span.name());
+                self.0.lock().unwrap().push((name(span), name(follows)));
+            }
+        }
+
+        let links = Links::default();
+        // the test runs on one thread, agents included
+        let _subscriber = tracing_subscriber::registry()
+            .with(links.clone())
+            .set_default();
+        let handler = |_sender, _message: u32| async { Ok::<_, TokioSendError<u32>>(()) };
+        let traced = AgentBuilder::new().with_trace_propagation().spawn(handler);
+        let untraced = AgentBuilder::new().spawn(handler);
+        async {
+            traced.send(1).await?;
+            untraced.send(1).await
+        }
+        .instrument(tracing::info_span!("conversation"))
+        .await?;
+        traced.terminate().await;
+        untraced.terminate().await;
+
+        assert_eq!(*links.0.lock().unwrap(), [("handle", "conversation")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_panic_isolation() -> Result<()> {
+        let dead_letters = dead_letter::DeadLetters::new();
+        let (failures, mut reported) = tokio::sync::mpsc::unbounded_channel();
+        let (handled, mut handled_messages) = tokio::sync::mpsc::unbounded_channel();
+        let agent = AgentBuilder::new()
+            .with_name("isolated")
+            .with_panic_isolation()
+            .with_dead_letters(dead_letters.clone())
+            .on_panic(move |_context, failure| {
+                let _ = failures.send(failure);
+                async {}
+            })
+            .spawn(move |_sender, message: u32| {
+                let handled = handled.clone();
+                async move {
+                    if message == 1 {
+                        panic!("can't handle {message}");
+                    }
+                    let _ = handled.send(message);
+                    Ok::<_, TokioSendError<u32>>(())
+                }
+            });
+        agent.send(1).await?;
+        agent.send(2).await?;
+        assert_eq!(handled_messages.recv().await, Some(2));
+        assert_eq!(
+            reported.recv().await,
+            Some(AgentFailure {
+                id: agent.id,
+                name: Some("isolated".to_string()),
+                panic: "can't handle 1".to_string(),
+                recovered: true,
+            })
+        );
+        assert_eq!(agent.status(), Status::Running);
+        let letters = dead_letters.letters_for(agent.id);
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].message, "1");
+        assert!(matches!(
+            letters[0].reason,
+            dead_letter::Reason::Panicked(_)
+        ));
+
+        // without isolation the panic stops the agent
+        let agent = AgentBuilder::new().spawn(|_sender, _message: u32| async {
+            panic!("broken");
+            #[allow(unreachable_code)]
+            Ok::<_, TokioSendError<u32>>(())
+        });
+        agent.send(1).await?;
+        assert!(agent.join().await.is_err_and(|e| e.is_panic()));
+        Ok(())
+    }
 }
@@ -0,0 +1,482 @@
+//! Pools of identical agents. An [`AgentPool`] spawns a number of workers
+//! running the same handler behind a single sender, and routes each message
+//! sent to it to one of the workers according to its [`Routing`]. Workers
+//! whose handler failed are routed around, and the pool stops once none are
+//! left.
+
+use {
+    super::{mailbox, Agent, Sender, Shutdown, Status},
+    std::{
+        collections::hash_map::DefaultHasher,
+        fmt::Debug,
+        future::Future,
+        hash::{Hash, Hasher},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    },
+    tokio::{sync::oneshot, task::JoinHandle},
+    uuid::Uuid,
+};
+
+/// The number of points each worker has on the hash ring, which spreads keys
+/// evenly across the workers.
+const VIRTUAL_NODES: usize = 64;
+
+/// How a pool picks the worker for a message.
+pub enum Routing<M> {
+    /// Each worker in turn.
+    RoundRobin,
+
+    /// The worker with the fewest messages waiting or being handled, for
+    /// messages that take uneven time to handle.
+    LeastOutstanding,
+
+    /// The worker a key of the message hashes to, so messages with the same
+    /// key are handled in order by the same worker. See
+    /// [`Routing::consistent_hash`].
+    ConsistentHash(Arc<dyn Fn(&M) -> u64 + Send + Sync>),
+}
+
+impl<M> Routing<M> {
+    /// Route messages by the key `key` returns for them.
+    pub fn consistent_hash<K, F>(key: F) -> Self
+    where
+        K: Hash,
+        F: Fn(&M) -> K + Send + Sync + 'static,
+    {
+        Self::ConsistentHash(Arc::new(move |message| hash(&key(message))))
+    }
+}
+
+impl<M> Default for Routing<M> {
+    fn default() -> Self {
+        Self::RoundRobin
+    }
+}
+
+impl<M> Debug for Routing<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::RoundRobin => "RoundRobin",
+            Self::LeastOutstanding => "LeastOutstanding",
+            Self::ConsistentHash(_) => "ConsistentHash",
+        })
+    }
+}
+
+/// Returns the hash of `value`.
+fn hash(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A worker, as the router sees it.
+#[derive(Debug)]
+struct Worker<M> {
+    sender: Sender<M>,
+
+    /// The number of messages routed to the worker that it hasn't finished
+    /// handling.
+    outstanding: Arc<AtomicUsize>,
+}
+
+/// Routes the messages sent to a pool to its workers.
+#[derive(Debug)]
+struct Router<M> {
+    workers: Vec<Worker<M>>,
+    routing: Routing<M>,
+
+    /// The workers' points on the hash ring, by hash.
+    ring: Vec<(u64, usize)>,
+
+    /// The next worker in turn.
+    next: usize,
+}
+
+impl<M> Router<M> {
+    fn new(workers: Vec<Worker<M>>, routing: Routing<M>) -> Self {
+        let mut ring = Vec::new();
+        if let Routing::ConsistentHash(_) = routing {
+            ring = (0..workers.len())
+                .flat_map(|worker| (0..VIRTUAL_NODES).map(move |node| (worker, node)))
+                .map(|point| (hash(&point), point.0))
+                .collect();
+            ring.sort_unstable();
+        }
+        Self {
+            workers,
+            routing,
+            ring,
+            next: 0,
+        }
+    }
+
+    /// Returns the worker to route `message` to, skipping stopped workers,
+    /// or None if they all stopped.
+    fn pick(&mut self, message: &M) -> Option<usize> {
+        let live = |worker: &usize| !self.workers[*worker].sender.is_closed();
+        match &self.routing {
+            Routing::RoundRobin => {
+                let count = self.workers.len();
+                let worker = (self.next..self.next + count)
+                    .map(|worker| worker % count)
+                    .find(live)?;
+                self.next = worker + 1;
+                Some(worker)
+            }
+            Routing::LeastOutstanding => (0..self.workers.len())
+                .filter(live)
+                .min_by_key(|worker| self.workers[*worker].outstanding.load(Ordering::Relaxed)),
+            Routing::ConsistentHash(key) => {
+                let key = key(message);
+                // the first point at or after the key, wrapping around
+                let start = self.ring.partition_point(|(point, _)| *point < key);
+                let (lower, upper) = self.ring.split_at(start);
+                upper
+                    .iter()
+                    .chain(lower)
+                    .map(|(_, worker)| *worker)
+                    .find(live)
+            }
+        }
+    }
+
+    /// Route `message` to a worker. Returns the message if every worker
+    /// stopped.
+    async fn route(&mut self, mut message: M) -> Result<(), M> {
+        while let Some(index) = self.pick(&message) {
+            let worker = &self.workers[index];
+            worker.outstanding.fetch_add(1, Ordering::Relaxed);
+            match worker.sender.send(message).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    // the worker stopped since it was picked
+                    worker.outstanding.fetch_sub(1, Ordering::Relaxed);
+                    message = e.message;
+                }
+            }
+        }
+        Err(message)
+    }
+}
+
+/// A pool of identical agents behind one sender.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::agent::pool::{AgentPool, Routing};
+/// # tokio_test::block_on(async {
+/// // requests for the same user go to the same worker, in order
+/// let pool = AgentPool::spawn_with_routing(
+///     4,
+///     Routing::consistent_hash(|(user, _): &(u32, String)| *user),
+///     |_sender, (user, request): (u32, String)| async move {
+///         println!("handling {request:?} for user {user}");
+///         Ok::<_, std::fmt::Error>(())
+///     },
+/// );
+/// pool.send((7, "resize the image".to_string())).await?;
+/// pool.terminate().await;
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct AgentPool<M, E> {
+    /// A channel to send messages to the pool.
+    sender: Sender<M>,
+
+    workers: Vec<Agent<M, E>>,
+
+    /// The number of messages each worker hasn't finished handling.
+    outstanding: Vec<Arc<AtomicUsize>>,
+
+    /// Tells the router to close the pool's mailbox.
+    shutdown: oneshot::Sender<()>,
+
+    /// A handle to the router.
+    router: JoinHandle<()>,
+}
+
+impl<M, E> AgentPool<M, E>
+where
+    M: Debug + Send + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    /// Spawn `n` workers handling messages with `handler`, routed to each
+    /// in turn. The handler is given the pool's sender.
+    ///
+    /// Panics if `n` is zero.
+    pub fn spawn<H, R>(n: usize, handler: H) -> Self
+    where
+        H: Fn(Sender<M>, M) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        Self::spawn_with_routing(n, Routing::default(), handler)
+    }
+
+    /// Spawn `n` workers handling messages with `handler`, routed by
+    /// `routing`. The handler is given the pool's sender.
+    ///
+    /// Panics if `n` is zero.
+    pub fn spawn_with_routing<H, R>(n: usize, routing: Routing<M>, handler: H) -> Self
+    where
+        H: Fn(Sender<M>, M) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        assert!(n > 0, "a pool needs at least one worker");
+        let (sender, mut receiver) = mailbox::channel(None);
+        let handler = Arc::new(handler);
+        let outstanding = (0..n)
+            .map(|_| Arc::new(AtomicUsize::new(0)))
+            .collect::<Vec<_>>();
+        let workers = outstanding
+            .iter()
+            .map(|outstanding| {
+                let (handler, sender, outstanding) =
+                    (handler.clone(), sender.clone(), outstanding.clone());
+                Agent::spawn(Uuid::new_v4(), None, move |_worker, message| {
+                    let handled = handler(sender.clone(), message);
+                    let outstanding = outstanding.clone();
+                    async move {
+                        let result = handled.await;
+                        outstanding.fetch_sub(1, Ordering::Relaxed);
+                        result
+                    }
+                })
+            })
+            .collect::<Vec<Agent<M, E>>>();
+
+        let mut router = Router::new(
+            workers
+                .iter()
+                .zip(&outstanding)
+                .map(|(worker, outstanding)| Worker {
+                    sender: worker.sender(),
+                    outstanding: outstanding.clone(),
+                })
+                .collect(),
+            routing,
+        );
+        let (shutdown, mut shutdown_requested) = oneshot::channel::<()>();
+        let router = tokio::spawn(async move {
+            let mut closing = false;
+            loop {
+                tokio::select! {
+                    message = receiver.recv() => {
+                        let Some(message) = message else { break };
+                        if let Err(message) = router.route(message).await {
+                            tracing::warn!(?message, "every worker in the pool stopped; dropping message");
+                            break;
+                        }
+                    }
+                    // the workers keep senders of their own, so only an
+                    // explicit shutdown closes the mailbox
+                    requested = &mut shutdown_requested, if !closing => {
+                        closing = true;
+                        if requested.is_ok() {
+                            receiver.close();
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            sender,
+            workers,
+            outstanding,
+            shutdown,
+            router,
+        }
+    }
+
+    /// Returns a sender that can be used to send messages to the pool.
+    pub fn sender(&self) -> Sender<M> {
+        self.sender.clone()
+    }
+
+    /// Send a message to the pool, to be handled by one of its workers.
+    pub async fn send(&self, message: M) -> Result<(), super::SendError<M>> {
+        self.sender.send(message).await
+    }
+
+    /// Returns the number of workers in the pool.
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Returns whether the pool has no workers, which is never the case.
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    /// Returns the number of messages each worker hasn't finished handling.
+    pub fn outstanding(&self) -> Vec<usize> {
+        self.outstanding
+            .iter()
+            .map(|outstanding| outstanding.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Returns whether each worker is running, and how it ended if not.
+    pub fn statuses(&self) -> Vec<Status> {
+        self.workers.iter().map(Agent::status).collect()
+    }
+
+    /// Returns whether every worker stopped, so the pool can't handle
+    /// messages any more.
+    pub fn is_finished(&self) -> bool {
+        self.workers.iter().all(Agent::is_finished)
+    }
+
+    /// Terminates the pool: closes its mailbox, routes the messages left in
+    /// it, then terminates every worker at once. Reports a forced shutdown
+    /// if any worker had to be aborted.
+    pub async fn terminate(self) -> Shutdown {
+        self.sender.mark_terminated();
+        drop(self.sender);
+        let _ = self.shutdown.send(());
+        if let Err(e) = self.router.await {
+            tracing::error!(error = %e, "pool router panicked");
+        }
+        let terminating = self
+            .workers
+            .into_iter()
+            .map(|worker| tokio::spawn(worker.terminate()))
+            .collect::<Vec<_>>();
+        let mut shutdown = Shutdown::Graceful;
+        for terminated in terminating {
+            if !matches!(terminated.await, Ok(Shutdown::Graceful)) {
+                shutdown = Shutdown::Forced;
+            }
+        }
+        shutdown
+    }
+
+    /// Aborts the pool's router and workers immediately, dropping the
+    /// messages they hold.
+    pub fn abort(self) {
+        self.sender.mark_terminated();
+        self.router.abort();
+        for worker in self.workers {
+            worker.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, anyhow::Result, std::time::Duration};
+
+    /// Waits until the pool's workers have `expected` messages outstanding.
+    async fn settle<M, E>(pool: &AgentPool<M, E>, expected: &[usize]) -> Result<()>
+    where
+        M: Debug + Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while pool.outstanding() != expected {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() -> Result<()> {
+        // handlers that never finish, so nothing leaves the workers
+        let pool = AgentPool::spawn(3, |_sender, _message: u32| {
+            std::future::pending::<Result<(), std::fmt::Error>>()
+        });
+        for message in 0..5 {
+            pool.send(message).await?;
+        }
+        settle(&pool, &[2, 2, 1]).await?;
+        pool.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_least_outstanding() -> Result<()> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let pool = AgentPool::spawn_with_routing(
+            2,
+            Routing::LeastOutstanding,
+            move |_sender, message: &'static str| {
+                let tx = tx.clone();
+                async move {
+                    if message == "slow" {
+                        std::future::pending::<()>().await;
+                    }
+                    tx.send(message).map_err(|_| std::fmt::Error)
+                }
+            },
+        );
+        pool.send("slow").await?;
+        settle(&pool, &[1, 0]).await?;
+        // round-robin would have routed "b" behind "slow"
+        for message in ["a", "b", "c"] {
+            pool.send(message).await?;
+            assert_eq!(rx.recv().await, Some(message));
+        }
+        settle(&pool, &[1, 0]).await?;
+        pool.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_consistent_hash() -> Result<()> {
+        let pool = AgentPool::spawn_with_routing(
+            4,
+            Routing::consistent_hash(|(key, _): &(&str, u32)| *key),
+            |_sender, _message| std::future::pending::<Result<(), std::fmt::Error>>(),
+        );
+        for n in 0..3 {
+            pool.send(("user-1", n)).await?;
+        }
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !pool.outstanding().contains(&3) {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await?;
+        pool.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_workers() -> Result<()> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let pool = AgentPool::spawn(2, move |_sender, message: u32| {
+            let tx = tx.clone();
+            async move {
+                if message == 0 {
+                    return Err(std::fmt::Error);
+                }
+                tx.send(message).map_err(|_| std::fmt::Error)
+            }
+        });
+        // the first worker fails; the rest go to the second
+        pool.send(0).await?;
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !pool
+                .statuses()
+                .contains(&Status::Failed(std::fmt::Error.to_string()))
+            {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await?;
+        for message in 1..4 {
+            pool.send(message).await?;
+            assert_eq!(rx.recv().await, Some(message));
+        }
+        assert!(!pool.is_finished());
+        assert_eq!(pool.terminate().await, Shutdown::Graceful);
+        Ok(())
+    }
+}
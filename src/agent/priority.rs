@@ -0,0 +1,426 @@
+//! Priority mailboxes. An agent spawned with
+//! [`AgentBuilder::with_priority_mailbox`](super::AgentBuilder::with_priority_mailbox)
+//! takes the most urgent message waiting instead of the oldest, e.g. so a
+//! user's chat message isn't stuck behind a pile of background tasks.
+//! Control messages, like a request to cancel, go ahead of everything else.
+//! Messages age as they wait, so a steady stream of urgent messages can't
+//! starve the rest.
+
+use {
+    super::{clock::Clock, mailbox::Envelope},
+    std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+        task::{Context, Poll, Waker},
+        time::{Duration, Instant},
+    },
+    tokio::sync::{AcquireError, Semaphore, TryAcquireError},
+};
+
+/// How long a message waits before it's treated as one level more urgent,
+/// when no aging is configured.
+pub const DEFAULT_AGING: Duration = Duration::from_secs(5);
+
+/// How urgent a message is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    /// Work that can wait, e.g. a background task.
+    Background,
+    #[default]
+    Normal,
+    High,
+
+    /// Messages that steer the agent, e.g. a request to cancel, which go
+    /// ahead of all others. Other messages never age into control messages.
+    Control,
+}
+
+impl Level {
+    /// The levels, least urgent first.
+    const ALL: [Level; 4] = [Self::Background, Self::Normal, Self::High, Self::Control];
+
+    /// Returns the level of a message at this level that has waited
+    /// `waited`, aging one level every `aging`.
+    fn aged(self, waited: Duration, aging: Duration) -> Self {
+        if self == Self::Control || aging.is_zero() {
+            return self;
+        }
+        let steps = (waited.as_nanos() / aging.as_nanos()).min(Self::ALL.len() as u128) as usize;
+        Self::ALL[(self as usize + steps).min(Self::High as usize)]
+    }
+}
+
+/// A message that knows how urgent it is.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::agent::{priority::{Level, Priority}, AgentBuilder, SendError};
+/// # tokio_test::block_on(async {
+/// #[derive(Debug)]
+/// enum Work {
+///     Chat(String),
+///     Reindex,
+///     Cancel,
+/// }
+///
+/// impl Priority for Work {
+///     fn priority(&self) -> Level {
+///         match self {
+///             Work::Chat(_) => Level::High,
+///             Work::Reindex => Level::Background,
+///             Work::Cancel => Level::Control,
+///         }
+///     }
+/// }
+///
+/// let agent = AgentBuilder::<Work, SendError<Work>>::new()
+///     .with_priority_mailbox()
+///     .spawn(|_sender, work| async move {
+///         println!("{work:?}");
+///         Ok(())
+///     });
+/// agent.send(Work::Reindex).await?;
+/// agent.send(Work::Chat("hello".to_string())).await?;
+/// // ahead of whatever is still waiting
+/// agent
+///     .sender()
+///     .send_with_priority(Work::Cancel, Level::Control)
+///     .await?;
+/// # anyhow::Ok(())
+/// # });
+/// ```
+pub trait Priority {
+    /// Returns how urgent the message is. Defaults to [`Level::Normal`].
+    fn priority(&self) -> Level {
+        Level::Normal
+    }
+}
+
+/// Orders a priority mailbox's messages.
+#[derive(Debug)]
+pub(crate) struct Ordering<M> {
+    /// Returns a message's level, unless it was sent with one.
+    pub(crate) priority_of: fn(&M) -> Level,
+
+    /// How long a message waits before it's treated as one level more
+    /// urgent.
+    pub(crate) aging: Duration,
+}
+
+#[derive(Debug)]
+struct State<M> {
+    /// The waiting messages, and when they arrived, by level, least urgent
+    /// first.
+    levels: [VecDeque<(Instant, Envelope<M>)>; 4],
+
+    /// The number of senders, so the receiver knows when there are none.
+    senders: usize,
+
+    /// Whether the mailbox no longer takes messages.
+    closed: bool,
+
+    /// Wakes the receiver when a message arrives or the last sender goes.
+    waker: Option<Waker>,
+}
+
+#[derive(Debug)]
+struct Queue<M> {
+    state: Mutex<State<M>>,
+    ordering: Ordering<M>,
+    clock: Arc<dyn Clock>,
+
+    /// The free slots of a bounded mailbox.
+    space: Option<Semaphore>,
+}
+
+impl<M> Queue<M> {
+    /// Queue `message` at `level`, or the level it has. Returns the message
+    /// if the mailbox is closed.
+    fn push(&self, envelope: Envelope<M>, level: Option<Level>) -> Result<(), M> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(envelope.message);
+        }
+        let level = level.unwrap_or_else(|| (self.ordering.priority_of)(&envelope.message));
+        state.levels[level as usize].push_back((self.clock.now(), envelope));
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Take the most urgent message, after aging, oldest first among equals.
+    fn pop(&self, state: &mut State<M>) -> Option<Envelope<M>> {
+        let now = self.clock.now();
+        // the front of each level has waited the longest there
+        let (index, _) = state
+            .levels
+            .iter()
+            .enumerate()
+            .filter_map(|(index, messages)| {
+                let (arrived, _) = messages.front()?;
+                let level = Level::ALL[index].aged(now - *arrived, self.ordering.aging);
+                Some((index, (level, std::cmp::Reverse(*arrived))))
+            })
+            .max_by_key(|(_, key)| *key)?;
+        let (_, message) = state.levels[index].pop_front()?;
+        if let Some(space) = &self.space {
+            space.add_permits(1);
+        }
+        Some(message)
+    }
+
+    /// Take no more messages.
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        if let Some(space) = &self.space {
+            space.close();
+        }
+    }
+}
+
+/// The sending half of a priority mailbox.
+#[derive(Debug)]
+pub(crate) struct QueueSender<M>(Arc<Queue<M>>);
+
+impl<M> Clone for QueueSender<M> {
+    fn clone(&self) -> Self {
+        self.0.state.lock().unwrap().senders += 1;
+        Self(self.0.clone())
+    }
+}
+
+impl<M> Drop for QueueSender<M> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<M> QueueSender<M> {
+    /// Send `message` at `level`, or the level it has, waiting for space in
+    /// a bounded mailbox. Returns the message if the mailbox is closed.
+    pub(crate) async fn send(
+        &self,
+        message: impl Into<Envelope<M>>,
+        level: Option<Level>,
+    ) -> Result<(), M> {
+        let envelope = message.into();
+        if self.reserve().await.is_err() {
+            return Err(envelope.message);
+        }
+        self.0.push(envelope, level)
+    }
+
+    /// Waits for space in a bounded mailbox, and takes it. Fails if the
+    /// mailbox is closed.
+    pub(crate) async fn reserve(&self) -> Result<(), AcquireError> {
+        if let Some(space) = &self.0.space {
+            space.acquire().await?.forget();
+        }
+        Ok(())
+    }
+
+    /// Send `message` at `level`, or the level it has, if there's space for
+    /// it. Fails with whether the mailbox is closed, and the message.
+    pub(crate) fn try_send(
+        &self,
+        message: impl Into<Envelope<M>>,
+        level: Option<Level>,
+    ) -> Result<(), (bool, M)> {
+        let envelope = message.into();
+        if let Some(space) = &self.0.space {
+            match space.try_acquire() {
+                Ok(permit) => permit.forget(),
+                Err(TryAcquireError::NoPermits) => return Err((false, envelope.message)),
+                Err(TryAcquireError::Closed) => return Err((true, envelope.message)),
+            }
+        }
+        self.0
+            .push(envelope, level)
+            .map_err(|message| (true, message))
+    }
+
+    /// Send `message` at `level`, or the level it has, in space already
+    /// taken with [`QueueSender::reserve`].
+    pub(crate) fn send_reserved(
+        &self,
+        envelope: Envelope<M>,
+        level: Option<Level>,
+    ) -> Result<(), M> {
+        self.0.push(envelope, level)
+    }
+
+    /// Returns the number of messages waiting.
+    pub(crate) fn len(&self) -> usize {
+        let state = self.0.state.lock().unwrap();
+        state.levels.iter().map(VecDeque::len).sum()
+    }
+
+    /// Returns whether the mailbox no longer takes messages.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.0.state.lock().unwrap().closed
+    }
+}
+
+/// The receiving half of a priority mailbox.
+#[derive(Debug)]
+pub(crate) struct QueueReceiver<M>(Arc<Queue<M>>);
+
+impl<M> Drop for QueueReceiver<M> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+impl<M> QueueReceiver<M> {
+    /// Poll for the most urgent message, or `None` once the mailbox is
+    /// closed and empty or every sender is gone.
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Envelope<M>>> {
+        let mut state = self.0.state.lock().unwrap();
+        if let Some(message) = self.0.pop(&mut state) {
+            return Poll::Ready(Some(message));
+        }
+        if state.closed || state.senders == 0 {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Take the most urgent message if one is waiting.
+    pub(crate) fn try_recv(&mut self) -> Option<M> {
+        let mut state = self.0.state.lock().unwrap();
+        self.0.pop(&mut state).map(|envelope| envelope.message)
+    }
+
+    /// Take no more messages. Messages already waiting can still be
+    /// received.
+    pub(crate) fn close(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Create a priority mailbox ordered by `ordering` and aged on `clock`. The
+/// mailbox is bounded if a capacity is given.
+pub(crate) fn queue<M>(
+    capacity: Option<usize>,
+    ordering: Ordering<M>,
+    clock: Arc<dyn Clock>,
+) -> (QueueSender<M>, QueueReceiver<M>) {
+    let queue = Arc::new(Queue {
+        state: Mutex::new(State {
+            levels: Default::default(),
+            senders: 1,
+            closed: false,
+            waker: None,
+        }),
+        ordering,
+        clock,
+        space: capacity.map(Semaphore::new),
+    });
+    (QueueSender(queue.clone()), QueueReceiver(queue))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::agent::{AgentBuilder, ManualClock, SendError},
+        anyhow::Result,
+    };
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Job(&'static str, Level);
+
+    impl Priority for Job {
+        fn priority(&self) -> Level {
+            self.1
+        }
+    }
+
+    #[tokio::test]
+    async fn test_priority_mailbox() -> Result<()> {
+        let (started, mut has_started) = tokio::sync::mpsc::unbounded_channel();
+        let (open, opened) = tokio::sync::oneshot::channel::<()>();
+        let opened = Mutex::new(Some(opened));
+        let (handled, mut handled_jobs) = tokio::sync::mpsc::unbounded_channel();
+        let agent = AgentBuilder::<Job, SendError<Job>>::new()
+            .with_priority_mailbox()
+            .spawn(move |_sender, job| {
+                let (started, handled) = (started.clone(), handled.clone());
+                let opened = opened.lock().unwrap().take();
+                async move {
+                    let _ = started.send(());
+                    // hold the first job until everything else is queued
+                    if let Some(opened) = opened {
+                        let _ = opened.await;
+                    }
+                    let _ = handled.send(job.0);
+                    Ok(())
+                }
+            });
+
+        agent.send(Job("first", Level::Normal)).await?;
+        has_started.recv().await;
+        for job in [
+            Job("reindex", Level::Background),
+            Job("chat", Level::Normal),
+            Job("urgent", Level::High),
+            Job("cancel", Level::Control),
+        ] {
+            agent.send(job).await?;
+        }
+        let sender = agent.sender();
+        sender
+            .send_with_priority(Job("escalated", Level::Background), Level::High)
+            .await?;
+        assert_eq!(sender.len(), 5);
+        let _ = open.send(());
+
+        let mut order = Vec::new();
+        for _ in 0..6 {
+            order.extend(handled_jobs.recv().await);
+        }
+        assert_eq!(
+            order,
+            ["first", "cancel", "urgent", "escalated", "chat", "reindex"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_aging() -> Result<()> {
+        let clock = ManualClock::new();
+        let ordering = Ordering {
+            priority_of: Job::priority,
+            aging: Duration::from_secs(10),
+        };
+        let (sender, mut receiver) = queue(Some(2), ordering, Arc::new(clock.clone()));
+
+        sender.send(Job("old", Level::Background), None).await.ok();
+        clock.advance(Duration::from_secs(20));
+        sender.send(Job("new", Level::Normal), None).await.ok();
+        // the mailbox is full
+        assert!(matches!(
+            sender.try_send(Job("late", Level::High), None),
+            Err((false, _))
+        ));
+
+        // waiting twice the aging makes a background job high priority
+        assert_eq!(receiver.try_recv(), Some(Job("old", Level::Background)));
+        sender.send(Job("control", Level::Control), None).await.ok();
+        assert_eq!(receiver.try_recv(), Some(Job("control", Level::Control)));
+        assert_eq!(receiver.try_recv(), Some(Job("new", Level::Normal)));
+
+        receiver.close();
+        assert!(sender.is_closed());
+        assert!(sender.send(Job("closed", Level::High), None).await.is_err());
+        Ok(())
+    }
+}
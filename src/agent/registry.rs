@@ -0,0 +1,480 @@
+//! Agents spawned on demand. A [`Registry`] addresses agents by name and
+//! spawns an agent from its factory the first time a message is sent to it,
+//! or again after it stopped, in the style of virtual actors. This makes it
+//! easy to keep e.g. one assistant per user without managing their lifetimes
+//! by hand.
+//!
+//! With an idle timeout, agents that haven't been sent anything for a while
+//! are checkpointed and evicted, and restored from their checkpoint by the
+//! next message, which bounds the number of agents running at once.
+
+use {
+    super::{Agent, Clock, SendError, Sender, Shutdown, SystemClock},
+    std::{
+        collections::HashMap,
+        fmt::{self, Debug},
+        future::Future,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex, Weak,
+        },
+        time::{Duration, Instant},
+    },
+};
+
+/// Something a [`Registry`] can spawn and evict.
+pub trait Instance: Send + 'static {
+    /// The messages the instance receives.
+    type Message: Send + 'static;
+
+    /// What the instance is restored from after it's evicted.
+    type Checkpoint: Send + 'static;
+
+    /// Returns a sender that can be used to send messages to the instance.
+    fn sender(&self) -> Sender<Self::Message>;
+
+    /// Returns whether the instance has stopped.
+    fn is_finished(&self) -> bool;
+
+    /// Returns a checkpoint to restore the instance from, or `None` if the
+    /// instance has no state worth keeping.
+    fn checkpoint(&self) -> Option<Self::Checkpoint> {
+        None
+    }
+
+    /// Terminates the instance.
+    fn terminate(self) -> impl Future<Output = Shutdown> + Send;
+}
+
+impl<M, E> Instance for Agent<M, E>
+where
+    M: Debug + Send + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    type Checkpoint = ();
+    type Message = M;
+
+    fn sender(&self) -> Sender<M> {
+        Agent::sender(self)
+    }
+
+    fn is_finished(&self) -> bool {
+        Agent::is_finished(self)
+    }
+
+    fn terminate(self) -> impl Future<Output = Shutdown> + Send {
+        Agent::terminate(self)
+    }
+}
+
+/// Creates the instance for a name, restoring it from a checkpoint if it was
+/// evicted.
+type Factory<I> = dyn Fn(&str, Option<<I as Instance>::Checkpoint>) -> I + Send + Sync;
+
+/// The agents of a registry, shared by its clones.
+struct Shared<I: Instance> {
+    agents: Mutex<HashMap<String, Entry<I>>>,
+    checkpoints: Mutex<HashMap<String, I::Checkpoint>>,
+
+    /// Whether the task evicting idle agents has been started.
+    sweeping: AtomicBool,
+}
+
+struct Entry<I> {
+    instance: I,
+
+    /// When the instance was last sent something through the registry.
+    last_active: Instant,
+}
+
+/// Agents addressed by name, spawned on demand from a factory. Clones share
+/// the same agents.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::agent::{registry::Registry, Agent, SendError}, uuid::Uuid};
+/// # tokio_test::block_on(async {
+/// let registry = Registry::new(|name: &str| {
+///     Agent::spawn(
+///         Uuid::new_v4(),
+///         Some(name.to_string()),
+///         |_sender, message: String| async move {
+///             println!("{message}");
+///             Ok::<_, SendError<String>>(())
+///         },
+///     )
+/// });
+/// // spawns the agent named "alice", then sends it the message
+/// registry.send("alice", "hello".to_string()).await?;
+/// # anyhow::Ok(())
+/// # });
+/// ```
+pub struct Registry<I: Instance> {
+    factory: Arc<Factory<I>>,
+    shared: Arc<Shared<I>>,
+
+    /// How long an agent can go without messages before it's evicted.
+    idle_timeout: Option<Duration>,
+
+    /// The clock idleness is measured on.
+    clock: Arc<dyn Clock>,
+}
+
+// implemented by hand so that cloning a registry doesn't require `I: Clone`
+impl<I: Instance> Clone for Registry<I> {
+    fn clone(&self) -> Self {
+        Self {
+            factory: self.factory.clone(),
+            shared: self.shared.clone(),
+            idle_timeout: self.idle_timeout,
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl<I: Instance> Debug for Registry<I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let agents = self.shared.agents.lock().unwrap();
+        f.debug_set().entries(agents.keys()).finish()
+    }
+}
+
+impl<I: Instance> Registry<I> {
+    /// Create a registry that spawns agents with `factory`, which is given
+    /// the name of the agent to spawn.
+    pub fn new(factory: impl Fn(&str) -> I + Send + Sync + 'static) -> Self {
+        Self::with_restore(move |name, _checkpoint| factory(name))
+    }
+
+    /// Create a registry that spawns agents with `factory`, which is given
+    /// the name of the agent to spawn and, if the agent was evicted, the
+    /// checkpoint to restore it from.
+    pub fn with_restore(
+        factory: impl Fn(&str, Option<I::Checkpoint>) -> I + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            factory: Arc::new(factory),
+            shared: Arc::new(Shared {
+                agents: Default::default(),
+                checkpoints: Default::default(),
+                sweeping: AtomicBool::new(false),
+            }),
+            idle_timeout: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Checkpoint and evict agents that haven't been sent anything through
+    /// the registry for `timeout`. Idle agents are checked for periodically
+    /// once the first agent is spawned.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Measure idleness on `clock`.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Returns a sender for the agent named `name`, spawning the agent first
+    /// if it isn't running.
+    pub fn sender(&self, name: &str) -> Sender<I::Message> {
+        let now = self.clock.now();
+        let mut agents = self.shared.agents.lock().unwrap();
+        if let Some(entry) = agents
+            .get_mut(name)
+            .filter(|entry| !entry.instance.is_finished())
+        {
+            entry.last_active = now;
+            return entry.instance.sender();
+        }
+        let checkpoint = self.shared.checkpoints.lock().unwrap().remove(name);
+        tracing::trace!(
+            name,
+            restored = checkpoint.is_some(),
+            "spawning agent on demand"
+        );
+        let instance = (self.factory)(name, checkpoint);
+        let sender = instance.sender();
+        agents.insert(
+            name.to_string(),
+            Entry {
+                instance,
+                last_active: now,
+            },
+        );
+        drop(agents);
+        self.sweep();
+        sender
+    }
+
+    /// Send a message to the agent named `name`, spawning the agent first if
+    /// it isn't running. If the agent stops or is evicted before the message
+    /// is delivered, it's spawned again and the message is sent to the new
+    /// agent.
+    pub async fn send(&self, name: &str, message: I::Message) -> Result<(), SendError<I::Message>> {
+        match self.sender(name).send(message).await {
+            Err(SendError { message, .. }) => self.sender(name).send(message).await,
+            result => result,
+        }
+    }
+
+    /// Returns whether the agent named `name` is running.
+    pub fn is_running(&self, name: &str) -> bool {
+        let agents = self.shared.agents.lock().unwrap();
+        agents
+            .get(name)
+            .is_some_and(|entry| !entry.instance.is_finished())
+    }
+
+    /// Returns whether the agent named `name` was evicted and will be
+    /// restored by the next message.
+    pub fn is_evicted(&self, name: &str) -> bool {
+        self.shared.checkpoints.lock().unwrap().contains_key(name)
+    }
+
+    /// Returns the names of the running agents.
+    pub fn names(&self) -> Vec<String> {
+        let agents = self.shared.agents.lock().unwrap();
+        agents
+            .iter()
+            .filter(|(_, entry)| !entry.instance.is_finished())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Remove the agent named `name`, e.g. to terminate it, and forget its
+    /// checkpoint. The next message sent to the name spawns a new agent.
+    pub fn remove(&self, name: &str) -> Option<I> {
+        self.shared.checkpoints.lock().unwrap().remove(name);
+        let entry = self.shared.agents.lock().unwrap().remove(name);
+        entry.map(|entry| entry.instance)
+    }
+
+    /// Checkpoint and evict the agents that have been idle for longer than
+    /// the idle timeout. Returns the names of the evicted agents.
+    ///
+    /// An agent still handling a message when it's evicted finishes handling
+    /// it, but the checkpoint is taken before it does.
+    pub fn evict_idle(&self) -> Vec<String> {
+        match self.idle_timeout {
+            Some(timeout) => evict_idle(&self.shared, self.clock.now(), timeout),
+            None => Vec::new(),
+        }
+    }
+
+    /// Start evicting idle agents in the background, unless there is no idle
+    /// timeout or eviction is already running. Stops once every clone of the
+    /// registry is dropped.
+    fn sweep(&self) {
+        let Some(timeout) = self.idle_timeout else {
+            return;
+        };
+        if self.shared.sweeping.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let shared = Arc::downgrade(&self.shared);
+        let clock = self.clock.clone();
+        tokio::spawn(async move {
+            loop {
+                clock.sleep(timeout / 2).await;
+                let Some(shared) = Weak::upgrade(&shared) else {
+                    break;
+                };
+                evict_idle(&shared, clock.now(), timeout);
+            }
+        });
+    }
+}
+
+/// Checkpoint and evict the agents idle for longer than `timeout` at `now`.
+/// Agents are terminated in the background.
+fn evict_idle<I: Instance>(shared: &Shared<I>, now: Instant, timeout: Duration) -> Vec<String> {
+    let mut agents = shared.agents.lock().unwrap();
+    let idle = agents
+        .iter()
+        .filter(|(_, entry)| now.saturating_duration_since(entry.last_active) >= timeout)
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    for name in &idle {
+        let Some(entry) = agents.remove(name) else {
+            continue;
+        };
+        // checkpoint while still holding the lock, so a message arriving
+        // meanwhile restores the agent from this checkpoint
+        if let Some(checkpoint) = entry.instance.checkpoint() {
+            shared
+                .checkpoints
+                .lock()
+                .unwrap()
+                .insert(name.clone(), checkpoint);
+        }
+        tracing::trace!(name, "evicting idle agent");
+        tokio::spawn(entry.instance.terminate());
+    }
+    idle
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*, crate::agent::ManualClock, anyhow::Result, std::sync::atomic::AtomicUsize,
+        tokio::sync::mpsc, uuid::Uuid,
+    };
+
+    #[tokio::test]
+    async fn test_spawn_on_demand() -> Result<()> {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let registry = Registry::new({
+            let spawned = spawned.clone();
+            move |name: &str| {
+                spawned.fetch_add(1, Ordering::SeqCst);
+                let name = name.to_string();
+                let tx = tx.clone();
+                Agent::spawn(
+                    Uuid::new_v4(),
+                    Some(name.clone()),
+                    move |_sender, message: &'static str| {
+                        let name = name.clone();
+                        let tx = tx.clone();
+                        async move {
+                            tx.send(format!("{name}: {message}")).ok();
+                            // "fail" stops the agent
+                            match message {
+                                "fail" => Err(SendError::new(message)),
+                                _ => Ok(()),
+                            }
+                        }
+                    },
+                )
+            }
+        });
+
+        assert!(!registry.is_running("alice"));
+        registry.send("alice", "hi").await?;
+        registry.send("alice", "again").await?;
+        registry.send("bob", "hi").await?;
+        assert_eq!(spawned.load(Ordering::SeqCst), 2);
+        assert_eq!(rx.recv().await.as_deref(), Some("alice: hi"));
+        assert_eq!(rx.recv().await.as_deref(), Some("alice: again"));
+        assert_eq!(rx.recv().await.as_deref(), Some("bob: hi"));
+
+        // a stopped agent is spawned again by the next message
+        registry.send("alice", "fail").await?;
+        assert_eq!(rx.recv().await.as_deref(), Some("alice: fail"));
+        while registry.is_running("alice") {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(registry.names(), ["bob"]);
+        registry.send("alice", "back").await?;
+        assert_eq!(rx.recv().await.as_deref(), Some("alice: back"));
+        assert_eq!(spawned.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    /// Counts the messages it receives, and is restored with its count.
+    struct Counter {
+        agent: Agent<u32, SendError<u32>>,
+        count: Arc<AtomicUsize>,
+    }
+
+    impl Counter {
+        fn spawn(count: usize) -> Self {
+            let count = Arc::new(AtomicUsize::new(count));
+            let agent = Agent::spawn(Uuid::new_v4(), None, {
+                let count = count.clone();
+                move |_sender, _message: u32| {
+                    count.fetch_add(1, Ordering::SeqCst);
+                    async { Ok(()) }
+                }
+            });
+            Self { agent, count }
+        }
+    }
+
+    impl Instance for Counter {
+        type Checkpoint = usize;
+        type Message = u32;
+
+        fn sender(&self) -> Sender<u32> {
+            self.agent.sender()
+        }
+
+        fn is_finished(&self) -> bool {
+            self.agent.is_finished()
+        }
+
+        fn checkpoint(&self) -> Option<usize> {
+            Some(self.count.load(Ordering::SeqCst))
+        }
+
+        fn terminate(self) -> impl Future<Output = Shutdown> + Send {
+            self.agent.terminate()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_evict_idle() -> Result<()> {
+        let clock = ManualClock::new();
+        let counts = Arc::new(Mutex::new(Vec::new()));
+        let registry = Registry::with_restore({
+            let counts = counts.clone();
+            move |_name, checkpoint| {
+                counts.lock().unwrap().push(checkpoint);
+                Counter::spawn(checkpoint.unwrap_or_default())
+            }
+        })
+        .with_idle_timeout(Duration::from_secs(60))
+        .with_clock(clock.clone());
+
+        registry.send("alice", 1).await?;
+        registry.send("alice", 2).await?;
+        clock.advance(Duration::from_secs(30));
+        registry.send("bob", 1).await?;
+        while registry
+            .shared
+            .agents
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.instance.count.load(Ordering::SeqCst))
+            .sum::<usize>()
+            < 3
+        {
+            tokio::task::yield_now().await;
+        }
+
+        // only alice has been idle for the whole timeout
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(registry.evict_idle(), ["alice"]);
+        assert!(registry.is_evicted("alice"));
+        assert_eq!(registry.names(), ["bob"]);
+
+        // the next message restores alice with her count
+        registry.send("alice", 3).await?;
+        assert!(!registry.is_evicted("alice"));
+        assert_eq!(*counts.lock().unwrap(), [None, None, Some(2)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_evict_in_background() -> Result<()> {
+        let clock = ManualClock::new();
+        let registry = Registry::with_restore(|_name, checkpoint| {
+            Counter::spawn(checkpoint.unwrap_or_default())
+        })
+        .with_idle_timeout(Duration::from_secs(60))
+        .with_clock(clock.clone());
+
+        registry.send("alice", 1).await?;
+        while !registry.is_evicted("alice") {
+            clock.advance(Duration::from_secs(30));
+            tokio::task::yield_now().await;
+        }
+        assert!(!registry.is_running("alice"));
+        Ok(())
+    }
+}
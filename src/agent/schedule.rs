@@ -0,0 +1,180 @@
+//! Messages sent later or on a schedule, for reminders, heartbeats and
+//! periodic polling.
+
+use {
+    super::{clock::Clock, Sender, SystemClock},
+    std::{sync::Arc, time::Duration},
+    tokio::task::JoinHandle,
+};
+
+/// A scheduled send, returned by [`Sender::send_after`] and
+/// [`Sender::send_interval`]. Dropping it cancels the schedule; call
+/// [`Scheduled::detach`] to keep it running.
+#[derive(Debug)]
+#[must_use = "dropping the handle cancels the schedule"]
+pub struct Scheduled {
+    task: Option<JoinHandle<()>>,
+}
+
+impl Scheduled {
+    /// Cancel the schedule. Messages already sent aren't recalled.
+    pub fn cancel(self) {}
+
+    /// Keep the schedule running without the handle. It stops once the
+    /// agent stops.
+    pub fn detach(mut self) {
+        self.task = None;
+    }
+
+    /// Returns whether the schedule is over: the message was sent, or the
+    /// agent stopped.
+    pub fn is_finished(&self) -> bool {
+        self.task.as_ref().map_or(true, JoinHandle::is_finished)
+    }
+}
+
+impl Drop for Scheduled {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+impl<M: Send + 'static> Sender<M> {
+    /// Send `message` to the agent once `delay` has passed.
+    ///
+    /// Usage:
+    /// ```
+    /// # use {autogen_rs::agent::AgentBuilder, std::time::Duration};
+    /// # tokio_test::block_on(async {
+    /// let agent = AgentBuilder::new().spawn(|_sender, reminder: String| async move {
+    ///     println!("{reminder}");
+    ///     Ok::<_, std::io::Error>(())
+    /// });
+    /// agent
+    ///     .sender()
+    ///     .send_after("stand up".to_string(), Duration::from_millis(10))
+    ///     .detach();
+    /// # anyhow::Ok(())
+    /// # });
+    /// ```
+    pub fn send_after(&self, message: M, delay: Duration) -> Scheduled {
+        self.send_after_on(Arc::new(SystemClock), message, delay)
+    }
+
+    /// Like [`Sender::send_after`], but measures the delay on `clock`.
+    pub(crate) fn send_after_on(
+        &self,
+        clock: Arc<dyn Clock>,
+        message: M,
+        delay: Duration,
+    ) -> Scheduled {
+        let sender = self.clone();
+        // start the delay now, not when the task first runs
+        let sleep = clock.sleep(delay);
+        Scheduled {
+            task: Some(tokio::spawn(async move {
+                sleep.await;
+                if let Err(e) = sender.send(message).await {
+                    tracing::debug!(target = ?e.target, "dropping scheduled message: {}", e.reason);
+                }
+            })),
+        }
+    }
+
+    /// Send a message made by `factory` to the agent every `period`, the
+    /// first one after a period has passed. Stops once the agent stops. If
+    /// the agent's mailbox is bounded and full, the next message waits for
+    /// space and the schedule catches up afterwards.
+    pub fn send_interval<F>(&self, factory: F, period: Duration) -> Scheduled
+    where
+        F: FnMut() -> M + Send + 'static,
+    {
+        self.send_interval_on(Arc::new(SystemClock), factory, period)
+    }
+
+    /// Like [`Sender::send_interval`], but measures the period on `clock`.
+    pub(crate) fn send_interval_on<F>(
+        &self,
+        clock: Arc<dyn Clock>,
+        mut factory: F,
+        period: Duration,
+    ) -> Scheduled
+    where
+        F: FnMut() -> M + Send + 'static,
+    {
+        let sender = self.clone();
+        let start = clock.now();
+        let mut sleep = clock.sleep(period);
+        Scheduled {
+            task: Some(tokio::spawn(async move {
+                for tick in 2.. {
+                    sleep.await;
+                    // schedule the next tick from the start, so sends don't
+                    // make the schedule drift
+                    let next = (start + period * tick).saturating_duration_since(clock.now());
+                    sleep = clock.sleep(next);
+                    if let Err(e) = sender.send(factory()).await {
+                        tracing::debug!(target = ?e.target, "stopping scheduled messages: {}", e.reason);
+                        return;
+                    }
+                }
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::agent::{channel, ManualClock},
+        anyhow::Result,
+    };
+
+    #[tokio::test]
+    async fn test_send_after() -> Result<()> {
+        let clock = ManualClock::new();
+        let (sender, mut receiver) = channel(None);
+
+        let reminder =
+            sender.send_after_on(Arc::new(clock.clone()), "later", Duration::from_secs(5));
+        let cancelled =
+            sender.send_after_on(Arc::new(clock.clone()), "never", Duration::from_secs(5));
+        cancelled.cancel();
+        clock.advance(Duration::from_secs(5));
+
+        assert_eq!(receiver.recv().await, Some("later"));
+        tokio::task::yield_now().await;
+        assert!(reminder.is_finished());
+        assert_eq!(receiver.try_recv(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_interval() -> Result<()> {
+        let clock = ManualClock::new();
+        let (sender, mut receiver) = channel(None);
+
+        let mut beats = 0;
+        let heartbeat = sender.send_interval_on(
+            Arc::new(clock.clone()),
+            move || {
+                beats += 1;
+                beats
+            },
+            Duration::from_secs(10),
+        );
+        for beat in 1..=3 {
+            clock.advance(Duration::from_secs(10));
+            assert_eq!(receiver.recv().await, Some(beat));
+        }
+
+        drop(heartbeat);
+        clock.advance(Duration::from_secs(10));
+        tokio::task::yield_now().await;
+        assert_eq!(receiver.try_recv(), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+//! Stateful message handlers.
+
+use {
+    super::{AgentContext, Sender},
+    std::future::Future,
+};
+
+/// A message handler that owns its state. The agent's event loop owns the
+/// handler and gives it exclusive access to itself for each message, so state
+/// such as conversation history or counters can be kept in plain fields.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::agent::{Agent, AgentContext, AgentState, ReplyTo, SendError};
+/// struct Counter {
+///     count: u64,
+/// }
+///
+/// impl AgentState<ReplyTo<u64>> for Counter {
+///     type Error = SendError<u64>;
+///
+///     async fn handle(
+///         &mut self,
+///         _context: &AgentContext<ReplyTo<u64>>,
+///         reply_to: ReplyTo<u64>,
+///     ) -> Result<(), Self::Error> {
+///         self.count += 1;
+///         reply_to.send(self.count)
+///     }
+/// }
+///
+/// # tokio_test::block_on(async {
+/// let counter = Agent::spawn_with_state(uuid::Uuid::new_v4(), None, Counter { count: 0 });
+/// counter.ask(|reply_to| reply_to).await?;
+/// assert_eq!(counter.ask(|reply_to| reply_to).await?, 2);
+/// # anyhow::Ok(())
+/// # });
+/// ```
+pub trait AgentState<M>: Send + 'static {
+    type Error;
+
+    /// Handle a message.
+    fn handle(
+        &mut self,
+        context: &AgentContext<M>,
+        message: M,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// Adapts a stateless handler closure to [`AgentState`].
+pub(crate) struct FnHandler<H>(pub(crate) H);
+
+impl<M, E, H, R> AgentState<M> for FnHandler<H>
+where
+    H: Fn(Sender<M>, M) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<(), E>> + Send + 'static,
+{
+    type Error = E;
+
+    fn handle(
+        &mut self,
+        context: &AgentContext<M>,
+        message: M,
+    ) -> impl Future<Output = Result<(), E>> + Send {
+        (self.0)(context.sender.clone(), message)
+    }
+}
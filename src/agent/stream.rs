@@ -0,0 +1,94 @@
+//! Streaming a reply as it's generated.
+
+use {
+    super::{
+        mailbox::{self, Receiver},
+        Message, SendError, Sender, StreamEvent,
+    },
+    futures_core::Stream,
+    std::{
+        future::poll_fn,
+        pin::Pin,
+        task::{Context, Poll},
+    },
+};
+
+/// The events streamed while an agent generates a reply to
+/// [`Sender::ask_stream`]. Ends with a [`StreamEvent::Complete`] carrying the
+/// full reply, or without one if the agent dropped the message without
+/// replying.
+#[derive(Debug)]
+pub struct ReplyStream {
+    events: Receiver<StreamEvent>,
+    replies: Receiver<Box<Message>>,
+    reply: Option<String>,
+    done: bool,
+}
+
+impl ReplyStream {
+    /// Returns the next event, or `None` once the reply is complete.
+    pub async fn next(&mut self) -> Option<StreamEvent> {
+        poll_fn(|cx| self.poll_event(cx)).await
+    }
+
+    fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<Option<StreamEvent>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        if let Some(reply) = &self.reply {
+            // events are sent before the reply, so the ones left are already
+            // waiting
+            return Poll::Ready(Some(match self.events.try_recv() {
+                Some(event) => event,
+                None => {
+                    self.done = true;
+                    StreamEvent::Complete(reply.clone())
+                }
+            }));
+        }
+        if let Poll::Ready(Some(event)) = self.events.poll_recv(cx) {
+            return Poll::Ready(Some(event));
+        }
+        match self.replies.poll_recv(cx) {
+            Poll::Ready(Some(reply)) => {
+                self.reply = Some(reply.content.to_string());
+                self.poll_event(cx)
+            }
+            Poll::Ready(None) => {
+                self.done = true;
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Stream for ReplyStream {
+    type Item = StreamEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_event(cx)
+    }
+}
+
+impl Sender<Box<Message>> {
+    /// Send `content` to the agent and stream its reply as it's generated.
+    /// Agents that don't stream only yield the complete reply.
+    pub async fn ask_stream(
+        &self,
+        content: impl ToString,
+    ) -> Result<ReplyStream, SendError<Box<Message>>> {
+        let (stream, events) = mailbox::channel(None);
+        let (sender, replies) = mailbox::channel(None);
+        self.send(Box::new(
+            Message::new(sender, content.to_string()).with_stream(stream),
+        ))
+        .await?;
+        Ok(ReplyStream {
+            events,
+            replies,
+            reply: None,
+            done: false,
+        })
+    }
+}
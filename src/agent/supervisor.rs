@@ -0,0 +1,588 @@
+//! Supervision of agents. A [`Supervisor`] owns child agents, watches their
+//! event loops for handler errors and panics, and restarts failed children
+//! according to its [`RestartStrategy`]. Restarted children keep their mailbox,
+//! so senders handed out before a failure keep working afterwards.
+//! [`Supervisor::terminate`] closes every child's mailbox and waits for the
+//! children to handle what's left in it.
+
+use {
+    super::{clock, grace_period, mailbox, panic_message, Clock, Sender, Shutdown, SystemClock},
+    std::{collections::VecDeque, fmt::Debug, future::Future, sync::Arc, time::Duration},
+    tokio::{
+        sync::{broadcast, oneshot, Mutex},
+        task::JoinHandle,
+    },
+    uuid::Uuid,
+};
+
+/// The number of lifecycle events buffered for slow subscribers.
+const EVENT_CAPACITY: usize = 64;
+
+/// How a supervisor restarts failed children. Each child is restarted on its
+/// own (one-for-one); the other children are left running.
+///
+/// By default a failed child is restarted immediately and without limit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestartStrategy {
+    /// Exponential backoff applied between restarts.
+    pub backoff: Option<Backoff>,
+
+    /// Give up on a child that fails more than this many times within the
+    /// window.
+    pub max_restarts: Option<(usize, Duration)>,
+}
+
+impl RestartStrategy {
+    /// Restart failed children immediately and without limit.
+    pub fn one_for_one() -> Self {
+        Default::default()
+    }
+
+    /// Wait between restarts, doubling the delay for each restart within the
+    /// restart window, up to `max`.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = Some(Backoff { initial, max });
+        self
+    }
+
+    /// Give up on a child that needs more than `max_restarts` restarts within
+    /// `window`.
+    pub fn with_max_restarts(mut self, max_restarts: usize, window: Duration) -> Self {
+        self.max_restarts = Some((max_restarts, window));
+        self
+    }
+}
+
+/// Exponential backoff between restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    /// The delay before the first restart.
+    pub initial: Duration,
+
+    /// The longest delay between restarts.
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Returns the delay before the given restart, counting from 1.
+    pub fn delay(&self, restart: usize) -> Duration {
+        let exponent = restart.saturating_sub(1).min(u32::MAX as usize) as u32;
+        self.initial
+            .checked_mul(2u32.saturating_pow(exponent))
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+}
+
+/// Lifecycle events of supervised agents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SupervisorEvent {
+    /// A child's event loop was started.
+    Started { id: Uuid, name: Option<String> },
+
+    /// A child's handler returned an error or panicked.
+    Failed {
+        id: Uuid,
+        name: Option<String>,
+        error: String,
+    },
+
+    /// A failed child was restarted. `restarts` counts the restarts within
+    /// the current restart window.
+    Restarted {
+        id: Uuid,
+        name: Option<String>,
+        restarts: usize,
+    },
+
+    /// A child failed too often and won't be restarted.
+    GaveUp { id: Uuid, name: Option<String> },
+
+    /// A child's mailbox was closed and its event loop finished.
+    Stopped { id: Uuid, name: Option<String> },
+}
+
+/// Owns and restarts child agents.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::agent::supervisor::{RestartStrategy, Supervisor};
+/// # use std::time::Duration;
+/// # tokio_test::block_on(async {
+/// let mut supervisor = Supervisor::new(
+///     RestartStrategy::one_for_one()
+///         .with_backoff(Duration::from_millis(10), Duration::from_secs(1))
+///         .with_max_restarts(5, Duration::from_secs(60)),
+/// );
+/// let events = supervisor.subscribe();
+///
+/// let sender = supervisor.spawn(uuid::Uuid::new_v4(), None, |_sender, n: u32| async move {
+///     if n == 0 {
+///         return Err(std::fmt::Error);
+///     }
+///     Ok(())
+/// });
+/// sender.send(0).await?;
+/// supervisor.terminate().await;
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct Supervisor {
+    /// How failed children are restarted.
+    strategy: RestartStrategy,
+
+    /// Publishes lifecycle events of the children.
+    events: broadcast::Sender<SupervisorEvent>,
+
+    /// The clock restart windows and backoff are measured on.
+    clock: Arc<dyn Clock>,
+
+    /// The children's watchers.
+    children: Vec<ChildHandle>,
+}
+
+/// A handle to a supervised child.
+#[derive(Debug)]
+struct ChildHandle {
+    /// Tells the child to close its mailbox.
+    shutdown: oneshot::Sender<()>,
+
+    /// The task watching the child.
+    watcher: JoinHandle<()>,
+}
+
+impl Supervisor {
+    /// Create a supervisor with no children.
+    pub fn new(strategy: RestartStrategy) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            strategy,
+            events,
+            clock: Arc::new(SystemClock),
+            children: Vec::new(),
+        }
+    }
+
+    /// Measure restart windows and backoff on `clock` instead of the system
+    /// clock. Applies to children spawned afterwards.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Subscribe to the lifecycle events of the supervisor's children.
+    pub fn subscribe(&self) -> broadcast::Receiver<SupervisorEvent> {
+        self.events.subscribe()
+    }
+
+    /// Spawn a supervised child with an unbounded mailbox. Returns a sender
+    /// that stays valid across restarts.
+    pub fn spawn<M, E, H, R>(&mut self, id: Uuid, name: Option<String>, handler: H) -> Sender<M>
+    where
+        M: Debug + Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+        H: Fn(Sender<M>, M) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        self.spawn_with_mailbox(id, name, None, handler)
+    }
+
+    /// Spawn a supervised child whose mailbox holds at most `capacity`
+    /// messages.
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn spawn_bounded<M, E, H, R>(
+        &mut self,
+        id: Uuid,
+        name: Option<String>,
+        capacity: usize,
+        handler: H,
+    ) -> Sender<M>
+    where
+        M: Debug + Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+        H: Fn(Sender<M>, M) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        self.spawn_with_mailbox(id, name, Some(capacity), handler)
+    }
+
+    fn spawn_with_mailbox<M, E, H, R>(
+        &mut self,
+        id: Uuid,
+        name: Option<String>,
+        capacity: Option<usize>,
+        handler: H,
+    ) -> Sender<M>
+    where
+        M: Debug + Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+        H: Fn(Sender<M>, M) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        let (sender, receiver) = mailbox::channel(capacity);
+        let (shutdown, shutdown_requested) = oneshot::channel();
+        let child = Child {
+            id,
+            name,
+            sender: sender.clone(),
+            mailbox: Arc::new(Mutex::new(Mailbox {
+                receiver,
+                shutdown_requested,
+                closing: false,
+            })),
+            handler: Arc::new(handler),
+            strategy: self.strategy.clone(),
+            events: self.events.clone(),
+            clock: self.clock.clone(),
+        };
+        self.children.push(ChildHandle {
+            shutdown,
+            watcher: tokio::spawn(child.watch()),
+        });
+        sender
+    }
+
+    /// Terminates every child by closing its mailbox and waiting up to the
+    /// grace period for the children to handle the messages left in them.
+    /// Children that stop are reported as [`SupervisorEvent::Stopped`];
+    /// children still busy when the grace period ends are aborted, and
+    /// reported as a forced shutdown.
+    pub async fn terminate(self) -> Shutdown {
+        let mut watchers = Vec::with_capacity(self.children.len());
+        for child in self.children {
+            // the child may already have given up
+            let _ = child.shutdown.send(());
+            watchers.push(child.watcher);
+        }
+        let stopped = clock::timeout(&*self.clock, grace_period(), async {
+            for watcher in &mut watchers {
+                let _ = watcher.await;
+            }
+        })
+        .await;
+        match stopped {
+            Some(()) => Shutdown::Graceful,
+            None => {
+                for watcher in watchers {
+                    watcher.abort();
+                }
+                Shutdown::Forced
+            }
+        }
+    }
+
+    /// Stops all children immediately, dropping the messages they hold.
+    pub fn abort(self) {
+        for child in self.children {
+            child.watcher.abort();
+        }
+    }
+}
+
+/// A supervised child's mailbox, kept across restarts.
+struct Mailbox<M> {
+    receiver: mailbox::Receiver<M>,
+
+    /// Fires when the supervisor terminates the child.
+    shutdown_requested: oneshot::Receiver<()>,
+
+    /// Whether the shutdown was requested, so the mailbox is draining.
+    closing: bool,
+}
+
+/// A supervised child agent.
+struct Child<M, H> {
+    id: Uuid,
+    name: Option<String>,
+    sender: Sender<M>,
+    mailbox: Arc<Mutex<Mailbox<M>>>,
+    handler: Arc<H>,
+    strategy: RestartStrategy,
+    events: broadcast::Sender<SupervisorEvent>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<M, H> Child<M, H>
+where
+    M: Debug + Send + 'static,
+{
+    /// Runs the child's event loop, restarting it when it fails.
+    async fn watch<E, R>(self)
+    where
+        E: std::error::Error + Send + Sync + 'static,
+        H: Fn(Sender<M>, M) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        let (id, name) = (self.id, self.name.clone());
+        let mut failures = VecDeque::new();
+
+        loop {
+            self.emit(SupervisorEvent::Started {
+                id,
+                name: name.clone(),
+            });
+
+            // the event loop runs in its own task so that panics are caught
+            let mut event_loop = AbortOnDrop(tokio::spawn(Self::run(
+                id,
+                name.clone(),
+                self.sender.clone(),
+                self.mailbox.clone(),
+                self.handler.clone(),
+            )));
+            let error = match (&mut event_loop.0).await {
+                Ok(Ok(())) => {
+                    tracing::trace!(name, %id, "stopping");
+                    self.emit(SupervisorEvent::Stopped { id, name });
+                    return;
+                }
+                Ok(Err(e)) => e.to_string(),
+                Err(e) if e.is_panic() => panic_message(&*e.into_panic()),
+                Err(_) => return, // cancelled
+            };
+            tracing::warn!(name, %id, error, "agent failed");
+            self.emit(SupervisorEvent::Failed {
+                id,
+                name: name.clone(),
+                error,
+            });
+
+            let now = self.clock.now();
+            failures.push_back(now);
+            if let Some((max_restarts, window)) = self.strategy.max_restarts {
+                while failures
+                    .front()
+                    .is_some_and(|failure| now.duration_since(*failure) > window)
+                {
+                    failures.pop_front();
+                }
+                if failures.len() > max_restarts {
+                    tracing::warn!(name, %id, "giving up on agent");
+                    self.emit(SupervisorEvent::GaveUp { id, name });
+                    return;
+                }
+            }
+
+            let restarts = failures.len();
+            if let Some(backoff) = self.strategy.backoff {
+                self.clock.sleep(backoff.delay(restarts)).await;
+            }
+            tracing::trace!(name, %id, restarts, "restarting");
+            self.emit(SupervisorEvent::Restarted {
+                id,
+                name: name.clone(),
+                restarts,
+            });
+        }
+    }
+
+    async fn run<E, R>(
+        id: Uuid,
+        name: Option<String>,
+        sender: Sender<M>,
+        mailbox: Arc<Mutex<Mailbox<M>>>,
+        handler: Arc<H>,
+    ) -> Result<(), E>
+    where
+        H: Fn(Sender<M>, M) -> R,
+        R: Future<Output = Result<(), E>>,
+    {
+        tracing::trace!(name, %id, "starting");
+        let mut mailbox = mailbox.lock().await;
+        let Mailbox {
+            receiver,
+            shutdown_requested,
+            closing,
+        } = &mut *mailbox;
+        loop {
+            tokio::select! {
+                message = receiver.recv() => {
+                    let Some(message) = message else { break };
+                    tracing::trace!(name, %id, ?message, "received message");
+                    handler(sender.clone(), message).await?;
+                }
+                // the child keeps a sender of its own, so only an explicit
+                // shutdown closes the mailbox
+                requested = &mut *shutdown_requested, if !*closing => {
+                    *closing = true;
+                    if requested.is_ok() {
+                        tracing::trace!(name, %id, "closing mailbox");
+                        receiver.close();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn emit(&self, event: SupervisorEvent) {
+        // there may be no subscribers
+        let _ = self.events.send(event);
+    }
+}
+
+/// Aborts a task when dropped, so that aborting a child's watcher also stops
+/// its event loop.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::agent::ManualClock, anyhow::Result};
+
+    #[derive(thiserror::Error, Debug)]
+    #[error("boom")]
+    struct Boom;
+
+    /// Spawns a child that fails on 0, panics on 1 and forwards anything
+    /// else.
+    fn spawn_flaky(
+        supervisor: &mut Supervisor,
+    ) -> (Sender<u32>, tokio::sync::mpsc::UnboundedReceiver<u32>) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let sender = supervisor.spawn(
+            Uuid::new_v4(),
+            Some("flaky".to_string()),
+            move |_sender, n| {
+                let tx = tx.clone();
+                async move {
+                    match n {
+                        0 => Err(Boom),
+                        1 => panic!("panicked on {n}"),
+                        n => {
+                            tx.send(n).ok();
+                            Ok(())
+                        }
+                    }
+                }
+            },
+        );
+        (sender, rx)
+    }
+
+    /// Waits for the next event that isn't a start or restart.
+    async fn next_outcome(
+        events: &mut broadcast::Receiver<SupervisorEvent>,
+    ) -> Result<SupervisorEvent> {
+        loop {
+            match events.recv().await? {
+                SupervisorEvent::Started { .. } | SupervisorEvent::Restarted { .. } => {}
+                event => return Ok(event),
+            }
+        }
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(200));
+        assert_eq!(backoff.delay(4), Duration::from_millis(800));
+        assert_eq!(backoff.delay(5), Duration::from_secs(1));
+        assert_eq!(backoff.delay(100), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_restart_after_error_and_panic() -> Result<()> {
+        let mut supervisor = Supervisor::new(RestartStrategy::one_for_one());
+        let mut events = supervisor.subscribe();
+        let (sender, mut rx) = spawn_flaky(&mut supervisor);
+
+        sender.send(0).await?;
+        assert!(
+            matches!(next_outcome(&mut events).await?, SupervisorEvent::Failed { error, .. } if error == "boom")
+        );
+
+        sender.send(1).await?;
+        assert!(
+            matches!(next_outcome(&mut events).await?, SupervisorEvent::Failed { error, .. } if error == "panicked on 1")
+        );
+
+        sender.send(2).await?;
+        assert_eq!(rx.recv().await, Some(2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_give_up_after_max_restarts() -> Result<()> {
+        let mut supervisor = Supervisor::new(
+            RestartStrategy::one_for_one().with_max_restarts(1, Duration::from_secs(60)),
+        );
+        let mut events = supervisor.subscribe();
+        let (sender, _rx) = spawn_flaky(&mut supervisor);
+
+        sender.send(0).await?;
+        sender.send(0).await?;
+        assert!(matches!(
+            next_outcome(&mut events).await?,
+            SupervisorEvent::Failed { .. }
+        ));
+        assert!(matches!(
+            next_outcome(&mut events).await?,
+            SupervisorEvent::Failed { .. }
+        ));
+        assert!(matches!(
+            next_outcome(&mut events).await?,
+            SupervisorEvent::GaveUp { .. }
+        ));
+
+        // the mailbox is closed once the supervisor gives up
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(sender.send(2).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backoff_on_clock() -> Result<()> {
+        let clock = ManualClock::new();
+        let mut supervisor = Supervisor::new(
+            RestartStrategy::one_for_one().with_backoff(Duration::from_secs(60), Duration::MAX),
+        )
+        .with_clock(clock.clone());
+        let mut events = supervisor.subscribe();
+        let (sender, mut rx) = spawn_flaky(&mut supervisor);
+
+        sender.send(0).await?;
+        sender.send(2).await?;
+        assert!(matches!(
+            next_outcome(&mut events).await?,
+            SupervisorEvent::Failed { .. }
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(rx.try_recv().is_err(), "the restart should be backing off");
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(rx.recv().await, Some(2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_terminate() -> Result<()> {
+        let mut supervisor = Supervisor::new(RestartStrategy::one_for_one());
+        let mut events = supervisor.subscribe();
+        let (sender, mut rx) = spawn_flaky(&mut supervisor);
+
+        // the messages left in the mailbox are handled before it stops
+        sender.send(2).await?;
+        sender.send(3).await?;
+        assert_eq!(supervisor.terminate().await, Shutdown::Graceful);
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(rx.recv().await, Some(3));
+        assert!(matches!(
+            next_outcome(&mut events).await?,
+            SupervisorEvent::Stopped { name: Some(name), .. } if name == "flaky"
+        ));
+        assert!(sender.send(4).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+//! Running agents as one system. An [`AgentSystem`] owns the agents
+//! registered with it and shuts them all down together, e.g. when the
+//! process is told to stop: it signals them to drain, gives them until a
+//! deadline to handle what's left in their mailboxes and then stops the
+//! stragglers.
+
+use {
+    super::{registry::Instance, AgentBuilder, Sender, Shutdown},
+    std::{
+        fmt::Debug,
+        future::Future,
+        io,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio_util::sync::CancellationToken,
+};
+
+/// How long agents get to drain their mailboxes by default.
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(10);
+
+/// An agent owned by a system, whatever its type.
+trait Member: Send {
+    fn is_finished(&self) -> bool;
+
+    fn terminate(self: Box<Self>) -> Pin<Box<dyn Future<Output = Shutdown> + Send>>;
+}
+
+impl<I: Instance> Member for I {
+    fn is_finished(&self) -> bool {
+        Instance::is_finished(self)
+    }
+
+    fn terminate(self: Box<Self>) -> Pin<Box<dyn Future<Output = Shutdown> + Send>> {
+        Box::pin(Instance::terminate(*self))
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    members: Mutex<Vec<Box<dyn Member>>>,
+
+    /// Cancelled when the system starts shutting down.
+    draining: CancellationToken,
+
+    /// Cancelled once the deadline passes, stopping the agents spawned with
+    /// the system's token.
+    stopped: CancellationToken,
+}
+
+/// The agents of an application, shut down together. Clones share the same
+/// agents.
+///
+/// Agents spawned with [`AgentSystem::spawn`], or built with the system's
+/// [token](AgentSystem::token), are stopped at once when the deadline
+/// passes; other agents registered with the system get their own grace
+/// period on top.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::agent::{system::AgentSystem, AgentBuilder, Shutdown}, std::time::Duration};
+/// # tokio_test::block_on(async {
+/// let system = AgentSystem::new().with_deadline(Duration::from_secs(5));
+/// let logger = system.spawn(
+///     AgentBuilder::new().with_name("logger"),
+///     |_sender, line: String| async move {
+///         println!("{line}");
+///         Ok::<_, std::io::Error>(())
+///     },
+/// );
+/// logger.send("starting".to_string()).await?;
+///
+/// // in an application, `system.shutdown_on_signal().await?` waits for
+/// // Ctrl-C or SIGTERM first
+/// assert_eq!(system.shutdown().await, Shutdown::Graceful);
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Clone)]
+pub struct AgentSystem {
+    shared: Arc<Shared>,
+    deadline: Duration,
+}
+
+impl Default for AgentSystem {
+    fn default() -> Self {
+        Self {
+            shared: Default::default(),
+            deadline: DEFAULT_DEADLINE,
+        }
+    }
+}
+
+impl Debug for AgentSystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AgentSystem")
+            .field("agents", &self.len())
+            .field("deadline", &self.deadline)
+            .field("draining", &self.is_draining())
+            .finish()
+    }
+}
+
+impl AgentSystem {
+    /// Create a system with no agents.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Give agents `deadline` to drain their mailboxes when the system shuts
+    /// down. Defaults to [`DEFAULT_DEADLINE`].
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Returns a token that's cancelled when the deadline passes, to build
+    /// agents with, e.g. with
+    /// [`AgentBuilder::with_cancellation`].
+    pub fn token(&self) -> CancellationToken {
+        self.shared.stopped.child_token()
+    }
+
+    /// Returns a token that's cancelled as soon as the system starts
+    /// shutting down, so agents can stop taking on new work.
+    pub fn draining(&self) -> CancellationToken {
+        self.shared.draining.child_token()
+    }
+
+    /// Returns whether the system is shutting down.
+    pub fn is_draining(&self) -> bool {
+        self.shared.draining.is_cancelled()
+    }
+
+    /// Spawn an agent built by `builder`, handling messages with `handler`,
+    /// and own it. Returns a sender to it.
+    pub fn spawn<M, E, H, R>(&self, builder: AgentBuilder<M, E>, handler: H) -> Sender<M>
+    where
+        M: Debug + Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+        H: Fn(Sender<M>, M) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        let agent = builder.with_cancellation(self.token()).spawn(handler);
+        let sender = agent.sender();
+        self.register(agent);
+        sender
+    }
+
+    /// Own `instance`, e.g. an agent or an
+    /// [assistant](super::assistant::Assistant), and shut it down with the
+    /// system. Instances registered once the system is shutting down are
+    /// terminated at once.
+    pub fn register(&self, instance: impl Instance) {
+        if self.is_draining() {
+            tracing::debug!("system is shutting down; terminating agent");
+            tokio::spawn(instance.terminate());
+            return;
+        }
+        let mut members = self.shared.members.lock().unwrap();
+        members.retain(|member| !member.is_finished());
+        members.push(Box::new(instance));
+    }
+
+    /// Returns the number of agents still running.
+    pub fn len(&self) -> usize {
+        let mut members = self.shared.members.lock().unwrap();
+        members.retain(|member| !member.is_finished());
+        members.len()
+    }
+
+    /// Returns whether no agents are running.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Shut the system down: signal the agents to drain, terminate them all
+    /// at once, and stop those still running when the deadline passes.
+    /// Reports a forced shutdown if any agent had to be stopped.
+    pub async fn shutdown(&self) -> Shutdown {
+        tracing::debug!(agents = self.len(), deadline = ?self.deadline, "shutting down");
+        self.shared.draining.cancel();
+        let members = std::mem::take(&mut *self.shared.members.lock().unwrap());
+        let terminating = members
+            .into_iter()
+            .map(|member| tokio::spawn(member.terminate()))
+            .collect::<Vec<_>>();
+
+        let deadline = tokio::time::Instant::now() + self.deadline;
+        let mut shutdown = Shutdown::Graceful;
+        for mut terminated in terminating {
+            let terminated = match tokio::time::timeout_at(deadline, &mut terminated).await {
+                Ok(terminated) => terminated,
+                Err(_) => {
+                    if !self.shared.stopped.is_cancelled() {
+                        tracing::warn!("deadline passed; stopping the agents still running");
+                        self.shared.stopped.cancel();
+                    }
+                    shutdown = Shutdown::Forced;
+                    terminated.await
+                }
+            };
+            if !matches!(terminated, Ok(Shutdown::Graceful)) {
+                shutdown = Shutdown::Forced;
+            }
+        }
+        // agents spawned with the token but never registered stop too
+        self.shared.stopped.cancel();
+        tracing::debug!(?shutdown, "shut down");
+        shutdown
+    }
+
+    /// Wait for Ctrl-C, or SIGTERM on Unix, and then shut the system down.
+    pub async fn shutdown_on_signal(&self) -> io::Result<Shutdown> {
+        signal().await?;
+        Ok(self.shutdown().await)
+    }
+}
+
+/// Waits for Ctrl-C, or SIGTERM on Unix.
+pub async fn signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            signalled = tokio::signal::ctrl_c() => signalled,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::agent::{Agent, SendError},
+        anyhow::Result,
+    };
+
+    #[tokio::test]
+    async fn test_shutdown() -> Result<()> {
+        let system = AgentSystem::new().with_deadline(Duration::from_millis(100));
+        let draining = system.draining();
+        let (handled, mut handled_messages) = tokio::sync::mpsc::unbounded_channel();
+        let quick = system.spawn(AgentBuilder::new(), move |_sender, message: u32| {
+            let handled = handled.clone();
+            async move {
+                let _ = handled.send(message);
+                Ok::<_, SendError<u32>>(())
+            }
+        });
+        let stuck = system.spawn(AgentBuilder::new(), |_sender, _message: u32| async {
+            std::future::pending::<Result<(), SendError<u32>>>().await
+        });
+        quick.send(1).await?;
+        quick.send(2).await?;
+        stuck.send(1).await?;
+        assert_eq!(system.len(), 2);
+
+        assert_eq!(system.shutdown().await, Shutdown::Forced);
+        assert!(draining.is_cancelled());
+        assert_eq!(handled_messages.recv().await, Some(1));
+        assert_eq!(handled_messages.recv().await, Some(2));
+        assert!(quick.is_closed() && stuck.is_closed());
+        assert!(system.is_empty());
+
+        // too late to join
+        let late = Agent::spawn(uuid::Uuid::new_v4(), None, |_sender, _message: u32| async {
+            Ok::<_, SendError<u32>>(())
+        });
+        let late_sender = late.sender();
+        system.register(late);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !late_sender.is_closed() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await?;
+
+        let system = AgentSystem::new();
+        system.spawn(AgentBuilder::new(), |_sender, _message: u32| async {
+            Ok::<_, SendError<u32>>(())
+        });
+        assert_eq!(system.shutdown().await, Shutdown::Graceful);
+        Ok(())
+    }
+}
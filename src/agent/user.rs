@@ -1,9 +1,27 @@
 //! A proxy agent for the user. Every time the agent receives a message, it asks
 //! the user for input and sends the input back to the sender of the message.
+//!
+//! Replies streamed to the user agent are previewed on the terminal as they
+//! are generated.
+//!
+//! Input is read asynchronously, so waiting for the user doesn't block the
+//! runtime's worker threads.
 
 use {
-    super::{Actor, Message, Sender},
+    super::{console::Console, registry::Instance, Actor, Message, Sender, Shutdown, StreamEvent},
     crate::Agent,
+    std::{
+        fmt,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    },
+    tokio::{
+        io::{AsyncBufRead, AsyncBufReadExt, BufReader},
+        sync::Mutex,
+        task::JoinHandle,
+    },
     uuid::Uuid,
 };
 
@@ -15,10 +33,20 @@ pub enum Error {
 
     #[error("failed to read user input: {0:?}")]
     ReadUserInputError(#[from] std::io::Error),
+
+    #[error("the user's input ended")]
+    InputEnded,
 }
 
 const USER_INPUT_PREFIX: &str = ">>>";
 
+/// The reply sent for the user once their input ends, which ends
+/// conversations with conversable agents and human fallbacks.
+const EXIT: &str = "exit";
+
+/// Where a user agent reads the user's input from.
+pub type Input = Box<dyn AsyncBufRead + Send + Unpin>;
+
 /// A user proxy agent.
 ///
 /// Usage:
@@ -32,44 +60,142 @@ const USER_INPUT_PREFIX: &str = ">>>";
 #[derive(Debug)]
 pub struct UserAgent {
     pub agent: Agent<Box<Message>, Error>,
+
+    /// A channel to stream replies to the user agent.
+    stream: Sender<StreamEvent>,
+
+    /// A handle to the task rendering streamed replies.
+    renderer: JoinHandle<()>,
+
+    /// Whether failing to read the user's input is replied to with the
+    /// error rather than stopping the user agent.
+    error_replies: Arc<AtomicBool>,
 }
 
 impl UserAgent {
-    /// Create a new user agent.
-    pub fn spawn(id: Uuid, name: Option<String>) -> Self {
+    /// Create a new user agent that reads the user's input from `input`.
+    /// Once the input ends, e.g. the user hits Ctrl-D or a script runs out,
+    /// the user agent replies `exit` and stops, so the conversation doesn't
+    /// go on without the user.
+    pub fn spawn(id: Uuid, name: Option<String>, input: Input) -> Self {
+        // the reader is shared by every message so buffered input isn't lost
+        let input = Arc::new(Mutex::new(input));
         let prompt_id = name.clone().unwrap_or_else(|| id.to_string());
-        let agent = Agent::<Box<Message>, _>::spawn(id, name, move |sender, message| {
-            let prompt_id = prompt_id.clone();
-            async move {
-                println!("{prompt_id} {USER_INPUT_PREFIX} {}", message.content);
-                let mut input = String::new();
-                std::io::stdin().read_line(&mut input)?;
-
-                // reply to message sender with the user input
-                message.sender.send(Box::new(Message {
-                    sender,
-                    content: input.trim().to_string(),
-                }))?;
-                Ok(())
+        let console = Console::new();
+        let (stream, mut events) = super::channel(None);
+        let renderer = {
+            let console = console.clone();
+            tokio::spawn(async move {
+                let spinner = tokio::spawn(console.clone().spin());
+                while let Some(event) = events.recv().await {
+                    match event {
+                        StreamEvent::Token(token) => console.token(&token),
+                        StreamEvent::Progress(progress) => console.progress(&progress),
+                        // thoughts aren't part of the reply, so they aren't shown, and the
+                        // complete reply is shown once it's delivered
+                        StreamEvent::Thought(_) | StreamEvent::Complete(_) => {}
+                    }
+                }
+                spinner.abort();
+            })
+        };
+
+        let error_replies = Arc::new(AtomicBool::new(true));
+        let agent = Agent::<Box<Message>, _>::spawn(id, name, {
+            let error_replies = error_replies.clone();
+            move |sender, message| {
+                let prompt_id = prompt_id.clone();
+                let console = console.clone();
+                let input = input.clone();
+                let error_replies = error_replies.clone();
+                async move {
+                    console.message(&format!(
+                        "{prompt_id} {USER_INPUT_PREFIX} {}",
+                        message.content
+                    ));
+                    let mut line = String::new();
+                    let read = input.lock().await.read_line(&mut line).await;
+                    match read {
+                        Ok(0) => {
+                            tracing::debug!(%id, "input ended; leaving the conversation");
+                            message
+                                .sender
+                                .send(Box::new(Message::new(sender, EXIT)))
+                                .await?;
+                            return Err(Error::InputEnded);
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            let e = Error::from(e);
+                            if !error_replies.load(Ordering::Relaxed) {
+                                return Err(e);
+                            }
+                            tracing::debug!(%id, error = %e, "unable to read input; telling the sender");
+                            line = format!("error: {e}");
+                        }
+                    }
+
+                    // reply to message sender with the user input
+                    message
+                        .sender
+                        .send(Box::new(Message::new(sender, line.trim().to_string())))
+                        .await?;
+                    console.wait();
+                    Ok(())
+                }
             }
         });
 
-        Self { agent }
+        Self {
+            agent,
+            stream,
+            renderer,
+            error_replies,
+        }
+    }
+
+    /// Reply with the error, e.g. `error: failed to read user input: ...`,
+    /// when the user's input can't be read, so the conversation can go on,
+    /// instead of stopping the user agent. On by default.
+    pub fn set_error_replies(&self, error_replies: bool) {
+        self.error_replies.store(error_replies, Ordering::Relaxed);
+    }
+
+    /// Returns a sender that streams replies to the user agent. Tokens sent to
+    /// it are previewed on the terminal until the complete reply arrives.
+    pub fn stream_sender(&self) -> Sender<StreamEvent> {
+        self.stream.clone()
     }
 
     /// Returns a sender that can be used to send messages to the user agent.
     pub fn sender(&self) -> Sender<Box<Message>> {
-        Sender(self.agent.sender.clone())
+        self.agent.sender()
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Default)]
 pub struct UserAgentBuilder {
     /// Unique identifier for the user agent.
     pub id: Option<Uuid>,
 
     /// A user-friendly name for the user agent.
     pub name: Option<String>,
+
+    /// Where to read the user's input from. Defaults to stdin.
+    pub input: Option<Input>,
+
+    /// Whether failing to read the user's input is replied to with the
+    /// error rather than stopping the user agent. Defaults to `true`.
+    pub error_replies: Option<bool>,
+}
+
+impl fmt::Debug for UserAgentBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserAgentBuilder")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
 }
 
 impl UserAgentBuilder {
@@ -90,9 +216,49 @@ impl UserAgentBuilder {
         self
     }
 
+    /// Read the user's input from `input` instead of stdin, e.g. to script
+    /// the user's side of a conversation.
+    pub fn with_input(mut self, input: impl AsyncBufRead + Send + Unpin + 'static) -> Self {
+        self.input = Some(Box::new(input));
+        self
+    }
+
+    /// Reply with the error when the user's input can't be read, or stop
+    /// the user agent if `false`. See [`UserAgent::set_error_replies`].
+    pub fn with_error_replies(mut self, error_replies: bool) -> Self {
+        self.error_replies = Some(error_replies);
+        self
+    }
+
     /// Builds the user agent.
     pub fn build(self) -> UserAgent {
-        UserAgent::spawn(self.id.unwrap_or_else(Uuid::new_v4), self.name)
+        let user_agent = UserAgent::spawn(
+            self.id.unwrap_or_else(Uuid::new_v4),
+            self.name,
+            self.input
+                .unwrap_or_else(|| Box::new(BufReader::new(tokio::io::stdin()))),
+        );
+        if let Some(error_replies) = self.error_replies {
+            user_agent.set_error_replies(error_replies);
+        }
+        user_agent
+    }
+}
+
+impl Instance for UserAgent {
+    type Checkpoint = ();
+    type Message = Box<Message>;
+
+    fn sender(&self) -> Sender<Box<Message>> {
+        self.agent.sender()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.agent.is_finished()
+    }
+
+    async fn terminate(self) -> Shutdown {
+        Actor::terminate(self).await
     }
 }
 
@@ -109,16 +275,90 @@ impl Actor for UserAgent {
         self.agent.name.as_deref()
     }
 
-    async fn terminate(self) {
-        self.agent.terminate().await;
+    fn sender(&self) -> Sender<Box<Message>> {
+        self.agent.sender()
+    }
+
+    async fn terminate(self) -> Shutdown {
+        let shutdown = self.agent.terminate().await;
+        self.renderer.abort();
+        shutdown
     }
 
     fn abort(self) {
-        self.agent.abort()
+        self.agent.abort();
+        self.renderer.abort();
+    }
+
+    async fn send(&self, message: Self::Message) -> Result<(), Self::Error> {
+        self.agent.send(Box::new(message)).await?;
+        Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, anyhow::Result};
+
+    #[tokio::test]
+    async fn test_reads_input_in_order() -> Result<()> {
+        let user = UserAgentBuilder::new()
+            .with_input(&b"first\nsecond\n"[..])
+            .build();
+
+        let mut reply = user.sender().ask_stream("one").await?;
+        assert_eq!(
+            reply.next().await,
+            Some(StreamEvent::Complete("first".to_string()))
+        );
+        let mut reply = user.sender().ask_stream("two").await?;
+        assert_eq!(
+            reply.next().await,
+            Some(StreamEvent::Complete("second".to_string()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_error_replies() -> Result<()> {
+        let input = tokio_test::io::Builder::new()
+            .read_error(std::io::Error::other("closed"))
+            .read(b"still here\n")
+            .build();
+        let user = UserAgentBuilder::new()
+            .with_input(BufReader::new(input))
+            .build();
+
+        let mut reply = user.sender().ask_stream("one").await?;
+        match reply.next().await {
+            Some(StreamEvent::Complete(content)) => assert!(content.starts_with("error: ")),
+            event => panic!("expected the error, got {event:?}"),
+        }
+        let mut reply = user.sender().ask_stream("two").await?;
+        assert_eq!(
+            reply.next().await,
+            Some(StreamEvent::Complete("still here".to_string()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_leaves_when_input_ends() -> Result<()> {
+        let user = UserAgentBuilder::new()
+            .with_input(&b"only\n"[..])
+            .build();
 
-    fn send(&self, message: Self::Message) -> Result<(), Self::Error> {
-        self.agent.send(Box::new(message))?;
+        let mut reply = user.sender().ask_stream("one").await?;
+        assert_eq!(
+            reply.next().await,
+            Some(StreamEvent::Complete("only".to_string()))
+        );
+        let mut reply = user.sender().ask_stream("two").await?;
+        assert_eq!(
+            reply.next().await,
+            Some(StreamEvent::Complete(EXIT.to_string()))
+        );
+        assert!(matches!(user.agent.join().await?, Err(Error::InputEnded)));
         Ok(())
     }
 }
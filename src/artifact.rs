@@ -0,0 +1,298 @@
+//! Keeping long outputs out of conversations. A [`Spill`] stores text over a
+//! size threshold, e.g. a verbose tool's result, as an artifact and puts a
+//! short reference with a preview in its place, so one output can't fill a
+//! model's context. The model reads the rest through the [`ReadArtifact`]
+//! tool when it needs it.
+
+use {
+    crate::tools::{Error as ToolError, Tool, ToolFuture},
+    serde_json::{json, Value},
+    std::{
+        collections::HashMap,
+        fmt::Debug,
+        io,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    },
+    uuid::Uuid,
+};
+
+/// The length, in characters, over which text is spilled when no threshold
+/// is configured.
+pub const DEFAULT_THRESHOLD: usize = 8 * 1024;
+
+/// The number of characters previewed in a reference when no preview length
+/// is configured.
+pub const DEFAULT_PREVIEW: usize = 500;
+
+/// The name of the [`ReadArtifact`] tool.
+pub const READ_TOOL: &str = "read_artifact";
+
+/// Where artifacts are kept.
+pub trait ArtifactStore: Debug + Send + Sync + 'static {
+    /// Store `content`. Returns the artifact's id.
+    fn put(&self, content: &str) -> io::Result<String>;
+
+    /// Returns the content of the artifact with `id`, or `None` if there's
+    /// no such artifact.
+    fn get(&self, id: &str) -> io::Result<Option<String>>;
+}
+
+/// Artifacts kept in memory, for as long as the store lives.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    artifacts: Mutex<HashMap<String, String>>,
+}
+
+impl MemoryStore {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl ArtifactStore for MemoryStore {
+    fn put(&self, content: &str) -> io::Result<String> {
+        let id = Uuid::new_v4().to_string();
+        self.artifacts
+            .lock()
+            .unwrap()
+            .insert(id.clone(), content.to_string());
+        Ok(id)
+    }
+
+    fn get(&self, id: &str) -> io::Result<Option<String>> {
+        Ok(self.artifacts.lock().unwrap().get(id).cloned())
+    }
+}
+
+/// Artifacts kept as files in a directory, one file per artifact.
+#[derive(Debug, Clone)]
+pub struct DirStore {
+    dir: PathBuf,
+}
+
+impl DirStore {
+    /// Create a store in `dir`, which is created when the first artifact is
+    /// stored.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        // ids are ours, so anything else can't name an artifact
+        Uuid::parse_str(id)
+            .ok()
+            .map(|id| self.dir.join(format!("{id}.txt")))
+    }
+}
+
+impl ArtifactStore for DirStore {
+    fn put(&self, content: &str) -> io::Result<String> {
+        std::fs::create_dir_all(&self.dir)?;
+        let id = Uuid::new_v4().to_string();
+        std::fs::write(self.dir.join(format!("{id}.txt")), content)?;
+        Ok(id)
+    }
+
+    fn get(&self, id: &str) -> io::Result<Option<String>> {
+        let Some(path) = self.path(id) else {
+            return Ok(None);
+        };
+        match std::fs::read_to_string(path) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Spills text over a threshold into an [`ArtifactStore`]. Clones share the
+/// same store.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::{agent::assistant::AssistantBuilder, artifact::{MemoryStore, Spill}};
+/// # tokio_test::block_on(async {
+/// let spill = Spill::new(MemoryStore::new()).with_threshold(4000);
+/// assert_eq!(spill.apply("short".to_string()), "short");
+/// assert!(spill.apply("x".repeat(5000)).contains("stored as artifact"));
+///
+/// // the assistant spills long messages and tool results, and can read them back
+/// let assistant = AssistantBuilder::new().with_spill(spill).build();
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct Spill {
+    store: Arc<dyn ArtifactStore>,
+    threshold: usize,
+    preview: usize,
+}
+
+impl Spill {
+    /// Spill text over [`DEFAULT_THRESHOLD`] characters into `store`.
+    pub fn new(store: impl ArtifactStore) -> Self {
+        Self {
+            store: Arc::new(store),
+            threshold: DEFAULT_THRESHOLD,
+            preview: DEFAULT_PREVIEW,
+        }
+    }
+
+    /// Spill text over `threshold` characters.
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Preview the first `preview` characters of spilled text.
+    pub fn with_preview(mut self, preview: usize) -> Self {
+        self.preview = preview;
+        self
+    }
+
+    /// Returns the store artifacts are kept in.
+    pub fn store(&self) -> &Arc<dyn ArtifactStore> {
+        &self.store
+    }
+
+    /// Returns `content`, or a reference to it if it's over the threshold.
+    /// If it can't be stored, it's returned as is.
+    pub fn apply(&self, content: String) -> String {
+        let length = content.chars().count();
+        if length <= self.threshold {
+            return content;
+        }
+        match self.store.put(&content) {
+            Ok(id) => {
+                tracing::debug!(id, length, "spilled output to artifact");
+                format!(
+                    "[{length} characters stored as artifact {id}; call {READ_TOOL} to read more]\n{}...",
+                    prefix(&content, self.preview)
+                )
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, length, "unable to spill output; keeping it");
+                content
+            }
+        }
+    }
+
+    /// Returns a tool that reads spilled artifacts, a page at a time.
+    pub fn tool(&self) -> ReadArtifact {
+        ReadArtifact {
+            store: self.store.clone(),
+            page: self.threshold.max(1),
+        }
+    }
+}
+
+/// Returns the first `chars` characters of `text`.
+fn prefix(text: &str, chars: usize) -> &str {
+    text.char_indices()
+        .nth(chars)
+        .map_or(text, |(end, _)| &text[..end])
+}
+
+/// A tool that reads part of an artifact.
+#[derive(Debug, Clone)]
+pub struct ReadArtifact {
+    store: Arc<dyn ArtifactStore>,
+    /// The most characters read at once.
+    page: usize,
+}
+
+impl Tool for ReadArtifact {
+    fn name(&self) -> &str {
+        READ_TOOL
+    }
+
+    fn description(&self) -> &str {
+        "Reads part of a stored artifact, starting at a character offset."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "id": { "type": "string", "description": "The artifact's id." },
+                "offset": { "type": "integer", "minimum": 0, "description": "The character to start at." },
+                "length": { "type": "integer", "minimum": 1, "maximum": self.page, "description": "The most characters to read." },
+            },
+            "required": ["id"],
+        })
+    }
+
+    fn call(&self, arguments: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let id = arguments["id"]
+                .as_str()
+                .ok_or_else(|| ToolError::InvalidArguments("no artifact id".to_string()))?;
+            let offset = arguments["offset"].as_u64().unwrap_or(0) as usize;
+            let length = arguments["length"]
+                .as_u64()
+                .map_or(self.page, |length| (length as usize).min(self.page));
+            let content = self
+                .store
+                .get(id)
+                .map_err(|e| ToolError::Failed(e.to_string()))?
+                .ok_or_else(|| ToolError::Failed(format!("no artifact {id}")))?;
+
+            let total = content.chars().count();
+            let page = content
+                .chars()
+                .skip(offset)
+                .take(length)
+                .collect::<String>();
+            let end = (offset + length).min(total);
+            Ok(if end < total {
+                format!(
+                    "{page}\n[{} more characters; continue at offset {end}]",
+                    total - end
+                )
+            } else {
+                page
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, anyhow::Result};
+
+    #[tokio::test]
+    async fn test_spill_and_read() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("autogen-rs-test-{}", Uuid::new_v4()));
+        let spill = Spill::new(DirStore::new(&dir))
+            .with_threshold(10)
+            .with_preview(3);
+        assert_eq!(spill.apply("0123456789".to_string()), "0123456789");
+
+        let reference = spill.apply("0123456789abcdefghij".to_string());
+        assert!(reference.starts_with("[20 characters stored as artifact "));
+        assert!(reference.ends_with("]\n012..."));
+        let id = reference
+            .split_whitespace()
+            .nth(5)
+            .map(|id| id.trim_end_matches(';'))
+            .unwrap_or_default();
+
+        let read = spill.tool();
+        assert_eq!(
+            read.call(json!({ "id": id, "offset": 5, "length": 5 }))
+                .await?,
+            "56789\n[10 more characters; continue at offset 10]"
+        );
+        // pages are at most the threshold long
+        assert_eq!(
+            read.call(json!({ "id": id, "offset": 10, "length": 100 }))
+                .await?,
+            "abcdefghij"
+        );
+        assert!(read.call(json!({ "id": "../secret" })).await.is_err());
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
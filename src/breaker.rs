@@ -0,0 +1,467 @@
+//! Circuit breakers for LLM backends and tools. A [`CircuitBreaker`] watches
+//! the outcome of recent calls; once too many fail it opens and calls are
+//! skipped without waiting on the failing provider. After a cool-down it lets
+//! a single probe through (half-open), and closes again if the probe succeeds.
+//!
+//! [`BreakerClient`] wraps an [`LlmClient`] and shifts traffic to a fallback
+//! while the breaker is open; [`BreakerTool`] wraps a [`Tool`].
+
+use {
+    crate::{
+        agent::{Clock, SystemClock},
+        llm::{self, CompletionRequest, Delta, LlmClient, LlmFuture},
+        tools::{self, Tool, ToolFuture},
+    },
+    serde_json::Value,
+    std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
+};
+
+/// Error returned instead of calling a provider whose breaker is open.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("{name} is unavailable: circuit breaker is open")]
+pub struct OpenError {
+    /// The name of the provider.
+    pub name: String,
+}
+
+/// When a breaker opens and for how long.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+    /// Open once at least this fraction of recent calls failed, from 0 to 1.
+    pub failure_rate: f64,
+
+    /// The number of recent calls the failure rate is measured over.
+    pub window: usize,
+
+    /// Don't open before this many calls were made, so a single early
+    /// failure doesn't open the breaker.
+    pub min_calls: usize,
+
+    /// How long the breaker stays open before letting a probe through.
+    pub open_for: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            failure_rate: 0.5,
+            window: 20,
+            min_calls: 5,
+            open_for: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The state of a circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Calls go through.
+    Closed,
+
+    /// Calls are skipped.
+    Open,
+
+    /// The cool-down is over; the next call is let through as a probe.
+    HalfOpen,
+}
+
+/// Tracks the outcome of calls to a provider and decides whether to make
+/// them.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    policy: Policy,
+    clock: Arc<dyn Clock>,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// Whether each recent call succeeded, oldest first.
+    outcomes: VecDeque<bool>,
+
+    /// When the breaker opened, if it's open or half-open.
+    opened_at: Option<Instant>,
+
+    /// When the probe in flight started, if the breaker is half-open.
+    probe_started: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// Create a closed breaker.
+    pub fn new(policy: Policy) -> Self {
+        Self {
+            policy,
+            clock: Arc::new(SystemClock),
+            inner: Mutex::new(Inner {
+                outcomes: VecDeque::new(),
+                opened_at: None,
+                probe_started: None,
+            }),
+        }
+    }
+
+    /// Measure the cool-down on `clock`.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Returns the breaker's state.
+    pub fn state(&self) -> State {
+        let inner = self.inner.lock().unwrap();
+        match inner.opened_at {
+            None => State::Closed,
+            Some(opened_at) if self.cooling_down(opened_at) => State::Open,
+            Some(_) => State::HalfOpen,
+        }
+    }
+
+    /// Returns whether a call may be made. A half-open breaker lets one
+    /// probe through at a time; a probe that's never recorded is given up on
+    /// after the cool-down.
+    pub fn try_acquire(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(opened_at) = inner.opened_at else {
+            return true;
+        };
+        if self.cooling_down(opened_at)
+            || inner
+                .probe_started
+                .is_some_and(|started| self.cooling_down(started))
+        {
+            return false;
+        }
+        inner.probe_started = Some(self.clock.now());
+        true
+    }
+
+    /// Record the outcome of a call.
+    pub fn record(&self, success: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.opened_at.is_some() {
+            // the outcome of the probe decides whether to close
+            inner.probe_started = None;
+            if success {
+                tracing::debug!("circuit breaker closed");
+                inner.opened_at = None;
+                inner.outcomes.clear();
+            } else {
+                inner.opened_at = Some(self.clock.now());
+            }
+            return;
+        }
+
+        inner.outcomes.push_back(success);
+        while inner.outcomes.len() > self.policy.window.max(1) {
+            inner.outcomes.pop_front();
+        }
+        let failures = inner.outcomes.iter().filter(|success| !**success).count();
+        let calls = inner.outcomes.len();
+        if calls >= self.policy.min_calls
+            && failures as f64 >= self.policy.failure_rate * calls as f64
+        {
+            tracing::debug!(failures, calls, "circuit breaker opened");
+            inner.opened_at = Some(self.clock.now());
+        }
+    }
+
+    fn cooling_down(&self, since: Instant) -> bool {
+        self.clock.now().saturating_duration_since(since) < self.policy.open_for
+    }
+}
+
+/// An [`LlmClient`] behind a circuit breaker. While the breaker is open,
+/// requests go to the fallback, if there is one, or fail right away.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{breaker::{BreakerClient, Policy}, llm::openai}, std::sync::Arc};
+/// let primary = Arc::new(openai::Client::new(None, None));
+/// let fallback = Arc::new(openai::Client::new(
+///     None,
+///     Some("http://localhost:11434/v1".to_string()),
+/// ));
+/// let client = BreakerClient::new("openai", primary, Policy::default()).with_fallback(fallback);
+/// ```
+#[derive(Debug)]
+pub struct BreakerClient {
+    name: String,
+    client: Arc<dyn LlmClient>,
+    breaker: CircuitBreaker,
+    fallback: Option<Arc<dyn LlmClient>>,
+}
+
+impl BreakerClient {
+    /// Put `client` behind a breaker with `policy`. The name identifies the
+    /// backend in errors.
+    pub fn new(name: impl ToString, client: Arc<dyn LlmClient>, policy: Policy) -> Self {
+        Self {
+            name: name.to_string(),
+            client,
+            breaker: CircuitBreaker::new(policy),
+            fallback: None,
+        }
+    }
+
+    /// Send requests to `fallback` while the breaker is open.
+    pub fn with_fallback(mut self, fallback: Arc<dyn LlmClient>) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// Measure the breaker's cool-down on `clock`.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.breaker = self.breaker.with_clock(clock);
+        self
+    }
+
+    /// Returns the breaker's state.
+    pub fn state(&self) -> State {
+        self.breaker.state()
+    }
+
+    /// Returns the client to send the next request to, or an error if there
+    /// is none. Returns true along with the client if it's the primary.
+    fn select(&self) -> Result<(&dyn LlmClient, bool), llm::Error> {
+        if self.breaker.try_acquire() {
+            return Ok((&*self.client, true));
+        }
+        match &self.fallback {
+            Some(fallback) => {
+                tracing::trace!(name = self.name, "circuit open; using fallback");
+                Ok((&**fallback, false))
+            }
+            None => Err(llm::Error::Other(Box::new(OpenError {
+                name: self.name.clone(),
+            }))),
+        }
+    }
+}
+
+impl LlmClient for BreakerClient {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            let (client, primary) = self.select()?;
+            let result = client.complete(request).await;
+            if primary {
+                self.breaker.record(result.is_ok());
+            }
+            result
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: CompletionRequest,
+        on_delta: &'a mut (dyn FnMut(Delta<'_>) + Send),
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            let (client, primary) = self.select()?;
+            let result = client.stream(request, on_delta).await;
+            if primary {
+                self.breaker.record(result.is_ok());
+            }
+            result
+        })
+    }
+}
+
+/// A [`Tool`] behind a circuit breaker. Only failures of the tool itself
+/// count; invalid arguments from the model don't.
+#[derive(Debug)]
+pub struct BreakerTool<T> {
+    tool: T,
+    breaker: CircuitBreaker,
+}
+
+impl<T: Tool> BreakerTool<T> {
+    /// Put `tool` behind a breaker with `policy`.
+    pub fn new(tool: T, policy: Policy) -> Self {
+        Self {
+            tool,
+            breaker: CircuitBreaker::new(policy),
+        }
+    }
+
+    /// Measure the breaker's cool-down on `clock`.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.breaker = self.breaker.with_clock(clock);
+        self
+    }
+
+    /// Returns the breaker's state.
+    pub fn state(&self) -> State {
+        self.breaker.state()
+    }
+}
+
+impl<T: Tool> Tool for BreakerTool<T> {
+    fn name(&self) -> &str {
+        self.tool.name()
+    }
+
+    fn description(&self) -> &str {
+        self.tool.description()
+    }
+
+    fn parameters(&self) -> Value {
+        self.tool.parameters()
+    }
+
+    fn call(&self, arguments: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            if !self.breaker.try_acquire() {
+                let name = self.name().to_string();
+                return Err(tools::Error::Failed(OpenError { name }.to_string()));
+            }
+            let result = self.tool.call(arguments).await;
+            self.breaker
+                .record(!matches!(result, Err(tools::Error::Failed(_))));
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            agent::ManualClock,
+            llm::{openai, Completion},
+            tools::FnTool,
+        },
+        anyhow::Result,
+        serde_json::json,
+        std::sync::atomic::{AtomicBool, Ordering},
+    };
+
+    fn policy() -> Policy {
+        Policy {
+            failure_rate: 0.5,
+            window: 4,
+            min_calls: 2,
+            open_for: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn test_breaker_states() {
+        let clock = ManualClock::new();
+        let breaker = CircuitBreaker::new(policy()).with_clock(clock.clone());
+
+        breaker.record(false);
+        assert_eq!(breaker.state(), State::Closed);
+        breaker.record(false);
+        assert_eq!(breaker.state(), State::Open);
+        assert!(!breaker.try_acquire());
+
+        // a failed probe opens the breaker again
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(breaker.state(), State::HalfOpen);
+        assert!(breaker.try_acquire());
+        assert!(!breaker.try_acquire());
+        breaker.record(false);
+        assert_eq!(breaker.state(), State::Open);
+
+        // a successful probe closes it
+        clock.advance(Duration::from_secs(30));
+        assert!(breaker.try_acquire());
+        breaker.record(true);
+        assert_eq!(breaker.state(), State::Closed);
+        breaker.record(false);
+        assert_eq!(breaker.state(), State::Closed);
+    }
+
+    /// A backend that fails while `failing` is set.
+    #[derive(Debug, Default)]
+    struct Flaky {
+        failing: AtomicBool,
+    }
+
+    impl LlmClient for Flaky {
+        fn complete(&self, _request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                if self.failing.load(Ordering::SeqCst) {
+                    return Err(openai::Error::EmptyResponse.into());
+                }
+                Ok(Completion {
+                    content: "primary".to_string(),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    /// A backend that always replies "fallback".
+    #[derive(Debug)]
+    struct Fallback;
+
+    impl LlmClient for Fallback {
+        fn complete(&self, _request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                Ok(Completion {
+                    content: "fallback".to_string(),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    fn request() -> CompletionRequest {
+        CompletionRequest {
+            model: "any".to_string(),
+            messages: Vec::new(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_shifts_to_fallback() -> Result<()> {
+        let clock = ManualClock::new();
+        let primary = Arc::new(Flaky::default());
+        let client = BreakerClient::new("primary", primary.clone(), policy())
+            .with_fallback(Arc::new(Fallback))
+            .with_clock(clock.clone());
+
+        primary.failing.store(true, Ordering::SeqCst);
+        assert!(client.complete(request()).await.is_err());
+        assert!(client.complete(request()).await.is_err());
+        assert_eq!(client.complete(request()).await?.content, "fallback");
+
+        primary.failing.store(false, Ordering::SeqCst);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(client.complete(request()).await?.content, "primary");
+        assert_eq!(client.state(), State::Closed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tool_fails_fast_when_open() -> Result<()> {
+        let tool = BreakerTool::new(
+            FnTool::new("down", "Always fails.", json!({}), |_| async {
+                Err(tools::Error::Failed("service unavailable".to_string()))
+            }),
+            policy(),
+        );
+
+        for _ in 0..2 {
+            assert_eq!(
+                tool.call(json!({})).await,
+                Err(tools::Error::Failed("service unavailable".to_string()))
+            );
+        }
+        assert_eq!(tool.state(), State::Open);
+        assert_eq!(
+            tool.call(json!({})).await,
+            Err(tools::Error::Failed(
+                "down is unavailable: circuit breaker is open".to_string()
+            ))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+//! Pulling typed variables out of a finished chat. An [`Extractor`] asks a
+//! model to read the transcript and fill in the variables it's given, e.g.
+//! an order id, a decision or a score, each with a JSON schema. A chat with
+//! an extractor puts them in [`ChatOutcome::variables`](super::ChatOutcome),
+//! so code after the chat doesn't have to parse free text.
+
+use {
+    super::ChatMessage,
+    crate::{
+        agent::assistant::parse_json,
+        llm::{self, CompletionRequest, HistoryMessage, LlmClient, ResponseFormat, Role},
+        prompts::{Prompt, PromptPack},
+    },
+    schemars::JsonSchema,
+    serde_json::{json, Map, Value},
+    std::{collections::HashMap, sync::Arc},
+};
+
+/// An error extracting variables from a chat.
+#[derive(thiserror::Error, Debug)]
+pub enum ExtractError {
+    /// The model couldn't be asked.
+    #[error("unable to ask model: {0}")]
+    Llm(#[from] llm::Error),
+
+    /// The model's reply isn't JSON.
+    #[error("unable to parse extracted variables: {0}")]
+    Parse(#[from] serde_json::Error),
+
+    /// The model's reply is JSON, but not an object of variables.
+    #[error("extracted variables aren't a JSON object")]
+    NotAnObject,
+}
+
+/// A variable to extract.
+#[derive(Debug, Clone, PartialEq)]
+struct Variable {
+    name: String,
+    description: String,
+    schema: Value,
+}
+
+/// A model extracts variables from a chat's transcript. Variables the chat
+/// doesn't settle are left out.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{chat::{extract::Extractor, ChatBuilder}, llm::openai}, serde_json::json, std::sync::Arc};
+/// #[derive(schemars::JsonSchema)]
+/// enum Decision {
+///     Refund,
+///     Replace,
+///     Reject,
+/// }
+///
+/// let extractor = Extractor::new(Arc::new(openai::Client::new(None, None)), "gpt-4o-mini")
+///     .with_variable("order_id", "The id of the order discussed", json!({"type": "string"}))
+///     .with_typed_variable::<Decision>("decision", "What was decided about the order");
+/// let chat = ChatBuilder::new().with_extractor(extractor);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Extractor {
+    client: Arc<dyn LlmClient>,
+    model: String,
+    variables: Vec<Variable>,
+
+    /// The schemas the variables' schemas refer to, by name.
+    definitions: Map<String, Value>,
+    prompts: PromptPack,
+}
+
+impl Extractor {
+    /// Create an extractor that asks `model` of `client`, with no variables.
+    pub fn new(client: Arc<dyn LlmClient>, model: impl ToString) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            variables: Vec::new(),
+            definitions: Map::new(),
+            prompts: PromptPack::default(),
+        }
+    }
+
+    /// Extract the variable `name`, described to the model by
+    /// `description`, as JSON matching `schema`.
+    pub fn with_variable(
+        mut self,
+        name: impl ToString,
+        description: impl ToString,
+        schema: Value,
+    ) -> Self {
+        self.variables.push(Variable {
+            name: name.to_string(),
+            description: description.to_string(),
+            schema,
+        });
+        self
+    }
+
+    /// Extract the variable `name`, described to the model by
+    /// `description`, as JSON that deserializes into `T`.
+    pub fn with_typed_variable<T: JsonSchema>(
+        mut self,
+        name: impl ToString,
+        description: impl ToString,
+    ) -> Self {
+        let mut schema = ResponseFormat::of::<T>().schema;
+        if let Some(schema) = schema.as_object_mut() {
+            schema.remove("$schema");
+            // nested types refer to their schemas from the top
+            if let Some(Value::Object(definitions)) = schema.remove("definitions") {
+                self.definitions.extend(definitions);
+            }
+        }
+        self.with_variable(name, description, schema)
+    }
+
+    /// Ask the model with `prompts` instead of the English ones.
+    pub fn with_prompts(mut self, prompts: PromptPack) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    /// Returns the format of the model's reply: an object with every
+    /// variable, each null if the chat doesn't settle it.
+    fn format(&self) -> ResponseFormat {
+        let properties = self
+            .variables
+            .iter()
+            .map(|variable| {
+                let schema = json!({
+                    "description": variable.description,
+                    "anyOf": [variable.schema, {"type": "null"}],
+                });
+                (variable.name.clone(), schema)
+            })
+            .collect::<Map<_, _>>();
+        let mut schema = json!({
+            "type": "object",
+            "properties": properties,
+            "required": self.variables.iter().map(|v| &v.name).collect::<Vec<_>>(),
+            "additionalProperties": false,
+        });
+        if !self.definitions.is_empty() {
+            schema["definitions"] = Value::Object(self.definitions.clone());
+        }
+        ResponseFormat::new("variables", schema)
+    }
+
+    /// Extract the variables from `transcript`.
+    pub async fn extract(
+        &self,
+        transcript: &[ChatMessage],
+    ) -> Result<HashMap<String, Value>, ExtractError> {
+        if self.variables.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let mut messages = vec![HistoryMessage::new(
+            Role::System,
+            self.prompts.get(Prompt::Extraction),
+        )];
+        messages.extend(transcript.iter().map(|message| {
+            HistoryMessage::new(Role::User, format!("{}: {}", message.name, message.content))
+        }));
+        let request = CompletionRequest {
+            model: self.model.clone(),
+            messages,
+            response_format: Some(self.format()),
+            ..Default::default()
+        };
+        let completion = self.client.complete(request).await?;
+        let Value::Object(mut extracted) = parse_json(&completion.content)? else {
+            return Err(ExtractError::NotAnObject);
+        };
+        let variables = self
+            .variables
+            .iter()
+            .filter_map(|variable| {
+                let value = extracted.remove(&variable.name)?;
+                (!value.is_null()).then(|| (variable.name.clone(), value))
+            })
+            .collect::<HashMap<_, _>>();
+        tracing::debug!(
+            extracted = variables.len(),
+            of = self.variables.len(),
+            "extracted variables"
+        );
+        Ok(variables)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            agent::{Message, SendError},
+            chat::ChatBuilder,
+            llm::{Completion, LlmFuture},
+            Agent,
+        },
+        anyhow::Result,
+        uuid::Uuid,
+    };
+
+    /// Spawns an agent that replies "ok".
+    fn spawn_ok() -> Agent<Box<Message>, SendError<Box<Message>>> {
+        Agent::spawn(
+            Uuid::new_v4(),
+            None,
+            |sender, message: Box<Message>| async move {
+                message
+                    .sender
+                    .send(Box::new(Message::new(sender, "ok".to_string())))
+                    .await
+            },
+        )
+    }
+
+    /// Replies with the variables it finds, checking it was asked for them.
+    #[derive(Debug)]
+    struct Reader;
+
+    impl LlmClient for Reader {
+        fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                let format = request.response_format.unwrap();
+                assert_eq!(
+                    format.schema["required"],
+                    json!(["order_id", "decision", "score"])
+                );
+                assert!(format.schema["definitions"]["Kind"].is_object());
+                Ok(Completion {
+                    content: "```json\n{\"order_id\": \"A-17\", \"decision\": {\"kind\": \"refund\"}, \"score\": null}\n```"
+                        .to_string(),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    #[derive(Debug, PartialEq, serde::Deserialize, JsonSchema)]
+    #[serde(rename_all = "snake_case")]
+    enum Kind {
+        Refund,
+        Replace,
+    }
+
+    #[derive(Debug, PartialEq, serde::Deserialize, JsonSchema)]
+    struct Decision {
+        kind: Kind,
+    }
+
+    #[tokio::test]
+    async fn test_extract() -> Result<()> {
+        let extractor = Extractor::new(Arc::new(Reader), "any")
+            .with_variable("order_id", "The order's id", json!({"type": "string"}))
+            .with_typed_variable::<Decision>("decision", "What was decided")
+            .with_variable(
+                "score",
+                "How satisfied the customer was",
+                json!({"type": "integer"}),
+            );
+        let transcript = [ChatMessage {
+            name: "customer".to_string(),
+            content: "Order A-17 arrived broken; I'd like my money back.".to_string(),
+            thought: None,
+        }];
+        let variables = extractor.extract(&transcript).await?;
+        assert_eq!(variables.len(), 2);
+        assert_eq!(variables["order_id"], "A-17");
+        assert_eq!(
+            serde_json::from_value::<Decision>(variables["decision"].clone())?,
+            Decision { kind: Kind::Refund }
+        );
+
+        // a chat with the extractor puts them in its outcome
+        let (a, b) = (spawn_ok(), spawn_ok());
+        let outcome = ChatBuilder::new()
+            .with_participant("customer", a.sender())
+            .with_participant("support", b.sender())
+            .with_max_turns(1)
+            .with_extractor(extractor)
+            .start("Order A-17 arrived broken; I'd like my money back.")
+            .join()
+            .await;
+        assert_eq!(
+            outcome.variable::<String>("order_id").as_deref(),
+            Some("A-17")
+        );
+        assert_eq!(
+            outcome.variable::<Decision>("decision"),
+            Some(Decision { kind: Kind::Refund })
+        );
+        assert_eq!(outcome.variable::<u32>("score"), None);
+        Ok(())
+    }
+}
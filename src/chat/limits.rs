@@ -0,0 +1,183 @@
+//! Limits on the length of each reply in a chat, to keep agents replying to
+//! each other from running up ever longer, costlier messages. A reply over a
+//! limit is either cut short or sent back to its speaker to try again, and
+//! the speaker is told why.
+
+use {crate::tokenizer::TokenizerRegistry, std::fmt};
+
+/// What a reply's length is measured in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// The reply's characters.
+    Chars,
+
+    /// The reply's tokens.
+    Tokens,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Chars => f.write_str("characters"),
+            Self::Tokens => f.write_str("tokens"),
+        }
+    }
+}
+
+/// A reply that's over a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    /// The limit the reply is over.
+    pub limit: Limit,
+
+    /// The reply's length.
+    pub length: usize,
+
+    /// The most the reply may have.
+    pub max: usize,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the reply was {} {}, over the limit of {}",
+            self.length, self.limit, self.max
+        )
+    }
+}
+
+/// What happens to a reply over a limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Enforcement {
+    /// Cut the reply to the limit, and tell the speaker next time it speaks.
+    #[default]
+    Truncate,
+
+    /// Send the reply back to the speaker to try again, up to `retries`
+    /// times, then cut it to the limit.
+    Reject { retries: usize },
+}
+
+/// Caps on the length of each reply in a chat.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::chat::limits::ReplyLimits;
+/// let limits = ReplyLimits::new()
+///     .with_max_chars(2_000)
+///     .with_max_tokens(400)
+///     .with_rejection(1);
+/// assert!(limits.check("a short reply").is_none());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ReplyLimits {
+    max_chars: Option<usize>,
+    max_tokens: Option<usize>,
+    tokenizers: TokenizerRegistry,
+    model: String,
+    enforcement: Enforcement,
+}
+
+impl ReplyLimits {
+    /// Create limits that allow any reply.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Allow replies of at most `max_chars` characters.
+    pub fn with_max_chars(mut self, max_chars: usize) -> Self {
+        self.max_chars = Some(max_chars);
+        self
+    }
+
+    /// Allow replies of at most `max_tokens` tokens, estimated from their
+    /// length unless a tokenizer is set.
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Count tokens as `model` does, with its tokenizer in `tokenizers`.
+    pub fn with_tokenizer(mut self, tokenizers: TokenizerRegistry, model: impl ToString) -> Self {
+        self.tokenizers = tokenizers;
+        self.model = model.to_string();
+        self
+    }
+
+    /// Send replies over a limit back to their speaker up to `retries`
+    /// times before cutting them short.
+    pub fn with_rejection(mut self, retries: usize) -> Self {
+        self.enforcement = Enforcement::Reject { retries };
+        self
+    }
+
+    /// Returns what happens to a reply over a limit.
+    pub fn enforcement(&self) -> Enforcement {
+        self.enforcement
+    }
+
+    /// Returns the limit `content` is over, if any.
+    pub fn check(&self, content: &str) -> Option<Violation> {
+        let chars = self.max_chars.and_then(|max| {
+            let length = content.chars().count();
+            (length > max).then_some(Violation {
+                limit: Limit::Chars,
+                length,
+                max,
+            })
+        });
+        chars.or_else(|| {
+            self.max_tokens.and_then(|max| {
+                let length = self.tokenizers.count(&self.model, content);
+                (length > max).then_some(Violation {
+                    limit: Limit::Tokens,
+                    length,
+                    max,
+                })
+            })
+        })
+    }
+
+    /// Returns the longest prefix of `content` within the limits.
+    pub fn truncate<'a>(&self, content: &'a str) -> &'a str {
+        let content = match self.max_chars {
+            Some(max) => content
+                .char_indices()
+                .nth(max)
+                .map_or(content, |(end, _)| &content[..end]),
+            None => content,
+        };
+        match self.max_tokens {
+            Some(max) => self.tokenizers.truncate(&self.model, content, max),
+            None => content,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let limits = ReplyLimits::new().with_max_chars(5);
+        assert_eq!(limits.check("héllo"), None);
+        assert_eq!(
+            limits.check("héllo!"),
+            Some(Violation {
+                limit: Limit::Chars,
+                length: 6,
+                max: 5
+            })
+        );
+        assert_eq!(limits.truncate("héllo, world"), "héllo");
+
+        let limits = ReplyLimits::new().with_max_tokens(2);
+        let long = "one two three four five six seven eight nine ten";
+        let violation = limits.check(long).unwrap();
+        assert_eq!(violation.limit, Limit::Tokens);
+        assert!(limits.check(limits.truncate(long)).is_none());
+        assert!(long.starts_with(limits.truncate(long)));
+    }
+}
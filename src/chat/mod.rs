@@ -0,0 +1,1620 @@
+//! Conversations between agents.
+//!
+//! A chat sits between its participants: it delivers each message to the next
+//! speaker with itself as the sender, waits for the reply, records it and
+//! passes it on. Because every message flows through the chat, a running
+//! conversation can be stopped, paused or steered from the outside through its
+//! [`ChatHandle`].
+
+use {
+    crate::{
+        agent::{Actor, Message, SendError, Sender, StreamEvent, ToolProgress},
+        cleanup::{CleanupReport, Resources},
+        group_chat::selector::{Action, RoundRobin, Selection, Speaker, SpeakerSelector},
+        prompts::{Prompt, PromptPack},
+        recorder::{RecordKind, RecordedMessage, Recorder},
+    },
+    extract::Extractor,
+    limits::{Enforcement, ReplyLimits, Violation},
+    moderation::{Moderator, Review, Verdict},
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    std::{collections::HashMap, fmt, time::SystemTime},
+    termination::{Keyword, TerminationCondition, TerminationFuture},
+    tokio::{
+        sync::{broadcast, mpsc},
+        task::JoinHandle,
+    },
+    uuid::Uuid,
+};
+
+pub mod extract;
+pub mod limits;
+pub mod moderation;
+pub mod termination;
+pub mod typed;
+
+/// The name injected messages are recorded under in the transcript.
+const HUMAN_NAME: &str = "human";
+
+/// The name a group chat manager's messages are recorded under.
+const MANAGER_NAME: &str = "manager";
+
+/// The number of events buffered for slow subscribers.
+const EVENT_CAPACITY: usize = 256;
+
+/// The maximum number of replies in a chat started with [`initiate_chat`]
+/// when none is configured.
+pub const DEFAULT_MAX_TURNS: usize = 10;
+
+/// Why a conversation ended. Returned by conversations so callers can branch
+/// on how they finished.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TerminationReason {
+    /// The conversation reached its natural end.
+    Completed,
+
+    /// The conversation reached its maximum number of turns.
+    MaxTurns(usize),
+
+    /// A message contained a termination keyword.
+    Keyword(String),
+
+    /// The conversation exhausted its token or cost budget.
+    Budget,
+
+    /// The conversation ran out of time.
+    Timeout,
+
+    /// A human stopped the conversation.
+    HumanStop,
+
+    /// The conversation was ended by an error.
+    Error(String),
+}
+
+impl TerminationReason {
+    /// Returns true if the conversation ended because of an error.
+    pub fn is_error(&self) -> bool {
+        matches!(self, Self::Error(_))
+    }
+}
+
+impl fmt::Display for TerminationReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Completed => write!(f, "completed"),
+            Self::MaxTurns(turns) => write!(f, "reached maximum of {turns} turns"),
+            Self::Keyword(keyword) => write!(f, "termination keyword {keyword:?} received"),
+            Self::Budget => write!(f, "budget exhausted"),
+            Self::Timeout => write!(f, "timed out"),
+            Self::HumanStop => write!(f, "stopped by human"),
+            Self::Error(error) => write!(f, "error: {error}"),
+        }
+    }
+}
+
+/// A message recorded in a chat's transcript.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// The name of the participant that sent the message.
+    pub name: String,
+
+    /// The content of the message.
+    pub content: String,
+
+    /// The reasoning the participant shared while writing the message, if
+    /// any. It isn't delivered to other participants unless the chat shares
+    /// thoughts.
+    pub thought: Option<String>,
+}
+
+/// The result of a finished chat.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatOutcome {
+    /// Every message exchanged during the chat, in order.
+    pub transcript: Vec<ChatMessage>,
+
+    /// Why the chat ended.
+    pub reason: TerminationReason,
+
+    /// The variables extracted from the chat, by name, if the chat has an
+    /// [extractor](extract::Extractor).
+    #[serde(default)]
+    pub variables: HashMap<String, serde_json::Value>,
+}
+
+impl ChatOutcome {
+    /// Returns the extracted variable `name` as a `T`, or `None` if it
+    /// wasn't extracted or isn't a `T`.
+    pub fn variable<T: DeserializeOwned>(&self, name: &str) -> Option<T> {
+        let value = self.variables.get(name)?;
+        serde_json::from_value(value.clone()).ok()
+    }
+}
+
+/// Something that happened in a running chat, for UIs to display.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatEvent {
+    /// A message was recorded in the transcript.
+    Message(ChatMessage),
+
+    /// A participant streamed a piece of the reply it's generating.
+    Token { name: String, token: String },
+
+    /// A participant shared a piece of its reasoning.
+    Thought { name: String, thought: String },
+
+    /// A participant reported progress on a long-running tool.
+    ToolProgress {
+        name: String,
+        progress: ToolProgress,
+    },
+
+    /// The chat changed, by its manager or through its handle.
+    Action(Action),
+
+    /// A participant's reply was over the chat's
+    /// [limits](limits::ReplyLimits).
+    LimitExceeded { name: String, violation: Violation },
+
+    /// The chat's [moderator](moderation::Moderator) sent a participant's
+    /// reply back for revision or vetoed it.
+    Moderated { name: String, verdict: Verdict },
+}
+
+/// Out-of-band commands for a running chat.
+#[derive(Debug, Clone)]
+pub enum Control {
+    /// End the chat as soon as possible.
+    Stop,
+
+    /// Hold the next message until the chat is resumed.
+    Pause,
+
+    /// Continue a paused chat.
+    Resume,
+
+    /// Add guidance to the message delivered to the next speaker.
+    Inject(String),
+
+    /// Add a participant once the current reply is in.
+    Join {
+        name: String,
+        sender: Sender<Box<Message>>,
+    },
+
+    /// Remove the participant with this name once the current reply is in.
+    Leave(String),
+}
+
+/// A change to the participants requested through a chat's handle, held
+/// until the current reply is in.
+#[derive(Debug)]
+enum RosterChange {
+    Join(Participant),
+    Leave(String),
+}
+
+/// A participant in a chat.
+#[derive(Debug, Clone)]
+pub(crate) struct Participant {
+    pub(crate) name: String,
+    pub(crate) description: Option<String>,
+    pub(crate) sender: Sender<Box<Message>>,
+}
+
+impl Participant {
+    /// Returns `actor` as a participant, named after the actor or, if it has
+    /// no name, its id.
+    pub(crate) fn of<A>(actor: &A) -> Self
+    where
+        A: Actor<Message = Message>,
+    {
+        Self {
+            name: actor
+                .name()
+                .map(ToString::to_string)
+                .unwrap_or_else(|| actor.id().to_string()),
+            description: None,
+            sender: actor.sender(),
+        }
+    }
+}
+
+/// Builds and starts a chat.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::{agent::{AgentBuilder, Message, SendError}, chat::ChatBuilder};
+/// # tokio_test::block_on(async {
+/// let echo = |sender, message: Box<Message>| async move {
+///     let content = message.content.clone();
+///     message
+///         .sender
+///         .send(Box::new(Message::new(sender, content)))
+///         .await
+/// };
+/// let assistant = AgentBuilder::<_, SendError<_>>::new().spawn(echo);
+/// let critic = AgentBuilder::<_, SendError<_>>::new().spawn(echo);
+///
+/// let chat = ChatBuilder::new()
+///     .with_participant("assistant", assistant.sender())
+///     .with_participant("critic", critic.sender())
+///     .with_max_turns(4)
+///     .start("Write a haiku about Rust.");
+///
+/// let outcome = chat.join().await;
+/// assert_eq!(outcome.transcript.len(), 5);
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug, Default)]
+pub struct ChatBuilder {
+    /// The participants, in speaking order.
+    participants: Vec<Participant>,
+
+    /// The maximum number of replies before the chat ends.
+    max_turns: Option<usize>,
+
+    /// Whether participants see each other's thoughts.
+    share_thoughts: bool,
+
+    /// Ends the chat early.
+    termination: Option<Box<dyn TerminationCondition>>,
+
+    /// Caps the length of each reply.
+    limits: Option<ReplyLimits>,
+
+    /// What the participants started, cleaned up if the chat is aborted.
+    resources: Option<Resources>,
+
+    /// Extracts variables from the chat once it ends.
+    extractor: Option<Extractor>,
+
+    /// Records each message delivered to a participant.
+    recorder: Option<Recorder>,
+
+    /// The chat's id.
+    conversation_id: Option<String>,
+}
+
+impl ChatBuilder {
+    /// Create a new chat builder.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a participant. Participants speak in the order they are added.
+    pub fn with_participant(mut self, name: impl ToString, sender: Sender<Box<Message>>) -> Self {
+        self.participants.push(Participant {
+            name: name.to_string(),
+            description: None,
+            sender,
+        });
+        self
+    }
+
+    /// Set the maximum number of replies before the chat ends.
+    pub fn with_max_turns(mut self, max_turns: usize) -> Self {
+        self.max_turns = Some(max_turns);
+        self
+    }
+
+    /// Deliver each reply's thoughts to the next speaker along with the
+    /// reply. By default thoughts are only recorded in the transcript.
+    pub fn with_shared_thoughts(mut self) -> Self {
+        self.share_thoughts = true;
+        self
+    }
+
+    /// End the chat when `condition` is met, in addition to the maximum
+    /// number of turns.
+    pub fn with_termination(mut self, condition: impl TerminationCondition) -> Self {
+        self.termination = Some(Box::new(condition));
+        self
+    }
+
+    /// Cap the length of each reply with `limits`.
+    pub fn with_limits(mut self, limits: ReplyLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Clean up `resources`, e.g. the participants' requests and containers,
+    /// when the chat is aborted with [`ChatHandle::abort`].
+    pub fn with_resources(mut self, resources: Resources) -> Self {
+        self.resources = Some(resources);
+        self
+    }
+
+    /// Extract variables from the chat with `extractor` once it ends, into
+    /// [`ChatOutcome::variables`].
+    pub fn with_extractor(mut self, extractor: Extractor) -> Self {
+        self.extractor = Some(extractor);
+        self
+    }
+
+    /// Record each message delivered to a participant, with who it came
+    /// from, in `recorder`.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Identify the chat by `id`, e.g. in metrics, instead of a random id.
+    pub fn with_conversation_id(mut self, id: impl ToString) -> Self {
+        self.conversation_id = Some(id.to_string());
+        self
+    }
+
+    /// Start the chat. The first participant opens the conversation by
+    /// sending `message` to the second participant.
+    pub fn start(self, message: impl ToString) -> ChatHandle {
+        let config = Config {
+            max_turns: self.max_turns,
+            condition: self.termination,
+            share_thoughts: self.share_thoughts,
+            limits: self.limits,
+            resources: self.resources,
+            extractor: self.extractor,
+            recorder: self.recorder,
+            conversation_id: self.conversation_id,
+            ..Default::default()
+        };
+        spawn(self.participants, config, message.to_string())
+    }
+}
+
+/// Options for a chat started with [`initiate_chat`].
+#[derive(Debug)]
+pub struct ChatOptions {
+    /// The maximum number of replies before the chat ends.
+    pub max_turns: usize,
+
+    /// The chat ends when a reply contains any of these keywords.
+    pub termination_keywords: Vec<String>,
+
+    /// The chat ends when this condition is met.
+    pub termination: Option<Box<dyn TerminationCondition>>,
+
+    /// Extracts variables from the chat once it ends.
+    pub extractor: Option<Extractor>,
+
+    /// Records each message delivered to either actor.
+    pub recorder: Option<Recorder>,
+}
+
+impl Default for ChatOptions {
+    fn default() -> Self {
+        Self {
+            max_turns: DEFAULT_MAX_TURNS,
+            termination_keywords: Vec::new(),
+            termination: None,
+            extractor: None,
+            recorder: None,
+        }
+    }
+}
+
+impl ChatOptions {
+    /// Create options with the default maximum number of turns and no
+    /// termination keywords.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the maximum number of replies before the chat ends.
+    pub fn with_max_turns(mut self, max_turns: usize) -> Self {
+        self.max_turns = max_turns;
+        self
+    }
+
+    /// End the chat when a reply contains `keyword`.
+    pub fn with_termination_keyword(mut self, keyword: impl ToString) -> Self {
+        self.termination_keywords.push(keyword.to_string());
+        self
+    }
+
+    /// End the chat when `condition` is met.
+    pub fn with_termination(mut self, condition: impl TerminationCondition) -> Self {
+        self.termination = Some(Box::new(condition));
+        self
+    }
+
+    /// Extract variables from the chat with `extractor` once it ends.
+    pub fn with_extractor(mut self, extractor: Extractor) -> Self {
+        self.extractor = Some(extractor);
+        self
+    }
+
+    /// Record each message delivered to either actor in `recorder`.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+}
+
+/// Run a conversation between two actors and wait for it to end. `a` opens
+/// the conversation by sending `message` to `b`, and from then on they take
+/// turns replying to each other until the chat reaches its maximum number of
+/// turns, a reply contains a termination keyword or the termination
+/// condition is met. Actors are named after
+/// their name or, if they have none, their id.
+///
+/// Usage:
+/// ```no_run
+/// # use autogen_rs::{agent::{assistant::AssistantBuilder, user::UserAgentBuilder}, chat::{initiate_chat, ChatOptions}};
+/// # tokio_test::block_on(async {
+/// let user = UserAgentBuilder::new().with_name("user").build();
+/// let assistant = AssistantBuilder::new().with_name("assistant").build();
+///
+/// let outcome = initiate_chat(
+///     &assistant,
+///     &user,
+///     "What can I do for you?",
+///     ChatOptions::new().with_termination_keyword("TERMINATE"),
+/// )
+/// .await;
+/// println!("chat ended: {}", outcome.reason);
+/// # anyhow::Ok(())
+/// # });
+/// ```
+pub async fn initiate_chat<A, B>(
+    a: &A,
+    b: &B,
+    message: impl ToString,
+    options: ChatOptions,
+) -> ChatOutcome
+where
+    A: Actor<Message = Message>,
+    B: Actor<Message = Message>,
+{
+    let conditions = options
+        .termination_keywords
+        .into_iter()
+        .map(|keyword| Box::new(Keyword::new(keyword)) as Box<dyn TerminationCondition>)
+        .chain(options.termination)
+        .collect();
+    let config = Config {
+        max_turns: Some(options.max_turns),
+        condition: termination::any(conditions),
+        extractor: options.extractor,
+        recorder: options.recorder,
+        ..Default::default()
+    };
+    spawn(
+        vec![Participant::of(a), Participant::of(b)],
+        config,
+        message.to_string(),
+    )
+    .join()
+    .await
+}
+
+/// How a chat runs, besides who takes part.
+#[derive(Default)]
+pub(crate) struct Config {
+    /// The maximum number of replies before the chat ends.
+    pub(crate) max_turns: Option<usize>,
+
+    /// Ends the chat early.
+    pub(crate) condition: Option<Box<dyn TerminationCondition>>,
+
+    /// Whether participants see each other's thoughts.
+    pub(crate) share_thoughts: bool,
+
+    /// Chooses the next speaker. Participants take turns if unset.
+    pub(crate) selector: Option<Box<dyn SpeakerSelector>>,
+
+    /// Participants the selector may bring into the chat.
+    pub(crate) standby: Vec<Participant>,
+
+    /// Caps the length of each reply.
+    pub(crate) limits: Option<ReplyLimits>,
+
+    /// What the chat is meant to achieve.
+    pub(crate) goal: Option<String>,
+
+    /// What the chat tells its participants.
+    pub(crate) prompts: PromptPack,
+
+    /// Participants that receive every message but never speak.
+    pub(crate) observers: Vec<Participant>,
+
+    /// Reviews each reply before it's relayed.
+    pub(crate) moderator: Option<Box<dyn Moderator>>,
+
+    /// The most times a reply can be sent back for revision.
+    pub(crate) max_revisions: usize,
+
+    /// What the participants started, cleaned up if the chat is aborted.
+    pub(crate) resources: Option<Resources>,
+
+    /// Extracts variables from the chat once it ends.
+    pub(crate) extractor: Option<Extractor>,
+
+    /// Records each message delivered to a participant.
+    pub(crate) recorder: Option<Recorder>,
+
+    /// The chat's id, or a random one if `None`.
+    pub(crate) conversation_id: Option<String>,
+}
+
+/// Spawn a chat between `participants`. The first participant opens the
+/// conversation by sending `message` to the second participant.
+pub(crate) fn spawn(participants: Vec<Participant>, config: Config, message: String) -> ChatHandle {
+    let (control, control_receiver) = mpsc::unbounded_channel();
+    let (events, _) = broadcast::channel(EVENT_CAPACITY);
+    // observers never speak, so anything they send back is dropped
+    let (observed_sender, mut observed) = crate::agent::channel::<Box<Message>>(None);
+    tokio::spawn(async move { while observed.recv().await.is_some() {} });
+    let conversation_id = config
+        .conversation_id
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let chat = Chat {
+        conversation_id: conversation_id.clone(),
+        participants,
+        max_turns: config.max_turns,
+        condition: config.condition,
+        share_thoughts: config.share_thoughts,
+        selector: config.selector.unwrap_or_else(|| Box::new(RoundRobin)),
+        standby: config.standby,
+        limits: config.limits,
+        goal: config.goal,
+        prompts: config.prompts,
+        observers: config.observers,
+        observed: observed_sender,
+        moderator: config.moderator,
+        max_revisions: config.max_revisions,
+        extractor: config.extractor,
+        recorder: config.recorder,
+        notes: HashMap::new(),
+        roster: Vec::new(),
+        control: control_receiver,
+        events: events.clone(),
+        transcript: Vec::new(),
+        injected: Vec::new(),
+        thought: None,
+        paused: false,
+    };
+    let handle = tokio::spawn(chat.run(message));
+    ChatHandle {
+        conversation_id,
+        control,
+        events,
+        handle,
+        resources: config.resources,
+    }
+}
+
+/// A handle to a running chat.
+#[derive(Debug)]
+pub struct ChatHandle {
+    /// The chat's id.
+    conversation_id: String,
+
+    /// A channel to send control commands to the chat.
+    control: mpsc::UnboundedSender<Control>,
+
+    /// Publishes what happens in the chat.
+    events: broadcast::Sender<ChatEvent>,
+
+    /// A handle to the chat's task.
+    handle: JoinHandle<ChatOutcome>,
+
+    /// What the participants started, cleaned up if the chat is aborted.
+    resources: Option<Resources>,
+}
+
+impl ChatHandle {
+    /// Returns the chat's id, which the messages delivered to its
+    /// participants carry.
+    pub fn conversation_id(&self) -> &str {
+        &self.conversation_id
+    }
+
+    /// Stop the chat. A chat waiting on a reply stops without waiting for it.
+    pub fn stop(&self) -> Result<(), SendError<Control>> {
+        self.control(Control::Stop)
+    }
+
+    /// Pause the chat. The reply currently being generated is recorded but
+    /// not delivered until the chat is resumed.
+    pub fn pause(&self) -> Result<(), SendError<Control>> {
+        self.control(Control::Pause)
+    }
+
+    /// Resume a paused chat.
+    pub fn resume(&self) -> Result<(), SendError<Control>> {
+        self.control(Control::Resume)
+    }
+
+    /// Inject a message into the chat. It's recorded in the transcript and
+    /// delivered to the next speaker along with the previous reply.
+    pub fn inject_message(&self, content: impl ToString) -> Result<(), SendError<Control>> {
+        self.control(Control::Inject(content.to_string()))
+    }
+
+    /// Add a participant to the chat. The change is announced to the next
+    /// speaker, and the new participant can speak from then on.
+    pub fn add_participant(
+        &self,
+        name: impl ToString,
+        sender: Sender<Box<Message>>,
+    ) -> Result<(), SendError<Control>> {
+        self.control(Control::Join {
+            name: name.to_string(),
+            sender,
+        })
+    }
+
+    /// Remove the participant named `name` from the chat once its current
+    /// reply, if any, is in. The change is announced to the next speaker. A
+    /// chat keeps at least two participants.
+    pub fn remove_participant(&self, name: impl ToString) -> Result<(), SendError<Control>> {
+        self.control(Control::Leave(name.to_string()))
+    }
+
+    /// Send a control command to the chat. Fails if the chat has ended.
+    pub fn control(&self, control: Control) -> Result<(), SendError<Control>> {
+        self.control.send(control).map_err(|e| SendError::new(e.0))
+    }
+
+    /// Subscribe to what happens in the chat from now on: recorded messages,
+    /// streamed tokens, thoughts and tool progress.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChatEvent> {
+        self.events.subscribe()
+    }
+
+    /// Wait for the chat to end.
+    pub async fn join(self) -> ChatOutcome {
+        self.handle.await.unwrap_or_else(|e| ChatOutcome {
+            transcript: Vec::new(),
+            reason: TerminationReason::Error(e.to_string()),
+            variables: HashMap::new(),
+        })
+    }
+
+    /// Abort the chat without waiting for the reply in progress, and clean
+    /// up what its participants started: their requests to models are
+    /// interrupted, and their containers, workspaces and agents removed.
+    /// Returns what was cleaned up.
+    pub async fn abort(self) -> CleanupReport {
+        // an aborted chat stops tracking its resources, so clean up first
+        let report = match &self.resources {
+            Some(resources) => resources.cancel().await,
+            None => CleanupReport::default(),
+        };
+        self.handle.abort();
+        let _ = self.handle.await;
+        report
+    }
+}
+
+/// The state of a running chat.
+struct Chat {
+    conversation_id: String,
+    participants: Vec<Participant>,
+    max_turns: Option<usize>,
+    condition: Option<Box<dyn TerminationCondition>>,
+    share_thoughts: bool,
+    selector: Box<dyn SpeakerSelector>,
+    /// Participants the selector may bring into the chat.
+    standby: Vec<Participant>,
+    /// Changes to the participants to make before the next speaker is chosen.
+    roster: Vec<RosterChange>,
+    limits: Option<ReplyLimits>,
+    /// What the chat is meant to achieve.
+    goal: Option<String>,
+    /// What the chat tells its participants.
+    prompts: PromptPack,
+    /// Participants that receive every message but never speak.
+    observers: Vec<Participant>,
+    /// Where observers' replies go, to be dropped.
+    observed: Sender<Box<Message>>,
+    /// Reviews each reply before it's relayed.
+    moderator: Option<Box<dyn Moderator>>,
+    max_revisions: usize,
+    /// Extracts variables from the chat once it ends.
+    extractor: Option<Extractor>,
+    /// Records each message delivered to a participant.
+    recorder: Option<Recorder>,
+    /// Feedback for participants, delivered the next time they speak.
+    notes: HashMap<String, String>,
+    control: mpsc::UnboundedReceiver<Control>,
+    events: broadcast::Sender<ChatEvent>,
+    transcript: Vec<ChatMessage>,
+    injected: Vec<String>,
+    /// The thoughts the current speaker has shared so far.
+    thought: Option<String>,
+    paused: bool,
+}
+
+impl Chat {
+    async fn run(mut self, message: String) -> ChatOutcome {
+        #[cfg(feature = "metrics")]
+        let conversation = crate::metrics::Conversation::start(&self.conversation_id);
+        #[cfg(feature = "metrics")]
+        let turns = self.transcript.len();
+        let reason = self.converse(message).await;
+        #[cfg(feature = "metrics")]
+        {
+            // the opening message isn't a reply
+            let replies = self.transcript.len().saturating_sub(turns + 1);
+            conversation.increment(crate::metrics::CONVERSATION_TURNS, replies as u64);
+            conversation.finish();
+        }
+        tracing::trace!(%reason, "chat ended");
+        let variables = match &self.extractor {
+            Some(extractor) => extractor
+                .extract(&self.transcript)
+                .await
+                .unwrap_or_else(|e| {
+                    tracing::warn!(error = %e, "unable to extract variables from chat");
+                    HashMap::new()
+                }),
+            None => HashMap::new(),
+        };
+        ChatOutcome {
+            transcript: self.transcript,
+            reason,
+            variables,
+        }
+    }
+
+    async fn converse(&mut self, message: String) -> TerminationReason {
+        if self.participants.len() < 2 {
+            return TerminationReason::Error("a chat needs at least two participants".to_string());
+        }
+
+        // held apart from the chat, so it can be waited on while the chat changes
+        let condition = self.condition.take();
+        if let Some(condition) = &condition {
+            condition.start();
+        }
+        let triggered = || match &condition {
+            Some(condition) => condition.triggered(),
+            None => Box::pin(std::future::pending()) as TerminationFuture<'_>,
+        };
+        let limits = self.limits.take();
+        let mut rejections = 0;
+        let moderator = self.moderator.take();
+        let mut revisions = 0;
+
+        let (inbox, mut replies) = crate::agent::channel::<Box<Message>>(None);
+        self.record(self.participants[0].name.clone(), message.clone(), None);
+        let mut content = message;
+        // who the content delivered next came from, for the recorder
+        let mut from = self.participants[0].name.clone();
+        let mut speaker = match self.next_speaker(0).await {
+            Ok(speaker) => speaker,
+            Err(reason) => return reason,
+        };
+        let mut turns = 0;
+
+        loop {
+            // drain pending commands, blocking for as long as the chat is paused
+            while let Ok(control) = self.control.try_recv() {
+                if self.apply(control) {
+                    return TerminationReason::HumanStop;
+                }
+            }
+            while self.paused {
+                tokio::select! {
+                    control = self.control.recv() => match control {
+                        Some(control) => {
+                            if self.apply(control) {
+                                return TerminationReason::HumanStop;
+                            }
+                        }
+                        None => self.paused = false,
+                    },
+                    reason = triggered() => return reason,
+                }
+            }
+
+            if !self.injected.is_empty() {
+                let injected = std::mem::take(&mut self.injected);
+                content = std::iter::once(content)
+                    .chain(injected)
+                    .collect::<Vec<_>>()
+                    .join("\n\n");
+            }
+            if let Some(note) = self.notes.remove(&self.participants[speaker].name) {
+                content = format!("{content}\n\n{note}");
+            }
+
+            // the participant streams tokens, thoughts and progress back to the chat
+            // while it works on its reply
+            let (stream, mut stream_events) = crate::agent::channel(None);
+            let mut message = Message::new(inbox.clone(), content).with_stream(stream);
+            message.system_prompt = self.role_prompt(speaker);
+            message.conversation = Some(self.conversation_id.clone());
+            let participant = &self.participants[speaker];
+            if let Some(recorder) = &self.recorder {
+                recorder.push(RecordedMessage {
+                    sender: Some(from.clone()),
+                    receiver: participant.name.clone(),
+                    role: message.role,
+                    content: message.content.to_string(),
+                    sent_at: message.timestamp,
+                    recorded_at: SystemTime::now(),
+                    kind: RecordKind::Delivered,
+                    tool_calls: Vec::new(),
+                });
+            }
+            if let Err(e) = participant.sender.send(Box::new(message)).await {
+                return TerminationReason::Error(format!(
+                    "unable to reach {}: {e}",
+                    participant.name
+                ));
+            }
+
+            let mut triggered = triggered();
+            let reply = loop {
+                tokio::select! {
+                    reason = &mut triggered => return reason,
+                    reply = replies.recv() => break reply,
+                    Some(event) = stream_events.recv() => self.stream(speaker, event),
+                    Some(control) = self.control.recv() => {
+                        if self.apply(control) {
+                            return TerminationReason::HumanStop;
+                        }
+                    }
+                }
+            };
+            // the chat holds a sender, so the inbox never closes
+            let Some(reply) = reply else {
+                return TerminationReason::Completed;
+            };
+            // publish output streamed just before the reply arrived
+            while let Some(event) = stream_events.try_recv() {
+                self.stream(speaker, event);
+            }
+
+            let thought = self.thought.take();
+            let name = self.participants[speaker].name.clone();
+            let mut reply = reply.content.to_string();
+            if let Some((limits, violation)) = limits
+                .as_ref()
+                .and_then(|limits| Some((limits, limits.check(&reply)?)))
+            {
+                self.emit(ChatEvent::LimitExceeded {
+                    name: name.clone(),
+                    violation,
+                });
+                match limits.enforcement() {
+                    Enforcement::Reject { retries } if rejections < retries => {
+                        tracing::debug!(name, %violation, "reply over limit; asking again");
+                        rejections += 1;
+                        from = MANAGER_NAME.to_string();
+                        content = self.prompts.render(
+                            Prompt::ReplyRejected,
+                            &[("violation", &violation.to_string())],
+                        );
+                        continue;
+                    }
+                    _ => {
+                        tracing::debug!(name, %violation, "reply over limit; cutting it short");
+                        reply = limits.truncate(&reply).to_string();
+                        self.notes.insert(
+                            name.clone(),
+                            self.prompts.render(
+                                Prompt::ReplyCutShort,
+                                &[("violation", &violation.to_string())],
+                            ),
+                        );
+                    }
+                }
+            }
+            rejections = 0;
+            let verdict = match &moderator {
+                Some(moderator) => {
+                    let review = Review {
+                        name: &name,
+                        reply: &reply,
+                        transcript: &self.transcript,
+                    };
+                    moderator.review(review).await
+                }
+                None => Verdict::Approve,
+            };
+            if verdict != Verdict::Approve {
+                self.emit(ChatEvent::Moderated {
+                    name: name.clone(),
+                    verdict: verdict.clone(),
+                });
+            }
+            match verdict {
+                Verdict::Approve => {}
+                Verdict::Revise(feedback) if revisions < self.max_revisions => {
+                    tracing::debug!(name, feedback, "reply sent back for revision");
+                    revisions += 1;
+                    from = MANAGER_NAME.to_string();
+                    content = self
+                        .prompts
+                        .render(Prompt::Revision, &[("feedback", &feedback)]);
+                    continue;
+                }
+                Verdict::Revise(reason) | Verdict::Veto(reason) => {
+                    tracing::debug!(name, reason, "reply vetoed");
+                    revisions = 0;
+                    self.notes.insert(
+                        name,
+                        self.prompts.render(Prompt::Veto, &[("reason", &reason)]),
+                    );
+                    // the next speaker picks up from the last message relayed
+                    if let Some(last) = self.transcript.last() {
+                        from = last.name.clone();
+                        content = last.content.clone();
+                    } else {
+                        content = String::new();
+                    }
+                    turns += 1;
+                    if self.max_turns.is_some_and(|max_turns| turns >= max_turns) {
+                        return TerminationReason::MaxTurns(turns);
+                    }
+                    speaker = match self.next_speaker(speaker).await {
+                        Ok(speaker) => speaker,
+                        Err(reason) => return reason,
+                    };
+                    continue;
+                }
+            }
+            revisions = 0;
+            self.record(name.clone(), reply.clone(), thought.clone());
+            from = name;
+            if let Some(reason) = condition
+                .as_ref()
+                .and_then(|condition| condition.check(&self.transcript))
+            {
+                return reason;
+            }
+            content = match thought {
+                Some(thought) if self.share_thoughts => {
+                    format!("<thought>\n{thought}\n</thought>\n\n{reply}")
+                }
+                _ => reply,
+            };
+            turns += 1;
+            if self.max_turns.is_some_and(|max_turns| turns >= max_turns) {
+                return TerminationReason::MaxTurns(turns);
+            }
+            speaker = match self.next_speaker(speaker).await {
+                Ok(speaker) => speaker,
+                Err(reason) => return reason,
+            };
+        }
+    }
+
+    /// Ask the selector who speaks after `last`, applying the changes it
+    /// makes to the chat. Returns the reason the chat ends if it ends it.
+    async fn next_speaker(&mut self, last: usize) -> Result<usize, TerminationReason> {
+        let last = self.change_roster(last);
+        let speakers = as_speakers(&self.participants);
+        let standby = as_speakers(&self.standby);
+        let selection = Selection {
+            speakers: &speakers,
+            last,
+            transcript: &self.transcript,
+            standby: &standby,
+        };
+        let decision = self.selector.decide(selection).await;
+        if decision.actions.is_empty() {
+            return Ok(match decision.next {
+                Some(next) if next < speakers.len() => next,
+                next => {
+                    if next.is_some() {
+                        tracing::warn!(next, "selected speaker doesn't exist; taking turns");
+                    }
+                    selection.next()
+                }
+            });
+        }
+
+        // participants move in and out, so find the chosen one and the last
+        // speaker again by name once the chat has changed
+        let name = |i: usize| speakers.iter().chain(&standby).nth(i).map(|s| &s.name);
+        for action in decision.actions {
+            if let Some(reason) = self.act(action) {
+                return Err(reason);
+            }
+        }
+        let position = |name: &String| self.participants.iter().position(|p| p.name == *name);
+        if let Some(next) = decision.next.and_then(name).and_then(position) {
+            return Ok(next);
+        }
+        Ok(match position(&speakers[last].name) {
+            Some(last) => (last + 1) % self.participants.len(),
+            // whoever took the removed speaker's place
+            None => last % self.participants.len(),
+        })
+    }
+
+    /// Apply a change the selector makes to the chat. Returns the reason
+    /// the chat ends if it ends it.
+    fn act(&mut self, action: Action) -> Option<TerminationReason> {
+        tracing::debug!(%action, "manager changed the chat");
+        match &action {
+            Action::AddParticipant(name) => {
+                match self.standby.iter().position(|p| p.name == *name) {
+                    Some(i) => {
+                        let participant = self.standby.remove(i);
+                        self.join(participant);
+                    }
+                    None => tracing::warn!(name, "no participant on standby to add"),
+                }
+                None
+            }
+            Action::RemoveParticipant(name) => {
+                self.leave(name);
+                None
+            }
+            Action::ChangeTopic(topic) => {
+                self.announce(
+                    self.prompts
+                        .render(Prompt::TopicChanged, &[("topic", topic)]),
+                );
+                self.emit(ChatEvent::Action(action));
+                None
+            }
+            Action::EndChat(reason) => {
+                self.record(MANAGER_NAME.to_string(), reason.clone(), None);
+                self.emit(ChatEvent::Action(action));
+                Some(TerminationReason::Completed)
+            }
+        }
+    }
+
+    /// Make the roster changes requested through the handle. Returns the
+    /// index of the last speaker afterwards or, if it left, of the
+    /// participant before it, so whoever took its place speaks next.
+    fn change_roster(&mut self, last: usize) -> usize {
+        if self.roster.is_empty() {
+            return last;
+        }
+        let name = self.participants[last].name.clone();
+        for change in std::mem::take(&mut self.roster) {
+            match change {
+                RosterChange::Join(participant) => {
+                    let mut everyone = self.participants.iter().chain(&self.standby);
+                    if everyone.any(|p| p.name == participant.name) {
+                        tracing::warn!(name = participant.name, "participant already exists");
+                    } else {
+                        self.join(participant);
+                    }
+                }
+                RosterChange::Leave(name) => self.leave(&name),
+            }
+        }
+        let count = self.participants.len();
+        self.participants
+            .iter()
+            .position(|p| p.name == name)
+            .unwrap_or((last + count - 1) % count)
+    }
+
+    /// Add `participant` to the chat and announce it.
+    fn join(&mut self, participant: Participant) {
+        let name = participant.name.clone();
+        self.participants.push(participant);
+        self.announce(self.prompts.render(Prompt::Joined, &[("name", &name)]));
+        self.emit(ChatEvent::Action(Action::AddParticipant(name)));
+    }
+
+    /// Move the participant named `name` onto standby and announce it,
+    /// unless that leaves fewer than two participants.
+    fn leave(&mut self, name: &str) {
+        let Some(i) = self.participants.iter().position(|p| p.name == name) else {
+            tracing::warn!(name, "no participant to remove");
+            return;
+        };
+        if self.participants.len() <= 2 {
+            tracing::warn!(name, "a chat needs at least two participants");
+            return;
+        }
+        let participant = self.participants.remove(i);
+        self.standby.push(participant);
+        self.announce(self.prompts.render(Prompt::Left, &[("name", name)]));
+        self.emit(ChatEvent::Action(Action::RemoveParticipant(
+            name.to_string(),
+        )));
+    }
+
+    /// Returns the instructions sent to `speaker` with each message: who it
+    /// is in the chat, its role and the chat's goal. Participants without a
+    /// description in a chat without a goal get none.
+    fn role_prompt(&self, speaker: usize) -> Option<String> {
+        let participant = &self.participants[speaker];
+        if participant.description.is_none() && self.goal.is_none() {
+            return None;
+        }
+        let others = self
+            .participants
+            .iter()
+            .filter(|other| other.name != participant.name)
+            .map(|other| other.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut prompt = self.prompts.render(
+            Prompt::Participant,
+            &[("name", &participant.name), ("others", &others)],
+        );
+        if let Some(description) = &participant.description {
+            prompt.push('\n');
+            prompt.push_str(
+                &self
+                    .prompts
+                    .render(Prompt::ParticipantRole, &[("description", description)]),
+            );
+        }
+        if let Some(goal) = &self.goal {
+            prompt.push('\n');
+            prompt.push_str(&self.prompts.render(Prompt::ChatGoal, &[("goal", goal)]));
+        }
+        Some(prompt)
+    }
+
+    /// Record `content` from the chat and deliver it to the next speaker.
+    fn announce(&mut self, content: String) {
+        self.record(MANAGER_NAME.to_string(), content.clone(), None);
+        self.injected.push(content);
+    }
+
+    /// Apply a control command. Returns true if the chat should stop.
+    fn apply(&mut self, control: Control) -> bool {
+        tracing::trace!(?control, "received control command");
+        match control {
+            Control::Stop => return true,
+            Control::Pause => self.paused = true,
+            Control::Resume => self.paused = false,
+            Control::Inject(content) => {
+                self.record(HUMAN_NAME.to_string(), content.clone(), None);
+                self.injected.push(content);
+            }
+            Control::Join { name, sender } => self.roster.push(RosterChange::Join(Participant {
+                name,
+                description: None,
+                sender,
+            })),
+            Control::Leave(name) => self.roster.push(RosterChange::Leave(name)),
+        }
+        false
+    }
+
+    fn record(&mut self, name: String, content: String, thought: Option<String>) {
+        let message = ChatMessage {
+            name,
+            content,
+            thought,
+        };
+        self.emit(ChatEvent::Message(message.clone()));
+        self.observe(&message);
+        self.transcript.push(message);
+    }
+
+    /// Deliver a copy of `message` to every observer. Delivery is best
+    /// effort, so a slow observer can't hold up the chat.
+    fn observe(&self, message: &ChatMessage) {
+        for observer in &self.observers {
+            let copy = Message::new(self.observed.clone(), message.content.clone())
+                .with_name(&message.name);
+            if let Err(e) = observer.sender.try_send(Box::new(copy)) {
+                tracing::warn!(observer = observer.name, error = %e, "unable to reach observer");
+            }
+        }
+    }
+
+    /// Publish output streamed by the participant at `speaker`. Thoughts are
+    /// also collected so they can be recorded with the reply.
+    fn stream(&mut self, speaker: usize, event: StreamEvent) {
+        let name = self.participants[speaker].name.clone();
+        let event = match event {
+            StreamEvent::Token(token) => ChatEvent::Token { name, token },
+            StreamEvent::Thought(thought) => {
+                self.thought
+                    .get_or_insert_with(String::new)
+                    .push_str(&thought);
+                ChatEvent::Thought { name, thought }
+            }
+            StreamEvent::Progress(progress) => ChatEvent::ToolProgress { name, progress },
+            // the reply is recorded once it's delivered
+            StreamEvent::Complete(_) => return,
+        };
+        self.emit(event);
+    }
+
+    fn emit(&self, event: ChatEvent) {
+        // there may be no subscribers
+        let _ = self.events.send(event);
+    }
+}
+
+/// Returns `participants` as a speaker selector sees them.
+fn as_speakers(participants: &[Participant]) -> Vec<Speaker> {
+    participants
+        .iter()
+        .map(|participant| Speaker {
+            name: participant.name.clone(),
+            description: participant.description.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{agent::assistant::AssistantBuilder, llm::openai::mock::echo_server, Agent},
+        anyhow::Result,
+        std::{convert::Infallible, time::Duration},
+    };
+
+    #[tokio::test]
+    async fn test_initiate_chat() -> Result<()> {
+        let base_url = echo_server().await;
+        let spawn = |name| {
+            AssistantBuilder::new()
+                .with_name(name)
+                .with_api_key("key")
+                .with_base_url(&base_url)
+                .build()
+        };
+        let (a, b) = (spawn("a"), spawn("b"));
+
+        let outcome = initiate_chat(&a, &b, "hello", ChatOptions::new().with_max_turns(3)).await;
+        assert_eq!(outcome.reason, TerminationReason::MaxTurns(3));
+        let speakers = outcome
+            .transcript
+            .iter()
+            .map(|m| m.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(speakers, ["a", "b", "a", "b"]);
+
+        // the echoed keyword ends the chat after the first reply
+        let options = ChatOptions::new().with_termination_keyword("TERMINATE");
+        let outcome = initiate_chat(&a, &b, "bye TERMINATE", options).await;
+        assert_eq!(
+            outcome.reason,
+            TerminationReason::Keyword("TERMINATE".to_string())
+        );
+        assert_eq!(outcome.transcript.len(), 2);
+        Ok(())
+    }
+
+    /// Spawns an agent that replies with its name after waiting for `delay`.
+    fn spawn_named(
+        name: &'static str,
+        delay: Duration,
+    ) -> Agent<Box<Message>, SendError<Box<Message>>> {
+        Agent::spawn(
+            Uuid::new_v4(),
+            Some(name.to_string()),
+            move |sender, message: Box<Message>| async move {
+                tokio::time::sleep(delay).await;
+                message
+                    .sender
+                    .send(Box::new(Message::new(sender, name.to_string())))
+                    .await
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_max_turns() -> Result<()> {
+        let a = spawn_named("a", Duration::ZERO);
+        let b = spawn_named("b", Duration::ZERO);
+
+        let outcome = ChatBuilder::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_max_turns(3)
+            .start("hello")
+            .join()
+            .await;
+
+        assert_eq!(outcome.reason, TerminationReason::MaxTurns(3));
+        let contents = outcome
+            .transcript
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, ["hello", "b", "a", "b"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_termination_reason() -> Result<()> {
+        let a = spawn_named("a", Duration::ZERO);
+        let b = spawn_named("b", Duration::ZERO);
+        let chat = || {
+            ChatBuilder::new()
+                .with_participant("a", a.sender())
+                .with_participant("b", b.sender())
+                .with_max_turns(2)
+        };
+
+        let outcome = chat().start("hello").join().await;
+        assert_eq!(outcome.reason, TerminationReason::MaxTurns(2));
+        assert_eq!(outcome.reason.to_string(), "reached maximum of 2 turns");
+        assert!(!outcome.reason.is_error());
+
+        // "b" replies first, so the chat ends before the turns run out
+        let outcome = chat()
+            .with_termination(Keyword::new("b"))
+            .start("hello")
+            .join()
+            .await;
+        assert_eq!(outcome.reason, TerminationReason::Keyword("b".to_string()));
+        assert_eq!(
+            outcome.reason.to_string(),
+            "termination keyword \"b\" received"
+        );
+        assert!(!outcome.reason.is_error());
+        assert_eq!(outcome.transcript.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stop_while_waiting_for_reply() -> Result<()> {
+        let a = spawn_named("a", Duration::ZERO);
+        let b = spawn_named("b", Duration::from_secs(60));
+
+        let chat = ChatBuilder::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .start("hello");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        chat.stop()?;
+
+        let outcome = tokio::time::timeout(Duration::from_secs(1), chat.join()).await?;
+        assert_eq!(outcome.reason, TerminationReason::HumanStop);
+        assert_eq!(outcome.transcript.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tool_progress_events() -> Result<()> {
+        let a = spawn_named("a", Duration::ZERO);
+        let b = Agent::spawn(
+            Uuid::new_v4(),
+            None,
+            move |sender, message: Box<Message>| async move {
+                message.report_progress("compile", Some(50), "building");
+                message
+                    .sender
+                    .send(Box::new(Message::new(sender, "done".to_string())))
+                    .await
+            },
+        );
+
+        let chat = ChatBuilder::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_max_turns(1)
+            .start("hello");
+        let mut events = chat.subscribe();
+        chat.join().await;
+
+        let mut progress = None;
+        while let Ok(event) = events.try_recv() {
+            if let ChatEvent::ToolProgress { name, progress: p } = event {
+                progress = Some((name, p));
+            }
+        }
+        assert_eq!(
+            progress,
+            Some((
+                "b".to_string(),
+                ToolProgress {
+                    tool: "compile".to_string(),
+                    percent: Some(50),
+                    status: "building".to_string(),
+                }
+            ))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_termination_while_waiting_for_reply() -> Result<()> {
+        use termination::{MaxMessages, Timeout};
+
+        let a = spawn_named("a", Duration::ZERO);
+        let b = spawn_named("b", Duration::from_millis(50));
+        let slow = spawn_named("slow", Duration::from_secs(60));
+
+        let outcome = ChatBuilder::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_termination(MaxMessages(3))
+            .start("hello")
+            .join()
+            .await;
+        assert_eq!(outcome.reason, TerminationReason::MaxTurns(3));
+
+        let outcome = ChatBuilder::new()
+            .with_participant("a", a.sender())
+            .with_participant("slow", slow.sender())
+            .with_termination(MaxMessages(3).or(Timeout::new(Duration::from_millis(100))))
+            .start("hello");
+        let outcome = tokio::time::timeout(Duration::from_secs(1), outcome.join()).await?;
+        assert_eq!(outcome.reason, TerminationReason::Timeout);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pause_inject_resume() -> Result<()> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let a = spawn_named("a", Duration::from_millis(100));
+        let b = Agent::spawn(
+            Uuid::new_v4(),
+            None,
+            move |sender, message: Box<Message>| {
+                let tx = tx.clone();
+                async move {
+                    tx.send(message.content.to_string()).ok();
+                    message
+                        .sender
+                        .send(Box::new(Message::new(sender, "b".to_string())))
+                        .await
+                        .ok();
+                    Result::<_, Infallible>::Ok(())
+                }
+            },
+        );
+
+        let chat = ChatBuilder::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_max_turns(3)
+            .start("hello");
+        assert_eq!(rx.recv().await.as_deref(), Some("hello"));
+
+        chat.pause()?;
+        chat.inject_message("be brief")?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(
+            rx.try_recv().is_err(),
+            "paused chat shouldn't deliver messages"
+        );
+
+        chat.resume()?;
+        assert_eq!(rx.recv().await.as_deref(), Some("a\n\nbe brief"));
+
+        let outcome = chat.join().await;
+        assert_eq!(outcome.reason, TerminationReason::MaxTurns(3));
+        assert!(outcome.transcript.contains(&ChatMessage {
+            name: HUMAN_NAME.to_string(),
+            content: "be brief".to_string(),
+            thought: None,
+        }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_and_remove_participants() -> Result<()> {
+        let a = spawn_named("a", Duration::ZERO);
+        let b = spawn_named("b", Duration::from_millis(100));
+        let c = spawn_named("c", Duration::ZERO);
+
+        let chat = ChatBuilder::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_max_turns(4)
+            .start("hello");
+        let mut events = chat.subscribe();
+        // both take effect once b replies
+        chat.add_participant("c", c.sender())?;
+        chat.remove_participant("a")?;
+        chat.remove_participant("b")?;
+
+        let outcome = chat.join().await;
+        assert_eq!(outcome.reason, TerminationReason::MaxTurns(4));
+        let transcript = outcome
+            .transcript
+            .iter()
+            .map(|m| format!("{}: {}", m.name, m.content))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            transcript,
+            [
+                "a: hello",
+                "b: b",
+                "manager: c joined the chat.",
+                "manager: a left the chat.",
+                "c: c",
+                "b: b",
+                "c: c",
+            ]
+        );
+        let mut actions = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let ChatEvent::Action(action) = event {
+                actions.push(action);
+            }
+        }
+        // b stays, since a chat needs at least two participants
+        assert_eq!(
+            actions,
+            [
+                Action::AddParticipant("c".to_string()),
+                Action::RemoveParticipant("a".to_string())
+            ]
+        );
+        Ok(())
+    }
+
+    /// Spawns an agent that thinks out loud before replying with its name,
+    /// and forwards every message it receives to `received`.
+    fn spawn_thinker(
+        name: &'static str,
+        received: mpsc::UnboundedSender<String>,
+    ) -> Agent<Box<Message>, SendError<Box<Message>>> {
+        Agent::spawn(
+            Uuid::new_v4(),
+            Some(name.to_string()),
+            move |sender, message: Box<Message>| {
+                let received = received.clone();
+                async move {
+                    received.send(message.content.to_string()).ok();
+                    message.report_thought(format!("{name} is thinking"));
+                    message
+                        .sender
+                        .send(Box::new(Message::new(sender, name.to_string())))
+                        .await
+                }
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_thoughts_kept_out_of_context() -> Result<()> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let a = spawn_thinker("a", tx.clone());
+        let b = spawn_thinker("b", tx);
+
+        let outcome = ChatBuilder::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_max_turns(2)
+            .start("hello")
+            .join()
+            .await;
+
+        assert_eq!(rx.recv().await.as_deref(), Some("hello"));
+        assert_eq!(rx.recv().await.as_deref(), Some("b"));
+        assert_eq!(
+            outcome.transcript[1],
+            ChatMessage {
+                name: "b".to_string(),
+                content: "b".to_string(),
+                thought: Some("b is thinking".to_string()),
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shared_thoughts() -> Result<()> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let a = spawn_thinker("a", tx.clone());
+        let b = spawn_thinker("b", tx);
+
+        ChatBuilder::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_max_turns(2)
+            .with_shared_thoughts()
+            .start("hello")
+            .join()
+            .await;
+
+        assert_eq!(rx.recv().await.as_deref(), Some("hello"));
+        assert_eq!(
+            rx.recv().await.as_deref(),
+            Some("<thought>\nb is thinking\n</thought>\n\nb")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+//! Moderating replies before they're relayed. A chat with a [`Moderator`]
+//! shows it each reply before passing it on; the moderator approves the
+//! reply, sends it back to its speaker for revision, or vetoes it so the
+//! chat carries on without it.
+
+use {
+    super::ChatMessage,
+    crate::{
+        llm::{CompletionRequest, HistoryMessage, LlmClient, Role},
+        prompts::{Prompt, PromptPack},
+    },
+    std::{fmt, future::Future, pin::Pin, sync::Arc},
+};
+
+/// The number of times a reply can be sent back for revision when none is
+/// configured.
+pub const DEFAULT_MAX_REVISIONS: usize = 2;
+
+/// A boxed future returned by [`Moderator::review`].
+pub type ReviewFuture<'a> = Pin<Box<dyn Future<Output = Verdict> + Send + 'a>>;
+
+/// A reply for a [`Moderator`] to review.
+#[derive(Debug, Clone, Copy)]
+pub struct Review<'a> {
+    /// The name of the participant that wrote the reply.
+    pub name: &'a str,
+
+    /// The reply.
+    pub reply: &'a str,
+
+    /// Every message so far, not counting the reply.
+    pub transcript: &'a [ChatMessage],
+}
+
+/// What a [`Moderator`] makes of a reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Relay the reply.
+    Approve,
+
+    /// Send the reply back to its speaker with this feedback. Once a reply
+    /// has been revised as many times as the chat allows, it's vetoed.
+    Revise(String),
+
+    /// Drop the reply, for this reason, and move on to the next speaker.
+    Veto(String),
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Approve => f.write_str("approved"),
+            Self::Revise(feedback) => write!(f, "sent back for revision: {feedback}"),
+            Self::Veto(reason) => write!(f, "vetoed: {reason}"),
+        }
+    }
+}
+
+/// Reviews the replies in a chat before they're relayed.
+pub trait Moderator: Send + Sync + 'static {
+    /// Returns what to do with the reply under review.
+    fn review<'a>(&'a self, review: Review<'a>) -> ReviewFuture<'a>;
+}
+
+/// A model judges whether each reply stays on topic and moves the
+/// conversation forward. If the model fails or its verdict can't be read,
+/// the reply is approved.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{chat::moderation::LlmModerator, group_chat::GroupChat, llm::openai}, std::sync::Arc};
+/// let chat = GroupChat::new()
+///     .with_moderator(LlmModerator::new(Arc::new(openai::Client::new(None, None)), "gpt-4"))
+///     .with_max_revisions(1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct LlmModerator {
+    client: Arc<dyn LlmClient>,
+    model: String,
+    prompts: PromptPack,
+}
+
+impl LlmModerator {
+    /// Create a moderator that asks `model` of `client`.
+    pub fn new(client: Arc<dyn LlmClient>, model: impl ToString) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            prompts: PromptPack::default(),
+        }
+    }
+
+    /// Ask the model with `prompts` instead of the English ones.
+    pub fn with_prompts(mut self, prompts: PromptPack) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    /// Returns the conversation that asks the model for its verdict.
+    fn request(&self, review: &Review<'_>) -> CompletionRequest {
+        let mut messages = vec![HistoryMessage::new(
+            Role::System,
+            self.prompts.get(Prompt::Moderation),
+        )];
+        messages.extend(review.transcript.iter().map(|message| {
+            HistoryMessage::new(Role::User, format!("{}: {}", message.name, message.content))
+        }));
+        messages.push(HistoryMessage::new(
+            Role::System,
+            self.prompts.render(
+                Prompt::ModerationReview,
+                &[("name", review.name), ("reply", review.reply)],
+            ),
+        ));
+        CompletionRequest {
+            model: self.model.clone(),
+            messages,
+            ..Default::default()
+        }
+    }
+}
+
+impl Moderator for LlmModerator {
+    fn review<'a>(&'a self, review: Review<'a>) -> ReviewFuture<'a> {
+        Box::pin(async move {
+            let reply = match self.client.complete(self.request(&review)).await {
+                Ok(completion) => completion.content,
+                Err(e) => {
+                    tracing::warn!(error = %e, "unable to moderate reply; approving it");
+                    return Verdict::Approve;
+                }
+            };
+            parse_verdict(&reply).unwrap_or_else(|| {
+                tracing::warn!(reply, "model gave no verdict; approving the reply");
+                Verdict::Approve
+            })
+        })
+    }
+}
+
+/// Returns the verdict a model's reply gives, if any.
+fn parse_verdict(reply: &str) -> Option<Verdict> {
+    let reply = reply.trim();
+    let (verdict, reason) = reply.split_once(':').unwrap_or((reply, ""));
+    let reason = reason.trim().to_string();
+    match verdict.trim().to_ascii_uppercase().as_str() {
+        "APPROVE" => Some(Verdict::Approve),
+        "REVISE" => Some(Verdict::Revise(reason)),
+        "VETO" => Some(Verdict::Veto(reason)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_verdict() {
+        assert_eq!(parse_verdict(" APPROVE\n"), Some(Verdict::Approve));
+        assert_eq!(
+            parse_verdict("Revise: cite a source"),
+            Some(Verdict::Revise("cite a source".to_string()))
+        );
+        assert_eq!(
+            parse_verdict("VETO: it's about cooking"),
+            Some(Verdict::Veto("it's about cooking".to_string()))
+        );
+        assert_eq!(parse_verdict("looks good to me"), None);
+    }
+}
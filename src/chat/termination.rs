@@ -0,0 +1,380 @@
+//! Deciding when a conversation ends. A [`TerminationCondition`] looks at the
+//! transcript after every message, and can also end a conversation while it
+//! waits on a reply, e.g. when it runs out of time or is cancelled.
+//!
+//! Conditions combine with [`or`](TerminationCondition::or) and
+//! [`and`](TerminationCondition::and):
+//!
+//! ```
+//! # use {autogen_rs::chat::termination::{Keyword, MaxMessages, TerminationCondition, Timeout}, std::time::Duration};
+//! let condition = Keyword::new("TERMINATE")
+//!     .or(MaxMessages(20))
+//!     .or(Timeout::new(Duration::from_secs(300)));
+//! ```
+
+use {
+    super::{ChatMessage, TerminationReason},
+    crate::{
+        agent::{Clock, SystemClock},
+        tokenizer::TokenizerRegistry,
+    },
+    std::{
+        fmt,
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
+    tokio_util::sync::CancellationToken,
+};
+
+/// A boxed future returned by [`TerminationCondition::triggered`].
+pub type TerminationFuture<'a> = Pin<Box<dyn Future<Output = TerminationReason> + Send + 'a>>;
+
+/// Decides when a conversation ends.
+pub trait TerminationCondition: fmt::Debug + Send + Sync + 'static {
+    /// Returns why the conversation should end, given every message so far,
+    /// or `None` to continue.
+    fn check(&self, transcript: &[ChatMessage]) -> Option<TerminationReason>;
+
+    /// Returns a future that completes if the condition is met while the
+    /// conversation waits, e.g. on a timeout. Never completes by default.
+    fn triggered(&self) -> TerminationFuture<'_> {
+        Box::pin(std::future::pending())
+    }
+
+    /// Called when a conversation starts, so conditions that keep state,
+    /// e.g. timers, start over.
+    fn start(&self) {}
+
+    /// End the conversation when either condition is met.
+    fn or<C: TerminationCondition>(self, other: C) -> Or<Self, C>
+    where
+        Self: Sized,
+    {
+        Or(self, other)
+    }
+
+    /// End the conversation when both conditions are met, with the first
+    /// condition's reason.
+    fn and<C: TerminationCondition>(self, other: C) -> And<Self, C>
+    where
+        Self: Sized,
+    {
+        And(self, other)
+    }
+}
+
+impl<C: TerminationCondition + ?Sized> TerminationCondition for Box<C> {
+    fn check(&self, transcript: &[ChatMessage]) -> Option<TerminationReason> {
+        (**self).check(transcript)
+    }
+
+    fn triggered(&self) -> TerminationFuture<'_> {
+        (**self).triggered()
+    }
+
+    fn start(&self) {
+        (**self).start()
+    }
+}
+
+impl<C: TerminationCondition + ?Sized> TerminationCondition for Arc<C> {
+    fn check(&self, transcript: &[ChatMessage]) -> Option<TerminationReason> {
+        (**self).check(transcript)
+    }
+
+    fn triggered(&self) -> TerminationFuture<'_> {
+        (**self).triggered()
+    }
+
+    fn start(&self) {
+        (**self).start()
+    }
+}
+
+/// Returns a condition met when any of `conditions` is, or `None` if there
+/// are none.
+pub(crate) fn any(
+    conditions: Vec<Box<dyn TerminationCondition>>,
+) -> Option<Box<dyn TerminationCondition>> {
+    conditions
+        .into_iter()
+        .reduce(|a, b| Box::new(a.or(b)) as Box<dyn TerminationCondition>)
+}
+
+/// Met when either condition is met. See [`TerminationCondition::or`].
+#[derive(Debug, Clone)]
+pub struct Or<A, B>(A, B);
+
+impl<A: TerminationCondition, B: TerminationCondition> TerminationCondition for Or<A, B> {
+    fn check(&self, transcript: &[ChatMessage]) -> Option<TerminationReason> {
+        self.0
+            .check(transcript)
+            .or_else(|| self.1.check(transcript))
+    }
+
+    fn triggered(&self) -> TerminationFuture<'_> {
+        Box::pin(async move {
+            tokio::select! {
+                reason = self.0.triggered() => reason,
+                reason = self.1.triggered() => reason,
+            }
+        })
+    }
+
+    fn start(&self) {
+        self.0.start();
+        self.1.start();
+    }
+}
+
+/// Met when both conditions are met. See [`TerminationCondition::and`].
+///
+/// While the conversation waits, it's only met if both conditions are met
+/// by waiting, e.g. a timeout and a cancellation.
+#[derive(Debug, Clone)]
+pub struct And<A, B>(A, B);
+
+impl<A: TerminationCondition, B: TerminationCondition> TerminationCondition for And<A, B> {
+    fn check(&self, transcript: &[ChatMessage]) -> Option<TerminationReason> {
+        let reason = self.0.check(transcript)?;
+        self.1.check(transcript).map(|_| reason)
+    }
+
+    fn triggered(&self) -> TerminationFuture<'_> {
+        Box::pin(async move {
+            let (reason, _) = tokio::join!(self.0.triggered(), self.1.triggered());
+            reason
+        })
+    }
+
+    fn start(&self) {
+        self.0.start();
+        self.1.start();
+    }
+}
+
+/// Met once the transcript has this many messages, including the opening
+/// message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxMessages(pub usize);
+
+impl TerminationCondition for MaxMessages {
+    fn check(&self, transcript: &[ChatMessage]) -> Option<TerminationReason> {
+        (transcript.len() >= self.0).then_some(TerminationReason::MaxTurns(transcript.len()))
+    }
+}
+
+/// Met once the messages in the transcript add up to a number of tokens,
+/// ending the conversation as over [`Budget`](TerminationReason::Budget).
+#[derive(Debug, Clone)]
+pub struct MaxTokens {
+    max_tokens: usize,
+    tokenizers: TokenizerRegistry,
+    model: String,
+}
+
+impl MaxTokens {
+    /// Create a condition met at `max_tokens` tokens, estimated from the
+    /// messages' lengths.
+    pub fn new(max_tokens: usize) -> Self {
+        Self {
+            max_tokens,
+            tokenizers: TokenizerRegistry::new(),
+            model: String::new(),
+        }
+    }
+
+    /// Count tokens as `model` does, with its tokenizer in `tokenizers`.
+    pub fn with_tokenizer(mut self, tokenizers: TokenizerRegistry, model: impl ToString) -> Self {
+        self.tokenizers = tokenizers;
+        self.model = model.to_string();
+        self
+    }
+}
+
+impl TerminationCondition for MaxTokens {
+    fn check(&self, transcript: &[ChatMessage]) -> Option<TerminationReason> {
+        let tokens = transcript
+            .iter()
+            .map(|message| self.tokenizers.count(&self.model, &message.content))
+            .sum::<usize>();
+        (tokens >= self.max_tokens).then_some(TerminationReason::Budget)
+    }
+}
+
+/// Met when the last message contains a keyword, e.g. `TERMINATE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keyword(String);
+
+impl Keyword {
+    /// Create a condition met by messages containing `keyword`.
+    pub fn new(keyword: impl ToString) -> Self {
+        Self(keyword.to_string())
+    }
+}
+
+impl TerminationCondition for Keyword {
+    fn check(&self, transcript: &[ChatMessage]) -> Option<TerminationReason> {
+        transcript
+            .last()
+            .filter(|message| message.content.contains(&self.0))
+            .map(|_| TerminationReason::Keyword(self.0.clone()))
+    }
+}
+
+/// Met once a conversation has run for a while, even while it waits on a
+/// reply.
+#[derive(Debug)]
+pub struct Timeout {
+    timeout: Duration,
+    clock: Arc<dyn Clock>,
+    /// When the current conversation started.
+    started: Mutex<Option<Instant>>,
+}
+
+impl Timeout {
+    /// Create a condition met `timeout` after the conversation starts.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            clock: Arc::new(SystemClock),
+            started: Mutex::new(None),
+        }
+    }
+
+    /// Measure time on `clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Returns the time left, or `None` if the conversation hasn't started.
+    fn remaining(&self) -> Option<Duration> {
+        let started = (*self.started.lock().unwrap())?;
+        let elapsed = self.clock.now().saturating_duration_since(started);
+        Some(self.timeout.saturating_sub(elapsed))
+    }
+}
+
+impl TerminationCondition for Timeout {
+    fn check(&self, _transcript: &[ChatMessage]) -> Option<TerminationReason> {
+        self.remaining()
+            .filter(Duration::is_zero)
+            .map(|_| TerminationReason::Timeout)
+    }
+
+    fn triggered(&self) -> TerminationFuture<'_> {
+        let Some(remaining) = self.remaining() else {
+            return Box::pin(std::future::pending());
+        };
+        let sleep = self.clock.sleep(remaining);
+        Box::pin(async move {
+            sleep.await;
+            TerminationReason::Timeout
+        })
+    }
+
+    fn start(&self) {
+        *self.started.lock().unwrap() = Some(self.clock.now());
+    }
+}
+
+/// Met once a token is cancelled, e.g. when the user hits Ctrl-C, ending
+/// the conversation as [`HumanStop`](TerminationReason::HumanStop).
+#[derive(Debug, Clone, Default)]
+pub struct Cancelled(pub CancellationToken);
+
+impl TerminationCondition for Cancelled {
+    fn check(&self, _transcript: &[ChatMessage]) -> Option<TerminationReason> {
+        self.0
+            .is_cancelled()
+            .then_some(TerminationReason::HumanStop)
+    }
+
+    fn triggered(&self) -> TerminationFuture<'_> {
+        Box::pin(async move {
+            self.0.cancelled().await;
+            TerminationReason::HumanStop
+        })
+    }
+}
+
+/// Met when a function of the transcript returns true, ending the
+/// conversation as [`Completed`](TerminationReason::Completed).
+pub struct Predicate<F>(pub F);
+
+impl<F> fmt::Debug for Predicate<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Predicate(..)")
+    }
+}
+
+impl<F> TerminationCondition for Predicate<F>
+where
+    F: Fn(&[ChatMessage]) -> bool + Send + Sync + 'static,
+{
+    fn check(&self, transcript: &[ChatMessage]) -> Option<TerminationReason> {
+        (self.0)(transcript).then_some(TerminationReason::Completed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::agent::ManualClock};
+
+    fn transcript(contents: &[&str]) -> Vec<ChatMessage> {
+        contents
+            .iter()
+            .map(|content| ChatMessage {
+                name: "a".to_string(),
+                content: content.to_string(),
+                thought: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_combinators() {
+        let condition = Keyword::new("TERMINATE").or(MaxMessages(3));
+        assert_eq!(condition.check(&transcript(&["hi", "hello"])), None);
+        assert_eq!(
+            condition.check(&transcript(&["hi", "TERMINATE"])),
+            Some(TerminationReason::Keyword("TERMINATE".to_string()))
+        );
+        assert_eq!(
+            condition.check(&transcript(&["hi", "hello", "hey"])),
+            Some(TerminationReason::MaxTurns(3))
+        );
+
+        let condition = MaxTokens::new(4).and(Predicate(|transcript: &[ChatMessage]| {
+            transcript.iter().any(|message| message.content == "done")
+        }));
+        assert_eq!(condition.check(&transcript(&["done"])), None);
+        assert_eq!(
+            condition.check(&transcript(&["a long message, over budget", "done"])),
+            Some(TerminationReason::Budget)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_waiting() {
+        let clock = Arc::new(ManualClock::new());
+        let token = CancellationToken::new();
+        let timeout = Timeout::new(Duration::from_secs(10)).with_clock(clock.clone());
+        let condition = Cancelled(token.clone()).or(timeout);
+        condition.start();
+        assert_eq!(condition.check(&[]), None);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(condition.check(&[]), Some(TerminationReason::Timeout));
+        assert_eq!(condition.triggered().await, TerminationReason::Timeout);
+
+        // starting over resets the timer
+        condition.start();
+        let triggered = condition.triggered();
+        token.cancel();
+        assert_eq!(triggered.await, TerminationReason::HumanStop);
+    }
+}
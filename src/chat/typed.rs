@@ -0,0 +1,286 @@
+//! Chats with typed ends. A [`Chat`] is declared with the Rust types it
+//! takes and returns: the input is rendered into the opening message with a
+//! template, and the chat's last reply is parsed and validated into the
+//! output, so a conversation between agents is called like any other async
+//! function.
+
+use {
+    super::{ChatBuilder, ChatOutcome, TerminationReason},
+    crate::{
+        agent::assistant::parse_json,
+        llm::ResponseFormat,
+        prompts::{self, Prompt, PromptPack},
+    },
+    schemars::JsonSchema,
+    serde::{de::DeserializeOwned, Serialize},
+    serde_json::Value,
+    std::{fmt, marker::PhantomData, sync::Arc},
+};
+
+/// An error calling a typed chat.
+#[derive(thiserror::Error, Debug)]
+pub enum TypedChatError {
+    /// The input couldn't be serialized to fill in the template.
+    #[error("unable to serialize input: {0}")]
+    Input(serde_json::Error),
+
+    /// The chat ended by an error, or before anyone replied.
+    #[error("chat ended without a result: {0}")]
+    Ended(TerminationReason),
+
+    /// The chat's last reply isn't the output.
+    #[error("unable to parse chat's result: {error}")]
+    Parse {
+        /// Why the reply couldn't be parsed.
+        error: serde_json::Error,
+
+        /// The chat's outcome, with the reply that couldn't be parsed.
+        outcome: Box<ChatOutcome>,
+    },
+
+    /// The output was rejected by the chat's validator.
+    #[error("invalid chat result: {0}")]
+    Invalid(String),
+}
+
+/// Checks an output once it's parsed, returning why it's invalid.
+type Validator<O> = Arc<dyn Fn(&O) -> Result<(), String> + Send + Sync>;
+
+/// A chat that takes an `I` and returns an `O`. Each call starts a chat from
+/// the builder returned by its factory, opened with the template filled in
+/// from the input: `{field}` is replaced by the input's field, strings as
+/// they are and anything else as JSON, and `{input}` by the whole input as
+/// JSON. The participants are told the output's schema, and the chat's last
+/// reply is parsed into an `O`.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::{agent::{AgentBuilder, Message, SendError}, chat::{typed::Chat, ChatBuilder}};
+/// # tokio_test::block_on(async {
+/// #[derive(serde::Serialize)]
+/// struct Ticket {
+///     title: String,
+///     body: String,
+/// }
+///
+/// #[derive(serde::Deserialize, schemars::JsonSchema)]
+/// struct Triage {
+///     severity: u8,
+/// }
+///
+/// let triager = AgentBuilder::<_, SendError<_>>::new().spawn(|sender, message: Box<Message>| async move {
+///     message.sender.send(Box::new(Message::new(sender, r#"{"severity": 2}"#))).await
+/// });
+/// let reporter = AgentBuilder::<_, SendError<Box<Message>>>::new().spawn(|_, _: Box<Message>| async { Ok(()) });
+/// let (reporter, triager) = (reporter.sender(), triager.sender());
+///
+/// let triage = Chat::<Ticket, Triage>::new("Triage this ticket.\n{title}\n\n{body}", move || {
+///     ChatBuilder::new()
+///         .with_participant("reporter", reporter.clone())
+///         .with_participant("triager", triager.clone())
+///         .with_max_turns(1)
+/// })
+/// .with_validator(|triage| match triage.severity {
+///     1..=4 => Ok(()),
+///     severity => Err(format!("severity {severity} isn't from 1 to 4")),
+/// });
+///
+/// let ticket = Ticket {
+///     title: "Login fails".to_string(),
+///     body: "Since the update, nobody can log in.".to_string(),
+/// };
+/// assert_eq!(triage.call(&ticket).await?.severity, 2);
+/// # anyhow::Ok(())
+/// # });
+/// ```
+pub struct Chat<I, O> {
+    template: String,
+    factory: Arc<dyn Fn() -> ChatBuilder + Send + Sync>,
+    validator: Option<Validator<O>>,
+    prompts: PromptPack,
+    types: PhantomData<fn(&I) -> O>,
+}
+
+impl<I, O> Clone for Chat<I, O> {
+    fn clone(&self) -> Self {
+        Self {
+            template: self.template.clone(),
+            factory: self.factory.clone(),
+            validator: self.validator.clone(),
+            prompts: self.prompts.clone(),
+            types: PhantomData,
+        }
+    }
+}
+
+impl<I, O> fmt::Debug for Chat<I, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Chat")
+            .field("template", &self.template)
+            .field("validator", &self.validator.is_some())
+            .field("prompts", &self.prompts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<I, O> Chat<I, O>
+where
+    I: Serialize,
+    O: DeserializeOwned + JsonSchema,
+{
+    /// Create a chat that opens with `template` filled in from the input,
+    /// between the participants of the builder `factory` returns.
+    pub fn new(
+        template: impl ToString,
+        factory: impl Fn() -> ChatBuilder + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            template: template.to_string(),
+            factory: Arc::new(factory),
+            validator: None,
+            prompts: PromptPack::default(),
+            types: PhantomData,
+        }
+    }
+
+    /// Reject outputs `validator` returns an error for, e.g. a score out of
+    /// range.
+    pub fn with_validator(
+        mut self,
+        validator: impl Fn(&O) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.validator = Some(Arc::new(validator));
+        self
+    }
+
+    /// Tell the participants the output's schema with `prompts` instead of
+    /// the English ones.
+    pub fn with_prompts(mut self, prompts: PromptPack) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    /// Returns the message the chat opens with for `input`.
+    pub fn render(&self, input: &I) -> Result<String, TypedChatError> {
+        let input = serde_json::to_value(input).map_err(TypedChatError::Input)?;
+        let text = |value: &Value| match value {
+            Value::String(string) => string.clone(),
+            value => value.to_string(),
+        };
+        let mut values = vec![("input".to_string(), input.to_string())];
+        if let Value::Object(fields) = &input {
+            values.extend(
+                fields
+                    .iter()
+                    .map(|(name, value)| (name.clone(), text(value))),
+            );
+        }
+        let values = values
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        let schema = ResponseFormat::of::<O>().schema;
+        let schema = serde_json::to_string_pretty(&schema).map_err(TypedChatError::Input)?;
+        let output = self
+            .prompts
+            .render(Prompt::TypedOutput, &[("schema", &schema)]);
+        Ok(format!(
+            "{}\n\n{output}",
+            prompts::render(&self.template, &values)
+        ))
+    }
+
+    /// Run the chat on `input` and return its output.
+    pub async fn call(&self, input: &I) -> Result<O, TypedChatError> {
+        let message = self.render(input)?;
+        let outcome = (self.factory)().start(message).join().await;
+        if outcome.reason.is_error() {
+            return Err(TypedChatError::Ended(outcome.reason));
+        }
+        // the first message is the template's, not a reply
+        let Some(reply) = outcome.transcript.iter().skip(1).last() else {
+            return Err(TypedChatError::Ended(outcome.reason));
+        };
+        let output = match parse_json::<O>(&reply.content) {
+            Ok(output) => output,
+            Err(error) => {
+                let outcome = Box::new(outcome);
+                return Err(TypedChatError::Parse { error, outcome });
+            }
+        };
+        if let Some(validator) = &self.validator {
+            validator(&output).map_err(TypedChatError::Invalid)?;
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::agent::{AgentBuilder, Message, SendError},
+        anyhow::Result,
+        serde::Deserialize,
+    };
+
+    #[derive(Serialize)]
+    struct Order {
+        id: u32,
+        items: Vec<String>,
+        note: String,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+    struct Summary {
+        count: usize,
+    }
+
+    #[tokio::test]
+    async fn test_typed_chat() -> Result<()> {
+        // replies with the number of lines it's sent, checking it was told
+        // the schema
+        let counter = AgentBuilder::<_, SendError<_>>::new().spawn(
+            |sender, message: Box<Message>| async move {
+                let content = message.content.to_string();
+                assert!(content.contains("\"count\""));
+                let count = content.lines().take_while(|line| !line.is_empty()).count();
+                let reply = format!("```json\n{{\"count\": {count}}}\n```");
+                message
+                    .sender
+                    .send(Box::new(Message::new(sender, reply)))
+                    .await
+            },
+        );
+        let opener = AgentBuilder::<_, SendError<Box<Message>>>::new()
+            .spawn(|_, _: Box<Message>| async { Ok(()) });
+        let (opener, counter) = (opener.sender(), counter.sender());
+        let chat =
+            Chat::<Order, Summary>::new("Order {id}: {note}\n{items}\n{unknown}", move || {
+                ChatBuilder::new()
+                    .with_participant("opener", opener.clone())
+                    .with_participant("counter", counter.clone())
+                    .with_max_turns(1)
+            });
+
+        let order = Order {
+            id: 7,
+            items: vec!["tea".to_string()],
+            note: "gift".to_string(),
+        };
+        assert!(chat
+            .render(&order)?
+            .starts_with("Order 7: gift\n[\"tea\"]\n{unknown}\n\n"));
+        assert_eq!(chat.call(&order).await?, Summary { count: 3 });
+
+        let chat = chat.with_validator(|summary| match summary.count {
+            0..=2 => Ok(()),
+            count => Err(format!("{count} lines")),
+        });
+        match chat.call(&order).await {
+            Err(TypedChatError::Invalid(reason)) => assert_eq!(reason, "3 lines"),
+            result => panic!("expected the output to be invalid, got {result:?}"),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,397 @@
+//! Cleaning up after aborted work. Aborting a task only drops it, so
+//! anything it started outside the process, e.g. a container or a
+//! half-written workspace, would be left behind. [`Resources`] keeps track of
+//! what a chat or workflow has started: in-flight model requests, containers,
+//! temporary directories and agents. Cancelling it interrupts the requests
+//! and cleans up everything else, and reports what was cleaned up.
+
+use {
+    crate::{
+        agent::Actor,
+        llm::{self, CompletionRequest, Delta, LlmClient, LlmFuture},
+    },
+    std::{
+        collections::HashMap,
+        fmt,
+        future::Future,
+        path::PathBuf,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+    },
+    tokio_util::sync::CancellationToken,
+};
+
+/// A boxed future returned by a cleanup. Fails with a description of what
+/// went wrong.
+pub type CleanupFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+
+/// Cleans up a resource.
+type Cleanup = Box<dyn FnOnce() -> CleanupFuture + Send>;
+
+/// What kind of resource is tracked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResourceKind {
+    /// A request to a model.
+    Request,
+
+    /// A container running code.
+    Container,
+
+    /// A temporary directory.
+    Workspace,
+
+    /// An agent.
+    Agent,
+
+    /// Anything else.
+    Other,
+}
+
+impl fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Request => "request",
+            Self::Container => "container",
+            Self::Workspace => "workspace",
+            Self::Agent => "agent",
+            Self::Other => "resource",
+        })
+    }
+}
+
+/// A tracked resource.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resource {
+    pub kind: ResourceKind,
+
+    /// What the resource is, e.g. a container's name or a directory's path.
+    pub name: String,
+}
+
+impl Resource {
+    /// Create a resource of `kind` named `name`.
+    pub fn new(kind: ResourceKind, name: impl ToString) -> Self {
+        Self {
+            kind,
+            name: name.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.name)
+    }
+}
+
+/// What cancelling [`Resources`] cleaned up.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CleanupReport {
+    /// The resources that were cleaned up.
+    pub released: Vec<Resource>,
+
+    /// The resources that couldn't be cleaned up, and why.
+    pub failed: Vec<(Resource, String)>,
+}
+
+impl CleanupReport {
+    /// Returns whether every resource was cleaned up.
+    pub fn is_clean(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl fmt::Display for CleanupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cleaned up {} resources", self.released.len())?;
+        for (resource, error) in &self.failed {
+            write!(f, "; unable to clean up {resource}: {error}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    tracked: Mutex<HashMap<u64, (Resource, Cleanup)>>,
+    next: AtomicU64,
+    cancelled: CancellationToken,
+}
+
+/// The resources a chat or workflow started. Clones share the same
+/// resources.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::{agent::assistant::AssistantBuilder, chat::ChatBuilder, cleanup::Resources};
+/// # tokio_test::block_on(async {
+/// let resources = Resources::new();
+/// let workspace = resources.track_dir(std::env::temp_dir().join("scratch"));
+/// let assistant = AssistantBuilder::new()
+///     .with_resources(resources.clone())
+///     .build();
+/// let chat = ChatBuilder::new()
+///     .with_participant("assistant", assistant.sender())
+///     .with_resources(resources)
+///     .start("Write a script that sorts a CSV.");
+///
+/// // interrupts the assistant's request, and removes the workspace
+/// let report = chat.abort().await;
+/// assert!(report.is_clean());
+/// # });
+/// ```
+#[derive(Clone, Default)]
+pub struct Resources(Arc<Shared>);
+
+impl fmt::Debug for Resources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tracked = self.0.tracked.lock().unwrap();
+        f.debug_list()
+            .entries(tracked.values().map(|(resource, _)| resource))
+            .finish()
+    }
+}
+
+impl Resources {
+    /// Create an empty set of resources.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Track `resource` until the returned guard is dropped, cleaning it up
+    /// with `cleanup` if the resources are cancelled first. A resource
+    /// tracked after they're cancelled is cleaned up right away.
+    pub fn track<F, R>(&self, resource: Resource, cleanup: F) -> Tracked
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        if self.is_cancelled() {
+            tokio::spawn(async move {
+                if let Err(e) = cleanup().await {
+                    tracing::warn!(%resource, error = e, "unable to clean up resource");
+                }
+            });
+            return Tracked(None);
+        }
+        let id = self.0.next.fetch_add(1, Ordering::Relaxed);
+        let cleanup: Cleanup = Box::new(move || Box::pin(cleanup()));
+        self.0
+            .tracked
+            .lock()
+            .unwrap()
+            .insert(id, (resource, cleanup));
+        Tracked(Some((self.clone(), id)))
+    }
+
+    /// Track the directory at `path`, removing it if the resources are
+    /// cancelled before the returned guard is dropped.
+    pub fn track_dir(&self, path: impl Into<PathBuf>) -> Tracked {
+        let path = path.into();
+        let resource = Resource::new(ResourceKind::Workspace, path.display());
+        self.track(resource, move || async move {
+            match tokio::fs::remove_dir_all(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    /// Take ownership of `actor`, aborting it when the resources are
+    /// cancelled. Keep its sender to talk to it.
+    pub fn adopt<A>(&self, actor: A)
+    where
+        A: Actor + Send + 'static,
+    {
+        let name = actor
+            .name()
+            .map(ToString::to_string)
+            .unwrap_or_else(|| actor.id().to_string());
+        let resource = Resource::new(ResourceKind::Agent, name);
+        self.track(resource, move || async move {
+            actor.abort();
+            Ok(())
+        })
+        .keep();
+    }
+
+    /// Returns `client`, with each call tracked as a request and
+    /// interrupted with [`llm::Error::Cancelled`] when the resources are
+    /// cancelled.
+    pub fn client(&self, client: Arc<dyn LlmClient>) -> Arc<dyn LlmClient> {
+        Arc::new(TrackedClient {
+            client,
+            resources: self.clone(),
+        })
+    }
+
+    /// Returns whether the resources were cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.is_cancelled()
+    }
+
+    /// Returns a token that's cancelled along with the resources, for work
+    /// that should stop then.
+    pub fn token(&self) -> CancellationToken {
+        self.0.cancelled.child_token()
+    }
+
+    /// Cancel the resources: interrupt tracked requests and clean up
+    /// everything else. Returns what was cleaned up.
+    pub async fn cancel(&self) -> CleanupReport {
+        self.0.cancelled.cancel();
+        let mut tracked = std::mem::take(&mut *self.0.tracked.lock().unwrap())
+            .into_iter()
+            .collect::<Vec<_>>();
+        // clean up in the order the resources were tracked
+        tracked.sort_by_key(|(id, _)| *id);
+        let mut report = CleanupReport::default();
+        for (_, (resource, cleanup)) in tracked {
+            match cleanup().await {
+                Ok(()) => report.released.push(resource),
+                Err(e) => {
+                    tracing::warn!(%resource, error = e, "unable to clean up resource");
+                    report.failed.push((resource, e));
+                }
+            }
+        }
+        report
+    }
+}
+
+/// Tracks a resource until it's dropped, once the resource was released
+/// normally and no longer needs cleaning up.
+#[derive(Debug)]
+#[must_use = "dropping the guard stops tracking the resource"]
+pub struct Tracked(Option<(Resources, u64)>);
+
+impl Tracked {
+    /// Keep tracking the resource until the resources are cancelled.
+    pub fn keep(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Some((resources, id)) = &self.0 {
+            resources.0.tracked.lock().unwrap().remove(id);
+        }
+    }
+}
+
+/// A client whose calls are tracked as requests.
+#[derive(Debug)]
+struct TrackedClient {
+    client: Arc<dyn LlmClient>,
+    resources: Resources,
+}
+
+impl TrackedClient {
+    /// Runs `call` as a tracked request for `model`, unless cancelled first.
+    async fn track<F>(&self, model: &str, call: F) -> Result<llm::Completion, llm::Error>
+    where
+        F: Future<Output = Result<llm::Completion, llm::Error>>,
+    {
+        let token = self.resources.token();
+        // dropping the call is what interrupts it
+        let _tracked = self
+            .resources
+            .track(Resource::new(ResourceKind::Request, model), || async {
+                Ok(())
+            });
+        tokio::select! {
+            completion = call => completion,
+            _ = token.cancelled() => Err(llm::Error::Cancelled),
+        }
+    }
+}
+
+impl LlmClient for TrackedClient {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            let model = request.model.clone();
+            self.track(&model, self.client.complete(request)).await
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: CompletionRequest,
+        on_delta: &'a mut (dyn FnMut(Delta<'_>) + Send),
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            let model = request.model.clone();
+            self.track(&model, self.client.stream(request, on_delta))
+                .await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::llm::{Completion, HistoryMessage, Role},
+        anyhow::Result,
+    };
+
+    /// A client that never replies.
+    #[derive(Debug)]
+    struct Stuck;
+
+    impl LlmClient for Stuck {
+        fn complete(&self, _request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(std::future::pending::<Result<Completion, llm::Error>>())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel() -> Result<()> {
+        let resources = Resources::new();
+        let dir = std::env::temp_dir().join(format!("workspace-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let workspace = resources.track_dir(&dir);
+        // released normally, so not cleaned up
+        drop(
+            resources.track(Resource::new(ResourceKind::Other, "done"), || async {
+                Err("cleaned up twice".to_string())
+            }),
+        );
+        let container = resources.track(Resource::new(ResourceKind::Container, "box"), || async {
+            Err("no such container".to_string())
+        });
+
+        let client = resources.client(Arc::new(Stuck));
+        let request = CompletionRequest {
+            model: "gpt-4".to_string(),
+            messages: vec![HistoryMessage::new(Role::User, "hello")],
+            ..Default::default()
+        };
+        let call = tokio::spawn(async move { client.complete(request).await });
+        tokio::task::yield_now().await;
+
+        let report = resources.cancel().await;
+        assert!(matches!(call.await?, Err(llm::Error::Cancelled)));
+        assert!(!dir.exists());
+        assert_eq!(
+            report.released,
+            [
+                Resource::new(ResourceKind::Workspace, dir.display()),
+                Resource::new(ResourceKind::Request, "gpt-4"),
+            ]
+        );
+        assert_eq!(
+            report.failed,
+            [(
+                Resource::new(ResourceKind::Container, "box"),
+                "no such container".to_string()
+            )]
+        );
+        drop((workspace, container));
+        Ok(())
+    }
+}
@@ -0,0 +1,403 @@
+//! Running code in Docker containers, so code written by a model never runs
+//! on the host. Each code block gets a fresh container with the scratch
+//! directory mounted, resource limits and, by default, no network.
+//!
+//! Containers that need to reach particular hosts, e.g. a package index, are
+//! attached to an internal network with no route out, and reach the allowed
+//! hosts through an [`EgressProxy`] on the host that refuses every other
+//! host.
+
+use {
+    super::{
+        network::{Allowlist, EgressProxy},
+        run, CodeBlock, CodeExecutor, Error, ExecuteFuture, Language, ResourceLimits,
+        DEFAULT_MAX_OUTPUT, DEFAULT_TIMEOUT,
+    },
+    crate::cleanup::{Resource, ResourceKind, Resources},
+    std::{
+        io,
+        net::IpAddr,
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::Duration,
+    },
+    tokio::{process::Command, sync::OnceCell},
+    uuid::Uuid,
+};
+
+/// The image code is run in by default.
+pub const DEFAULT_IMAGE: &str = "python:3-slim";
+
+/// Where the scratch directory is mounted in the container.
+const CONTAINER_DIR: &str = "/workspace";
+
+/// The internal network containers with allowed hosts are attached to.
+pub const EGRESS_NETWORK: &str = "autogen-rs-egress";
+
+/// How code is run in a container.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DockerOptions {
+    /// The image to run code in. It must have `sh`, and the interpreter of
+    /// each language run in it, e.g. `python3`.
+    pub image: String,
+
+    /// How many CPUs a container may use, if limited.
+    pub cpus: Option<f64>,
+
+    /// How much memory a container may use, in bytes, if limited.
+    pub memory: Option<u64>,
+
+    /// The network to attach containers to, letting them connect to
+    /// anything it reaches. None isolates them from the network, except for
+    /// the allowed hosts.
+    pub network: Option<String>,
+
+    /// The hosts containers may connect to when they're isolated from the
+    /// network. Code reaches them through a proxy, set in the `HTTP_PROXY`
+    /// and `HTTPS_PROXY` environment variables, so only HTTP clients that
+    /// honor those can.
+    pub allowed_hosts: Allowlist,
+}
+
+impl Default for DockerOptions {
+    fn default() -> Self {
+        Self {
+            image: DEFAULT_IMAGE.to_string(),
+            cpus: Some(1.0),
+            memory: Some(512 * 1024 * 1024),
+            network: None,
+            allowed_hosts: Allowlist::default(),
+        }
+    }
+}
+
+impl DockerOptions {
+    /// Create options that run code in `image` with the default limits.
+    pub fn new(image: impl ToString) -> Self {
+        Self {
+            image: image.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Let containers use at most `cpus` CPUs.
+    pub fn with_cpus(mut self, cpus: f64) -> Self {
+        self.cpus = Some(cpus);
+        self
+    }
+
+    /// Let containers use at most `memory` bytes of memory.
+    pub fn with_memory(mut self, memory: u64) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Attach containers to `network`, e.g. "bridge", instead of isolating
+    /// them.
+    pub fn with_network(mut self, network: impl ToString) -> Self {
+        self.network = Some(network.to_string());
+        self
+    }
+
+    /// Let isolated containers connect to `hosts` and no others. See
+    /// [`Allowlist`] for the patterns hosts can be given as.
+    pub fn with_allowed_hosts<I, S>(mut self, hosts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        self.allowed_hosts = Allowlist::new(hosts);
+        self
+    }
+}
+
+/// Runs code in a Docker container, through the `docker` command.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::code_executor::{docker::DockerOptions, CodeExecutorAgentBuilder};
+/// # tokio_test::block_on(async {
+/// let executor = CodeExecutorAgentBuilder::new()
+///     .with_work_dir("scratch")
+///     .with_docker(DockerOptions::new("python:3.12-slim").with_memory(256 * 1024 * 1024))
+///     .build();
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct DockerCodeExecutor {
+    work_dir: PathBuf,
+    options: DockerOptions,
+    timeout: Duration,
+    max_output: usize,
+    limits: ResourceLimits,
+    /// The proxy to allowed hosts, started for the first code block.
+    egress: Arc<OnceCell<EgressProxy>>,
+    /// Tracks running containers, so they're removed if the work they're
+    /// for is aborted.
+    resources: Option<Resources>,
+}
+
+impl DockerCodeExecutor {
+    /// Create an executor that mounts `work_dir` as the containers' working
+    /// directory. It's created if it doesn't exist.
+    pub fn new(work_dir: impl Into<PathBuf>, options: DockerOptions) -> Self {
+        Self {
+            work_dir: work_dir.into(),
+            options,
+            timeout: DEFAULT_TIMEOUT,
+            max_output: DEFAULT_MAX_OUTPUT,
+            limits: ResourceLimits::default(),
+            egress: Default::default(),
+            resources: None,
+        }
+    }
+
+    /// Kill containers that run longer than `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Keep at most `max_output` bytes of each output stream.
+    pub fn with_max_output(mut self, max_output: usize) -> Self {
+        self.max_output = max_output;
+        self
+    }
+
+    /// Hold containers to `limits`. A memory limit overrides
+    /// [`DockerOptions::memory`].
+    pub fn with_limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Track running containers in `resources`, removing them when the
+    /// resources are cancelled.
+    pub fn with_resources(mut self, resources: Resources) -> Self {
+        self.resources = Some(resources);
+        self
+    }
+
+    /// Returns the directory mounted in the containers.
+    pub fn work_dir(&self) -> &Path {
+        &self.work_dir
+    }
+
+    /// Returns the proxy containers reach allowed hosts through, starting it
+    /// if it isn't running, or `None` if containers don't go through one.
+    async fn egress(&self) -> io::Result<Option<&EgressProxy>> {
+        if self.options.network.is_some() || self.options.allowed_hosts.is_empty() {
+            return Ok(None);
+        }
+        let proxy = self
+            .egress
+            .get_or_try_init(|| async {
+                let gateway = egress_gateway().await?;
+                tracing::debug!(%gateway, hosts = %self.options.allowed_hosts, "starting egress proxy");
+                EgressProxy::start((gateway, 0), self.options.allowed_hosts.clone()).await
+            })
+            .await?;
+        Ok(Some(proxy))
+    }
+
+    /// Returns the command that runs `script` from `work_dir` in a container
+    /// named `name`, connecting to allowed hosts through the proxy at
+    /// `proxy`, if there is one.
+    fn command(
+        &self,
+        work_dir: &Path,
+        block: &CodeBlock,
+        script: &str,
+        name: &str,
+        proxy: Option<&str>,
+    ) -> Command {
+        let mut command = Command::new("docker");
+        command
+            .args(["run", "--rm", "--name", name])
+            .arg("--volume")
+            .arg(format!("{}:{CONTAINER_DIR}", work_dir.display()))
+            .args(["--workdir", CONTAINER_DIR]);
+        match proxy {
+            Some(proxy) => {
+                command.args(["--network", EGRESS_NETWORK]);
+                for var in ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"] {
+                    command.arg("--env").arg(format!("{var}={proxy}"));
+                }
+            }
+            None => {
+                command.args([
+                    "--network",
+                    self.options.network.as_deref().unwrap_or("none"),
+                ]);
+            }
+        }
+        if let Some(cpus) = self.options.cpus {
+            command.arg("--cpus").arg(cpus.to_string());
+        }
+        if let Some(memory) = self.limits.memory.or(self.options.memory) {
+            command.arg("--memory").arg(memory.to_string());
+        }
+        if let Some(processes) = self.limits.processes {
+            command.arg("--pids-limit").arg(processes.to_string());
+        }
+        if let Some(seconds) = self.limits.cpu_seconds() {
+            command
+                .arg("--ulimit")
+                .arg(format!("cpu={seconds}:{}", seconds + 1));
+        }
+        command
+            .arg(&self.options.image)
+            .args(block.language.command(script));
+        command
+    }
+}
+
+impl CodeExecutor for DockerCodeExecutor {
+    fn execute<'a>(&'a self, block: &'a CodeBlock) -> ExecuteFuture<'a> {
+        Box::pin(async move {
+            tokio::fs::create_dir_all(&self.work_dir).await?;
+            let work_dir = tokio::fs::canonicalize(&self.work_dir).await?;
+            let id = Uuid::new_v4();
+            let script = format!("{id}.{}", block.language.extension());
+            tokio::fs::write(work_dir.join(&script), &block.code).await?;
+
+            let name = format!("autogen-rs-{id}");
+            let proxy = self
+                .egress()
+                .await
+                .map_err(|e| Error::Network(e.to_string()))?
+                .map(EgressProxy::url);
+            let command = self.command(&work_dir, block, &script, &name, proxy.as_deref());
+            let _container = self.resources.as_ref().map(|resources| {
+                let name = name.clone();
+                let container = Resource::new(ResourceKind::Container, &name);
+                resources.track(container, move || async move { remove(&name).await })
+            });
+            let mut execution = run(command, self.timeout, self.max_output).await;
+            if let Ok(execution) = &mut execution {
+                self.limits.classify(execution);
+            }
+            if execution
+                .as_ref()
+                .map_or(true, |execution| execution.timed_out)
+            {
+                // killing the client doesn't stop the container
+                let _ = remove(&name).await;
+            }
+            let _ = tokio::fs::remove_file(work_dir.join(&script)).await;
+            if block.language == Language::Rust {
+                let _ = tokio::fs::remove_file(work_dir.join(id.to_string())).await;
+            }
+            execution
+        })
+    }
+}
+
+/// Removes the container named `name`, stopping it if it's running.
+async fn remove(name: &str) -> Result<(), String> {
+    let output = Command::new("docker")
+        .args(["rm", "--force", name])
+        .output()
+        .await
+        .map_err(|e| e.to_string())?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+    }
+}
+
+/// Returns the address of the host on the internal network for containers
+/// with allowed hosts, creating the network if it doesn't exist.
+async fn egress_gateway() -> io::Result<IpAddr> {
+    let inspect = || {
+        Command::new("docker")
+            .args(["network", "inspect", "--format"])
+            .arg("{{range .IPAM.Config}}{{.Gateway}}{{end}}")
+            .arg(EGRESS_NETWORK)
+            .output()
+    };
+    let mut output = inspect().await?;
+    if !output.status.success() {
+        // another executor may create it first, so only the inspection after
+        // creating it counts
+        let _ = Command::new("docker")
+            .args(["network", "create", "--internal", EGRESS_NETWORK])
+            .output()
+            .await?;
+        output = inspect().await?;
+    }
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    let gateway = String::from_utf8_lossy(&output.stdout);
+    gateway.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{EGRESS_NETWORK} has no gateway address: {gateway:?}"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        let executor = DockerCodeExecutor::new(
+            "scratch",
+            DockerOptions::new("python:3.12-slim").with_memory(1024),
+        );
+        let block = CodeBlock {
+            language: Language::Python,
+            code: "print('hi')".to_string(),
+        };
+        let command = executor.command(Path::new("/tmp/scratch"), &block, "a.py", "a", None);
+        let args = command
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            args,
+            [
+                "run",
+                "--rm",
+                "--name",
+                "a",
+                "--volume",
+                "/tmp/scratch:/workspace",
+                "--workdir",
+                "/workspace",
+                "--network",
+                "none",
+                "--cpus",
+                "1",
+                "--memory",
+                "1024",
+                "python:3.12-slim",
+                "python3",
+                "a.py",
+            ]
+        );
+
+        // containers with allowed hosts go through the proxy
+        let command = executor.command(
+            Path::new("/tmp/scratch"),
+            &block,
+            "a.py",
+            "a",
+            Some("http://172.18.0.1:3128"),
+        );
+        let args = command
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(args[8..11], ["--network", EGRESS_NETWORK, "--env"]);
+        assert!(args.contains(&"HTTPS_PROXY=http://172.18.0.1:3128"));
+    }
+}
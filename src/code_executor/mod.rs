@@ -0,0 +1,1337 @@
+//! Running code written by a model. A [`CodeExecutorAgent`] extracts fenced
+//! code blocks from the messages it receives, runs them with a
+//! [`CodeExecutor`] and replies with each block's exit code and output, so an
+//! assistant can see what its code did and fix it.
+//!
+//! A block's language comes from its info string, or is detected from the
+//! code when there isn't one. A [`LanguageRouter`] sends each language to
+//! its own executor; blocks in languages that can't be run are answered with
+//! an "unsupported language" note instead of output.
+//!
+//! The [`LocalExecutor`] runs code in a subprocess on the host. It confines
+//! the process to a working directory, kills it after a timeout, caps the
+//! output it keeps and can hold it to [`ResourceLimits`], but it doesn't stop
+//! the code from touching the rest of the file system or the network. In
+//! production, use the [`docker::DockerCodeExecutor`] instead, which runs
+//! each code block in its own container, offline or with a
+//! [network allowlist](network::Allowlist).
+
+pub mod docker;
+pub mod network;
+pub mod venv;
+
+use {
+    crate::{
+        agent::{self, Actor, Message, Sender, Shutdown},
+        cleanup::Resources,
+        flags::{AgentFlags, CODE_EXECUTION},
+        Agent,
+    },
+    docker::{DockerCodeExecutor, DockerOptions},
+    std::{
+        collections::HashMap,
+        ffi::OsString,
+        fmt::{self, Debug},
+        future::Future,
+        path::{Path, PathBuf},
+        pin::Pin,
+        process::Stdio,
+        sync::Arc,
+        time::Duration,
+    },
+    tokio::{
+        io::{AsyncRead, AsyncReadExt},
+        process::{Child, Command},
+    },
+    uuid::Uuid,
+    venv::VenvExecutor,
+};
+
+/// How long code may run by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How much of each output stream is kept by default, in bytes.
+pub const DEFAULT_MAX_OUTPUT: usize = 64 * 1024;
+
+/// How long output is still read for once the code's process group is
+/// killed.
+const OUTPUT_GRACE: Duration = Duration::from_millis(100);
+
+/// A boxed future returned by [`CodeExecutor::execute`].
+pub type ExecuteFuture<'a> = Pin<Box<dyn Future<Output = Result<Execution, Error>> + Send + 'a>>;
+
+/// Errors that can occur when running code.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("unable to run code: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("unable to send reply: {0}")]
+    SendError(#[from] agent::SendError<Box<Message>>),
+
+    /// No executor runs code in the language.
+    #[error("unsupported language: {0}")]
+    UnsupportedLanguage(Language),
+
+    /// The network code is allowed to use couldn't be set up.
+    #[error("unable to set up network: {0}")]
+    Network(String),
+
+    /// The agent's [`CODE_EXECUTION`] flag is off.
+    #[error("code execution is disabled")]
+    Disabled,
+}
+
+/// A language code can be run in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Language {
+    /// A POSIX shell script.
+    Shell,
+
+    /// A Python 3 script.
+    Python,
+
+    /// A JavaScript script, run with Node.js.
+    JavaScript,
+
+    /// A Rust program, compiled with `rustc` and then run.
+    Rust,
+}
+
+impl Language {
+    /// Every supported language.
+    pub const ALL: [Self; 4] = [Self::Python, Self::Shell, Self::JavaScript, Self::Rust];
+
+    /// Returns the language of a code block's info string, e.g. "bash" or
+    /// "py", or None if it isn't supported.
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        match tag.trim().to_lowercase().as_str() {
+            "sh" | "shell" | "bash" | "console" => Some(Self::Shell),
+            "python" | "py" | "python3" => Some(Self::Python),
+            "javascript" | "js" | "node" | "mjs" => Some(Self::JavaScript),
+            "rust" | "rs" => Some(Self::Rust),
+            _ => None,
+        }
+    }
+
+    /// Returns the language `code` is most likely written in, judging by its
+    /// shebang or telltale lines, or None if it's unclear.
+    pub fn detect(code: &str) -> Option<Self> {
+        let first = code.lines().find(|line| !line.trim().is_empty())?.trim();
+        if let Some(shebang) = first.strip_prefix("#!") {
+            return [
+                ("python", Self::Python),
+                ("node", Self::JavaScript),
+                ("sh", Self::Shell),
+            ]
+            .into_iter()
+            .find(|(program, _)| shebang.contains(program))
+            .map(|(_, language)| language);
+        }
+
+        let starts = |prefix: &str| {
+            code.lines()
+                .any(|line| line.trim_start().starts_with(prefix))
+        };
+        if code.contains("fn main()") {
+            Some(Self::Rust)
+        } else if code.contains("console.log(") || starts("const ") || starts("function ") {
+            Some(Self::JavaScript)
+        } else if starts("def ") || starts("import ") || starts("from ") || starts("print(") {
+            Some(Self::Python)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the extension of a script in the language.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Shell => "sh",
+            Self::Python => "py",
+            Self::JavaScript => "js",
+            Self::Rust => "rs",
+        }
+    }
+
+    /// Returns the program that runs, or for Rust compiles, a script in the
+    /// language.
+    pub fn interpreter(self) -> &'static str {
+        match self {
+            Self::Shell => "sh",
+            Self::Python => "python3",
+            Self::JavaScript => "node",
+            Self::Rust => "rustc",
+        }
+    }
+
+    /// Returns the command that runs `script`, a file in the working
+    /// directory. A Rust program is compiled next to it, to a binary named
+    /// after the script without its extension.
+    pub fn command(self, script: &str) -> Vec<String> {
+        match self {
+            Self::Rust => {
+                let binary = script.strip_suffix(".rs").unwrap_or(script);
+                [
+                    "sh",
+                    "-c",
+                    r#"rustc --edition 2021 -o "$1" "$2" && exec "./$1""#,
+                    "sh",
+                    binary,
+                    script,
+                ]
+                .map(str::to_string)
+                .to_vec()
+            }
+            _ => vec![self.interpreter().to_string(), script.to_string()],
+        }
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Shell => "sh",
+            Self::Python => "python",
+            Self::JavaScript => "javascript",
+            Self::Rust => "rust",
+        })
+    }
+}
+
+/// A fenced code block in a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeBlock {
+    /// The language the code is written in.
+    pub language: Language,
+
+    /// The code, without the fences.
+    pub code: String,
+}
+
+/// Returns the fenced code blocks in `content` that are in a supported
+/// language, in order. Blocks without a language are kept if it can be
+/// [detected](Language::detect), and skipped otherwise, since they're as
+/// likely to be output as code.
+pub fn extract_code_blocks(content: &str) -> Vec<CodeBlock> {
+    fenced_blocks(content)
+        .into_iter()
+        .filter_map(Result::ok)
+        .collect()
+}
+
+/// Returns the fenced code blocks in `content`, in order, with the info
+/// strings of blocks in unsupported languages as errors. Blocks without a
+/// language that can't be detected are skipped.
+fn fenced_blocks(content: &str) -> Vec<Result<CodeBlock, String>> {
+    let mut blocks = Vec::new();
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+        let Some(tag) = line.trim_start().strip_prefix("```") else {
+            continue;
+        };
+        let mut code = String::new();
+        for line in lines.by_ref() {
+            if line.trim_start().starts_with("```") {
+                break;
+            }
+            code.push_str(line);
+            code.push('\n');
+        }
+        let tag = tag.trim();
+        let language = match tag {
+            "" => Language::detect(&code),
+            tag => Language::from_tag(tag),
+        };
+        match language {
+            Some(language) => blocks.push(Ok(CodeBlock { language, code })),
+            None if !tag.is_empty() => blocks.push(Err(tag.to_string())),
+            None => {}
+        }
+    }
+    blocks
+}
+
+/// The outcome of running a code block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Execution {
+    /// The exit code, or None if the process was killed.
+    pub exit_code: Option<i32>,
+
+    /// What the code wrote to stdout, up to the output cap.
+    pub stdout: String,
+
+    /// What the code wrote to stderr, up to the output cap.
+    pub stderr: String,
+
+    /// Whether the code was killed for running too long.
+    pub timed_out: bool,
+
+    /// Whether output past the cap was dropped.
+    pub truncated: bool,
+
+    /// The signal that killed the process, if one did.
+    pub signal: Option<i32>,
+
+    /// The resource limit the code ran into, if it did.
+    pub limit: Option<Limit>,
+}
+
+impl Execution {
+    /// Returns whether the code ran to completion and exited with 0.
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0) && !self.timed_out && self.limit.is_none()
+    }
+}
+
+impl fmt::Display for Execution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.exit_code {
+            _ if self.timed_out => writeln!(f, "exit code: none (timed out)")?,
+            Some(code) => writeln!(f, "exit code: {code}")?,
+            None => writeln!(f, "exit code: none (killed)")?,
+        }
+        match self.limit {
+            Some(Limit::WallTime) | None => {}
+            Some(limit) => writeln!(f, "limit exceeded: {limit}")?,
+        }
+        writeln!(f, "stdout:\n{}", self.stdout.trim_end())?;
+        write!(f, "stderr:\n{}", self.stderr.trim_end())?;
+        if self.truncated {
+            write!(f, "\n(output truncated)")?;
+        }
+        Ok(())
+    }
+}
+
+/// A resource code can run out of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    /// The time the code may run for; see [`LocalExecutor::with_timeout`].
+    WallTime,
+
+    /// [`ResourceLimits::cpu_time`].
+    CpuTime,
+
+    /// [`ResourceLimits::memory`].
+    Memory,
+
+    /// [`ResourceLimits::processes`].
+    Processes,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::WallTime => "wall time",
+            Self::CpuTime => "CPU time",
+            Self::Memory => "memory",
+            Self::Processes => "processes",
+        })
+    }
+}
+
+/// Limits on the resources code may use, so runaway code fails on its own
+/// rather than starving the host. Unset limits aren't enforced. How long
+/// code may run is limited by the executor's timeout.
+///
+/// On the host the limits are rlimits, so they're per process, and the
+/// process limit is per user and not enforced for root. In containers,
+/// they're the container's limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// The CPU time the code may use, in whole seconds.
+    pub cpu_time: Option<Duration>,
+
+    /// The memory the code may use, in bytes. On the host this limits
+    /// address space, of which some runtimes, e.g. Node.js, reserve far more
+    /// than they use.
+    pub memory: Option<u64>,
+
+    /// The number of processes the code may run at once.
+    pub processes: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Create limits that limit nothing.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Let code use at most `cpu_time` of CPU time, rounded up to seconds.
+    pub fn with_cpu_time(mut self, cpu_time: Duration) -> Self {
+        self.cpu_time = Some(cpu_time);
+        self
+    }
+
+    /// Let code use at most `memory` bytes of memory.
+    pub fn with_memory(mut self, memory: u64) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Let code run at most `processes` processes at once.
+    pub fn with_processes(mut self, processes: u64) -> Self {
+        self.processes = Some(processes);
+        self
+    }
+
+    /// Returns the CPU time limit in whole seconds, rounded up.
+    pub(crate) fn cpu_seconds(&self) -> Option<u64> {
+        self.cpu_time
+            .map(|cpu_time| cpu_time.as_secs() + u64::from(cpu_time.subsec_nanos() > 0))
+            .map(|seconds| seconds.max(1))
+    }
+
+    /// Makes `command` set the limits on its process before the code starts.
+    #[cfg(unix)]
+    fn apply(&self, command: &mut Command) {
+        if *self == Self::default() {
+            return;
+        }
+        let cpu = self.cpu_seconds().map(|seconds| (seconds, seconds + 1));
+        let memory = self.memory.map(|memory| (memory, memory));
+        let processes = self.processes.map(|processes| (processes, processes));
+        let limits = [
+            // past the soft limit, the process gets SIGXCPU; past the hard
+            // limit, SIGKILL
+            (libc::RLIMIT_CPU, cpu),
+            (libc::RLIMIT_AS, memory),
+            (libc::RLIMIT_NPROC, processes),
+        ];
+        // SAFETY: the closure only calls setrlimit, which is async-signal-safe
+        unsafe {
+            command.pre_exec(move || {
+                for (resource, limit) in limits {
+                    let Some((soft, hard)) = limit else {
+                        continue;
+                    };
+                    let limit = libc::rlimit {
+                        rlim_cur: soft as libc::rlim_t,
+                        rlim_max: hard as libc::rlim_t,
+                    };
+                    if libc::setrlimit(resource, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Records in `execution` which limit it ran into, if any, judging by
+    /// how the code ended and what it wrote to stderr.
+    pub(crate) fn classify(&self, execution: &mut Execution) {
+        if execution.timed_out {
+            execution.limit = Some(Limit::WallTime);
+            return;
+        }
+        // shells and container runtimes exit with 128 plus the signal that
+        // killed the code
+        let signal = execution.signal.or(execution
+            .exit_code
+            .filter(|code| *code > 128)
+            .map(|code| code - 128));
+        let killed = signal == Some(libc::SIGKILL);
+        let stderr = &execution.stderr;
+        execution.limit = if self.cpu_time.is_some() && (signal == Some(libc::SIGXCPU) || killed) {
+            Some(Limit::CpuTime)
+        } else if self.memory.is_some()
+            && (killed
+                || [
+                    "MemoryError",
+                    "Cannot allocate memory",
+                    "out of memory",
+                    "bad_alloc",
+                    "memory allocation of",
+                ]
+                .iter()
+                .any(|pattern| stderr.contains(pattern)))
+        {
+            Some(Limit::Memory)
+        } else if self.processes.is_some() && stderr.contains("Resource temporarily unavailable") {
+            Some(Limit::Processes)
+        } else {
+            None
+        };
+    }
+}
+
+/// Runs code blocks.
+pub trait CodeExecutor: Debug + Send + Sync + 'static {
+    /// Run `block` and return its outcome. Code that fails or times out is
+    /// reported in the [`Execution`]; an error means it couldn't be run, e.g.
+    /// [`Error::UnsupportedLanguage`].
+    fn execute<'a>(&'a self, block: &'a CodeBlock) -> ExecuteFuture<'a>;
+}
+
+impl<E: CodeExecutor + ?Sized> CodeExecutor for Arc<E> {
+    fn execute<'a>(&'a self, block: &'a CodeBlock) -> ExecuteFuture<'a> {
+        (**self).execute(block)
+    }
+}
+
+/// Runs each code block with the executor for its language, e.g. Python in
+/// a container and shell scripts on the host.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::code_executor::{docker::{DockerCodeExecutor, DockerOptions}, Language, LanguageRouter, LocalExecutor};
+/// let router = LanguageRouter::new()
+///     .with_executor(Language::Python, DockerCodeExecutor::new("scratch", DockerOptions::default()))
+///     .with_executor(Language::Shell, LocalExecutor::new("scratch"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LanguageRouter {
+    executors: HashMap<Language, Arc<dyn CodeExecutor>>,
+    fallback: Option<Arc<dyn CodeExecutor>>,
+}
+
+impl LanguageRouter {
+    /// Create a router that runs no languages.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Run code in `language` with `executor`.
+    pub fn with_executor(mut self, language: Language, executor: impl CodeExecutor) -> Self {
+        self.executors.insert(language, Arc::new(executor));
+        self
+    }
+
+    /// Run code in languages without an executor of their own with
+    /// `executor`, rather than reporting them as unsupported.
+    pub fn with_fallback(mut self, executor: impl CodeExecutor) -> Self {
+        self.fallback = Some(Arc::new(executor));
+        self
+    }
+
+    /// Returns whether code in `language` can be run.
+    pub fn supports(&self, language: Language) -> bool {
+        self.fallback.is_some() || self.executors.contains_key(&language)
+    }
+}
+
+impl CodeExecutor for LanguageRouter {
+    fn execute<'a>(&'a self, block: &'a CodeBlock) -> ExecuteFuture<'a> {
+        match self
+            .executors
+            .get(&block.language)
+            .or(self.fallback.as_ref())
+        {
+            Some(executor) => executor.execute(block),
+            None => Box::pin(async move { Err(Error::UnsupportedLanguage(block.language)) }),
+        }
+    }
+}
+
+/// Runs code only while the [`CODE_EXECUTION`] flag is on.
+#[derive(Debug)]
+struct Flagged {
+    executor: Arc<dyn CodeExecutor>,
+    flags: AgentFlags,
+}
+
+impl CodeExecutor for Flagged {
+    fn execute<'a>(&'a self, block: &'a CodeBlock) -> ExecuteFuture<'a> {
+        match self.flags.is_enabled(CODE_EXECUTION) {
+            true => self.executor.execute(block),
+            false => Box::pin(async { Err(Error::Disabled) }),
+        }
+    }
+}
+
+/// Runs code in a subprocess on the host.
+///
+/// The process starts in the working directory, which the script is written
+/// to, with a clean environment whose `HOME` and `TMPDIR` point at it. It's
+/// killed once the timeout passes. Each language's interpreter, or `rustc`
+/// for Rust, must be installed on the host.
+#[derive(Debug, Clone)]
+pub struct LocalExecutor {
+    work_dir: PathBuf,
+    timeout: Duration,
+    max_output: usize,
+    envs: Vec<(String, OsString)>,
+    limits: ResourceLimits,
+}
+
+impl LocalExecutor {
+    /// Create an executor that runs code in `work_dir`, which is created if
+    /// it doesn't exist.
+    pub fn new(work_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            work_dir: work_dir.into(),
+            timeout: DEFAULT_TIMEOUT,
+            max_output: DEFAULT_MAX_OUTPUT,
+            envs: Vec::new(),
+            limits: ResourceLimits::default(),
+        }
+    }
+
+    /// Kill code that runs longer than `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Keep at most `max_output` bytes of each output stream.
+    pub fn with_max_output(mut self, max_output: usize) -> Self {
+        self.max_output = max_output;
+        self
+    }
+
+    /// Set the environment variable `key` to `value` for the code, e.g. to
+    /// put a virtualenv's programs first on the `PATH`.
+    pub fn with_env(mut self, key: impl ToString, value: impl Into<OsString>) -> Self {
+        self.envs.push((key.to_string(), value.into()));
+        self
+    }
+
+    /// Hold code to `limits`. Code that exceeds one fails with
+    /// [`Execution::limit`] set.
+    pub fn with_limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Returns the directory code is run in.
+    pub fn work_dir(&self) -> &Path {
+        &self.work_dir
+    }
+
+    /// Returns how long code may run.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Returns how much of each output stream is kept, in bytes.
+    pub fn max_output(&self) -> usize {
+        self.max_output
+    }
+
+    /// Returns the limits code is held to.
+    pub fn limits(&self) -> ResourceLimits {
+        self.limits
+    }
+}
+
+impl CodeExecutor for LocalExecutor {
+    fn execute<'a>(&'a self, block: &'a CodeBlock) -> ExecuteFuture<'a> {
+        Box::pin(async move {
+            tokio::fs::create_dir_all(&self.work_dir).await?;
+            let work_dir = tokio::fs::canonicalize(&self.work_dir).await?;
+            let id = Uuid::new_v4().to_string();
+            let script = format!("{id}.{}", block.language.extension());
+            tokio::fs::write(work_dir.join(&script), &block.code).await?;
+
+            let args = block.language.command(&script);
+            let mut command = Command::new(&args[0]);
+            command
+                .args(&args[1..])
+                .current_dir(&work_dir)
+                .env_clear()
+                .env("HOME", &work_dir)
+                .env("TMPDIR", &work_dir);
+            if let Some(path) = std::env::var_os("PATH") {
+                command.env("PATH", path);
+            }
+            command.envs(self.envs.iter().map(|(key, value)| (key, value)));
+            if block.language == Language::Rust {
+                // rustup finds toolchains through these, relative to the
+                // real home directory by default
+                let home = std::env::var_os("HOME").map(PathBuf::from);
+                for (var, default) in [("RUSTUP_HOME", ".rustup"), ("CARGO_HOME", ".cargo")] {
+                    let dir = std::env::var_os(var)
+                        .map(PathBuf::from)
+                        .or_else(|| home.as_ref().map(|home| home.join(default)));
+                    if let Some(dir) = dir {
+                        command.env(var, dir);
+                    }
+                }
+            }
+            #[cfg(unix)]
+            self.limits.apply(&mut command);
+            let mut execution = run(command, self.timeout, self.max_output).await;
+            if let Ok(execution) = &mut execution {
+                self.limits.classify(execution);
+            }
+            let _ = tokio::fs::remove_file(work_dir.join(&script)).await;
+            if block.language == Language::Rust {
+                let _ = tokio::fs::remove_file(work_dir.join(&id)).await;
+            }
+            execution
+        })
+    }
+}
+
+/// Run `command`, killing it after `timeout` and keeping at most
+/// `max_output` bytes of each output stream. Executors that run code through
+/// another program, e.g. a container runtime, share this.
+///
+/// The code runs in its own process group, which is killed once the code
+/// exits or times out, so processes it left in the background neither keep
+/// running nor hold its output open.
+pub(crate) async fn run(
+    mut command: Command,
+    timeout: Duration,
+    max_output: usize,
+) -> Result<Execution, Error> {
+    #[cfg(unix)]
+    command.process_group(0);
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let group = child.id();
+    let (stdout_pipe, stderr_pipe) = (child.stdout.take(), child.stderr.take());
+    let mut stdout = Capped::new(max_output);
+    let mut stderr = Capped::new(max_output);
+    let status = {
+        let output = async { tokio::join!(stdout.read(stdout_pipe), stderr.read(stderr_pipe)) };
+        tokio::pin!(output);
+        // read while waiting, so the code doesn't block on a full pipe
+        let mut read = false;
+        let status = tokio::time::timeout(timeout, async {
+            loop {
+                tokio::select! {
+                    status = child.wait() => break status,
+                    _ = &mut output, if !read => read = true,
+                }
+            }
+        })
+        .await;
+        kill_group(&mut child, group).await?;
+        if !read {
+            // the pipes close once the group is gone, unless a process left it
+            let _ = tokio::time::timeout(OUTPUT_GRACE, &mut output).await;
+        }
+        status
+    };
+    let (stdout, stderr) = (stdout.finish(), stderr.finish());
+    let truncated = stdout.1 || stderr.1;
+
+    let status = match status {
+        Ok(status) => status?,
+        Err(_) => {
+            tracing::debug!(?timeout, "code timed out");
+            return Ok(Execution {
+                stdout: stdout.0,
+                stderr: stderr.0,
+                timed_out: true,
+                truncated,
+                limit: Some(Limit::WallTime),
+                ..Default::default()
+            });
+        }
+    };
+    #[cfg(unix)]
+    let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+    #[cfg(not(unix))]
+    let signal = None;
+    Ok(Execution {
+        exit_code: status.code(),
+        stdout: stdout.0,
+        stderr: stderr.0,
+        timed_out: false,
+        truncated,
+        signal,
+        limit: None,
+    })
+}
+
+/// Kills what's left of the process group led by `child`, `group`, and
+/// the child itself if it's still running.
+async fn kill_group(child: &mut Child, group: Option<u32>) -> Result<(), Error> {
+    #[cfg(unix)]
+    if let Some(group) = group {
+        // SAFETY: killpg only sends a signal
+        unsafe { libc::killpg(group as libc::pid_t, libc::SIGKILL) };
+    }
+    #[cfg(not(unix))]
+    let _ = group;
+    if child.try_wait()?.is_none() {
+        child.kill().await?;
+    }
+    Ok(())
+}
+
+/// Output read from a stream, keeping at most `max` bytes. The rest is read
+/// and dropped so the process doesn't block on a full pipe.
+struct Capped {
+    kept: Vec<u8>,
+    max: usize,
+    truncated: bool,
+}
+
+impl Capped {
+    fn new(max: usize) -> Self {
+        Self {
+            kept: Vec::new(),
+            max,
+            truncated: false,
+        }
+    }
+
+    /// Read `reader` to the end.
+    async fn read(&mut self, reader: Option<impl AsyncRead + Unpin>) {
+        let Some(mut reader) = reader else {
+            return;
+        };
+        let mut buffer = [0; 8192];
+        while let Ok(read @ 1..) = reader.read(&mut buffer).await {
+            let room = self.max.saturating_sub(self.kept.len());
+            self.kept.extend_from_slice(&buffer[..read.min(room)]);
+            self.truncated |= read > room;
+        }
+    }
+
+    /// Returns the text read and whether any was dropped.
+    fn finish(self) -> (String, bool) {
+        (String::from_utf8_lossy(&self.kept).into_owned(), self.truncated)
+    }
+}
+
+/// Returns the note a code block in an unsupported language is answered
+/// with.
+fn unsupported(language: &str) -> String {
+    let supported = Language::ALL.map(|language| language.to_string());
+    format!(
+        "unsupported language: {language}. Code blocks can be in {}.",
+        supported.join(", ")
+    )
+}
+
+/// Formats the reply to a message with the outcome of each of its code
+/// blocks.
+fn reply(outcomes: &[String]) -> String {
+    match outcomes {
+        [] => "No code blocks to run. Put code in a fenced block tagged with \
+            its language, e.g. ```python or ```sh."
+            .to_string(),
+        [outcome] => outcome.clone(),
+        outcomes => outcomes
+            .iter()
+            .enumerate()
+            .map(|(index, outcome)| format!("code block {}:\n{outcome}", index + 1))
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+    }
+}
+
+/// An agent that runs the code in the messages it receives and replies with
+/// the outcome, the counterpart to autogen's code executing user proxy.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::code_executor::CodeExecutorAgentBuilder;
+/// # tokio_test::block_on(async {
+/// let executor = CodeExecutorAgentBuilder::new()
+///     .with_name("executor")
+///     .with_work_dir("scratch")
+///     .build();
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct CodeExecutorAgent {
+    pub agent: Agent<Box<Message>, Error>,
+}
+
+impl CodeExecutorAgent {
+    /// Create a new agent that runs code with `executor`. Code blocks in a
+    /// message are run one after another; blocks in languages that aren't
+    /// supported, or that `executor` can't run, are answered with a note.
+    pub fn spawn(id: Uuid, name: Option<String>, executor: Arc<dyn CodeExecutor>) -> Self {
+        let agent = Agent::<Box<Message>, _>::spawn(id, name, move |sender, message| {
+            let executor = executor.clone();
+            async move {
+                let blocks = fenced_blocks(&message.content.to_string());
+                let mut outcomes = Vec::with_capacity(blocks.len());
+                for (index, block) in blocks.iter().enumerate() {
+                    let block = match block {
+                        Ok(block) => block,
+                        Err(tag) => {
+                            outcomes.push(unsupported(tag));
+                            continue;
+                        }
+                    };
+                    message.report_progress(
+                        "code_executor",
+                        Some((index * 100 / blocks.len()) as u8),
+                        format!("running code block {} of {}", index + 1, blocks.len()),
+                    );
+                    outcomes.push(match executor.execute(block).await {
+                        Ok(execution) => execution.to_string(),
+                        Err(e @ Error::UnsupportedLanguage(_)) => {
+                            format!("{e}. No executor is configured to run it.")
+                        }
+                        Err(e @ Error::Disabled) => format!("{e}; the code was not run."),
+                        Err(e) => return Err(e),
+                    });
+                }
+
+                message
+                    .sender
+                    .send(Box::new(Message::new(sender, reply(&outcomes))))
+                    .await?;
+                Ok(())
+            }
+        });
+        Self { agent }
+    }
+
+    /// Returns a sender that can be used to send messages to the agent.
+    pub fn sender(&self) -> Sender<Box<Message>> {
+        self.agent.sender()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CodeExecutorAgentBuilder {
+    /// Unique identifier for the agent.
+    pub id: Option<Uuid>,
+
+    /// A user-friendly name for the agent.
+    pub name: Option<String>,
+
+    /// The directory code is run in. Defaults to a directory named after the
+    /// agent in the system's temporary directory.
+    pub work_dir: Option<PathBuf>,
+
+    /// How long code may run. Defaults to [`DEFAULT_TIMEOUT`].
+    pub timeout: Option<Duration>,
+
+    /// How much of each output stream is kept. Defaults to
+    /// [`DEFAULT_MAX_OUTPUT`].
+    pub max_output: Option<usize>,
+
+    /// The resources code may use. Defaults to no limits.
+    pub limits: Option<ResourceLimits>,
+
+    /// Runs code in Docker containers instead of on the host, if set.
+    pub docker: Option<DockerOptions>,
+
+    /// The packages the model may install in a persistent Python
+    /// virtualenv, if code runs in one.
+    pub virtualenv: Option<Vec<String>>,
+
+    /// Runs the code instead of the built-in executors, if set.
+    pub executor: Option<Arc<dyn CodeExecutor>>,
+
+    /// Runs code in particular languages instead of the other executors.
+    pub language_executors: HashMap<Language, Arc<dyn CodeExecutor>>,
+
+    /// Tracks containers and the default working directory, so they're
+    /// removed when the work they're for is aborted.
+    pub resources: Option<Resources>,
+
+    /// Turns running code on and off at runtime.
+    pub flags: Option<AgentFlags>,
+}
+
+impl CodeExecutorAgentBuilder {
+    /// Create a new agent builder.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the id of the agent.
+    pub fn with_id(mut self, id: Uuid) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Set the name of the agent.
+    pub fn with_name(mut self, name: impl ToString) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Run code in `work_dir`.
+    pub fn with_work_dir(mut self, work_dir: impl Into<PathBuf>) -> Self {
+        self.work_dir = Some(work_dir.into());
+        self
+    }
+
+    /// Kill code that runs longer than `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Keep at most `max_output` bytes of each output stream.
+    pub fn with_max_output(mut self, max_output: usize) -> Self {
+        self.max_output = Some(max_output);
+        self
+    }
+
+    /// Run code in Docker containers with `options` instead of on the host.
+    /// The working directory is mounted in each container.
+    pub fn with_docker(mut self, options: DockerOptions) -> Self {
+        self.docker = Some(options);
+        self
+    }
+
+    /// Hold code to `limits`, e.g. so code that loops forever or allocates
+    /// without bound fails instead of starving the host.
+    pub fn with_limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Run code on the host with a Python virtualenv that persists across
+    /// messages, letting the model `pip install` any of `packages`. Ignored
+    /// when code runs in Docker.
+    pub fn with_virtualenv<I, S>(mut self, packages: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        self.virtualenv = Some(packages.into_iter().map(|p| p.to_string()).collect());
+        self
+    }
+
+    /// Run code with `executor`. The working directory, timeout, output cap,
+    /// limits, Docker and virtualenv options only apply to the built-in
+    /// executors.
+    pub fn with_executor(mut self, executor: impl CodeExecutor) -> Self {
+        self.executor = Some(Arc::new(executor));
+        self
+    }
+
+    /// Run code in `language` with `executor`, and code in other languages
+    /// as configured otherwise.
+    pub fn with_language_executor(
+        mut self,
+        language: Language,
+        executor: impl CodeExecutor,
+    ) -> Self {
+        self.language_executors.insert(language, Arc::new(executor));
+        self
+    }
+
+    /// Track containers, and the working directory if it's the default
+    /// temporary one, in `resources`, removing them when the resources are
+    /// cancelled.
+    pub fn with_resources(mut self, resources: Resources) -> Self {
+        self.resources = Some(resources);
+        self
+    }
+
+    /// Run code only while the [`CODE_EXECUTION`] flag in `flags` is on,
+    /// answering with a note otherwise.
+    pub fn with_flags(mut self, flags: AgentFlags) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Builds the agent.
+    pub fn build(self) -> CodeExecutorAgent {
+        let id = self.id.unwrap_or_else(Uuid::new_v4);
+        let executor = self.executor.unwrap_or_else(|| {
+            let work_dir = self.work_dir.unwrap_or_else(|| {
+                let work_dir = std::env::temp_dir().join(format!("autogen-rs-{id}"));
+                if let Some(resources) = &self.resources {
+                    resources.track_dir(&work_dir).keep();
+                }
+                work_dir
+            });
+            let timeout = self.timeout.unwrap_or(DEFAULT_TIMEOUT);
+            let max_output = self.max_output.unwrap_or(DEFAULT_MAX_OUTPUT);
+            let limits = self.limits.unwrap_or_default();
+            match (self.docker, self.virtualenv) {
+                (Some(options), _) => {
+                    let docker = DockerCodeExecutor::new(work_dir, options)
+                        .with_timeout(timeout)
+                        .with_max_output(max_output)
+                        .with_limits(limits);
+                    match self.resources {
+                        Some(resources) => Arc::new(docker.with_resources(resources)),
+                        None => Arc::new(docker),
+                    }
+                }
+                (None, virtualenv) => {
+                    let local = LocalExecutor::new(work_dir)
+                        .with_timeout(timeout)
+                        .with_max_output(max_output)
+                        .with_limits(limits);
+                    match virtualenv {
+                        Some(packages) => Arc::new(
+                            VenvExecutor::with_local(local).with_allowed_packages(packages),
+                        ),
+                        None => Arc::new(local),
+                    }
+                }
+            }
+        });
+        let executor = match self.language_executors.is_empty() {
+            true => executor,
+            false => Arc::new(LanguageRouter {
+                executors: self.language_executors,
+                fallback: Some(executor),
+            }),
+        };
+        let executor = match self.flags {
+            Some(flags) => Arc::new(Flagged { executor, flags }),
+            None => executor,
+        };
+        CodeExecutorAgent::spawn(id, self.name, executor)
+    }
+}
+
+impl Actor for CodeExecutorAgent {
+    type Error = agent::SendError<Box<Message>>;
+    type Message = Message;
+
+    fn id(&self) -> Uuid {
+        self.agent.id
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.agent.name.as_deref()
+    }
+
+    fn sender(&self) -> Sender<Box<Message>> {
+        self.agent.sender()
+    }
+
+    async fn terminate(self) -> Shutdown {
+        self.agent.terminate().await
+    }
+
+    fn abort(self) {
+        self.agent.abort();
+    }
+
+    async fn send(&self, message: Self::Message) -> Result<(), Self::Error> {
+        self.agent.send(Box::new(message)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::agent::StreamEvent, anyhow::Result};
+
+    fn shell(code: &str) -> CodeBlock {
+        CodeBlock {
+            language: Language::Shell,
+            code: code.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_extract_code_blocks() {
+        let content = "Run this:\n```python\nprint('hi')\n```\nthen\n```\noutput\n```\n\
+            ```bash\necho hi\nls\n```";
+        assert_eq!(
+            extract_code_blocks(content),
+            vec![
+                CodeBlock {
+                    language: Language::Python,
+                    code: "print('hi')\n".to_string(),
+                },
+                shell("echo hi\nls\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_detect_language() {
+        assert_eq!(
+            Language::detect("#!/usr/bin/env python3\nprint(1)"),
+            Some(Language::Python)
+        );
+        assert_eq!(Language::detect("#!/bin/bash\nls"), Some(Language::Shell));
+        assert_eq!(
+            Language::detect("use std::fs;\n\nfn main() {}"),
+            Some(Language::Rust)
+        );
+        assert_eq!(
+            Language::detect("const x = 1;\nconsole.log(x);"),
+            Some(Language::JavaScript)
+        );
+        assert_eq!(
+            Language::detect("import os\nprint(os.getcwd())"),
+            Some(Language::Python)
+        );
+        assert_eq!(Language::detect("total 0\n-rw-r--r-- a.txt"), None);
+    }
+
+    #[tokio::test]
+    async fn test_languages() -> Result<()> {
+        let work_dir = std::env::temp_dir().join(format!("autogen-rs-test-{}", Uuid::new_v4()));
+        let executor = LocalExecutor::new(&work_dir).with_timeout(Duration::from_secs(30));
+
+        for (language, code) in [
+            (Language::JavaScript, "console.log(6 * 7);"),
+            (Language::Rust, "fn main() { println!(\"{}\", 6 * 7); }"),
+        ] {
+            let block = CodeBlock {
+                language,
+                code: code.to_string(),
+            };
+            let execution = executor.execute(&block).await?;
+            assert_eq!(execution.stdout, "42\n", "{language}: {execution}");
+        }
+        // the compiled program is removed along with its source
+        assert_eq!(std::fs::read_dir(&work_dir)?.count(), 0);
+
+        tokio::fs::remove_dir_all(work_dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_executor() -> Result<()> {
+        let work_dir = std::env::temp_dir().join(format!("autogen-rs-test-{}", Uuid::new_v4()));
+        let executor = LocalExecutor::new(&work_dir)
+            .with_timeout(Duration::from_millis(500))
+            .with_max_output(5);
+
+        let execution = executor
+            .execute(&shell("echo hello world\necho oops >&2\nexit 3"))
+            .await?;
+        assert_eq!(execution.exit_code, Some(3));
+        assert_eq!(execution.stdout, "hello");
+        assert_eq!(execution.stderr, "oops\n");
+        assert!(execution.truncated);
+
+        let execution = executor.execute(&shell("sleep 10")).await?;
+        assert!(execution.timed_out);
+        assert_eq!(execution.limit, Some(Limit::WallTime));
+        assert!(!execution.succeeded());
+
+        tokio::fs::remove_dir_all(work_dir).await?;
+        Ok(())
+    }
+
+    /// Returns whether the process with `pid` is running, as opposed to
+    /// gone or waiting to be reaped.
+    #[cfg(target_os = "linux")]
+    fn running(pid: &str) -> bool {
+        std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()))
+            .map_or(false, |stat| !stat.contains(") Z "))
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_kills_background_processes() -> Result<()> {
+        let work_dir = std::env::temp_dir().join(format!("autogen-rs-test-{}", Uuid::new_v4()));
+        let executor = LocalExecutor::new(&work_dir).with_timeout(Duration::from_secs(5));
+
+        // the code finishes even though a process it started holds its output
+        let started = std::time::Instant::now();
+        let execution = executor
+            .execute(&shell("sleep 30 &\necho $!"))
+            .await?;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(execution.succeeded(), "{execution}");
+        assert!(!running(&execution.stdout));
+
+        // code that times out keeps its output, and its processes are killed
+        let executor = executor.with_timeout(Duration::from_millis(500));
+        let execution = executor
+            .execute(&shell("sleep 30 &\necho $!\nsleep 10"))
+            .await?;
+        assert!(execution.timed_out);
+        assert!(!execution.stdout.is_empty());
+        assert!(!running(&execution.stdout));
+
+        tokio::fs::remove_dir_all(work_dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resource_limits() -> Result<()> {
+        let work_dir = std::env::temp_dir().join(format!("autogen-rs-test-{}", Uuid::new_v4()));
+        let limits = ResourceLimits::new()
+            .with_cpu_time(Duration::from_secs(1))
+            .with_memory(256 * 1024 * 1024);
+        let executor = LocalExecutor::new(&work_dir)
+            .with_timeout(Duration::from_secs(10))
+            .with_limits(limits);
+        let python = |code: &str| CodeBlock {
+            language: Language::Python,
+            code: code.to_string(),
+        };
+
+        let execution = executor
+            .execute(&python("print(len(bytearray(10)))"))
+            .await?;
+        assert!(execution.succeeded(), "{execution}");
+
+        let execution = executor
+            .execute(&python("data = bytearray(512 * 1024 * 1024)"))
+            .await?;
+        assert_eq!(execution.limit, Some(Limit::Memory), "{execution}");
+        assert!(execution.to_string().contains("limit exceeded: memory"));
+
+        let execution = executor.execute(&python("while True: pass")).await?;
+        assert_eq!(execution.limit, Some(Limit::CpuTime), "{execution}");
+        assert!(!execution.timed_out);
+
+        tokio::fs::remove_dir_all(work_dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replies_with_output() -> Result<()> {
+        let work_dir = std::env::temp_dir().join(format!("autogen-rs-test-{}", Uuid::new_v4()));
+        let agent = CodeExecutorAgentBuilder::new()
+            .with_work_dir(&work_dir)
+            .build();
+
+        let mut reply = agent
+            .sender()
+            .ask_stream("```python\nprint(6 * 7)\n```")
+            .await?;
+        let mut content = None;
+        while let Some(event) = reply.next().await {
+            if let StreamEvent::Complete(complete) = event {
+                content = Some(complete);
+            }
+        }
+        assert_eq!(
+            content.as_deref(),
+            Some("exit code: 0\nstdout:\n42\nstderr:\n")
+        );
+        agent.terminate().await;
+        tokio::fs::remove_dir_all(work_dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_language() -> Result<()> {
+        let work_dir = std::env::temp_dir().join(format!("autogen-rs-test-{}", Uuid::new_v4()));
+        let agent = CodeExecutorAgentBuilder::new()
+            .with_executor(
+                LanguageRouter::new().with_executor(Language::Shell, LocalExecutor::new(&work_dir)),
+            )
+            .build();
+        let (inbox, mut replies) = crate::agent::channel(None);
+
+        agent
+            .send(Message::new(
+                inbox,
+                "```sh\necho hi\n```\n```python\nprint(1)\n```\n```cobol\nDISPLAY 'HI'.\n```"
+                    .to_string(),
+            ))
+            .await?;
+        let reply = replies.recv().await.map(|reply| reply.content.to_string());
+        let supported = "Code blocks can be in python, sh, javascript, rust.";
+        assert_eq!(
+            reply.unwrap_or_default(),
+            format!(
+                "code block 1:\nexit code: 0\nstdout:\nhi\nstderr:\n\n\n\
+                code block 2:\nunsupported language: python. No executor is configured to run it.\n\n\
+                code block 3:\nunsupported language: cobol. {supported}"
+            )
+        );
+        agent.terminate().await;
+        tokio::fs::remove_dir_all(work_dir).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+//! Limiting where executed code can connect to. Code runs with no network by
+//! default; an [`Allowlist`] lets it reach particular hosts through an
+//! [`EgressProxy`], an HTTP proxy that refuses every other host, so code
+//! can fetch from a package index or an API without being able to send data
+//! anywhere else.
+
+use {
+    std::{fmt, io, net::SocketAddr, sync::Arc},
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream, ToSocketAddrs},
+        task::JoinHandle,
+    },
+};
+
+/// The longest request head the proxy reads, in bytes.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// The hosts code may connect to.
+///
+/// Hosts are matched by name, ignoring case and port. A pattern that starts
+/// with `*.` matches the subdomains of the rest, e.g. `*.pythonhosted.org`
+/// matches `files.pythonhosted.org`.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::code_executor::network::Allowlist;
+/// let allowlist = Allowlist::new(["pypi.org", "*.pythonhosted.org"]);
+/// assert!(allowlist.allows("files.pythonhosted.org"));
+/// assert!(!allowlist.allows("example.com"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Allowlist {
+    hosts: Vec<String>,
+}
+
+impl Allowlist {
+    /// Create an allowlist of `hosts`.
+    pub fn new<I, S>(hosts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        Self {
+            hosts: hosts
+                .into_iter()
+                .map(|host| host.to_string().to_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Returns whether the allowlist is empty, allowing no host.
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    /// Returns the allowed hosts.
+    pub fn hosts(&self) -> &[String] {
+        &self.hosts
+    }
+
+    /// Returns whether code may connect to `host`.
+    pub fn allows(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_lowercase();
+        self.hosts
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|subdomain| subdomain.ends_with('.')),
+                None => *pattern == host,
+            })
+    }
+}
+
+impl fmt::Display for Allowlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.hosts.join(", "))
+    }
+}
+
+/// An HTTP proxy that only connects to allowed hosts. It tunnels HTTPS with
+/// `CONNECT` and forwards plain HTTP; requests for other hosts get a 403.
+/// The proxy stops when it's dropped.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::code_executor::network::{Allowlist, EgressProxy};
+/// # tokio_test::block_on(async {
+/// let proxy = EgressProxy::start("127.0.0.1:0", Allowlist::new(["pypi.org"])).await?;
+/// // e.g. HTTPS_PROXY=http://127.0.0.1:<port>
+/// let url = proxy.url();
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct EgressProxy {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl EgressProxy {
+    /// Start a proxy on `addr` that only connects to hosts in `allowlist`.
+    pub async fn start(addr: impl ToSocketAddrs, allowlist: Allowlist) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let allowlist = Arc::new(allowlist);
+        let task = tokio::spawn(async move {
+            loop {
+                let (client, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "egress proxy unable to accept connection");
+                        continue;
+                    }
+                };
+                let allowlist = allowlist.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = proxy(client, &allowlist).await {
+                        tracing::debug!(%peer, error = %e, "egress proxy connection failed");
+                    }
+                });
+            }
+        });
+        Ok(Self { addr, task })
+    }
+
+    /// Returns the address the proxy listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the proxy's URL, for `HTTP_PROXY` and `HTTPS_PROXY`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+}
+
+impl Drop for EgressProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Serve one proxied connection.
+async fn proxy(mut client: TcpStream, allowlist: &Allowlist) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    let head_end = loop {
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if buffer.len() > MAX_HEAD {
+            return reply(&mut client, "431 Request Header Fields Too Large", "").await;
+        }
+        let mut chunk = [0; 4096];
+        let read = client.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+    let head = String::from_utf8_lossy(&buffer[..head_end]).into_owned();
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let (method, target, version) = (
+        request_line.next().unwrap_or_default(),
+        request_line.next().unwrap_or_default(),
+        request_line.next().unwrap_or_default(),
+    );
+
+    let tunnel = method.eq_ignore_ascii_case("CONNECT");
+    let (authority, path) = match tunnel {
+        true => (target, ""),
+        false => match target.strip_prefix("http://") {
+            Some(rest) => rest.split_at(rest.find('/').unwrap_or(rest.len())),
+            None => return reply(&mut client, "400 Bad Request", "not a proxy request").await,
+        },
+    };
+    let (host, port) = split_authority(authority, if tunnel { 443 } else { 80 });
+    if !allowlist.allows(host) {
+        tracing::debug!(host, "egress proxy refused connection");
+        let body = format!("blocked by network policy: {host} isn't an allowed host");
+        return reply(&mut client, "403 Forbidden", &body).await;
+    }
+    let mut upstream = match TcpStream::connect((host, port)).await {
+        Ok(upstream) => upstream,
+        Err(e) => return reply(&mut client, "502 Bad Gateway", &e.to_string()).await,
+    };
+
+    if tunnel {
+        client
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+    } else {
+        // origin servers expect the path only
+        let path = if path.is_empty() { "/" } else { path };
+        let rest = head.split_once("\r\n").map_or("\r\n", |(_, rest)| rest);
+        upstream
+            .write_all(format!("{method} {path} {version}\r\n{rest}").as_bytes())
+            .await?;
+    }
+    upstream.write_all(&buffer[head_end..]).await?;
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+/// Splits `authority` into its host and port, defaulting to `default_port`.
+fn split_authority(authority: &str, default_port: u16) -> (&str, u16) {
+    // bracketed IPv6 addresses have colons of their own
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').unwrap_or((rest, ""));
+        let port = rest.strip_prefix(':').and_then(|port| port.parse().ok());
+        return (host, port.unwrap_or(default_port));
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => (host, port),
+            Err(_) => (authority, default_port),
+        },
+        None => (authority, default_port),
+    }
+}
+
+/// Reply to the client with `status` and close the connection.
+async fn reply(client: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    client.write_all(response.as_bytes()).await?;
+    client.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, anyhow::Result};
+
+    #[test]
+    fn test_allowlist() {
+        let allowlist = Allowlist::new(["PyPI.org", "*.pythonhosted.org"]);
+        assert!(allowlist.allows("pypi.org"));
+        assert!(allowlist.allows("pypi.org."));
+        assert!(allowlist.allows("files.pythonhosted.org"));
+        assert!(!allowlist.allows("pythonhosted.org"));
+        assert!(!allowlist.allows("evilpythonhosted.org"));
+        assert!(!allowlist.allows("pypi.org.evil.com"));
+        assert!(!Allowlist::default().allows("pypi.org"));
+
+        assert_eq!(split_authority("pypi.org:8443", 443), ("pypi.org", 8443));
+        assert_eq!(split_authority("pypi.org", 80), ("pypi.org", 80));
+        assert_eq!(split_authority("[::1]:8080", 80), ("::1", 8080));
+    }
+
+    #[tokio::test]
+    async fn test_proxy() -> Result<()> {
+        // an origin server that replies with the request line it got
+        let origin = TcpListener::bind("127.0.0.1:0").await?;
+        let origin_port = origin.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = origin.accept().await {
+                let mut request = vec![0; 1024];
+                let read = stream.read(&mut request).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..read]).into_owned();
+                let line = request.lines().next().unwrap_or_default().to_string();
+                let _ = stream.write_all(line.as_bytes()).await;
+            }
+        });
+
+        let proxy = EgressProxy::start("127.0.0.1:0", Allowlist::new(["localhost"])).await?;
+        let addr = proxy.local_addr();
+        let send = |request: String| async move {
+            let mut stream = TcpStream::connect(addr).await?;
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            anyhow::Ok(response)
+        };
+
+        let response = send(format!(
+            "GET http://localhost:{origin_port}/simple/ HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        ))
+        .await?;
+        assert_eq!(response, "GET /simple/ HTTP/1.1");
+
+        let response = send(format!(
+            "CONNECT localhost:{origin_port} HTTP/1.1\r\n\r\nhello\r\n"
+        ))
+        .await?;
+        assert_eq!(response, "HTTP/1.1 200 Connection Established\r\n\r\nhello");
+
+        // the same server, by another name
+        let response = send(format!("CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\n\r\n")).await?;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden"));
+        assert!(response.contains("127.0.0.1 isn't an allowed host"));
+        Ok(())
+    }
+}
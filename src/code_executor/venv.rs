@@ -0,0 +1,386 @@
+//! Running Python in a virtualenv that lasts as long as the conversation.
+//! A [`VenvExecutor`] creates the virtualenv in the working directory the
+//! first time it's needed and runs every later code block in it, so
+//! packages installed in one turn are there in the next.
+//!
+//! The model installs packages the way it would anywhere, with a
+//! `pip install` line in a shell block, or `%pip install` in a Python block.
+//! Only packages on the allowlist are installed; anything else is refused
+//! with a note saying what may be installed.
+
+use {
+    super::{
+        run, CodeBlock, CodeExecutor, Error, ExecuteFuture, Execution, Language, LocalExecutor,
+    },
+    std::{
+        collections::{BTreeSet, HashSet},
+        ffi::OsString,
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::Duration,
+    },
+    tokio::process::Command,
+};
+
+/// The directory the virtualenv is created in, under the working directory.
+pub const VENV_DIR: &str = ".venv";
+
+/// How long installing packages may take by default.
+pub const DEFAULT_INSTALL_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Environment variables passed through to `pip`, so it can reach a package
+/// index through a proxy or mirror.
+const PIP_ENV_VARS: [&str; 7] = [
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "NO_PROXY",
+    "http_proxy",
+    "https_proxy",
+    "no_proxy",
+    "PIP_INDEX_URL",
+];
+
+/// Runs code on the host with a persistent Python virtualenv, installing
+/// allowed packages when the model asks for them.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::code_executor::{venv::VenvExecutor, CodeExecutorAgentBuilder};
+/// # tokio_test::block_on(async {
+/// let executor = CodeExecutorAgentBuilder::new()
+///     .with_executor(VenvExecutor::new("scratch").with_allowed_packages(["numpy", "pandas"]))
+///     .build();
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct VenvExecutor {
+    local: LocalExecutor,
+    /// The virtualenv's absolute path.
+    venv: PathBuf,
+    /// The normalized names of the packages that may be installed.
+    allowed: HashSet<String>,
+    install_timeout: Duration,
+    /// The requirements installed so far.
+    installed: Mutex<HashSet<String>>,
+    /// Whether the virtualenv was created. Held while it's being created.
+    created: tokio::sync::Mutex<bool>,
+}
+
+impl VenvExecutor {
+    /// Create an executor that runs code in `work_dir`, with the virtualenv
+    /// in its [`VENV_DIR`]. No packages may be installed until allowed.
+    pub fn new(work_dir: impl Into<PathBuf>) -> Self {
+        Self::with_local(LocalExecutor::new(work_dir))
+    }
+
+    /// Create an executor that runs code with `local`, e.g. one with a
+    /// custom timeout, with the virtualenv in its working directory.
+    pub fn with_local(local: LocalExecutor) -> Self {
+        // code runs in the working directory, so relative paths won't do
+        let mut venv = local.work_dir().join(VENV_DIR);
+        if venv.is_relative() {
+            if let Ok(current_dir) = std::env::current_dir() {
+                venv = current_dir.join(venv);
+            }
+        }
+        let mut path = OsString::from(venv.join("bin"));
+        if let Some(host) = std::env::var_os("PATH") {
+            path.push(":");
+            path.push(host);
+        }
+        Self {
+            local: local.with_env("PATH", path).with_env("VIRTUAL_ENV", &venv),
+            venv,
+            allowed: HashSet::new(),
+            install_timeout: DEFAULT_INSTALL_TIMEOUT,
+            installed: Default::default(),
+            created: Default::default(),
+        }
+    }
+
+    /// Let the model install `packages`, by name, at any version.
+    pub fn with_allowed_packages<I, S>(mut self, packages: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.allowed
+            .extend(packages.into_iter().map(|name| normalize(name.as_ref())));
+        self
+    }
+
+    /// Give up on installing packages after `timeout`.
+    pub fn with_install_timeout(mut self, timeout: Duration) -> Self {
+        self.install_timeout = timeout;
+        self
+    }
+
+    /// Returns the directory of the virtualenv.
+    pub fn venv_dir(&self) -> &Path {
+        &self.venv
+    }
+
+    /// Returns the requirements installed so far, e.g. `numpy==1.26`.
+    pub fn installed(&self) -> Vec<String> {
+        let installed = self.installed.lock().unwrap();
+        installed
+            .iter()
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Creates the virtualenv if it doesn't exist yet. Returns the outcome
+    /// of creating it if that failed.
+    async fn ensure_venv(&self) -> Result<Option<Execution>, Error> {
+        let mut created = self.created.lock().await;
+        if *created {
+            return Ok(None);
+        }
+        tokio::fs::create_dir_all(self.local.work_dir()).await?;
+        if !self.venv_dir().join("bin").join("python").exists() {
+            let mut command = self.command(Path::new("python3"));
+            command.args(["-m", "venv", VENV_DIR]);
+            let execution = run(command, self.install_timeout, self.local.max_output()).await?;
+            if !execution.succeeded() {
+                return Ok(Some(execution));
+            }
+        }
+        *created = true;
+        Ok(None)
+    }
+
+    /// Installs the `requirements` that aren't installed yet, if they're all
+    /// allowed. Returns the outcome, or `None` if there was nothing to do.
+    async fn install(&self, requirements: &[String]) -> Result<Option<Execution>, Error> {
+        let mut refused = Vec::new();
+        for requirement in requirements {
+            match package_name(requirement) {
+                Some(name) if self.allowed.contains(&normalize(name)) => {}
+                _ => refused.push(requirement.as_str()),
+            }
+        }
+        if !refused.is_empty() {
+            let mut allowed = self.allowed.iter().cloned().collect::<Vec<_>>();
+            allowed.sort();
+            let allowed = match allowed.is_empty() {
+                true => "none".to_string(),
+                false => allowed.join(", "),
+            };
+            return Ok(Some(Execution {
+                exit_code: Some(1),
+                stderr: format!(
+                    "not installing {}: only allowed packages can be installed ({allowed})",
+                    refused.join(", ")
+                ),
+                ..Default::default()
+            }));
+        }
+
+        let missing = {
+            let installed = self.installed.lock().unwrap();
+            requirements
+                .iter()
+                .filter(|requirement| !installed.contains(*requirement))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        if missing.is_empty() {
+            return Ok(None);
+        }
+        let mut command = self.command(&self.venv_dir().join("bin").join("python"));
+        command
+            .args([
+                "-m",
+                "pip",
+                "install",
+                "--disable-pip-version-check",
+                "--no-input",
+            ])
+            .args(&missing);
+        let execution = run(command, self.install_timeout, self.local.max_output()).await?;
+        if execution.succeeded() {
+            tracing::debug!(?missing, "installed packages");
+            self.installed.lock().unwrap().extend(missing);
+        }
+        Ok(Some(execution))
+    }
+
+    /// Returns a command that runs `program` in the working directory with a
+    /// clean environment.
+    fn command(&self, program: &Path) -> Command {
+        let work_dir = self.local.work_dir();
+        let mut command = Command::new(program);
+        command
+            .current_dir(work_dir)
+            .env_clear()
+            .env("HOME", work_dir)
+            .env("TMPDIR", work_dir);
+        for var in std::iter::once("PATH").chain(PIP_ENV_VARS) {
+            if let Some(value) = std::env::var_os(var) {
+                command.env(var, value);
+            }
+        }
+        command
+    }
+}
+
+impl CodeExecutor for VenvExecutor {
+    fn execute<'a>(&'a self, block: &'a CodeBlock) -> ExecuteFuture<'a> {
+        Box::pin(async move {
+            if let Some(failed) = self.ensure_venv().await? {
+                return Ok(failed);
+            }
+            let (requirements, code) = split_installs(block);
+            let installed = match requirements.is_empty() {
+                true => None,
+                false => self.install(&requirements).await?,
+            };
+            match installed {
+                Some(installed) if !installed.succeeded() => return Ok(installed),
+                _ if code.trim().is_empty() => return Ok(installed.unwrap_or_default()),
+                _ => {}
+            }
+
+            let block = CodeBlock {
+                language: block.language,
+                code,
+            };
+            let mut execution = self.local.execute(&block).await?;
+            if let Some(installed) = installed {
+                execution.stdout = installed.stdout + &execution.stdout;
+                execution.truncated |= installed.truncated;
+            }
+            Ok(execution)
+        })
+    }
+}
+
+/// Splits `block` into the requirements its `pip install` lines ask for and
+/// the rest of its code.
+fn split_installs(block: &CodeBlock) -> (Vec<String>, String) {
+    let mut requirements = Vec::new();
+    let mut code = String::new();
+    for line in block.code.lines() {
+        let trimmed = line.trim();
+        let command = match block.language {
+            Language::Shell => Some(trimmed),
+            Language::Python => trimmed
+                .strip_prefix('%')
+                .or_else(|| trimmed.strip_prefix('!')),
+            _ => None,
+        };
+        let install = command.and_then(|command| {
+            [
+                "pip install",
+                "pip3 install",
+                "python -m pip install",
+                "python3 -m pip install",
+            ]
+            .into_iter()
+            .find_map(|prefix| command.strip_prefix(prefix))
+        });
+        match install {
+            Some(arguments) => requirements.extend(
+                arguments
+                    .split_whitespace()
+                    .map(|argument| argument.trim_matches(|c| c == '"' || c == '\'').to_string()),
+            ),
+            None => {
+                code.push_str(line);
+                code.push('\n');
+            }
+        }
+    }
+    (requirements, code)
+}
+
+/// Returns the name of the package a requirement like `numpy>=1.26` asks
+/// for, or `None` if it's anything but a name with optional extras and
+/// version constraints, e.g. an option, a path or a URL.
+fn package_name(requirement: &str) -> Option<&str> {
+    let end = requirement
+        .find(|c: char| !(c.is_ascii_alphanumeric() || "._-".contains(c)))
+        .unwrap_or(requirement.len());
+    let (name, rest) = requirement.split_at(end);
+    let rest = match rest.strip_prefix('[') {
+        Some(extras) => {
+            let (extras, rest) = extras.split_once(']')?;
+            extras
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || ",._-".contains(c))
+                .then_some(rest)?
+        }
+        None => rest,
+    };
+    let version = rest.is_empty()
+        || (rest.starts_with(['=', '<', '>', '~', '!'])
+            && rest
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || ".*,=<>~!".contains(c)));
+    (!name.is_empty() && !name.starts_with(['-', '.']) && version).then_some(name)
+}
+
+/// Returns a package name as the package index compares names.
+fn normalize(name: &str) -> String {
+    name.trim().to_lowercase().replace(['_', '.'], "-")
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, anyhow::Result, uuid::Uuid};
+
+    fn block(language: Language, code: &str) -> CodeBlock {
+        CodeBlock {
+            language,
+            code: code.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_split_installs() {
+        let (requirements, code) = split_installs(&block(
+            Language::Python,
+            "%pip install numpy 'pandas>=2'\nimport numpy\n",
+        ));
+        assert_eq!(requirements, ["numpy", "pandas>=2"]);
+        assert_eq!(code, "import numpy\n");
+
+        assert_eq!(package_name("Flask_Login[extra]~=0.6"), Some("Flask_Login"));
+        assert_eq!(normalize("Flask_Login"), "flask-login");
+        for requirement in ["-r", "--index-url", "./local", "git+https://x/y", "a@b"] {
+            assert_eq!(package_name(requirement), None, "{requirement}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_persistent_venv() -> Result<()> {
+        let work_dir = std::env::temp_dir().join(format!("autogen-rs-test-{}", Uuid::new_v4()));
+        let executor = VenvExecutor::new(&work_dir).with_allowed_packages(["numpy"]);
+
+        let execution = executor
+            .execute(&block(Language::Python, "import sys\nprint(sys.prefix)"))
+            .await?;
+        let prefix = tokio::fs::canonicalize(executor.venv_dir()).await?;
+        assert_eq!(execution.stdout.trim(), prefix.to_string_lossy());
+
+        // the virtualenv is reused, and only allowed packages are installed
+        let execution = executor
+            .execute(&block(
+                Language::Shell,
+                "pip install requests\necho unreachable",
+            ))
+            .await?;
+        assert_eq!(execution.exit_code, Some(1));
+        assert_eq!(
+            execution.stderr,
+            "not installing requests: only allowed packages can be installed (numpy)"
+        );
+        assert!(executor.installed().is_empty());
+
+        tokio::fs::remove_dir_all(work_dir).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,513 @@
+//! Keeping conversations within a model's context window. A
+//! [`ContextWindow`] caps the tokens an assistant sends the model, and its
+//! [`ContextStrategy`] decides what to leave out when the history grows past
+//! the cap: the oldest messages beyond a [`SlidingWindow`], as few of the
+//! oldest messages as needed with [`DropOldest`], or a summary of them with
+//! [`SummarizeOldest`].
+//!
+//! Strategies only shorten what's sent; the assistant's history is kept in
+//! full. The system messages a conversation starts with are always kept, and
+//! a tool call is never separated from its results.
+
+use {
+    crate::{
+        llm::{CompletionRequest, HistoryMessage, LlmClient, Role},
+        prompts::{Prompt, PromptPack},
+        tokenizer::TokenizerRegistry,
+    },
+    std::{
+        fmt::Debug,
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+    },
+};
+
+/// The most tokens a summary is when no limit is configured.
+pub const DEFAULT_MAX_SUMMARY_TOKENS: usize = 500;
+
+/// A boxed future returned by [`ContextStrategy::fit`].
+pub type FitFuture<'a> = Pin<Box<dyn Future<Output = Vec<HistoryMessage>> + Send + 'a>>;
+
+/// How many tokens messages sent to a model may take.
+#[derive(Debug, Clone, Copy)]
+pub struct Budget<'a> {
+    /// The model the messages are sent to.
+    pub model: &'a str,
+
+    /// The most tokens the messages may take, overhead included.
+    pub max_tokens: usize,
+
+    /// Counts the model's tokens.
+    pub tokenizers: &'a TokenizerRegistry,
+}
+
+impl Budget<'_> {
+    /// Returns the number of tokens `messages` take.
+    pub fn count(&self, messages: &[HistoryMessage]) -> usize {
+        self.tokenizers.count_messages(self.model, messages)
+    }
+
+    /// Returns whether `messages` fit the budget.
+    pub fn fits(&self, messages: &[HistoryMessage]) -> bool {
+        self.count(messages) <= self.max_tokens
+    }
+
+    /// Returns the index of the first message to keep after the pinned
+    /// ones, no earlier than `from`, so the rest fit with `reserve` tokens to
+    /// spare. The last turn is kept even if it doesn't fit.
+    fn cut(&self, messages: &[HistoryMessage], from: usize, reserve: usize) -> usize {
+        let pinned = pinned(messages);
+        let mut from = from.max(pinned);
+        // tool results stay with the call they answer
+        while from > pinned && from < messages.len() && messages[from].tool_call_id.is_some() {
+            from -= 1;
+        }
+
+        let base = self.count(&[]);
+        let cost = |message: &HistoryMessage| self.count(std::slice::from_ref(message)) - base;
+        let mut tokens = base + reserve + messages[..pinned].iter().map(cost).sum::<usize>();
+        let mut start = messages.len();
+        // walk back from the newest message, moving the cut to the start of
+        // each turn that still fits
+        for (i, message) in messages.iter().enumerate().skip(from).rev() {
+            tokens += cost(message);
+            if message.tool_call_id.is_some() {
+                continue;
+            }
+            if tokens > self.max_tokens && start < messages.len() {
+                break;
+            }
+            start = i;
+        }
+        start
+    }
+}
+
+/// Decides what's sent to a model when a conversation doesn't fit its
+/// context window.
+pub trait ContextStrategy: Debug + Send + Sync + 'static {
+    /// Returns `messages`, shortened to fit `budget` if they don't.
+    fn fit<'a>(&'a self, messages: Vec<HistoryMessage>, budget: Budget<'a>) -> FitFuture<'a>;
+}
+
+/// The number of system messages a conversation starts with, which are
+/// always kept.
+fn pinned(messages: &[HistoryMessage]) -> usize {
+    messages
+        .iter()
+        .take_while(|message| message.role == Role::System)
+        .count()
+}
+
+/// Returns the pinned messages followed by `messages[start..]`, with
+/// `summary` in between, if there is one.
+fn keep(
+    mut messages: Vec<HistoryMessage>,
+    start: usize,
+    summary: Option<HistoryMessage>,
+) -> Vec<HistoryMessage> {
+    let pinned = pinned(&messages);
+    messages.splice(pinned..start, summary);
+    messages
+}
+
+/// Sends at most the most recent `max_messages` messages after the pinned
+/// ones, and fewer if they don't fit the budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlidingWindow {
+    /// The most messages sent after the pinned ones.
+    pub max_messages: usize,
+}
+
+impl SlidingWindow {
+    /// Create a window of at most `max_messages` messages.
+    pub fn new(max_messages: usize) -> Self {
+        Self { max_messages }
+    }
+}
+
+impl ContextStrategy for SlidingWindow {
+    fn fit<'a>(&'a self, messages: Vec<HistoryMessage>, budget: Budget<'a>) -> FitFuture<'a> {
+        Box::pin(async move {
+            let from = messages.len().saturating_sub(self.max_messages);
+            let start = budget.cut(&messages, from, 0);
+            keep(messages, start, None)
+        })
+    }
+}
+
+/// Leaves out as few of the oldest messages as needed to fit the budget.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DropOldest;
+
+impl ContextStrategy for DropOldest {
+    fn fit<'a>(&'a self, messages: Vec<HistoryMessage>, budget: Budget<'a>) -> FitFuture<'a> {
+        Box::pin(async move {
+            if budget.fits(&messages) {
+                return messages;
+            }
+            let start = budget.cut(&messages, 0, 0);
+            keep(messages, start, None)
+        })
+    }
+}
+
+/// A summary and the messages it covers.
+#[derive(Debug)]
+struct Summary {
+    covers: Vec<HistoryMessage>,
+    content: String,
+}
+
+/// Replaces the oldest messages with a summary of them, written by a model,
+/// when the conversation doesn't fit the budget.
+///
+/// The last summary is kept, and folded into the next one along with the
+/// messages left out since, so each message is summarized once. If the
+/// summary can't be written, the oldest messages are left out as with
+/// [`DropOldest`].
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::assistant::AssistantBuilder, context::{ContextWindow, SummarizeOldest}, llm::openai}, std::sync::Arc};
+/// # tokio_test::block_on(async {
+/// let summarize = SummarizeOldest::new(Arc::new(openai::Client::new(None, None)))
+///     .with_model("gpt-3.5-turbo");
+/// let assistant = AssistantBuilder::new()
+///     .with_context_window(ContextWindow::new(8192, summarize).with_reserve(1024))
+///     .build();
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct SummarizeOldest {
+    client: Arc<dyn LlmClient>,
+    model: Option<String>,
+    max_summary_tokens: usize,
+    prompts: PromptPack,
+    last: Mutex<Option<Summary>>,
+}
+
+impl SummarizeOldest {
+    /// Summarize with the model the conversation is with, through `client`.
+    pub fn new(client: Arc<dyn LlmClient>) -> Self {
+        Self {
+            client,
+            model: None,
+            max_summary_tokens: DEFAULT_MAX_SUMMARY_TOKENS,
+            prompts: PromptPack::default(),
+            last: Default::default(),
+        }
+    }
+
+    /// Summarize with `model`, e.g. a cheaper one.
+    pub fn with_model(mut self, model: impl ToString) -> Self {
+        self.model = Some(model.to_string());
+        self
+    }
+
+    /// Keep summaries to at most `max_summary_tokens` tokens.
+    pub fn with_max_summary_tokens(mut self, max_summary_tokens: usize) -> Self {
+        self.max_summary_tokens = max_summary_tokens;
+        self
+    }
+
+    /// Summarize with `prompts` instead of the English ones.
+    pub fn with_prompts(mut self, prompts: PromptPack) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    /// Returns the message that stands in for the summarized messages.
+    fn summary_message(&self, summary: &str) -> HistoryMessage {
+        HistoryMessage::new(
+            Role::System,
+            self.prompts
+                .render(Prompt::EarlierSummary, &[("summary", summary)]),
+        )
+    }
+
+    /// Returns a summary of `messages`, folding in the last summary if it
+    /// covers the start of them.
+    async fn summarize(&self, messages: &[HistoryMessage], budget: Budget<'_>) -> Option<String> {
+        let previous = {
+            let last = self.last.lock().unwrap();
+            last.as_ref()
+                .filter(|last| messages.starts_with(&last.covers))
+                .map(|last| (last.covers.len(), last.content.clone()))
+        };
+        let (covered, previous) = previous.unzip();
+        let new = &messages[covered.unwrap_or(0)..];
+        if new.is_empty() {
+            return previous;
+        }
+
+        let mut input = String::new();
+        if let Some(previous) = &previous {
+            input.push_str(
+                &self
+                    .prompts
+                    .render(Prompt::PreviousSummary, &[("summary", previous)]),
+            );
+            input.push_str("\n\n");
+        }
+        input.push_str(self.prompts.get(Prompt::Conversation));
+        input.push('\n');
+        for message in new {
+            input.push_str(&transcribe(message));
+        }
+        let request = CompletionRequest {
+            model: self.model.as_deref().unwrap_or(budget.model).to_string(),
+            messages: vec![
+                HistoryMessage::new(Role::System, self.prompts.get(Prompt::Summary)),
+                HistoryMessage::new(Role::User, input),
+            ],
+            ..Default::default()
+        };
+        let content = match self.client.complete(request).await {
+            Ok(completion) => budget
+                .tokenizers
+                .truncate(
+                    budget.model,
+                    completion.content.trim(),
+                    self.max_summary_tokens,
+                )
+                .to_string(),
+            Err(e) => {
+                tracing::warn!(error = %e, "unable to summarize conversation; leaving messages out");
+                return None;
+            }
+        };
+        tracing::debug!(messages = new.len(), "summarized conversation");
+        *self.last.lock().unwrap() = Some(Summary {
+            covers: messages.to_vec(),
+            content: content.clone(),
+        });
+        Some(content)
+    }
+}
+
+impl ContextStrategy for SummarizeOldest {
+    fn fit<'a>(&'a self, messages: Vec<HistoryMessage>, budget: Budget<'a>) -> FitFuture<'a> {
+        Box::pin(async move {
+            if budget.fits(&messages) {
+                return messages;
+            }
+            let reserve = budget.count(&[self.summary_message("")]) - budget.count(&[])
+                + self.max_summary_tokens;
+            let start = budget.cut(&messages, 0, reserve);
+            let pinned = pinned(&messages);
+            let summary = match start > pinned {
+                true => self.summarize(&messages[pinned..start], budget).await,
+                false => None,
+            };
+            keep(messages, start, summary.map(|s| self.summary_message(&s)))
+        })
+    }
+}
+
+/// Returns a line of a transcript for the summarizing model.
+fn transcribe(message: &HistoryMessage) -> String {
+    let role = match message.role {
+        Role::System => "system",
+        Role::User => "user",
+        Role::Assistant => "assistant",
+        Role::Tool => "tool",
+    };
+    let mut line = format!("{role}: {}\n", message.content);
+    for call in &message.tool_calls {
+        line.push_str(&format!(
+            "{role}: called {}({})\n",
+            call.function.name, call.function.arguments
+        ));
+    }
+    line
+}
+
+/// The size of a model's context window and how an assistant keeps its
+/// conversation within it.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::{agent::assistant::AssistantBuilder, context::{ContextWindow, SlidingWindow}};
+/// # tokio_test::block_on(async {
+/// let assistant = AssistantBuilder::new()
+///     .with_context_window(ContextWindow::new(8192, SlidingWindow::new(20)))
+///     .build();
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct ContextWindow {
+    /// The most tokens the model takes, its reply included.
+    pub max_tokens: usize,
+
+    /// The tokens left for the model's reply, and for tool definitions,
+    /// which aren't counted.
+    pub reserve: usize,
+
+    /// Decides what's sent when the conversation doesn't fit.
+    pub strategy: Arc<dyn ContextStrategy>,
+}
+
+impl ContextWindow {
+    /// Create a window of `max_tokens` tokens, kept to with `strategy`.
+    pub fn new(max_tokens: usize, strategy: impl ContextStrategy) -> Self {
+        Self {
+            max_tokens,
+            reserve: 0,
+            strategy: Arc::new(strategy),
+        }
+    }
+
+    /// Leave `reserve` tokens of the window for the model's reply.
+    pub fn with_reserve(mut self, reserve: usize) -> Self {
+        self.reserve = reserve;
+        self
+    }
+
+    /// Returns `messages`, shortened to fit the window for `model`.
+    pub async fn fit(
+        &self,
+        messages: Vec<HistoryMessage>,
+        model: &str,
+        tokenizers: &TokenizerRegistry,
+    ) -> Vec<HistoryMessage> {
+        let budget = Budget {
+            model,
+            max_tokens: self.max_tokens.saturating_sub(self.reserve),
+            tokenizers,
+        };
+        self.strategy.fit(messages, budget).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            llm::{Completion, LlmFuture},
+            tokenizer::{Estimate, Tokenizer},
+        },
+        anyhow::Result,
+        std::sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Counts words, so counts are easy to check.
+    #[derive(Debug)]
+    struct Words;
+
+    impl Tokenizer for Words {
+        fn count(&self, text: &str) -> usize {
+            text.split_whitespace().count()
+        }
+    }
+
+    /// Summarizes by counting the conversation lines it's given.
+    #[derive(Debug, Default)]
+    struct Counter {
+        calls: AtomicUsize,
+    }
+
+    impl LlmClient for Counter {
+        fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                let input = &request.messages[1].content;
+                let lines = input.split_once("Conversation:\n").unwrap().1.lines();
+                Ok(Completion {
+                    content: format!("{} new lines", lines.count()),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    fn conversation() -> Vec<HistoryMessage> {
+        let mut messages = vec![HistoryMessage::new(Role::System, "be brief")];
+        for i in 0..4 {
+            messages.push(HistoryMessage::new(Role::User, format!("question {i}")));
+            messages.push(HistoryMessage::new(Role::Assistant, format!("answer {i}")));
+        }
+        messages
+    }
+
+    /// Returns the contents of `messages`.
+    fn contents(messages: &[HistoryMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.content.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_and_sliding_window() {
+        let tokenizers = TokenizerRegistry::new().with_fallback(Words);
+        // the system message and two more messages, with 3 tokens of overhead
+        // each and 3 to prime the reply
+        let window = ContextWindow::new(2 + 5 + 5 + 5 + 3, DropOldest);
+        let messages = window.fit(conversation(), "any", &tokenizers).await;
+        assert_eq!(contents(&messages), ["be brief", "question 3", "answer 3"]);
+
+        // everything fits
+        let window = ContextWindow::new(1000, DropOldest);
+        assert_eq!(
+            window.fit(conversation(), "any", &tokenizers).await.len(),
+            9
+        );
+
+        let window = ContextWindow::new(1000, SlidingWindow::new(3));
+        let messages = window.fit(conversation(), "any", &tokenizers).await;
+        assert_eq!(
+            contents(&messages),
+            ["be brief", "answer 2", "question 3", "answer 3"]
+        );
+
+        // a tool call isn't separated from its result, and the last turn is
+        // kept even if it doesn't fit
+        let mut messages = conversation();
+        messages.push(HistoryMessage::tool_result("call_0", "result"));
+        let window = ContextWindow::new(1000, SlidingWindow::new(1));
+        let messages = window.fit(messages, "any", &tokenizers).await;
+        assert_eq!(contents(&messages), ["be brief", "answer 3", "result"]);
+        let window = ContextWindow::new(0, DropOldest);
+        assert_eq!(
+            window.fit(conversation(), "any", &tokenizers).await.len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_summarize_oldest() -> Result<()> {
+        let tokenizers = TokenizerRegistry::new().with_fallback(Estimate { chars_per_token: 1 });
+        let counter = Arc::new(Counter::default());
+        let window = ContextWindow::new(
+            100,
+            SummarizeOldest::new(counter.clone()).with_max_summary_tokens(20),
+        );
+
+        let messages = window.fit(conversation(), "any", &tokenizers).await;
+        assert_eq!(messages[0].content, "be brief");
+        assert_eq!(
+            messages[1].content,
+            "Summary of the earlier conversation:\n6 new lines"
+        );
+        assert_eq!(contents(&messages[2..]), ["question 3", "answer 3"]);
+        assert!(tokenizers.count_messages("any", &messages) <= 100);
+
+        // the next summary folds in the last one and only the new messages
+        let mut longer = conversation();
+        longer.push(HistoryMessage::new(Role::User, "question 4"));
+        longer.push(HistoryMessage::new(Role::Assistant, "answer 4"));
+        let messages = window.fit(longer, "any", &tokenizers).await;
+        assert!(messages[1].content.ends_with("2 new lines"));
+        assert_eq!(contents(&messages[2..]), ["question 4", "answer 4"]);
+        assert_eq!(counter.calls.load(Ordering::SeqCst), 2);
+
+        // nothing is summarized when everything fits
+        let window = ContextWindow::new(1000, SummarizeOldest::new(counter.clone()));
+        assert_eq!(
+            window.fit(conversation(), "any", &tokenizers).await.len(),
+            9
+        );
+        assert_eq!(counter.calls.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,377 @@
+//! Dynamic batching of embedding requests. Agents doing retrieval tend to
+//! embed a few texts at a time; a [`Batcher`] holds requests for a short
+//! window, combines them into as few provider calls as the size limits
+//! allow, and hands each request its own embeddings back. Fewer calls means
+//! less per-call overhead and less rate-limit pressure.
+
+use {
+    super::{EmbedFuture, Embedder, Embedding, EmbeddingRequest, Error},
+    crate::{
+        agent::{Clock, SystemClock},
+        tokenizer::TokenizerRegistry,
+    },
+    std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio::{
+        sync::{mpsc, oneshot},
+        task::JoinHandle,
+    },
+};
+
+/// How requests are combined.
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    /// The most inputs sent in one call.
+    pub max_inputs: usize,
+
+    /// The most tokens sent in one call.
+    pub max_tokens: usize,
+
+    /// How long to wait for more requests after the first one arrives.
+    pub window: Duration,
+
+    /// Counts the tokens of inputs.
+    pub tokenizers: TokenizerRegistry,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            max_inputs: 256,
+            max_tokens: 100_000,
+            window: Duration::from_millis(10),
+            tokenizers: TokenizerRegistry::default(),
+        }
+    }
+}
+
+/// What a [`Batcher`] has sent so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchMetrics {
+    /// The number of requests received.
+    pub requests: u64,
+
+    /// The number of calls made to the backend.
+    pub batches: u64,
+
+    /// The number of inputs embedded.
+    pub inputs: u64,
+
+    /// The number of tokens embedded.
+    pub tokens: u64,
+}
+
+/// A request waiting to be batched.
+struct Pending {
+    request: EmbeddingRequest,
+    reply: oneshot::Sender<Result<Vec<Embedding>, Error>>,
+}
+
+impl Pending {
+    fn tokens(&self, tokenizers: &TokenizerRegistry) -> usize {
+        let tokenizer = tokenizers.get(&self.request.model);
+        self.request
+            .inputs
+            .iter()
+            .map(|input| tokenizer.count(input))
+            .sum()
+    }
+}
+
+/// Combines concurrent embedding requests into batched calls to a backend.
+/// Requests for different models are never combined. A request that's over
+/// the limits on its own is sent by itself.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{embedding::{batch::{BatchOptions, Batcher}, Embedder, EmbeddingRequest}, llm::openai}, std::sync::Arc};
+/// # tokio_test::block_on(async {
+/// let batcher = Arc::new(Batcher::new(
+///     Arc::new(openai::Client::new(None, None)),
+///     BatchOptions::default(),
+/// ));
+/// // share the batcher between agents; their requests are sent together
+/// let embeddings = batcher.embed(EmbeddingRequest::new("text-embedding-3-small", ["hello"]));
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct Batcher {
+    requests: mpsc::UnboundedSender<Pending>,
+    metrics: Arc<Mutex<BatchMetrics>>,
+    /// The task collecting requests into batches.
+    task: JoinHandle<()>,
+}
+
+impl Batcher {
+    /// Create a batcher in front of `embedder`.
+    pub fn new(embedder: Arc<dyn Embedder>, options: BatchOptions) -> Self {
+        Self::with_clock(embedder, options, SystemClock)
+    }
+
+    /// Like [`Batcher::new`], but measures the window on `clock`.
+    pub fn with_clock(
+        embedder: Arc<dyn Embedder>,
+        options: BatchOptions,
+        clock: impl Clock,
+    ) -> Self {
+        let (requests, receiver) = mpsc::unbounded_channel();
+        let metrics = Arc::new(Mutex::new(BatchMetrics::default()));
+        let task = tokio::spawn(collect(
+            embedder,
+            options,
+            Arc::new(clock),
+            receiver,
+            metrics.clone(),
+        ));
+        Self {
+            requests,
+            metrics,
+            task,
+        }
+    }
+
+    /// Returns what the batcher has sent so far.
+    pub fn metrics(&self) -> BatchMetrics {
+        *self.metrics.lock().unwrap()
+    }
+}
+
+impl Drop for Batcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Embedder for Batcher {
+    fn embed(&self, request: EmbeddingRequest) -> EmbedFuture<'_> {
+        Box::pin(async move {
+            let (reply, embeddings) = oneshot::channel();
+            self.requests
+                .send(Pending { request, reply })
+                .map_err(|_| Error::Other("embedding batcher stopped".into()))?;
+            embeddings
+                .await
+                .map_err(|_| Error::Other("embedding batcher dropped the request".into()))?
+        })
+    }
+}
+
+/// Collect requests for a window at a time and send them in batches.
+async fn collect(
+    embedder: Arc<dyn Embedder>,
+    options: BatchOptions,
+    clock: Arc<dyn Clock>,
+    mut receiver: mpsc::UnboundedReceiver<Pending>,
+    metrics: Arc<Mutex<BatchMetrics>>,
+) {
+    while let Some(first) = receiver.recv().await {
+        let mut inputs = first.request.inputs.len();
+        let mut pending = vec![first];
+        let window = clock.sleep(options.window);
+        tokio::pin!(window);
+        // stop early once there's enough for a full batch
+        while inputs < options.max_inputs {
+            tokio::select! {
+                () = &mut window => break,
+                next = receiver.recv() => match next {
+                    Some(next) => {
+                        inputs += next.request.inputs.len();
+                        pending.push(next);
+                    }
+                    None => break,
+                },
+            }
+        }
+
+        metrics.lock().unwrap().requests += pending.len() as u64;
+        for batch in pack(pending, &options) {
+            tokio::spawn(send(
+                embedder.clone(),
+                batch,
+                options.tokenizers.clone(),
+                metrics.clone(),
+            ));
+        }
+    }
+}
+
+/// Split requests into batches within the limits, grouped by model and in
+/// the order they arrived.
+fn pack(pending: Vec<Pending>, options: &BatchOptions) -> Vec<Vec<Pending>> {
+    let mut models = Vec::<(String, Vec<Vec<Pending>>)>::new();
+    for request in pending {
+        let batches = match models
+            .iter_mut()
+            .find(|(model, _)| *model == request.request.model)
+        {
+            Some((_, batches)) => batches,
+            None => {
+                models.push((request.request.model.clone(), Vec::new()));
+                &mut models.last_mut().unwrap().1
+            }
+        };
+        let fits = batches.last().is_some_and(|batch| {
+            let inputs = batch.iter().map(|p| p.request.inputs.len()).sum::<usize>();
+            let tokens = batch
+                .iter()
+                .map(|p| p.tokens(&options.tokenizers))
+                .sum::<usize>();
+            inputs + request.request.inputs.len() <= options.max_inputs
+                && tokens + request.tokens(&options.tokenizers) <= options.max_tokens
+        });
+        match batches.last_mut() {
+            Some(batch) if fits => batch.push(request),
+            _ => batches.push(vec![request]),
+        }
+    }
+    models
+        .into_iter()
+        .flat_map(|(_, batches)| batches)
+        .collect()
+}
+
+/// Send a batch and split the embeddings between its requests.
+async fn send(
+    embedder: Arc<dyn Embedder>,
+    batch: Vec<Pending>,
+    tokenizers: TokenizerRegistry,
+    metrics: Arc<Mutex<BatchMetrics>>,
+) {
+    let model = batch[0].request.model.clone();
+    let inputs = batch
+        .iter()
+        .flat_map(|pending| pending.request.inputs.iter().cloned())
+        .collect::<Vec<_>>();
+    {
+        let mut metrics = metrics.lock().unwrap();
+        metrics.batches += 1;
+        metrics.inputs += inputs.len() as u64;
+        metrics.tokens += batch.iter().map(|p| p.tokens(&tokenizers)).sum::<usize>() as u64;
+    }
+    tracing::trace!(
+        model,
+        requests = batch.len(),
+        inputs = inputs.len(),
+        "sending embedding batch"
+    );
+
+    let expected = inputs.len();
+    let result = match embedder.embed(EmbeddingRequest { model, inputs }).await {
+        Ok(embeddings) if embeddings.len() == expected => Ok(embeddings),
+        Ok(embeddings) => Err(Error::Other(
+            format!("expected {expected} embeddings, got {}", embeddings.len()).into(),
+        )),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(embeddings) => {
+            let mut embeddings = embeddings.into_iter();
+            for pending in batch {
+                let count = pending.request.inputs.len();
+                let _ = pending
+                    .reply
+                    .send(Ok(embeddings.by_ref().take(count).collect()));
+            }
+        }
+        Err(e) => {
+            let e = Arc::new(e);
+            for pending in batch {
+                let _ = pending.reply.send(Err(Error::Batch(e.clone())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, anyhow::Result, tokio::task::JoinSet};
+
+    /// Embeds each input as its length, and remembers each call's inputs.
+    #[derive(Debug, Default)]
+    struct Lengths {
+        calls: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl Embedder for Lengths {
+        fn embed(&self, request: EmbeddingRequest) -> EmbedFuture<'_> {
+            Box::pin(async move {
+                let embeddings = request
+                    .inputs
+                    .iter()
+                    .map(|input| vec![input.len() as f32])
+                    .collect();
+                self.calls.lock().unwrap().push(request.inputs);
+                Ok(embeddings)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_combines_concurrent_requests() -> Result<()> {
+        let lengths = Arc::new(Lengths::default());
+        let options = BatchOptions {
+            window: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let batcher = Arc::new(Batcher::new(lengths.clone(), options));
+
+        let mut requests = JoinSet::new();
+        for i in 1..=10 {
+            let batcher = batcher.clone();
+            requests.spawn(async move {
+                let inputs = ["a".repeat(i), "b".repeat(i * 10)];
+                let embeddings = batcher.embed(EmbeddingRequest::new("model", inputs)).await;
+                (i, embeddings)
+            });
+        }
+        while let Some(result) = requests.join_next().await {
+            let (i, embeddings) = result?;
+            assert_eq!(embeddings?, [vec![i as f32], vec![(i * 10) as f32]]);
+        }
+
+        assert_eq!(lengths.calls.lock().unwrap().len(), 1);
+        let metrics = batcher.metrics();
+        assert_eq!(
+            (metrics.requests, metrics.batches, metrics.inputs),
+            (10, 1, 20)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_within_limits() {
+        let options = BatchOptions {
+            max_inputs: 3,
+            max_tokens: 10,
+            ..Default::default()
+        };
+        let pending = |model: &str, inputs: &[&str]| Pending {
+            request: EmbeddingRequest::new(model, inputs),
+            reply: oneshot::channel().0,
+        };
+        let batches = pack(
+            vec![
+                pending("a", &["1", "2"]),
+                pending("b", &["1"]),
+                pending("a", &["3"]),
+                pending("a", &["4"]),
+                pending("a", &[&"x".repeat(100)]),
+            ],
+            &options,
+        );
+        let batches = batches
+            .iter()
+            .map(|batch| {
+                batch
+                    .iter()
+                    .flat_map(|p| p.request.inputs.iter().map(|i| i.len()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        // "a" splits on the input limit, then on the token limit
+        assert_eq!(batches, [vec![1, 1, 1], vec![1], vec![100], vec![1]]);
+    }
+}
@@ -0,0 +1,148 @@
+//! An embedding model that runs in-process with [candle], so retrieval and
+//! semantic routing work offline and without per-call cost. Any BERT-style
+//! sentence transformer works, e.g. `sentence-transformers/all-MiniLM-L6-v2`:
+//! download its `config.json`, `tokenizer.json` and `model.safetensors` into a
+//! directory and [`LocalEmbedder::load`] it.
+//!
+//! Requires the `local-embeddings` feature.
+//!
+//! [candle]: https://github.com/huggingface/candle
+
+use {
+    super::{EmbedFuture, Embedder, Embedding, EmbeddingRequest},
+    candle_core::{DType, Device, Tensor},
+    candle_nn::VarBuilder,
+    candle_transformers::models::bert::{BertModel, Config, DTYPE},
+    std::{fmt, path::Path, sync::Arc},
+    tokenizers::{PaddingParams, Tokenizer, TruncationParams},
+};
+
+/// The most tokens of an input that are embedded; the rest is cut off.
+pub const MAX_TOKENS: usize = 512;
+
+/// Errors that can occur when loading or running a local model.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("unable to read model file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid model config: {0}")]
+    Config(#[from] serde_json::Error),
+
+    #[error("model failed: {0}")]
+    Model(#[from] candle_core::Error),
+
+    #[error("tokenizer failed: {0}")]
+    Tokenizer(String),
+}
+
+/// A sentence transformer running on the CPU. Embeddings are the mean of the
+/// model's token embeddings, normalized to unit length.
+///
+/// Usage:
+/// ```no_run
+/// # use autogen_rs::embedding::{local::LocalEmbedder, Embedder, EmbeddingRequest};
+/// # tokio_test::block_on(async {
+/// let embedder = LocalEmbedder::load("models/all-MiniLM-L6-v2")?;
+/// let embeddings = embedder
+///     .embed(EmbeddingRequest::new("local", ["hello"]))
+///     .await?;
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Clone)]
+pub struct LocalEmbedder {
+    model: Arc<BertModel>,
+    tokenizer: Arc<Tokenizer>,
+}
+
+impl fmt::Debug for LocalEmbedder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalEmbedder").finish_non_exhaustive()
+    }
+}
+
+impl LocalEmbedder {
+    /// Load the model in `dir`, which must hold `config.json`,
+    /// `tokenizer.json` and `model.safetensors`.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        let config = serde_json::from_slice::<Config>(&std::fs::read(dir.join("config.json"))?)?;
+        let mut tokenizer = Tokenizer::from_file(dir.join("tokenizer.json"))
+            .map_err(|e| Error::Tokenizer(e.to_string()))?;
+        tokenizer.with_padding(Some(PaddingParams::default()));
+        tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: MAX_TOKENS,
+                ..Default::default()
+            }))
+            .map_err(|e| Error::Tokenizer(e.to_string()))?;
+
+        let weights = std::fs::read(dir.join("model.safetensors"))?;
+        let weights = VarBuilder::from_buffered_safetensors(weights, DTYPE, &Device::Cpu)?;
+        Ok(Self {
+            model: Arc::new(BertModel::load(weights, &config)?),
+            tokenizer: Arc::new(tokenizer),
+        })
+    }
+
+    /// Embed `inputs`, blocking until done. Returns one embedding per input,
+    /// in order.
+    pub fn embed_blocking(&self, inputs: Vec<String>) -> Result<Vec<Embedding>, Error> {
+        if inputs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let encodings = self
+            .tokenizer
+            .encode_batch(inputs, true)
+            .map_err(|e| Error::Tokenizer(e.to_string()))?;
+        let tensor = |values: Vec<&[u32]>| Tensor::new(values, &Device::Cpu);
+        let ids = tensor(encodings.iter().map(|e| e.get_ids()).collect())?;
+        let mask = tensor(encodings.iter().map(|e| e.get_attention_mask()).collect())?;
+
+        let tokens = self.model.forward(&ids, &ids.zeros_like()?)?;
+        Ok(mean_pool(&tokens, &mask)?.to_vec2()?)
+    }
+}
+
+/// Average the embeddings of each input's tokens, skipping padding, and
+/// normalize the result to unit length.
+fn mean_pool(tokens: &Tensor, mask: &Tensor) -> Result<Tensor, candle_core::Error> {
+    let mask = mask.to_dtype(DType::F32)?.unsqueeze(2)?;
+    let sum = tokens.broadcast_mul(&mask)?.sum(1)?;
+    let mean = sum.broadcast_div(&mask.sum(1)?)?;
+    let norm = mean.sqr()?.sum_keepdim(1)?.sqrt()?;
+    mean.broadcast_div(&norm)
+}
+
+impl Embedder for LocalEmbedder {
+    /// Embeds with the loaded model; the request's model is ignored.
+    fn embed(&self, request: EmbeddingRequest) -> EmbedFuture<'_> {
+        Box::pin(async move {
+            // inference is CPU-bound, so keep it off the runtime's workers
+            let embedder = self.clone();
+            tokio::task::spawn_blocking(move || embedder.embed_blocking(request.inputs))
+                .await
+                .map_err(|e| super::Error::Other(Box::new(e)))?
+                .map_err(|e| super::Error::Other(Box::new(e)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, anyhow::Result};
+
+    #[test]
+    fn test_mean_pool_skips_padding() -> Result<()> {
+        // two inputs of two tokens each; the second input's last token is padding
+        let tokens = Tensor::new(
+            &[[[3f32, 0.0], [0.0, 4.0]], [[0.0, 2.0], [9.0, 9.0]]],
+            &Device::Cpu,
+        )?;
+        let mask = Tensor::new(&[[1u32, 1], [1, 0]], &Device::Cpu)?;
+        let pooled = mean_pool(&tokens, &mask)?.to_vec2::<f32>()?;
+        assert_eq!(pooled, [[0.6, 0.8], [0.0, 1.0]]);
+        Ok(())
+    }
+}
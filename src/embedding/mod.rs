@@ -0,0 +1,76 @@
+//! Embedding models, for retrieval and semantic routing. Models are called
+//! through the [`Embedder`] trait;
+//! [`openai::Client`](crate::llm::openai::Client) is one backend, and a
+//! [`batch::Batcher`] can sit in front of any backend to combine requests from
+//! many agents into fewer calls.
+
+use {
+    crate::llm::openai,
+    std::{future::Future, pin::Pin, sync::Arc},
+};
+
+pub mod batch;
+#[cfg(feature = "local-embeddings")]
+pub mod local;
+
+/// A vector representing the meaning of a piece of text.
+pub type Embedding = Vec<f32>;
+
+/// A boxed future returned by [`Embedder::embed`].
+pub type EmbedFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<Embedding>, Error>> + Send + 'a>>;
+
+/// Errors that can occur when embedding text.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    OpenAi(#[from] openai::Error),
+
+    /// The error of a batched call, shared by every request in the batch.
+    #[error(transparent)]
+    Batch(Arc<Error>),
+
+    /// An error from another backend.
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// A request to embed texts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddingRequest {
+    /// The model to embed with.
+    pub model: String,
+
+    /// The texts to embed.
+    pub inputs: Vec<String>,
+}
+
+impl EmbeddingRequest {
+    /// Create a request to embed `inputs` with `model`.
+    pub fn new<I, S>(model: impl ToString, inputs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        Self {
+            model: model.to_string(),
+            inputs: inputs.into_iter().map(|input| input.to_string()).collect(),
+        }
+    }
+}
+
+/// A client for an embedding model.
+pub trait Embedder: std::fmt::Debug + Send + Sync + 'static {
+    /// Embed the request's inputs. Returns one embedding per input, in order.
+    fn embed(&self, request: EmbeddingRequest) -> EmbedFuture<'_>;
+}
+
+/// Returns the cosine similarity of two embeddings, from -1 to 1, or 0 if
+/// either is all zeros.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    match norm(a) * norm(b) {
+        norms if norms == 0.0 => 0.0,
+        norms => dot / norms,
+    }
+}
@@ -0,0 +1,238 @@
+//! Toggling capabilities at runtime. [`Flags`] turn capabilities, e.g. web
+//! access, code execution or long-term memory, on and off by default, per
+//! tenant and per agent, from a config file or as the application runs, so
+//! they can be changed without redeploying. Agents check their flags on
+//! every message: an assistant offers only the tools in enabled namespaces,
+//! and a code executor only runs code while [`CODE_EXECUTION`] is enabled.
+//!
+//! Flags that were never set are enabled.
+
+use {
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::BTreeMap,
+        io,
+        path::Path,
+        sync::{Arc, RwLock},
+    },
+};
+
+/// The flag of the tools in the `"web"` namespace.
+pub const WEB_ACCESS: &str = "web";
+
+/// The flag of code executors running code.
+pub const CODE_EXECUTION: &str = "code_execution";
+
+/// The flag of the tools in the `"memory"` namespace, e.g. a
+/// [knowledge base](crate::knowledge::KnowledgeBase)'s.
+pub const LONG_TERM_MEMORY: &str = "memory";
+
+/// Which flags are set, as loaded from and saved to config files.
+///
+/// ```json
+/// {
+///   "defaults": { "web": false },
+///   "tenants": { "acme": { "web": true } },
+///   "agents": { "coder": { "code_execution": false } }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlagConfig {
+    /// Flags set for everyone.
+    #[serde(default)]
+    pub defaults: BTreeMap<String, bool>,
+
+    /// Flags set per tenant, overriding the defaults.
+    #[serde(default)]
+    pub tenants: BTreeMap<String, BTreeMap<String, bool>>,
+
+    /// Flags set per agent name, overriding the tenant's.
+    #[serde(default)]
+    pub agents: BTreeMap<String, BTreeMap<String, bool>>,
+}
+
+/// Who a flag is set for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scope {
+    /// Everyone.
+    Default,
+
+    /// The agents of a tenant.
+    Tenant(String),
+
+    /// The agents with a name.
+    Agent(String),
+}
+
+/// Capability flags. Clones share the same flags, so changes made through
+/// one, e.g. by an admin endpoint, apply to every agent.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::{agent::assistant::AssistantBuilder, flags::{Flags, Scope, WEB_ACCESS}};
+/// # tokio_test::block_on(async {
+/// let flags = Flags::new();
+/// flags.set(Scope::Tenant("acme".to_string()), WEB_ACCESS, false);
+///
+/// let researcher = flags.for_agent("researcher").with_tenant("acme");
+/// assert!(!researcher.is_enabled(WEB_ACCESS));
+/// let assistant = AssistantBuilder::new()
+///     .with_name("researcher")
+///     .with_flags(researcher)
+///     .build();
+///
+/// // the researcher may browse again from its next message
+/// flags.set(Scope::Agent("researcher".to_string()), WEB_ACCESS, true);
+/// # });
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Flags(Arc<RwLock<FlagConfig>>);
+
+impl Flags {
+    /// Create flags with none set, so everything is enabled.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Create flags set as in `config`.
+    pub fn from_config(config: FlagConfig) -> Self {
+        Self(Arc::new(RwLock::new(config)))
+    }
+
+    /// Load flags from the JSON config file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::from_config(read(path.as_ref())?))
+    }
+
+    /// Replace the flags with those in the JSON config file at `path`, e.g.
+    /// after it changed. The flags are left as they were if it can't be
+    /// read.
+    pub fn reload(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.replace(read(path.as_ref())?);
+        Ok(())
+    }
+
+    /// Replace the flags with those in `config`.
+    pub fn replace(&self, config: FlagConfig) {
+        *self.0.write().unwrap() = config;
+    }
+
+    /// Returns the flags that are set.
+    pub fn config(&self) -> FlagConfig {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Set `flag` for `scope`.
+    pub fn set(&self, scope: Scope, flag: impl ToString, enabled: bool) {
+        let mut config = self.0.write().unwrap();
+        let flags = match scope {
+            Scope::Default => &mut config.defaults,
+            Scope::Tenant(tenant) => config.tenants.entry(tenant).or_default(),
+            Scope::Agent(agent) => config.agents.entry(agent).or_default(),
+        };
+        flags.insert(flag.to_string(), enabled);
+    }
+
+    /// Unset `flag` for `scope`, so it falls back to the broader scopes.
+    pub fn clear(&self, scope: &Scope, flag: &str) {
+        let mut config = self.0.write().unwrap();
+        let flags = match scope {
+            Scope::Default => Some(&mut config.defaults),
+            Scope::Tenant(tenant) => config.tenants.get_mut(tenant),
+            Scope::Agent(agent) => config.agents.get_mut(agent),
+        };
+        if let Some(flags) = flags {
+            flags.remove(flag);
+        }
+    }
+
+    /// Returns whether `flag` is enabled for the agent named `agent` of
+    /// `tenant`. The agent's setting wins over the tenant's, which wins over
+    /// the default.
+    pub fn is_enabled(&self, tenant: Option<&str>, agent: Option<&str>, flag: &str) -> bool {
+        let config = self.0.read().unwrap();
+        let agent = agent.and_then(|agent| config.agents.get(agent)?.get(flag));
+        let tenant = tenant.and_then(|tenant| config.tenants.get(tenant)?.get(flag));
+        agent
+            .or(tenant)
+            .or(config.defaults.get(flag))
+            .copied()
+            .unwrap_or(true)
+    }
+
+    /// Returns the flags of the agent named `agent`.
+    pub fn for_agent(&self, agent: impl ToString) -> AgentFlags {
+        AgentFlags {
+            flags: self.clone(),
+            agent: agent.to_string(),
+            tenant: None,
+        }
+    }
+}
+
+/// Reads a config file.
+fn read(path: &Path) -> io::Result<FlagConfig> {
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+/// The flags of one agent.
+#[derive(Debug, Clone)]
+pub struct AgentFlags {
+    flags: Flags,
+    agent: String,
+    tenant: Option<String>,
+}
+
+impl AgentFlags {
+    /// Apply the flags of `tenant` too.
+    pub fn with_tenant(mut self, tenant: impl ToString) -> Self {
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    /// Returns whether `flag` is enabled for the agent.
+    pub fn is_enabled(&self, flag: &str) -> bool {
+        self.flags
+            .is_enabled(self.tenant.as_deref(), Some(&self.agent), flag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, anyhow::Result};
+
+    #[test]
+    fn test_precedence() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("flags-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            r#"{ "defaults": { "web": false }, "tenants": { "acme": { "web": true } } }"#,
+        )?;
+        let flags = Flags::load(&path)?;
+        let coder = flags.for_agent("coder");
+        let acme_coder = flags.for_agent("coder").with_tenant("acme");
+        assert!(!coder.is_enabled(WEB_ACCESS));
+        assert!(acme_coder.is_enabled(WEB_ACCESS));
+        // never set
+        assert!(coder.is_enabled(CODE_EXECUTION));
+
+        flags.set(Scope::Agent("coder".to_string()), WEB_ACCESS, false);
+        assert!(!acme_coder.is_enabled(WEB_ACCESS));
+        flags.clear(&Scope::Agent("coder".to_string()), WEB_ACCESS);
+        assert!(acme_coder.is_enabled(WEB_ACCESS));
+
+        std::fs::write(
+            &path,
+            r#"{ "agents": { "coder": { "code_execution": false } } }"#,
+        )?;
+        flags.reload(&path)?;
+        assert!(coder.is_enabled(WEB_ACCESS));
+        assert!(!coder.is_enabled(CODE_EXECUTION));
+
+        std::fs::write(&path, "not json")?;
+        assert!(flags.reload(&path).is_err());
+        assert!(!coder.is_enabled(CODE_EXECUTION));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,500 @@
+//! A group chat manager that runs the chat with tools. Besides picking the
+//! next speaker, a model can call tools to add participants from standby,
+//! remove participants, change the topic or end the chat, so a team can
+//! organize itself. Guardrails limit which tools the model gets, how large
+//! or small the team can get and how many changes it makes per reply.
+
+use {
+    super::selector::{
+        pick, Action, DecideFuture, Decision, SelectFuture, Selection, Speaker, SpeakerSelector,
+    },
+    crate::{
+        llm::{
+            CompletionRequest, FunctionDefinition, HistoryMessage, LlmClient, Role, ToolCall,
+            ToolDefinition, ToolKind,
+        },
+        prompts::{Prompt, PromptPack},
+    },
+    serde_json::{json, Value},
+    std::{fmt, sync::Arc},
+};
+
+/// The most changes a manager makes after a reply when none is configured.
+pub const DEFAULT_MAX_ACTIONS: usize = 2;
+
+const SELECT_SPEAKER: &str = "select_speaker";
+const ADD_PARTICIPANT: &str = "add_participant";
+const REMOVE_PARTICIPANT: &str = "remove_participant";
+const CHANGE_TOPIC: &str = "change_topic";
+const END_CHAT: &str = "end_chat";
+
+/// A tool a [`ToolManager`] may offer its model, besides picking the next
+/// speaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ManagerTool {
+    /// Bring a participant in from standby.
+    AddParticipant,
+
+    /// Move a participant out of the chat.
+    RemoveParticipant,
+
+    /// Steer the conversation to a new topic.
+    ChangeTopic,
+
+    /// End the chat.
+    EndChat,
+}
+
+impl ManagerTool {
+    /// Every tool.
+    pub const ALL: [Self; 4] = [
+        Self::AddParticipant,
+        Self::RemoveParticipant,
+        Self::ChangeTopic,
+        Self::EndChat,
+    ];
+
+    /// Returns the name the model calls the tool by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::AddParticipant => ADD_PARTICIPANT,
+            Self::RemoveParticipant => REMOVE_PARTICIPANT,
+            Self::ChangeTopic => CHANGE_TOPIC,
+            Self::EndChat => END_CHAT,
+        }
+    }
+}
+
+impl fmt::Display for ManagerTool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A model manages the group chat through tools: after every reply it picks
+/// the next speaker and may add participants from standby, remove
+/// participants, change the topic or end the chat. Calls outside the
+/// guardrails are ignored, and if the model fails or picks no one,
+/// participants take turns.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{group_chat::{manager::{ManagerTool, ToolManager}, GroupChat}, llm::openai}, std::sync::Arc};
+/// let manager = ToolManager::new(Arc::new(openai::Client::new(None, None)), "gpt-4")
+///     .with_tools([ManagerTool::AddParticipant, ManagerTool::EndChat])
+///     .with_max_participants(4);
+/// let chat = GroupChat::new().with_speaker_selector(manager);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ToolManager {
+    client: Arc<dyn LlmClient>,
+    model: String,
+
+    /// The tools offered besides picking the next speaker.
+    tools: Vec<ManagerTool>,
+
+    /// The fewest participants the manager leaves in the chat.
+    min_participants: usize,
+
+    /// The most participants the manager brings the chat to, if limited.
+    max_participants: Option<usize>,
+
+    /// The most changes the manager makes after a reply.
+    max_actions: usize,
+
+    /// What the model is told.
+    prompts: PromptPack,
+}
+
+impl ToolManager {
+    /// Create a manager that asks `model` of `client`, offering every tool.
+    pub fn new(client: Arc<dyn LlmClient>, model: impl ToString) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            tools: ManagerTool::ALL.to_vec(),
+            min_participants: 2,
+            max_participants: None,
+            max_actions: DEFAULT_MAX_ACTIONS,
+            prompts: PromptPack::default(),
+        }
+    }
+
+    /// Only offer `tools`, besides picking the next speaker.
+    pub fn with_tools(mut self, tools: impl IntoIterator<Item = ManagerTool>) -> Self {
+        self.tools = tools.into_iter().collect();
+        self
+    }
+
+    /// Leave at least `min` participants in the chat. A chat always keeps at
+    /// least two.
+    pub fn with_min_participants(mut self, min: usize) -> Self {
+        self.min_participants = min.max(2);
+        self
+    }
+
+    /// Bring the chat to at most `max` participants.
+    pub fn with_max_participants(mut self, max: usize) -> Self {
+        self.max_participants = Some(max);
+        self
+    }
+
+    /// Make at most `max` changes to the chat after a reply.
+    pub fn with_max_actions(mut self, max: usize) -> Self {
+        self.max_actions = max;
+        self
+    }
+
+    /// Tell the model `prompts` instead of the English ones.
+    pub fn with_prompts(mut self, prompts: PromptPack) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    fn offers(&self, tool: ManagerTool) -> bool {
+        self.tools.contains(&tool)
+    }
+
+    /// Returns the conversation that asks the model to manage the chat.
+    fn request(&self, selection: &Selection<'_>) -> CompletionRequest {
+        let list = |speakers: &[Speaker]| {
+            speakers
+                .iter()
+                .map(|speaker| match &speaker.description {
+                    Some(description) => format!("{}: {description}", speaker.name),
+                    None => speaker.name.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let mut prompt = self.prompts.render(
+            Prompt::Manager,
+            &[("participants", &list(selection.speakers))],
+        );
+        if self.offers(ManagerTool::AddParticipant) && !selection.standby.is_empty() {
+            prompt.push_str("\n\n");
+            prompt.push_str(&self.prompts.render(
+                Prompt::ManagerStandby,
+                &[("standby", &list(selection.standby))],
+            ));
+        }
+        prompt.push_str("\n\n");
+        prompt.push_str(&self.prompts.render(
+            Prompt::ManagerInstructions,
+            &[("select_speaker", SELECT_SPEAKER)],
+        ));
+        if !self.tools.is_empty() {
+            let tools = self
+                .tools
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            prompt.push(' ');
+            prompt.push_str(&self.prompts.render(
+                Prompt::ManagerTools,
+                &[
+                    ("tools", &tools.join(", ")),
+                    ("max_actions", &self.max_actions.to_string()),
+                ],
+            ));
+        }
+
+        let mut messages = vec![HistoryMessage::new(Role::System, prompt)];
+        messages.extend(selection.transcript.iter().map(|message| {
+            HistoryMessage::new(Role::User, format!("{}: {}", message.name, message.content))
+        }));
+        messages.push(HistoryMessage::new(
+            Role::System,
+            self.prompts.render(
+                Prompt::ManagerReminder,
+                &[("select_speaker", SELECT_SPEAKER)],
+            ),
+        ));
+        CompletionRequest {
+            model: self.model.clone(),
+            messages,
+            tools: self.definitions(selection),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the tools offered for `selection`.
+    fn definitions(&self, selection: &Selection<'_>) -> Vec<ToolDefinition> {
+        let names = |speakers: &[Speaker]| {
+            speakers
+                .iter()
+                .map(|speaker| speaker.name.clone())
+                .collect::<Vec<_>>()
+        };
+        let name_parameter = |names: Vec<String>| {
+            json!({
+                "type": "object",
+                "properties": {"name": {"type": "string", "enum": names}},
+                "required": ["name"],
+            })
+        };
+        let definition = |name: &str, description: &str, parameters: Value| ToolDefinition {
+            kind: ToolKind::Function,
+            function: FunctionDefinition {
+                name: name.to_string(),
+                description: description.to_string(),
+                parameters,
+            },
+        };
+
+        let mut candidates = names(selection.speakers);
+        if self.offers(ManagerTool::AddParticipant) {
+            candidates.extend(names(selection.standby));
+        }
+        let mut definitions = vec![definition(
+            SELECT_SPEAKER,
+            "Choose the participant that speaks next.",
+            name_parameter(candidates),
+        )];
+        for tool in &self.tools {
+            definitions.push(match tool {
+                ManagerTool::AddParticipant if selection.standby.is_empty() => continue,
+                ManagerTool::AddParticipant => definition(
+                    ADD_PARTICIPANT,
+                    "Bring a participant into the chat.",
+                    name_parameter(names(selection.standby)),
+                ),
+                ManagerTool::RemoveParticipant => definition(
+                    REMOVE_PARTICIPANT,
+                    "Remove a participant that's no longer needed from the chat.",
+                    name_parameter(names(selection.speakers)),
+                ),
+                ManagerTool::ChangeTopic => definition(
+                    CHANGE_TOPIC,
+                    "Steer the conversation to a new topic.",
+                    json!({
+                        "type": "object",
+                        "properties": {"topic": {"type": "string"}},
+                        "required": ["topic"],
+                    }),
+                ),
+                ManagerTool::EndChat => definition(
+                    END_CHAT,
+                    "End the chat once its goal is met or can't be met.",
+                    json!({
+                        "type": "object",
+                        "properties": {"reason": {"type": "string"}},
+                        "required": ["reason"],
+                    }),
+                ),
+            });
+        }
+        definitions
+    }
+
+    /// Returns the decision the model's tool calls make, leaving out calls
+    /// outside the guardrails.
+    fn decision(&self, selection: &Selection<'_>, calls: &[ToolCall]) -> Decision {
+        let mut decision = Decision::default();
+        let mut participants = selection.speakers.len();
+        for call in calls {
+            let arguments =
+                serde_json::from_str::<Value>(&call.function.arguments).unwrap_or_default();
+            let argument = |key: &str| {
+                arguments
+                    .get(key)
+                    .and_then(Value::as_str)
+                    .map(str::trim)
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let name = argument("name");
+            let position =
+                |speakers: &[Speaker]| speakers.iter().position(|speaker| speaker.name == name);
+
+            let (tool, action) = match call.function.name.as_str() {
+                SELECT_SPEAKER => {
+                    let standby = position(selection.standby)
+                        .filter(|_| self.offers(ManagerTool::AddParticipant))
+                        .map(|i| selection.speakers.len() + i);
+                    match position(selection.speakers).or(standby) {
+                        Some(next) => decision.next = Some(next),
+                        None => tracing::warn!(name, "manager selected no participant"),
+                    }
+                    continue;
+                }
+                ADD_PARTICIPANT => (
+                    ManagerTool::AddParticipant,
+                    position(selection.standby)
+                        .filter(|_| self.max_participants.map_or(true, |max| participants < max))
+                        .map(|_| Action::AddParticipant(name.clone())),
+                ),
+                REMOVE_PARTICIPANT => (
+                    ManagerTool::RemoveParticipant,
+                    position(selection.speakers)
+                        .filter(|_| participants > self.min_participants)
+                        .map(|_| Action::RemoveParticipant(name.clone())),
+                ),
+                CHANGE_TOPIC => (
+                    ManagerTool::ChangeTopic,
+                    Some(argument("topic"))
+                        .filter(|topic| !topic.is_empty())
+                        .map(Action::ChangeTopic),
+                ),
+                END_CHAT => (
+                    ManagerTool::EndChat,
+                    Some(Action::EndChat(argument("reason"))),
+                ),
+                tool => {
+                    tracing::warn!(tool, "manager called an unknown tool");
+                    continue;
+                }
+            };
+            let action = action.filter(|action| {
+                self.offers(tool)
+                    && decision.actions.len() < self.max_actions
+                    && !decision.actions.contains(action)
+            });
+            let Some(action) = action else {
+                tracing::warn!(%tool, arguments = call.function.arguments, "manager tool call not allowed");
+                continue;
+            };
+            match action {
+                Action::AddParticipant(_) => participants += 1,
+                Action::RemoveParticipant(_) => participants -= 1,
+                _ => {}
+            }
+            decision.actions.push(action);
+        }
+        decision
+    }
+}
+
+impl SpeakerSelector for ToolManager {
+    fn select<'a>(&'a self, selection: Selection<'a>) -> SelectFuture<'a> {
+        Box::pin(async move {
+            let decision = self.decide(selection).await;
+            decision
+                .next
+                .filter(|&next| next < selection.speakers.len())
+                .unwrap_or_else(|| selection.next())
+        })
+    }
+
+    fn decide<'a>(&'a self, selection: Selection<'a>) -> DecideFuture<'a> {
+        Box::pin(async move {
+            let completion = match self.client.complete(self.request(&selection)).await {
+                Ok(completion) => completion,
+                Err(e) => {
+                    tracing::warn!(error = %e, "unable to manage chat; taking turns");
+                    return Decision::default();
+                }
+            };
+            let mut decision = self.decision(&selection, &completion.tool_calls);
+            // models sometimes answer with the name instead of calling the tool
+            if decision.next.is_none() {
+                decision.next = pick(selection.speakers, &completion.content);
+            }
+            decision
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::llm::{Completion, FunctionCall, LlmFuture},
+    };
+
+    /// Replies with the same tool calls every time.
+    #[derive(Debug)]
+    struct Calls(Vec<(&'static str, Value)>);
+
+    impl LlmClient for Calls {
+        fn complete(&self, _: CompletionRequest) -> LlmFuture<'_> {
+            let tool_calls = self
+                .0
+                .iter()
+                .enumerate()
+                .map(|(i, (name, arguments))| ToolCall {
+                    id: i.to_string(),
+                    kind: ToolKind::Function,
+                    function: FunctionCall {
+                        name: name.to_string(),
+                        arguments: arguments.to_string(),
+                    },
+                })
+                .collect();
+            Box::pin(async move {
+                Ok(Completion {
+                    tool_calls,
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    fn speakers(names: &[&str]) -> Vec<Speaker> {
+        names
+            .iter()
+            .map(|name| Speaker {
+                name: name.to_string(),
+                description: None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_guardrails() {
+        let (speakers, standby) = (speakers(&["a", "b", "c"]), speakers(&["d", "e"]));
+        let selection = Selection {
+            speakers: &speakers,
+            last: 0,
+            transcript: &[],
+            standby: &standby,
+        };
+        let calls = Calls(vec![
+            (ADD_PARTICIPANT, json!({"name": "d"})),
+            (ADD_PARTICIPANT, json!({"name": "e"})),
+            (ADD_PARTICIPANT, json!({"name": "z"})),
+            (CHANGE_TOPIC, json!({"topic": "testing"})),
+            (SELECT_SPEAKER, json!({"name": "d"})),
+        ]);
+        let manager = ToolManager::new(Arc::new(calls), "manager").with_max_participants(4);
+        let decision = manager.decide(selection).await;
+        assert_eq!(
+            decision,
+            Decision {
+                // e would exceed the limit and z isn't on standby
+                actions: vec![
+                    Action::AddParticipant("d".to_string()),
+                    Action::ChangeTopic("testing".to_string()),
+                ],
+                next: Some(3),
+            }
+        );
+
+        let calls = Calls(vec![
+            (REMOVE_PARTICIPANT, json!({"name": "a"})),
+            (REMOVE_PARTICIPANT, json!({"name": "b"})),
+            (END_CHAT, json!({"reason": "done"})),
+            (SELECT_SPEAKER, json!({"name": "e"})),
+        ]);
+        let manager = ToolManager::new(Arc::new(calls), "manager")
+            .with_tools([ManagerTool::RemoveParticipant])
+            .with_max_actions(3);
+        let decision = manager.decide(selection).await;
+        assert_eq!(
+            decision,
+            Decision {
+                // b would leave too few participants, ending the chat and
+                // picking from standby aren't offered
+                actions: vec![Action::RemoveParticipant("a".to_string())],
+                next: None,
+            }
+        );
+        let request = manager.request(&selection);
+        let tools = request
+            .tools
+            .iter()
+            .map(|tool| tool.function.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(tools, [SELECT_SPEAKER, REMOVE_PARTICIPANT]);
+    }
+}
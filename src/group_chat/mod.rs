@@ -0,0 +1,786 @@
+//! Group chats between more than two agents, mirroring AutoGen's
+//! `GroupChat` and `GroupChatManager`.
+//!
+//! A group chat relays each reply to the next speaker, chosen by a
+//! [`SpeakerSelector`], ends after a maximum number of rounds, and can end
+//! early when a termination condition is met. A [`ToolManager`] can also
+//! reorganize the chat as it goes, bringing in participants from standby,
+//! removing them, changing the topic or ending the chat.
+//!
+//! [`ToolManager`]: manager::ToolManager
+
+use {
+    crate::{
+        agent::{Actor, Message, Sender},
+        chat::{
+            self,
+            extract::Extractor,
+            limits::ReplyLimits,
+            moderation::{Moderator, DEFAULT_MAX_REVISIONS},
+            termination::{self, Keyword, Predicate, TerminationCondition},
+            ChatHandle, ChatMessage, Config, Participant,
+        },
+        cleanup::Resources,
+        prompts::PromptPack,
+        recorder::Recorder,
+    },
+    selector::SpeakerSelector,
+};
+
+pub mod manager;
+pub mod selector;
+
+/// The maximum number of rounds when none is configured.
+pub const DEFAULT_MAX_ROUNDS: usize = 10;
+
+/// A group chat between agents.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::{agent::{AgentBuilder, Message, SendError}, group_chat::GroupChat, TerminationReason};
+/// # tokio_test::block_on(async {
+/// let echo = |sender, message: Box<Message>| async move {
+///     let content = message.content.clone();
+///     message
+///         .sender
+///         .send(Box::new(Message::new(sender, content)))
+///         .await
+/// };
+/// let planner = AgentBuilder::<_, SendError<_>>::new().spawn(echo);
+/// let coder = AgentBuilder::<_, SendError<_>>::new().spawn(echo);
+/// let reviewer = AgentBuilder::<_, SendError<_>>::new().spawn(echo);
+///
+/// let outcome = GroupChat::new()
+///     .with_participant("planner", planner.sender())
+///     .with_participant("coder", coder.sender())
+///     .with_participant("reviewer", reviewer.sender())
+///     .with_max_rounds(6)
+///     .with_termination_keyword("TERMINATE")
+///     .start("Let's build a CLI.")
+///     .join()
+///     .await;
+/// assert_eq!(outcome.reason, TerminationReason::MaxTurns(6));
+/// # anyhow::Ok(())
+/// # });
+/// ```
+pub struct GroupChat {
+    /// The participants, in speaking order.
+    participants: Vec<Participant>,
+
+    /// The maximum number of replies before the chat ends.
+    max_rounds: usize,
+
+    /// Conditions that end the chat early, checked after every reply.
+    conditions: Vec<Box<dyn TerminationCondition>>,
+
+    /// Chooses the next speaker. Participants take turns if unset.
+    selector: Option<Box<dyn SpeakerSelector>>,
+
+    /// Participants the selector may bring into the chat.
+    standby: Vec<Participant>,
+
+    /// Caps the length of each reply.
+    limits: Option<ReplyLimits>,
+
+    /// What the chat is meant to achieve.
+    goal: Option<String>,
+
+    /// What the chat tells its participants.
+    prompts: PromptPack,
+
+    /// Participants that receive every message but never speak.
+    observers: Vec<Participant>,
+
+    /// Reviews each reply before it's relayed.
+    moderator: Option<Box<dyn Moderator>>,
+
+    /// The most times a reply can be sent back for revision.
+    max_revisions: usize,
+
+    /// What the chat's participants started, cleaned up if it's aborted.
+    resources: Option<Resources>,
+
+    /// Extracts variables from the chat once it ends.
+    extractor: Option<Extractor>,
+
+    /// Records each message delivered to a participant.
+    recorder: Option<Recorder>,
+
+    /// The chat's id.
+    conversation_id: Option<String>,
+}
+
+impl Default for GroupChat {
+    fn default() -> Self {
+        Self {
+            participants: Vec::new(),
+            max_rounds: DEFAULT_MAX_ROUNDS,
+            conditions: Vec::new(),
+            selector: None,
+            standby: Vec::new(),
+            limits: None,
+            goal: None,
+            prompts: PromptPack::default(),
+            observers: Vec::new(),
+            moderator: None,
+            max_revisions: DEFAULT_MAX_REVISIONS,
+            resources: None,
+            extractor: None,
+            recorder: None,
+            conversation_id: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for GroupChat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GroupChat")
+            .field("participants", &self.participants)
+            .field("max_rounds", &self.max_rounds)
+            .field("conditions", &self.conditions)
+            .field("selector", &self.selector.is_some())
+            .field("standby", &self.standby)
+            .field("limits", &self.limits)
+            .field("goal", &self.goal)
+            .field("prompts", &self.prompts.language)
+            .field("observers", &self.observers)
+            .field("moderator", &self.moderator.is_some())
+            .field("max_revisions", &self.max_revisions)
+            .field("resources", &self.resources)
+            .field("extractor", &self.extractor)
+            .field("recorder", &self.recorder)
+            .field("conversation_id", &self.conversation_id)
+            .finish()
+    }
+}
+
+impl GroupChat {
+    /// Create a group chat with no participants.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a participant. Unless a speaker selector is set, participants
+    /// speak in the order they are added.
+    pub fn with_participant(mut self, name: impl ToString, sender: Sender<Box<Message>>) -> Self {
+        self.participants.push(Participant {
+            name: name.to_string(),
+            description: None,
+            sender,
+        });
+        self
+    }
+
+    /// Add a participant that doesn't take part in the chat until a
+    /// selector such as [`ToolManager`](manager::ToolManager) adds it.
+    pub fn with_standby(mut self, name: impl ToString, sender: Sender<Box<Message>>) -> Self {
+        self.standby.push(Participant {
+            name: name.to_string(),
+            description: None,
+            sender,
+        });
+        self
+    }
+
+    /// Add an observer, e.g. a logger, evaluator or safety monitor. Observers
+    /// receive a copy of every message in the chat, named after who wrote it,
+    /// but are never chosen to speak and their replies are ignored.
+    pub fn with_observer(mut self, name: impl ToString, sender: Sender<Box<Message>>) -> Self {
+        self.observers.push(Participant {
+            name: name.to_string(),
+            description: None,
+            sender,
+        });
+        self
+    }
+
+    /// Describe what the participant named `name` does, for speaker
+    /// selectors such as [`LlmSelector`](selector::LlmSelector). The
+    /// participant is also told its role with every message, so
+    /// assistants act their part without repeating it in their own
+    /// system prompts.
+    pub fn with_description(mut self, name: &str, description: impl ToString) -> Self {
+        let mut participants = self.participants.iter_mut().chain(&mut self.standby);
+        match participants.find(|p| p.name == name) {
+            Some(participant) => participant.description = Some(description.to_string()),
+            None => tracing::warn!(name, "no participant to describe"),
+        }
+        self
+    }
+
+    /// Set what the chat is meant to achieve. Every participant is told the
+    /// goal with every message.
+    pub fn with_goal(mut self, goal: impl ToString) -> Self {
+        self.goal = Some(goal.to_string());
+        self
+    }
+
+    /// Tell participants who they are, the goal and what happens in the
+    /// chat with `prompts` instead of the English ones. Selectors and
+    /// moderators that ask a model are given their prompts separately.
+    pub fn with_prompts(mut self, prompts: PromptPack) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    /// Extract variables from the chat with `extractor` once it ends, into
+    /// [`ChatOutcome::variables`](chat::ChatOutcome::variables).
+    pub fn with_extractor(mut self, extractor: Extractor) -> Self {
+        self.extractor = Some(extractor);
+        self
+    }
+
+    /// Record each message delivered to a participant, with who it came
+    /// from, in `recorder`.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Identify the chat by `id`, e.g. in metrics, instead of a random id.
+    pub fn with_conversation_id(mut self, id: impl ToString) -> Self {
+        self.conversation_id = Some(id.to_string());
+        self
+    }
+
+    /// Choose each next speaker with `selector`.
+    pub fn with_speaker_selector(mut self, selector: impl SpeakerSelector) -> Self {
+        self.selector = Some(Box::new(selector));
+        self
+    }
+
+    /// Add an actor as a participant, named after the actor or, if it has no
+    /// name, its id.
+    pub fn with_actor<A>(mut self, actor: &A) -> Self
+    where
+        A: Actor<Message = Message>,
+    {
+        self.participants.push(Participant::of(actor));
+        self
+    }
+
+    /// Set the maximum number of replies before the chat ends.
+    pub fn with_max_rounds(mut self, max_rounds: usize) -> Self {
+        self.max_rounds = max_rounds;
+        self
+    }
+
+    /// Cap the length of each reply with `limits`. Replies over them are
+    /// cut short or sent back to their speaker to try again, and the speaker
+    /// is told why.
+    pub fn with_limits(mut self, limits: ReplyLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Clean up `resources` when the chat is aborted with
+    /// [`ChatHandle::abort`].
+    pub fn with_resources(mut self, resources: Resources) -> Self {
+        self.resources = Some(resources);
+        self
+    }
+
+    /// Review each reply with `moderator` before it's relayed. Replies it
+    /// sends back are revised by their speaker, and replies it vetoes are
+    /// dropped; either way the speaker is told why.
+    pub fn with_moderator(mut self, moderator: impl Moderator) -> Self {
+        self.moderator = Some(Box::new(moderator));
+        self
+    }
+
+    /// Set the most times a reply can be sent back for revision before it's
+    /// vetoed. Defaults to [`DEFAULT_MAX_REVISIONS`].
+    pub fn with_max_revisions(mut self, max_revisions: usize) -> Self {
+        self.max_revisions = max_revisions;
+        self
+    }
+
+    /// End the chat as completed when a reply satisfies `condition`.
+    pub fn with_termination_condition<F>(self, condition: F) -> Self
+    where
+        F: Fn(&ChatMessage) -> bool + Send + Sync + 'static,
+    {
+        self.with_termination(Predicate(move |transcript: &[ChatMessage]| {
+            transcript.last().is_some_and(&condition)
+        }))
+    }
+
+    /// End the chat when a reply contains `keyword`.
+    pub fn with_termination_keyword(self, keyword: impl ToString) -> Self {
+        self.with_termination(Keyword::new(keyword))
+    }
+
+    /// End the chat when `condition` is met. The chat ends when any of its
+    /// conditions is met.
+    pub fn with_termination(mut self, condition: impl TerminationCondition) -> Self {
+        self.conditions.push(Box::new(condition));
+        self
+    }
+
+    /// Start the chat. The first participant opens the conversation by
+    /// sending `message` to the participant the selector chooses, by default
+    /// the second participant; from then on every reply is relayed to the
+    /// next speaker.
+    pub fn start(self, message: impl ToString) -> ChatHandle {
+        let config = Config {
+            max_turns: Some(self.max_rounds),
+            condition: termination::any(self.conditions),
+            selector: self.selector,
+            standby: self.standby,
+            limits: self.limits,
+            goal: self.goal,
+            prompts: self.prompts,
+            observers: self.observers,
+            moderator: self.moderator,
+            max_revisions: self.max_revisions,
+            resources: self.resources,
+            extractor: self.extractor,
+            recorder: self.recorder,
+            conversation_id: self.conversation_id,
+            ..Default::default()
+        };
+        chat::spawn(self.participants, config, message.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{agent::SendError, chat::TerminationReason, prompts::Prompt, Agent},
+        anyhow::Result,
+        uuid::Uuid,
+    };
+
+    /// Spawns an agent that replies with its name.
+    fn spawn_named(name: &'static str) -> Agent<Box<Message>, SendError<Box<Message>>> {
+        Agent::spawn(
+            Uuid::new_v4(),
+            Some(name.to_string()),
+            move |sender, message: Box<Message>| async move {
+                message
+                    .sender
+                    .send(Box::new(Message::new(sender, name.to_string())))
+                    .await
+            },
+        )
+    }
+
+    fn speakers(outcome: &chat::ChatOutcome) -> Vec<&str> {
+        outcome.transcript.iter().map(|m| m.name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_until_max_rounds() -> Result<()> {
+        let (a, b, c) = (spawn_named("a"), spawn_named("b"), spawn_named("c"));
+
+        let outcome = GroupChat::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_participant("c", c.sender())
+            .with_max_rounds(4)
+            .start("hello")
+            .join()
+            .await;
+
+        assert_eq!(outcome.reason, TerminationReason::MaxTurns(4));
+        assert_eq!(speakers(&outcome), ["a", "b", "c", "a", "b"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_moderator() -> Result<()> {
+        use crate::chat::moderation::{Review, ReviewFuture, Verdict};
+
+        /// Sends "draft" back for revision and vetoes "off topic".
+        struct Strict;
+
+        impl Moderator for Strict {
+            fn review<'a>(&'a self, review: Review<'a>) -> ReviewFuture<'a> {
+                let verdict = match review.reply {
+                    "draft" => Verdict::Revise("finish it".to_string()),
+                    "off topic" => Verdict::Veto("it's off topic".to_string()),
+                    _ => Verdict::Approve,
+                };
+                Box::pin(std::future::ready(verdict))
+            }
+        }
+
+        /// Replies with a draft, then with what it's asked to revise.
+        fn spawn_drafter() -> Agent<Box<Message>, SendError<Box<Message>>> {
+            Agent::spawn(
+                Uuid::new_v4(),
+                None,
+                |sender, message: Box<Message>| async move {
+                    let reply = match message.content.to_string() {
+                        content if content.starts_with("Your reply needs revision") => "done",
+                        _ => "draft",
+                    };
+                    message
+                        .sender
+                        .send(Box::new(Message::new(sender, reply)))
+                        .await
+                },
+            )
+        }
+        let (a, b, c) = (spawn_named("a"), spawn_drafter(), spawn_named("off topic"));
+
+        let chat = GroupChat::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_participant("c", c.sender())
+            .with_moderator(Strict)
+            .with_max_rounds(3)
+            .start("hello");
+        let mut events = chat.subscribe();
+        let outcome = chat.join().await;
+
+        // c's reply is dropped, so a picks up from b's revised reply
+        assert_eq!(speakers(&outcome), ["a", "b", "a"]);
+        assert_eq!(outcome.transcript[1].content, "done");
+        assert_eq!(outcome.reason, TerminationReason::MaxTurns(3));
+        let mut verdicts = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let chat::ChatEvent::Moderated { verdict, .. } = event {
+                verdicts.push(verdict);
+            }
+        }
+        assert_eq!(
+            verdicts,
+            [
+                Verdict::Revise("finish it".to_string()),
+                Verdict::Veto("it's off topic".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_observers() -> Result<()> {
+        let (a, b) = (spawn_named("a"), spawn_named("b"));
+        // the observer replies too, to show its replies are ignored
+        let observer = spawn_named("observer");
+        let (log, mut logged) = crate::agent::channel::<Box<Message>>(None);
+        let logger = Agent::spawn(Uuid::new_v4(), None, move |_, message: Box<Message>| {
+            let log = log.clone();
+            async move { log.send(message).await }
+        });
+
+        let outcome = GroupChat::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_observer("observer", observer.sender())
+            .with_observer("logger", logger.sender())
+            .with_max_rounds(3)
+            .start("hello")
+            .join()
+            .await;
+
+        assert_eq!(speakers(&outcome), ["a", "b", "a", "b"]);
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            let message = logged.recv().await.unwrap();
+            seen.push((message.name.unwrap(), message.content.to_string()));
+        }
+        assert_eq!(
+            seen,
+            [("a", "hello"), ("b", "b"), ("a", "a"), ("b", "b")]
+                .map(|(name, content)| (name.to_string(), content.to_string()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_role_prompts() -> Result<()> {
+        /// Spawns an agent that replies with its instructions.
+        fn spawn_obedient() -> Agent<Box<Message>, SendError<Box<Message>>> {
+            Agent::spawn(
+                Uuid::new_v4(),
+                None,
+                |sender, message: Box<Message>| async move {
+                    let prompt = message.system_prompt.clone().unwrap_or_default();
+                    message
+                        .sender
+                        .send(Box::new(Message::new(sender, prompt)))
+                        .await
+                },
+            )
+        }
+        let (a, b, c) = (spawn_obedient(), spawn_obedient(), spawn_obedient());
+
+        let outcome = GroupChat::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_participant("c", c.sender())
+            .with_description("b", "writes code")
+            .with_goal("ship a CLI")
+            .with_max_rounds(2)
+            .start("hello")
+            .join()
+            .await;
+
+        let replies = outcome.transcript[1..]
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            replies,
+            [
+                "You are b in a group chat with a, c.\nYour role: writes code\nThe goal of the chat: ship a CLI",
+                "You are c in a group chat with a, b.\nThe goal of the chat: ship a CLI",
+            ]
+        );
+
+        let prompts = PromptPack::new("de")
+            .with_prompt(Prompt::Participant, "Du bist {name}, mit {others}.")
+            .with_prompt(Prompt::ChatGoal, "Ziel: {goal}");
+        let outcome = GroupChat::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_goal("ship a CLI")
+            .with_prompts(prompts)
+            .with_max_rounds(1)
+            .start("hello")
+            .join()
+            .await;
+        assert_eq!(
+            outcome.transcript[1].content,
+            "Du bist b, mit a.\nZiel: ship a CLI"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_termination_keyword() -> Result<()> {
+        let (a, b, c) = (spawn_named("a"), spawn_named("b"), spawn_named("TERMINATE"));
+
+        let outcome = GroupChat::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_participant("c", c.sender())
+            .with_termination_condition(|message| message.content == "never")
+            .with_termination_keyword("TERMINATE")
+            .start("hello")
+            .join()
+            .await;
+
+        assert_eq!(
+            outcome.reason,
+            TerminationReason::Keyword("TERMINATE".to_string())
+        );
+        assert_eq!(speakers(&outcome), ["a", "b", "c"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_speaker_selectors() -> Result<()> {
+        use {
+            crate::llm::{Completion, CompletionRequest, LlmClient, LlmFuture},
+            selector::{LlmSelector, Manual},
+            std::sync::Arc,
+        };
+
+        /// Always picks the reviewer, if it's described.
+        #[derive(Debug)]
+        struct Picker;
+
+        impl LlmClient for Picker {
+            fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+                let described = request.messages[0].content.contains("c: reviews code");
+                Box::pin(async move {
+                    Ok(Completion {
+                        content: if described { "c" } else { "?" }.to_string(),
+                        ..Default::default()
+                    })
+                })
+            }
+        }
+
+        let (a, b, c) = (spawn_named("a"), spawn_named("b"), spawn_named("c"));
+        let group = || {
+            GroupChat::new()
+                .with_participant("a", a.sender())
+                .with_participant("b", b.sender())
+                .with_participant("c", c.sender())
+                .with_max_rounds(3)
+        };
+
+        // always answer the opener
+        let outcome = group()
+            .with_speaker_selector(Manual(
+                |selection: selector::Selection<'_>| {
+                    if selection.last == 0 {
+                        2
+                    } else {
+                        0
+                    }
+                },
+            ))
+            .start("hello")
+            .join()
+            .await;
+        assert_eq!(speakers(&outcome), ["a", "c", "a", "c"]);
+
+        let outcome = group()
+            .with_description("c", "reviews code")
+            .with_speaker_selector(LlmSelector::new(Arc::new(Picker), "picker"))
+            .start("hello")
+            .join()
+            .await;
+        assert_eq!(speakers(&outcome), ["a", "c", "c", "c"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tool_manager() -> Result<()> {
+        use {
+            crate::llm::{
+                Completion, CompletionRequest, FunctionCall, LlmClient, LlmFuture, ToolCall,
+            },
+            manager::ToolManager,
+            serde_json::json,
+            std::sync::Arc,
+        };
+
+        /// Brings in d, then moves on to testing without b, then ends.
+        #[derive(Debug)]
+        struct Script;
+
+        impl LlmClient for Script {
+            fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+                // the prompt and instruction surround the transcript
+                let calls = match request.messages.len() - 2 {
+                    1 => vec![
+                        ("add_participant", json!({"name": "d"})),
+                        ("select_speaker", json!({"name": "d"})),
+                    ],
+                    3 => vec![
+                        ("change_topic", json!({"topic": "tests"})),
+                        ("remove_participant", json!({"name": "b"})),
+                        ("select_speaker", json!({"name": "c"})),
+                    ],
+                    _ => vec![("end_chat", json!({"reason": "done"}))],
+                };
+                let tool_calls = calls
+                    .into_iter()
+                    .map(|(name, arguments)| ToolCall {
+                        id: name.to_string(),
+                        kind: Default::default(),
+                        function: FunctionCall {
+                            name: name.to_string(),
+                            arguments: arguments.to_string(),
+                        },
+                    })
+                    .collect();
+                Box::pin(async move {
+                    Ok(Completion {
+                        tool_calls,
+                        ..Default::default()
+                    })
+                })
+            }
+        }
+
+        let (a, b, c, d) = (
+            spawn_named("a"),
+            spawn_named("b"),
+            spawn_named("c"),
+            spawn_named("d"),
+        );
+        let chat = GroupChat::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_participant("c", c.sender())
+            .with_standby("d", d.sender())
+            .with_speaker_selector(ToolManager::new(Arc::new(Script), "manager"))
+            .start("hello");
+        let mut events = chat.subscribe();
+        let outcome = chat.join().await;
+
+        assert_eq!(outcome.reason, TerminationReason::Completed);
+        assert_eq!(
+            speakers(&outcome),
+            ["a", "manager", "d", "manager", "manager", "c", "manager"]
+        );
+        let announcements = outcome.transcript[1..5]
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            announcements,
+            [
+                "d joined the chat.",
+                "d",
+                "The topic is now: tests",
+                "b left the chat."
+            ]
+        );
+        let mut actions = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let chat::ChatEvent::Action(action) = event {
+                actions.push(action.to_string());
+            }
+        }
+        assert_eq!(
+            actions,
+            [
+                "added d",
+                "changed the topic to \"tests\"",
+                "removed b",
+                "ended the chat: done"
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_limits() -> Result<()> {
+        use {crate::chat::limits::ReplyLimits, tokio::sync::mpsc};
+
+        // b rambles, and reports what it's told
+        let (received, mut told) = mpsc::unbounded_channel();
+        let b = Agent::spawn(
+            Uuid::new_v4(),
+            Some("b".to_string()),
+            move |sender, message: Box<Message>| {
+                let received = received.clone();
+                async move {
+                    received.send(message.content.to_string()).ok();
+                    message
+                        .sender
+                        .send(Box::new(Message::new(sender, "b".repeat(20))))
+                        .await
+                }
+            },
+        );
+        let (a, c) = (spawn_named("a"), spawn_named("c"));
+
+        let outcome = GroupChat::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_participant("c", c.sender())
+            .with_limits(ReplyLimits::new().with_max_chars(10).with_rejection(1))
+            .with_max_rounds(4)
+            .start("hello")
+            .join()
+            .await;
+
+        assert_eq!(speakers(&outcome), ["a", "b", "c", "a", "b"]);
+        assert_eq!(outcome.transcript[1].content, "b".repeat(10));
+        let violation = "the reply was 20 characters, over the limit of 10";
+        assert_eq!(told.recv().await.as_deref(), Some("hello"));
+        assert_eq!(
+            told.recv().await,
+            Some(format!(
+                "Your reply was rejected: {violation}. Reply again within the limit."
+            ))
+        );
+        assert_eq!(
+            told.recv().await,
+            Some(format!(
+                "a\n\nYour last reply was cut short: {violation}. Keep your replies within the limit."
+            ))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+//! Choosing who speaks next in a group chat. After every reply the chat asks
+//! its [`SpeakerSelector`] for the next speaker; by default participants take
+//! turns with [`RoundRobin`]. A selector can also [`decide`] to change the
+//! chat itself, e.g. bring in another participant or end the chat, as a
+//! [`ToolManager`](super::manager::ToolManager) does.
+//!
+//! [`decide`]: SpeakerSelector::decide
+
+use {
+    crate::{
+        chat::ChatMessage,
+        llm::{CompletionRequest, HistoryMessage, LlmClient, Role},
+        prompts::{Prompt, PromptPack},
+    },
+    rand::Rng,
+    std::{fmt, future::Future, pin::Pin, sync::Arc},
+};
+
+/// A boxed future returned by [`SpeakerSelector::select`].
+pub type SelectFuture<'a> = Pin<Box<dyn Future<Output = usize> + Send + 'a>>;
+
+/// A boxed future returned by [`SpeakerSelector::decide`].
+pub type DecideFuture<'a> = Pin<Box<dyn Future<Output = Decision> + Send + 'a>>;
+
+/// A participant, as seen by a [`SpeakerSelector`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Speaker {
+    /// The participant's name.
+    pub name: String,
+
+    /// What the participant does, if described.
+    pub description: Option<String>,
+}
+
+/// What a [`SpeakerSelector`] chooses from.
+#[derive(Debug, Clone, Copy)]
+pub struct Selection<'a> {
+    /// The participants, in the order they were added.
+    pub speakers: &'a [Speaker],
+
+    /// The index of the participant that spoke last. The participant that
+    /// opened the chat speaks first.
+    pub last: usize,
+
+    /// Every message so far.
+    pub transcript: &'a [ChatMessage],
+
+    /// The participants that may be added to the chat, but aren't in it.
+    pub standby: &'a [Speaker],
+}
+
+impl Selection<'_> {
+    /// Returns the participant after the last speaker.
+    pub fn next(&self) -> usize {
+        (self.last + 1) % self.speakers.len()
+    }
+}
+
+/// A change to a running group chat, made by its manager.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Bring the standby participant with this name into the chat.
+    AddParticipant(String),
+
+    /// Move the participant with this name out of the chat, onto standby.
+    /// A chat keeps at least two participants.
+    RemoveParticipant(String),
+
+    /// Steer the conversation to a new topic, told to the next speaker.
+    ChangeTopic(String),
+
+    /// End the chat as completed, for the given reason.
+    EndChat(String),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AddParticipant(name) => write!(f, "added {name}"),
+            Self::RemoveParticipant(name) => write!(f, "removed {name}"),
+            Self::ChangeTopic(topic) => write!(f, "changed the topic to {topic:?}"),
+            Self::EndChat(reason) => write!(f, "ended the chat: {reason}"),
+        }
+    }
+}
+
+/// What happens after a reply: changes to the chat, then the next speaker.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Decision {
+    /// Changes to apply to the chat, in order.
+    pub actions: Vec<Action>,
+
+    /// The index of the participant that speaks next, counting the
+    /// participants in the chat and then those on standby, so a participant
+    /// added by `actions` can speak right away. If unset or out of range,
+    /// the participant after the last speaker speaks next.
+    pub next: Option<usize>,
+}
+
+/// Chooses the next speaker in a group chat.
+pub trait SpeakerSelector: Send + Sync + 'static {
+    /// Returns the index of the participant that speaks next. Indexes out
+    /// of range fall back to the participant after the last speaker.
+    fn select<'a>(&'a self, selection: Selection<'a>) -> SelectFuture<'a>;
+
+    /// Returns the changes to make to the chat and who speaks next. By
+    /// default the chat doesn't change and [`select`](Self::select) picks
+    /// the next speaker.
+    fn decide<'a>(&'a self, selection: Selection<'a>) -> DecideFuture<'a> {
+        Box::pin(async move {
+            Decision {
+                actions: Vec::new(),
+                next: Some(self.select(selection).await),
+            }
+        })
+    }
+}
+
+/// Participants take turns in the order they were added.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoundRobin;
+
+impl SpeakerSelector for RoundRobin {
+    fn select<'a>(&'a self, selection: Selection<'a>) -> SelectFuture<'a> {
+        Box::pin(std::future::ready(selection.next()))
+    }
+}
+
+/// A random participant other than the last speaker speaks next.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Random;
+
+impl SpeakerSelector for Random {
+    fn select<'a>(&'a self, selection: Selection<'a>) -> SelectFuture<'a> {
+        // pick from everyone but the last speaker, then skip over them
+        let others = selection.speakers.len().saturating_sub(1).max(1);
+        let pick = rand::thread_rng().gen_range(0..others);
+        let next = if selection.speakers.len() > 1 && pick >= selection.last {
+            pick + 1
+        } else {
+            pick
+        };
+        Box::pin(std::future::ready(next))
+    }
+}
+
+/// The next speaker is chosen by a function, e.g. one that asks a person.
+pub struct Manual<F>(pub F);
+
+impl<F> fmt::Debug for Manual<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Manual(..)")
+    }
+}
+
+impl<F> SpeakerSelector for Manual<F>
+where
+    F: Fn(Selection<'_>) -> usize + Send + Sync + 'static,
+{
+    fn select<'a>(&'a self, selection: Selection<'a>) -> SelectFuture<'a> {
+        Box::pin(std::future::ready((self.0)(selection)))
+    }
+}
+
+/// A model picks the next speaker from the participants' names and
+/// descriptions and the conversation so far, like AutoGen's
+/// `GroupChatManager`. If the model fails or names no participant, the
+/// participant after the last speaker speaks next.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{group_chat::{selector::LlmSelector, GroupChat}, llm::openai}, std::sync::Arc};
+/// let chat = GroupChat::new()
+///     .with_speaker_selector(LlmSelector::new(Arc::new(openai::Client::new(None, None)), "gpt-4"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct LlmSelector {
+    client: Arc<dyn LlmClient>,
+    model: String,
+    prompts: PromptPack,
+}
+
+impl LlmSelector {
+    /// Create a selector that asks `model` of `client`.
+    pub fn new(client: Arc<dyn LlmClient>, model: impl ToString) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            prompts: PromptPack::default(),
+        }
+    }
+
+    /// Ask the model with `prompts` instead of the English ones.
+    pub fn with_prompts(mut self, prompts: PromptPack) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    /// Returns the conversation that asks the model for the next speaker.
+    fn request(&self, selection: &Selection<'_>) -> CompletionRequest {
+        let roles = selection
+            .speakers
+            .iter()
+            .map(|speaker| match &speaker.description {
+                Some(description) => format!("{}: {description}", speaker.name),
+                None => speaker.name.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let names = selection
+            .speakers
+            .iter()
+            .map(|speaker| speaker.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let mut messages = vec![HistoryMessage::new(
+            Role::System,
+            self.prompts.render(
+                Prompt::SpeakerSelection,
+                &[("roles", &roles), ("names", &names)],
+            ),
+        )];
+        messages.extend(selection.transcript.iter().map(|message| {
+            HistoryMessage::new(Role::User, format!("{}: {}", message.name, message.content))
+        }));
+        messages.push(HistoryMessage::new(
+            Role::System,
+            self.prompts
+                .render(Prompt::SpeakerSelectionReminder, &[("names", &names)]),
+        ));
+        CompletionRequest {
+            model: self.model.clone(),
+            messages,
+            ..Default::default()
+        }
+    }
+}
+
+impl SpeakerSelector for LlmSelector {
+    fn select<'a>(&'a self, selection: Selection<'a>) -> SelectFuture<'a> {
+        Box::pin(async move {
+            let reply = match self.client.complete(self.request(&selection)).await {
+                Ok(completion) => completion.content,
+                Err(e) => {
+                    tracing::warn!(error = %e, "unable to select speaker; taking turns");
+                    return selection.next();
+                }
+            };
+            match pick(selection.speakers, &reply) {
+                Some(speaker) => speaker,
+                None => {
+                    tracing::warn!(reply, "model named no participant; taking turns");
+                    selection.next()
+                }
+            }
+        })
+    }
+}
+
+/// Returns the participant the model's reply names: an exact match, or
+/// else the longest name the reply mentions.
+pub(crate) fn pick(speakers: &[Speaker], reply: &str) -> Option<usize> {
+    let reply = reply.trim();
+    speakers
+        .iter()
+        .position(|speaker| speaker.name == reply)
+        .or_else(|| {
+            speakers
+                .iter()
+                .enumerate()
+                .filter(|(_, speaker)| reply.contains(speaker.name.as_str()))
+                .max_by_key(|(_, speaker)| speaker.name.len())
+                .map(|(i, _)| i)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn speakers(names: &[&str]) -> Vec<Speaker> {
+        names
+            .iter()
+            .map(|name| Speaker {
+                name: name.to_string(),
+                description: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pick() {
+        let speakers = speakers(&["coder", "senior coder", "critic"]);
+        assert_eq!(pick(&speakers, " critic\n"), Some(2));
+        assert_eq!(pick(&speakers, "The senior coder should go."), Some(1));
+        assert_eq!(pick(&speakers, "nobody"), None);
+    }
+
+    #[tokio::test]
+    async fn test_random_skips_last_speaker() {
+        let speakers = speakers(&["a", "b", "c"]);
+        for _ in 0..50 {
+            let selection = Selection {
+                speakers: &speakers,
+                last: 1,
+                transcript: &[],
+                standby: &[],
+            };
+            let next = Random.select(selection).await;
+            assert!(next == 0 || next == 2);
+        }
+    }
+}
@@ -0,0 +1,416 @@
+//! Knowledge shared across conversations. A [`KnowledgeBase`] holds what a
+//! team has learned: findings, searched by meaning, and facts, looked up by
+//! key. Each entry records who added it and in which conversation, so agents
+//! in later chats can weigh what they find. Agents read and write the
+//! knowledge base through the [`Remember`] and [`Recall`] tools.
+
+use {
+    crate::{
+        embedding::{self, cosine_similarity, Embedder, Embedding, EmbeddingRequest},
+        tools::{Error as ToolError, Tool, ToolFuture},
+    },
+    serde_json::{json, Value},
+    std::{
+        collections::HashMap,
+        fmt::Write,
+        sync::{Arc, Mutex},
+        time::SystemTime,
+    },
+    uuid::Uuid,
+};
+
+/// The name of the [`Remember`] tool.
+pub const REMEMBER_TOOL: &str = "remember";
+
+/// The name of the [`Recall`] tool.
+pub const RECALL_TOOL: &str = "recall";
+
+/// The number of findings [`Recall`] returns when none is configured.
+pub const DEFAULT_RECALL_LIMIT: usize = 5;
+
+/// Where an entry in a knowledge base came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    /// The agent that added the entry.
+    pub author: String,
+
+    /// The conversation the entry was learned in, if known.
+    pub conversation: Option<String>,
+
+    /// When the entry was added.
+    pub recorded_at: SystemTime,
+}
+
+impl Provenance {
+    /// Create a provenance for an entry `author` adds now.
+    pub fn new(author: impl ToString) -> Self {
+        Self {
+            author: author.to_string(),
+            conversation: None,
+            recorded_at: SystemTime::now(),
+        }
+    }
+
+    /// Set the conversation the entry was learned in.
+    pub fn with_conversation(mut self, conversation: impl ToString) -> Self {
+        self.conversation = Some(conversation.to_string());
+        self
+    }
+}
+
+/// A finding in a knowledge base.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    /// The finding's id.
+    pub id: Uuid,
+
+    /// What was found.
+    pub content: String,
+
+    /// Where the finding came from.
+    pub provenance: Provenance,
+}
+
+/// A finding matching a search, and how well it matches, from -1 to 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub finding: Finding,
+    pub score: f32,
+}
+
+/// A fact in a knowledge base, stored under a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fact {
+    /// The fact's value.
+    pub value: String,
+
+    /// Where the fact came from.
+    pub provenance: Provenance,
+}
+
+/// The entries of a knowledge base.
+#[derive(Debug, Default)]
+struct Entries {
+    findings: Vec<(Finding, Embedding)>,
+    facts: HashMap<String, Fact>,
+}
+
+/// Knowledge shared by a team's agents across conversations. Clones share
+/// the same entries.
+///
+/// Usage:
+/// ```no_run
+/// # use {autogen_rs::{knowledge::{KnowledgeBase, Provenance}, llm::openai}, std::sync::Arc};
+/// # tokio_test::block_on(async {
+/// let knowledge = KnowledgeBase::new(Arc::new(openai::Client::new(None, None)), "text-embedding-3-small");
+/// knowledge
+///     .record("The CI runs on nightly Rust.", Provenance::new("coder").with_conversation("ci-fix"))
+///     .await?;
+/// let hits = knowledge.search("Which toolchain does CI use?", 3).await?;
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct KnowledgeBase {
+    embedder: Arc<dyn Embedder>,
+    model: String,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl KnowledgeBase {
+    /// Create an empty knowledge base that searches findings by their
+    /// embeddings from `model` of `embedder`.
+    pub fn new(embedder: Arc<dyn Embedder>, model: impl ToString) -> Self {
+        Self {
+            embedder,
+            model: model.to_string(),
+            entries: Default::default(),
+        }
+    }
+
+    /// Add a finding. Returns its id.
+    pub async fn record(
+        &self,
+        content: impl ToString,
+        provenance: Provenance,
+    ) -> Result<Uuid, embedding::Error> {
+        let content = content.to_string();
+        let embedding = self.embed(&content).await?;
+        let finding = Finding {
+            id: Uuid::new_v4(),
+            content,
+            provenance,
+        };
+        let id = finding.id;
+        self.entries
+            .lock()
+            .unwrap()
+            .findings
+            .push((finding, embedding));
+        Ok(id)
+    }
+
+    /// Returns the `limit` findings most like `query`, best first.
+    pub async fn search(&self, query: &str, limit: usize) -> Result<Vec<Hit>, embedding::Error> {
+        let query = self.embed(query).await?;
+        let entries = self.entries.lock().unwrap();
+        let mut hits = entries
+            .findings
+            .iter()
+            .map(|(finding, embedding)| Hit {
+                finding: finding.clone(),
+                score: cosine_similarity(&query, embedding),
+            })
+            .collect::<Vec<_>>();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(limit);
+        Ok(hits)
+    }
+
+    /// Returns every finding, in the order they were added.
+    pub fn findings(&self) -> Vec<Finding> {
+        let entries = self.entries.lock().unwrap();
+        entries.findings.iter().map(|(f, _)| f.clone()).collect()
+    }
+
+    /// Store `value` under `key`. Returns the fact it replaces, if any.
+    pub fn set(
+        &self,
+        key: impl ToString,
+        value: impl ToString,
+        provenance: Provenance,
+    ) -> Option<Fact> {
+        let fact = Fact {
+            value: value.to_string(),
+            provenance,
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .facts
+            .insert(key.to_string(), fact)
+    }
+
+    /// Returns the fact stored under `key`, if any.
+    pub fn get(&self, key: &str) -> Option<Fact> {
+        self.entries.lock().unwrap().facts.get(key).cloned()
+    }
+
+    /// Returns a tool that adds findings and facts on behalf of `provenance`'s
+    /// author, in its conversation.
+    pub fn remember_tool(&self, provenance: Provenance) -> Remember {
+        Remember {
+            knowledge: self.clone(),
+            provenance,
+        }
+    }
+
+    /// Returns a tool that searches findings and looks up facts.
+    pub fn recall_tool(&self) -> Recall {
+        Recall {
+            knowledge: self.clone(),
+            limit: DEFAULT_RECALL_LIMIT,
+        }
+    }
+
+    async fn embed(&self, text: &str) -> Result<Embedding, embedding::Error> {
+        let request = EmbeddingRequest::new(&self.model, [text]);
+        let mut embeddings = self.embedder.embed(request).await?;
+        embeddings
+            .pop()
+            .ok_or_else(|| embedding::Error::Other("no embedding returned".into()))
+    }
+}
+
+/// Describes where an entry came from, for a model.
+fn cite(provenance: &Provenance) -> String {
+    match &provenance.conversation {
+        Some(conversation) => format!("from {} in {conversation}", provenance.author),
+        None => format!("from {}", provenance.author),
+    }
+}
+
+/// A tool that adds a finding, or a fact under a key, to a knowledge base.
+#[derive(Debug, Clone)]
+pub struct Remember {
+    knowledge: KnowledgeBase,
+    provenance: Provenance,
+}
+
+impl Tool for Remember {
+    fn name(&self) -> &str {
+        REMEMBER_TOOL
+    }
+
+    fn description(&self) -> &str {
+        "Saves a distilled finding to the team's knowledge base, for later conversations. Give a key to save it as a fact others can look up by that key."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "content": { "type": "string", "description": "The finding, stated on its own." },
+                "key": { "type": "string", "description": "A key to save the finding under as a fact." },
+            },
+            "required": ["content"],
+        })
+    }
+
+    fn call(&self, arguments: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let content = arguments["content"]
+                .as_str()
+                .ok_or_else(|| ToolError::InvalidArguments("no content".to_string()))?;
+            let provenance = Provenance {
+                recorded_at: SystemTime::now(),
+                ..self.provenance.clone()
+            };
+            if let Some(key) = arguments["key"].as_str() {
+                self.knowledge.set(key, content, provenance);
+                return Ok(format!("saved under {key:?}"));
+            }
+            self.knowledge
+                .record(content, provenance)
+                .await
+                .map_err(|e| ToolError::Failed(e.to_string()))?;
+            Ok("saved".to_string())
+        })
+    }
+}
+
+/// A tool that searches a knowledge base's findings, or looks up a fact by
+/// key, citing where each entry came from.
+#[derive(Debug, Clone)]
+pub struct Recall {
+    knowledge: KnowledgeBase,
+    limit: usize,
+}
+
+impl Recall {
+    /// Return at most `limit` findings per search.
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = limit.max(1);
+        self
+    }
+}
+
+impl Tool for Recall {
+    fn name(&self) -> &str {
+        RECALL_TOOL
+    }
+
+    fn description(&self) -> &str {
+        "Searches the team's knowledge base for findings from earlier conversations, or looks up a fact by key."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": { "type": "string", "description": "What to search for." },
+                "key": { "type": "string", "description": "The key of a fact to look up." },
+            },
+        })
+    }
+
+    fn call(&self, arguments: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            if let Some(key) = arguments["key"].as_str() {
+                return Ok(match self.knowledge.get(key) {
+                    Some(fact) => format!("{} ({})", fact.value, cite(&fact.provenance)),
+                    None => format!("no fact under {key:?}"),
+                });
+            }
+            let query = arguments["query"]
+                .as_str()
+                .ok_or_else(|| ToolError::InvalidArguments("no query or key".to_string()))?;
+            let hits = self
+                .knowledge
+                .search(query, self.limit)
+                .await
+                .map_err(|e| ToolError::Failed(e.to_string()))?;
+            if hits.is_empty() {
+                return Ok("nothing found".to_string());
+            }
+            let mut found = String::new();
+            for hit in hits {
+                let _ = writeln!(
+                    found,
+                    "- {} ({}, relevance {:.2})",
+                    hit.finding.content,
+                    cite(&hit.finding.provenance),
+                    hit.score
+                );
+            }
+            Ok(found.trim_end().to_string())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::embedding::{EmbedFuture, EmbeddingRequest},
+        anyhow::Result,
+    };
+
+    /// Embeds text by counting the words of a small vocabulary.
+    #[derive(Debug)]
+    struct Words;
+
+    impl Embedder for Words {
+        fn embed(&self, request: EmbeddingRequest) -> EmbedFuture<'_> {
+            let vocabulary = ["rust", "borrow", "soup", "salt"];
+            let embeddings = request
+                .inputs
+                .iter()
+                .map(|input| {
+                    let input = input.to_lowercase();
+                    vocabulary
+                        .iter()
+                        .map(|word| input.matches(word).count() as f32)
+                        .collect()
+                })
+                .collect();
+            Box::pin(std::future::ready(Ok(embeddings)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_knowledge_base() -> Result<()> {
+        let knowledge = KnowledgeBase::new(Arc::new(Words), "words");
+        // one conversation's lessons...
+        let remember =
+            knowledge.remember_tool(Provenance::new("coder").with_conversation("refactor"));
+        remember
+            .call(json!({ "content": "Rust's borrow checker rejects the cache." }))
+            .await?;
+        remember
+            .call(json!({ "content": "Salt the soup last." }))
+            .await?;
+        remember
+            .call(json!({ "content": "nightly", "key": "toolchain" }))
+            .await?;
+
+        // ...are found in another
+        let recall = knowledge.recall_tool().with_limit(1);
+        assert_eq!(
+            recall
+                .call(json!({ "query": "borrow errors in rust" }))
+                .await?,
+            "- Rust's borrow checker rejects the cache. (from coder in refactor, relevance 1.00)"
+        );
+        assert_eq!(
+            recall.call(json!({ "key": "toolchain" })).await?,
+            "nightly (from coder in refactor)"
+        );
+        assert_eq!(knowledge.findings().len(), 2);
+        assert_eq!(
+            knowledge.get("toolchain").unwrap().provenance.author,
+            "coder"
+        );
+        Ok(())
+    }
+}
@@ -1,7 +1,32 @@
 //! Autogen-rs is a Rust library for building AI agents.
 pub mod agent;
+pub mod artifact;
+pub mod breaker;
+pub mod chat;
+pub mod cleanup;
+pub mod code_executor;
+pub mod context;
+pub mod embedding;
+pub mod flags;
+pub mod group_chat;
+pub mod knowledge;
+pub mod llm;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod prompts;
+pub mod recorder;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod tokenizer;
+pub mod tools;
+pub mod workflow;
 
-pub use agent::{user::UserAgent, Agent};
+pub use {
+    agent::{user::UserAgent, Agent},
+    chat::TerminationReason,
+};
 
 /// Init logger for unit tests.
 #[cfg(test)]
@@ -0,0 +1,974 @@
+//! A client for Anthropic's Messages API, to back assistants with Claude
+//! models.
+//!
+//! The API takes system prompts apart from the conversation, so system
+//! messages in the history are joined into one. Tool results are sent as
+//! user messages, and consecutive messages from the same role are merged,
+//! since the API expects the roles to alternate.
+
+use {
+    super::{
+        openai::{MAX_LINE_BYTES, MAX_RESPONSE_BYTES, MAX_TOOL_CALLS},
+        Completion, CompletionRequest, Delta, FunctionCall, HistoryMessage, LlmClient, LlmFuture,
+        Parameters, Role, ToolCall, ToolDefinition, ToolKind, Usage,
+    },
+    serde::{Deserialize, Serialize},
+    serde_json::Value,
+};
+
+/// The Anthropic API's base URL.
+pub const DEFAULT_BASE_URL: &str = "https://api.anthropic.com/v1";
+
+/// The ANTHROPIC_API_KEY environment variable is used when no API key is
+/// configured.
+pub const API_KEY_ENV_VAR: &str = "ANTHROPIC_API_KEY";
+
+/// The ANTHROPIC_BASE_URL environment variable can be used to override the
+/// default base URL, e.g. to point at a proxy.
+pub const BASE_URL_ENV_VAR: &str = "ANTHROPIC_BASE_URL";
+
+/// The version of the API requests are made against.
+pub const API_VERSION: &str = "2023-06-01";
+
+/// The most tokens a reply may have when none is configured. The API
+/// requires a limit.
+pub const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+/// Errors that can occur when calling the Anthropic API.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(
+        "no Anthropic API key configured; set {API_KEY_ENV_VAR} or configure one on the client"
+    )]
+    MissingApiKey,
+
+    #[error("request to Anthropic API failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+
+    #[error("Anthropic API returned {status}: {message}")]
+    ApiError {
+        status: u16,
+        message: String,
+        /// How long the API asked to wait before retrying, if it did.
+        retry_after: Option<std::time::Duration>,
+    },
+
+    #[error("Anthropic API failed while streaming: {0}")]
+    StreamError(String),
+
+    #[error("Anthropic API response exceeded {limit} bytes")]
+    ResponseTooLarge { limit: usize },
+
+    #[error("Anthropic API returned an invalid response: {0}")]
+    InvalidResponse(String),
+}
+
+/// The body of a messages request.
+#[derive(Debug, Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<ApiMessage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ApiTool<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<ToolChoice<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    stop_sequences: &'a [String],
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ApiMessage {
+    role: &'static str,
+    content: Vec<ContentBlock>,
+}
+
+#[derive(Debug, Serialize)]
+struct ApiTool<'a> {
+    name: &'a str,
+    description: &'a str,
+    input_schema: &'a Value,
+}
+
+/// Makes the model call a particular tool.
+#[derive(Debug, Serialize)]
+struct ToolChoice<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    name: &'a str,
+}
+
+/// A block of a message's content.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+    },
+    Thinking {
+        thinking: String,
+    },
+    /// Blocks we don't use, e.g. redacted thinking.
+    #[serde(other)]
+    Other,
+}
+
+/// The parts of a messages response that we use.
+#[derive(Debug, Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    usage: Option<ApiUsage>,
+}
+
+/// The tokens a call took.
+#[derive(Debug, Default, Deserialize)]
+struct ApiUsage {
+    #[serde(default)]
+    input_tokens: usize,
+    #[serde(default)]
+    output_tokens: usize,
+}
+
+/// An event of a streamed response.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    ContentBlockStart {
+        index: usize,
+        content_block: ContentBlock,
+    },
+    ContentBlockDelta {
+        index: usize,
+        delta: BlockDelta,
+    },
+    Error {
+        error: ErrorDetail,
+    },
+    /// Starts the response, with the tokens of the prompt.
+    MessageStart {
+        message: StartedMessage,
+    },
+    /// Ends the response, with the tokens of the reply.
+    MessageDelta {
+        #[serde(default)]
+        usage: ApiUsage,
+    },
+    /// Events we don't use, e.g. pings.
+    #[serde(other)]
+    Other,
+}
+
+/// The response as its stream starts.
+#[derive(Debug, Deserialize)]
+struct StartedMessage {
+    #[serde(default)]
+    usage: ApiUsage,
+}
+
+/// A piece of a streamed content block.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockDelta {
+    TextDelta {
+        text: String,
+    },
+    InputJsonDelta {
+        partial_json: String,
+    },
+    ThinkingDelta {
+        thinking: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// The body of an error response.
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: ErrorDetail,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorDetail {
+    message: String,
+}
+
+/// A client for Anthropic's Messages API.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::assistant::AssistantBuilder, llm::anthropic}, std::sync::Arc};
+/// # tokio_test::block_on(async {
+/// let client = anthropic::Client::new(None, None).with_max_tokens(1024);
+/// let assistant = AssistantBuilder::new()
+///     .with_client(Arc::new(client))
+///     .with_model("claude-3-5-sonnet-latest")
+///     .build();
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// The HTTP client used to make requests.
+    http: reqwest::Client,
+
+    /// The API key sent with each request.
+    api_key: Option<String>,
+
+    /// The base URL of the API, without a trailing slash.
+    base_url: String,
+
+    /// The most tokens a reply may have.
+    max_tokens: u32,
+}
+
+impl Client {
+    /// Create a new client. The API key and base URL fall back to the
+    /// ANTHROPIC_API_KEY and ANTHROPIC_BASE_URL environment variables, and
+    /// then to no key and the public Anthropic API.
+    pub fn new(api_key: Option<String>, base_url: Option<String>) -> Self {
+        let api_key = api_key.or_else(|| std::env::var(API_KEY_ENV_VAR).ok());
+        let base_url = base_url
+            .or_else(|| std::env::var(BASE_URL_ENV_VAR).ok())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+        Self {
+            http: reqwest::Client::new(),
+            api_key,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            max_tokens: DEFAULT_MAX_TOKENS,
+        }
+    }
+
+    /// Limit replies to `max_tokens` tokens.
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Returns the base URL requests are sent to.
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Ask `model` to continue the conversation, offering it `tools` to call.
+    /// Returns the model's reply, including its thinking if it's enabled.
+    pub async fn messages(
+        &self,
+        model: &str,
+        messages: &[HistoryMessage],
+        tools: &[ToolDefinition],
+    ) -> Result<Completion, Error> {
+        let parameters = Parameters::default();
+        self.send(&request(
+            model,
+            self.max_tokens,
+            &parameters,
+            messages,
+            tools,
+            false,
+        ))
+        .await
+    }
+
+    /// Like [`Client::messages`], but streams the reply, calling `on_delta`
+    /// with each piece of content or thinking as it arrives. Tool calls are
+    /// collected as they stream in. Returns the complete reply.
+    pub async fn messages_stream(
+        &self,
+        model: &str,
+        messages: &[HistoryMessage],
+        tools: &[ToolDefinition],
+        mut on_delta: impl FnMut(Delta<'_>),
+    ) -> Result<Completion, Error> {
+        let parameters = Parameters::default();
+        let request = request(model, self.max_tokens, &parameters, messages, tools, true);
+        self.send_stream(&request, &mut on_delta).await
+    }
+
+    /// Send a messages request and return the model's reply.
+    async fn send(&self, request: &MessagesRequest<'_>) -> Result<Completion, Error> {
+        let response = self.post("messages", request).await?;
+        parse_completion(&read_body(response).await?)
+    }
+
+    /// Send a streaming messages request, calling `on_delta` with each piece
+    /// of the reply, and return the complete reply.
+    async fn send_stream(
+        &self,
+        request: &MessagesRequest<'_>,
+        mut on_delta: impl FnMut(Delta<'_>),
+    ) -> Result<Completion, Error> {
+        let mut response = self.post("messages", request).await?;
+        let mut parser = StreamParser::default();
+        while let Some(chunk) = response.chunk().await? {
+            parser.push(&chunk, &mut on_delta)?;
+        }
+        parser.finish(&mut on_delta)
+    }
+
+    /// Returns the body that sends `request`.
+    fn body<'a>(&self, request: &'a CompletionRequest, stream: bool) -> MessagesRequest<'a> {
+        let CompletionRequest {
+            model,
+            messages,
+            tools,
+            parameters,
+            response_format,
+        } = request;
+        let mut body = self::request(model, self.max_tokens, parameters, messages, tools, stream);
+        // the API can't constrain replies, so the model replies by calling
+        // a tool taking the schema
+        if let Some(format) = response_format {
+            body.tools.push(ApiTool {
+                name: &format.name,
+                description: "Reply with the arguments of this tool.",
+                input_schema: &format.schema,
+            });
+            body.tool_choice = Some(ToolChoice {
+                kind: "tool",
+                name: &format.name,
+            });
+        }
+        body
+    }
+
+    /// Send a request to the API's `path`, turning error statuses into
+    /// errors.
+    async fn post(&self, path: &str, body: &impl Serialize) -> Result<reqwest::Response, Error> {
+        let api_key = self.api_key.as_deref().ok_or(Error::MissingApiKey)?;
+        let response = self
+            .http
+            .post(format!("{}/{path}", self.base_url))
+            .header("x-api-key", api_key)
+            .header("anthropic-version", API_VERSION)
+            .json(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = super::retry_after(response.headers());
+            let body = read_body(response).await?;
+            let message = serde_json::from_slice::<ErrorResponse>(&body)
+                .map(|e| e.error.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned());
+            return Err(Error::ApiError {
+                status: status.as_u16(),
+                message,
+                retry_after,
+            });
+        }
+        Ok(response)
+    }
+}
+
+impl LlmClient for Client {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            let completion = self.send(&self.body(&request, false)).await?;
+            Ok(structured(completion, &request))
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: CompletionRequest,
+        on_delta: &'a mut (dyn FnMut(Delta<'_>) + Send),
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            let completion = self
+                .send_stream(&self.body(&request, true), on_delta)
+                .await?;
+            Ok(structured(completion, &request))
+        })
+    }
+}
+
+/// Returns the body of a request for `model` to continue `messages`. Replies
+/// are limited to `max_tokens` unless `parameters` limit them.
+fn request<'a>(
+    model: &'a str,
+    max_tokens: u32,
+    parameters: &'a Parameters,
+    messages: &[HistoryMessage],
+    tools: &'a [ToolDefinition],
+    stream: bool,
+) -> MessagesRequest<'a> {
+    let mut system = Vec::new();
+    let mut api_messages = Vec::<ApiMessage>::new();
+    for message in messages {
+        let (role, content) = match message.role {
+            Role::System => {
+                system.push(message.content.as_str());
+                continue;
+            }
+            Role::User => ("user", vec![text(&message.content)]),
+            Role::Assistant => {
+                let calls = message.tool_calls.iter().map(|call| ContentBlock::ToolUse {
+                    id: call.id.clone(),
+                    name: call.function.name.clone(),
+                    // the API only takes objects
+                    input: serde_json::from_str(&call.function.arguments)
+                        .ok()
+                        .filter(Value::is_object)
+                        .unwrap_or_else(|| Value::Object(Default::default())),
+                });
+                (
+                    "assistant",
+                    std::iter::once(text(&message.content))
+                        .chain(calls)
+                        .collect(),
+                )
+            }
+            Role::Tool => (
+                "user",
+                vec![ContentBlock::ToolResult {
+                    tool_use_id: message.tool_call_id.clone().unwrap_or_default(),
+                    content: message.content.clone(),
+                }],
+            ),
+        };
+        // the API rejects empty text
+        let content = content
+            .into_iter()
+            .filter(|block| !matches!(block, ContentBlock::Text { text } if text.is_empty()))
+            .collect::<Vec<_>>();
+        if content.is_empty() {
+            continue;
+        }
+        match api_messages.last_mut() {
+            Some(last) if last.role == role => last.content.extend(content),
+            _ => api_messages.push(ApiMessage { role, content }),
+        }
+    }
+
+    MessagesRequest {
+        model,
+        max_tokens: parameters.max_tokens.unwrap_or(max_tokens),
+        system: (!system.is_empty()).then(|| system.join("\n\n")),
+        messages: api_messages,
+        tools: tools
+            .iter()
+            .map(|tool| ApiTool {
+                name: &tool.function.name,
+                description: &tool.function.description,
+                input_schema: &tool.function.parameters,
+            })
+            .collect(),
+        temperature: parameters.temperature,
+        top_p: parameters.top_p,
+        tool_choice: None,
+        stop_sequences: &parameters.stop_sequences,
+        stream,
+    }
+}
+
+/// Returns `completion` with the arguments of its call to the tool standing
+/// in for `request`'s response format, if any, as its content.
+fn structured(mut completion: Completion, request: &CompletionRequest) -> Completion {
+    let Some(format) = &request.response_format else {
+        return completion;
+    };
+    if let Some(i) = completion
+        .tool_calls
+        .iter()
+        .position(|call| call.function.name == format.name)
+    {
+        completion.content = completion.tool_calls.remove(i).function.arguments;
+    }
+    completion
+}
+
+fn text(text: &str) -> ContentBlock {
+    ContentBlock::Text {
+        text: text.to_string(),
+    }
+}
+
+/// Reads a response body, failing if it's larger than
+/// [`MAX_RESPONSE_BYTES`].
+async fn read_body(mut response: reqwest::Response) -> Result<Vec<u8>, Error> {
+    let too_large = Error::ResponseTooLarge {
+        limit: MAX_RESPONSE_BYTES,
+    };
+    if response
+        .content_length()
+        .is_some_and(|length| length > MAX_RESPONSE_BYTES as u64)
+    {
+        return Err(too_large);
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > MAX_RESPONSE_BYTES {
+            return Err(too_large);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Parses the body of a messages response.
+fn parse_completion(body: &[u8]) -> Result<Completion, Error> {
+    let response = serde_json::from_slice::<MessagesResponse>(body)
+        .map_err(|e| Error::InvalidResponse(e.to_string()))?;
+    let mut completion = Completion {
+        usage: response.usage.map(|usage| Usage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+        }),
+        ..Default::default()
+    };
+    for block in response.content {
+        match block {
+            ContentBlock::Text { text } => completion.content.push_str(&text),
+            ContentBlock::Thinking { thinking } => completion
+                .reasoning
+                .get_or_insert_with(String::new)
+                .push_str(&thinking),
+            ContentBlock::ToolUse { id, name, input } => {
+                completion
+                    .tool_calls
+                    .push(tool_call(id, name, input.to_string()))
+            }
+            ContentBlock::ToolResult { .. } | ContentBlock::Other => {}
+        }
+    }
+    if completion.tool_calls.len() > MAX_TOOL_CALLS {
+        return Err(Error::InvalidResponse(format!(
+            "more than {MAX_TOOL_CALLS} tool calls"
+        )));
+    }
+    Ok(completion)
+}
+
+fn tool_call(id: String, name: String, arguments: String) -> ToolCall {
+    ToolCall {
+        id,
+        kind: ToolKind::Function,
+        function: FunctionCall { name, arguments },
+    }
+}
+
+/// Parses a streamed messages response as it arrives. The response is a
+/// stream of server-sent events; content arrives in blocks, each started
+/// and then extended by deltas.
+#[derive(Debug, Default)]
+struct StreamParser {
+    /// Bytes of a line that hasn't ended yet.
+    buffer: Vec<u8>,
+
+    /// The number of bytes received so far.
+    received: usize,
+
+    /// The reply so far.
+    completion: Completion,
+
+    /// The index of the content block each tool call streams in.
+    tool_blocks: Vec<usize>,
+}
+
+impl StreamParser {
+    /// Parse the next bytes of the response, calling `on_delta` with each
+    /// piece of content or thinking.
+    fn push(&mut self, bytes: &[u8], mut on_delta: impl FnMut(Delta<'_>)) -> Result<(), Error> {
+        self.received += bytes.len();
+        if self.received > MAX_RESPONSE_BYTES {
+            return Err(Error::ResponseTooLarge {
+                limit: MAX_RESPONSE_BYTES,
+            });
+        }
+        self.buffer.extend_from_slice(bytes);
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+            self.parse_line(&line, &mut on_delta)?;
+        }
+        if self.buffer.len() > MAX_LINE_BYTES {
+            return Err(Error::ResponseTooLarge {
+                limit: MAX_LINE_BYTES,
+            });
+        }
+        Ok(())
+    }
+
+    /// Parse whatever is left of the response and return the complete reply.
+    fn finish(mut self, mut on_delta: impl FnMut(Delta<'_>)) -> Result<Completion, Error> {
+        let line = std::mem::take(&mut self.buffer);
+        self.parse_line(&line, &mut on_delta)?;
+        // calls without arguments stream none
+        for call in &mut self.completion.tool_calls {
+            if call.function.arguments.is_empty() {
+                call.function.arguments = "{}".to_string();
+            }
+        }
+        Ok(self.completion)
+    }
+
+    fn parse_line(
+        &mut self,
+        line: &[u8],
+        on_delta: &mut impl FnMut(Delta<'_>),
+    ) -> Result<(), Error> {
+        let line = String::from_utf8_lossy(line);
+        let Some(data) = line.trim().strip_prefix("data:") else {
+            return Ok(());
+        };
+        let Ok(event) = serde_json::from_str::<Event>(data.trim()) else {
+            return Ok(());
+        };
+        let completion = &mut self.completion;
+        match event {
+            Event::ContentBlockStart {
+                index,
+                content_block,
+            } => match content_block {
+                ContentBlock::ToolUse { id, name, .. } => {
+                    if completion.tool_calls.len() >= MAX_TOOL_CALLS {
+                        return Err(Error::InvalidResponse(format!(
+                            "more than {MAX_TOOL_CALLS} tool calls"
+                        )));
+                    }
+                    // the input streams in as deltas
+                    completion
+                        .tool_calls
+                        .push(tool_call(id, name, String::new()));
+                    self.tool_blocks.push(index);
+                }
+                ContentBlock::Text { text } if !text.is_empty() => {
+                    on_delta(Delta::Content(&text));
+                    completion.content.push_str(&text);
+                }
+                _ => {}
+            },
+            Event::ContentBlockDelta { index, delta } => match delta {
+                BlockDelta::TextDelta { text } if !text.is_empty() => {
+                    on_delta(Delta::Content(&text));
+                    completion.content.push_str(&text);
+                }
+                BlockDelta::ThinkingDelta { thinking } if !thinking.is_empty() => {
+                    on_delta(Delta::Reasoning(&thinking));
+                    completion
+                        .reasoning
+                        .get_or_insert_with(String::new)
+                        .push_str(&thinking);
+                }
+                BlockDelta::InputJsonDelta { partial_json } => {
+                    let call = self
+                        .tool_blocks
+                        .iter()
+                        .position(|block| *block == index)
+                        .ok_or_else(|| {
+                            Error::InvalidResponse(format!("no tool call in block {index}"))
+                        })?;
+                    completion.tool_calls[call]
+                        .function
+                        .arguments
+                        .push_str(&partial_json);
+                }
+                _ => {}
+            },
+            Event::Error { error } => return Err(Error::StreamError(error.message)),
+            Event::MessageStart { message } => {
+                completion
+                    .usage
+                    .get_or_insert_with(Default::default)
+                    .prompt_tokens = message.usage.input_tokens;
+            }
+            Event::MessageDelta { usage } => {
+                completion
+                    .usage
+                    .get_or_insert_with(Default::default)
+                    .completion_tokens = usage.output_tokens;
+            }
+            Event::Other => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::llm::{openai::mock::read_request, FunctionDefinition, ResponseFormat},
+        anyhow::Result,
+        serde_json::json,
+        tokio::{io::AsyncWriteExt, net::TcpListener},
+    };
+
+    /// Starts a server that answers every request by echoing the last text
+    /// of the conversation, after the system prompt in brackets if there is
+    /// one. A message of the form `call <tool> <arguments>` is answered with
+    /// a call to that tool. Returns the server's base URL.
+    async fn echo_server() -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buffer = Vec::new();
+                    while let Some((headers, request)) =
+                        read_request(&mut stream, &mut buffer).await?
+                    {
+                        let response = respond(&headers, &request);
+                        stream.write_all(response.as_bytes()).await?;
+                    }
+                    std::io::Result::Ok(())
+                });
+            }
+        });
+        Ok(format!("http://{address}/v1"))
+    }
+
+    fn respond(headers: &str, request: &Value) -> String {
+        let headers = headers.to_lowercase();
+        if !headers.contains("x-api-key: key") || !headers.contains("anthropic-version") {
+            let body = json!({ "type": "error", "error": { "type": "authentication_error", "message": "invalid x-api-key" } });
+            return format!(
+                "HTTP/1.1 401 Unauthorized\r\ncontent-length: {}\r\n\r\n{body}",
+                body.to_string().len()
+            );
+        }
+
+        let last = request["messages"]
+            .as_array()
+            .and_then(|messages| messages.last())
+            .and_then(|message| message["content"].as_array())
+            .and_then(|content| content.last())
+            .cloned()
+            .unwrap_or_default();
+        let last = last["text"].as_str().or(last["content"].as_str());
+        let mut content = last.unwrap_or_default().to_string();
+        if let Some(system) = request["system"].as_str() {
+            content = format!("[{system}] {content}");
+        }
+        let tool_call = last
+            .and_then(|last| last.strip_prefix("call "))
+            .filter(|_| request["tools"].is_array())
+            .map(|call| call.split_once(' ').unwrap_or((call, "")));
+
+        let (content_type, body) = if request["stream"] == true {
+            let mut events = vec![json!({ "type": "message_start", "message": {} })];
+            match tool_call {
+                Some((name, arguments)) => {
+                    events.push(json!({ "type": "content_block_start", "index": 0,
+                        "content_block": { "type": "tool_use", "id": "toolu_0", "name": name, "input": {} } }));
+                    // stream the input in two pieces, like the real API does
+                    let (first, second) = arguments.split_at(arguments.len() / 2);
+                    for piece in [first, second] {
+                        events.push(json!({ "type": "content_block_delta", "index": 0,
+                            "delta": { "type": "input_json_delta", "partial_json": piece } }));
+                    }
+                }
+                None => {
+                    events.push(json!({ "type": "content_block_start", "index": 0,
+                        "content_block": { "type": "text", "text": "" } }));
+                    for token in content.split_inclusive(' ') {
+                        events.push(json!({ "type": "content_block_delta", "index": 0,
+                            "delta": { "type": "text_delta", "text": token } }));
+                    }
+                }
+            }
+            events.push(json!({ "type": "content_block_stop", "index": 0 }));
+            events.push(json!({ "type": "message_stop" }));
+            let mut body = String::new();
+            for event in events {
+                let name = event["type"].as_str().unwrap_or_default();
+                body.push_str(&format!("event: {name}\ndata: {event}\n\n"));
+            }
+            ("text/event-stream", body)
+        } else {
+            let block = match tool_call {
+                Some((name, arguments)) => {
+                    json!({ "type": "tool_use", "id": "toolu_0", "name": name,
+                    "input": serde_json::from_str::<Value>(arguments).unwrap_or(json!({})) })
+                }
+                None => json!({ "type": "text", "text": content }),
+            };
+            let body =
+                json!({ "role": "assistant", "content": [block], "stop_reason": "end_turn" });
+            ("application/json", body.to_string())
+        };
+        format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    fn client(base_url: String) -> Client {
+        Client::new(Some("key".to_string()), Some(base_url))
+    }
+
+    #[test]
+    fn test_request() {
+        let call = tool_call("toolu_0".to_string(), "add".to_string(), "[1]".to_string());
+        let messages = [
+            HistoryMessage::new(Role::System, "Be brief."),
+            HistoryMessage::new(Role::User, "add 1 and 2"),
+            HistoryMessage {
+                tool_calls: vec![call],
+                ..HistoryMessage::new(Role::Assistant, "")
+            },
+            HistoryMessage::tool_result("toolu_0", "3"),
+            HistoryMessage::new(Role::User, "thanks"),
+        ];
+        let request = serde_json::to_value(request(
+            "claude",
+            100,
+            &Parameters::default(),
+            &messages,
+            &[],
+            false,
+        ))
+        .unwrap();
+        assert_eq!(
+            request,
+            json!({
+                "model": "claude",
+                "max_tokens": 100,
+                "system": "Be brief.",
+                "messages": [
+                    { "role": "user", "content": [{ "type": "text", "text": "add 1 and 2" }] },
+                    { "role": "assistant", "content": [
+                        { "type": "tool_use", "id": "toolu_0", "name": "add", "input": {} },
+                    ] },
+                    // the tool's result and the next message are one user turn
+                    { "role": "user", "content": [
+                        { "type": "tool_result", "tool_use_id": "toolu_0", "content": "3" },
+                        { "type": "text", "text": "thanks" },
+                    ] },
+                ],
+            })
+        );
+
+        let parameters = Parameters {
+            temperature: Some(0.5),
+            max_tokens: Some(50),
+            stop_sequences: vec!["END".to_string()],
+            ..Default::default()
+        };
+        let request = super::request("claude", 100, &parameters, &messages, &[], false);
+        let request = serde_json::to_value(request).unwrap();
+        assert_eq!(request["max_tokens"], 50);
+        assert_eq!(request["temperature"], 0.5);
+        assert_eq!(request["stop_sequences"], json!(["END"]));
+        assert!(request.get("top_p").is_none());
+    }
+
+    #[test]
+    fn test_response_format() {
+        let request = CompletionRequest {
+            model: "claude".to_string(),
+            response_format: Some(ResponseFormat::new("point", json!({ "type": "object" }))),
+            ..Default::default()
+        };
+        let client = client(DEFAULT_BASE_URL.to_string());
+        let body = serde_json::to_value(client.body(&request, false)).unwrap();
+        assert_eq!(body["tools"][0]["name"], "point");
+        assert_eq!(
+            body["tools"][0]["input_schema"],
+            json!({ "type": "object" })
+        );
+        assert_eq!(
+            body["tool_choice"],
+            json!({ "type": "tool", "name": "point" })
+        );
+
+        // the model's call to the format's tool becomes its reply
+        let completion = Completion {
+            tool_calls: vec![tool_call(
+                "toolu_0".to_string(),
+                "point".to_string(),
+                r#"{"x":1}"#.to_string(),
+            )],
+            ..Default::default()
+        };
+        let completion = structured(completion, &request);
+        assert_eq!(completion.content, r#"{"x":1}"#);
+        assert!(completion.tool_calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_messages() -> Result<()> {
+        let client = client(echo_server().await?);
+        let messages = [
+            HistoryMessage::new(Role::System, "be brief"),
+            HistoryMessage::new(Role::User, "hello there"),
+        ];
+
+        let reply = client.messages("claude", &messages, &[]).await?;
+        assert_eq!(reply.content, "[be brief] hello there");
+
+        let mut tokens = Vec::new();
+        let reply = client
+            .messages_stream("claude", &messages, &[], |delta| {
+                if let Delta::Content(token) = delta {
+                    tokens.push(token.to_string());
+                }
+            })
+            .await?;
+        assert_eq!(reply.content, "[be brief] hello there");
+        assert_eq!(tokens, ["[be ", "brief] ", "hello ", "there"]);
+
+        let unauthorized = Client::new(Some("wrong".to_string()), Some(client.base_url.clone()));
+        assert!(matches!(
+            unauthorized.messages("claude", &messages, &[]).await,
+            Err(Error::ApiError { status: 401, .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls() -> Result<()> {
+        let client = client(echo_server().await?);
+        let messages = [HistoryMessage::new(Role::User, r#"call add {"a":1,"b":2}"#)];
+        let tools = [ToolDefinition {
+            kind: ToolKind::Function,
+            function: FunctionDefinition {
+                name: "add".to_string(),
+                description: "Adds two numbers.".to_string(),
+                parameters: json!({ "type": "object" }),
+            },
+        }];
+        let expected = [tool_call(
+            "toolu_0".to_string(),
+            "add".to_string(),
+            r#"{"a":1,"b":2}"#.to_string(),
+        )];
+
+        let reply = client.messages("claude", &messages, &tools).await?;
+        assert_eq!(reply.tool_calls, expected);
+
+        let reply = client
+            .messages_stream("claude", &messages, &tools, |_| {})
+            .await?;
+        assert_eq!(reply.tool_calls, expected);
+        assert_eq!(reply.content, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_error() {
+        let mut parser = StreamParser::default();
+        let error = b"event: error\ndata: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}\n";
+        assert!(matches!(
+            parser.push(error, |_| {}),
+            Err(Error::StreamError(message)) if message == "Overloaded"
+        ));
+    }
+}
@@ -0,0 +1,401 @@
+//! A client for Azure OpenAI, which serves OpenAI models from deployments in
+//! an Azure resource. Requests and replies are the same as OpenAI's, but
+//! requests are sent to a deployment rather than naming a model, carry an
+//! `api-version` query parameter, and authenticate with an API key or a
+//! Microsoft Entra ID (Azure AD) token.
+
+use {
+    super::{
+        openai::{self, read_body, ChatCompletionRequest, ErrorResponse, StreamParser},
+        Completion, CompletionRequest, Delta, HistoryMessage, LlmClient, LlmFuture, Parameters,
+        ToolDefinition,
+    },
+    serde::Serialize,
+    std::collections::HashMap,
+};
+
+/// The AZURE_OPENAI_ENDPOINT environment variable is used when no endpoint
+/// is configured, e.g. `https://my-resource.openai.azure.com`.
+pub const ENDPOINT_ENV_VAR: &str = "AZURE_OPENAI_ENDPOINT";
+
+/// The AZURE_OPENAI_API_KEY environment variable is used when no credentials
+/// are configured.
+pub const API_KEY_ENV_VAR: &str = "AZURE_OPENAI_API_KEY";
+
+/// The AZURE_OPENAI_AD_TOKEN environment variable is used when no
+/// credentials are configured and there's no API key.
+pub const AD_TOKEN_ENV_VAR: &str = "AZURE_OPENAI_AD_TOKEN";
+
+/// The OPENAI_API_VERSION environment variable can be used to override the
+/// default API version.
+pub const API_VERSION_ENV_VAR: &str = "OPENAI_API_VERSION";
+
+/// The version of the API requests are made against by default.
+pub const DEFAULT_API_VERSION: &str = "2024-10-21";
+
+/// Errors that can occur when calling Azure OpenAI.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("no Azure OpenAI endpoint configured; set {ENDPOINT_ENV_VAR} or configure one on the client")]
+    MissingEndpoint,
+
+    #[error("invalid Azure OpenAI endpoint: {0}")]
+    InvalidEndpoint(String),
+
+    #[error("no Azure OpenAI credentials configured; set {API_KEY_ENV_VAR} or {AD_TOKEN_ENV_VAR}, or configure them on the client")]
+    MissingCredentials,
+
+    #[error("request to Azure OpenAI failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+
+    #[error("Azure OpenAI returned {status}: {message}")]
+    ApiError {
+        status: u16,
+        message: String,
+        /// How long the API asked to wait before retrying, if it did.
+        retry_after: Option<std::time::Duration>,
+    },
+
+    /// The response couldn't be read, e.g. it was too large or invalid.
+    #[error(transparent)]
+    Response(#[from] openai::Error),
+}
+
+/// How requests are authenticated.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Auth {
+    /// A key of the Azure resource, sent in the `api-key` header.
+    ApiKey(String),
+
+    /// A Microsoft Entra ID (Azure AD) access token, sent as a bearer token.
+    /// Tokens expire, so replace the client's with
+    /// [`Client::with_auth`] before then.
+    Token(String),
+}
+
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // keep credentials out of logs
+        f.write_str(match self {
+            Self::ApiKey(_) => "ApiKey(..)",
+            Self::Token(_) => "Token(..)",
+        })
+    }
+}
+
+/// A client for Azure OpenAI's chat completions API.
+///
+/// A request's model names the deployment it's sent to, unless it's mapped
+/// to another deployment with [`Client::with_deployment`].
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::assistant::AssistantBuilder, llm::azure}, std::sync::Arc};
+/// # tokio_test::block_on(async {
+/// let client = azure::Client::new(
+///     Some("https://my-resource.openai.azure.com".to_string()),
+///     Some(azure::Auth::ApiKey("key".to_string())),
+/// )
+/// .with_deployment("gpt-4o", "gpt-4o-prod")
+/// .with_api_version("2024-10-21");
+/// let assistant = AssistantBuilder::new()
+///     .with_client(Arc::new(client))
+///     .with_model("gpt-4o")
+///     .build();
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// The HTTP client used to make requests.
+    http: reqwest::Client,
+
+    /// The resource's endpoint, without a trailing slash.
+    endpoint: Option<String>,
+
+    /// How requests are authenticated.
+    auth: Option<Auth>,
+
+    /// The API version sent with each request.
+    api_version: String,
+
+    /// The deployment each model is served from, where it isn't named after
+    /// the model.
+    deployments: HashMap<String, String>,
+}
+
+impl Client {
+    /// Create a new client. The endpoint falls back to the
+    /// AZURE_OPENAI_ENDPOINT environment variable, and the credentials to an
+    /// API key in AZURE_OPENAI_API_KEY and then a token in
+    /// AZURE_OPENAI_AD_TOKEN.
+    pub fn new(endpoint: Option<String>, auth: Option<Auth>) -> Self {
+        let endpoint = endpoint.or_else(|| std::env::var(ENDPOINT_ENV_VAR).ok());
+        let auth = auth
+            .or_else(|| std::env::var(API_KEY_ENV_VAR).ok().map(Auth::ApiKey))
+            .or_else(|| std::env::var(AD_TOKEN_ENV_VAR).ok().map(Auth::Token));
+        let api_version =
+            std::env::var(API_VERSION_ENV_VAR).unwrap_or_else(|_| DEFAULT_API_VERSION.to_string());
+        Self {
+            http: reqwest::Client::new(),
+            endpoint: endpoint.map(|endpoint| endpoint.trim_end_matches('/').to_string()),
+            auth,
+            api_version,
+            deployments: HashMap::new(),
+        }
+    }
+
+    /// Authenticate requests with `auth`.
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Make requests against `api_version` of the API.
+    pub fn with_api_version(mut self, api_version: impl ToString) -> Self {
+        self.api_version = api_version.to_string();
+        self
+    }
+
+    /// Send requests for `model` to `deployment`.
+    pub fn with_deployment(mut self, model: impl ToString, deployment: impl ToString) -> Self {
+        self.deployments
+            .insert(model.to_string(), deployment.to_string());
+        self
+    }
+
+    /// Returns the deployment requests for `model` are sent to.
+    pub fn deployment<'a>(&'a self, model: &'a str) -> &'a str {
+        self.deployments.get(model).map_or(model, String::as_str)
+    }
+
+    /// Ask the deployment serving `model` to continue the conversation,
+    /// offering it `tools` to call. Returns the model's reply.
+    pub async fn chat_completion(
+        &self,
+        model: &str,
+        messages: &[HistoryMessage],
+        tools: &[ToolDefinition],
+    ) -> Result<Completion, Error> {
+        let request = ChatCompletionRequest {
+            model,
+            messages,
+            tools,
+            parameters: &Parameters::default(),
+            response_format: None,
+            stream: false,
+        };
+        self.send(&request).await
+    }
+
+    /// Like [`Client::chat_completion`], but streams the reply, calling
+    /// `on_delta` with each piece of content or reasoning as it arrives.
+    /// Returns the complete reply.
+    pub async fn chat_completion_stream(
+        &self,
+        model: &str,
+        messages: &[HistoryMessage],
+        tools: &[ToolDefinition],
+        mut on_delta: impl FnMut(Delta<'_>),
+    ) -> Result<Completion, Error> {
+        let request = ChatCompletionRequest {
+            model,
+            messages,
+            tools,
+            parameters: &Parameters::default(),
+            response_format: None,
+            stream: true,
+        };
+        self.send_stream(&request, &mut on_delta).await
+    }
+
+    /// Send a chat completion request to the deployment serving its model
+    /// and return the model's reply.
+    async fn send(&self, request: &ChatCompletionRequest<'_>) -> Result<Completion, Error> {
+        let response = self.post(request.model, request).await?;
+        Ok(openai::parse_completion(&read_body(response).await?)?)
+    }
+
+    /// Like [`Client::send`], but streams the reply to `on_delta`.
+    async fn send_stream(
+        &self,
+        request: &ChatCompletionRequest<'_>,
+        mut on_delta: impl FnMut(Delta<'_>),
+    ) -> Result<Completion, Error> {
+        let mut response = self.post(request.model, request).await?;
+        let mut parser = StreamParser::new();
+        while let Some(chunk) = response.chunk().await? {
+            parser.push(&chunk, &mut on_delta)?;
+        }
+        Ok(parser.finish(&mut on_delta)?)
+    }
+
+    /// Send a chat completion request to the deployment serving `model`,
+    /// turning error statuses into errors.
+    async fn post(&self, model: &str, body: &impl Serialize) -> Result<reqwest::Response, Error> {
+        let endpoint = self.endpoint.as_deref().ok_or(Error::MissingEndpoint)?;
+        let auth = self.auth.as_ref().ok_or(Error::MissingCredentials)?;
+        let invalid = || Error::InvalidEndpoint(endpoint.to_string());
+        let mut url = reqwest::Url::parse(endpoint).map_err(|_| invalid())?;
+        url.path_segments_mut()
+            .map_err(|_| invalid())?
+            .pop_if_empty()
+            .extend([
+                "openai",
+                "deployments",
+                self.deployment(model),
+                "chat",
+                "completions",
+            ]);
+        url.query_pairs_mut()
+            .append_pair("api-version", &self.api_version);
+
+        let request = self.http.post(url).json(body);
+        let request = match auth {
+            Auth::ApiKey(key) => request.header("api-key", key),
+            Auth::Token(token) => request.bearer_auth(token),
+        };
+        let response = request.send().await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = super::retry_after(response.headers());
+            let body = read_body(response).await?;
+            let message = serde_json::from_slice::<ErrorResponse>(&body)
+                .map(|e| e.error.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned());
+            return Err(Error::ApiError {
+                status: status.as_u16(),
+                message,
+                retry_after,
+            });
+        }
+        Ok(response)
+    }
+}
+
+impl LlmClient for Client {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            Ok(self
+                .send(&ChatCompletionRequest::new(&request, false))
+                .await?)
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: CompletionRequest,
+        on_delta: &'a mut (dyn FnMut(Delta<'_>) + Send),
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            Ok(self
+                .send_stream(&ChatCompletionRequest::new(&request, true), on_delta)
+                .await?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::llm::{openai::mock, Role},
+        anyhow::Result,
+        serde_json::json,
+        tokio::{io::AsyncWriteExt, net::TcpListener},
+    };
+
+    /// Starts a server that answers every request with its request line and
+    /// credentials. Returns the server's endpoint.
+    async fn echo_request_server() -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buffer = Vec::new();
+                    while let Some((headers, _)) =
+                        mock::read_request(&mut stream, &mut buffer).await?
+                    {
+                        let mut lines = headers.lines();
+                        let mut content = lines.next().unwrap_or_default().to_string();
+                        for line in lines {
+                            let name = line.split(':').next().unwrap_or_default();
+                            if ["api-key", "authorization"].contains(&name.to_lowercase().as_str())
+                            {
+                                content = format!("{content}\n{}", line.to_lowercase());
+                            }
+                        }
+                        let body = json!({ "choices": [{ "message": { "content": content } }] });
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{body}",
+                            body.to_string().len()
+                        );
+                        stream.write_all(response.as_bytes()).await?;
+                    }
+                    std::io::Result::Ok(())
+                });
+            }
+        });
+        Ok(format!("http://{address}/"))
+    }
+
+    #[tokio::test]
+    async fn test_deployments_and_auth() -> Result<()> {
+        let messages = [HistoryMessage::new(Role::User, "hello")];
+        let client = Client::new(
+            Some(echo_request_server().await?),
+            Some(Auth::ApiKey("KEY".to_string())),
+        )
+        .with_deployment("gpt-4o", "prod")
+        .with_api_version("2024-06-01");
+
+        let reply = client.chat_completion("gpt-4o", &messages, &[]).await?;
+        assert_eq!(
+            reply.content,
+            "POST /openai/deployments/prod/chat/completions?api-version=2024-06-01 HTTP/1.1\napi-key: key"
+        );
+
+        let client = client.with_auth(Auth::Token("TOKEN".to_string()));
+        let reply = client.chat_completion("gpt-35", &messages, &[]).await?;
+        assert_eq!(
+            reply.content,
+            "POST /openai/deployments/gpt-35/chat/completions?api-version=2024-06-01 HTTP/1.1\nauthorization: bearer token"
+        );
+
+        assert!(matches!(
+            Client {
+                auth: None,
+                ..client
+            }
+            .chat_completion("gpt-4o", &messages, &[])
+            .await,
+            Err(Error::MissingCredentials)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_stream() -> Result<()> {
+        let client = Client::new(
+            Some(mock::echo_server().await),
+            Some(Auth::ApiKey("key".to_string())),
+        );
+        let mut tokens = Vec::new();
+        let reply = client
+            .chat_completion_stream(
+                "gpt-4o",
+                &[HistoryMessage::new(Role::User, "hello there")],
+                &[],
+                |delta| {
+                    if let Delta::Content(token) = delta {
+                        tokens.push(token.to_string());
+                    }
+                },
+            )
+            .await?;
+        assert_eq!(reply.content, "hello there");
+        assert_eq!(tokens, ["hello ", "there"]);
+        Ok(())
+    }
+}
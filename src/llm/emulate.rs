@@ -0,0 +1,308 @@
+//! Tool calling for models without it. An [`EmulatedTools`] client describes
+//! the tools offered in the system prompt instead of sending them to the
+//! backend, and reads the calls the model makes from its reply, written
+//! ReAct-style as `Action:` and `Action Input:` lines. Calls and their
+//! results in the conversation are written out as text the same way, so the
+//! tools that work with other backends work unchanged with local or older
+//! models.
+
+use {
+    super::{
+        Completion, CompletionRequest, FunctionCall, HistoryMessage, LlmClient, LlmFuture, Role,
+        ToolCall, ToolDefinition, ToolKind,
+    },
+    crate::prompts::{Prompt, PromptPack},
+    serde_json::Value,
+    std::{collections::HashMap, sync::Arc},
+    uuid::Uuid,
+};
+
+/// The line a call starts with, followed by the tool's name.
+const ACTION: &str = "Action:";
+
+/// The line after [`ACTION`], followed by the call's arguments.
+const ACTION_INPUT: &str = "Action Input:";
+
+/// Wraps a client whose backend can't call tools, emulating tool calls in
+/// text. Requests without tools, and without calls in their conversation,
+/// are passed on unchanged.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::assistant::AssistantBuilder, llm::{emulate::EmulatedTools, ollama}}, std::sync::Arc};
+/// let client = EmulatedTools::new(Arc::new(ollama::Client::new(None)));
+/// let assistant = AssistantBuilder::new().with_client(Arc::new(client));
+/// ```
+#[derive(Debug)]
+pub struct EmulatedTools {
+    client: Arc<dyn LlmClient>,
+    prompts: PromptPack,
+}
+
+impl EmulatedTools {
+    /// Wrap `client`, emulating tool calls for it.
+    pub fn new(client: Arc<dyn LlmClient>) -> Self {
+        Self {
+            client,
+            prompts: PromptPack::default(),
+        }
+    }
+
+    /// Describe the tools and their results with `prompts` instead of the
+    /// English ones.
+    pub fn with_prompts(mut self, prompts: PromptPack) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    /// Returns `request` with its tools described in the system prompt, and
+    /// its calls and their results written out as text.
+    fn rewrite(&self, mut request: CompletionRequest) -> CompletionRequest {
+        let mut names = HashMap::new();
+        let mut messages = Vec::with_capacity(request.messages.len() + 1);
+        for message in request.messages {
+            if !message.tool_calls.is_empty() {
+                let mut content = message.content;
+                for call in &message.tool_calls {
+                    names.insert(call.id.clone(), call.function.name.clone());
+                    if !content.is_empty() {
+                        content.push('\n');
+                    }
+                    content.push_str(&format!(
+                        "{ACTION} {}\n{ACTION_INPUT} {}",
+                        call.function.name, call.function.arguments
+                    ));
+                }
+                messages.push(HistoryMessage::new(Role::Assistant, content));
+            } else if message.role == Role::Tool {
+                let id = message.tool_call_id.unwrap_or_default();
+                let name = names.get(&id).map_or("a tool", String::as_str);
+                let observation = self.prompts.render(
+                    Prompt::ToolObservation,
+                    &[("name", name), ("result", &message.content)],
+                );
+                messages.push(HistoryMessage::new(Role::User, observation));
+            } else {
+                messages.push(message);
+            }
+        }
+
+        let tools = std::mem::take(&mut request.tools);
+        if !tools.is_empty() {
+            let instructions = self
+                .prompts
+                .render(Prompt::ToolEmulation, &[("tools", &describe(&tools))]);
+            // local models often take only one system message
+            match messages.first_mut() {
+                Some(system) if system.role == Role::System => {
+                    system.content = format!("{}\n\n{instructions}", system.content);
+                }
+                _ => messages.insert(0, HistoryMessage::new(Role::System, instructions)),
+            }
+        }
+        request.messages = messages;
+        request
+    }
+}
+
+/// Returns `tools` described for the model, a line each with its name and
+/// description, then its arguments' schema.
+fn describe(tools: &[ToolDefinition]) -> String {
+    tools
+        .iter()
+        .map(|tool| {
+            let function = &tool.function;
+            format!(
+                "- {}: {}\n  Arguments: {}",
+                function.name, function.description, function.parameters
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Returns `reply` without the calls it makes, and the calls. Arguments that
+/// are JSON are passed on compacted, and any others as they are, for the
+/// tool to reject.
+fn parse_calls(reply: &str) -> (String, Vec<ToolCall>) {
+    let Some(start) = reply
+        .lines()
+        .position(|line| line.trim_start().starts_with(ACTION))
+    else {
+        return (reply.to_string(), Vec::new());
+    };
+    let lines = reply.lines().collect::<Vec<_>>();
+    let content = lines[..start].join("\n").trim_end().to_string();
+
+    let mut calls = Vec::new();
+    let mut call: Option<(String, Vec<&str>)> = None;
+    for line in &lines[start..] {
+        let trimmed = line.trim_start();
+        if let Some(arguments) = trimmed.strip_prefix(ACTION_INPUT) {
+            if let Some((_, input)) = &mut call {
+                input.push(arguments);
+            }
+        } else if let Some(name) = trimmed.strip_prefix(ACTION) {
+            calls.extend(call.take());
+            call = Some((name.trim().to_string(), Vec::new()));
+        } else if let Some((_, input)) = &mut call {
+            // arguments can run over several lines
+            if !input.is_empty() {
+                input.push(line);
+            }
+        }
+    }
+    calls.extend(call);
+
+    let calls = calls
+        .into_iter()
+        .map(|(name, input)| {
+            let input = input.join("\n");
+            let input = input.trim();
+            let input = input
+                .strip_prefix("```")
+                .and_then(|fenced| fenced.strip_suffix("```"))
+                .map_or(input, |fenced| {
+                    // skip the fence's language, e.g. `json`
+                    fenced.split_once('\n').map_or(fenced, |(_, body)| body)
+                });
+            let arguments = match serde_json::from_str::<Value>(input) {
+                Ok(arguments) => arguments.to_string(),
+                Err(_) if input.is_empty() => "{}".to_string(),
+                Err(_) => input.to_string(),
+            };
+            ToolCall {
+                id: format!("call_{}", Uuid::new_v4().simple()),
+                kind: ToolKind::Function,
+                function: FunctionCall { name, arguments },
+            }
+        })
+        .collect();
+    (content, calls)
+}
+
+impl LlmClient for EmulatedTools {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            let emulating = !request.tools.is_empty();
+            let request = self.rewrite(request);
+            let mut completion = self.client.complete(request).await?;
+            if emulating {
+                let (content, tool_calls) = parse_calls(&completion.content);
+                tracing::trace!(calls = tool_calls.len(), "read emulated tool calls");
+                completion = Completion {
+                    content,
+                    tool_calls,
+                    ..completion
+                };
+            }
+            Ok(completion)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*, crate::llm::FunctionDefinition, anyhow::Result, serde_json::json,
+        std::sync::Mutex,
+    };
+
+    /// Calls the weather tool, then answers, keeping the requests it gets.
+    #[derive(Debug, Default)]
+    struct Local(Mutex<Vec<CompletionRequest>>);
+
+    impl LlmClient for Local {
+        fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                let mut requests = self.0.lock().unwrap();
+                let content = match requests.len() {
+                    0 => "I'll check.\nAction: weather\nAction Input: ```json\n{\n  \"city\": \"Paris\"\n}\n```",
+                    _ => "It's sunny in Paris.",
+                };
+                requests.push(request);
+                Ok(Completion {
+                    content: content.to_string(),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_emulated_tools() -> Result<()> {
+        let local = Arc::new(Local::default());
+        let client = EmulatedTools::new(local.clone());
+        let mut request = CompletionRequest {
+            model: "llama".to_string(),
+            messages: vec![
+                HistoryMessage::new(Role::System, "Be brief."),
+                HistoryMessage::new(Role::User, "What's the weather in Paris?"),
+            ],
+            tools: vec![ToolDefinition {
+                kind: ToolKind::Function,
+                function: FunctionDefinition {
+                    name: "weather".to_string(),
+                    description: "Returns the weather in a city.".to_string(),
+                    parameters: json!({"type": "object", "properties": {"city": {"type": "string"}}}),
+                },
+            }],
+            ..Default::default()
+        };
+
+        let completion = client.complete(request.clone()).await?;
+        assert_eq!(completion.content, "I'll check.");
+        let [call] = &completion.tool_calls[..] else {
+            panic!("expected one call, got {:?}", completion.tool_calls);
+        };
+        assert_eq!(call.function.name, "weather");
+        assert_eq!(call.function.arguments, r#"{"city":"Paris"}"#);
+
+        request.messages.push(HistoryMessage {
+            tool_calls: completion.tool_calls.clone(),
+            ..HistoryMessage::new(Role::Assistant, &completion.content)
+        });
+        request
+            .messages
+            .push(HistoryMessage::tool_result(&call.id, "sunny"));
+        let completion = client.complete(request).await?;
+        assert_eq!(completion.content, "It's sunny in Paris.");
+        assert!(completion.tool_calls.is_empty());
+
+        let requests = local.0.lock().unwrap();
+        let last = &requests[1];
+        assert!(last.tools.is_empty());
+        assert_eq!(last.messages.len(), 4);
+        assert!(last.messages[0].content.starts_with(
+            "Be brief.\n\nYou can call these tools:\n- weather: Returns the weather in a city."
+        ));
+        assert_eq!(
+            last.messages[2].content,
+            "I'll check.\nAction: weather\nAction Input: {\"city\":\"Paris\"}"
+        );
+        assert!(last.messages[2].tool_calls.is_empty());
+        assert_eq!(
+            (last.messages[3].role, last.messages[3].content.as_str()),
+            (Role::User, "Observation from weather: sunny")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_calls() {
+        let (content, calls) =
+            parse_calls("Action: a\nAction Input: {}\nAction: b\nAction Input: not json");
+        assert_eq!(content, "");
+        let calls = calls
+            .iter()
+            .map(|call| {
+                (
+                    call.function.name.as_str(),
+                    call.function.arguments.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(calls, [("a", "{}"), ("b", "not json")]);
+        assert_eq!(parse_calls("No tools needed.").1, []);
+    }
+}
@@ -0,0 +1,285 @@
+//! Estimating what a call to a model will cost before making it. A
+//! [`CostEstimator`] counts the tokens a request would send with a
+//! [`TokenizerRegistry`] and prices them, and the longest reply it allows,
+//! with a [`PriceTable`], so orchestrators can warn users about expensive
+//! calls or pick a cheaper model before sending anything.
+
+use {
+    super::{usage::PriceTable, CompletionRequest, HistoryMessage, Role, Usage},
+    crate::tokenizer::TokenizerRegistry,
+    std::fmt,
+};
+
+/// The longest reply expected when a request doesn't limit its reply.
+pub const DEFAULT_MAX_REPLY_TOKENS: usize = 1000;
+
+/// What a call to a model is expected to cost.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostEstimate {
+    /// The model the call would be made to.
+    pub model: String,
+
+    /// The tokens the call would send, including retrieved context and tool
+    /// definitions.
+    pub prompt_tokens: usize,
+
+    /// The most tokens the reply is expected to take.
+    pub max_completion_tokens: usize,
+
+    /// What sending the prompt costs, in dollars. The call costs at least
+    /// this.
+    pub min_cost: f64,
+
+    /// What the call costs with the longest reply, in dollars.
+    pub max_cost: f64,
+}
+
+impl fmt::Display for CostEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} prompt tokens and up to {} completion tokens, ${:.4} to ${:.4}",
+            self.model,
+            self.prompt_tokens,
+            self.max_completion_tokens,
+            self.min_cost,
+            self.max_cost
+        )
+    }
+}
+
+/// Estimates what calls to models will cost.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::llm::{estimate::CostEstimator, HistoryMessage, Role};
+/// let estimator = CostEstimator::new()
+///     // each call also sends the 5 best matches of about 200 tokens
+///     .with_retrieval(5, 200)
+///     .with_max_reply_tokens(500);
+/// let history = [HistoryMessage::new(
+///     Role::User,
+///     "Summarize our refund policy.",
+/// )];
+///
+/// let estimate = estimator.estimate_cost(&history, "gpt-4");
+/// if estimate.max_cost > 0.05 {
+///     // the cheapest model that could answer instead
+///     let cheaper = estimator.cheapest(&history, ["gpt-4o", "gpt-4o-mini"]);
+///     assert_eq!(
+///         cheaper.map(|estimate| estimate.model).as_deref(),
+///         Some("gpt-4o-mini")
+///     );
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CostEstimator {
+    prices: PriceTable,
+    tokenizers: TokenizerRegistry,
+    max_reply_tokens: usize,
+
+    /// The tokens of context each call retrieves, as hits and tokens per hit.
+    retrieval: (usize, usize),
+}
+
+impl Default for CostEstimator {
+    fn default() -> Self {
+        Self {
+            prices: PriceTable::default(),
+            tokenizers: TokenizerRegistry::default(),
+            max_reply_tokens: DEFAULT_MAX_REPLY_TOKENS,
+            retrieval: (0, 0),
+        }
+    }
+}
+
+impl CostEstimator {
+    /// Create an estimator with the default prices and tokenizers.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Price calls with `prices` instead of the default table.
+    pub fn with_prices(mut self, prices: PriceTable) -> Self {
+        self.prices = prices;
+        self
+    }
+
+    /// Count tokens with `tokenizers` instead of the default ones.
+    pub fn with_tokenizers(mut self, tokenizers: TokenizerRegistry) -> Self {
+        self.tokenizers = tokenizers;
+        self
+    }
+
+    /// Expect replies of up to `max_tokens` when a request doesn't limit its
+    /// reply. Defaults to [`DEFAULT_MAX_REPLY_TOKENS`].
+    pub fn with_max_reply_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_reply_tokens = max_tokens;
+        self
+    }
+
+    /// Expect each call to also send `hits` retrieved passages of about
+    /// `tokens_per_hit` tokens each, e.g. a search's limit and
+    /// [`CostEstimator::average_tokens`] of some passages.
+    pub fn with_retrieval(mut self, hits: usize, tokens_per_hit: usize) -> Self {
+        self.retrieval = (hits, tokens_per_hit);
+        self
+    }
+
+    /// Returns the average number of `model`'s tokens in `passages`, e.g. a
+    /// sample of the chunks retrieval returns, rounded up.
+    pub fn average_tokens<'a>(
+        &self,
+        model: &str,
+        passages: impl IntoIterator<Item = &'a str>,
+    ) -> usize {
+        let (count, tokens) = passages
+            .into_iter()
+            .fold((0, 0), |(count, tokens), passage| {
+                (count + 1, tokens + self.tokenizers.count(model, passage))
+            });
+        match count {
+            0 => 0,
+            _ => tokens.div_ceil(count),
+        }
+    }
+
+    /// Returns what asking `model` to reply to `history` is expected to
+    /// cost.
+    pub fn estimate_cost(&self, history: &[HistoryMessage], model: &str) -> CostEstimate {
+        let prompt_tokens = self.tokenizers.count_messages(model, history);
+        self.estimate(model, prompt_tokens, self.max_reply_tokens)
+    }
+
+    /// Returns what asking `model` to reply to `prompt`, a single user
+    /// message, is expected to cost.
+    pub fn estimate_prompt(&self, prompt: &str, model: &str) -> CostEstimate {
+        self.estimate_cost(&[HistoryMessage::new(Role::User, prompt)], model)
+    }
+
+    /// Returns what sending `request` is expected to cost, counting its
+    /// tool definitions and limiting the reply to its `max_tokens`.
+    pub fn estimate_request(&self, request: &CompletionRequest) -> CostEstimate {
+        let model = &request.model;
+        let tools = request
+            .tools
+            .iter()
+            .map(|tool| {
+                let definition = serde_json::to_string(tool).unwrap_or_default();
+                self.tokenizers.count(model, &definition)
+            })
+            .sum::<usize>();
+        let prompt_tokens = self.tokenizers.count_messages(model, &request.messages) + tools;
+        let max_reply_tokens = request
+            .parameters
+            .max_tokens
+            .map_or(self.max_reply_tokens, |max_tokens| max_tokens as usize);
+        self.estimate(model, prompt_tokens, max_reply_tokens)
+    }
+
+    /// Returns the estimate for the model of `models` whose reply to
+    /// `history` is expected to cost least at most, or None if there are no
+    /// models.
+    pub fn cheapest<'a>(
+        &self,
+        history: &[HistoryMessage],
+        models: impl IntoIterator<Item = &'a str>,
+    ) -> Option<CostEstimate> {
+        models
+            .into_iter()
+            .map(|model| self.estimate_cost(history, model))
+            .min_by(|a, b| a.max_cost.total_cmp(&b.max_cost))
+    }
+
+    /// Returns the estimate of a call to `model` sending `prompt_tokens`,
+    /// plus retrieved context, with a reply of up to `max_reply_tokens`.
+    fn estimate(&self, model: &str, prompt_tokens: usize, max_reply_tokens: usize) -> CostEstimate {
+        let (hits, tokens_per_hit) = self.retrieval;
+        let prompt_tokens = prompt_tokens + hits * tokens_per_hit;
+        let pricing = self.prices.get(model).unwrap_or_default();
+        let cost = |completion_tokens| {
+            pricing.cost(Usage {
+                prompt_tokens,
+                completion_tokens,
+            })
+        };
+        CostEstimate {
+            model: model.to_string(),
+            prompt_tokens,
+            max_completion_tokens: max_reply_tokens,
+            min_cost: cost(0),
+            max_cost: cost(max_reply_tokens),
+        }
+    }
+}
+
+/// Returns what asking `model` to reply to `history` is expected to cost,
+/// with the default prices and tokenizers. See [`CostEstimator`].
+pub fn estimate_cost(history: &[HistoryMessage], model: &str) -> CostEstimate {
+    CostEstimator::new().estimate_cost(history, model)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            llm::{usage::Pricing, Parameters},
+            tokenizer::Estimate,
+        },
+        anyhow::Result,
+    };
+
+    #[test]
+    fn test_estimate_cost() -> Result<()> {
+        let estimator = CostEstimator::new()
+            .with_prices(
+                PriceTable::new()
+                    .with_price("big", Pricing::new(10.0, 20.0))
+                    .with_price("small", Pricing::new(1.0, 2.0)),
+            )
+            .with_tokenizers(
+                TokenizerRegistry::new().with_fallback(Estimate { chars_per_token: 4 }),
+            )
+            .with_max_reply_tokens(100);
+        // "12345678" is 2 tokens, with 3 for the message and 3 for the reply
+        let history = [HistoryMessage::new(Role::User, "12345678")];
+
+        let estimate = estimator.estimate_cost(&history, "big");
+        assert_eq!(
+            (estimate.prompt_tokens, estimate.max_completion_tokens),
+            (8, 100)
+        );
+        assert!((estimate.min_cost - 0.08).abs() < 1e-9);
+        assert!((estimate.max_cost - 2.08).abs() < 1e-9);
+
+        // 1 and 2 tokens, rounded up
+        let tokens_per_hit = estimator.average_tokens("big", ["1234", "12345678"]);
+        let estimator = estimator.with_retrieval(2, tokens_per_hit);
+        assert_eq!(
+            estimator.estimate_prompt("12345678", "big").prompt_tokens,
+            8 + 2 * 2
+        );
+
+        let request = CompletionRequest {
+            model: "small".to_string(),
+            messages: history.to_vec(),
+            parameters: Parameters {
+                max_tokens: Some(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            estimator.estimate_request(&request).max_completion_tokens,
+            10
+        );
+
+        let cheapest = estimator.cheapest(&history, ["big", "small", "unpriced"]);
+        assert_eq!(
+            cheapest.map(|estimate| estimate.model).as_deref(),
+            Some("unpriced")
+        );
+        Ok(())
+    }
+}
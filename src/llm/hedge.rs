@@ -0,0 +1,262 @@
+//! Latency control for backend calls. A [`Hedged`] client gives each call a
+//! timeout budget and, optionally, hedges slow calls: if a reply hasn't
+//! arrived by the backend's usual worst-case latency, a second attempt is
+//! sent and whichever replies first wins. Interactive agents trade a few
+//! extra requests for not waiting on the occasional stuck one.
+
+use {
+    super::{Completion, CompletionRequest, Delta, Error, LlmClient, LlmFuture},
+    crate::agent::{Clock, SystemClock},
+    std::{
+        collections::VecDeque,
+        future::Future,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+};
+
+/// When to send a second attempt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hedge {
+    /// Hedge after this percentile of recent latencies, from 0 to 1.
+    pub percentile: f64,
+
+    /// The number of recent latencies the percentile is taken over.
+    pub window: usize,
+
+    /// Hedge after this delay until enough latencies were seen to take the
+    /// percentile.
+    pub initial_delay: Duration,
+
+    /// The number of latencies needed to take the percentile.
+    pub min_samples: usize,
+}
+
+impl Default for Hedge {
+    fn default() -> Self {
+        Self {
+            percentile: 0.95,
+            window: 100,
+            initial_delay: Duration::from_secs(5),
+            min_samples: 20,
+        }
+    }
+}
+
+/// Wraps a client with a timeout budget and hedged retries.
+///
+/// Only [`LlmClient::complete`] is hedged, since streamed replies can't be
+/// raced without showing both. Streaming calls still get the budget.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::assistant::AssistantBuilder, llm::{hedge::{Hedge, Hedged}, openai}}, std::{sync::Arc, time::Duration}};
+/// let client = Hedged::new(Arc::new(openai::Client::new(None, None)))
+///     .with_timeout(Duration::from_secs(20))
+///     .with_hedge(Hedge::default());
+/// let assistant = AssistantBuilder::new().with_client(Arc::new(client));
+/// ```
+#[derive(Debug)]
+pub struct Hedged {
+    client: Arc<dyn LlmClient>,
+    timeout: Option<Duration>,
+    hedge: Option<Hedge>,
+    /// The latencies of recent successful attempts, oldest first.
+    latencies: Mutex<VecDeque<Duration>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Hedged {
+    /// Wrap `client`. Calls have no budget and aren't hedged until
+    /// configured.
+    pub fn new(client: Arc<dyn LlmClient>) -> Self {
+        Self {
+            client,
+            timeout: None,
+            hedge: None,
+            latencies: Default::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Fail calls, hedged attempts included, that take longer than `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Send a second attempt when a call is slow.
+    pub fn with_hedge(mut self, hedge: Hedge) -> Self {
+        self.hedge = Some(hedge);
+        self
+    }
+
+    /// Measure latencies and budgets on `clock`.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Returns how long a call waits before hedging, or None if calls aren't
+    /// hedged.
+    pub fn hedge_delay(&self) -> Option<Duration> {
+        let hedge = self.hedge?;
+        let latencies = self.latencies.lock().unwrap();
+        if latencies.len() < hedge.min_samples.max(1) {
+            return Some(hedge.initial_delay);
+        }
+        let mut latencies = latencies.iter().copied().collect::<Vec<_>>();
+        latencies.sort();
+        let index = ((latencies.len() - 1) as f64 * hedge.percentile.clamp(0.0, 1.0)).round();
+        Some(latencies[index as usize])
+    }
+
+    /// Make one attempt, recording its latency if it succeeds.
+    async fn attempt(&self, request: CompletionRequest) -> Result<Completion, Error> {
+        let started = self.clock.now();
+        let completion = self.client.complete(request).await?;
+        if let Some(hedge) = self.hedge {
+            let mut latencies = self.latencies.lock().unwrap();
+            latencies.push_back(self.clock.now().saturating_duration_since(started));
+            while latencies.len() > hedge.window.max(1) {
+                latencies.pop_front();
+            }
+        }
+        Ok(completion)
+    }
+
+    /// Make an attempt and, if it's slow, a second one. Returns the first
+    /// reply, or the second attempt's error if both fail.
+    async fn hedged(&self, request: CompletionRequest) -> Result<Completion, Error> {
+        let Some(delay) = self.hedge_delay() else {
+            return self.attempt(request).await;
+        };
+        let first = self.attempt(request.clone());
+        tokio::pin!(first);
+        tokio::select! {
+            result = &mut first => return result,
+            () = self.clock.sleep(delay) => {}
+        }
+
+        tracing::trace!(?delay, "hedging slow request");
+        let second = self.attempt(request);
+        tokio::pin!(second);
+        tokio::select! {
+            result = &mut first => match result {
+                Ok(completion) => Ok(completion),
+                Err(_) => second.await,
+            },
+            result = &mut second => match result {
+                Ok(completion) => Ok(completion),
+                Err(_) => first.await,
+            },
+        }
+    }
+
+    /// Run `call` within the budget, if there is one.
+    async fn within_budget<T>(
+        &self,
+        call: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let Some(timeout) = self.timeout else {
+            return call.await;
+        };
+        tokio::select! {
+            result = call => result,
+            () = self.clock.sleep(timeout) => Err(Error::Timeout(timeout)),
+        }
+    }
+}
+
+impl LlmClient for Hedged {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(self.within_budget(self.hedged(request)))
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: CompletionRequest,
+        on_delta: &'a mut (dyn FnMut(Delta<'_>) + Send),
+    ) -> LlmFuture<'a> {
+        Box::pin(self.within_budget(self.client.stream(request, on_delta)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        anyhow::Result,
+        std::sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Takes each attempt's delay from the list, in order, and replies with
+    /// the attempt's number.
+    #[derive(Debug)]
+    struct Slow {
+        delays: Vec<Duration>,
+        attempts: AtomicUsize,
+    }
+
+    impl Slow {
+        fn new(delays: &[u64]) -> Arc<Self> {
+            Arc::new(Self {
+                delays: delays.iter().copied().map(Duration::from_millis).collect(),
+                attempts: Default::default(),
+            })
+        }
+    }
+
+    impl LlmClient for Slow {
+        fn complete(&self, _request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(self.delays[attempt]).await;
+                Ok(Completion {
+                    content: attempt.to_string(),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    fn request() -> CompletionRequest {
+        CompletionRequest {
+            model: "any".to_string(),
+            messages: Vec::new(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout_budget() -> Result<()> {
+        let client = Hedged::new(Slow::new(&[10, 10_000])).with_timeout(Duration::from_millis(200));
+        assert_eq!(client.complete(request()).await?.content, "0");
+        assert!(matches!(
+            client.complete(request()).await,
+            Err(Error::Timeout(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hedges_slow_calls() -> Result<()> {
+        let hedge = Hedge {
+            initial_delay: Duration::from_millis(50),
+            min_samples: 2,
+            ..Default::default()
+        };
+        let slow = Slow::new(&[10_000, 10, 20, 5]);
+        let client = Hedged::new(slow.clone()).with_hedge(hedge);
+
+        // the first attempt is stuck, so the hedged second attempt wins
+        assert_eq!(client.complete(request()).await?.content, "1");
+        assert_eq!(client.hedge_delay(), Some(hedge.initial_delay));
+        assert_eq!(client.complete(request()).await?.content, "2");
+        // once enough latencies were seen, the delay follows them
+        assert!(client.hedge_delay() < Some(hedge.initial_delay));
+        assert_eq!(client.complete(request()).await?.content, "3");
+        assert_eq!(slow.attempts.load(Ordering::SeqCst), 4);
+        Ok(())
+    }
+}
@@ -0,0 +1,374 @@
+//! Clients for large language models. Assistants talk to models through the
+//! [`LlmClient`] trait, so any provider can back them; [`openai::Client`],
+//! [`azure::Client`], [`anthropic::Client`] and [`ollama::Client`], for local
+//! models, are some such backends, and [`offline::Offline`] stands in for a
+//! model when there's none to call. What a call will cost can be
+//! [estimated](estimate::CostEstimator) before making it, and replies that
+//! are no use can be [asked for again](reprompt::Reprompt). Backends that
+//! can't call tools can have calls [emulated](emulate::EmulatedTools).
+
+use {
+    serde::{Deserialize, Serialize},
+    std::{fmt::Debug, future::Future, pin::Pin, time::Duration},
+};
+
+pub mod anthropic;
+pub mod azure;
+pub mod emulate;
+pub mod estimate;
+pub mod hedge;
+pub mod offline;
+pub mod ollama;
+pub mod openai;
+pub mod reprompt;
+pub mod retry;
+pub mod router;
+pub mod speculative;
+pub mod usage;
+
+/// A boxed future returned by [`LlmClient`]'s methods.
+pub type LlmFuture<'a> = Pin<Box<dyn Future<Output = Result<Completion, Error>> + Send + 'a>>;
+
+/// Errors that can occur when asking a model for a completion.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    OpenAi(#[from] openai::Error),
+
+    #[error(transparent)]
+    Azure(#[from] azure::Error),
+
+    #[error(transparent)]
+    Anthropic(#[from] anthropic::Error),
+
+    #[error(transparent)]
+    Ollama(#[from] ollama::Error),
+
+    /// The backend didn't reply within the call's budget.
+    #[error("no reply within {0:?}")]
+    Timeout(std::time::Duration),
+
+    /// The calls made so far cost more than allowed.
+    #[error("spent ${spent:.4} of a ${budget:.4} budget")]
+    OverBudget { spent: f64, budget: f64 },
+
+    /// The call was interrupted because the work it was for was aborted.
+    #[error("cancelled")]
+    Cancelled,
+
+    /// An error from another backend.
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    /// Returns whether the call may succeed if it's made again: the backend
+    /// was rate limited, failed on its end, couldn't be reached or took too
+    /// long.
+    pub fn is_transient(&self) -> bool {
+        let transient_status = |status: u16| status == 408 || status == 429 || status >= 500;
+        let transient_request = |e: &reqwest::Error| e.is_timeout() || e.is_connect();
+        match self {
+            Self::OpenAi(openai::Error::ApiError { status, .. })
+            | Self::Azure(azure::Error::ApiError { status, .. })
+            | Self::Anthropic(anthropic::Error::ApiError { status, .. })
+            | Self::Ollama(ollama::Error::ApiError { status, .. }) => transient_status(*status),
+            Self::OpenAi(openai::Error::RequestError(e))
+            | Self::Azure(azure::Error::RequestError(e))
+            | Self::Anthropic(anthropic::Error::RequestError(e))
+            | Self::Ollama(ollama::Error::RequestError(e)) => transient_request(e),
+            // e.g. the API was overloaded mid-reply
+            Self::Anthropic(anthropic::Error::StreamError(_)) => true,
+            Self::Timeout(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns how long the backend asked to wait before calling it again,
+    /// if it did.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::OpenAi(openai::Error::ApiError { retry_after, .. })
+            | Self::Azure(azure::Error::ApiError { retry_after, .. })
+            | Self::Anthropic(anthropic::Error::ApiError { retry_after, .. })
+            | Self::Ollama(ollama::Error::ApiError { retry_after, .. }) => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// Returns how long a response asks to wait before retrying, from its
+/// `retry-after-ms` or `retry-after` header. Retry-after dates aren't read.
+pub(crate) fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let header = |name: &str| {
+        headers
+            .get(name)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|value| value.is_finite() && *value >= 0.0)
+    };
+    header("retry-after-ms")
+        .map(|ms| ms / 1000.0)
+        .or_else(|| header("retry-after"))
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+}
+
+/// A client for a model provider.
+pub trait LlmClient: Debug + Send + Sync + 'static {
+    /// Ask the model to continue the conversation. Returns the model's reply.
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_>;
+
+    /// Like [`LlmClient::complete`], but streams the reply, calling
+    /// `on_delta` with each piece of content or reasoning as it arrives.
+    /// Returns the complete reply.
+    ///
+    /// By default the reply isn't streamed: it is passed to `on_delta` in one
+    /// piece once it's complete.
+    fn stream<'a>(
+        &'a self,
+        request: CompletionRequest,
+        on_delta: &'a mut (dyn FnMut(Delta<'_>) + Send),
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            let completion = self.complete(request).await?;
+            if let Some(reasoning) = &completion.reasoning {
+                on_delta(Delta::Reasoning(reasoning));
+            }
+            if !completion.content.is_empty() {
+                on_delta(Delta::Content(&completion.content));
+            }
+            Ok(completion)
+        })
+    }
+}
+
+/// A request for a model to continue a conversation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompletionRequest {
+    /// The model to ask.
+    pub model: String,
+
+    /// The conversation so far.
+    pub messages: Vec<HistoryMessage>,
+
+    /// The tools the model can call.
+    pub tools: Vec<ToolDefinition>,
+
+    /// How the model samples its reply.
+    pub parameters: Parameters,
+
+    /// The shape the reply's content must take, if constrained.
+    pub response_format: Option<ResponseFormat>,
+}
+
+/// Constrains a reply's content to JSON matching a schema. Backends that
+/// can't constrain replies directly make the model call a tool taking the
+/// schema, and reply with the call's arguments.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResponseFormat {
+    /// The name of the schema.
+    pub name: String,
+
+    /// The JSON schema the reply must match.
+    pub schema: serde_json::Value,
+}
+
+impl ResponseFormat {
+    /// Create a format constraining replies to `schema`.
+    pub fn new(name: impl ToString, schema: serde_json::Value) -> Self {
+        Self {
+            name: name.to_string(),
+            schema,
+        }
+    }
+
+    /// Create a format constraining replies to JSON that deserializes into
+    /// `T`.
+    pub fn of<T: schemars::JsonSchema>() -> Self {
+        let schema = schemars::schema_for!(T);
+        // schema names are the type's, e.g. `Vec_of_String`, and some APIs
+        // only take names of letters, digits, underscores and hyphens
+        let name = T::schema_name()
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+                true => c,
+                false => '_',
+            })
+            .collect::<String>();
+        Self::new(name, serde_json::to_value(schema).unwrap_or_default())
+    }
+}
+
+/// How a model samples its reply. Unset parameters are left to the
+/// backend's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Parameters {
+    /// How random the reply is, usually from 0 to 2.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+
+    /// Only sample from the most likely tokens making up this probability
+    /// mass.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+
+    /// The most tokens the reply may have.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+
+    /// Sequences that end the reply when generated.
+    #[serde(default, rename = "stop", skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+}
+
+/// Who a message in a conversation came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Instructions for the model.
+    System,
+
+    /// A message the assistant received.
+    User,
+
+    /// A reply the assistant generated.
+    Assistant,
+
+    /// The result of a tool the assistant called.
+    Tool,
+}
+
+/// A message in an assistant's conversation history.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryMessage {
+    /// Who the message came from.
+    pub role: Role,
+
+    /// The content of the message.
+    pub content: String,
+
+    /// The tools the assistant asked to call, if this is an assistant
+    /// message.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+
+    /// The call this message answers, if this is a tool message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl HistoryMessage {
+    /// Create a message with no tool calls.
+    pub fn new(role: Role, content: impl ToString) -> Self {
+        Self {
+            role,
+            content: content.to_string(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    /// Create a message holding the result of the tool call with `call_id`.
+    pub fn tool_result(call_id: impl ToString, content: impl ToString) -> Self {
+        Self {
+            tool_call_id: Some(call_id.to_string()),
+            ..Self::new(Role::Tool, content)
+        }
+    }
+}
+
+/// The kind of a tool. Functions are the only kind of tool for now.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolKind {
+    #[default]
+    Function,
+}
+
+/// A model's request to call a tool.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// Identifies the call, so its result can be matched to it.
+    pub id: String,
+
+    /// The kind of tool called.
+    #[serde(rename = "type", default)]
+    pub kind: ToolKind,
+
+    /// The function to call.
+    pub function: FunctionCall,
+}
+
+/// The function a model asked to call.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    /// The name of the function.
+    pub name: String,
+
+    /// The arguments, as a JSON-encoded object.
+    pub arguments: String,
+}
+
+/// A tool offered to a model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolDefinition {
+    /// The kind of tool.
+    #[serde(rename = "type", default)]
+    pub kind: ToolKind,
+
+    /// The function the model can call.
+    pub function: FunctionDefinition,
+}
+
+/// A function offered to a model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    /// The name the model calls the function by.
+    pub name: String,
+
+    /// What the function does, for the model to decide when to call it.
+    pub description: String,
+
+    /// A JSON schema of the function's arguments.
+    pub parameters: serde_json::Value,
+}
+
+/// A model's reply.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Completion {
+    /// The content of the reply.
+    pub content: String,
+
+    /// The reasoning the model did before replying, for models that expose
+    /// it. It is not part of the reply.
+    pub reasoning: Option<String>,
+
+    /// The tools the model asked to call before it replies.
+    pub tool_calls: Vec<ToolCall>,
+
+    /// The tokens the call took, if the backend reported them.
+    pub usage: Option<Usage>,
+}
+
+/// The tokens a call to a model took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// The tokens of the messages sent.
+    pub prompt_tokens: usize,
+
+    /// The tokens of the reply.
+    pub completion_tokens: usize,
+}
+
+/// A piece of a streamed reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delta<'a> {
+    /// A piece of the reply's content.
+    Content(&'a str),
+
+    /// A piece of the model's reasoning.
+    Reasoning(&'a str),
+}
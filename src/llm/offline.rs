@@ -0,0 +1,164 @@
+//! Working without a model. An [`Offline`] client replies to every request
+//! with a placeholder filled in from a template, without calling a backend,
+//! so the structure of a workflow can be developed and tested without
+//! credentials or cost. Assistants fall back to it when no API key is
+//! configured.
+
+use super::{
+    Completion, CompletionRequest, FunctionCall, LlmClient, LlmFuture, Role, ToolCall, Usage,
+};
+
+/// The placeholder replies are made from when no template is configured.
+pub const DEFAULT_TEMPLATE: &str = "[offline reply from {model} to: {message}]";
+
+/// A client that replies with placeholders instead of calling a model.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::llm::{offline::Offline, CompletionRequest, HistoryMessage, LlmClient, Role};
+/// # tokio_test::block_on(async {
+/// let client = Offline::new().with_template("{model} would answer {message:?}");
+/// let request = CompletionRequest {
+///     model: "gpt-4o".to_string(),
+///     messages: vec![HistoryMessage::new(Role::User, "hi")],
+///     ..Default::default()
+/// };
+/// let completion = client.complete(request).await?;
+/// assert_eq!(completion.content, "gpt-4o would answer \"hi\"");
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct Offline {
+    template: String,
+    tool_calls: bool,
+}
+
+impl Default for Offline {
+    fn default() -> Self {
+        Self {
+            template: DEFAULT_TEMPLATE.to_string(),
+            tool_calls: false,
+        }
+    }
+}
+
+impl Offline {
+    /// Create a client that replies with [`DEFAULT_TEMPLATE`].
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Reply with `template`, where `{model}` is replaced by the model asked
+    /// and `{message}` by the last user message, or `{message:?}` by it
+    /// quoted.
+    pub fn with_template(mut self, template: impl ToString) -> Self {
+        self.template = template.to_string();
+        self
+    }
+
+    /// Ask to call each tool offered once, with no arguments, before
+    /// replying, so the way tool calls are routed can be exercised too.
+    pub fn with_tool_calls(mut self) -> Self {
+        self.tool_calls = true;
+        self
+    }
+
+    /// Returns the placeholder reply to `request`.
+    fn reply(&self, request: &CompletionRequest) -> String {
+        let message = request
+            .messages
+            .iter()
+            .rev()
+            .find(|message| message.role == Role::User)
+            .map_or("", |message| message.content.as_str());
+        self.template
+            .replace("{model}", &request.model)
+            .replace("{message:?}", &format!("{message:?}"))
+            .replace("{message}", message)
+    }
+}
+
+impl LlmClient for Offline {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            // call the tools once, then reply to their results
+            let answered = request
+                .messages
+                .last()
+                .is_some_and(|message| message.role == Role::Tool);
+            let tool_calls = match self.tool_calls && !answered {
+                true => request
+                    .tools
+                    .iter()
+                    .enumerate()
+                    .map(|(i, tool)| ToolCall {
+                        id: format!("offline-{i}"),
+                        kind: Default::default(),
+                        function: FunctionCall {
+                            name: tool.function.name.clone(),
+                            arguments: "{}".to_string(),
+                        },
+                    })
+                    .collect(),
+                false => Vec::new(),
+            };
+            tracing::debug!(
+                model = request.model,
+                tool_calls = tool_calls.len(),
+                "replying offline"
+            );
+            Ok(Completion {
+                content: match tool_calls.is_empty() {
+                    true => self.reply(&request),
+                    false => String::new(),
+                },
+                reasoning: None,
+                tool_calls,
+                usage: Some(Usage::default()),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::llm::{FunctionDefinition, HistoryMessage, ToolDefinition, ToolKind},
+        anyhow::Result,
+        serde_json::json,
+    };
+
+    #[tokio::test]
+    async fn test_tool_calls() -> Result<()> {
+        let client = Offline::new().with_tool_calls();
+        let mut request = CompletionRequest {
+            model: "gpt-4o".to_string(),
+            messages: vec![HistoryMessage::new(Role::User, "what's the weather?")],
+            tools: vec![ToolDefinition {
+                kind: ToolKind::Function,
+                function: FunctionDefinition {
+                    name: "weather".to_string(),
+                    description: "Looks up the weather.".to_string(),
+                    parameters: json!({ "type": "object" }),
+                },
+            }],
+            ..Default::default()
+        };
+        let completion = client.complete(request.clone()).await?;
+        assert_eq!(completion.tool_calls.len(), 1);
+        assert_eq!(completion.tool_calls[0].function.name, "weather");
+
+        request
+            .messages
+            .push(HistoryMessage::tool_result("offline-0", "sunny"));
+        let completion = client.complete(request).await?;
+        assert!(completion.tool_calls.is_empty());
+        assert_eq!(
+            completion.content,
+            "[offline reply from gpt-4o to: what's the weather?]"
+        );
+        Ok(())
+    }
+}
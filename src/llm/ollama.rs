@@ -0,0 +1,739 @@
+//! A client for Ollama's chat API, to back assistants with models running
+//! locally, so they work without network access or API keys.
+//!
+//! Ollama doesn't identify tool calls, so calls are given ids in the order
+//! they're made, and tool results are sent with the name of the tool that
+//! produced them.
+
+use {
+    super::{
+        openai::{MAX_LINE_BYTES, MAX_RESPONSE_BYTES, MAX_TOOL_CALLS},
+        Completion, CompletionRequest, Delta, FunctionCall, HistoryMessage, LlmClient, LlmFuture,
+        Parameters, Role, ToolCall, ToolDefinition, ToolKind, Usage,
+    },
+    serde::{Deserialize, Serialize},
+    serde_json::Value,
+    std::{collections::HashMap, time::Duration},
+};
+
+/// The address Ollama listens on by default.
+pub const DEFAULT_BASE_URL: &str = "http://localhost:11434";
+
+/// The OLLAMA_HOST environment variable can be used to override the default
+/// base URL, as it is for Ollama itself. The scheme may be left out.
+pub const BASE_URL_ENV_VAR: &str = "OLLAMA_HOST";
+
+/// Errors that can occur when calling Ollama.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("request to Ollama failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+
+    #[error("Ollama returned {status}: {message}")]
+    ApiError {
+        status: u16,
+        message: String,
+        /// How long the API asked to wait before retrying, if it did.
+        retry_after: Option<std::time::Duration>,
+    },
+
+    #[error("Ollama failed while streaming: {0}")]
+    StreamError(String),
+
+    #[error("Ollama response exceeded {limit} bytes")]
+    ResponseTooLarge { limit: usize },
+
+    #[error("Ollama returned an invalid response: {0}")]
+    InvalidResponse(String),
+}
+
+/// The body of a chat request.
+#[derive(Debug, Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<ApiMessage>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tools: &'a [ToolDefinition],
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<Options<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'a Value>,
+}
+
+/// How the model samples its reply, as Ollama takes it.
+#[derive(Debug, Serialize)]
+struct Options<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u32>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    stop: &'a [String],
+}
+
+/// A message as Ollama sends and receives it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ApiMessage {
+    #[serde(default)]
+    role: String,
+    #[serde(default)]
+    content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thinking: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ApiToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ApiToolCall {
+    function: ApiFunctionCall,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ApiFunctionCall {
+    name: String,
+    /// The arguments, as an object rather than encoded JSON.
+    #[serde(default)]
+    arguments: Value,
+}
+
+/// A chat response, or one line of a streamed one.
+#[derive(Debug, Deserialize)]
+struct ChatResponse {
+    #[serde(default)]
+    message: ApiMessage,
+    /// The tokens of the prompt, on the last line.
+    prompt_eval_count: Option<usize>,
+    /// The tokens of the reply, on the last line.
+    eval_count: Option<usize>,
+}
+
+impl ChatResponse {
+    /// Returns the tokens the call took, if this is the last line.
+    fn usage(&self) -> Option<Usage> {
+        Some(Usage {
+            prompt_tokens: self.prompt_eval_count?,
+            completion_tokens: self.eval_count?,
+        })
+    }
+}
+
+/// The body of an error response, or an error line of a streamed one.
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// A client for Ollama's chat API.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::assistant::AssistantBuilder, llm::ollama}, std::{sync::Arc, time::Duration}};
+/// # tokio_test::block_on(async {
+/// let client = ollama::Client::new(None)
+///     .with_model("llama3.1")
+///     .with_keep_alive(Duration::from_secs(30 * 60));
+/// let assistant = AssistantBuilder::new()
+///     .with_client(Arc::new(client))
+///     .build();
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// The HTTP client used to make requests.
+    http: reqwest::Client,
+
+    /// The base URL of the server, without a trailing slash.
+    base_url: String,
+
+    /// The model used instead of the one requested, if any.
+    model: Option<String>,
+
+    /// How long the model stays loaded after a request, or `None` for
+    /// Ollama's default.
+    keep_alive: Option<Duration>,
+}
+
+impl Client {
+    /// Create a new client. The base URL falls back to the OLLAMA_HOST
+    /// environment variable, and then to a server on this machine.
+    pub fn new(base_url: Option<String>) -> Self {
+        let base_url = base_url
+            .or_else(|| std::env::var(BASE_URL_ENV_VAR).ok())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+        let base_url = match base_url.contains("://") {
+            true => base_url,
+            false => format!("http://{base_url}"),
+        };
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model: None,
+            keep_alive: None,
+        }
+    }
+
+    /// Ask `model` whatever model a request names, e.g. so an assistant
+    /// built for a hosted model runs on a local one.
+    pub fn with_model(mut self, model: impl ToString) -> Self {
+        self.model = Some(model.to_string());
+        self
+    }
+
+    /// Keep the model loaded for `keep_alive` after each request, rather
+    /// than for Ollama's default of five minutes.
+    pub fn with_keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = Some(keep_alive);
+        self
+    }
+
+    /// Returns the base URL requests are sent to.
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Ask `model` to continue the conversation, offering it `tools` to call.
+    /// Returns the model's reply, including its thinking if it's enabled.
+    pub async fn chat(
+        &self,
+        model: &str,
+        messages: &[HistoryMessage],
+        tools: &[ToolDefinition],
+    ) -> Result<Completion, Error> {
+        let parameters = Parameters::default();
+        self.send(&self.request(model, &parameters, messages, tools, false))
+            .await
+    }
+
+    /// Like [`Client::chat`], but streams the reply, calling `on_delta` with
+    /// each piece of content or thinking as it arrives. Returns the complete
+    /// reply.
+    pub async fn chat_stream(
+        &self,
+        model: &str,
+        messages: &[HistoryMessage],
+        tools: &[ToolDefinition],
+        mut on_delta: impl FnMut(Delta<'_>),
+    ) -> Result<Completion, Error> {
+        let parameters = Parameters::default();
+        let request = self.request(model, &parameters, messages, tools, true);
+        self.send_stream(&request, &mut on_delta).await
+    }
+
+    /// Send a chat request and return the model's reply.
+    async fn send(&self, request: &ChatRequest<'_>) -> Result<Completion, Error> {
+        let response = self.post("api/chat", request).await?;
+        let response = serde_json::from_slice::<ChatResponse>(&read_body(response).await?)
+            .map_err(|e| Error::InvalidResponse(e.to_string()))?;
+        let mut completion = Completion {
+            usage: response.usage(),
+            content: response.message.content,
+            reasoning: response.message.thinking.filter(|t| !t.is_empty()),
+            tool_calls: Vec::new(),
+        };
+        push_tool_calls(&mut completion, response.message.tool_calls)?;
+        Ok(completion)
+    }
+
+    /// Send a streaming chat request, calling `on_delta` with each piece of
+    /// the reply, and return the complete reply.
+    async fn send_stream(
+        &self,
+        request: &ChatRequest<'_>,
+        mut on_delta: impl FnMut(Delta<'_>),
+    ) -> Result<Completion, Error> {
+        let mut response = self.post("api/chat", request).await?;
+        let mut parser = StreamParser::default();
+        while let Some(chunk) = response.chunk().await? {
+            parser.push(&chunk, &mut on_delta)?;
+        }
+        parser.finish(&mut on_delta)
+    }
+
+    /// Returns the body of a request for `model` to continue `messages`.
+    fn request<'a>(
+        &'a self,
+        model: &'a str,
+        parameters: &'a Parameters,
+        messages: &[HistoryMessage],
+        tools: &'a [ToolDefinition],
+        stream: bool,
+    ) -> ChatRequest<'a> {
+        let options = Options {
+            temperature: parameters.temperature,
+            top_p: parameters.top_p,
+            num_predict: parameters.max_tokens,
+            stop: &parameters.stop_sequences,
+        };
+        ChatRequest {
+            model: self.model.as_deref().unwrap_or(model),
+            messages: api_messages(messages),
+            tools,
+            stream,
+            keep_alive: self
+                .keep_alive
+                .map(|keep_alive| format!("{}s", keep_alive.as_secs())),
+            options: (*parameters != Parameters::default()).then_some(options),
+            format: None,
+        }
+    }
+
+    /// Returns the body that sends `request`.
+    fn body<'a>(&'a self, request: &'a CompletionRequest, stream: bool) -> ChatRequest<'a> {
+        let CompletionRequest {
+            model,
+            messages,
+            tools,
+            parameters,
+            response_format,
+        } = request;
+        ChatRequest {
+            format: response_format.as_ref().map(|format| &format.schema),
+            ..self.request(model, parameters, messages, tools, stream)
+        }
+    }
+
+    /// Send a request to the server's `path`, turning error statuses into
+    /// errors.
+    async fn post(&self, path: &str, body: &impl Serialize) -> Result<reqwest::Response, Error> {
+        let response = self
+            .http
+            .post(format!("{}/{path}", self.base_url))
+            .json(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = super::retry_after(response.headers());
+            let body = read_body(response).await?;
+            let message = serde_json::from_slice::<ErrorResponse>(&body)
+                .map(|e| e.error)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned());
+            return Err(Error::ApiError {
+                status: status.as_u16(),
+                message,
+                retry_after,
+            });
+        }
+        Ok(response)
+    }
+}
+
+impl LlmClient for Client {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move { Ok(self.send(&self.body(&request, false)).await?) })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: CompletionRequest,
+        on_delta: &'a mut (dyn FnMut(Delta<'_>) + Send),
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            Ok(self
+                .send_stream(&self.body(&request, true), on_delta)
+                .await?)
+        })
+    }
+}
+
+/// Returns `messages` as Ollama takes them.
+fn api_messages(messages: &[HistoryMessage]) -> Vec<ApiMessage> {
+    // tool results are matched to calls by the tool's name
+    let mut tool_names = HashMap::new();
+    messages
+        .iter()
+        .map(|message| {
+            let tool_calls = message
+                .tool_calls
+                .iter()
+                .map(|call| {
+                    tool_names.insert(call.id.as_str(), call.function.name.as_str());
+                    ApiToolCall {
+                        function: ApiFunctionCall {
+                            name: call.function.name.clone(),
+                            // Ollama only takes objects
+                            arguments: serde_json::from_str(&call.function.arguments)
+                                .ok()
+                                .filter(Value::is_object)
+                                .unwrap_or_else(|| Value::Object(Default::default())),
+                        },
+                    }
+                })
+                .collect();
+            let tool_name = message
+                .tool_call_id
+                .as_deref()
+                .and_then(|id| tool_names.get(id))
+                .map(|name| name.to_string());
+            ApiMessage {
+                role: match message.role {
+                    Role::System => "system",
+                    Role::User => "user",
+                    Role::Assistant => "assistant",
+                    Role::Tool => "tool",
+                }
+                .to_string(),
+                content: message.content.clone(),
+                thinking: None,
+                tool_calls,
+                tool_name,
+            }
+        })
+        .collect()
+}
+
+/// Adds `calls` to `completion`, giving each an id.
+fn push_tool_calls(completion: &mut Completion, calls: Vec<ApiToolCall>) -> Result<(), Error> {
+    for call in calls {
+        if completion.tool_calls.len() >= MAX_TOOL_CALLS {
+            return Err(Error::InvalidResponse(format!(
+                "more than {MAX_TOOL_CALLS} tool calls"
+            )));
+        }
+        let arguments = match call.function.arguments {
+            Value::Null => "{}".to_string(),
+            arguments => arguments.to_string(),
+        };
+        completion.tool_calls.push(ToolCall {
+            id: format!("call_{}", completion.tool_calls.len()),
+            kind: ToolKind::Function,
+            function: FunctionCall {
+                name: call.function.name,
+                arguments,
+            },
+        });
+    }
+    Ok(())
+}
+
+/// Reads a response body, failing if it's larger than
+/// [`MAX_RESPONSE_BYTES`].
+async fn read_body(mut response: reqwest::Response) -> Result<Vec<u8>, Error> {
+    let too_large = Error::ResponseTooLarge {
+        limit: MAX_RESPONSE_BYTES,
+    };
+    if response
+        .content_length()
+        .is_some_and(|length| length > MAX_RESPONSE_BYTES as u64)
+    {
+        return Err(too_large);
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > MAX_RESPONSE_BYTES {
+            return Err(too_large);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Parses a streamed chat response as it arrives. The response is one JSON
+/// object per line, each holding the next piece of the reply.
+#[derive(Debug, Default)]
+struct StreamParser {
+    /// Bytes of a line that hasn't ended yet.
+    buffer: Vec<u8>,
+
+    /// The number of bytes received so far.
+    received: usize,
+
+    /// The reply so far.
+    completion: Completion,
+}
+
+impl StreamParser {
+    /// Parse the next bytes of the response, calling `on_delta` with each
+    /// piece of content or thinking.
+    fn push(&mut self, bytes: &[u8], mut on_delta: impl FnMut(Delta<'_>)) -> Result<(), Error> {
+        self.received += bytes.len();
+        if self.received > MAX_RESPONSE_BYTES {
+            return Err(Error::ResponseTooLarge {
+                limit: MAX_RESPONSE_BYTES,
+            });
+        }
+        self.buffer.extend_from_slice(bytes);
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+            self.parse_line(&line, &mut on_delta)?;
+        }
+        if self.buffer.len() > MAX_LINE_BYTES {
+            return Err(Error::ResponseTooLarge {
+                limit: MAX_LINE_BYTES,
+            });
+        }
+        Ok(())
+    }
+
+    /// Parse whatever is left of the response and return the complete reply.
+    fn finish(mut self, mut on_delta: impl FnMut(Delta<'_>)) -> Result<Completion, Error> {
+        let line = std::mem::take(&mut self.buffer);
+        self.parse_line(&line, &mut on_delta)?;
+        Ok(self.completion)
+    }
+
+    fn parse_line(
+        &mut self,
+        line: &[u8],
+        on_delta: &mut impl FnMut(Delta<'_>),
+    ) -> Result<(), Error> {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        if let Ok(error) = serde_json::from_str::<ErrorResponse>(line) {
+            return Err(Error::StreamError(error.error));
+        }
+        let response = serde_json::from_str::<ChatResponse>(line)
+            .map_err(|e| Error::InvalidResponse(e.to_string()))?;
+        let completion = &mut self.completion;
+        completion.usage = response.usage().or(completion.usage);
+        let message = response.message;
+        if let Some(thinking) = message.thinking.filter(|t| !t.is_empty()) {
+            on_delta(Delta::Reasoning(&thinking));
+            completion
+                .reasoning
+                .get_or_insert_with(String::new)
+                .push_str(&thinking);
+        }
+        if !message.content.is_empty() {
+            on_delta(Delta::Content(&message.content));
+            completion.content.push_str(&message.content);
+        }
+        // calls arrive whole
+        push_tool_calls(completion, message.tool_calls)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::llm::{openai::mock::read_request, FunctionDefinition},
+        anyhow::Result,
+        serde_json::json,
+        tokio::{io::AsyncWriteExt, net::TcpListener},
+    };
+
+    /// Starts a server that answers every request by echoing the last
+    /// message, after the model and keep-alive in brackets. A message of the
+    /// form `call <tool> <arguments>` is answered with a call to that tool.
+    /// Returns the server's base URL.
+    async fn echo_server() -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buffer = Vec::new();
+                    while let Some((_, request)) = read_request(&mut stream, &mut buffer).await? {
+                        let response = respond(&request);
+                        stream.write_all(response.as_bytes()).await?;
+                    }
+                    std::io::Result::Ok(())
+                });
+            }
+        });
+        Ok(format!("http://{address}"))
+    }
+
+    fn respond(request: &Value) -> String {
+        let model = request["model"].as_str().unwrap_or_default();
+        if model == "missing" {
+            let body = json!({ "error": "model \"missing\" not found, try pulling it first" });
+            return format!(
+                "HTTP/1.1 404 Not Found\r\ncontent-length: {}\r\n\r\n{body}",
+                body.to_string().len()
+            );
+        }
+
+        let last = request["messages"]
+            .as_array()
+            .and_then(|messages| messages.last())
+            .and_then(|message| message["content"].as_str())
+            .unwrap_or_default();
+        let keep_alive = request["keep_alive"].as_str().unwrap_or("default");
+        let content = format!("[{model} {keep_alive}] {last}");
+        let tool_calls = last
+            .strip_prefix("call ")
+            .filter(|_| request["tools"].is_array())
+            .map(|call| call.split_once(' ').unwrap_or((call, "")))
+            .map(|(name, arguments)| {
+                json!([{ "function": { "name": name,
+                    "arguments": serde_json::from_str::<Value>(arguments).unwrap_or(json!({})) } }])
+            });
+
+        let (content_type, body) = if request["stream"] == true {
+            let mut lines = Vec::new();
+            match tool_calls {
+                Some(tool_calls) => lines.push(json!({ "model": model,
+                    "message": { "role": "assistant", "content": "", "tool_calls": tool_calls }, "done": false })),
+                None => {
+                    for token in content.split_inclusive(' ') {
+                        lines.push(json!({ "model": model,
+                            "message": { "role": "assistant", "content": token }, "done": false }));
+                    }
+                }
+            }
+            lines.push(json!({ "model": model,
+                "message": { "role": "assistant", "content": "" }, "done": true,
+                "prompt_eval_count": 10, "eval_count": 4 }));
+            let mut body = String::new();
+            for line in lines {
+                body.push_str(&format!("{line}\n"));
+            }
+            ("application/x-ndjson", body)
+        } else {
+            let message = match tool_calls {
+                Some(tool_calls) => {
+                    json!({ "role": "assistant", "content": "", "tool_calls": tool_calls })
+                }
+                None => json!({ "role": "assistant", "content": content }),
+            };
+            let body = json!({ "model": model, "message": message, "done": true,
+                "prompt_eval_count": 10, "eval_count": 4 });
+            ("application/json", body.to_string())
+        };
+        format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    #[test]
+    fn test_request() {
+        let call = ToolCall {
+            id: "call_0".to_string(),
+            kind: ToolKind::Function,
+            function: FunctionCall {
+                name: "add".to_string(),
+                arguments: r#"{"a":1}"#.to_string(),
+            },
+        };
+        let messages = [
+            HistoryMessage::new(Role::System, "Be brief."),
+            HistoryMessage {
+                tool_calls: vec![call],
+                ..HistoryMessage::new(Role::Assistant, "")
+            },
+            HistoryMessage::tool_result("call_0", "3"),
+        ];
+        let client = Client::new(Some("localhost:11434/".to_string()));
+        assert_eq!(client.base_url(), "http://localhost:11434");
+        let parameters = Parameters::default();
+        let request = client.request("llama", &parameters, &messages, &[], false);
+        let request = serde_json::to_value(request).unwrap();
+        assert_eq!(
+            request,
+            json!({
+                "model": "llama",
+                "messages": [
+                    { "role": "system", "content": "Be brief." },
+                    { "role": "assistant", "content": "",
+                        "tool_calls": [{ "function": { "name": "add", "arguments": { "a": 1 } } }] },
+                    { "role": "tool", "content": "3", "tool_name": "add" },
+                ],
+                "stream": false,
+            })
+        );
+
+        let parameters = Parameters {
+            top_p: Some(0.9),
+            max_tokens: Some(50),
+            ..Default::default()
+        };
+        let request = client.request("llama", &parameters, &messages, &[], false);
+        let request = serde_json::to_value(request).unwrap();
+        assert_eq!(
+            request["options"],
+            json!({ "top_p": 0.9f32, "num_predict": 50 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chat() -> Result<()> {
+        let base_url = echo_server().await?;
+        let client = Client::new(Some(base_url.clone())).with_keep_alive(Duration::from_secs(60));
+        let messages = [HistoryMessage::new(Role::User, "hello there")];
+
+        let reply = client.chat("llama", &messages, &[]).await?;
+        assert_eq!(reply.content, "[llama 60s] hello there");
+        let usage = Usage {
+            prompt_tokens: 10,
+            completion_tokens: 4,
+        };
+        assert_eq!(reply.usage, Some(usage));
+
+        let mut tokens = Vec::new();
+        let reply = client
+            .with_model("qwen")
+            .chat_stream("llama", &messages, &[], |delta| {
+                if let Delta::Content(token) = delta {
+                    tokens.push(token.to_string());
+                }
+            })
+            .await?;
+        assert_eq!(reply.content, "[qwen 60s] hello there");
+        assert_eq!(tokens, ["[qwen ", "60s] ", "hello ", "there"]);
+        assert_eq!(reply.usage, Some(usage));
+
+        assert!(matches!(
+            Client::new(Some(base_url))
+                .chat("missing", &messages, &[])
+                .await,
+            Err(Error::ApiError { status: 404, .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls() -> Result<()> {
+        let client = Client::new(Some(echo_server().await?));
+        let messages = [HistoryMessage::new(Role::User, r#"call add {"a":1,"b":2}"#)];
+        let tools = [ToolDefinition {
+            kind: ToolKind::Function,
+            function: FunctionDefinition {
+                name: "add".to_string(),
+                description: "Adds two numbers.".to_string(),
+                parameters: json!({ "type": "object" }),
+            },
+        }];
+        let expected = [ToolCall {
+            id: "call_0".to_string(),
+            kind: ToolKind::Function,
+            function: FunctionCall {
+                name: "add".to_string(),
+                arguments: r#"{"a":1,"b":2}"#.to_string(),
+            },
+        }];
+
+        let reply = client.chat("llama", &messages, &tools).await?;
+        assert_eq!(reply.tool_calls, expected);
+
+        let reply = client
+            .chat_stream("llama", &messages, &tools, |_| {})
+            .await?;
+        assert_eq!(reply.tool_calls, expected);
+        assert_eq!(reply.content, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_error() {
+        let mut parser = StreamParser::default();
+        assert!(matches!(
+            parser.push(b"{\"error\":\"out of memory\"}\n", |_| {}),
+            Err(Error::StreamError(message)) if message == "out of memory"
+        ));
+    }
+}
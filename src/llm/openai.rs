@@ -0,0 +1,897 @@
+//! A client for the OpenAI chat completions and embeddings APIs.
+
+use {
+    super::{
+        Completion, CompletionRequest, Delta, FunctionCall, HistoryMessage, LlmClient, LlmFuture,
+        Parameters, ResponseFormat, ToolCall, ToolDefinition, ToolKind, Usage,
+    },
+    crate::embedding::{self, EmbedFuture, Embedder, Embedding, EmbeddingRequest},
+    serde::{Deserialize, Serialize},
+};
+
+/// The OpenAI API's base URL.
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// The OPENAI_API_KEY environment variable is used when no API key is
+/// configured.
+pub const API_KEY_ENV_VAR: &str = "OPENAI_API_KEY";
+
+/// The OPENAI_BASE_URL environment variable can be used to override the
+/// default base URL, e.g. to point at an OpenAI-compatible server.
+pub const BASE_URL_ENV_VAR: &str = "OPENAI_BASE_URL";
+
+/// The largest response body accepted, streamed or not.
+pub const MAX_RESPONSE_BYTES: usize = 16 * 1024 * 1024;
+
+/// The longest line accepted in a streamed response.
+pub const MAX_LINE_BYTES: usize = 1024 * 1024;
+
+/// The most tool calls accepted in a single reply.
+pub const MAX_TOOL_CALLS: usize = 128;
+
+/// Errors that can occur when calling the OpenAI API.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("no OpenAI API key configured; set {API_KEY_ENV_VAR} or configure one on the builder")]
+    MissingApiKey,
+
+    #[error("request to OpenAI API failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+
+    #[error("OpenAI API returned {status}: {message}")]
+    ApiError {
+        status: u16,
+        message: String,
+        /// How long the API asked to wait before retrying, if it did.
+        retry_after: Option<std::time::Duration>,
+    },
+
+    #[error("OpenAI API returned no choices")]
+    EmptyResponse,
+
+    #[error("OpenAI API response exceeded {limit} bytes")]
+    ResponseTooLarge { limit: usize },
+
+    #[error("OpenAI API returned an invalid response: {0}")]
+    InvalidResponse(String),
+}
+
+/// The body of a chat completion request. Azure OpenAI takes the same body.
+#[derive(Debug, Serialize)]
+pub(crate) struct ChatCompletionRequest<'a> {
+    pub(crate) model: &'a str,
+    pub(crate) messages: &'a [HistoryMessage],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub(crate) tools: &'a [ToolDefinition],
+    #[serde(flatten)]
+    pub(crate) parameters: &'a Parameters,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) response_format: Option<ApiResponseFormat<'a>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) stream: bool,
+}
+
+/// A response format as the API takes it.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ApiResponseFormat<'a> {
+    JsonSchema { json_schema: &'a ResponseFormat },
+}
+
+impl<'a> ChatCompletionRequest<'a> {
+    /// Returns the body that sends `request`.
+    pub(crate) fn new(request: &'a CompletionRequest, stream: bool) -> Self {
+        Self {
+            model: &request.model,
+            messages: &request.messages,
+            tools: &request.tools,
+            parameters: &request.parameters,
+            response_format: request
+                .response_format
+                .as_ref()
+                .map(|json_schema| ApiResponseFormat::JsonSchema { json_schema }),
+            stream,
+        }
+    }
+}
+
+/// The parts of a chat completion response that we use.
+#[derive(Debug, Deserialize)]
+struct ChatCompletionResponse {
+    choices: Vec<Choice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Choice {
+    message: ChoiceMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChoiceMessage {
+    content: Option<String>,
+    reasoning_content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
+}
+
+/// A chunk of a streamed chat completion.
+#[derive(Debug, Deserialize)]
+struct ChatCompletionChunk {
+    choices: Vec<ChunkChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkChoice {
+    delta: ChunkDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkDelta {
+    content: Option<String>,
+    reasoning_content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCallDelta>,
+}
+
+/// A piece of a streamed tool call. The first piece of each call carries its
+/// id and name; later pieces carry more of its arguments.
+#[derive(Debug, Deserialize)]
+struct ToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionCallDelta>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FunctionCallDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+/// The body of an embeddings request.
+#[derive(Debug, Serialize)]
+struct EmbeddingsRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+/// The parts of an embeddings response that we use.
+#[derive(Debug, Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Embedding,
+}
+
+/// The body of an error response.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ErrorResponse {
+    pub(crate) error: ErrorDetail,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ErrorDetail {
+    pub(crate) message: String,
+}
+
+/// A client for the OpenAI chat completions API.
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// The HTTP client used to make requests.
+    http: reqwest::Client,
+
+    /// The API key sent with each request.
+    api_key: Option<String>,
+
+    /// The base URL of the API, without a trailing slash.
+    base_url: String,
+}
+
+impl Client {
+    /// Create a new client. The API key and base URL fall back to the
+    /// OPENAI_API_KEY and OPENAI_BASE_URL environment variables, and then to
+    /// no key and the public OpenAI API.
+    pub fn new(api_key: Option<String>, base_url: Option<String>) -> Self {
+        let api_key = api_key.or_else(|| std::env::var(API_KEY_ENV_VAR).ok());
+        let base_url = base_url
+            .or_else(|| std::env::var(BASE_URL_ENV_VAR).ok())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+        Self {
+            http: reqwest::Client::new(),
+            api_key,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Returns the base URL requests are sent to.
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Ask `model` to continue the conversation, offering it `tools` to call.
+    /// Returns the model's reply, including its reasoning if the model exposes
+    /// it, e.g. as `reasoning_content`.
+    pub async fn chat_completion(
+        &self,
+        model: &str,
+        messages: &[HistoryMessage],
+        tools: &[ToolDefinition],
+    ) -> Result<Completion, Error> {
+        let request = ChatCompletionRequest {
+            model,
+            messages,
+            tools,
+            parameters: &Parameters::default(),
+            response_format: None,
+            stream: false,
+        };
+        self.send(&request).await
+    }
+
+    /// Like [`Client::chat_completion`], but streams the reply, calling
+    /// `on_delta` with each piece of content or reasoning as it arrives.
+    /// Tool calls are collected as they stream in. Returns the complete reply.
+    pub async fn chat_completion_stream(
+        &self,
+        model: &str,
+        messages: &[HistoryMessage],
+        tools: &[ToolDefinition],
+        mut on_delta: impl FnMut(Delta<'_>),
+    ) -> Result<Completion, Error> {
+        let request = ChatCompletionRequest {
+            model,
+            messages,
+            tools,
+            parameters: &Parameters::default(),
+            response_format: None,
+            stream: true,
+        };
+        self.send_stream(&request, &mut on_delta).await
+    }
+
+    /// Send a chat completion request and return the model's reply.
+    async fn send(&self, request: &ChatCompletionRequest<'_>) -> Result<Completion, Error> {
+        let response = self.post("chat/completions", request).await?;
+        parse_completion(&read_body(response).await?)
+    }
+
+    /// Send a streaming chat completion request, calling `on_delta` with each
+    /// piece of the reply, and return the complete reply.
+    async fn send_stream(
+        &self,
+        request: &ChatCompletionRequest<'_>,
+        mut on_delta: impl FnMut(Delta<'_>),
+    ) -> Result<Completion, Error> {
+        let mut response = self.post("chat/completions", request).await?;
+        let mut parser = StreamParser::new();
+        while let Some(chunk) = response.chunk().await? {
+            parser.push(&chunk, &mut on_delta)?;
+        }
+        parser.finish(&mut on_delta)
+    }
+
+    /// Embed each of `inputs` with `model`. Returns the embeddings in the
+    /// order of the inputs.
+    pub async fn embeddings(
+        &self,
+        model: &str,
+        inputs: &[String],
+    ) -> Result<Vec<Embedding>, Error> {
+        let response = self
+            .post(
+                "embeddings",
+                &EmbeddingsRequest {
+                    model,
+                    input: inputs,
+                },
+            )
+            .await?;
+        let response = serde_json::from_slice::<EmbeddingsResponse>(&read_body(response).await?)
+            .map_err(|e| Error::InvalidResponse(e.to_string()))?;
+        if response.data.len() != inputs.len() {
+            return Err(Error::InvalidResponse(format!(
+                "expected {} embeddings, got {}",
+                inputs.len(),
+                response.data.len()
+            )));
+        }
+        let mut data = response.data;
+        data.sort_by_key(|data| data.index);
+        Ok(data.into_iter().map(|data| data.embedding).collect())
+    }
+
+    /// Send a request to the API's `path`, turning error statuses into
+    /// errors.
+    async fn post(&self, path: &str, body: &impl Serialize) -> Result<reqwest::Response, Error> {
+        let api_key = self.api_key.as_deref().ok_or(Error::MissingApiKey)?;
+        let response = self
+            .http
+            .post(format!("{}/{path}", self.base_url))
+            .bearer_auth(api_key)
+            .json(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = super::retry_after(response.headers());
+            let body = read_body(response).await?;
+            let message = serde_json::from_slice::<ErrorResponse>(&body)
+                .map(|e| e.error.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned());
+            return Err(Error::ApiError {
+                status: status.as_u16(),
+                message,
+                retry_after,
+            });
+        }
+        Ok(response)
+    }
+}
+
+impl LlmClient for Client {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            Ok(self
+                .send(&ChatCompletionRequest::new(&request, false))
+                .await?)
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: CompletionRequest,
+        on_delta: &'a mut (dyn FnMut(Delta<'_>) + Send),
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            Ok(self
+                .send_stream(&ChatCompletionRequest::new(&request, true), on_delta)
+                .await?)
+        })
+    }
+}
+
+impl Embedder for Client {
+    fn embed(&self, request: EmbeddingRequest) -> EmbedFuture<'_> {
+        Box::pin(async move {
+            Ok::<_, embedding::Error>(self.embeddings(&request.model, &request.inputs).await?)
+        })
+    }
+}
+
+/// Reads a response body, failing if it's larger than
+/// [`MAX_RESPONSE_BYTES`].
+pub(crate) async fn read_body(mut response: reqwest::Response) -> Result<Vec<u8>, Error> {
+    let too_large = Error::ResponseTooLarge {
+        limit: MAX_RESPONSE_BYTES,
+    };
+    if response
+        .content_length()
+        .is_some_and(|length| length > MAX_RESPONSE_BYTES as u64)
+    {
+        return Err(too_large);
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > MAX_RESPONSE_BYTES {
+            return Err(too_large);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Parses the body of a chat completion response. Nesting is limited by
+/// serde_json's recursion limit, so malformed input fails rather than
+/// overflowing the stack.
+pub fn parse_completion(body: &[u8]) -> Result<Completion, Error> {
+    let response = serde_json::from_slice::<ChatCompletionResponse>(body)
+        .map_err(|e| Error::InvalidResponse(e.to_string()))?;
+    let choice = response
+        .choices
+        .into_iter()
+        .next()
+        .ok_or(Error::EmptyResponse)?;
+    if choice.message.tool_calls.len() > MAX_TOOL_CALLS {
+        return Err(Error::InvalidResponse(format!(
+            "more than {MAX_TOOL_CALLS} tool calls"
+        )));
+    }
+    Ok(Completion {
+        content: choice.message.content.unwrap_or_default(),
+        reasoning: choice
+            .message
+            .reasoning_content
+            .filter(|reasoning| !reasoning.is_empty()),
+        tool_calls: choice.message.tool_calls,
+        usage: response.usage,
+    })
+}
+
+/// Parses a streamed chat completion as it arrives. The response is a stream
+/// of server-sent events, one per line; lines that aren't events with a
+/// delta are skipped.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::llm::{openai::StreamParser, Delta};
+/// let mut parser = StreamParser::new();
+/// let mut tokens = Vec::new();
+/// let mut on_delta = |delta: Delta<'_>| {
+///     if let Delta::Content(token) = delta {
+///         tokens.push(token.to_string());
+///     }
+/// };
+/// parser.push(
+///     b"data: {\"choices\":[{\"delta\":{\"content\":\"hi\"}}]}\n",
+///     &mut on_delta,
+/// )?;
+/// parser.push(b"data: [DONE]\n", &mut on_delta)?;
+/// assert_eq!(parser.finish(&mut on_delta)?.content, "hi");
+/// assert_eq!(tokens, ["hi"]);
+/// # anyhow::Ok(())
+/// ```
+#[derive(Debug, Default)]
+pub struct StreamParser {
+    /// Bytes of a line that hasn't ended yet.
+    buffer: Vec<u8>,
+
+    /// The number of bytes received so far.
+    received: usize,
+
+    /// The reply so far.
+    completion: Completion,
+}
+
+impl StreamParser {
+    /// Create a parser for a new response.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Parse the next bytes of the response, calling `on_delta` with each
+    /// piece of content or reasoning. Fails if the response or one of its
+    /// lines is too large.
+    pub fn push(&mut self, bytes: &[u8], mut on_delta: impl FnMut(Delta<'_>)) -> Result<(), Error> {
+        self.received += bytes.len();
+        if self.received > MAX_RESPONSE_BYTES {
+            return Err(Error::ResponseTooLarge {
+                limit: MAX_RESPONSE_BYTES,
+            });
+        }
+        self.buffer.extend_from_slice(bytes);
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+            self.parse_line(&line, &mut on_delta)?;
+        }
+        if self.buffer.len() > MAX_LINE_BYTES {
+            return Err(Error::ResponseTooLarge {
+                limit: MAX_LINE_BYTES,
+            });
+        }
+        Ok(())
+    }
+
+    /// Parse whatever is left of the response and return the complete reply.
+    pub fn finish(mut self, mut on_delta: impl FnMut(Delta<'_>)) -> Result<Completion, Error> {
+        let line = std::mem::take(&mut self.buffer);
+        self.parse_line(&line, &mut on_delta)?;
+        Ok(self.completion)
+    }
+
+    fn parse_line(
+        &mut self,
+        line: &[u8],
+        on_delta: &mut impl FnMut(Delta<'_>),
+    ) -> Result<(), Error> {
+        let Some(delta) = parse_event(&String::from_utf8_lossy(line)) else {
+            return Ok(());
+        };
+        let completion = &mut self.completion;
+        if let Some(reasoning) = delta.reasoning_content.filter(|r| !r.is_empty()) {
+            on_delta(Delta::Reasoning(&reasoning));
+            completion
+                .reasoning
+                .get_or_insert_with(String::new)
+                .push_str(&reasoning);
+        }
+        if let Some(content) = delta.content.filter(|c| !c.is_empty()) {
+            on_delta(Delta::Content(&content));
+            completion.content.push_str(&content);
+        }
+        for call in delta.tool_calls {
+            merge_tool_call(&mut completion.tool_calls, call)?;
+        }
+        Ok(())
+    }
+}
+
+/// Extracts the delta from a line of a streamed response, if it has one.
+fn parse_event(line: &str) -> Option<ChunkDelta> {
+    let data = line.trim().strip_prefix("data:")?.trim();
+    if data == "[DONE]" {
+        return None;
+    }
+    serde_json::from_str::<ChatCompletionChunk>(data)
+        .ok()?
+        .choices
+        .into_iter()
+        .next()
+        .map(|choice| choice.delta)
+}
+
+/// Adds a piece of a streamed tool call to the calls collected so far.
+fn merge_tool_call(calls: &mut Vec<ToolCall>, delta: ToolCallDelta) -> Result<(), Error> {
+    // the index is untrusted, so don't let it allocate more than a few calls
+    if delta.index >= MAX_TOOL_CALLS {
+        return Err(Error::InvalidResponse(format!(
+            "tool call index {} exceeds limit of {MAX_TOOL_CALLS}",
+            delta.index
+        )));
+    }
+    while calls.len() <= delta.index {
+        calls.push(ToolCall {
+            id: String::new(),
+            kind: ToolKind::Function,
+            function: FunctionCall {
+                name: String::new(),
+                arguments: String::new(),
+            },
+        });
+    }
+    let call = &mut calls[delta.index];
+    if let Some(id) = delta.id {
+        call.id = id;
+    }
+    if let Some(function) = delta.function {
+        call.function
+            .name
+            .push_str(&function.name.unwrap_or_default());
+        call.function
+            .arguments
+            .push_str(&function.arguments.unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// A fake OpenAI server for tests.
+#[cfg(test)]
+pub(crate) mod mock {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    /// The model the server reasons with before replying.
+    pub(crate) const REASONING_MODEL: &str = "reasoner";
+
+    /// Starts a server that answers every chat completion by echoing the
+    /// last message. Requests for [`REASONING_MODEL`] also get reasoning.
+    /// When tools are offered, a message of the form `call <tool> <arguments>`
+    /// is answered with a call to that tool. Embeddings requests get
+    /// `[length, 1.0]` for each input. Returns the server's base URL.
+    pub(crate) async fn echo_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream));
+            }
+        });
+        format!("http://{address}/v1")
+    }
+
+    /// Reads the next request on `stream`. Returns its headers and its JSON
+    /// body, or `None` once the client hangs up. `buffer` holds what was
+    /// read past the request.
+    pub(crate) async fn read_request(
+        stream: &mut TcpStream,
+        buffer: &mut Vec<u8>,
+    ) -> std::io::Result<Option<(String, serde_json::Value)>> {
+        let mut chunk = [0; 4096];
+        // read a request's headers, then its body
+        let header_end = loop {
+            if let Some(i) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+        };
+        let headers = String::from_utf8_lossy(&buffer[..header_end]).into_owned();
+        let content_length = headers
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("content-length")
+                    .then(|| value.trim().parse::<usize>().ok())?
+            })
+            .unwrap_or(0);
+        while buffer.len() < header_end + content_length {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+
+        let request = serde_json::from_slice(&buffer[header_end..header_end + content_length])
+            .unwrap_or_default();
+        buffer.drain(..header_end + content_length);
+        Ok(Some((headers, request)))
+    }
+
+    async fn serve(mut stream: TcpStream) -> std::io::Result<()> {
+        let mut buffer = Vec::new();
+        while let Some((_, request)) = read_request(&mut stream, &mut buffer).await? {
+            let content = request["messages"]
+                .as_array()
+                .and_then(|messages| messages.last())
+                .and_then(|message| message["content"].as_str())
+                .unwrap_or_default()
+                .to_string();
+            let reasoning =
+                (request["model"] == REASONING_MODEL).then(|| format!("thinking about {content}"));
+            let tool_call = content
+                .strip_prefix("call ")
+                .filter(|_| request["tools"].is_array())
+                .map(|call| call.split_once(' ').unwrap_or((call, "{}")));
+            let (content_type, body) = if let Some(inputs) = request["input"].as_array() {
+                // answer in reverse order, since the API doesn't promise any
+                let data = inputs
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(index, input)| {
+                        let length = input.as_str().unwrap_or_default().len();
+                        serde_json::json!({ "index": index, "embedding": [length, 1.0] })
+                    })
+                    .collect::<Vec<_>>();
+                let body = serde_json::json!({ "data": data, "usage": { "total_tokens": 0 } });
+                ("application/json", body.to_string())
+            } else if request["stream"].as_bool().unwrap_or(false) {
+                // stream the reply one word at a time
+                let mut events = String::new();
+                if let Some(reasoning) = &reasoning {
+                    let chunk = serde_json::json!({
+                        "choices": [{ "delta": { "reasoning_content": reasoning } }]
+                    });
+                    events.push_str(&format!("data: {chunk}\n\n"));
+                }
+                if let Some((name, arguments)) = tool_call {
+                    // stream the call in two pieces, like the real API does
+                    let pieces = [
+                        serde_json::json!({ "index": 0, "id": "call_0", "type": "function",
+                            "function": { "name": name, "arguments": "" } }),
+                        serde_json::json!({ "index": 0, "function": { "arguments": arguments } }),
+                    ];
+                    for piece in pieces {
+                        let chunk = serde_json::json!({ "choices": [{ "delta": { "tool_calls": [piece] } }] });
+                        events.push_str(&format!("data: {chunk}\n\n"));
+                    }
+                }
+                for token in content.split_inclusive(' ').filter(|_| tool_call.is_none()) {
+                    let chunk =
+                        serde_json::json!({ "choices": [{ "delta": { "content": token } }] });
+                    events.push_str(&format!("data: {chunk}\n\n"));
+                }
+                events.push_str("data: [DONE]\n\n");
+                ("text/event-stream", events)
+            } else if let Some((name, arguments)) = tool_call {
+                let body = serde_json::json!({
+                    "choices": [{ "message": {
+                        "role": "assistant",
+                        "content": null,
+                        "tool_calls": [{
+                            "id": "call_0",
+                            "type": "function",
+                            "function": { "name": name, "arguments": arguments },
+                        }],
+                    } }]
+                });
+                ("application/json", body.to_string())
+            } else {
+                let body = serde_json::json!({
+                    "choices": [{ "message": {
+                        "role": "assistant",
+                        "content": content,
+                        "reasoning_content": reasoning,
+                    } }]
+                });
+                ("application/json", body.to_string())
+            };
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\n\r\n{body}",
+                        body.len()
+                    )
+                    .as_bytes(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::llm::Role, anyhow::Result, serde_json::json};
+
+    #[test]
+    fn test_request_parameters() {
+        let request = CompletionRequest {
+            model: "gpt-4".to_string(),
+            parameters: Parameters {
+                temperature: Some(0.5),
+                stop_sequences: vec!["END".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let body = serde_json::to_value(ChatCompletionRequest::new(&request, false)).unwrap();
+        assert_eq!(
+            body,
+            json!({ "model": "gpt-4", "messages": [], "temperature": 0.5, "stop": ["END"] })
+        );
+
+        let request = CompletionRequest {
+            response_format: Some(ResponseFormat::new("point", json!({ "type": "object" }))),
+            ..request
+        };
+        let body = serde_json::to_value(ChatCompletionRequest::new(&request, false)).unwrap();
+        assert_eq!(
+            body["response_format"],
+            json!({
+                "type": "json_schema",
+                "json_schema": { "name": "point", "schema": { "type": "object" } },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion() -> Result<()> {
+        let client = Client::new(Some("key".to_string()), Some(mock::echo_server().await));
+        let reply = client
+            .chat_completion("gpt-4", &[HistoryMessage::new(Role::User, "hello")], &[])
+            .await?;
+        assert_eq!(reply.content, "hello");
+        assert_eq!(reply.reasoning, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_embeddings() -> Result<()> {
+        let client = Client::new(Some("key".to_string()), Some(mock::echo_server().await));
+        let inputs = ["hi", "hello"].map(String::from);
+        assert_eq!(
+            client.embeddings("embed", &inputs).await?,
+            [vec![2.0, 1.0], vec![5.0, 1.0]]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_reasoning() -> Result<()> {
+        let client = Client::new(Some("key".to_string()), Some(mock::echo_server().await));
+        let messages = [HistoryMessage::new(Role::User, "hello")];
+
+        let reply = client
+            .chat_completion(mock::REASONING_MODEL, &messages, &[])
+            .await?;
+        assert_eq!(reply.content, "hello");
+        assert_eq!(reply.reasoning.as_deref(), Some("thinking about hello"));
+
+        let mut reasoning = Vec::new();
+        let reply = client
+            .chat_completion_stream(mock::REASONING_MODEL, &messages, &[], |delta| {
+                if let Delta::Reasoning(r) = delta {
+                    reasoning.push(r.to_string());
+                }
+            })
+            .await?;
+        assert_eq!(reply.content, "hello");
+        assert_eq!(reasoning, ["thinking about hello"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_stream() -> Result<()> {
+        let client = Client::new(Some("key".to_string()), Some(mock::echo_server().await));
+        let mut tokens = Vec::new();
+        let reply = client
+            .chat_completion_stream(
+                "gpt-4",
+                &[HistoryMessage::new(Role::User, "hello there world")],
+                &[],
+                |delta| {
+                    if let Delta::Content(token) = delta {
+                        tokens.push(token.to_string())
+                    }
+                },
+            )
+            .await?;
+        assert_eq!(reply.content, "hello there world");
+        assert_eq!(tokens, ["hello ", "there ", "world"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls() -> Result<()> {
+        let client = Client::new(Some("key".to_string()), Some(mock::echo_server().await));
+        let messages = [HistoryMessage::new(Role::User, r#"call add {"a":1,"b":2}"#)];
+        let tools = [ToolDefinition {
+            kind: ToolKind::Function,
+            function: crate::llm::FunctionDefinition {
+                name: "add".to_string(),
+                description: "Adds two numbers.".to_string(),
+                parameters: serde_json::json!({ "type": "object" }),
+            },
+        }];
+        let expected = [ToolCall {
+            id: "call_0".to_string(),
+            kind: ToolKind::Function,
+            function: FunctionCall {
+                name: "add".to_string(),
+                arguments: r#"{"a":1,"b":2}"#.to_string(),
+            },
+        }];
+
+        let reply = client.chat_completion("gpt-4", &messages, &tools).await?;
+        assert_eq!(reply.tool_calls, expected);
+
+        let reply = client
+            .chat_completion_stream("gpt-4", &messages, &tools, |_| {})
+            .await?;
+        assert_eq!(reply.tool_calls, expected);
+        assert_eq!(reply.content, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_parser_limits() {
+        let mut parser = StreamParser::new();
+        let long_line = vec![b'a'; MAX_LINE_BYTES + 1];
+        assert!(matches!(
+            parser.push(&long_line, |_| {}),
+            Err(Error::ResponseTooLarge { .. })
+        ));
+
+        let mut parser = StreamParser::new();
+        let huge_index = br#"data: {"choices":[{"delta":{"tool_calls":[{"index":1000000000000}]}}]}
+"#;
+        assert!(matches!(
+            parser.push(huge_index, |_| {}),
+            Err(Error::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_completion_rejects_deep_nesting() {
+        let nested = "[".repeat(100_000);
+        assert!(matches!(
+            parse_completion(nested.as_bytes()),
+            Err(Error::InvalidResponse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_missing_api_key() {
+        let client = Client {
+            http: reqwest::Client::new(),
+            api_key: None,
+            base_url: DEFAULT_BASE_URL.to_string(),
+        };
+        assert!(matches!(
+            client.chat_completion("gpt-4", &[], &[]).await,
+            Err(Error::MissingApiKey)
+        ));
+    }
+}
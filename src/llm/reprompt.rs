@@ -0,0 +1,361 @@
+//! Asking again when a model's reply is no use. A [`Reprompt`] client checks
+//! each reply against a [`RepromptPolicy`], and when it's empty, a refusal or
+//! too short, asks the model again with instructions on what was wrong, a
+//! bounded number of times, before settling for the last reply.
+
+use {
+    super::{
+        Completion, CompletionRequest, Delta, HistoryMessage, LlmClient, LlmFuture, Role, Usage,
+    },
+    std::{collections::BTreeMap, fmt, sync::Arc},
+};
+
+/// The openings of replies that refuse, as checked by the default policy.
+pub const DEFAULT_REFUSALS: &[&str] = &[
+    "i'm sorry, but i can't",
+    "i'm sorry, but i cannot",
+    "i can't help with",
+    "i cannot help with",
+    "i can't assist with",
+    "i cannot assist with",
+    "i'm unable to",
+    "i am unable to",
+    "as an ai",
+];
+
+/// What's wrong with a reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ReplyIssue {
+    /// The reply has no content.
+    Empty,
+
+    /// The reply declines to answer.
+    Refusal,
+
+    /// The reply is shorter than the policy's minimum.
+    TooShort,
+}
+
+impl fmt::Display for ReplyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Empty => "empty",
+            Self::Refusal => "refusal",
+            Self::TooShort => "too short",
+        })
+    }
+}
+
+/// Which replies are asked for again, and how.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepromptPolicy {
+    /// The most times the model is asked again for one reply.
+    pub max_reprompts: u32,
+
+    /// The fewest characters a reply may have. Replies aren't checked for
+    /// length if 0.
+    pub min_chars: usize,
+
+    /// The lowercase openings of replies that refuse.
+    pub refusals: Vec<String>,
+
+    /// What the model is told when it's asked again, by what was wrong.
+    pub instructions: BTreeMap<ReplyIssue, String>,
+}
+
+impl Default for RepromptPolicy {
+    fn default() -> Self {
+        let instructions = [
+            (
+                ReplyIssue::Empty,
+                "Your reply was empty. Please answer the last message.",
+            ),
+            (
+                ReplyIssue::Refusal,
+                "Please help with as much of the last message as you can, and say exactly \
+                 what's missing for the rest.",
+            ),
+            (
+                ReplyIssue::TooShort,
+                "Your reply was too short to be useful. Please answer the last message in \
+                 more detail.",
+            ),
+        ];
+        Self {
+            max_reprompts: 2,
+            min_chars: 0,
+            refusals: DEFAULT_REFUSALS.iter().map(|s| s.to_string()).collect(),
+            instructions: instructions
+                .into_iter()
+                .map(|(issue, instruction)| (issue, instruction.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl RepromptPolicy {
+    /// Create a policy that asks again up to `max_reprompts` times.
+    pub fn new(max_reprompts: u32) -> Self {
+        Self {
+            max_reprompts,
+            ..Default::default()
+        }
+    }
+
+    /// Ask again when a reply has fewer than `min_chars` characters.
+    pub fn with_min_chars(mut self, min_chars: usize) -> Self {
+        self.min_chars = min_chars;
+        self
+    }
+
+    /// Treat replies opening with `refusal`, ignoring case, as refusals too.
+    pub fn with_refusal(mut self, refusal: impl ToString) -> Self {
+        self.refusals.push(refusal.to_string().to_lowercase());
+        self
+    }
+
+    /// Tell the model `instruction` when asking again after `issue`.
+    pub fn with_instruction(mut self, issue: ReplyIssue, instruction: impl ToString) -> Self {
+        self.instructions.insert(issue, instruction.to_string());
+        self
+    }
+
+    /// Returns what's wrong with `completion`, or `None` if it will do.
+    /// Replies that call tools will always do.
+    pub fn check(&self, completion: &Completion) -> Option<ReplyIssue> {
+        if !completion.tool_calls.is_empty() {
+            return None;
+        }
+        let content = completion.content.trim();
+        if content.is_empty() {
+            return Some(ReplyIssue::Empty);
+        }
+        // models write apostrophes either way
+        let opening = content.to_lowercase().replace('\u{2019}', "'");
+        if self
+            .refusals
+            .iter()
+            .any(|refusal| opening.starts_with(refusal.as_str()))
+        {
+            return Some(ReplyIssue::Refusal);
+        }
+        (content.chars().count() < self.min_chars).then_some(ReplyIssue::TooShort)
+    }
+
+    /// Returns what the model is told when it's asked again after `issue`.
+    pub fn instruction(&self, issue: ReplyIssue) -> &str {
+        self.instructions
+            .get(&issue)
+            .map_or("Please answer the last message.", String::as_str)
+    }
+}
+
+/// Wraps a client, asking the model again when a reply is no use. Each
+/// attempt is sent with the rejected reply and the policy's instructions
+/// appended to the conversation, and the usage of every attempt is added
+/// up. Once the policy's attempts run out the last reply is returned as is.
+///
+/// A streamed reply that's rejected has already been passed on by the time
+/// it's checked, so the replies asked for after it are streamed after it.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::assistant::AssistantBuilder, llm::{openai, reprompt::{Reprompt, RepromptPolicy}}}, std::sync::Arc};
+/// let policy = RepromptPolicy::new(3).with_min_chars(20);
+/// let client = Reprompt::new(Arc::new(openai::Client::new(None, None))).with_policy(policy);
+/// let assistant = AssistantBuilder::new().with_client(Arc::new(client));
+/// ```
+#[derive(Debug)]
+pub struct Reprompt {
+    client: Arc<dyn LlmClient>,
+    policy: RepromptPolicy,
+}
+
+impl Reprompt {
+    /// Wrap `client`, asking again with the default policy.
+    pub fn new(client: Arc<dyn LlmClient>) -> Self {
+        Self {
+            client,
+            policy: RepromptPolicy::default(),
+        }
+    }
+
+    /// Ask again with `policy`.
+    pub fn with_policy(mut self, policy: RepromptPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Returns whether `completion` is final after `reprompts` attempts, and
+    /// appends it and the instructions to `request` if it isn't.
+    fn settle(
+        &self,
+        request: &mut CompletionRequest,
+        completion: &Completion,
+        reprompts: u32,
+    ) -> bool {
+        let Some(issue) = self.policy.check(completion) else {
+            return true;
+        };
+        if reprompts >= self.policy.max_reprompts {
+            tracing::debug!(%issue, reprompts, "reply is no use; giving up asking again");
+            return true;
+        }
+        tracing::debug!(%issue, reprompt = reprompts + 1, "reply is no use; asking again");
+        request
+            .messages
+            .push(HistoryMessage::new(Role::Assistant, &completion.content));
+        request.messages.push(HistoryMessage::new(
+            Role::User,
+            self.policy.instruction(issue),
+        ));
+        false
+    }
+}
+
+/// Adds the usage of an earlier attempt to `completion`'s.
+fn add_usage(completion: &mut Completion, earlier: Option<Usage>) {
+    if let Some(earlier) = earlier {
+        let usage = completion.usage.get_or_insert_with(Default::default);
+        usage.prompt_tokens += earlier.prompt_tokens;
+        usage.completion_tokens += earlier.completion_tokens;
+    }
+}
+
+impl LlmClient for Reprompt {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            let mut request = request;
+            let mut usage = None;
+            for reprompts in 0.. {
+                let mut completion = self.client.complete(request.clone()).await?;
+                add_usage(&mut completion, usage);
+                if self.settle(&mut request, &completion, reprompts) {
+                    return Ok(completion);
+                }
+                usage = completion.usage;
+            }
+            unreachable!("attempts are bounded by the policy")
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: CompletionRequest,
+        on_delta: &'a mut (dyn FnMut(Delta<'_>) + Send),
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            let mut request = request;
+            let mut usage = None;
+            for reprompts in 0.. {
+                let mut completion = self.client.stream(request.clone(), on_delta).await?;
+                add_usage(&mut completion, usage);
+                if self.settle(&mut request, &completion, reprompts) {
+                    return Ok(completion);
+                }
+                usage = completion.usage;
+            }
+            unreachable!("attempts are bounded by the policy")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        anyhow::Result,
+        std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    /// Replies with each reply in turn, then with the last one.
+    #[derive(Debug)]
+    struct Scripted {
+        replies: Vec<&'static str>,
+        calls: AtomicUsize,
+        last_request: Mutex<Option<CompletionRequest>>,
+    }
+
+    impl Scripted {
+        fn new(replies: &[&'static str]) -> Arc<Self> {
+            Arc::new(Self {
+                replies: replies.to_vec(),
+                calls: Default::default(),
+                last_request: Default::default(),
+            })
+        }
+    }
+
+    impl LlmClient for Scripted {
+        fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                let call = self.calls.fetch_add(1, Ordering::SeqCst);
+                *self.last_request.lock().unwrap() = Some(request);
+                Ok(Completion {
+                    content: self.replies[call.min(self.replies.len() - 1)].to_string(),
+                    usage: Some(Usage {
+                        prompt_tokens: 10,
+                        completion_tokens: 1,
+                    }),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    fn request() -> CompletionRequest {
+        CompletionRequest {
+            model: "any".to_string(),
+            messages: vec![HistoryMessage::new(Role::User, "Summarize the report.")],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let policy = RepromptPolicy::default().with_min_chars(10);
+        let reply = |content: &str| Completion {
+            content: content.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(policy.check(&reply(" \n")), Some(ReplyIssue::Empty));
+        assert_eq!(
+            policy.check(&reply("I\u{2019}m sorry, but I can\u{2019}t do that.")),
+            Some(ReplyIssue::Refusal)
+        );
+        assert_eq!(policy.check(&reply("Sure.")), Some(ReplyIssue::TooShort));
+        assert_eq!(policy.check(&reply("Here's the summary.")), None);
+        assert_eq!(RepromptPolicy::default().check(&reply("Sure.")), None);
+    }
+
+    #[tokio::test]
+    async fn test_reprompts() -> Result<()> {
+        let model = Scripted::new(&["", "I'm unable to read it.", "It says sales grew."]);
+        let client = Reprompt::new(model.clone());
+        let completion = client.complete(request()).await?;
+        assert_eq!(completion.content, "It says sales grew.");
+        assert_eq!(
+            completion.usage,
+            Some(Usage {
+                prompt_tokens: 30,
+                completion_tokens: 3,
+            })
+        );
+        let last_request = model.last_request.lock().unwrap().clone().unwrap();
+        assert_eq!(last_request.messages.len(), 5);
+        assert_eq!(
+            last_request.messages[4].content,
+            RepromptPolicy::default().instruction(ReplyIssue::Refusal)
+        );
+
+        // gives up once the reprompts run out
+        let model = Scripted::new(&[""]);
+        let client = Reprompt::new(model.clone()).with_policy(RepromptPolicy::new(1));
+        assert_eq!(client.complete(request()).await?.content, "");
+        assert_eq!(model.calls.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+//! Retrying failed backend calls. A [`Retry`] client makes a call again when
+//! it fails in a way that may pass, e.g. the backend was rate limited or
+//! returned a server error, waiting longer after each attempt. Waits are
+//! jittered so clients that failed together don't retry together, and
+//! follow the backend's `retry-after` header when it sends one.
+
+use {
+    super::{CompletionRequest, Delta, Error, LlmClient, LlmFuture},
+    crate::agent::{Clock, SystemClock},
+    rand::Rng,
+    std::{sync::Arc, time::Duration},
+};
+
+/// How failed calls are retried.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// The most times a call is made, the first attempt included.
+    pub max_attempts: u32,
+
+    /// The wait before the first retry. Each retry waits twice as long as
+    /// the one before.
+    pub initial_delay: Duration,
+
+    /// The longest wait between attempts. A backend asking to wait longer
+    /// isn't retried.
+    pub max_delay: Duration,
+
+    /// The fraction of each wait that's random, from 0 to 1.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Create a policy that makes calls up to `max_attempts` times, with the
+    /// default waits.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    /// Wait `initial_delay` before the first retry, and at most `max_delay`
+    /// between attempts.
+    pub fn with_backoff(mut self, initial_delay: Duration, max_delay: Duration) -> Self {
+        self.initial_delay = initial_delay;
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Randomize `jitter` of each wait, from 0 for none to 1 for all of it.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Returns the wait before the given retry, counting from 1, without
+    /// jitter.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1);
+        self.initial_delay
+            .checked_mul(2u32.saturating_pow(exponent))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// Returns how long to wait after `error` before the given retry, or
+    /// `None` if the call shouldn't be retried.
+    fn wait(&self, retry: u32, error: &Error) -> Option<Duration> {
+        if retry >= self.max_attempts || !error.is_transient() {
+            return None;
+        }
+        if let Some(retry_after) = error.retry_after() {
+            return (retry_after <= self.max_delay).then_some(retry_after);
+        }
+        let delay = self.delay(retry);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        Some(delay.mul_f64(1.0 - jitter * rand::thread_rng().gen::<f64>()))
+    }
+}
+
+/// Wraps a client, retrying calls that fail transiently.
+///
+/// A streamed call is only retried if it failed before any of the reply
+/// arrived, so no piece of a reply is passed on twice.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::assistant::AssistantBuilder, llm::{openai, retry::{Retry, RetryPolicy}}}, std::{sync::Arc, time::Duration}};
+/// let policy = RetryPolicy::new(5).with_backoff(Duration::from_secs(1), Duration::from_secs(60));
+/// let client = Retry::new(Arc::new(openai::Client::new(None, None))).with_policy(policy);
+/// let assistant = AssistantBuilder::new().with_client(Arc::new(client));
+/// ```
+#[derive(Debug)]
+pub struct Retry {
+    client: Arc<dyn LlmClient>,
+    policy: RetryPolicy,
+    clock: Arc<dyn Clock>,
+}
+
+impl Retry {
+    /// Wrap `client`, retrying with the default policy.
+    pub fn new(client: Arc<dyn LlmClient>) -> Self {
+        Self {
+            client,
+            policy: RetryPolicy::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Retry with `policy`.
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Wait between attempts on `clock`.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+impl LlmClient for Retry {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            let mut attempt = 1;
+            loop {
+                let error = match self.client.complete(request.clone()).await {
+                    Ok(completion) => return Ok(completion),
+                    Err(e) => e,
+                };
+                let Some(wait) = self.policy.wait(attempt, &error) else {
+                    return Err(error);
+                };
+                tracing::debug!(attempt, ?wait, %error, "backend call failed; retrying");
+                #[cfg(feature = "metrics")]
+                crate::metrics::increment_conversation(crate::metrics::CONVERSATION_RETRIES, 1);
+                self.clock.sleep(wait).await;
+                attempt += 1;
+            }
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: CompletionRequest,
+        on_delta: &'a mut (dyn FnMut(Delta<'_>) + Send),
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            let mut attempt = 1;
+            loop {
+                let mut received = false;
+                let mut forward = |delta: Delta<'_>| {
+                    received = true;
+                    on_delta(delta);
+                };
+                let error = match self.client.stream(request.clone(), &mut forward).await {
+                    Ok(completion) => return Ok(completion),
+                    Err(e) => e,
+                };
+                let wait = match received {
+                    true => None,
+                    false => self.policy.wait(attempt, &error),
+                };
+                let Some(wait) = wait else {
+                    return Err(error);
+                };
+                tracing::debug!(attempt, ?wait, %error, "backend call failed; retrying");
+                #[cfg(feature = "metrics")]
+                crate::metrics::increment_conversation(crate::metrics::CONVERSATION_RETRIES, 1);
+                self.clock.sleep(wait).await;
+                attempt += 1;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::llm::{openai, Completion},
+        anyhow::Result,
+        std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    /// Fails with each status in turn, then replies.
+    #[derive(Debug)]
+    struct Flaky {
+        statuses: Mutex<Vec<u16>>,
+        attempts: AtomicUsize,
+    }
+
+    impl Flaky {
+        fn new(statuses: &[u16]) -> Arc<Self> {
+            Arc::new(Self {
+                statuses: Mutex::new(statuses.iter().rev().copied().collect()),
+                attempts: Default::default(),
+            })
+        }
+    }
+
+    impl LlmClient for Flaky {
+        fn complete(&self, _request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                self.attempts.fetch_add(1, Ordering::SeqCst);
+                match self.statuses.lock().unwrap().pop() {
+                    Some(status) => Err(openai::Error::ApiError {
+                        status,
+                        message: "failed".to_string(),
+                        retry_after: (status == 429).then_some(Duration::from_millis(50)),
+                    }
+                    .into()),
+                    None => Ok(Completion {
+                        content: "ok".to_string(),
+                        ..Default::default()
+                    }),
+                }
+            })
+        }
+    }
+
+    fn request() -> CompletionRequest {
+        CompletionRequest {
+            model: "any".to_string(),
+            messages: Vec::new(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::new(10)
+            .with_backoff(Duration::from_millis(100), Duration::from_secs(1))
+            .with_jitter(0.0);
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(100), Duration::from_secs(1));
+
+        let error = Error::from(openai::Error::EmptyResponse);
+        assert_eq!(policy.wait(1, &error), None);
+        let jittered = policy
+            .with_jitter(0.5)
+            .wait(2, &Error::Timeout(Duration::ZERO));
+        assert!(jittered >= Some(Duration::from_millis(100)));
+        assert!(jittered <= Some(Duration::from_millis(200)));
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_errors() -> Result<()> {
+        let policy = RetryPolicy::new(3)
+            .with_backoff(Duration::from_millis(10), Duration::from_secs(1))
+            .with_jitter(0.0);
+
+        // a server error, then a rate limit with a retry-after
+        let flaky = Flaky::new(&[503, 429]);
+        let client = Retry::new(flaky.clone()).with_policy(policy);
+        let started = std::time::Instant::now();
+        assert_eq!(client.complete(request()).await?.content, "ok");
+        assert_eq!(flaky.attempts.load(Ordering::SeqCst), 3);
+        assert!(started.elapsed() >= policy.delay(1) + Duration::from_millis(50));
+
+        // out of attempts
+        let flaky = Flaky::new(&[500, 502, 504]);
+        let client = Retry::new(flaky.clone()).with_policy(policy);
+        assert!(client.complete(request()).await.is_err());
+        assert_eq!(flaky.attempts.load(Ordering::SeqCst), 3);
+
+        // not worth retrying
+        let flaky = Flaky::new(&[401]);
+        let client = Retry::new(flaky.clone()).with_policy(policy);
+        assert!(client.stream(request(), &mut |_| {}).await.is_err());
+        assert_eq!(flaky.attempts.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,369 @@
+//! Cost-aware routing between a cheap and a premium model. A [`Router`] is an
+//! [`LlmClient`] that classifies each request, sends simple ones to a cheap
+//! backend and complex ones to a premium backend, and keeps track of what
+//! that saved.
+
+use {
+    super::{CompletionRequest, Delta, HistoryMessage, LlmClient, LlmFuture, Role},
+    crate::tokenizer::TokenizerRegistry,
+    std::{
+        fmt::Debug,
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+    },
+};
+
+/// A boxed future returned by [`Classifier::classify`].
+pub type ClassifyFuture<'a> = Pin<Box<dyn Future<Output = Tier> + Send + 'a>>;
+
+/// Which backend a request is routed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    /// A simple request, for the cheap backend.
+    Cheap,
+
+    /// A complex request, for the premium backend.
+    Premium,
+}
+
+/// Decides how complex a request is.
+pub trait Classifier: Debug + Send + Sync + 'static {
+    /// Returns the tier the request should be routed to.
+    fn classify<'a>(&'a self, request: &'a CompletionRequest) -> ClassifyFuture<'a>;
+}
+
+/// Classifies requests by the last message: long messages and messages with
+/// any of the keywords are complex, as are requests that offer tools.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heuristic {
+    /// Messages longer than this many characters are complex.
+    pub max_chars: usize,
+
+    /// Messages containing any of these words, ignoring case, are complex.
+    pub keywords: Vec<String>,
+}
+
+impl Default for Heuristic {
+    fn default() -> Self {
+        Self {
+            max_chars: 500,
+            keywords: [
+                "analyze",
+                "code",
+                "debug",
+                "explain why",
+                "plan",
+                "prove",
+                "step by step",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+impl Classifier for Heuristic {
+    fn classify<'a>(&'a self, request: &'a CompletionRequest) -> ClassifyFuture<'a> {
+        let content = request
+            .messages
+            .last()
+            .map(|message| message.content.to_lowercase())
+            .unwrap_or_default();
+        let complex = !request.tools.is_empty()
+            || content.chars().count() > self.max_chars
+            || self
+                .keywords
+                .iter()
+                .any(|keyword| content.contains(&keyword.to_lowercase()));
+        Box::pin(async move {
+            match complex {
+                true => Tier::Premium,
+                false => Tier::Cheap,
+            }
+        })
+    }
+}
+
+/// Asks a small model whether the last message is simple or complex.
+/// Requests are routed to the premium backend if the model fails or gives
+/// an unclear answer.
+#[derive(Debug, Clone)]
+pub struct ModelClassifier {
+    /// The client of the classifying model.
+    pub client: Arc<dyn LlmClient>,
+
+    /// The classifying model.
+    pub model: String,
+}
+
+/// The instructions given to a [`ModelClassifier`]'s model.
+const CLASSIFIER_PROMPT: &str = "Classify the user's message as SIMPLE if a small, \
+    fast model can answer it well, or COMPLEX if it needs careful reasoning, \
+    coding or expert knowledge. Answer with one word: SIMPLE or COMPLEX.";
+
+impl Classifier for ModelClassifier {
+    fn classify<'a>(&'a self, request: &'a CompletionRequest) -> ClassifyFuture<'a> {
+        Box::pin(async move {
+            let Some(message) = request.messages.last() else {
+                return Tier::Cheap;
+            };
+            let classification = CompletionRequest {
+                model: self.model.clone(),
+                messages: vec![
+                    HistoryMessage::new(Role::System, CLASSIFIER_PROMPT),
+                    HistoryMessage::new(Role::User, &message.content),
+                ],
+                ..Default::default()
+            };
+            match self.client.complete(classification).await {
+                Ok(completion) if completion.content.trim().eq_ignore_ascii_case("simple") => {
+                    Tier::Cheap
+                }
+                Ok(_) => Tier::Premium,
+                Err(e) => {
+                    tracing::debug!(error = %e, "unable to classify request");
+                    Tier::Premium
+                }
+            }
+        })
+    }
+}
+
+/// A model a [`Router`] can send requests to.
+#[derive(Debug, Clone)]
+pub struct Backend {
+    /// The client requests are sent with.
+    pub client: Arc<dyn LlmClient>,
+
+    /// The model requests are sent to. Replaces the request's model.
+    pub model: String,
+
+    /// What the model costs per 1000 tokens, in any currency.
+    pub cost_per_1k_tokens: f64,
+}
+
+impl Backend {
+    /// Create a backend for `model` of `client`.
+    pub fn new(client: Arc<dyn LlmClient>, model: impl ToString, cost_per_1k_tokens: f64) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            cost_per_1k_tokens,
+        }
+    }
+}
+
+/// What a [`Router`] has routed so far. Costs are estimated from the tokens
+/// in requests and replies.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RouterMetrics {
+    /// The number of requests sent to the cheap backend.
+    pub cheap_requests: u64,
+
+    /// The number of requests sent to the premium backend.
+    pub premium_requests: u64,
+
+    /// The estimated cost of every request.
+    pub cost: f64,
+
+    /// The estimated cost saved by sending requests to the cheap backend
+    /// instead of the premium backend.
+    pub savings: f64,
+}
+
+/// Routes simple requests to a cheap backend and complex ones to a premium
+/// backend.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::assistant::AssistantBuilder, llm::{openai, router::{Backend, Heuristic, Router}}}, std::sync::Arc};
+/// let client = Arc::new(openai::Client::new(None, None));
+/// let router = Router::new(
+///     Backend::new(client.clone(), "gpt-3.5-turbo", 0.002),
+///     Backend::new(client, "gpt-4", 0.06),
+///     Heuristic::default(),
+/// );
+/// let assistant = AssistantBuilder::new().with_client(Arc::new(router));
+/// ```
+#[derive(Debug)]
+pub struct Router {
+    cheap: Backend,
+    premium: Backend,
+    classifier: Box<dyn Classifier>,
+    tokenizers: TokenizerRegistry,
+    metrics: Mutex<RouterMetrics>,
+}
+
+impl Router {
+    /// Create a router that classifies requests with `classifier`.
+    pub fn new(cheap: Backend, premium: Backend, classifier: impl Classifier) -> Self {
+        Self {
+            cheap,
+            premium,
+            classifier: Box::new(classifier),
+            tokenizers: TokenizerRegistry::default(),
+            metrics: Default::default(),
+        }
+    }
+
+    /// Count the tokens of requests and replies with `tokenizers`.
+    pub fn with_tokenizers(mut self, tokenizers: TokenizerRegistry) -> Self {
+        self.tokenizers = tokenizers;
+        self
+    }
+
+    /// Returns what the router has routed so far.
+    pub fn metrics(&self) -> RouterMetrics {
+        *self.metrics.lock().unwrap()
+    }
+
+    /// Classify the request and point it at the chosen backend's model.
+    async fn route(&self, mut request: CompletionRequest) -> (Tier, &Backend, CompletionRequest) {
+        let tier = self.classifier.classify(&request).await;
+        let backend = match tier {
+            Tier::Cheap => &self.cheap,
+            Tier::Premium => &self.premium,
+        };
+        tracing::trace!(?tier, model = backend.model, "routing request");
+        request.model = backend.model.clone();
+        (tier, backend, request)
+    }
+
+    /// Record a completed request of `tokens` tokens, including the reply.
+    fn record(&self, tier: Tier, tokens: usize) {
+        let tokens = tokens as f64 / 1000.0;
+        let mut metrics = self.metrics.lock().unwrap();
+        match tier {
+            Tier::Cheap => {
+                metrics.cheap_requests += 1;
+                metrics.cost += tokens * self.cheap.cost_per_1k_tokens;
+                metrics.savings +=
+                    tokens * (self.premium.cost_per_1k_tokens - self.cheap.cost_per_1k_tokens);
+            }
+            Tier::Premium => {
+                metrics.premium_requests += 1;
+                metrics.cost += tokens * self.premium.cost_per_1k_tokens;
+            }
+        }
+    }
+}
+
+impl LlmClient for Router {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            let (tier, backend, request) = self.route(request).await;
+            let model = request.model.clone();
+            let tokens = self.tokenizers.count_messages(&model, &request.messages);
+            let completion = backend.client.complete(request).await?;
+            let reply = self.tokenizers.count(&model, &completion.content);
+            self.record(tier, tokens + reply);
+            Ok(completion)
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: CompletionRequest,
+        on_delta: &'a mut (dyn FnMut(Delta<'_>) + Send),
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            let (tier, backend, request) = self.route(request).await;
+            let model = request.model.clone();
+            let tokens = self.tokenizers.count_messages(&model, &request.messages);
+            let completion = backend.client.stream(request, on_delta).await?;
+            let reply = self.tokenizers.count(&model, &completion.content);
+            self.record(tier, tokens + reply);
+            Ok(completion)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::llm::Completion, anyhow::Result};
+
+    /// A backend that replies with the model it was asked.
+    #[derive(Debug)]
+    struct Model;
+
+    impl LlmClient for Model {
+        fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                Ok(Completion {
+                    content: request.model,
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    fn request(content: &str) -> CompletionRequest {
+        CompletionRequest {
+            model: "any".to_string(),
+            messages: vec![HistoryMessage::new(Role::User, content)],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_by_heuristic() -> Result<()> {
+        let router = Router::new(
+            Backend::new(Arc::new(Model), "cheap", 1.0),
+            Backend::new(Arc::new(Model), "premium", 3.0),
+            Heuristic::default(),
+        );
+
+        let hello = "hello".repeat(800);
+        assert_eq!(router.complete(request("hi")).await?.content, "cheap");
+        assert_eq!(router.complete(request(&hello)).await?.content, "premium");
+        assert_eq!(
+            router.complete(request("Debug this")).await?.content,
+            "premium"
+        );
+
+        let metrics = router.metrics();
+        assert_eq!(metrics.cheap_requests, 1);
+        assert_eq!(metrics.premium_requests, 2);
+        // "hi" and "cheap" are 3 tokens, plus 6 for the message and reply
+        assert_eq!(metrics.savings, 0.018);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_route_by_model() -> Result<()> {
+        /// Answers "SIMPLE" to short messages.
+        #[derive(Debug)]
+        struct Small;
+
+        impl LlmClient for Small {
+            fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+                Box::pin(async move {
+                    let simple = request.messages[1].content.len() < 10;
+                    Ok(Completion {
+                        content: if simple { "SIMPLE" } else { "COMPLEX" }.to_string(),
+                        ..Default::default()
+                    })
+                })
+            }
+        }
+
+        let router = Router::new(
+            Backend::new(Arc::new(Model), "cheap", 1.0),
+            Backend::new(Arc::new(Model), "premium", 3.0),
+            ModelClassifier {
+                client: Arc::new(Small),
+                model: "small".to_string(),
+            },
+        );
+        assert_eq!(router.complete(request("hi")).await?.content, "cheap");
+        assert_eq!(
+            router
+                .complete(request("what is the meaning of life?"))
+                .await?
+                .content,
+            "premium"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+//! Speculative execution across backends. A [`Speculative`] client sends the
+//! same request to several backends at once, e.g. a fast but flaky local
+//! model and a slower API, takes the first acceptable reply and cancels the
+//! rest, trading extra requests for lower tail latency.
+
+use {
+    super::{Completion, CompletionRequest, Error, LlmClient, LlmFuture},
+    std::{fmt, sync::Arc},
+    tokio::task::JoinSet,
+};
+
+/// Decides whether a reply is good enough to stop waiting for the others.
+type Acceptance = dyn Fn(&Completion) -> bool + Send + Sync;
+
+/// Races a request against several backends.
+///
+/// Replies aren't streamed: each backend's reply is raced as a whole, and the
+/// winner is passed on in one piece.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::assistant::AssistantBuilder, llm::{openai, speculative::Speculative}}, std::sync::Arc};
+/// let local = Arc::new(openai::Client::new(None, Some("http://localhost:11434/v1".to_string())));
+/// let api = Arc::new(openai::Client::new(None, None));
+/// let client = Speculative::new()
+///     .with_backend(local, "llama3")
+///     .with_backend(api, "gpt-4");
+/// let assistant = AssistantBuilder::new().with_client(Arc::new(client));
+/// ```
+#[derive(Clone)]
+pub struct Speculative {
+    /// The backends and the model each is asked for.
+    backends: Vec<(Arc<dyn LlmClient>, String)>,
+    acceptance: Arc<Acceptance>,
+}
+
+impl Default for Speculative {
+    fn default() -> Self {
+        Self {
+            backends: Vec::new(),
+            acceptance: Arc::new(|completion: &Completion| {
+                !completion.content.trim().is_empty() || !completion.tool_calls.is_empty()
+            }),
+        }
+    }
+}
+
+impl fmt::Debug for Speculative {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Speculative")
+            .field("backends", &self.backends)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Speculative {
+    /// Create a client with no backends. By default a reply is acceptable if
+    /// it has content or tool calls.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Race `model` of `client` too. The model replaces the request's model.
+    pub fn with_backend(mut self, client: Arc<dyn LlmClient>, model: impl ToString) -> Self {
+        self.backends.push((client, model.to_string()));
+        self
+    }
+
+    /// Only accept replies that satisfy `acceptance`.
+    pub fn with_acceptance<F>(mut self, acceptance: F) -> Self
+    where
+        F: Fn(&Completion) -> bool + Send + Sync + 'static,
+    {
+        self.acceptance = Arc::new(acceptance);
+        self
+    }
+}
+
+impl LlmClient for Speculative {
+    /// Returns the first acceptable reply. If no reply is acceptable, returns
+    /// the first reply, or the last error if every backend failed.
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            // dropping the set aborts the requests still in flight
+            let mut requests = JoinSet::new();
+            for (index, (client, model)) in self.backends.iter().enumerate() {
+                let client = client.clone();
+                let request = CompletionRequest {
+                    model: model.clone(),
+                    ..request.clone()
+                };
+                requests.spawn(async move { (index, client.complete(request).await) });
+            }
+
+            let mut fallback = None;
+            let mut error = None;
+            while let Some(result) = requests.join_next().await {
+                match result {
+                    Ok((index, Ok(completion))) if (self.acceptance)(&completion) => {
+                        tracing::trace!(model = self.backends[index].1, "speculative request won");
+                        return Ok(completion);
+                    }
+                    Ok((_, Ok(completion))) => {
+                        fallback.get_or_insert(completion);
+                    }
+                    Ok((index, Err(e))) => {
+                        tracing::debug!(model = self.backends[index].1, error = %e, "speculative request failed");
+                        error = Some(e);
+                    }
+                    Err(e) => error = Some(Error::Other(Box::new(e))),
+                }
+            }
+            match (fallback, error) {
+                (Some(completion), _) => Ok(completion),
+                (None, Some(e)) => Err(e),
+                (None, None) => Err(Error::Other("no backends to race".into())),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::llm::{openai, HistoryMessage, Role},
+        anyhow::Result,
+        std::{
+            sync::atomic::{AtomicBool, Ordering},
+            time::Duration,
+        },
+    };
+
+    /// Replies with `content` after `delay`, or fails if there's no content.
+    #[derive(Debug)]
+    struct Delayed {
+        delay: Duration,
+        content: Option<&'static str>,
+        cancelled: Arc<AtomicBool>,
+    }
+
+    /// Sets the flag when dropped before it's defused.
+    struct Guard(Arc<AtomicBool>, bool);
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            if !self.1 {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    impl Delayed {
+        fn new(delay: Duration, content: Option<&'static str>) -> Arc<Self> {
+            Arc::new(Self {
+                delay,
+                content,
+                cancelled: Default::default(),
+            })
+        }
+    }
+
+    impl LlmClient for Delayed {
+        fn complete(&self, _request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                let mut guard = Guard(self.cancelled.clone(), false);
+                tokio::time::sleep(self.delay).await;
+                guard.1 = true;
+                match self.content {
+                    Some(content) => Ok(Completion {
+                        content: content.to_string(),
+                        ..Default::default()
+                    }),
+                    None => Err(openai::Error::EmptyResponse.into()),
+                }
+            })
+        }
+    }
+
+    fn request() -> CompletionRequest {
+        CompletionRequest {
+            model: "any".to_string(),
+            messages: vec![HistoryMessage::new(Role::User, "hello")],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_first_acceptable_reply_wins() -> Result<()> {
+        let fast = Delayed::new(Duration::from_millis(10), Some("fast"));
+        let slow = Delayed::new(Duration::from_secs(60), Some("slow"));
+        let client = Speculative::new()
+            .with_backend(fast, "fast")
+            .with_backend(slow.clone(), "slow");
+
+        let completion =
+            tokio::time::timeout(Duration::from_secs(1), client.complete(request())).await??;
+        assert_eq!(completion.content, "fast");
+        // the slow request is cancelled once the task is aborted
+        while !slow.cancelled.load(Ordering::SeqCst) {
+            tokio::task::yield_now().await;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skips_failed_and_unacceptable_replies() -> Result<()> {
+        let failing = Delayed::new(Duration::ZERO, None);
+        let short = Delayed::new(Duration::from_millis(10), Some("ok"));
+        let good = Delayed::new(Duration::from_millis(20), Some("a proper answer"));
+        let client = Speculative::new()
+            .with_backend(failing, "failing")
+            .with_backend(short, "short")
+            .with_backend(good, "good")
+            .with_acceptance(|completion| completion.content.len() > 2);
+
+        assert_eq!(client.complete(request()).await?.content, "a proper answer");
+
+        // with nothing acceptable, the first reply is used
+        let client = client.with_acceptance(|_| false);
+        assert_eq!(client.complete(request()).await?.content, "ok");
+        Ok(())
+    }
+}
@@ -0,0 +1,573 @@
+//! Accounting for what calls to models use and cost. A [`Metered`] client
+//! records the tokens of every call it makes, as the backend reports them or
+//! estimated when it doesn't, in a [`UsageTracker`]. The tracker prices them
+//! with a [`PriceTable`] and adds them up per agent, per model and per
+//! conversation in a [`UsageReport`].
+//!
+//! A tracker can also hold calls to a [`Budget`]: once it's spent, calls
+//! fail, or go to a cheaper model instead. As a
+//! [termination condition](TerminationCondition), it ends a conversation
+//! that's over budget.
+
+use {
+    super::{CompletionRequest, Delta, Error, HistoryMessage, LlmClient, LlmFuture, Role, Usage},
+    crate::{
+        chat::{termination::TerminationCondition, ChatMessage, TerminationReason},
+        tokenizer::TokenizerRegistry,
+    },
+    std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    },
+};
+
+/// What a model's tokens cost, in dollars per thousand tokens.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Pricing {
+    /// The cost of a thousand tokens of the messages sent.
+    pub prompt_per_1k: f64,
+
+    /// The cost of a thousand tokens of the reply.
+    pub completion_per_1k: f64,
+}
+
+impl Pricing {
+    /// Create a price of `prompt_per_1k` dollars per thousand prompt tokens
+    /// and `completion_per_1k` per thousand completion tokens.
+    pub fn new(prompt_per_1k: f64, completion_per_1k: f64) -> Self {
+        Self {
+            prompt_per_1k,
+            completion_per_1k,
+        }
+    }
+
+    /// Returns the cost of `usage`, in dollars.
+    pub fn cost(&self, usage: Usage) -> f64 {
+        (usage.prompt_tokens as f64 * self.prompt_per_1k
+            + usage.completion_tokens as f64 * self.completion_per_1k)
+            / 1000.0
+    }
+}
+
+/// Maps model names to prices.
+///
+/// Prices are registered for model name prefixes; the longest registered
+/// prefix of a model's name picks its price. Models that match no prefix
+/// cost nothing. The default table has list prices of OpenAI's models at
+/// the time of writing; register current prices for the models you use.
+#[derive(Debug, Clone)]
+pub struct PriceTable {
+    prices: Vec<(String, Pricing)>,
+}
+
+impl Default for PriceTable {
+    fn default() -> Self {
+        Self::new()
+            .with_price("gpt-4", Pricing::new(0.03, 0.06))
+            .with_price("gpt-4-turbo", Pricing::new(0.01, 0.03))
+            .with_price("gpt-4o", Pricing::new(0.0025, 0.01))
+            .with_price("gpt-4o-mini", Pricing::new(0.00015, 0.0006))
+            .with_price("gpt-3.5-turbo", Pricing::new(0.0005, 0.0015))
+    }
+}
+
+impl PriceTable {
+    /// Create a table with no prices.
+    pub fn new() -> Self {
+        Self { prices: Vec::new() }
+    }
+
+    /// Price models whose name starts with `prefix` at `pricing`. Replaces
+    /// the price registered for the same prefix.
+    pub fn with_price(mut self, prefix: impl ToString, pricing: Pricing) -> Self {
+        let prefix = prefix.to_string();
+        self.prices.retain(|(registered, _)| *registered != prefix);
+        self.prices.push((prefix, pricing));
+        self
+    }
+
+    /// Returns the price of `model`, if it has one.
+    pub fn get(&self, model: &str) -> Option<Pricing> {
+        self.prices
+            .iter()
+            .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, pricing)| *pricing)
+    }
+
+    /// Returns the cost of `usage` of `model`, in dollars.
+    pub fn cost(&self, model: &str, usage: Usage) -> f64 {
+        self.get(model).map_or(0.0, |pricing| pricing.cost(usage))
+    }
+}
+
+/// One call to a model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallRecord {
+    /// The agent that made the call, if known.
+    pub agent: Option<String>,
+
+    /// The conversation the call was part of, if known.
+    pub conversation: Option<String>,
+
+    /// The model called.
+    pub model: String,
+
+    /// The tokens the call took.
+    pub usage: Usage,
+
+    /// Whether the tokens were counted locally because the backend didn't
+    /// report them.
+    pub estimated: bool,
+
+    /// What the call cost, in dollars.
+    pub cost: f64,
+}
+
+/// The usage of some calls, added up.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Totals {
+    /// The number of calls.
+    pub calls: usize,
+
+    /// The tokens of the messages sent.
+    pub prompt_tokens: usize,
+
+    /// The tokens of the replies.
+    pub completion_tokens: usize,
+
+    /// What the calls cost, in dollars.
+    pub cost: f64,
+}
+
+impl Totals {
+    fn add(&mut self, record: &CallRecord) {
+        self.calls += 1;
+        self.prompt_tokens += record.usage.prompt_tokens;
+        self.completion_tokens += record.usage.completion_tokens;
+        self.cost += record.cost;
+    }
+}
+
+/// The usage of every call a tracker recorded, added up in all, per agent,
+/// per model and per conversation. Calls without an agent or conversation
+/// only count towards the other totals.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageReport {
+    /// Every call.
+    pub total: Totals,
+
+    /// The calls of each agent.
+    pub by_agent: BTreeMap<String, Totals>,
+
+    /// The calls to each model.
+    pub by_model: BTreeMap<String, Totals>,
+
+    /// The calls of each conversation.
+    pub by_conversation: BTreeMap<String, Totals>,
+}
+
+impl UsageReport {
+    /// Returns a report of `records`.
+    pub fn new<'a>(records: impl IntoIterator<Item = &'a CallRecord>) -> Self {
+        let mut report = Self::default();
+        for record in records {
+            report.total.add(record);
+            if let Some(agent) = &record.agent {
+                report
+                    .by_agent
+                    .entry(agent.clone())
+                    .or_default()
+                    .add(record);
+            }
+            report
+                .by_model
+                .entry(record.model.clone())
+                .or_default()
+                .add(record);
+            if let Some(conversation) = &record.conversation {
+                report
+                    .by_conversation
+                    .entry(conversation.clone())
+                    .or_default()
+                    .add(record);
+            }
+        }
+        report
+    }
+}
+
+/// What happens to calls once a budget is spent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverBudget {
+    /// Calls fail with [`Error::OverBudget`].
+    Abort,
+
+    /// Calls go to this model instead, e.g. a cheaper one.
+    SwitchModel(String),
+}
+
+/// The most calls may cost, and what happens once they have.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Budget {
+    /// The most calls may cost, in dollars.
+    pub max_cost: f64,
+
+    /// What happens to calls made once the budget is spent.
+    pub action: OverBudget,
+}
+
+impl Budget {
+    /// Create a budget of `max_cost` dollars, after which calls fail.
+    pub fn new(max_cost: f64) -> Self {
+        Self {
+            max_cost,
+            action: OverBudget::Abort,
+        }
+    }
+
+    /// Send calls to `model` once the budget is spent, instead of failing
+    /// them.
+    pub fn with_fallback_model(mut self, model: impl ToString) -> Self {
+        self.action = OverBudget::SwitchModel(model.to_string());
+        self
+    }
+}
+
+/// Records calls to models. Clones share the same records.
+///
+/// A tracker for a conversation, from [`UsageTracker::conversation`], labels
+/// the calls it records with the conversation, and its budget only counts
+/// the conversation's calls.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::assistant::AssistantBuilder, llm::usage::{Budget, UsageTracker}}};
+/// # tokio_test::block_on(async {
+/// let tracker = UsageTracker::new();
+/// let conversation = tracker
+///     .conversation("support-1234")
+///     .with_budget(Budget::new(0.50).with_fallback_model("gpt-4o-mini"));
+/// let assistant = AssistantBuilder::new()
+///     .with_name("helper")
+///     .with_usage(conversation)
+///     .build();
+///
+/// // ...
+/// let report = tracker.report();
+/// println!("spent ${:.2} in all", report.total.cost);
+/// # });
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct UsageTracker {
+    records: Arc<Mutex<Vec<CallRecord>>>,
+    prices: Arc<PriceTable>,
+    conversation: Option<String>,
+    budget: Option<Budget>,
+}
+
+impl UsageTracker {
+    /// Create a tracker that prices calls with the default [`PriceTable`].
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Price calls with `prices`.
+    pub fn with_prices(mut self, prices: PriceTable) -> Self {
+        self.prices = Arc::new(prices);
+        self
+    }
+
+    /// Hold calls to `budget`.
+    pub fn with_budget(mut self, budget: Budget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Returns a tracker sharing this one's records and prices that labels
+    /// calls with `conversation`, without a budget.
+    pub fn conversation(&self, conversation: impl ToString) -> Self {
+        Self {
+            records: self.records.clone(),
+            prices: self.prices.clone(),
+            conversation: Some(conversation.to_string()),
+            budget: None,
+        }
+    }
+
+    /// Returns the budget calls are held to, if any.
+    pub fn budget(&self) -> Option<&Budget> {
+        self.budget.as_ref()
+    }
+
+    /// Record a call to `model` made by `agent`, returning what it cost.
+    pub fn record(&self, agent: Option<&str>, model: &str, usage: Usage, estimated: bool) -> f64 {
+        let cost = self.prices.cost(model, usage);
+        self.records.lock().unwrap().push(CallRecord {
+            agent: agent.map(str::to_string),
+            conversation: self.conversation.clone(),
+            model: model.to_string(),
+            usage,
+            estimated,
+            cost,
+        });
+        cost
+    }
+
+    /// Returns every call recorded, by any tracker sharing the records, in
+    /// order.
+    pub fn records(&self) -> Vec<CallRecord> {
+        self.records.lock().unwrap().clone()
+    }
+
+    /// Returns what the calls the budget counts cost, in dollars: the
+    /// conversation's calls for a conversation's tracker, and every call
+    /// otherwise.
+    pub fn spent(&self) -> f64 {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| {
+                self.conversation.is_none() || record.conversation == self.conversation
+            })
+            .map(|record| record.cost)
+            .sum()
+    }
+
+    /// Returns whether the budget is spent.
+    pub fn is_over_budget(&self) -> bool {
+        self.budget
+            .as_ref()
+            .is_some_and(|budget| self.spent() >= budget.max_cost)
+    }
+
+    /// Returns a report of every call recorded.
+    pub fn report(&self) -> UsageReport {
+        UsageReport::new(self.records.lock().unwrap().iter())
+    }
+}
+
+/// Met once the tracker's budget is spent, if it fails calls then, ending
+/// the conversation as over [`Budget`](TerminationReason::Budget).
+impl TerminationCondition for UsageTracker {
+    fn check(&self, _transcript: &[ChatMessage]) -> Option<TerminationReason> {
+        let aborts = self
+            .budget
+            .as_ref()
+            .is_some_and(|budget| budget.action == OverBudget::Abort);
+        (aborts && self.is_over_budget()).then_some(TerminationReason::Budget)
+    }
+}
+
+/// Wraps a client, recording the usage of every call in a [`UsageTracker`]
+/// and holding calls to its budget.
+///
+/// Tokens the backend doesn't report are counted with a
+/// [`TokenizerRegistry`].
+#[derive(Debug)]
+pub struct Metered {
+    client: Arc<dyn LlmClient>,
+    tracker: UsageTracker,
+    agent: Option<String>,
+    tokenizers: TokenizerRegistry,
+}
+
+impl Metered {
+    /// Wrap `client`, recording calls in `tracker`.
+    pub fn new(client: Arc<dyn LlmClient>, tracker: UsageTracker) -> Self {
+        Self {
+            client,
+            tracker,
+            agent: None,
+            tokenizers: TokenizerRegistry::default(),
+        }
+    }
+
+    /// Record calls as made by `agent`.
+    pub fn with_agent(mut self, agent: impl ToString) -> Self {
+        self.agent = Some(agent.to_string());
+        self
+    }
+
+    /// Count tokens the backend doesn't report with `tokenizers`.
+    pub fn with_tokenizers(mut self, tokenizers: TokenizerRegistry) -> Self {
+        self.tokenizers = tokenizers;
+        self
+    }
+
+    /// Returns the tracker calls are recorded in.
+    pub fn tracker(&self) -> &UsageTracker {
+        &self.tracker
+    }
+
+    /// Apply the budget to `request`, failing it or pointing it at the
+    /// fallback model if the budget is spent.
+    fn admit(&self, mut request: CompletionRequest) -> Result<CompletionRequest, Error> {
+        let Some(budget) = self.tracker.budget() else {
+            return Ok(request);
+        };
+        let spent = self.tracker.spent();
+        if spent < budget.max_cost {
+            return Ok(request);
+        }
+        match &budget.action {
+            OverBudget::Abort => Err(Error::OverBudget {
+                spent,
+                budget: budget.max_cost,
+            }),
+            OverBudget::SwitchModel(model) => {
+                tracing::debug!(
+                    spent,
+                    from = request.model,
+                    to = model,
+                    "over budget; switching model"
+                );
+                request.model = model.clone();
+                Ok(request)
+            }
+        }
+    }
+
+    /// Record a call of `request` that was answered with `completion`.
+    fn record(&self, model: &str, messages: &[HistoryMessage], completion: &super::Completion) {
+        let (usage, estimated) = match completion.usage {
+            Some(usage) => (usage, false),
+            None => {
+                let reply = HistoryMessage {
+                    tool_calls: completion.tool_calls.clone(),
+                    ..HistoryMessage::new(Role::Assistant, &completion.content)
+                };
+                let usage = Usage {
+                    prompt_tokens: self.tokenizers.count_messages(model, messages),
+                    completion_tokens: self.tokenizers.count_message(model, &reply),
+                };
+                (usage, true)
+            }
+        };
+        let cost = self
+            .tracker
+            .record(self.agent.as_deref(), model, usage, estimated);
+        tracing::trace!(agent = self.agent, model, ?usage, cost, "recorded call");
+    }
+}
+
+impl LlmClient for Metered {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            let request = self.admit(request)?;
+            let (model, messages) = (request.model.clone(), request.messages.clone());
+            let completion = self.client.complete(request).await?;
+            self.record(&model, &messages, &completion);
+            Ok(completion)
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: CompletionRequest,
+        on_delta: &'a mut (dyn FnMut(Delta<'_>) + Send),
+    ) -> LlmFuture<'a> {
+        Box::pin(async move {
+            let request = self.admit(request)?;
+            let (model, messages) = (request.model.clone(), request.messages.clone());
+            let completion = self.client.stream(request, on_delta).await?;
+            self.record(&model, &messages, &completion);
+            Ok(completion)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{llm::Completion, tokenizer::Estimate},
+        anyhow::Result,
+    };
+
+    /// Replies with the model it was asked, reporting usage for "gpt-4"
+    /// only.
+    #[derive(Debug)]
+    struct Model;
+
+    impl LlmClient for Model {
+        fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                let usage = (request.model == "gpt-4").then_some(Usage {
+                    prompt_tokens: 1000,
+                    completion_tokens: 500,
+                });
+                Ok(Completion {
+                    content: request.model,
+                    usage,
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    fn request(model: &str) -> CompletionRequest {
+        CompletionRequest {
+            model: model.to_string(),
+            messages: vec![HistoryMessage::new(Role::User, "12345678")],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_report() -> Result<()> {
+        let tracker = UsageTracker::new().with_prices(
+            PriceTable::new()
+                .with_price("gpt-4", Pricing::new(0.03, 0.06))
+                .with_price("cheap", Pricing::new(1.0, 1.0)),
+        );
+        let tokenizers = TokenizerRegistry::new().with_fallback(Estimate { chars_per_token: 4 });
+        let alice = Metered::new(Arc::new(Model), tracker.conversation("a"))
+            .with_agent("alice")
+            .with_tokenizers(tokenizers.clone());
+        let bob = Metered::new(Arc::new(Model), tracker.conversation("b"))
+            .with_agent("bob")
+            .with_tokenizers(tokenizers);
+
+        alice.complete(request("gpt-4")).await?;
+        alice.complete(request("cheap")).await?;
+        bob.stream(request("gpt-4"), &mut |_| {}).await?;
+
+        let report = tracker.report();
+        assert_eq!(report.total.calls, 3);
+        assert!((report.by_agent["alice"].cost - 0.07).abs() < 1e-9);
+        assert_eq!(report.by_model["gpt-4"].prompt_tokens, 2000);
+        // "12345678" is 2 tokens, with 3 for the message and 3 for the reply
+        assert_eq!(report.by_model["cheap"].prompt_tokens, 8);
+        assert_eq!(report.by_model["cheap"].completion_tokens, 2);
+        assert_eq!(report.by_conversation["b"].calls, 1);
+        assert!(tracker.records()[1].estimated);
+        assert!((tracker.conversation("a").spent() - 0.07).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_budget() -> Result<()> {
+        let tracker = UsageTracker::new().with_budget(Budget::new(0.05));
+        let client = Metered::new(Arc::new(Model), tracker.clone());
+        assert!(tracker.check(&[]).is_none());
+        client.complete(request("gpt-4")).await?;
+        assert!(matches!(
+            client.complete(request("gpt-4")).await,
+            Err(Error::OverBudget { .. })
+        ));
+        assert_eq!(tracker.check(&[]), Some(TerminationReason::Budget));
+
+        let tracker =
+            UsageTracker::new().with_budget(Budget::new(0.05).with_fallback_model("gpt-4o-mini"));
+        let client = Metered::new(Arc::new(Model), tracker.clone());
+        assert_eq!(client.complete(request("gpt-4")).await?.content, "gpt-4");
+        assert_eq!(
+            client.complete(request("gpt-4")).await?.content,
+            "gpt-4o-mini"
+        );
+        assert!(tracker.check(&[]).is_none());
+        Ok(())
+    }
+}
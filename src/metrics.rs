@@ -0,0 +1,515 @@
+//! Metrics for operators, with the `metrics` feature. Agents count the
+//! messages they receive, handle and fail on, time their handlers and report
+//! how many messages are left in their mailboxes, and assistants count the
+//! tokens their models use, all labeled with the agent's name and id.
+//!
+//! Conversations count their turns, tool calls, tokens and retries, and
+//! time themselves, labeled with the conversation's id, e.g. a chat's
+//! [`with_conversation_id`](crate::chat::ChatBuilder::with_conversation_id).
+//! Ids are kept as labels for up to [`set_max_conversations`] conversations
+//! at a time, and any over those are labeled [`OTHER_CONVERSATIONS`], so
+//! dashboards can show conversation-level SLOs without the number of series
+//! growing without bound. A conversation's id is freed for another when it
+//! finishes.
+//!
+//! Metrics are emitted through the `metrics` crate, so they go to whichever
+//! recorder is installed, e.g. a Prometheus exporter, and are dropped until
+//! one is.
+
+use {
+    crate::llm::Usage,
+    ::metrics::{counter, gauge, histogram},
+    std::{
+        collections::HashMap,
+        future::Future,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Instant,
+    },
+    uuid::Uuid,
+};
+
+/// Counts the messages agents took from their mailboxes.
+pub const MESSAGES_RECEIVED: &str = "autogen_messages_received_total";
+
+/// Counts the messages agents handled.
+pub const MESSAGES_PROCESSED: &str = "autogen_messages_processed_total";
+
+/// Counts the messages agents' handlers failed or panicked on.
+pub const MESSAGES_FAILED: &str = "autogen_messages_failed_total";
+
+/// Records how long agents' handlers took on a message, in seconds.
+pub const HANDLER_DURATION: &str = "autogen_handler_duration_seconds";
+
+/// The number of messages left in an agent's mailbox when it took the last.
+pub const MAILBOX_DEPTH: &str = "autogen_mailbox_depth";
+
+/// Counts the tokens of assistants' calls to models, labeled with the
+/// `model` and the `kind` of tokens, `prompt` or `completion`.
+pub const LLM_TOKENS: &str = "autogen_llm_tokens_total";
+
+/// Counts the replies in conversations.
+pub const CONVERSATION_TURNS: &str = "autogen_conversation_turns_total";
+
+/// Counts the tools called in conversations.
+pub const CONVERSATION_TOOL_CALLS: &str = "autogen_conversation_tool_calls_total";
+
+/// Counts the tokens of calls to models in conversations.
+pub const CONVERSATION_TOKENS: &str = "autogen_conversation_tokens_total";
+
+/// Counts the calls to models retried in conversations.
+pub const CONVERSATION_RETRIES: &str = "autogen_conversation_retries_total";
+
+/// Records how long conversations took, in seconds.
+pub const CONVERSATION_DURATION: &str = "autogen_conversation_duration_seconds";
+
+/// The `conversation` label of conversations over the limit.
+pub const OTHER_CONVERSATIONS: &str = "other";
+
+/// The most conversation ids kept as labels at a time, unless set
+/// otherwise.
+pub const DEFAULT_MAX_CONVERSATIONS: usize = 1000;
+
+/// A label of a metric and its value, e.g. `("agent", "coder")`.
+type Label = (&'static str, String);
+
+/// The conversation ids kept as labels.
+static CONVERSATIONS: Labels = Labels::new(DEFAULT_MAX_CONVERSATIONS);
+
+tokio::task_local! {
+    /// The label of the conversation the current task is working on.
+    static CONVERSATION: String;
+}
+
+/// Keep the ids of up to `max` conversations at a time as labels.
+/// Conversations already labeled keep their labels.
+pub fn set_max_conversations(max: usize) {
+    CONVERSATIONS.max.store(max, Ordering::Relaxed);
+}
+
+/// Returns the labels of the agent with `id` and `name`.
+fn agent_labels(id: Uuid, name: Option<&str>) -> Vec<Label> {
+    vec![
+        ("agent", name.unwrap_or_default().to_string()),
+        ("agent_id", id.to_string()),
+    ]
+}
+
+/// The handling of a message, timed from when the agent took it.
+pub(crate) struct Handling {
+    labels: Vec<Label>,
+    started: Instant,
+}
+
+impl Handling {
+    /// Records that the agent with `id` and `name` took a message, leaving
+    /// `depth` in its mailbox.
+    pub(crate) fn start(id: Uuid, name: Option<&str>, depth: usize) -> Self {
+        let labels = agent_labels(id, name);
+        counter!(MESSAGES_RECEIVED, 1, &labels);
+        gauge!(MAILBOX_DEPTH, depth as f64, &labels);
+        Self {
+            labels,
+            started: Instant::now(),
+        }
+    }
+
+    /// Records how long the handler took, and whether it handled the
+    /// message.
+    pub(crate) fn finish(self, handled: bool) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        histogram!(HANDLER_DURATION, elapsed, &self.labels);
+        let name = match handled {
+            true => MESSAGES_PROCESSED,
+            false => MESSAGES_FAILED,
+        };
+        counter!(name, 1, &self.labels);
+    }
+}
+
+/// The conversation ids kept as labels, with how many of each
+/// conversation's [`Conversation`]s are open.
+#[derive(Debug)]
+struct Labels {
+    max: AtomicUsize,
+    open: Mutex<Option<HashMap<String, usize>>>,
+}
+
+impl Labels {
+    const fn new(max: usize) -> Self {
+        Self {
+            max: AtomicUsize::new(max),
+            open: Mutex::new(None),
+        }
+    }
+
+    /// Keeps `id` as a label and returns `true`, unless there are already
+    /// as many conversations as labels allowed.
+    fn acquire(&self, id: &str) -> bool {
+        let mut open = self.open.lock().unwrap();
+        let open = open.get_or_insert_with(HashMap::new);
+        if let Some(count) = open.get_mut(id) {
+            *count += 1;
+            return true;
+        }
+        if open.len() >= self.max.load(Ordering::Relaxed) {
+            return false;
+        }
+        open.insert(id.to_string(), 1);
+        true
+    }
+
+    /// Frees the label of the conversation with `id` once none of its
+    /// conversations are open.
+    fn release(&self, id: &str) {
+        let mut open = self.open.lock().unwrap();
+        let Some(open) = open.as_mut() else {
+            return;
+        };
+        if let Some(count) = open.get_mut(id) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(id);
+            }
+        }
+    }
+}
+
+/// A conversation metrics are recorded for. Its id is kept as a label until
+/// it's dropped.
+#[derive(Debug)]
+pub(crate) struct Conversation {
+    label: String,
+    /// Where the label is kept, if it's the conversation's id.
+    kept: Option<&'static Labels>,
+    started: Instant,
+}
+
+impl Conversation {
+    /// Start the conversation with `id`, labeled with it unless there are
+    /// already as many conversations as labels allowed.
+    pub(crate) fn start(id: &str) -> Self {
+        Self::start_in(&CONVERSATIONS, id)
+    }
+
+    fn start_in(labels: &'static Labels, id: &str) -> Self {
+        let (label, kept) = match labels.acquire(id) {
+            true => (id.to_string(), Some(labels)),
+            false => (OTHER_CONVERSATIONS.to_string(), None),
+        };
+        Self {
+            label,
+            kept,
+            started: Instant::now(),
+        }
+    }
+
+    /// Add `value` to the conversation's counter `name`.
+    pub(crate) fn increment(&self, name: &'static str, value: u64) {
+        counter!(name, value, "conversation" => self.label.clone());
+    }
+
+    /// Records how long the conversation took, freeing its label.
+    pub(crate) fn finish(self) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        histogram!(CONVERSATION_DURATION, elapsed, "conversation" => self.label.clone());
+    }
+}
+
+impl Drop for Conversation {
+    fn drop(&mut self) {
+        if let Some(labels) = self.kept {
+            labels.release(&self.label);
+        }
+    }
+}
+
+/// Runs `future` as part of the conversation with `id`, if any, so the
+/// metrics it records are counted for the conversation too.
+pub(crate) async fn in_conversation<F: Future>(id: Option<&str>, future: F) -> F::Output {
+    match id {
+        Some(id) => {
+            let conversation = Conversation::start(id);
+            CONVERSATION
+                .scope(conversation.label.clone(), future)
+                .await
+        }
+        None => future.await,
+    }
+}
+
+/// Add `value` to the counter `name` of the conversation the current task is
+/// working on, if any.
+pub(crate) fn increment_conversation(name: &'static str, value: u64) {
+    let _ = CONVERSATION.try_with(|label| {
+        counter!(name, value, "conversation" => label.clone());
+    });
+}
+
+/// Records the tokens of a call to `model` by the agent with `id` and
+/// `name`.
+pub(crate) fn record_tokens(id: Uuid, name: &str, model: &str, usage: Usage) {
+    let mut labels = agent_labels(id, Some(name));
+    labels.push(("model", model.to_string()));
+    for (kind, tokens) in [
+        ("prompt", usage.prompt_tokens),
+        ("completion", usage.completion_tokens),
+    ] {
+        labels.push(("kind", kind.to_string()));
+        counter!(LLM_TOKENS, tokens as u64, &labels);
+        labels.pop();
+    }
+    let tokens = usage.prompt_tokens + usage.completion_tokens;
+    increment_conversation(CONVERSATION_TOKENS, tokens as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            agent::{assistant::AssistantBuilder, AgentBuilder, Message, SendError},
+            chat::ChatBuilder,
+            llm::{
+                offline::Offline,
+                retry::{Retry, RetryPolicy},
+                CompletionRequest, Error, LlmClient, LlmFuture,
+            },
+            tools::FnTool,
+        },
+        anyhow::Result,
+        serde_json::json,
+        ::metrics::{
+            Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Recorder,
+            SharedString, Unit,
+        },
+        std::{
+            sync::{atomic::AtomicBool, Arc, OnceLock},
+            time::Duration,
+        },
+    };
+
+    /// A metric's name, labels and a value recorded for it.
+    type Record = (String, Vec<(String, String)>, f64);
+
+    /// Keeps what's recorded.
+    #[derive(Default)]
+    struct Kept(Mutex<Vec<Record>>);
+
+    /// A metric registered with [`Kept`].
+    struct Handle {
+        key: Key,
+        kept: &'static Kept,
+    }
+
+    impl Handle {
+        fn record(&self, value: f64) {
+            let labels = self
+                .key
+                .labels()
+                .map(|label| (label.key().to_string(), label.value().to_string()))
+                .collect();
+            let name = self.key.name().to_string();
+            self.kept.0.lock().unwrap().push((name, labels, value));
+        }
+    }
+
+    impl CounterFn for Handle {
+        fn increment(&self, value: u64) {
+            self.record(value as f64);
+        }
+
+        fn absolute(&self, value: u64) {
+            self.record(value as f64);
+        }
+    }
+
+    impl GaugeFn for Handle {
+        fn increment(&self, value: f64) {
+            self.record(value);
+        }
+
+        fn decrement(&self, value: f64) {
+            self.record(-value);
+        }
+
+        fn set(&self, value: f64) {
+            self.record(value);
+        }
+    }
+
+    impl HistogramFn for Handle {
+        fn record(&self, value: f64) {
+            Handle::record(self, value);
+        }
+    }
+
+    impl Recorder for &'static Kept {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key) -> Counter {
+            Counter::from_arc(Arc::new(Handle {
+                key: key.clone(),
+                kept: self,
+            }))
+        }
+
+        fn register_gauge(&self, key: &Key) -> Gauge {
+            Gauge::from_arc(Arc::new(Handle {
+                key: key.clone(),
+                kept: self,
+            }))
+        }
+
+        fn register_histogram(&self, key: &Key) -> Histogram {
+            Histogram::from_arc(Arc::new(Handle {
+                key: key.clone(),
+                kept: self,
+            }))
+        }
+    }
+
+    /// Returns what's recorded, installing the recorder the first time.
+    fn kept() -> &'static Kept {
+        static KEPT: OnceLock<&'static Kept> = OnceLock::new();
+        KEPT.get_or_init(|| {
+            let kept: &'static Kept = Box::leak(Box::default());
+            ::metrics::set_boxed_recorder(Box::new(kept)).unwrap();
+            kept
+        })
+    }
+
+    /// Times out once, then replies offline, calling each tool once, with
+    /// a few tokens.
+    #[derive(Debug, Default)]
+    struct Flaky(AtomicBool);
+
+    impl LlmClient for Flaky {
+        fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+            Box::pin(async move {
+                if !self.0.swap(true, Ordering::SeqCst) {
+                    return Err(Error::Timeout(Duration::ZERO));
+                }
+                let mut completion = Offline::new().with_tool_calls().complete(request).await?;
+                completion.usage = Some(Usage {
+                    prompt_tokens: 5,
+                    completion_tokens: 1,
+                });
+                Ok(completion)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics() -> Result<()> {
+        let kept = kept();
+
+        let builder = AgentBuilder::new().with_name("counter");
+        let agent = builder.spawn(|_sender, message: u32| async move {
+            match message {
+                0 => Err(SendError::new(message)),
+                _ => Ok(()),
+            }
+        });
+        let id = agent.id;
+        agent.send(1).await?;
+        agent.send(0).await?;
+        let _ = agent.join().await;
+        record_tokens(
+            id,
+            "counter",
+            "gpt-4",
+            Usage {
+                prompt_tokens: 12,
+                completion_tokens: 3,
+            },
+        );
+
+        let agent_id = id.to_string();
+        let kept = kept.0.lock().unwrap();
+        let count = |name| {
+            kept.iter()
+                .filter(|(kept, labels, _)| {
+                    *kept == name && labels.iter().any(|label| label.1 == agent_id)
+                })
+                .map(|(_, _, value)| *value)
+                .sum::<f64>()
+        };
+        assert_eq!(count(MESSAGES_RECEIVED), 2.0);
+        assert_eq!(count(MESSAGES_PROCESSED), 1.0);
+        assert_eq!(count(MESSAGES_FAILED), 1.0);
+        assert_eq!(count(LLM_TOKENS), 15.0);
+        let durations = kept
+            .iter()
+            .filter(|(name, labels, _)| *name == HANDLER_DURATION && labels[1].1 == agent_id)
+            .count();
+        assert_eq!(durations, 2);
+        assert!(kept.iter().any(|(name, labels, _)| *name == MAILBOX_DEPTH
+            && labels[0] == ("agent".to_string(), "counter".to_string())));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_conversation_metrics() -> Result<()> {
+        let kept = kept();
+        let policy = RetryPolicy::new(2)
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(1))
+            .with_jitter(0.0);
+        let client = Retry::new(Arc::new(Flaky::default())).with_policy(policy);
+        let tool = FnTool::new("noop", "", json!({}), |_| async { Ok(String::new()) });
+        let assistant = AssistantBuilder::new()
+            .with_name("assistant")
+            .with_client(Arc::new(client))
+            .with_tool(tool)
+            .build();
+        let user = AgentBuilder::<_, SendError<Box<Message>>>::new()
+            .spawn(|_, _: Box<Message>| async { Ok(()) });
+        let chat = ChatBuilder::new()
+            .with_participant("user", user.sender())
+            .with_participant("assistant", assistant.sender())
+            .with_max_turns(1)
+            .with_conversation_id("metered")
+            .start("hello");
+        assert_eq!(chat.conversation_id(), "metered");
+        chat.join().await;
+
+        let conversation = ("conversation".to_string(), "metered".to_string());
+        let kept_for = |name| {
+            let kept = kept.0.lock().unwrap();
+            kept.iter()
+                .filter(|(kept, labels, _)| *kept == name && labels[..] == [conversation.clone()])
+                .map(|(_, _, value)| *value)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(kept_for(CONVERSATION_TURNS), [1.0]);
+        assert_eq!(kept_for(CONVERSATION_RETRIES), [1.0]);
+        assert_eq!(kept_for(CONVERSATION_TOOL_CALLS), [1.0]);
+        assert_eq!(kept_for(CONVERSATION_TOKENS), [6.0, 6.0]);
+        assert_eq!(kept_for(CONVERSATION_DURATION).len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_conversation_labels() {
+        static LABELS: Labels = Labels::new(1);
+        let first = Conversation::start_in(&LABELS, "first");
+        assert_eq!(first.label, "first");
+        assert_eq!(Conversation::start_in(&LABELS, "first").label, "first");
+
+        // conversations over the limit share a label
+        let second = Conversation::start_in(&LABELS, "second");
+        assert_eq!(second.label, OTHER_CONVERSATIONS);
+        drop(second);
+
+        // until a conversation finishes, freeing its label
+        first.finish();
+        assert_eq!(Conversation::start_in(&LABELS, "second").label, "second");
+    }
+}
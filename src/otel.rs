@@ -0,0 +1,173 @@
+//! OpenTelemetry export, with the `otel` feature. [`layer`] returns a
+//! `tracing` layer that exports the spans of agents, chats and anything else
+//! instrumented with `tracing` to an OpenTelemetry collector over OTLP.
+//!
+//! Spans keep their parents, so the spans within a handler share its trace,
+//! and the spans of agents built
+//! [`with_trace_propagation`](crate::agent::AgentBuilder::with_trace_propagation)
+//! are linked to the spans their messages were sent from, so a conversation
+//! can be followed from agent to agent. Events within a span are exported as
+//! the span's events.
+
+use {
+    opentelemetry::{trace::TraceError, KeyValue},
+    opentelemetry_otlp::WithExportConfig,
+    opentelemetry_sdk::{
+        runtime::Tokio,
+        trace::{self, Tracer},
+        Resource,
+    },
+    tracing::Subscriber,
+    tracing_opentelemetry::OpenTelemetryLayer,
+    tracing_subscriber::registry::LookupSpan,
+};
+
+/// The environment variable with the name of the service spans come from.
+const SERVICE_NAME_ENV_VAR: &str = "OTEL_SERVICE_NAME";
+
+/// Returns a layer that exports spans to the collector at
+/// `OTEL_EXPORTER_OTLP_ENDPOINT`, or on localhost, for the service named
+/// `OTEL_SERVICE_NAME`, or `autogen-rs`. Spans are exported in batches on the
+/// tokio runtime, so the layer must be created within one.
+///
+/// Usage:
+/// ```no_run
+/// # use tracing_subscriber::layer::SubscriberExt;
+/// # tokio_test::block_on(async {
+/// let layer = autogen_rs::otel::layer()?;
+/// tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))?;
+///
+/// // ...run the agents, then export the spans left before exiting
+/// autogen_rs::otel::shutdown();
+/// # anyhow::Ok(())
+/// # });
+/// ```
+pub fn layer<S>() -> Result<OpenTelemetryLayer<S, Tracer>, TraceError>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    layer_for(opentelemetry_otlp::new_exporter().tonic())
+}
+
+/// Returns a layer that exports spans to the collector at `endpoint`, e.g.
+/// `http://localhost:4317`, instead of the one in the environment. See
+/// [`layer`].
+pub fn layer_with_endpoint<S>(
+    endpoint: impl Into<String>,
+) -> Result<OpenTelemetryLayer<S, Tracer>, TraceError>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    layer_for(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+}
+
+/// Export the spans that haven't been yet and stop exporting, e.g. before
+/// the application exits.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Returns a layer that exports spans with `exporter`.
+fn layer_for<S>(
+    exporter: opentelemetry_otlp::TonicExporterBuilder,
+) -> Result<OpenTelemetryLayer<S, Tracer>, TraceError>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace::config().with_resource(resource()))
+        .install_batch(Tokio)?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Returns the resource spans come from, named `OTEL_SERVICE_NAME`, or
+/// `autogen-rs`.
+fn resource() -> Resource {
+    let service_name = std::env::var(SERVICE_NAME_ENV_VAR)
+        .unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_string());
+    Resource::new([KeyValue::new("service.name", service_name)])
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::agent::{AgentBuilder, Sender},
+        anyhow::Result,
+        futures_core::future::BoxFuture,
+        opentelemetry::trace::{SpanId, TracerProvider as _},
+        opentelemetry_sdk::{
+            export::trace::{ExportResult, SpanData, SpanExporter},
+            trace::TracerProvider,
+        },
+        std::sync::{Arc, Mutex},
+        tokio::sync::mpsc::error::SendError,
+        tracing::Instrument,
+        tracing_subscriber::layer::SubscriberExt,
+    };
+
+    /// Keeps the spans it's sent.
+    #[derive(Debug, Clone, Default)]
+    struct Kept(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Kept {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_layer() -> Result<()> {
+        // exporting is lazy, so a layer can be made without a collector
+        let _ = layer_with_endpoint::<tracing_subscriber::Registry>("http://127.0.0.1:1")?;
+
+        let kept = Kept::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(kept.clone())
+            .build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("tests"));
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+        // the test runs on one thread, agents included
+        let handler = |_: Sender<u32>, _: u32| {
+            async {
+                tracing::info!("handled");
+                Ok::<_, SendError<u32>>(())
+            }
+            .instrument(tracing::info_span!("reply"))
+        };
+        let agent = AgentBuilder::new().with_trace_propagation().spawn(handler);
+        agent
+            .send(1)
+            .instrument(tracing::info_span!("conversation"))
+            .await?;
+        agent.terminate().await;
+        provider.force_flush();
+
+        let kept = kept.0.lock().unwrap();
+        let span = |name: &str| kept.iter().find(|span| span.name == name).unwrap();
+        let (conversation, handle, reply) = (span("conversation"), span("handle"), span("reply"));
+
+        // the handler starts its own trace, linked to the send
+        assert_eq!(conversation.parent_span_id, SpanId::INVALID);
+        assert_ne!(
+            handle.span_context.trace_id(),
+            conversation.span_context.trace_id()
+        );
+        let links = handle.links.iter().collect::<Vec<_>>();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].span_context, conversation.span_context);
+
+        // spans within the handler share its trace
+        assert_eq!(reply.parent_span_id, handle.span_context.span_id());
+        assert_eq!(
+            reply.span_context.trace_id(),
+            handle.span_context.trace_id()
+        );
+        assert_eq!(reply.events.iter().next().unwrap().name, "handled");
+        Ok(())
+    }
+}
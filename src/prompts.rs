@@ -0,0 +1,361 @@
+//! The prompts built into the crate, e.g. the instructions for selecting the
+//! next speaker, summarizing a conversation or moderating a reply, and what
+//! a chat tells its participants. A [`PromptPack`] overrides any of them, so
+//! deployments in other languages can translate them, e.g. from a JSON file
+//! shipped with the application. Prompts a pack leaves out are in English.
+//!
+//! Prompts are templates: `{name}` is replaced by the value the crate fills
+//! in, e.g. `{names}` in [`Prompt::SpeakerSelection`] by the participants'
+//! names. Placeholders a prompt doesn't know are left as they are.
+
+use {
+    serde::{Deserialize, Serialize},
+    std::collections::BTreeMap,
+};
+
+/// A prompt built into the crate, with the placeholders it's filled in with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Prompt {
+    /// Asks a model for the next speaker: `{roles}`, `{names}`.
+    SpeakerSelection,
+
+    /// Reminds the model to answer with the next speaker, after the
+    /// conversation: `{names}`.
+    SpeakerSelectionReminder,
+
+    /// Tells a managing model who's in the chat: `{participants}`.
+    Manager,
+
+    /// Tells a managing model who can be added: `{standby}`.
+    ManagerStandby,
+
+    /// Tells a managing model how to pick the next speaker:
+    /// `{select_speaker}`.
+    ManagerInstructions,
+
+    /// Tells a managing model which other tools it may call: `{tools}`,
+    /// `{max_actions}`.
+    ManagerTools,
+
+    /// Reminds a managing model to pick the next speaker, after the
+    /// conversation: `{select_speaker}`.
+    ManagerReminder,
+
+    /// Tells a moderating model what it's reviewing.
+    Moderation,
+
+    /// Asks a moderating model for its verdict on a reply: `{name}`,
+    /// `{reply}`. Translations must keep `APPROVE`, `REVISE:` and `VETO:`,
+    /// which the verdict is read from.
+    ModerationReview,
+
+    /// Asks a model to extract variables from a chat.
+    Extraction,
+
+    /// Asks a model to summarize a conversation.
+    Summary,
+
+    /// Introduces the summary being folded into a new one: `{summary}`.
+    PreviousSummary,
+
+    /// Introduces the conversation being summarized.
+    Conversation,
+
+    /// Stands in for the messages a summary replaced: `{summary}`.
+    EarlierSummary,
+
+    /// Tells a participant who it is in a chat: `{name}`, `{others}`.
+    Participant,
+
+    /// Tells a participant its role: `{description}`.
+    ParticipantRole,
+
+    /// Tells a participant what the chat is for: `{goal}`.
+    ChatGoal,
+
+    /// Asks a participant again after its reply broke a limit:
+    /// `{violation}`.
+    ReplyRejected,
+
+    /// Tells a participant its last reply was cut short: `{violation}`.
+    ReplyCutShort,
+
+    /// Sends a reply back for revision: `{feedback}`.
+    Revision,
+
+    /// Tells a participant its last reply was vetoed: `{reason}`.
+    Veto,
+
+    /// Announces a new topic: `{topic}`.
+    TopicChanged,
+
+    /// Announces a participant joining: `{name}`.
+    Joined,
+
+    /// Announces a participant leaving: `{name}`.
+    Left,
+
+    /// Tells a model without native tool calling which tools it can call
+    /// and how: `{tools}`. Translations must keep `Action:` and
+    /// `Action Input:`, which calls are read from.
+    ToolEmulation,
+
+    /// Gives a model without native tool calling the result of a call:
+    /// `{name}`, `{result}`.
+    ToolObservation,
+
+    /// Tells the participants of a typed chat what its result must look
+    /// like: `{schema}`.
+    TypedOutput,
+}
+
+impl Prompt {
+    /// Every prompt.
+    pub const ALL: [Self; 27] = [
+        Self::SpeakerSelection,
+        Self::SpeakerSelectionReminder,
+        Self::Manager,
+        Self::ManagerStandby,
+        Self::ManagerInstructions,
+        Self::ManagerTools,
+        Self::ManagerReminder,
+        Self::Moderation,
+        Self::ModerationReview,
+        Self::Extraction,
+        Self::Summary,
+        Self::PreviousSummary,
+        Self::Conversation,
+        Self::EarlierSummary,
+        Self::Participant,
+        Self::ParticipantRole,
+        Self::ChatGoal,
+        Self::ReplyRejected,
+        Self::ReplyCutShort,
+        Self::Revision,
+        Self::Veto,
+        Self::TopicChanged,
+        Self::Joined,
+        Self::Left,
+        Self::ToolEmulation,
+        Self::ToolObservation,
+        Self::TypedOutput,
+    ];
+
+    /// Returns the prompt in English.
+    pub fn english(self) -> &'static str {
+        match self {
+            Self::SpeakerSelection => {
+                "You are in a role play game. The following roles are \
+                available:\n{roles}\n\nRead the following conversation. Then select the next \
+                role from [{names}] to play. Only return the role."
+            }
+            Self::SpeakerSelectionReminder => {
+                "Select the next role from [{names}] to play. Only return the role."
+            }
+            Self::Manager => "You manage a group chat. The participants are:\n{participants}",
+            Self::ManagerStandby => "These participants can be added to the chat:\n{standby}",
+            Self::ManagerInstructions => {
+                "Read the following conversation. Then call {select_speaker} to choose who \
+                 speaks next."
+            }
+            Self::ManagerTools => {
+                "If the conversation needs it, you may also call {tools} \
+                first, at most {max_actions} times in all."
+            }
+            Self::ManagerReminder => {
+                "Manage the chat. Call {select_speaker} to choose who speaks next."
+            }
+            Self::Moderation => {
+                "You moderate a group chat. Read the following conversation, \
+                then the reply a participant wants to send."
+            }
+            Self::ModerationReview => {
+                "{name} wants to reply:\n{reply}\n\nIf the reply stays \
+                on topic and moves the conversation forward, return APPROVE. If it could, with \
+                changes, return REVISE: followed by what to change. If it's off topic, return \
+                VETO: followed by why."
+            }
+            Self::Extraction => {
+                "Read the following conversation and extract the variables \
+                in the schema. Use null for variables the conversation doesn't settle. Reply \
+                with only JSON matching the schema."
+            }
+            Self::Summary => {
+                "Summarize the conversation below for the assistant taking part \
+                in it, so it can continue without the original messages. Keep facts, \
+                decisions, open questions and the results of tool calls. If there is a \
+                previous summary, fold it into the new one. Reply with the summary only."
+            }
+            Self::PreviousSummary => "Previous summary:\n{summary}",
+            Self::Conversation => "Conversation:",
+            Self::EarlierSummary => "Summary of the earlier conversation:\n{summary}",
+            Self::Participant => "You are {name} in a group chat with {others}.",
+            Self::ParticipantRole => "Your role: {description}",
+            Self::ChatGoal => "The goal of the chat: {goal}",
+            Self::ReplyRejected => {
+                "Your reply was rejected: {violation}. Reply again within the limit."
+            }
+            Self::ReplyCutShort => {
+                "Your last reply was cut short: {violation}. Keep your replies within the limit."
+            }
+            Self::Revision => "Your reply needs revision: {feedback}. Reply again.",
+            Self::Veto => "Your last reply was vetoed: {reason}. Stay on topic.",
+            Self::TopicChanged => "The topic is now: {topic}",
+            Self::Joined => "{name} joined the chat.",
+            Self::Left => "{name} left the chat.",
+            Self::ToolEmulation => {
+                "You can call these tools:\n{tools}\n\nTo call a tool, end your reply \
+                with:\nAction: the tool's name\nAction Input: the arguments, as a JSON object \
+                matching the tool's schema\n\nRepeat both lines to call several tools. The \
+                results come back as observations. To answer without calling a tool, reply \
+                without them."
+            }
+            Self::ToolObservation => "Observation from {name}: {result}",
+            Self::TypedOutput => {
+                "When the task is done, the last reply must be only JSON matching this \
+                schema:\n{schema}"
+            }
+        }
+    }
+}
+
+/// The prompts to use instead of the built-in English ones.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{group_chat::{selector::LlmSelector, GroupChat}, llm::openai, prompts::{Prompt, PromptPack}}, std::sync::Arc};
+/// let prompts = PromptPack::from_json(
+///     r#"{
+///         "language": "de",
+///         "prompts": {
+///             "speaker_selection_reminder": "Wähle die nächste Rolle aus [{names}]. Antworte nur mit der Rolle.",
+///             "joined": "{name} ist dem Chat beigetreten."
+///         }
+///     }"#,
+/// )?;
+/// assert_eq!(prompts.render(Prompt::Joined, &[("name", "coder")]), "coder ist dem Chat beigetreten.");
+/// // prompts that aren't translated are in English
+/// assert_eq!(prompts.render(Prompt::Left, &[("name", "coder")]), "coder left the chat.");
+///
+/// let client = Arc::new(openai::Client::new(None, None));
+/// let chat = GroupChat::new()
+///     .with_prompts(prompts.clone())
+///     .with_speaker_selector(LlmSelector::new(client, "gpt-4").with_prompts(prompts));
+/// # anyhow::Ok(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PromptPack {
+    /// The language of the prompts, e.g. `de`.
+    pub language: String,
+
+    /// The prompts overridden.
+    #[serde(default)]
+    pub prompts: BTreeMap<Prompt, String>,
+}
+
+impl Default for PromptPack {
+    fn default() -> Self {
+        Self::new("en")
+    }
+}
+
+impl PromptPack {
+    /// Create a pack of prompts in `language` that overrides none of them.
+    pub fn new(language: impl ToString) -> Self {
+        Self {
+            language: language.to_string(),
+            prompts: BTreeMap::new(),
+        }
+    }
+
+    /// Returns a pack with every prompt in English, e.g. to write out as a
+    /// starting point for a translation.
+    pub fn english() -> Self {
+        Self {
+            prompts: Prompt::ALL
+                .into_iter()
+                .map(|prompt| (prompt, prompt.english().to_string()))
+                .collect(),
+            ..Self::new("en")
+        }
+    }
+
+    /// Parse a pack from JSON with the language and the prompts by name,
+    /// e.g. `{"language": "de", "prompts": {"joined": "..."}}`.
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    /// Use `template` for `prompt`.
+    pub fn with_prompt(mut self, prompt: Prompt, template: impl ToString) -> Self {
+        self.prompts.insert(prompt, template.to_string());
+        self
+    }
+
+    /// Returns the template for `prompt`, in English if it isn't overridden.
+    pub fn get(&self, prompt: Prompt) -> &str {
+        self.prompts
+            .get(&prompt)
+            .map_or(prompt.english(), String::as_str)
+    }
+
+    /// Returns `prompt` with its placeholders replaced by `values`.
+    pub fn render(&self, prompt: Prompt, values: &[(&str, &str)]) -> String {
+        render(self.get(prompt), values)
+    }
+}
+
+/// Returns `template` with its `{name}` placeholders replaced by `values`.
+/// Placeholders without a value are left as they are.
+pub(crate) fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    // one pass, so values that look like placeholders are left alone
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let value = after.find('}').and_then(|close| {
+            let name = &after[..close];
+            let (_, value) = values.iter().find(|(key, _)| *key == name)?;
+            Some((value, close))
+        });
+        match value {
+            Some((value, close)) => {
+                rendered.push_str(value);
+                rest = &after[close + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, anyhow::Result};
+
+    #[test]
+    fn test_prompt_pack() -> Result<()> {
+        let pack = PromptPack::new("de").with_prompt(Prompt::Veto, "Abgelehnt: {reason} {other}");
+        assert_eq!(
+            pack.render(Prompt::Veto, &[("reason", "{reason}")]),
+            "Abgelehnt: {reason} {other}"
+        );
+        assert_eq!(
+            pack.render(Prompt::TopicChanged, &[("topic", "tests")]),
+            "The topic is now: tests"
+        );
+
+        let english = PromptPack::english();
+        assert_eq!(english.prompts.len(), Prompt::ALL.len());
+        let json = serde_json::to_string(&english)?;
+        assert_eq!(PromptPack::from_json(&json)?, english);
+        assert!(PromptPack::from_json(r#"{"language": "de", "prompts": {"nope": ""}}"#).is_err());
+        Ok(())
+    }
+}
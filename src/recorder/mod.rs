@@ -0,0 +1,331 @@
+//! Recording conversations for debugging. A [`Recorder`] keeps every message
+//! it sees, with who sent it, who received it and when, in memory. It's
+//! attached to agents as [middleware](crate::agent::Middleware), recording
+//! each message they handle, and to chats with
+//! [`ChatBuilder::with_recorder`](crate::chat::ChatBuilder::with_recorder),
+//! recording each message delivered to a participant. One recorder can be
+//! shared by every agent and chat in a run, and its transcript exported as
+//! JSON, JSON lines or Markdown. Wrapping an agent's model with
+//! [`Recorder::client`] records its replies too, so the run can be
+//! [replayed](replay::Replay) without the model.
+
+use {
+    crate::{
+        agent::{AgentContext, Content, Message, Middleware, MiddlewareFuture, Next},
+        llm::{CompletionRequest, LlmClient, LlmFuture, Role, ToolCall},
+    },
+    serde::{Deserialize, Serialize},
+    std::{
+        fmt::Write,
+        sync::{Arc, Mutex},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+pub mod replay;
+
+/// What a recorded message is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordKind {
+    /// A message delivered to an agent or participant.
+    #[default]
+    Delivered,
+
+    /// A model's reply to an agent, sent by the model.
+    Completion,
+}
+
+/// A message in a recorded transcript.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// The name of whoever sent the message, if known.
+    pub sender: Option<String>,
+
+    /// The name, or if it has none the id, of the agent or participant that
+    /// received the message.
+    pub receiver: String,
+
+    /// Who the message came from, as the receiver sees it.
+    pub role: Role,
+
+    /// What the message says.
+    pub content: String,
+
+    /// When the message was created.
+    pub sent_at: SystemTime,
+
+    /// When the message was recorded, as its receiver got it.
+    pub recorded_at: SystemTime,
+
+    /// What the message is.
+    #[serde(default)]
+    pub kind: RecordKind,
+
+    /// The tools a model's reply asked to call.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// Records the messages of agents and chats into a transcript.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::{agent::{AgentBuilder, Message, SendError}, chat::ChatBuilder, recorder::Recorder};
+/// # tokio_test::block_on(async {
+/// let echo = |sender, message: Box<Message>| async move {
+///     let content = message.content.clone();
+///     message
+///         .sender
+///         .send(Box::new(Message::new(sender, content)))
+///         .await
+/// };
+/// let recorder = Recorder::new();
+/// // records what the critic handles, whether in a chat or not
+/// let critic = AgentBuilder::<_, SendError<_>>::new()
+///     .with_name("critic")
+///     .with_middleware(recorder.clone())
+///     .spawn(echo);
+/// let writer = AgentBuilder::<_, SendError<_>>::new().spawn(echo);
+///
+/// // records every message the chat delivers, with who it came from
+/// let chat = ChatBuilder::new()
+///     .with_participant("writer", writer.sender())
+///     .with_participant("critic", critic.sender())
+///     .with_max_turns(2)
+///     .with_recorder(recorder.clone())
+///     .start("Write a haiku about Rust.");
+/// chat.join().await;
+///
+/// assert_eq!(recorder.messages().len(), 3);
+/// assert!(recorder.to_markdown().starts_with("### writer → critic (user, "));
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Recorder {
+    messages: Arc<Mutex<Vec<RecordedMessage>>>,
+}
+
+impl Recorder {
+    /// Create a recorder with an empty transcript.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Record `message`, received by `receiver`.
+    pub fn record(&self, receiver: impl ToString, message: &Message) {
+        self.push(RecordedMessage {
+            sender: message.name.clone(),
+            receiver: receiver.to_string(),
+            role: message.role,
+            content: message.content.to_string(),
+            sent_at: message.timestamp,
+            recorded_at: SystemTime::now(),
+            kind: RecordKind::Delivered,
+            tool_calls: match &message.content {
+                Content::ToolCalls(calls) => calls.clone(),
+                _ => Vec::new(),
+            },
+        });
+    }
+
+    /// Wrap the model of the agent named `name`, recording its replies as
+    /// sent by the model to the agent.
+    pub fn client(&self, name: impl ToString, client: Arc<dyn LlmClient>) -> RecordingClient {
+        RecordingClient {
+            name: name.to_string(),
+            client,
+            recorder: self.clone(),
+        }
+    }
+
+    /// Add `message` to the transcript.
+    pub fn push(&self, message: RecordedMessage) {
+        self.messages.lock().unwrap().push(message);
+    }
+
+    /// Returns the messages recorded so far, in the order they were
+    /// recorded.
+    pub fn messages(&self) -> Vec<RecordedMessage> {
+        self.messages.lock().unwrap().clone()
+    }
+
+    /// Forget the messages recorded so far.
+    pub fn clear(&self) {
+        self.messages.lock().unwrap().clear();
+    }
+
+    /// Returns the transcript as a JSON array.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(&*self.messages.lock().unwrap())
+    }
+
+    /// Returns the transcript as JSON lines, one message per line.
+    pub fn to_jsonl(&self) -> serde_json::Result<String> {
+        let messages = self.messages.lock().unwrap();
+        let mut jsonl = String::new();
+        for message in messages.iter() {
+            jsonl.push_str(&serde_json::to_string(message)?);
+            jsonl.push('\n');
+        }
+        Ok(jsonl)
+    }
+
+    /// Returns the transcript as Markdown, a section per message with its
+    /// sender, receiver, role and the seconds since the Unix epoch it was
+    /// sent at.
+    pub fn to_markdown(&self) -> String {
+        let messages = self.messages.lock().unwrap();
+        let mut markdown = String::new();
+        for message in messages.iter() {
+            let sender = message.sender.as_deref().unwrap_or("unknown");
+            let role = match message.role {
+                Role::System => "system",
+                Role::User => "user",
+                Role::Assistant => "assistant",
+                Role::Tool => "tool",
+            };
+            let sent_at = message
+                .sent_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            let _ = write!(
+                markdown,
+                "### {sender} → {} ({role}, {sent_at:.3})\n\n{}\n\n",
+                message.receiver, message.content
+            );
+            for call in &message.tool_calls {
+                let function = &call.function;
+                let _ = writeln!(
+                    markdown,
+                    "- calls `{}({})`\n",
+                    function.name, function.arguments
+                );
+            }
+        }
+        markdown
+    }
+}
+
+/// A model whose replies are recorded. See [`Recorder::client`].
+#[derive(Debug)]
+pub struct RecordingClient {
+    name: String,
+    client: Arc<dyn LlmClient>,
+    recorder: Recorder,
+}
+
+impl LlmClient for RecordingClient {
+    fn complete(&self, request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            let model = request.model.clone();
+            let sent_at = SystemTime::now();
+            let completion = self.client.complete(request).await?;
+            self.recorder.push(RecordedMessage {
+                sender: Some(model),
+                receiver: self.name.clone(),
+                role: Role::Assistant,
+                content: completion.content.clone(),
+                sent_at,
+                recorded_at: SystemTime::now(),
+                kind: RecordKind::Completion,
+                tool_calls: completion.tool_calls.clone(),
+            });
+            Ok(completion)
+        })
+    }
+}
+
+impl<E: Send + 'static> Middleware<Box<Message>, E> for Recorder {
+    fn handle<'a>(
+        &'a self,
+        context: &'a AgentContext<Box<Message>>,
+        message: Box<Message>,
+        next: Next<'a, Box<Message>, E>,
+    ) -> MiddlewareFuture<'a, E> {
+        let receiver = match &context.name {
+            Some(name) => name.clone(),
+            None => context.id.to_string(),
+        };
+        self.record(receiver, &message);
+        next.run(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            agent::{AgentBuilder, SendError},
+            chat::ChatBuilder,
+        },
+        anyhow::Result,
+    };
+
+    #[tokio::test]
+    async fn test_recorder() -> Result<()> {
+        let recorder = Recorder::new();
+        let spawn = |name: &'static str| {
+            AgentBuilder::<_, SendError<Box<Message>>>::new()
+                .with_name(name)
+                .with_middleware(recorder.clone())
+                .spawn(move |sender, message: Box<Message>| async move {
+                    let reply =
+                        Message::new(sender, format!("{name} read it")).with_role(Role::Assistant);
+                    message.sender.send(Box::new(reply)).await
+                })
+        };
+        let (a, b) = (spawn("a"), spawn("b"));
+        let chat_recorder = Recorder::new();
+        ChatBuilder::new()
+            .with_participant("a", a.sender())
+            .with_participant("b", b.sender())
+            .with_max_turns(2)
+            .with_recorder(chat_recorder.clone())
+            .start("hello")
+            .join()
+            .await;
+
+        // the agents see the messages the chat delivers to them
+        let received = recorder
+            .messages()
+            .into_iter()
+            .map(|message| (message.receiver, message.content))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            received,
+            [
+                ("b".to_string(), "hello".to_string()),
+                ("a".to_string(), "b read it".to_string())
+            ]
+        );
+
+        // the chat knows who each message came from
+        let delivered = chat_recorder.messages();
+        let senders = delivered
+            .iter()
+            .map(|message| (message.sender.as_deref(), message.receiver.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(senders, [(Some("a"), "b"), (Some("b"), "a")]);
+        assert!(delivered[0].sent_at <= delivered[0].recorded_at);
+
+        let jsonl = chat_recorder.to_jsonl()?;
+        let lines = jsonl
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<RecordedMessage>, _>>()?;
+        assert_eq!(lines, delivered);
+        let json: Vec<RecordedMessage> = serde_json::from_str(&chat_recorder.to_json()?)?;
+        assert_eq!(json, delivered);
+        let markdown = chat_recorder.to_markdown();
+        assert!(markdown.starts_with("### a → b (user, "));
+        assert!(markdown.contains("\n\nb read it\n\n"));
+
+        chat_recorder.clear();
+        assert!(chat_recorder.messages().is_empty());
+        Ok(())
+    }
+}
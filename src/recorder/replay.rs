@@ -0,0 +1,286 @@
+//! Replaying recorded runs. A [`Replay`] loads a transcript saved by a
+//! [`Recorder`] whose agents' models were [recorded](Recorder::client), and
+//! stands in for those models with [`Replay::client`], replying with what
+//! they replied then. Run the agents again, e.g. in the same chat, with a new
+//! recorder, and [`Replay::diverged`] finds the first message that differs:
+//! a bug reproduced without calling a model, or a regression test built from
+//! a production trace.
+
+use {
+    super::{RecordKind, RecordedMessage, Recorder},
+    crate::{
+        agent::{Message, SendError, Sender},
+        llm::{self, Completion, CompletionRequest, LlmClient, LlmFuture},
+    },
+    std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    },
+};
+
+/// An error replaying a recorded run.
+#[derive(thiserror::Error, Debug)]
+pub enum ReplayError {
+    /// The agent asked its model more often than it did when recorded.
+    #[error("no more recorded replies for {0}")]
+    Exhausted(String),
+
+    /// A recorded message couldn't be delivered to the agent.
+    #[error("unable to deliver recorded message: {0}")]
+    Send(#[from] SendError<Box<Message>>),
+
+    /// The agent stopped before replying to a recorded message.
+    #[error("agent stopped before replying")]
+    NoReply,
+}
+
+/// The first difference between a recorded run and its replay. Messages are
+/// compared by sender, receiver, role, content and tool calls, and not by
+/// when they were sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// The position of the message among those delivered.
+    pub index: usize,
+
+    /// The message delivered when the run was recorded, if there were as
+    /// many.
+    pub expected: Option<RecordedMessage>,
+
+    /// The message delivered in the replay, if there were as many.
+    pub actual: Option<RecordedMessage>,
+}
+
+/// A recorded run to replay.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::{agent::assistant::AssistantBuilder, chat::ChatBuilder, llm::offline::Offline, recorder::{replay::Replay, Recorder}}, std::sync::Arc};
+/// # tokio_test::block_on(async {
+/// let run = |writer, critic, recorder: Recorder| async move {
+///     let writer = AssistantBuilder::new().with_name("writer").with_client(writer).build();
+///     let critic = AssistantBuilder::new().with_name("critic").with_client(critic).build();
+///     ChatBuilder::new()
+///         .with_participant("writer", writer.sender())
+///         .with_participant("critic", critic.sender())
+///         .with_max_turns(2)
+///         .with_recorder(recorder)
+///         .start("Write a haiku about Rust.")
+///         .join()
+///         .await
+/// };
+///
+/// // record a run, with the models' replies
+/// let recorder = Recorder::new();
+/// let model = Arc::new(Offline::new());
+/// let writer = Arc::new(recorder.client("writer", model.clone()));
+/// let critic = Arc::new(recorder.client("critic", model));
+/// run(writer, critic, recorder.clone()).await;
+/// let saved = recorder.to_json()?;
+///
+/// // replay it without the models
+/// let replay = Replay::from_json(&saved)?;
+/// let replayed = Recorder::new();
+/// let (writer, critic) = (replay.client("writer"), replay.client("critic"));
+/// run(Arc::new(writer), Arc::new(critic), replayed.clone()).await;
+/// assert_eq!(replay.diverged(&replayed), None);
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replay {
+    messages: Vec<RecordedMessage>,
+}
+
+impl Replay {
+    /// Replay `messages`, as recorded.
+    pub fn new(messages: Vec<RecordedMessage>) -> Self {
+        Self { messages }
+    }
+
+    /// Replay a transcript saved with [`Recorder::to_json`].
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json).map(Self::new)
+    }
+
+    /// Replay a transcript saved with [`Recorder::to_jsonl`].
+    pub fn from_jsonl(jsonl: &str) -> serde_json::Result<Self> {
+        jsonl
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()
+            .map(Self::new)
+    }
+
+    /// Returns the messages delivered when the run was recorded, in order.
+    pub fn delivered(&self) -> impl Iterator<Item = &RecordedMessage> {
+        self.messages
+            .iter()
+            .filter(|message| message.kind == RecordKind::Delivered)
+    }
+
+    /// Returns a model for the agent named `name` that replies with the
+    /// agent's recorded replies, in order, whatever it's asked.
+    pub fn client(&self, name: &str) -> ReplayClient {
+        let completions = self
+            .messages
+            .iter()
+            .filter(|message| message.kind == RecordKind::Completion && message.receiver == name)
+            .map(|message| Completion {
+                content: message.content.clone(),
+                tool_calls: message.tool_calls.clone(),
+                ..Default::default()
+            })
+            .collect();
+        ReplayClient {
+            name: name.to_string(),
+            completions: Arc::new(Mutex::new(completions)),
+        }
+    }
+
+    /// Deliver the messages recorded as delivered to `receiver` to `agent`
+    /// again, one at a time, each after the agent replied to the one before.
+    /// Returns the agent's replies.
+    pub async fn feed(
+        &self,
+        receiver: &str,
+        agent: &Sender<Box<Message>>,
+    ) -> Result<Vec<String>, ReplayError> {
+        let (inbox, mut replies) = crate::agent::channel(None);
+        let mut contents = Vec::new();
+        for recorded in self
+            .delivered()
+            .filter(|message| message.receiver == receiver)
+        {
+            let mut message =
+                Message::new(inbox.clone(), recorded.content.clone()).with_role(recorded.role);
+            message.name = recorded.sender.clone();
+            agent.send(Box::new(message)).await?;
+            let reply = replies.recv().await.ok_or(ReplayError::NoReply)?;
+            contents.push(reply.content.to_string());
+        }
+        Ok(contents)
+    }
+
+    /// Returns the first difference between the messages delivered when the
+    /// run was recorded and those `replayed` recorded, or `None` if they're
+    /// the same.
+    pub fn diverged(&self, replayed: &Recorder) -> Option<Divergence> {
+        let replayed = replayed.messages();
+        let mut expected = self.delivered();
+        let mut actual = replayed
+            .iter()
+            .filter(|message| message.kind == RecordKind::Delivered);
+        for index in 0.. {
+            match (expected.next(), actual.next()) {
+                (None, None) => return None,
+                (Some(expected), Some(actual)) if same(expected, actual) => {}
+                (expected, actual) => {
+                    return Some(Divergence {
+                        index,
+                        expected: expected.cloned(),
+                        actual: actual.cloned(),
+                    })
+                }
+            }
+        }
+        unreachable!("transcripts are finite")
+    }
+}
+
+/// Returns whether `a` and `b` are the same message, whenever they were sent.
+fn same(a: &RecordedMessage, b: &RecordedMessage) -> bool {
+    a.sender == b.sender
+        && a.receiver == b.receiver
+        && a.role == b.role
+        && a.content == b.content
+        && a.tool_calls == b.tool_calls
+}
+
+/// A model that replies with an agent's recorded replies. See
+/// [`Replay::client`].
+#[derive(Debug, Clone)]
+pub struct ReplayClient {
+    name: String,
+    completions: Arc<Mutex<VecDeque<Completion>>>,
+}
+
+impl LlmClient for ReplayClient {
+    fn complete(&self, _request: CompletionRequest) -> LlmFuture<'_> {
+        Box::pin(async move {
+            let completion = self.completions.lock().unwrap().pop_front();
+            completion.ok_or_else(|| {
+                llm::Error::Other(Box::new(ReplayError::Exhausted(self.name.clone())))
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            agent::assistant::AssistantBuilder,
+            chat::ChatBuilder,
+            llm::{offline::Offline, Role},
+        },
+        anyhow::Result,
+    };
+
+    #[tokio::test]
+    async fn test_replay() -> Result<()> {
+        let spawn = |name: &str, client: Arc<dyn LlmClient>| {
+            AssistantBuilder::new()
+                .with_name(name)
+                .with_client(client)
+                .build()
+        };
+
+        // a recorded chat
+        let recorder = Recorder::new();
+        let model = Arc::new(Offline::new().with_template("{message}, answered"));
+        let writer = spawn("writer", Arc::new(recorder.client("writer", model.clone())));
+        let critic = spawn("critic", Arc::new(recorder.client("critic", model)));
+        ChatBuilder::new()
+            .with_participant("writer", writer.sender())
+            .with_participant("critic", critic.sender())
+            .with_max_turns(2)
+            .with_recorder(recorder.clone())
+            .start("hello")
+            .join()
+            .await;
+        let replay = Replay::from_jsonl(&recorder.to_jsonl()?)?;
+        assert_eq!(replay.delivered().count(), 2);
+
+        // the critic is sent what it was sent then, and replies as it did
+        // without the model
+        let critic = spawn("critic", Arc::new(replay.client("critic")));
+        let replies = replay.feed("critic", &critic.sender()).await?;
+        assert_eq!(replies, ["hello, answered"]);
+
+        // a run that went differently
+        let divergence = replay.diverged(&Recorder::new()).unwrap();
+        assert_eq!(divergence.index, 0);
+        let expected = divergence.expected.unwrap();
+        assert_eq!(
+            (
+                expected.sender.as_deref(),
+                expected.role,
+                expected.content.as_str()
+            ),
+            (Some("writer"), Role::User, "hello")
+        );
+        assert_eq!(divergence.actual, None);
+
+        // a model asked more often than recorded
+        let client = replay.client("writer");
+        client.complete(CompletionRequest::default()).await?;
+        let error = client
+            .complete(CompletionRequest::default())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no more recorded replies for writer");
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+//! [proptest] strategies for the crate's messages, configs and transcripts, so
+//! handlers and serialization round-trips can be property-tested. Enabled by
+//! the `testing` feature.
+
+use {
+    crate::{
+        agent::{
+            supervisor::{Backoff, RestartStrategy},
+            Message, Sender, StreamEvent, ToolProgress,
+        },
+        chat::{ChatMessage, TerminationReason},
+        llm::{HistoryMessage, Role},
+    },
+    proptest::{
+        arbitrary::Arbitrary,
+        collection,
+        prelude::*,
+        strategy::{BoxedStrategy, Strategy},
+    },
+    std::time::Duration,
+};
+
+/// Generates message content: any text, including empty text, up to 1024
+/// characters.
+pub fn content() -> impl Strategy<Value = String> {
+    prop_oneof![
+        // mostly plain prose, which is what agents usually exchange
+        3 => "[ -~\n]{0,256}",
+        1 => collection::vec(any::<char>(), 0..1024).prop_map(String::from_iter),
+    ]
+}
+
+/// Generates participant and agent names.
+pub fn name() -> impl Strategy<Value = String> {
+    "[a-z][a-z0-9_-]{0,15}"
+}
+
+/// Generates model names.
+pub fn model() -> impl Strategy<Value = String> {
+    "[a-z][a-z0-9.-]{0,23}"
+}
+
+/// Generates messages that reply to `sender`.
+pub fn message(sender: Sender<Box<Message>>) -> impl Strategy<Value = Message> {
+    content().prop_map(move |content| Message::new(sender.clone(), content))
+}
+
+/// Generates transcripts of up to `max_len` messages.
+pub fn transcript(max_len: usize) -> impl Strategy<Value = Vec<ChatMessage>> {
+    collection::vec(any::<ChatMessage>(), 0..=max_len)
+}
+
+/// Generates conversation histories of up to `max_len` messages.
+pub fn history(max_len: usize) -> impl Strategy<Value = Vec<HistoryMessage>> {
+    collection::vec(any::<HistoryMessage>(), 0..=max_len)
+}
+
+impl Arbitrary for Role {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with((): ()) -> Self::Strategy {
+        prop_oneof![
+            Just(Role::System),
+            Just(Role::User),
+            Just(Role::Assistant),
+            Just(Role::Tool),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for HistoryMessage {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with((): ()) -> Self::Strategy {
+        (any::<Role>(), content())
+            .prop_map(|(role, content)| HistoryMessage::new(role, content))
+            .boxed()
+    }
+}
+
+impl Arbitrary for ChatMessage {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with((): ()) -> Self::Strategy {
+        (name(), content(), proptest::option::of(content()))
+            .prop_map(|(name, content, thought)| ChatMessage {
+                name,
+                content,
+                thought,
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for TerminationReason {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with((): ()) -> Self::Strategy {
+        prop_oneof![
+            Just(TerminationReason::Completed),
+            any::<usize>().prop_map(TerminationReason::MaxTurns),
+            content().prop_map(TerminationReason::Keyword),
+            Just(TerminationReason::Budget),
+            Just(TerminationReason::Timeout),
+            Just(TerminationReason::HumanStop),
+            content().prop_map(TerminationReason::Error),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for ToolProgress {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with((): ()) -> Self::Strategy {
+        (name(), proptest::option::of(0..=100u8), content())
+            .prop_map(|(tool, percent, status)| ToolProgress {
+                tool,
+                percent,
+                status,
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for StreamEvent {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with((): ()) -> Self::Strategy {
+        prop_oneof![
+            content().prop_map(StreamEvent::Token),
+            content().prop_map(StreamEvent::Thought),
+            any::<ToolProgress>().prop_map(StreamEvent::Progress),
+            content().prop_map(StreamEvent::Complete),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for Backoff {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with((): ()) -> Self::Strategy {
+        (any::<Duration>(), any::<Duration>())
+            .prop_map(|(a, b)| Backoff {
+                initial: a.min(b),
+                max: a.max(b),
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for RestartStrategy {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with((): ()) -> Self::Strategy {
+        (
+            proptest::option::of(any::<Backoff>()),
+            proptest::option::of((0..100usize, any::<Duration>())),
+        )
+            .prop_map(|(backoff, max_restarts)| RestartStrategy {
+                backoff,
+                max_restarts,
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn test_history_round_trip(history in history(8)) {
+            let json = serde_json::to_string(&history).unwrap();
+            let parsed: Vec<HistoryMessage> = serde_json::from_str(&json).unwrap();
+            prop_assert_eq!(parsed, history);
+        }
+
+        #[test]
+        fn test_backoff_delay_within_bounds(backoff in any::<Backoff>(), restart in 0..64usize) {
+            let delay = backoff.delay(restart);
+            prop_assert!(delay <= backoff.max);
+            prop_assert!(delay >= backoff.initial.min(backoff.max));
+        }
+    }
+}
@@ -0,0 +1,363 @@
+//! Counting tokens the way each model does. Budgets, cost estimates and
+//! truncation all need to know how many tokens some text is, and models
+//! disagree: a [`TokenizerRegistry`] maps model names to the right
+//! [`Tokenizer`] and falls back to an estimate for models it doesn't know.
+//!
+//! OpenAI's encodings are available with the `tiktoken` feature, and
+//! Hugging Face tokenizers with the `hf-tokenizers` feature.
+
+use {
+    crate::llm::HistoryMessage,
+    std::{fmt::Debug, sync::Arc},
+};
+
+/// The tokens each message in a conversation costs on top of its content,
+/// for the role and separators.
+const MESSAGE_OVERHEAD: usize = 3;
+
+/// The tokens that prime the model's reply to a conversation.
+const REPLY_OVERHEAD: usize = 3;
+
+/// Splits text into a model's tokens.
+pub trait Tokenizer: Debug + Send + Sync + 'static {
+    /// Returns the number of tokens in `text`.
+    fn count(&self, text: &str) -> usize;
+
+    /// Returns the longest prefix of `text` that's at most `max_tokens`
+    /// tokens long.
+    ///
+    /// By default the prefix is found by counting prefixes of different
+    /// lengths; tokenizers that know where their tokens start should
+    /// override this.
+    fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> &'a str {
+        if self.count(text) <= max_tokens {
+            return text;
+        }
+        let ends = text
+            .char_indices()
+            .map(|(i, _)| i)
+            .skip(1)
+            .chain([text.len()])
+            .collect::<Vec<_>>();
+        let fits = ends.partition_point(|&end| self.count(&text[..end]) <= max_tokens);
+        &text[..fits.checked_sub(1).map_or(0, |i| ends[i])]
+    }
+}
+
+/// Estimates tokens from the number of characters, for models without a
+/// known tokenizer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Estimate {
+    /// How many characters a token is taken to be.
+    pub chars_per_token: usize,
+}
+
+impl Default for Estimate {
+    /// About right for English text with most tokenizers.
+    fn default() -> Self {
+        Self { chars_per_token: 4 }
+    }
+}
+
+impl Tokenizer for Estimate {
+    fn count(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(self.chars_per_token.max(1))
+    }
+
+    fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> &'a str {
+        let chars = max_tokens.saturating_mul(self.chars_per_token.max(1));
+        match text.char_indices().nth(chars) {
+            Some((end, _)) => &text[..end],
+            None => text,
+        }
+    }
+}
+
+/// Maps model names to tokenizers.
+///
+/// Tokenizers are registered for model name prefixes, e.g. `"gpt-4"`; the
+/// longest registered prefix of a model's name picks its tokenizer. Models
+/// that match no prefix are counted with the fallback, an [`Estimate`]
+/// unless set otherwise. With the `tiktoken` feature, the default registry
+/// knows OpenAI's models.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::tokenizer::{Estimate, TokenizerRegistry};
+/// let tokenizers =
+///     TokenizerRegistry::new().with_tokenizer("my-model", Estimate { chars_per_token: 3 });
+/// assert_eq!(tokenizers.count("my-model-v2", "hello world"), 4);
+/// assert_eq!(tokenizers.count("unknown", "hello world"), 3);
+/// ```
+#[derive(Debug, Clone)]
+pub struct TokenizerRegistry {
+    tokenizers: Vec<(String, Arc<dyn Tokenizer>)>,
+    fallback: Arc<dyn Tokenizer>,
+}
+
+impl Default for TokenizerRegistry {
+    fn default() -> Self {
+        #[cfg(feature = "tiktoken")]
+        {
+            Self::new().with_tiktoken()
+        }
+        #[cfg(not(feature = "tiktoken"))]
+        {
+            Self::new()
+        }
+    }
+}
+
+impl TokenizerRegistry {
+    /// Create a registry with no tokenizers, that estimates every model's
+    /// tokens.
+    pub fn new() -> Self {
+        Self {
+            tokenizers: Vec::new(),
+            fallback: Arc::new(Estimate::default()),
+        }
+    }
+
+    /// Count tokens of models whose name starts with `prefix` with
+    /// `tokenizer`. Replaces the tokenizer registered for the same prefix.
+    pub fn with_tokenizer(mut self, prefix: impl ToString, tokenizer: impl Tokenizer) -> Self {
+        self.insert(prefix.to_string(), Arc::new(tokenizer));
+        self
+    }
+
+    /// Count tokens of models that match no prefix with `tokenizer`.
+    pub fn with_fallback(mut self, tokenizer: impl Tokenizer) -> Self {
+        self.fallback = Arc::new(tokenizer);
+        self
+    }
+
+    /// Count tokens of OpenAI's models with their encodings.
+    #[cfg(feature = "tiktoken")]
+    pub fn with_tiktoken(mut self) -> Self {
+        use tiktoken_rs::tokenizer::Tokenizer::{Cl100kBase, O200kBase};
+
+        let o200k = Arc::new(Tiktoken::new(O200kBase));
+        let cl100k = Arc::new(Tiktoken::new(Cl100kBase));
+        for prefix in ["gpt-4o", "o1"] {
+            self.insert(prefix.to_string(), o200k.clone());
+        }
+        for prefix in [
+            "gpt-4",
+            "gpt-3.5-turbo",
+            "gpt-35-turbo",
+            "text-embedding-3",
+            "text-embedding-ada-002",
+        ] {
+            self.insert(prefix.to_string(), cl100k.clone());
+        }
+        self
+    }
+
+    fn insert(&mut self, prefix: String, tokenizer: Arc<dyn Tokenizer>) {
+        self.tokenizers
+            .retain(|(registered, _)| *registered != prefix);
+        self.tokenizers.push((prefix, tokenizer));
+    }
+
+    /// Returns the tokenizer for `model`.
+    pub fn get(&self, model: &str) -> Arc<dyn Tokenizer> {
+        self.tokenizers
+            .iter()
+            .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or_else(|| self.fallback.clone(), |(_, tokenizer)| tokenizer.clone())
+    }
+
+    /// Returns the number of tokens in `text` for `model`.
+    pub fn count(&self, model: &str, text: &str) -> usize {
+        self.get(model).count(text)
+    }
+
+    /// Returns the number of tokens in a message's content and tool calls for
+    /// `model`, without the message's overhead.
+    pub fn count_message(&self, model: &str, message: &HistoryMessage) -> usize {
+        count_message(&*self.get(model), message)
+    }
+
+    /// Returns the number of tokens a conversation costs `model`, including
+    /// each message's overhead and the tokens that prime the reply.
+    pub fn count_messages(&self, model: &str, messages: &[HistoryMessage]) -> usize {
+        let tokenizer = self.get(model);
+        let content = messages
+            .iter()
+            .map(|message| MESSAGE_OVERHEAD + count_message(&*tokenizer, message))
+            .sum::<usize>();
+        content + REPLY_OVERHEAD
+    }
+
+    /// Returns the longest prefix of `text` that's at most `max_tokens` of
+    /// `model`'s tokens long.
+    pub fn truncate<'a>(&self, model: &str, text: &'a str, max_tokens: usize) -> &'a str {
+        self.get(model).truncate(text, max_tokens)
+    }
+}
+
+/// Returns the number of tokens in a message's content and tool calls.
+fn count_message(tokenizer: &dyn Tokenizer, message: &HistoryMessage) -> usize {
+    let calls = message
+        .tool_calls
+        .iter()
+        .map(|call| {
+            tokenizer.count(&call.function.name) + tokenizer.count(&call.function.arguments)
+        })
+        .sum::<usize>();
+    tokenizer.count(&message.content) + calls
+}
+
+/// One of OpenAI's encodings. The encoding is loaded the first time it's
+/// used.
+#[cfg(feature = "tiktoken")]
+pub struct Tiktoken {
+    encoding: tiktoken_rs::tokenizer::Tokenizer,
+    bpe: std::sync::OnceLock<tiktoken_rs::CoreBPE>,
+}
+
+#[cfg(feature = "tiktoken")]
+impl Debug for Tiktoken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tiktoken")
+            .field("encoding", &self.encoding)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "tiktoken")]
+impl Tiktoken {
+    /// Create a tokenizer for `encoding`.
+    pub fn new(encoding: tiktoken_rs::tokenizer::Tokenizer) -> Self {
+        Self {
+            encoding,
+            bpe: Default::default(),
+        }
+    }
+
+    /// Create a tokenizer for the encoding `model` uses, if it's an OpenAI
+    /// model.
+    pub fn for_model(model: &str) -> Option<Self> {
+        tiktoken_rs::tokenizer::get_tokenizer(model).map(Self::new)
+    }
+
+    fn bpe(&self) -> &tiktoken_rs::CoreBPE {
+        use tiktoken_rs::tokenizer::Tokenizer::*;
+
+        self.bpe.get_or_init(|| {
+            match self.encoding {
+                O200kBase => tiktoken_rs::o200k_base(),
+                Cl100kBase => tiktoken_rs::cl100k_base(),
+                P50kBase => tiktoken_rs::p50k_base(),
+                P50kEdit => tiktoken_rs::p50k_edit(),
+                R50kBase | Gpt2 => tiktoken_rs::r50k_base(),
+            }
+            .expect("the encodings are bundled with tiktoken")
+        })
+    }
+}
+
+#[cfg(feature = "tiktoken")]
+impl Tokenizer for Tiktoken {
+    fn count(&self, text: &str) -> usize {
+        self.bpe().encode_with_special_tokens(text).len()
+    }
+}
+
+/// Counts tokens without the tokenizer's padding, if it's configured with
+/// any. Configure it without truncation, or counts stop at its limit.
+#[cfg(feature = "hf-tokenizers")]
+impl Tokenizer for tokenizers::Tokenizer {
+    fn count(&self, text: &str) -> usize {
+        match self.encode(text, false) {
+            Ok(encoding) => encoding
+                .get_attention_mask()
+                .iter()
+                .filter(|&&m| m == 1)
+                .count(),
+            Err(e) => {
+                tracing::warn!(error = %e, "unable to tokenize; estimating");
+                Estimate::default().count(text)
+            }
+        }
+    }
+
+    fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> &'a str {
+        match self.encode(text, false) {
+            Ok(_) if max_tokens == 0 => "",
+            Ok(encoding) => match encoding.get_offsets().get(max_tokens - 1) {
+                Some(&(_, end)) if max_tokens < self.count(text) => &text[..end],
+                _ => text,
+            },
+            Err(e) => {
+                tracing::warn!(error = %e, "unable to tokenize; estimating");
+                Estimate::default().truncate(text, max_tokens)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::llm::{FunctionCall, Role, ToolCall, ToolKind},
+    };
+
+    /// Counts words, so counts are easy to check.
+    #[derive(Debug)]
+    struct Words;
+
+    impl Tokenizer for Words {
+        fn count(&self, text: &str) -> usize {
+            text.split_whitespace().count()
+        }
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let tokenizers = TokenizerRegistry::new()
+            .with_tokenizer("gpt-4", Words)
+            .with_tokenizer("gpt-4o", Estimate { chars_per_token: 1 });
+        assert_eq!(tokenizers.count("gpt-4-turbo", "hello big world"), 3);
+        assert_eq!(tokenizers.count("gpt-4o-mini", "hello big world"), 15);
+        assert_eq!(tokenizers.count("llama", "hello big world"), 4);
+
+        let messages = [
+            HistoryMessage::new(Role::User, "what is one plus two"),
+            HistoryMessage {
+                tool_calls: vec![ToolCall {
+                    id: "call_0".to_string(),
+                    kind: ToolKind::Function,
+                    function: FunctionCall {
+                        name: "add".to_string(),
+                        arguments: "{}".to_string(),
+                    },
+                }],
+                ..HistoryMessage::new(Role::Assistant, "")
+            },
+        ];
+        assert_eq!(
+            tokenizers.count_messages("gpt-4", &messages),
+            7 + 2 * MESSAGE_OVERHEAD + REPLY_OVERHEAD
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(Words.truncate("one two three four", 2), "one two ");
+        assert_eq!(Words.truncate("one two", 2), "one two");
+        assert_eq!(Words.truncate("one", 0), "");
+        let estimate = Estimate { chars_per_token: 2 };
+        assert_eq!(estimate.truncate("héllo world", 2), "héll");
+    }
+
+    #[cfg(feature = "tiktoken")]
+    #[test]
+    fn test_tiktoken() {
+        let tokenizers = TokenizerRegistry::default();
+        assert_eq!(tokenizers.count("gpt-4-0613", "hello world"), 2);
+        assert_eq!(tokenizers.truncate("gpt-4o", "hello world", 1), "hello");
+    }
+}
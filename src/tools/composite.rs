@@ -0,0 +1,202 @@
+//! Tools made of other agents. A [`CompositeTool`] runs a pipeline of steps,
+//! e.g. a retriever agent followed by a summarizer agent, and offers the
+//! whole pipeline to the model as one tool, so higher-level capabilities
+//! don't need the model to orchestrate each step.
+
+use {
+    super::{Error, Tool, ToolFuture},
+    crate::agent::{channel, Message, Sender},
+    serde_json::{json, Value},
+    std::{fmt, future::Future, time::Duration},
+};
+
+/// A step of a [`CompositeTool`].
+enum Step {
+    /// Ask an agent, and continue with its reply.
+    Agent(Sender<Box<Message>>),
+
+    /// Call a function, and continue with what it returns.
+    Fn(Box<dyn Fn(String) -> ToolFuture<'static> + Send + Sync>),
+}
+
+impl fmt::Debug for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Agent(sender) => f.debug_tuple("Agent").field(&sender.target()).finish(),
+            Self::Fn(_) => f.write_str("Fn"),
+        }
+    }
+}
+
+/// A tool that runs its input through a pipeline of steps. Each step gets
+/// the previous step's output as text, and the last step's output is the
+/// tool's result.
+///
+/// By default the tool takes one string argument, `input`. Tools with other
+/// parameters start the pipeline with their arguments as JSON.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::{agent::assistant::AssistantBuilder, tools::composite::CompositeTool};
+/// # tokio_test::block_on(async {
+/// let researcher = AssistantBuilder::new().with_name("researcher").build();
+/// let summarizer = AssistantBuilder::new().with_name("summarizer").build();
+/// let research = CompositeTool::new("research", "Researches a topic and summarizes it.")
+///     .then_agent(researcher.sender())
+///     .then(|notes| async move { Ok(format!("Summarize in three bullets:\n{notes}")) })
+///     .then_agent(summarizer.sender());
+/// let assistant = AssistantBuilder::new().with_tool(research).build();
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct CompositeTool {
+    name: String,
+    description: String,
+    parameters: Value,
+    steps: Vec<Step>,
+    timeout: Option<Duration>,
+}
+
+impl CompositeTool {
+    /// Create a tool with no steps, which returns its input.
+    pub fn new(name: impl ToString, description: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": { "input": { "type": "string" } },
+                "required": ["input"],
+            }),
+            steps: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    /// Take arguments described by `parameters`, a JSON schema, instead of
+    /// one `input` string.
+    pub fn with_parameters(mut self, parameters: Value) -> Self {
+        self.parameters = parameters;
+        self
+    }
+
+    /// Fail a step if an agent doesn't reply within `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Ask the agent behind `sender`, and continue with its reply.
+    pub fn then_agent(mut self, sender: Sender<Box<Message>>) -> Self {
+        self.steps.push(Step::Agent(sender));
+        self
+    }
+
+    /// Call `function`, and continue with what it returns, e.g. to reshape
+    /// the text between agents or to run a nested chat.
+    pub fn then<F, R>(mut self, function: F) -> Self
+    where
+        F: Fn(String) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<String, Error>> + Send + 'static,
+    {
+        self.steps
+            .push(Step::Fn(Box::new(move |input| Box::pin(function(input)))));
+        self
+    }
+
+    /// Ask an agent for a reply to `input`.
+    async fn ask(&self, agent: &Sender<Box<Message>>, input: String) -> Result<String, Error> {
+        let name = agent
+            .target()
+            .map_or_else(|| "agent".to_string(), ToString::to_string);
+        let (sender, mut replies) = channel(None);
+        agent
+            .send(Box::new(Message::new(sender, input)))
+            .await
+            .map_err(|e| Error::Failed(format!("unable to reach {name}: {}", e.reason)))?;
+        let reply = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, replies.recv())
+                .await
+                .map_err(|_| Error::Failed(format!("{name} didn't reply within {timeout:?}")))?,
+            None => replies.recv().await,
+        };
+        reply
+            .map(|reply| reply.content.to_string())
+            .ok_or_else(|| Error::Failed(format!("{name} stopped without replying")))
+    }
+}
+
+impl Tool for CompositeTool {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn parameters(&self) -> Value {
+        self.parameters.clone()
+    }
+
+    fn call(&self, arguments: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let mut output = match &arguments["input"] {
+                Value::String(input) => input.clone(),
+                _ => arguments.to_string(),
+            };
+            for (i, step) in self.steps.iter().enumerate() {
+                tracing::trace!(tool = self.name, step = i, ?step, "running step");
+                output = match step {
+                    Step::Agent(agent) => self.ask(agent, output).await?,
+                    Step::Fn(function) => function(output).await?,
+                };
+            }
+            Ok(output)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::agent::{AgentBuilder, SendError},
+        anyhow::Result,
+    };
+
+    /// Spawn an agent that replies with `transform` of each message.
+    fn agent(transform: fn(&str) -> String) -> Sender<Box<Message>> {
+        let agent = AgentBuilder::<_, SendError<_>>::new().spawn(
+            move |sender, message: Box<Message>| async move {
+                let reply = transform(&message.content.to_string());
+                message
+                    .sender
+                    .send(Box::new(Message::new(sender, reply)))
+                    .await
+            },
+        );
+        agent.sender()
+    }
+
+    #[tokio::test]
+    async fn test_pipeline() -> Result<()> {
+        let tool = CompositeTool::new("shout_twice", "")
+            .then_agent(agent(str::to_uppercase))
+            .then(|text| async move { Ok(format!("{text} {text}")) })
+            .then_agent(agent(|text| format!("{text}!")));
+        assert_eq!(tool.call(json!({ "input": "hey" })).await?, "HEY HEY!");
+
+        let silent = AgentBuilder::<_, SendError<Box<Message>>>::new()
+            .with_name("silent")
+            .spawn(|_sender, _message: Box<Message>| async { Ok(()) });
+        let tool = CompositeTool::new("ask_silent", "")
+            .with_timeout(Duration::from_millis(10))
+            .then_agent(silent.sender());
+        assert!(matches!(
+            tool.call(json!({ "input": "hello?" })).await,
+            Err(Error::Failed(_))
+        ));
+        Ok(())
+    }
+}
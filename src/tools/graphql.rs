@@ -0,0 +1,594 @@
+//! Tools generated from GraphQL schemas. Each field of an API's query and
+//! mutation types becomes a [`Tool`]: the field's arguments are offered to
+//! the model as a JSON schema, and calls run the query with the arguments as
+//! variables.
+//!
+//! The schema is read by introspecting the API. What a call selects is
+//! derived from the field's type: its scalar fields, and those of nested
+//! objects down to a [depth](GraphQl::with_selection_depth). Fields that
+//! take arguments are left out of selections.
+
+use {
+    super::{openapi::Auth, schema, Error as ToolError, Tool, ToolFuture},
+    serde_json::{json, Map, Value},
+    std::collections::HashMap,
+};
+
+/// How deep nested objects are selected when no depth is configured.
+pub const DEFAULT_SELECTION_DEPTH: usize = 2;
+
+/// The most characters of a result returned to the model when no limit is
+/// configured.
+pub const DEFAULT_MAX_RESULT_CHARS: usize = 16 * 1024;
+
+/// How deep input objects are described in argument schemas, so recursive
+/// inputs terminate.
+const MAX_INPUT_DEPTH: usize = 8;
+
+/// The query that reads an API's schema.
+const INTROSPECTION_QUERY: &str = "query {
+  __schema {
+    queryType { name }
+    mutationType { name }
+    types {
+      kind name description
+      fields(includeDeprecated: false) {
+        name description
+        args { name description type { ...TypeRef } }
+        type { ...TypeRef }
+      }
+      inputFields { name description type { ...TypeRef } }
+      enumValues(includeDeprecated: false) { name }
+    }
+  }
+}
+fragment TypeRef on __Type {
+  kind name
+  ofType { kind name ofType { kind name ofType { kind name ofType { kind name } } } }
+}";
+
+/// Errors that can occur when reading a GraphQL schema.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("introspection request failed: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("introspection failed: {0}")]
+    Introspection(String),
+}
+
+/// A GraphQL API, ready to generate tools.
+///
+/// Usage:
+/// ```no_run
+/// # use autogen_rs::tools::{graphql::GraphQl, openapi::Auth, ToolRegistry};
+/// # tokio_test::block_on(async {
+/// let api = GraphQl::new("https://api.github.com/graphql")
+///     .with_auth(Auth::Bearer("token".to_string()))
+///     .with_max_result_chars(4000);
+/// let mut tools = ToolRegistry::new();
+/// for tool in api.tools().await? {
+///     tools.register_in("github", tool);
+/// }
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug, Clone)]
+pub struct GraphQl {
+    endpoint: String,
+    /// The introspected schema, if it was given.
+    schema: Option<Value>,
+    auth: Option<Auth>,
+    client: reqwest::Client,
+    selection_depth: usize,
+    max_result_chars: usize,
+}
+
+impl GraphQl {
+    /// Create an adapter for the API at `endpoint`.
+    pub fn new(endpoint: impl ToString) -> Self {
+        Self {
+            endpoint: endpoint.to_string(),
+            schema: None,
+            auth: None,
+            client: reqwest::Client::new(),
+            selection_depth: DEFAULT_SELECTION_DEPTH,
+            max_result_chars: DEFAULT_MAX_RESULT_CHARS,
+        }
+    }
+
+    /// Use `introspection`, the result of an introspection query, instead
+    /// of introspecting the API. Either the whole response or its `data`
+    /// will do.
+    pub fn with_schema(mut self, introspection: Value) -> Self {
+        self.schema = Some(introspection);
+        self
+    }
+
+    /// Authenticate requests with `auth`.
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Send requests with `client`, e.g. to set timeouts or a proxy.
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Select nested objects down to `depth` levels.
+    pub fn with_selection_depth(mut self, depth: usize) -> Self {
+        self.selection_depth = depth;
+        self
+    }
+
+    /// Cut results longer than `max_chars` characters, so large responses
+    /// don't fill the model's context.
+    pub fn with_max_result_chars(mut self, max_chars: usize) -> Self {
+        self.max_result_chars = max_chars;
+        self
+    }
+
+    /// Returns a tool for each field of the query and mutation types,
+    /// introspecting the API unless a schema was given.
+    pub async fn tools(&self) -> Result<Vec<GraphQlTool>, Error> {
+        let introspection = match &self.schema {
+            Some(schema) => schema.clone(),
+            None => self.introspect().await?,
+        };
+        let schema = ["/data/__schema", "/__schema"]
+            .into_iter()
+            .find_map(|pointer| introspection.pointer(pointer))
+            .ok_or_else(|| Error::Introspection("no __schema in the result".to_string()))?;
+        let types = schema["types"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|kind| Some((kind["name"].as_str()?, kind)))
+            .collect::<HashMap<_, _>>();
+        let types = Types(types);
+
+        let mut tools = Vec::new();
+        for operation in ["query", "mutation"] {
+            let Some(root) = schema[format!("{operation}Type")]["name"].as_str() else {
+                continue;
+            };
+            let fields = types.0.get(root).map(|root| &root["fields"]);
+            for field in fields.and_then(Value::as_array).into_iter().flatten() {
+                if let Some(tool) = self.tool(&types, operation, field) {
+                    tools.push(tool);
+                }
+            }
+        }
+        Ok(tools)
+    }
+
+    /// Runs the introspection query.
+    async fn introspect(&self) -> Result<Value, Error> {
+        let response = authorize(self.client.post(&self.endpoint), &self.auth)
+            .json(&json!({ "query": INTROSPECTION_QUERY }))
+            .send()
+            .await?;
+        let status = response.status();
+        let body = response.json::<Value>().await?;
+        if !status.is_success() || body["data"].is_null() {
+            return Err(Error::Introspection(format!("{status}: {body}")));
+        }
+        Ok(body)
+    }
+
+    /// Returns the tool for a field of the `operation` type.
+    fn tool(&self, types: &Types<'_>, operation: &str, field: &Value) -> Option<GraphQlTool> {
+        let name = field["name"].as_str()?;
+        let mut properties = Map::new();
+        let mut required = Vec::new();
+        let mut arguments = Vec::new();
+        for argument in field["args"].as_array().into_iter().flatten() {
+            let Some(argument_name) = argument["name"].as_str() else {
+                continue;
+            };
+            let mut schema = types.schema(&argument["type"], 0);
+            if let (Some(schema), Some(description)) =
+                (schema.as_object_mut(), argument["description"].as_str())
+            {
+                schema.insert("description".to_string(), json!(description));
+            }
+            properties.insert(argument_name.to_string(), schema);
+            if argument["type"]["kind"] == "NON_NULL" {
+                required.push(json!(argument_name));
+            }
+            arguments.push((argument_name.to_string(), render(&argument["type"])));
+        }
+
+        let description = match (operation, field["description"].as_str()) {
+            ("mutation", Some(description)) => format!("Mutation: {description}"),
+            ("mutation", None) => format!("Mutation: {name}"),
+            (_, description) => description.unwrap_or(name).to_string(),
+        };
+        Some(GraphQlTool {
+            name: name.to_string(),
+            description,
+            parameters: json!({
+                "type": "object",
+                "properties": properties,
+                "required": required,
+            }),
+            operation: operation.to_string(),
+            arguments,
+            selection: types.selection(&field["type"], self.selection_depth),
+            endpoint: self.endpoint.clone(),
+            auth: self.auth.clone(),
+            client: self.client.clone(),
+            max_result_chars: self.max_result_chars,
+        })
+    }
+}
+
+/// The schema's types, by name.
+struct Types<'a>(HashMap<&'a str, &'a Value>);
+
+impl Types<'_> {
+    /// Returns a JSON schema of values of `type_ref`.
+    fn schema(&self, type_ref: &Value, depth: usize) -> Value {
+        match type_ref["kind"].as_str() {
+            Some("NON_NULL") => return self.schema(&type_ref["ofType"], depth),
+            Some("LIST") => {
+                return json!({ "type": "array", "items": self.schema(&type_ref["ofType"], depth) })
+            }
+            _ => {}
+        }
+        let name = type_ref["name"].as_str().unwrap_or_default();
+        match name {
+            "Int" => return json!({ "type": "integer" }),
+            "Float" => return json!({ "type": "number" }),
+            "String" | "ID" => return json!({ "type": "string" }),
+            "Boolean" => return json!({ "type": "boolean" }),
+            _ => {}
+        }
+        let Some(kind) = self.0.get(name) else {
+            return json!({});
+        };
+        match kind["kind"].as_str() {
+            Some("ENUM") => {
+                let values = kind["enumValues"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|value| value["name"].as_str())
+                    .collect::<Vec<_>>();
+                json!({ "type": "string", "enum": values })
+            }
+            Some("INPUT_OBJECT") if depth < MAX_INPUT_DEPTH => {
+                let mut properties = Map::new();
+                let mut required = Vec::new();
+                for field in kind["inputFields"].as_array().into_iter().flatten() {
+                    let Some(name) = field["name"].as_str() else {
+                        continue;
+                    };
+                    properties.insert(name.to_string(), self.schema(&field["type"], depth + 1));
+                    if field["type"]["kind"] == "NON_NULL" {
+                        required.push(json!(name));
+                    }
+                }
+                json!({ "type": "object", "properties": properties, "required": required })
+            }
+            // custom scalars, and inputs nested too deep, take anything
+            _ => json!({}),
+        }
+    }
+
+    /// Returns the selection set for values of `type_ref`, or `None` for
+    /// scalars.
+    fn selection(&self, type_ref: &Value, depth: usize) -> Option<String> {
+        let kind = self.0.get(named(type_ref))?;
+        match kind["kind"].as_str() {
+            Some("OBJECT" | "INTERFACE") => {}
+            Some("UNION") => return Some("{ __typename }".to_string()),
+            _ => return None,
+        }
+        let mut fields = Vec::new();
+        for field in kind["fields"].as_array().into_iter().flatten() {
+            let takes_arguments = field["args"]
+                .as_array()
+                .is_some_and(|args| !args.is_empty());
+            let Some(name) = field["name"].as_str().filter(|_| !takes_arguments) else {
+                continue;
+            };
+            let is_leaf = self.0.get(named(&field["type"])).map_or(true, |kind| {
+                matches!(kind["kind"].as_str(), Some("SCALAR" | "ENUM"))
+            });
+            if is_leaf {
+                fields.push(name.to_string());
+            } else if depth > 0 {
+                if let Some(selection) = self.selection(&field["type"], depth - 1) {
+                    fields.push(format!("{name} {selection}"));
+                }
+            }
+        }
+        if fields.is_empty() {
+            // every selection needs a field
+            fields.push("__typename".to_string());
+        }
+        Some(format!("{{ {} }}", fields.join(" ")))
+    }
+}
+
+/// Returns the name of the type `type_ref` wraps in lists and non-nulls.
+fn named(type_ref: &Value) -> &str {
+    match type_ref["name"].as_str() {
+        Some(name) => name,
+        None if type_ref["ofType"].is_object() => named(&type_ref["ofType"]),
+        None => "",
+    }
+}
+
+/// Returns `type_ref` as written in GraphQL, e.g. `[ID!]!`.
+fn render(type_ref: &Value) -> String {
+    match type_ref["kind"].as_str() {
+        Some("NON_NULL") => format!("{}!", render(&type_ref["ofType"])),
+        Some("LIST") => format!("[{}]", render(&type_ref["ofType"])),
+        _ => type_ref["name"].as_str().unwrap_or_default().to_string(),
+    }
+}
+
+fn authorize(request: reqwest::RequestBuilder, auth: &Option<Auth>) -> reqwest::RequestBuilder {
+    match auth {
+        Some(Auth::Bearer(token)) => request.bearer_auth(token),
+        Some(Auth::Header { name, value }) => request.header(name, value),
+        Some(Auth::Query { name, value }) => request.query(&[(name, value)]),
+        None => request,
+    }
+}
+
+/// A query or mutation of a GraphQL API, callable as a tool. The result is
+/// the field's value as JSON, cut to the configured length.
+#[derive(Debug, Clone)]
+pub struct GraphQlTool {
+    name: String,
+    description: String,
+    parameters: Value,
+    /// `query` or `mutation`.
+    operation: String,
+    /// The field's arguments and their GraphQL types.
+    arguments: Vec<(String, String)>,
+    selection: Option<String>,
+    endpoint: String,
+    auth: Option<Auth>,
+    client: reqwest::Client,
+    max_result_chars: usize,
+}
+
+impl GraphQlTool {
+    /// Returns the document for a call with `arguments`. Only arguments
+    /// that were given are passed.
+    fn query(&self, arguments: &Value) -> String {
+        let given = self
+            .arguments
+            .iter()
+            .filter(|(name, _)| !arguments[name].is_null())
+            .collect::<Vec<_>>();
+        let (variables, passed) = if given.is_empty() {
+            (String::new(), String::new())
+        } else {
+            let variables = given
+                .iter()
+                .map(|(name, kind)| format!("${name}: {kind}"))
+                .collect::<Vec<_>>();
+            let passed = given
+                .iter()
+                .map(|(name, _)| format!("{name}: ${name}"))
+                .collect::<Vec<_>>();
+            (
+                format!("({})", variables.join(", ")),
+                format!("({})", passed.join(", ")),
+            )
+        };
+        let selection = self
+            .selection
+            .as_ref()
+            .map(|selection| format!(" {selection}"))
+            .unwrap_or_default();
+        format!(
+            "{}{variables} {{ {}{passed}{selection} }}",
+            self.operation, self.name
+        )
+    }
+}
+
+impl Tool for GraphQlTool {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn parameters(&self) -> Value {
+        self.parameters.clone()
+    }
+
+    fn call(&self, arguments: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let violations = schema::validate(&self.parameters, &arguments);
+            if !violations.is_empty() {
+                return Err(ToolError::Schema(violations));
+            }
+            let variables = self
+                .arguments
+                .iter()
+                .filter_map(|(name, _)| {
+                    let value = arguments.get(name).filter(|value| !value.is_null())?;
+                    Some((name.clone(), value.clone()))
+                })
+                .collect::<Map<_, _>>();
+            let request = json!({ "query": self.query(&arguments), "variables": variables });
+            let response = authorize(self.client.post(&self.endpoint), &self.auth)
+                .json(&request)
+                .send()
+                .await
+                .map_err(|e| ToolError::Failed(e.to_string()))?;
+            let status = response.status();
+            let body = response
+                .text()
+                .await
+                .map_err(|e| ToolError::Failed(e.to_string()))?;
+            if !status.is_success() {
+                return Err(ToolError::Failed(format!("{status}: {body}")));
+            }
+
+            let body = serde_json::from_str::<Value>(&body)
+                .map_err(|e| ToolError::Failed(format!("invalid response: {e}")))?;
+            let data = &body["data"][&self.name];
+            if data.is_null() {
+                if let Some(errors) = body["errors"].as_array().filter(|e| !e.is_empty()) {
+                    let messages = errors
+                        .iter()
+                        .map(|error| error["message"].as_str().unwrap_or("unknown error"))
+                        .collect::<Vec<_>>();
+                    return Err(ToolError::Failed(messages.join("; ")));
+                }
+            }
+            Ok(truncate(data.to_string(), self.max_result_chars))
+        })
+    }
+}
+
+/// Returns `result` cut to `max_chars` characters, noting if it was cut.
+fn truncate(result: String, max_chars: usize) -> String {
+    let Some((end, _)) = result.char_indices().nth(max_chars) else {
+        return result;
+    };
+    let total = result.chars().count();
+    format!(
+        "{}\n... (truncated, {total} characters in all)",
+        &result[..end]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::llm::openai::mock::read_request,
+        anyhow::Result,
+        tokio::{io::AsyncWriteExt, net::TcpListener},
+    };
+
+    fn named_type(kind: &str, name: &str) -> Value {
+        json!({ "kind": kind, "name": name, "ofType": null })
+    }
+
+    fn non_null(of: Value) -> Value {
+        json!({ "kind": "NON_NULL", "name": null, "ofType": of })
+    }
+
+    fn field(name: &str, args: Value, kind: Value) -> Value {
+        json!({ "name": name, "description": null, "args": args, "type": kind })
+    }
+
+    fn argument(name: &str, kind: Value) -> Value {
+        json!({ "name": name, "description": null, "type": kind })
+    }
+
+    /// Users with managers and friends, and a mutation to rename them.
+    fn introspection() -> Value {
+        let id = non_null(named_type("SCALAR", "ID"));
+        let user = named_type("OBJECT", "User");
+        json!({ "data": { "__schema": {
+            "queryType": { "name": "Query" },
+            "mutationType": { "name": "Mutation" },
+            "types": [
+                { "kind": "OBJECT", "name": "Query", "fields": [
+                    field("user", json!([argument("id", id.clone())]), user.clone()),
+                ] },
+                { "kind": "OBJECT", "name": "Mutation", "fields": [
+                    field("rename", json!([
+                        argument("id", id.clone()),
+                        argument("input", non_null(named_type("INPUT_OBJECT", "RenameInput"))),
+                    ]), user.clone()),
+                ] },
+                { "kind": "OBJECT", "name": "User", "fields": [
+                    field("id", json!([]), id),
+                    field("name", json!([]), named_type("SCALAR", "String")),
+                    field("role", json!([]), named_type("ENUM", "Role")),
+                    field("manager", json!([]), user.clone()),
+                    field("friends", json!([argument("first", named_type("SCALAR", "Int"))]),
+                        json!({ "kind": "LIST", "name": null, "ofType": user })),
+                ] },
+                { "kind": "INPUT_OBJECT", "name": "RenameInput", "inputFields": [
+                    argument("name", non_null(named_type("SCALAR", "String"))),
+                    argument("role", named_type("ENUM", "Role")),
+                ] },
+                { "kind": "ENUM", "name": "Role", "enumValues": [{ "name": "ADMIN" }, { "name": "MEMBER" }] },
+                { "kind": "SCALAR", "name": "ID" },
+                { "kind": "SCALAR", "name": "String" },
+            ],
+        } } })
+    }
+
+    #[tokio::test]
+    async fn test_tools() -> Result<()> {
+        let tools = GraphQl::new("http://localhost")
+            .with_schema(introspection())
+            .with_selection_depth(1)
+            .tools()
+            .await?;
+        let names = tools.iter().map(|tool| tool.name()).collect::<Vec<_>>();
+        assert_eq!(names, ["user", "rename"]);
+
+        // the manager's manager is too deep, and friends take arguments
+        assert_eq!(
+            tools[0].query(&json!({ "id": "1" })),
+            "query($id: ID!) { user(id: $id) { id name role manager { id name role } } }"
+        );
+        let parameters = tools[1].parameters();
+        assert_eq!(parameters["required"], json!(["id", "input"]));
+        assert_eq!(
+            parameters["properties"]["input"]["properties"]["role"]["enum"],
+            json!(["ADMIN", "MEMBER"])
+        );
+        assert!(matches!(
+            tools[1].call(json!({ "id": "1", "input": {} })).await,
+            Err(ToolError::Schema(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_call() -> Result<()> {
+        // answers every query with the query and its variables
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = format!("http://{}/graphql", listener.local_addr()?);
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut buffer = Vec::new();
+            while let Some((_, request)) = read_request(&mut stream, &mut buffer).await? {
+                let body = json!({ "data": { "user": request } }).to_string();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await?;
+            }
+            anyhow::Ok(())
+        });
+
+        let api = GraphQl::new(endpoint).with_schema(introspection());
+        let user = &api.tools().await?[0];
+        let arguments = json!({ "id": "7", "unknown": true });
+        let result = serde_json::from_str::<Value>(&user.call(arguments).await?)?;
+        assert_eq!(result["variables"], json!({ "id": "7" }));
+        assert!(result["query"]
+            .as_str()
+            .is_some_and(|query| query.starts_with("query($id: ID!) { user(id: $id) {")));
+
+        let api = api.with_max_result_chars(10);
+        let user = &api.tools().await?[0];
+        let result = user.call(json!({ "id": "7" })).await?;
+        assert!(result.starts_with(r#"{"query":""#));
+        assert!(result.contains("... (truncated"));
+        Ok(())
+    }
+}
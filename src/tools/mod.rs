@@ -0,0 +1,384 @@
+//! Tools an assistant can call. A [`Tool`] describes itself with a name, a
+//! description and a JSON schema of its arguments, which are offered to the
+//! model. When the model asks for a tool, the assistant looks it up in its
+//! [`ToolRegistry`], calls it and feeds the result back to the model.
+//!
+//! Tools can be grouped into namespaces, e.g. `"web"` or `"admin"`, so one
+//! registry can serve workflows that may use different tools:
+//! [`ToolRegistry::select`] returns the tools a workflow may use.
+
+use {
+    crate::llm::{FunctionDefinition, ToolCall, ToolDefinition, ToolKind},
+    schema::Violation,
+    serde_json::Value,
+    std::{collections::BTreeMap, fmt, future::Future, pin::Pin, sync::Arc},
+};
+
+pub mod composite;
+pub mod graphql;
+pub mod openapi;
+pub mod schema;
+
+/// A boxed future returned by [`Tool::call`].
+pub type ToolFuture<'a> = Pin<Box<dyn Future<Output = Result<String, Error>> + Send + 'a>>;
+
+/// Errors that can occur when calling a tool. They are reported to the model
+/// so it can correct itself.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("no tool named {0:?}")]
+    NotFound(String),
+
+    #[error("invalid arguments: {0}")]
+    InvalidArguments(String),
+
+    /// The arguments don't match the tool's schema. The violations are
+    /// shown to the model as JSON, so it can fix each one.
+    #[error("arguments don't match the schema: {}", serde_json::json!(.0))]
+    Schema(Vec<Violation>),
+
+    #[error("tool failed: {0}")]
+    Failed(String),
+}
+
+/// Something an assistant can do besides replying, e.g. search the web or
+/// run code.
+pub trait Tool: Send + Sync + 'static {
+    /// The name the model calls the tool by.
+    fn name(&self) -> &str;
+
+    /// What the tool does, for the model to decide when to call it.
+    fn description(&self) -> &str;
+
+    /// A JSON schema of the tool's arguments.
+    fn parameters(&self) -> Value;
+
+    /// Call the tool. Returns the result to show the model.
+    fn call(&self, arguments: Value) -> ToolFuture<'_>;
+
+    /// Returns the definition offered to the model.
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            kind: ToolKind::Function,
+            function: FunctionDefinition {
+                name: self.name().to_string(),
+                description: self.description().to_string(),
+                parameters: self.parameters(),
+            },
+        }
+    }
+}
+
+/// A tool backed by an async function.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::tools::{FnTool, ToolRegistry}, serde_json::json};
+/// let add = FnTool::new(
+///     "add",
+///     "Adds two numbers.",
+///     json!({
+///         "type": "object",
+///         "properties": { "a": { "type": "number" }, "b": { "type": "number" } },
+///         "required": ["a", "b"],
+///     }),
+///     |arguments| async move {
+///         let sum = arguments["a"].as_f64().unwrap_or_default()
+///             + arguments["b"].as_f64().unwrap_or_default();
+///         Ok(sum.to_string())
+///     },
+/// );
+/// let tools = ToolRegistry::new().with_tool(add);
+/// ```
+pub struct FnTool<F> {
+    name: String,
+    description: String,
+    parameters: Value,
+    function: F,
+}
+
+impl<F, R> FnTool<F>
+where
+    F: Fn(Value) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<String, Error>> + Send + 'static,
+{
+    /// Create a tool that calls `function` with the model's arguments.
+    pub fn new(
+        name: impl ToString,
+        description: impl ToString,
+        parameters: Value,
+        function: F,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters,
+            function,
+        }
+    }
+}
+
+impl<F> fmt::Debug for FnTool<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FnTool")
+            .field("name", &self.name)
+            .field("description", &self.description)
+            .field("parameters", &self.parameters)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F, R> Tool for FnTool<F>
+where
+    F: Fn(Value) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<String, Error>> + Send + 'static,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn parameters(&self) -> Value {
+        self.parameters.clone()
+    }
+
+    fn call(&self, arguments: Value) -> ToolFuture<'_> {
+        Box::pin((self.function)(arguments))
+    }
+}
+
+/// The tools available to an assistant, by name. Names are unique across
+/// namespaces.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::tools::{FnTool, ToolRegistry}, serde_json::json};
+/// let tool = |name: &str| FnTool::new(name, "", json!({}), |_| async { Ok(String::new()) });
+/// let tools = ToolRegistry::new()
+///     .with_tool(tool("now"))
+///     .with_tool_in("web", tool("search"))
+///     .with_tool_in("admin", tool("delete_user"));
+/// // tools without a namespace are always selected
+/// let support = tools.select(&["web"]);
+/// assert!(support.get("search").is_some() && support.get("now").is_some());
+/// assert!(support.get("delete_user").is_none());
+/// ```
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: BTreeMap<String, Arc<dyn Tool>>,
+
+    /// The namespace of each tool that has one.
+    namespaces: BTreeMap<String, String>,
+}
+
+impl ToolRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a tool, replacing any tool with the same name.
+    pub fn with_tool(mut self, tool: impl Tool) -> Self {
+        self.register(tool);
+        self
+    }
+
+    /// Add a tool in `namespace`, replacing any tool with the same name.
+    pub fn with_tool_in(mut self, namespace: impl ToString, tool: impl Tool) -> Self {
+        self.register_in(namespace, tool);
+        self
+    }
+
+    /// Add a tool. Returns the tool it replaced, if one had the same name.
+    pub fn register(&mut self, tool: impl Tool) -> Option<Arc<dyn Tool>> {
+        self.namespaces.remove(tool.name());
+        self.tools.insert(tool.name().to_string(), Arc::new(tool))
+    }
+
+    /// Add a tool in `namespace`. Returns the tool it replaced, if one had
+    /// the same name.
+    pub fn register_in(
+        &mut self,
+        namespace: impl ToString,
+        tool: impl Tool,
+    ) -> Option<Arc<dyn Tool>> {
+        let name = tool.name().to_string();
+        let replaced = self.register(tool);
+        self.namespaces.insert(name, namespace.to_string());
+        replaced
+    }
+
+    /// Returns the namespace of the tool named `name`, if it has one.
+    pub fn namespace(&self, name: &str) -> Option<&str> {
+        self.namespaces.get(name).map(String::as_str)
+    }
+
+    /// Returns every namespace, ordered by name.
+    pub fn namespaces(&self) -> Vec<&str> {
+        let namespaces = self
+            .namespaces
+            .values()
+            .map(String::as_str)
+            .collect::<std::collections::BTreeSet<_>>();
+        namespaces.into_iter().collect()
+    }
+
+    /// Returns the tools in `namespaces`, along with the tools that have no
+    /// namespace.
+    pub fn select<S: AsRef<str>>(&self, namespaces: &[S]) -> Self {
+        let selected = |name: &String| {
+            self.namespaces.get(name).map_or(true, |namespace| {
+                namespaces
+                    .iter()
+                    .any(|selected| selected.as_ref() == namespace)
+            })
+        };
+        Self {
+            tools: self
+                .tools
+                .iter()
+                .filter(|(name, _)| selected(name))
+                .map(|(name, tool)| (name.clone(), tool.clone()))
+                .collect(),
+            namespaces: self
+                .namespaces
+                .iter()
+                .filter(|(name, _)| selected(name))
+                .map(|(name, namespace)| (name.clone(), namespace.clone()))
+                .collect(),
+        }
+    }
+
+    /// Returns the tool named `name`.
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn Tool>> {
+        self.tools.get(name)
+    }
+
+    /// Returns true if there are no tools.
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// Returns the definitions of every tool, ordered by name.
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools.values().map(|tool| tool.definition()).collect()
+    }
+
+    /// Make a call the model asked for. The arguments are checked against
+    /// the tool's schema first; the tool isn't called if they don't match.
+    pub async fn call(&self, call: &ToolCall) -> Result<String, Error> {
+        let tool = self
+            .get(&call.function.name)
+            .ok_or_else(|| Error::NotFound(call.function.name.clone()))?;
+        // models sometimes send no arguments for tools that take none
+        let arguments = match call.function.arguments.trim() {
+            "" => Value::Object(Default::default()),
+            arguments => serde_json::from_str(arguments)
+                .map_err(|e| Error::InvalidArguments(e.to_string()))?,
+        };
+        let violations = schema::validate(&tool.parameters(), &arguments);
+        if !violations.is_empty() {
+            tracing::debug!(
+                tool = call.function.name,
+                ?violations,
+                "rejecting arguments"
+            );
+            return Err(Error::Schema(violations));
+        }
+        tracing::trace!(tool = call.function.name, %arguments, "calling tool");
+        tool.call(arguments).await
+    }
+}
+
+impl fmt::Debug for ToolRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.tools.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::llm::FunctionCall, anyhow::Result, serde_json::json};
+
+    fn call(name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: "call_0".to_string(),
+            kind: ToolKind::Function,
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registry_call() -> Result<()> {
+        let tools = ToolRegistry::new().with_tool(FnTool::new(
+            "shout",
+            "Shouts the text.",
+            json!({ "type": "object", "properties": { "text": { "type": "string" } } }),
+            |arguments| async move {
+                arguments["text"]
+                    .as_str()
+                    .map(str::to_uppercase)
+                    .ok_or_else(|| Error::InvalidArguments("text is required".to_string()))
+            },
+        ));
+
+        assert_eq!(tools.definitions()[0].function.name, "shout");
+        assert_eq!(tools.call(&call("shout", r#"{"text":"hi"}"#)).await?, "HI");
+        assert!(matches!(
+            tools.call(&call("shout", "")).await,
+            Err(Error::InvalidArguments(_))
+        ));
+        assert_eq!(
+            tools.call(&call("shout", r#"{"text":7}"#)).await,
+            Err(Error::Schema(vec![Violation {
+                path: "/text".to_string(),
+                message: "expected string, got number".to_string(),
+            }]))
+        );
+        assert!(matches!(
+            tools.call(&call("shout", "{")).await,
+            Err(Error::InvalidArguments(_))
+        ));
+        assert_eq!(
+            tools.call(&call("whisper", "{}")).await,
+            Err(Error::NotFound("whisper".to_string()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_select_namespaces() -> Result<()> {
+        let tool =
+            |name: &str| FnTool::new(name, "", json!({}), |_| async { Ok("done".to_string()) });
+        let mut tools = ToolRegistry::new()
+            .with_tool_in("files", tool("read"))
+            .with_tool_in("files", tool("write"))
+            .with_tool_in("shell", tool("run"));
+        assert_eq!(tools.namespaces(), ["files", "shell"]);
+
+        // re-registering moves a tool out of its namespace
+        tools.register(tool("read"));
+        let reader = tools.select(&["none"]);
+        assert_eq!(reader.call(&call("read", "{}")).await?, "done");
+        assert_eq!(
+            reader.call(&call("run", "{}")).await,
+            Err(Error::NotFound("run".to_string()))
+        );
+        let names = |tools: &ToolRegistry| {
+            tools
+                .definitions()
+                .into_iter()
+                .map(|definition| definition.function.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&tools.select(&["files"])), ["read", "write"]);
+        assert_eq!(tools.select(&["files"]).namespace("write"), Some("files"));
+        Ok(())
+    }
+}
@@ -0,0 +1,651 @@
+//! Tools generated from OpenAPI documents. Each operation of a REST API
+//! becomes a [`Tool`]: its path, query and header parameters and its JSON
+//! body are offered to the model as one schema, and calls are sent to the
+//! API with the configured authentication.
+//!
+//! Documents are read as JSON. Local `$ref`s, e.g. to
+//! `#/components/schemas`, are resolved. Parameters that would carry the
+//! configured credentials, e.g. an `Authorization` header, aren't offered to
+//! the model, so it can't replace them.
+
+use {
+    super::{Error as ToolError, Tool, ToolFuture},
+    reqwest::{Method, Url},
+    serde_json::{json, Map, Value},
+};
+
+/// How deep `$ref`s are followed, so recursive schemas terminate.
+const MAX_REF_DEPTH: usize = 8;
+
+/// The longest tool name models accept.
+const MAX_NAME_LEN: usize = 64;
+
+/// Errors that can occur when reading an OpenAPI document.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("invalid OpenAPI document: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("the document has no servers; set a base URL")]
+    NoServer,
+
+    #[error("invalid base URL {0:?}")]
+    BaseUrl(String),
+}
+
+/// How calls to the API are authenticated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Auth {
+    /// An `Authorization: Bearer <token>` header.
+    Bearer(String),
+
+    /// A header with a fixed value, e.g. `X-API-Key`.
+    Header { name: String, value: String },
+
+    /// A query parameter with a fixed value, e.g. `api_key`.
+    Query { name: String, value: String },
+}
+
+impl Auth {
+    /// Returns whether a parameter named `name` at `location` would carry
+    /// these credentials.
+    fn covers(&self, location: Location, name: &str) -> bool {
+        match (self, location) {
+            (Self::Bearer(_), Location::Header) => name.eq_ignore_ascii_case("authorization"),
+            (Self::Header { name: header, .. }, Location::Header) => {
+                name.eq_ignore_ascii_case(header)
+            }
+            (Self::Query { name: query, .. }, Location::Query) => name == query,
+            _ => false,
+        }
+    }
+}
+
+/// Where an operation's parameter goes in the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+    Path,
+    Query,
+    Header,
+}
+
+/// A parameter of an operation.
+#[derive(Debug, Clone)]
+struct Parameter {
+    name: String,
+    location: Location,
+}
+
+/// An OpenAPI document, ready to generate tools.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::tools::{openapi::{Auth, OpenApi}, ToolRegistry}, serde_json::json};
+/// let spec = json!({
+///     "openapi": "3.0.0",
+///     "servers": [{ "url": "https://api.example.com/v1" }],
+///     "paths": {
+///         "/pets/{id}": {
+///             "get": {
+///                 "operationId": "getPet",
+///                 "summary": "Returns a pet by id.",
+///                 "parameters": [
+///                     { "name": "id", "in": "path", "required": true, "schema": { "type": "integer" } }
+///                 ],
+///             }
+///         }
+///     }
+/// });
+/// let api = OpenApi::from_json(&spec.to_string())?.with_auth(Auth::Bearer("secret".to_string()));
+/// let mut tools = ToolRegistry::new();
+/// for tool in api.tools()? {
+///     tools.register_in("pets", tool);
+/// }
+/// assert!(tools.get("getPet").is_some());
+/// # anyhow::Ok(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct OpenApi {
+    spec: Value,
+    base_url: Option<String>,
+    auth: Option<Auth>,
+    client: reqwest::Client,
+}
+
+impl OpenApi {
+    /// Read an OpenAPI document in JSON.
+    pub fn from_json(spec: &str) -> Result<Self, Error> {
+        Ok(Self::new(serde_json::from_str(spec)?))
+    }
+
+    /// Use a parsed OpenAPI document.
+    pub fn new(spec: Value) -> Self {
+        Self {
+            spec,
+            base_url: None,
+            auth: None,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Send calls to `base_url` instead of the document's first server.
+    pub fn with_base_url(mut self, base_url: impl ToString) -> Self {
+        self.base_url = Some(base_url.to_string());
+        self
+    }
+
+    /// Authenticate calls with `auth`.
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Send calls with `client`, e.g. to set timeouts or a proxy.
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Returns a tool for each operation in the document, in the
+    /// document's order.
+    pub fn tools(&self) -> Result<Vec<OpenApiTool>, Error> {
+        let base_url = match &self.base_url {
+            Some(base_url) => base_url.clone(),
+            None => self.spec["servers"][0]["url"]
+                .as_str()
+                .ok_or(Error::NoServer)?
+                .to_string(),
+        };
+        let base_url = Url::parse(&base_url).map_err(|_| Error::BaseUrl(base_url))?;
+
+        let mut tools = Vec::new();
+        let paths = self.spec["paths"].as_object().into_iter().flatten();
+        for (path, item) in paths {
+            let item = self.resolve(item, 0);
+            // parameters shared by every operation on the path
+            let shared = item["parameters"].as_array().cloned().unwrap_or_default();
+            for (method, operation) in item.as_object().into_iter().flatten() {
+                // other keys are the path's summary, parameters and so on
+                let method = match method.as_str() {
+                    "get" => Method::GET,
+                    "put" => Method::PUT,
+                    "post" => Method::POST,
+                    "delete" => Method::DELETE,
+                    "patch" => Method::PATCH,
+                    _ => continue,
+                };
+                let operation = self.resolve(operation, 0);
+                tools.push(self.tool(&base_url, path, method, &operation, &shared));
+            }
+        }
+        Ok(tools)
+    }
+
+    /// Returns the tool for one operation.
+    fn tool(
+        &self,
+        base_url: &Url,
+        path: &str,
+        method: Method,
+        operation: &Value,
+        shared: &[Value],
+    ) -> OpenApiTool {
+        let name = match operation["operationId"].as_str() {
+            Some(id) => sanitize(id),
+            None => sanitize(&format!("{}_{path}", method.as_str().to_lowercase())),
+        };
+        let description = [&operation["summary"], &operation["description"]]
+            .into_iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        let mut properties = Map::new();
+        let mut required = Vec::new();
+        let mut parameters = Vec::<Parameter>::new();
+        let own = operation["parameters"].as_array().into_iter().flatten();
+        // an operation's parameters override the path's parameters of the same name
+        for parameter in own.chain(shared).map(|p| self.resolve(p, 0)) {
+            let (Some(name), Some(location)) = (parameter["name"].as_str(), location(&parameter))
+            else {
+                continue;
+            };
+            if parameters.iter().any(|p| p.name == name) {
+                continue;
+            }
+            // the model mustn't add or replace credentials
+            if self
+                .auth
+                .as_ref()
+                .is_some_and(|auth| auth.covers(location, name))
+            {
+                tracing::debug!(name, "skipping parameter that carries credentials");
+                continue;
+            }
+            let mut schema = self.resolve(&parameter["schema"], 0);
+            if schema.is_null() {
+                schema = json!({ "type": "string" });
+            }
+            if let (Some(schema), Some(description)) =
+                (schema.as_object_mut(), parameter["description"].as_str())
+            {
+                schema.insert("description".to_string(), json!(description));
+            }
+            properties.insert(name.to_string(), schema);
+            if location == Location::Path || parameter["required"] == true {
+                required.push(json!(name));
+            }
+            parameters.push(Parameter {
+                name: name.to_string(),
+                location,
+            });
+        }
+
+        let body = self.resolve(&operation["requestBody"], 0);
+        let has_body = match self.resolve(&body["content"]["application/json"]["schema"], 0) {
+            Value::Null => false,
+            schema => {
+                properties.insert("body".to_string(), schema);
+                if body["required"] == true {
+                    required.push(json!("body"));
+                }
+                true
+            }
+        };
+
+        OpenApiTool {
+            name,
+            description,
+            parameters: json!({
+                "type": "object",
+                "properties": properties,
+                "required": required,
+            }),
+            method,
+            base_url: base_url.clone(),
+            path: path.to_string(),
+            params: parameters,
+            has_body,
+            auth: self.auth.clone(),
+            client: self.client.clone(),
+        }
+    }
+
+    /// Returns `value` with its local `$ref`s replaced by what they point
+    /// to, up to [`MAX_REF_DEPTH`] deep.
+    fn resolve(&self, value: &Value, depth: usize) -> Value {
+        match value {
+            Value::Object(object) => {
+                if let Some(pointer) = object.get("$ref").and_then(Value::as_str) {
+                    let target = pointer
+                        .strip_prefix('#')
+                        .and_then(|pointer| self.spec.pointer(pointer));
+                    return match target {
+                        Some(target) if depth < MAX_REF_DEPTH => self.resolve(target, depth + 1),
+                        // leave what can't be resolved open
+                        _ => json!({}),
+                    };
+                }
+                Value::Object(
+                    object
+                        .iter()
+                        .map(|(key, value)| (key.clone(), self.resolve(value, depth)))
+                        .collect(),
+                )
+            }
+            Value::Array(items) => {
+                Value::Array(items.iter().map(|item| self.resolve(item, depth)).collect())
+            }
+            value => value.clone(),
+        }
+    }
+}
+
+fn location(parameter: &Value) -> Option<Location> {
+    match parameter["in"].as_str()? {
+        "path" => Some(Location::Path),
+        "query" => Some(Location::Query),
+        "header" => Some(Location::Header),
+        // cookies aren't supported
+        _ => None,
+    }
+}
+
+/// Returns `name` with characters models don't accept in tool names
+/// replaced, and cut to the length they accept.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .take(MAX_NAME_LEN)
+        .collect()
+}
+
+/// Returns `segment` of a path template with each `{name}` in it replaced by
+/// the argument of that name, e.g. `{id}.json` or `{a}-{b}`.
+fn fill(segment: &str, argument: impl Fn(&str) -> Option<String>) -> Result<String, ToolError> {
+    let mut filled = String::with_capacity(segment.len());
+    let mut rest = segment;
+    while let Some((before, after)) = rest.split_once('{') {
+        let Some((name, after)) = after.split_once('}') else {
+            break;
+        };
+        let value = argument(name).ok_or_else(|| {
+            ToolError::InvalidArguments(format!("missing path parameter {name:?}"))
+        })?;
+        filled.push_str(before);
+        filled.push_str(&value);
+        rest = after;
+    }
+    filled.push_str(rest);
+    Ok(filled)
+}
+
+/// An operation of a REST API, callable as a tool. The result is the
+/// response body; responses with an error status fail the call.
+#[derive(Debug, Clone)]
+pub struct OpenApiTool {
+    name: String,
+    description: String,
+    parameters: Value,
+    method: Method,
+    base_url: Url,
+    path: String,
+    params: Vec<Parameter>,
+    has_body: bool,
+    auth: Option<Auth>,
+    client: reqwest::Client,
+}
+
+impl OpenApiTool {
+    /// Returns the request for a call with `arguments`.
+    fn request(&self, arguments: &Value) -> Result<reqwest::RequestBuilder, ToolError> {
+        let argument = |name: &str| match &arguments[name] {
+            Value::Null => None,
+            Value::String(value) => Some(value.clone()),
+            value => Some(value.to_string()),
+        };
+
+        let mut url = self.base_url.clone();
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|()| ToolError::Failed(format!("invalid base URL {}", self.base_url)))?;
+            segments.pop_if_empty();
+            for segment in self.path.split('/').filter(|s| !s.is_empty()) {
+                // pushing a segment percent-encodes it, values included
+                segments.push(&fill(segment, argument)?);
+            }
+        }
+
+        let mut request = self.client.request(self.method.clone(), url);
+        for param in &self.params {
+            let Some(value) = argument(&param.name) else {
+                continue;
+            };
+            request = match param.location {
+                Location::Path => request,
+                Location::Query => request.query(&[(&param.name, value)]),
+                Location::Header => request.header(&param.name, value),
+            };
+        }
+        if self.has_body && !arguments["body"].is_null() {
+            request = request.json(&arguments["body"]);
+        }
+        Ok(match &self.auth {
+            Some(Auth::Bearer(token)) => request.bearer_auth(token),
+            Some(Auth::Header { name, value }) => request.header(name, value),
+            Some(Auth::Query { name, value }) => request.query(&[(name, value)]),
+            None => request,
+        })
+    }
+}
+
+impl Tool for OpenApiTool {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn parameters(&self) -> Value {
+        self.parameters.clone()
+    }
+
+    fn call(&self, arguments: Value) -> ToolFuture<'_> {
+        Box::pin(async move {
+            let response = self
+                .request(&arguments)?
+                .send()
+                .await
+                .map_err(|e| ToolError::Failed(e.to_string()))?;
+            let status = response.status();
+            let body = response
+                .text()
+                .await
+                .map_err(|e| ToolError::Failed(e.to_string()))?;
+            if !status.is_success() {
+                return Err(ToolError::Failed(format!("{status}: {body}")));
+            }
+            Ok(body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::llm::openai::mock::read_request,
+        anyhow::Result,
+        tokio::{io::AsyncWriteExt, net::TcpListener},
+    };
+
+    /// Starts a server that answers each request with its request line, its
+    /// authorization and `x-trace` headers, and its body.
+    async fn echo_request() -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut buffer = Vec::new();
+            while let Some((head, body)) = read_request(&mut stream, &mut buffer).await? {
+                let mut lines = head.lines();
+                let mut echo = vec![lines.next().unwrap_or_default().to_string()];
+                echo.extend(
+                    lines
+                        .filter(|line| {
+                            let line = line.to_lowercase();
+                            line.starts_with("authorization") || line.starts_with("x-trace")
+                        })
+                        .map(str::to_lowercase),
+                );
+                if !body.is_null() {
+                    echo.push(body.to_string());
+                }
+                let echo = echo.join("\n");
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{echo}",
+                    echo.len()
+                );
+                stream.write_all(response.as_bytes()).await?;
+            }
+            anyhow::Ok(())
+        });
+        Ok(format!("http://{address}/api"))
+    }
+
+    fn spec() -> Value {
+        json!({
+            "openapi": "3.0.0",
+            "paths": {
+                "/pets/{id}/notes": {
+                    "parameters": [{ "$ref": "#/components/parameters/Id" }],
+                    "post": {
+                        "operationId": "add note",
+                        "summary": "Adds a note to a pet.",
+                        "parameters": [
+                            { "name": "x-trace", "in": "header", "schema": { "type": "string" } },
+                            { "name": "notify", "in": "query", "schema": { "type": "boolean" } },
+                        ],
+                        "requestBody": {
+                            "required": true,
+                            "content": {
+                                "application/json": { "schema": { "$ref": "#/components/schemas/Note" } }
+                            },
+                        },
+                    },
+                },
+            },
+            "components": {
+                "parameters": {
+                    "Id": { "name": "id", "in": "path", "required": true, "schema": { "type": "string" } },
+                },
+                "schemas": {
+                    "Note": { "type": "object", "properties": { "text": { "type": "string" } } },
+                },
+            },
+        })
+    }
+
+    #[test]
+    fn test_schema_mapping() -> Result<()> {
+        let tools = OpenApi::new(spec())
+            .with_base_url("http://localhost")
+            .tools()?;
+        assert_eq!(tools.len(), 1);
+        assert_eq!(tools[0].name(), "add_note");
+        assert_eq!(tools[0].description(), "Adds a note to a pet.");
+        let parameters = tools[0].parameters();
+        assert_eq!(parameters["required"], json!(["id", "body"]));
+        assert_eq!(
+            parameters["properties"]["body"]["properties"]["text"]["type"],
+            "string"
+        );
+        assert!(matches!(OpenApi::new(spec()).tools(), Err(Error::NoServer)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_call() -> Result<()> {
+        let tools = OpenApi::new(spec())
+            .with_base_url(echo_request().await?)
+            .with_auth(Auth::Bearer("secret".to_string()))
+            .tools()?;
+        let reply = tools[0]
+            .call(json!({
+                "id": "rex/1",
+                "notify": true,
+                "x-trace": "abc",
+                "body": { "text": "good dog" },
+            }))
+            .await?;
+        assert_eq!(
+            reply,
+            "POST /api/pets/rex%2F1/notes?notify=true HTTP/1.1\n\
+             x-trace: abc\n\
+             authorization: bearer secret\n\
+             {\"text\":\"good dog\"}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_credentials_not_offered() -> Result<()> {
+        let spec = json!({
+            "openapi": "3.0.0",
+            "paths": {
+                "/me": {
+                    "get": {
+                        "operationId": "me",
+                        "parameters": [
+                            { "name": "Authorization", "in": "header" },
+                            { "name": "X-API-Key", "in": "header" },
+                            { "name": "api_key", "in": "query" },
+                            { "name": "x-trace", "in": "header" },
+                        ],
+                    },
+                },
+            },
+        });
+        let offered = |auth: Auth| -> Result<Vec<String>> {
+            let tools = OpenApi::new(spec.clone())
+                .with_base_url("http://localhost")
+                .with_auth(auth)
+                .tools()?;
+            let properties = tools[0].parameters()["properties"].clone();
+            Ok(properties
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(|(name, _)| name.clone())
+                .collect())
+        };
+        let secret = || "secret".to_string();
+        assert_eq!(
+            offered(Auth::Bearer(secret()))?,
+            ["X-API-Key", "api_key", "x-trace"]
+        );
+        let header = Auth::Header {
+            name: "x-api-key".to_string(),
+            value: secret(),
+        };
+        assert_eq!(
+            offered(header.clone())?,
+            ["Authorization", "api_key", "x-trace"]
+        );
+        let query = Auth::Query {
+            name: "api_key".to_string(),
+            value: secret(),
+        };
+        assert_eq!(offered(query)?, ["Authorization", "X-API-Key", "x-trace"]);
+
+        // arguments for them are ignored
+        let tools = OpenApi::new(spec)
+            .with_base_url("http://localhost")
+            .with_auth(header)
+            .tools()?;
+        let request = tools[0]
+            .request(&json!({ "X-API-Key": "stolen", "x-trace": "abc" }))?
+            .build()?;
+        let keys = request
+            .headers()
+            .get_all("x-api-key")
+            .iter()
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["secret"]);
+        assert_eq!(request.headers()["x-trace"], "abc");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_path_templates() -> Result<()> {
+        let spec = json!({
+            "openapi": "3.0.0",
+            "paths": {
+                "/files/{id}.json": { "get": { "operationId": "file" } },
+                "/v1/{a}-{b}/{c": { "get": { "operationId": "range" } },
+            },
+        });
+        let tools = OpenApi::new(spec)
+            .with_base_url(echo_request().await?)
+            .tools()?;
+        assert_eq!(
+            tools[0].call(json!({ "id": "a b/c" })).await?,
+            "GET /api/files/a%20b%2Fc.json HTTP/1.1"
+        );
+        assert_eq!(
+            tools[1].call(json!({ "a": 1, "b": "z?" })).await?,
+            "GET /api/v1/1-z%3F/%7Bc HTTP/1.1"
+        );
+        assert!(matches!(
+            tools[1].call(json!({ "a": 1 })).await,
+            Err(ToolError::InvalidArguments(_))
+        ));
+        Ok(())
+    }
+}
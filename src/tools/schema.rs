@@ -0,0 +1,325 @@
+//! Checking a model's tool arguments against the tool's JSON schema, so
+//! tools get the arguments they describe and the model learns what it got
+//! wrong instead of the tool failing on bad input.
+//!
+//! The common JSON schema keywords are checked: `type`, `enum`, `const`,
+//! numeric ranges, string and array lengths, `items`, `properties`,
+//! `required`, `additionalProperties`, `allOf`, `anyOf` and `oneOf`. Other
+//! keywords are ignored, so schemas that use them still validate what they
+//! can.
+
+use {
+    serde::Serialize,
+    serde_json::{Map, Value},
+};
+
+/// A way arguments don't match a schema.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Violation {
+    /// Where in the arguments the violation is, as a JSON pointer, e.g.
+    /// `/items/0`. Empty for the arguments as a whole.
+    pub path: String,
+
+    /// What's wrong.
+    pub message: String,
+}
+
+/// Check `value` against `schema`. Returns every violation found, or
+/// nothing if the value matches.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::tools::schema::validate, serde_json::json};
+/// let schema = json!({
+///     "type": "object",
+///     "properties": { "count": { "type": "integer", "minimum": 1 } },
+///     "required": ["count"],
+/// });
+/// assert!(validate(&schema, &json!({ "count": 3 })).is_empty());
+/// assert_eq!(validate(&schema, &json!({ "count": 0 }))[0].path, "/count");
+/// ```
+pub fn validate(schema: &Value, value: &Value) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    check(schema, value, &mut String::new(), &mut violations);
+    violations
+}
+
+fn check(schema: &Value, value: &Value, path: &mut String, violations: &mut Vec<Violation>) {
+    let schema = match schema {
+        Value::Object(schema) => schema,
+        Value::Bool(false) => {
+            return violate(violations, path, "no value is allowed here".to_string())
+        }
+        _ => return,
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let types = match expected {
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            Value::String(kind) => vec![kind.as_str()],
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|kind| is_type(value, kind)) {
+            return violate(
+                violations,
+                path,
+                format!("expected {}, got {}", types.join(" or "), type_name(value)),
+            );
+        }
+    }
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            violate(
+                violations,
+                path,
+                format!(
+                    "expected one of {}, got {value}",
+                    Value::Array(allowed.clone())
+                ),
+            );
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            violate(
+                violations,
+                path,
+                format!("expected {expected}, got {value}"),
+            );
+        }
+    }
+
+    match value {
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            let bound = |keyword| schema.get(keyword).and_then(Value::as_f64);
+            if let Some(minimum) = bound("minimum").filter(|&minimum| number < minimum) {
+                violate(
+                    violations,
+                    path,
+                    format!("must be at least {minimum}, got {number}"),
+                );
+            }
+            if let Some(maximum) = bound("maximum").filter(|&maximum| number > maximum) {
+                violate(
+                    violations,
+                    path,
+                    format!("must be at most {maximum}, got {number}"),
+                );
+            }
+            if let Some(minimum) = bound("exclusiveMinimum").filter(|&minimum| number <= minimum) {
+                violate(
+                    violations,
+                    path,
+                    format!("must be greater than {minimum}, got {number}"),
+                );
+            }
+            if let Some(maximum) = bound("exclusiveMaximum").filter(|&maximum| number >= maximum) {
+                violate(
+                    violations,
+                    path,
+                    format!("must be less than {maximum}, got {number}"),
+                );
+            }
+        }
+        Value::String(string) => {
+            let length = string.chars().count();
+            check_length(schema, "Length", length, "characters", violations, path);
+        }
+        Value::Array(items) => {
+            check_length(schema, "Items", items.len(), "items", violations, path);
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    nested(path, &i.to_string(), |path| {
+                        check(item_schema, item, path, violations)
+                    });
+                }
+            }
+        }
+        Value::Object(object) => check_object(schema, object, path, violations),
+        _ => {}
+    }
+
+    if let Some(Value::Array(schemas)) = schema.get("allOf") {
+        for schema in schemas {
+            check(schema, value, path, violations);
+        }
+    }
+    let matching = |schemas: &Vec<Value>| {
+        schemas
+            .iter()
+            .filter(|schema| validate(schema, value).is_empty())
+            .count()
+    };
+    if let Some(Value::Array(schemas)) = schema.get("anyOf") {
+        if matching(schemas) == 0 {
+            violate(
+                violations,
+                path,
+                "matches none of the allowed schemas".to_string(),
+            );
+        }
+    }
+    if let Some(Value::Array(schemas)) = schema.get("oneOf") {
+        match matching(schemas) {
+            1 => {}
+            0 => violate(
+                violations,
+                path,
+                "matches none of the allowed schemas".to_string(),
+            ),
+            _ => violate(
+                violations,
+                path,
+                "matches more than one of the allowed schemas".to_string(),
+            ),
+        }
+    }
+}
+
+fn check_object(
+    schema: &Map<String, Value>,
+    object: &Map<String, Value>,
+    path: &mut String,
+    violations: &mut Vec<Violation>,
+) {
+    if let Some(Value::Array(required)) = schema.get("required") {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !object.contains_key(name) {
+                violate(
+                    violations,
+                    path,
+                    format!("missing required property {name:?}"),
+                );
+            }
+        }
+    }
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for (name, value) in object {
+        let property = properties.and_then(|properties| properties.get(name));
+        match (property, schema.get("additionalProperties")) {
+            (Some(property), _) => {
+                nested(path, name, |path| check(property, value, path, violations))
+            }
+            (None, Some(Value::Bool(false))) => {
+                violate(violations, path, format!("unexpected property {name:?}"))
+            }
+            (None, Some(additional)) => nested(path, name, |path| {
+                check(additional, value, path, violations)
+            }),
+            (None, None) => {}
+        }
+    }
+}
+
+/// Check `minLength`/`maxLength` or `minItems`/`maxItems`.
+fn check_length(
+    schema: &Map<String, Value>,
+    keyword: &str,
+    length: usize,
+    unit: &str,
+    violations: &mut Vec<Violation>,
+    path: &str,
+) {
+    let bound = |prefix| {
+        schema
+            .get(&format!("{prefix}{keyword}"))
+            .and_then(Value::as_u64)
+    };
+    if let Some(minimum) = bound("min").filter(|&minimum| (length as u64) < minimum) {
+        violate(
+            violations,
+            path,
+            format!("must have at least {minimum} {unit}, got {length}"),
+        );
+    }
+    if let Some(maximum) = bound("max").filter(|&maximum| (length as u64) > maximum) {
+        violate(
+            violations,
+            path,
+            format!("must have at most {maximum} {unit}, got {length}"),
+        );
+    }
+}
+
+/// Run `f` with `segment` appended to the path.
+fn nested(path: &mut String, segment: &str, f: impl FnOnce(&mut String)) {
+    let len = path.len();
+    path.push('/');
+    // escape as JSON pointers do
+    path.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    f(path);
+    path.truncate(len);
+}
+
+fn violate(violations: &mut Vec<Violation>, path: &str, message: String) {
+    violations.push(Violation {
+        path: path.to_string(),
+        message,
+    });
+}
+
+fn is_type(value: &Value, kind: &str) -> bool {
+    match kind {
+        "integer" => value.as_f64().is_some_and(|number| number.fract() == 0.0),
+        kind => type_name(value) == kind,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, serde_json::json};
+
+    #[test]
+    fn test_validate() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "unit": { "enum": ["celsius", "fahrenheit"] },
+                "days": { "type": "integer", "minimum": 1, "maximum": 14 },
+                "cities": {
+                    "type": "array",
+                    "minItems": 1,
+                    "items": { "type": "string", "maxLength": 8 },
+                },
+            },
+            "required": ["cities"],
+            "additionalProperties": false,
+        });
+        assert_eq!(
+            validate(&schema, &json!({ "cities": ["Paris"], "days": 3 })),
+            []
+        );
+
+        let arguments = json!({
+            "unit": "kelvin",
+            "days": 1.5,
+            "cities": ["Paris", 7, "Llanfairpwll"],
+            "when": "now",
+        });
+        let paths = validate(&schema, &arguments)
+            .into_iter()
+            .map(|violation| violation.path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/cities/1", "/cities/2", "/days", "/unit", ""]);
+        assert_eq!(
+            validate(&schema, &json!({}))[0].message,
+            r#"missing required property "cities""#
+        );
+        assert_eq!(
+            validate(&schema, &json!([]))[0].message,
+            "expected object, got array"
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Cron expressions, for running workflows on a schedule. Expressions have
+//! the usual five fields: minute, hour, day of the month, month and day of
+//! the week. Each field is `*`, a number, a range like `1-5`, any of those
+//! with a step like `*/15`, or a comma-separated list of them. Times are in
+//! UTC.
+
+use {
+    std::{
+        str::FromStr,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+    thiserror::Error,
+};
+
+/// The number of days searched for the next matching time, enough to find
+/// e.g. the next February 29th.
+const SEARCH_DAYS: u64 = 8 * 366;
+
+/// A cron expression that can't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid cron expression {expression:?}: {reason}")]
+pub struct ParseError {
+    pub expression: String,
+    pub reason: String,
+}
+
+/// A parsed cron expression.
+///
+/// Usage:
+/// ```
+/// # use {autogen_rs::workflow::cron::Cron, std::time::{Duration, UNIX_EPOCH}};
+/// // at 09:30 on weekdays
+/// let cron: Cron = "30 9 * * 1-5".parse()?;
+/// // 1970-01-01 was a Thursday
+/// let next = cron.next_after(UNIX_EPOCH).unwrap();
+/// assert_eq!(next, UNIX_EPOCH + Duration::from_secs(9 * 3600 + 30 * 60));
+/// # anyhow::Ok(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+
+    /// Whether the day of the month is restricted. If both days are, a day
+    /// matching either runs.
+    restricts_day: bool,
+
+    /// Whether the day of the week is restricted.
+    restricts_weekday: bool,
+}
+
+impl Cron {
+    /// Returns the first time after `time` the expression matches, to the
+    /// minute, or `None` if it never does, e.g. on February 30th.
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let seconds = time.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let minute = seconds / 60 + 1;
+        let (first_day, mut start) = (minute / 1440, minute % 1440);
+        for day in first_day..first_day + SEARCH_DAYS {
+            if self.matches_day(day) {
+                let matched = (start..1440)
+                    .find(|m| self.hours & 1 << (m / 60) != 0 && self.minutes & 1 << (m % 60) != 0);
+                if let Some(m) = matched {
+                    return Some(UNIX_EPOCH + Duration::from_secs((day * 1440 + m) * 60));
+                }
+            }
+            start = 0;
+        }
+        None
+    }
+
+    /// Returns whether the expression matches `day`, counted from the epoch.
+    fn matches_day(&self, day: u64) -> bool {
+        let (month, day_of_month) = civil_from_days(day);
+        if self.months & 1 << month == 0 {
+            return false;
+        }
+        // the epoch was a Thursday, and Sunday is 0
+        let weekday = (day + 4) % 7;
+        let by_day = self.days & 1 << day_of_month != 0;
+        let by_weekday = self.weekdays & 1 << weekday != 0;
+        match (self.restricts_day, self.restricts_weekday) {
+            (true, true) => by_day || by_weekday,
+            _ => by_day && by_weekday,
+        }
+    }
+}
+
+impl FromStr for Cron {
+    type Err = ParseError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let error = |reason: String| ParseError {
+            expression: expression.to_string(),
+            reason,
+        };
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(error(format!("expected 5 fields, got {}", fields.len())));
+        };
+        let mut weekdays = parse_field(weekday, 0, 7).map_err(error)?;
+        // 7 is Sunday too
+        if weekdays & 1 << 7 != 0 {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59).map_err(error)?,
+            hours: parse_field(hour, 0, 23).map_err(error)?,
+            days: parse_field(day, 1, 31).map_err(error)?,
+            months: parse_field(month, 1, 12).map_err(error)?,
+            weekdays,
+            restricts_day: day != "*",
+            restricts_weekday: weekday != "*",
+        })
+    }
+}
+
+/// Returns the values a field matches, as bits, checking they're within
+/// `min..=max`.
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let number = |value: &str| {
+        value
+            .parse::<u64>()
+            .ok()
+            .filter(|n| (min..=max).contains(n))
+            .ok_or_else(|| format!("{value:?} isn't a number from {min} to {max}"))
+    };
+    let mut bits = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => match step.parse::<u64>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("{step:?} isn't a step")),
+            },
+            None => (item, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (number(start)?, number(end)?),
+            // a single value with a step runs to the end of the field
+            None if step > 1 => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if start > end {
+            return Err(format!("{range:?} is backwards"));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// Returns the month, from 1, and the day of the month of `days` after the
+/// epoch, in the proleptic Gregorian calendar.
+fn civil_from_days(days: u64) -> (u64, u64) {
+    // Howard Hinnant's algorithm, with eras starting on March 1st
+    let z = days + 719_468;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    (month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, anyhow::Result};
+
+    /// Returns the time `days`, `hours` and `minutes` after the epoch.
+    fn at(days: u64, hours: u64, minutes: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(((days * 24 + hours) * 60 + minutes) * 60)
+    }
+
+    #[test]
+    fn test_next_after() -> Result<()> {
+        let every_quarter: Cron = "*/15 * * * *".parse()?;
+        assert_eq!(every_quarter.next_after(at(0, 0, 0)), Some(at(0, 0, 15)));
+        assert_eq!(every_quarter.next_after(at(0, 0, 50)), Some(at(0, 1, 0)));
+
+        // 1970-01-01 was a Thursday, so the next Sunday is the 4th
+        let sundays: Cron = "0 12 * * 7".parse()?;
+        assert_eq!(sundays.next_after(at(0, 13, 0)), Some(at(3, 12, 0)));
+
+        // either the 1st of the month or a Monday
+        let either: Cron = "0 0 1 * 1".parse()?;
+        assert_eq!(either.next_after(at(0, 0, 0)), Some(at(4, 0, 0)));
+        assert_eq!(either.next_after(at(25, 0, 0)), Some(at(31, 0, 0)));
+
+        // 1972 was the first leap year after the epoch
+        let leap_days: Cron = "0 0 29 2 *".parse()?;
+        assert_eq!(leap_days.next_after(at(0, 0, 0)), Some(at(789, 0, 0)));
+
+        let never: Cron = "0 0 30 2 *".parse()?;
+        assert_eq!(never.next_after(at(0, 0, 0)), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for expression in ["* * * *", "60 * * * *", "* * * * */0", "5-1 * * * *"] {
+            assert!(expression.parse::<Cron>().is_err(), "{expression}");
+        }
+    }
+}
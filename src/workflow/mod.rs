@@ -0,0 +1,859 @@
+//! Running workflows as a service. A [`WorkflowRunner`] runs named
+//! workflows, each a conversation started from a task, on a cron schedule or
+//! as tasks are submitted to it. It caps how many run at once, retries runs
+//! that end in an error, and saves every run's outcome to a [`RunStore`].
+//! Urgent tasks go first, and can park [`Preemptible`] workflows already
+//! running until a slot frees up. Workflows described by a [`Template`] can
+//! be dry run, to review what a run would cost before starting it.
+
+use {
+    crate::{
+        agent::{Clock, Sleep, SystemClock},
+        chat::ChatOutcome,
+        cleanup::{CleanupReport, Resources},
+    },
+    cron::Cron,
+    queue::Queue,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        fmt::Debug,
+        future::Future,
+        io,
+        path::PathBuf,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant, SystemTime},
+    },
+    thiserror::Error,
+    tokio::task::{JoinHandle, JoinSet},
+    uuid::Uuid,
+};
+
+pub mod cron;
+mod plan;
+mod queue;
+
+pub use {
+    plan::{
+        AgentPlan, AgentSpec, Bounds, Plan, Template, Templated, DEFAULT_MAX_REPLY_TOKENS,
+        DEFAULT_MIN_REPLY_TOKENS, TOOL_DEFINITION_TOKENS,
+    },
+    queue::{Checkpoint, Priority},
+};
+
+/// The number of workflows run at once when no limit is configured.
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// The number of times a failed run is retried when none is configured.
+pub const DEFAULT_MAX_RETRIES: usize = 2;
+
+/// How long to wait before the first retry when no delay is configured.
+/// Each later retry waits that much longer.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Errors submitting a task to a runner.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Error {
+    #[error("no workflow named {0:?}")]
+    UnknownWorkflow(String),
+
+    #[error("the runner has stopped")]
+    Stopped,
+
+    #[error("workflow {0:?} has no template to dry run")]
+    NoTemplate(String),
+}
+
+/// A boxed future returned by [`Workflow::run`].
+pub type WorkflowFuture = Pin<Box<dyn Future<Output = ChatOutcome> + Send>>;
+
+/// A conversation run for a task. Implemented for closures that take the
+/// task and return a future of the chat's outcome.
+pub trait Workflow: Send + Sync + 'static {
+    /// Run the workflow for `task`.
+    fn run(&self, task: String) -> WorkflowFuture;
+
+    /// Run the workflow for `task`, parking at `checkpoint` when asked to.
+    /// Runs the workflow without checkpoints by default, so it's never
+    /// parked.
+    fn run_with(&self, task: String, checkpoint: Checkpoint) -> WorkflowFuture {
+        let _ = checkpoint;
+        self.run(task)
+    }
+
+    /// Returns the template describing the workflow, for dry runs. None by
+    /// default; see [`Template::workflow`].
+    fn template(&self) -> Option<&Template> {
+        None
+    }
+}
+
+impl<F, R> Workflow for F
+where
+    F: Fn(String) -> R + Send + Sync + 'static,
+    R: Future<Output = ChatOutcome> + Send + 'static,
+{
+    fn run(&self, task: String) -> WorkflowFuture {
+        Box::pin(self(task))
+    }
+}
+
+/// A workflow that can be parked for more urgent tasks. Wraps a closure
+/// that takes the task and a [`Checkpoint`], and checks it wherever the
+/// workflow can safely pause, e.g. between turns of its chat.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::{chat::{ChatOutcome, TerminationReason}, workflow::{Checkpoint, MemoryStore, Preemptible, Priority, WorkflowRunner}};
+/// # tokio_test::block_on(async {
+/// let runner = WorkflowRunner::new(MemoryStore::new())
+///     .with_workflow(
+///         "report",
+///         Preemptible(|task: String, checkpoint: Checkpoint| async move {
+///             for section in ["intro", "findings", "summary"] {
+///                 // write the section, then let urgent tasks go ahead
+///                 checkpoint.check().await;
+///             }
+///             ChatOutcome {
+///                 transcript: Vec::new(),
+///                 reason: TerminationReason::Completed,
+///                 variables: Default::default(),
+///             }
+///         }),
+///     )
+///     .with_concurrency(1)
+///     .start();
+/// runner.submit_with_priority("report", "Quarterly report", Priority::Low)?;
+/// runner.submit_with_priority("report", "Incident report", Priority::Urgent)?;
+/// runner.shutdown().await;
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Preemptible<F>(pub F);
+
+impl<F, R> Workflow for Preemptible<F>
+where
+    F: Fn(String, Checkpoint) -> R + Send + Sync + 'static,
+    R: Future<Output = ChatOutcome> + Send + 'static,
+{
+    fn run(&self, task: String) -> WorkflowFuture {
+        self.run_with(task, Checkpoint::default())
+    }
+
+    fn run_with(&self, task: String, checkpoint: Checkpoint) -> WorkflowFuture {
+        Box::pin((self.0)(task, checkpoint))
+    }
+}
+
+impl Debug for dyn Workflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Workflow")
+    }
+}
+
+/// A finished run of a workflow.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Run {
+    /// The run's id.
+    pub id: Uuid,
+
+    /// The name of the workflow that ran.
+    pub workflow: String,
+
+    /// The task it ran for.
+    pub task: String,
+
+    /// How urgent the task was.
+    #[serde(default)]
+    pub priority: Priority,
+
+    /// How many times the workflow ran, counting retries.
+    pub attempts: usize,
+
+    /// How many times the run was parked for more urgent tasks.
+    #[serde(default)]
+    pub parked: usize,
+
+    /// When the first attempt started.
+    pub started_at: SystemTime,
+
+    /// When the last attempt finished.
+    pub finished_at: SystemTime,
+
+    /// The outcome of the last attempt.
+    pub outcome: ChatOutcome,
+}
+
+/// Where finished runs are saved.
+pub trait RunStore: Debug + Send + Sync + 'static {
+    /// Save `run`.
+    fn save(&self, run: &Run) -> io::Result<()>;
+
+    /// Returns every saved run.
+    fn runs(&self) -> io::Result<Vec<Run>>;
+}
+
+/// Runs kept in memory, for as long as the store lives. Clones share the
+/// same runs.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    runs: Arc<Mutex<Vec<Run>>>,
+}
+
+impl MemoryStore {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl RunStore for MemoryStore {
+    fn save(&self, run: &Run) -> io::Result<()> {
+        self.runs.lock().unwrap().push(run.clone());
+        Ok(())
+    }
+
+    fn runs(&self) -> io::Result<Vec<Run>> {
+        Ok(self.runs.lock().unwrap().clone())
+    }
+}
+
+/// Runs kept as JSON files in a directory, one file per run.
+#[derive(Debug, Clone)]
+pub struct DirStore {
+    dir: PathBuf,
+}
+
+impl DirStore {
+    /// Create a store in `dir`, which is created when the first run is
+    /// saved.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl RunStore for DirStore {
+    fn save(&self, run: &Run) -> io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let json = serde_json::to_vec_pretty(run)?;
+        std::fs::write(self.dir.join(format!("{}.json", run.id)), json)
+    }
+
+    fn runs(&self) -> io::Result<Vec<Run>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut runs = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                runs.push(serde_json::from_slice(&std::fs::read(path)?)?);
+            }
+        }
+        runs.sort_by_key(|run: &Run| run.started_at);
+        Ok(runs)
+    }
+}
+
+/// A task waiting to run.
+#[derive(Debug)]
+struct Task {
+    workflow: String,
+    task: String,
+}
+
+/// How failed runs are retried.
+#[derive(Debug, Clone, Copy)]
+struct Retries {
+    max: usize,
+    delay: Duration,
+}
+
+/// Runs workflows on a schedule and from a queue of tasks.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::{chat::{ChatOutcome, TerminationReason}, workflow::{MemoryStore, RunStore, WorkflowRunner}};
+/// # tokio_test::block_on(async {
+/// let store = MemoryStore::new();
+/// let runner = WorkflowRunner::new(store.clone())
+///     .with_workflow("summarize", |task: String| async move {
+///         // start a chat for the task, and return its outcome
+///         ChatOutcome {
+///             transcript: Vec::new(),
+///             reason: TerminationReason::Completed,
+///             variables: Default::default(),
+///         }
+///     })
+///     .with_schedule("summarize", "0 9 * * 1-5".parse()?, "Summarize yesterday's tickets.")
+///     .with_concurrency(2)
+///     .start();
+///
+/// runner.submit("summarize", "Summarize the release notes.")?;
+/// runner.shutdown().await;
+/// assert_eq!(store.runs()?.len(), 1);
+/// # anyhow::Ok(())
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct WorkflowRunner {
+    workflows: HashMap<String, Arc<dyn Workflow>>,
+    schedules: Vec<(String, Cron, String)>,
+    store: Arc<dyn RunStore>,
+    concurrency: usize,
+    retries: Retries,
+    resources: Option<Resources>,
+    clock: Arc<dyn Clock>,
+}
+
+impl WorkflowRunner {
+    /// Create a runner that saves runs to `store`.
+    pub fn new(store: impl RunStore) -> Self {
+        Self {
+            workflows: HashMap::new(),
+            schedules: Vec::new(),
+            store: Arc::new(store),
+            concurrency: DEFAULT_CONCURRENCY,
+            retries: Retries {
+                max: DEFAULT_MAX_RETRIES,
+                delay: DEFAULT_RETRY_DELAY,
+            },
+            resources: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Add a workflow under `name`, replacing any with the same name.
+    pub fn with_workflow(mut self, name: impl ToString, workflow: impl Workflow) -> Self {
+        self.workflows.insert(name.to_string(), Arc::new(workflow));
+        self
+    }
+
+    /// Run the workflow named `workflow` for `task`, at normal priority,
+    /// whenever `cron` matches.
+    pub fn with_schedule(
+        mut self,
+        workflow: impl ToString,
+        cron: Cron,
+        task: impl ToString,
+    ) -> Self {
+        self.schedules
+            .push((workflow.to_string(), cron, task.to_string()));
+        self
+    }
+
+    /// Run at most `concurrency` workflows at once. Other tasks wait their
+    /// turn, most urgent first.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Retry a run that ends in an error up to `max_retries` times.
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.retries.max = max_retries;
+        self
+    }
+
+    /// Wait `delay` before the first retry, and that much longer before
+    /// each later one.
+    pub fn with_retry_delay(mut self, delay: Duration) -> Self {
+        self.retries.delay = delay;
+        self
+    }
+
+    /// Clean up `resources`, e.g. what the workflows' agents started, when
+    /// the runner is aborted with [`RunnerHandle::abort`].
+    pub fn with_resources(mut self, resources: Resources) -> Self {
+        self.resources = Some(resources);
+        self
+    }
+
+    /// Wait for schedules and retries on `clock` instead of the system
+    /// clock, e.g. a [`ManualClock`](crate::agent::ManualClock) in tests.
+    /// Cron schedules and runs' times follow it from when the runner starts.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Returns the plan of a run of the workflow named `workflow` for
+    /// `task`, without running it. See [`Template`].
+    pub fn dry_run(&self, workflow: impl ToString, task: &str) -> Result<Plan, Error> {
+        dry_run(&self.workflows, workflow.to_string(), task)
+    }
+
+    /// Start running scheduled tasks and submitted ones.
+    pub fn start(self) -> RunnerHandle {
+        let queue = Queue::new(self.concurrency);
+        let workflows = Arc::new(self.workflows);
+        let clock = WallClock::new(self.clock);
+        let schedules = self
+            .schedules
+            .into_iter()
+            .map(|(workflow, cron, task)| {
+                let (queue, clock) = (queue.clone(), clock.clone());
+                tokio::spawn(async move {
+                    while let Some(next) = cron.next_after(clock.now()) {
+                        let wait = next.duration_since(clock.now()).unwrap_or_default();
+                        clock.sleep(wait).await;
+                        let task = Task {
+                            workflow: workflow.clone(),
+                            task: task.clone(),
+                        };
+                        if queue.push(task, Priority::Normal).is_err() {
+                            return;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let (store, retries) = (self.store, self.retries);
+        let dispatcher = {
+            let (workflows, queue) = (workflows.clone(), queue.clone());
+            tokio::spawn(async move {
+                let mut running = JoinSet::new();
+                loop {
+                    tokio::select! {
+                        next = queue.next() => {
+                            let Some((task, priority, checkpoint)) = next else { break };
+                            let Some(workflow) = workflows.get(&task.workflow).cloned() else {
+                                tracing::warn!(workflow = task.workflow, "no such workflow; dropping task");
+                                continue;
+                            };
+                            let (store, clock) = (store.clone(), clock.clone());
+                            running.spawn(async move {
+                                let run = execute(workflow, task, priority, checkpoint, retries, clock).await;
+                                if let Err(e) = store.save(&run) {
+                                    tracing::warn!(id = %run.id, workflow = run.workflow, error = %e, "unable to save run");
+                                }
+                            });
+                        }
+                        // reap finished runs as we go
+                        Some(result) = running.join_next() => report(result),
+                    }
+                }
+                while let Some(result) = running.join_next().await {
+                    report(result);
+                }
+            })
+        };
+        RunnerHandle {
+            queue,
+            workflows,
+            schedules,
+            dispatcher,
+            resources: self.resources,
+        }
+    }
+}
+
+/// Logs a run that panicked.
+fn report(result: Result<(), tokio::task::JoinError>) {
+    if let Err(e) = result {
+        tracing::error!(error = %e, "workflow run panicked");
+    }
+}
+
+/// Wall-clock time on a [`Clock`], which only measures time elapsed:
+/// the system time when it was created, moved on by the clock since.
+#[derive(Debug, Clone)]
+struct WallClock {
+    clock: Arc<dyn Clock>,
+    started: (SystemTime, Instant),
+}
+
+impl WallClock {
+    fn new(clock: Arc<dyn Clock>) -> Self {
+        let started = (SystemTime::now(), clock.now());
+        Self { clock, started }
+    }
+
+    fn now(&self) -> SystemTime {
+        let (time, instant) = self.started;
+        time + self.clock.now().saturating_duration_since(instant)
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        self.clock.sleep(duration)
+    }
+}
+
+/// Runs `workflow` for `task`, retrying while it ends in an error. The run
+/// holds its slot until `checkpoint` is dropped.
+async fn execute(
+    workflow: Arc<dyn Workflow>,
+    task: Task,
+    priority: Priority,
+    checkpoint: Checkpoint,
+    retries: Retries,
+    clock: WallClock,
+) -> Run {
+    let started_at = clock.now();
+    let mut attempts = 0;
+    let outcome = loop {
+        attempts += 1;
+        let outcome = workflow
+            .run_with(task.task.clone(), checkpoint.clone())
+            .await;
+        if !outcome.reason.is_error() || attempts > retries.max {
+            break outcome;
+        }
+        tracing::warn!(workflow = task.workflow, attempts, reason = %outcome.reason, "workflow failed; retrying");
+        clock.sleep(retries.delay * attempts as u32).await;
+    };
+    Run {
+        id: Uuid::new_v4(),
+        workflow: task.workflow,
+        task: task.task,
+        priority,
+        attempts,
+        parked: checkpoint.parked(),
+        started_at,
+        finished_at: clock.now(),
+        outcome,
+    }
+}
+
+/// Returns the plan of a run of the workflow named `workflow` in
+/// `workflows` for `task`.
+fn dry_run(
+    workflows: &HashMap<String, Arc<dyn Workflow>>,
+    workflow: String,
+    task: &str,
+) -> Result<Plan, Error> {
+    let template = workflows
+        .get(&workflow)
+        .ok_or_else(|| Error::UnknownWorkflow(workflow.clone()))?
+        .template()
+        .ok_or(Error::NoTemplate(workflow))?;
+    Ok(template.dry_run(task))
+}
+
+/// A handle to a running [`WorkflowRunner`].
+#[derive(Debug)]
+pub struct RunnerHandle {
+    queue: Arc<Queue>,
+    workflows: Arc<HashMap<String, Arc<dyn Workflow>>>,
+    schedules: Vec<JoinHandle<()>>,
+    dispatcher: JoinHandle<()>,
+    resources: Option<Resources>,
+}
+
+impl RunnerHandle {
+    /// Queue a run of the workflow named `workflow` for `task`, at normal
+    /// priority.
+    pub fn submit(&self, workflow: impl ToString, task: impl ToString) -> Result<(), Error> {
+        self.submit_with_priority(workflow, task, Priority::Normal)
+    }
+
+    /// Queue a run of the workflow named `workflow` for `task`, ahead of
+    /// less urgent tasks.
+    pub fn submit_with_priority(
+        &self,
+        workflow: impl ToString,
+        task: impl ToString,
+        priority: Priority,
+    ) -> Result<(), Error> {
+        let workflow = workflow.to_string();
+        if !self.workflows.contains_key(&workflow) {
+            return Err(Error::UnknownWorkflow(workflow));
+        }
+        let task = Task {
+            workflow,
+            task: task.to_string(),
+        };
+        self.queue.push(task, priority)
+    }
+
+    /// Returns the plan of a run of the workflow named `workflow` for
+    /// `task`, without running it. See [`Template`].
+    pub fn dry_run(&self, workflow: impl ToString, task: &str) -> Result<Plan, Error> {
+        dry_run(&self.workflows, workflow.to_string(), task)
+    }
+
+    /// Stop the schedules and wait for queued and running tasks to finish.
+    pub async fn shutdown(self) {
+        for schedule in &self.schedules {
+            schedule.abort();
+        }
+        drop(self.schedules);
+        self.queue.close();
+        if let Err(e) = self.dispatcher.await {
+            tracing::error!(error = %e, "workflow runner panicked");
+        }
+    }
+
+    /// Stop the schedules, drop queued tasks and abort running ones without
+    /// saving them, then clean up what the runs started. Returns what was
+    /// cleaned up.
+    pub async fn abort(self) -> CleanupReport {
+        for schedule in &self.schedules {
+            schedule.abort();
+        }
+        self.queue.close();
+        // aborted runs stop tracking their resources, so clean up first
+        let report = match &self.resources {
+            Some(resources) => resources.cancel().await,
+            None => CleanupReport::default(),
+        };
+        // the dispatcher aborts the runs it holds as it's dropped
+        self.dispatcher.abort();
+        let _ = self.dispatcher.await;
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            chat::{ChatMessage, TerminationReason},
+            agent::ManualClock,
+            cleanup::{Resource, ResourceKind},
+        },
+        anyhow::Result,
+        std::sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Returns an outcome with a single message and `reason`.
+    fn outcome(content: &str, reason: TerminationReason) -> ChatOutcome {
+        ChatOutcome {
+            transcript: vec![ChatMessage {
+                name: "worker".to_string(),
+                content: content.to_string(),
+                thought: None,
+            }],
+            reason,
+            variables: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_runner() -> Result<()> {
+        let store = MemoryStore::new();
+        let (running, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let work = {
+            let (running, peak) = (running.clone(), peak.clone());
+            move |task: String| {
+                let (running, peak) = (running.clone(), peak.clone());
+                async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    outcome(&task.to_uppercase(), TerminationReason::Completed)
+                }
+            }
+        };
+        let flaky = {
+            let calls = calls.clone();
+            move |task: String| {
+                let calls = calls.clone();
+                async move {
+                    match calls.fetch_add(1, Ordering::SeqCst) {
+                        0 => outcome("", TerminationReason::Error("rate limited".to_string())),
+                        _ => outcome(&task, TerminationReason::Completed),
+                    }
+                }
+            }
+        };
+        let runner = WorkflowRunner::new(store.clone())
+            .with_workflow("work", work)
+            .with_workflow("flaky", flaky)
+            .with_concurrency(2)
+            .with_retry_delay(Duration::ZERO)
+            .start();
+
+        for task in ["a", "b", "c", "d"] {
+            runner.submit("work", task)?;
+        }
+        runner.submit("flaky", "retry me")?;
+        assert_eq!(
+            runner.submit("missing", "x"),
+            Err(Error::UnknownWorkflow("missing".to_string()))
+        );
+        runner.shutdown().await;
+
+        let runs = store.runs()?;
+        assert_eq!(runs.len(), 5);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        let flaky = runs.iter().find(|run| run.workflow == "flaky").unwrap();
+        assert_eq!(flaky.attempts, 2);
+        assert_eq!(flaky.outcome.transcript[0].content, "retry me");
+        let mut results = runs
+            .iter()
+            .filter(|run| run.workflow == "work")
+            .map(|run| run.outcome.transcript[0].content.as_str())
+            .collect::<Vec<_>>();
+        results.sort();
+        assert_eq!(results, ["A", "B", "C", "D"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_preemption() -> Result<()> {
+        let store = MemoryStore::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (started, mut has_started) = tokio::sync::mpsc::unbounded_channel();
+        let steps = {
+            let log = log.clone();
+            Preemptible(move |task: String, checkpoint: Checkpoint| {
+                let (log, started) = (log.clone(), started.clone());
+                async move {
+                    let _ = started.send(());
+                    for step in 0..3 {
+                        log.lock().unwrap().push(format!("{task} {step}"));
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        checkpoint.check().await;
+                    }
+                    outcome(&task, TerminationReason::Completed)
+                }
+            })
+        };
+        let runner = WorkflowRunner::new(store.clone())
+            .with_workflow("steps", steps)
+            .with_concurrency(1)
+            .start();
+
+        runner.submit_with_priority("steps", "slow", Priority::Low)?;
+        has_started.recv().await;
+        runner.submit("steps", "normal")?;
+        runner.submit_with_priority("steps", "urgent", Priority::Urgent)?;
+        runner.shutdown().await;
+
+        // the slow task is parked at its first checkpoint, and waits for
+        // the tasks that outrank it
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "slow 0", "urgent 0", "urgent 1", "urgent 2", "normal 0", "normal 1", "normal 2",
+                "slow 1", "slow 2"
+            ]
+        );
+        let runs = store.runs()?;
+        let slow = runs.iter().find(|run| run.task == "slow").unwrap();
+        assert_eq!((slow.priority, slow.parked), (Priority::Low, 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clock() -> Result<()> {
+        let store = MemoryStore::new();
+        let clock = ManualClock::new();
+        let (ran, mut runs) = tokio::sync::mpsc::unbounded_channel();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let flaky = {
+            let calls = calls.clone();
+            move |task: String| {
+                let (calls, ran) = (calls.clone(), ran.clone());
+                async move {
+                    let _ = ran.send(task.clone());
+                    match calls.fetch_add(1, Ordering::SeqCst) {
+                        0 => outcome("", TerminationReason::Error("rate limited".to_string())),
+                        _ => outcome(&task, TerminationReason::Completed),
+                    }
+                }
+            }
+        };
+        let runner = WorkflowRunner::new(store.clone())
+            .with_workflow("flaky", flaky)
+            .with_schedule("flaky", "* * * * *".parse()?, "tick")
+            .with_retry_delay(Duration::from_secs(3600))
+            .with_clock(clock.clone())
+            .start();
+        let settle = || async {
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+        };
+
+        // the schedule waits for the clock to reach the next minute
+        settle().await;
+        assert!(runs.try_recv().is_err());
+        clock.advance(Duration::from_secs(60));
+        settle().await;
+        assert_eq!(runs.try_recv()?, "tick");
+
+        // and the retry for the clock to pass the delay
+        assert!(runs.try_recv().is_err());
+        clock.advance(Duration::from_secs(3600));
+        settle().await;
+        assert_eq!(runs.try_recv()?, "tick");
+        runner.abort().await;
+
+        let runs = store.runs()?;
+        let run = runs.iter().find(|run| run.attempts == 2).unwrap();
+        let took = run.finished_at.duration_since(run.started_at)?;
+        assert!(took >= Duration::from_secs(3600));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_abort() -> Result<()> {
+        let store = MemoryStore::new();
+        let resources = Resources::new();
+        let (started, mut has_started) = tokio::sync::mpsc::unbounded_channel();
+        let stuck = {
+            let resources = resources.clone();
+            move |task: String| {
+                let (resources, started) = (resources.clone(), started.clone());
+                async move {
+                    let container = Resource::new(ResourceKind::Container, &task);
+                    let _tracked = resources.track(container, || async { Ok(()) });
+                    let _ = started.send(());
+                    std::future::pending::<ChatOutcome>().await
+                }
+            }
+        };
+        let runner = WorkflowRunner::new(store.clone())
+            .with_workflow("stuck", stuck)
+            .with_concurrency(1)
+            .with_resources(resources)
+            .start();
+
+        runner.submit("stuck", "running")?;
+        runner.submit("stuck", "queued")?;
+        has_started.recv().await;
+        let report = runner.abort().await;
+
+        assert_eq!(
+            report.released,
+            [Resource::new(ResourceKind::Container, "running")]
+        );
+        assert!(store.runs()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir_store() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("runs-{}", Uuid::new_v4()));
+        let store = DirStore::new(&dir);
+        assert!(store.runs()?.is_empty());
+        let run = Run {
+            id: Uuid::new_v4(),
+            workflow: "work".to_string(),
+            task: "a".to_string(),
+            priority: Priority::High,
+            attempts: 1,
+            parked: 0,
+            started_at: SystemTime::now(),
+            finished_at: SystemTime::now(),
+            outcome: outcome("A", TerminationReason::MaxTurns(3)),
+        };
+        store.save(&run)?;
+        assert_eq!(store.runs()?, [run]);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
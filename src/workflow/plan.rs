@@ -0,0 +1,412 @@
+//! Dry runs of workflows. A [`Template`] describes a workflow's
+//! conversation: the agents taking part, the models and tools they use and
+//! how many turns it may take. A dry run walks the template and produces a
+//! [`Plan`] of a run, with the range of tokens and dollars it may cost,
+//! without calling any backend, so an expensive workflow can be reviewed
+//! before it runs.
+
+use {
+    super::{Checkpoint, Workflow, WorkflowFuture},
+    crate::{
+        chat::DEFAULT_MAX_TURNS,
+        llm::{usage::PriceTable, HistoryMessage, Role, Usage},
+        tokenizer::TokenizerRegistry,
+    },
+    std::fmt,
+};
+
+/// The fewest tokens a reply is expected to take when no range is
+/// configured.
+pub const DEFAULT_MIN_REPLY_TOKENS: usize = 50;
+
+/// The most tokens a reply is expected to take when no range is configured.
+pub const DEFAULT_MAX_REPLY_TOKENS: usize = 500;
+
+/// The tokens a tool's definition is expected to add to each call, since a
+/// template only names its tools.
+pub const TOOL_DEFINITION_TOKENS: usize = 100;
+
+/// An agent taking part in a workflow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentSpec {
+    /// The agent's name.
+    pub name: String,
+
+    /// The model the agent replies with.
+    pub model: String,
+
+    /// The instructions the agent sends ahead of the conversation.
+    pub system_prompt: Option<String>,
+
+    /// The names of the tools the agent may call.
+    pub tools: Vec<String>,
+
+    /// The fewest and most tokens each of the agent's replies is expected
+    /// to take.
+    pub reply_tokens: (usize, usize),
+}
+
+impl AgentSpec {
+    /// Describe an agent named `name` replying with `model`.
+    pub fn new(name: impl ToString, model: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            model: model.to_string(),
+            system_prompt: None,
+            tools: Vec::new(),
+            reply_tokens: (DEFAULT_MIN_REPLY_TOKENS, DEFAULT_MAX_REPLY_TOKENS),
+        }
+    }
+
+    /// Set the instructions the agent sends ahead of the conversation.
+    pub fn with_system_prompt(mut self, prompt: impl ToString) -> Self {
+        self.system_prompt = Some(prompt.to_string());
+        self
+    }
+
+    /// Let the agent call the tools named `tools`.
+    pub fn with_tools<I, S>(mut self, tools: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        self.tools = tools.into_iter().map(|tool| tool.to_string()).collect();
+        self
+    }
+
+    /// Expect each of the agent's replies to take from `min` to `max`
+    /// tokens, e.g. its `max_tokens`.
+    pub fn with_reply_tokens(mut self, min: usize, max: usize) -> Self {
+        self.reply_tokens = (min, max.max(min));
+        self
+    }
+}
+
+/// The low and high ends of an estimate.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Bounds<T> {
+    pub low: T,
+    pub high: T,
+}
+
+/// What a run of a workflow is expected to take.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    /// The task the workflow would run for.
+    pub task: String,
+
+    /// The agents taking part, in speaking order.
+    pub agents: Vec<AgentPlan>,
+
+    /// The number of replies in the conversation.
+    pub turns: Bounds<usize>,
+
+    /// The tokens of every call to a model.
+    pub usage: Bounds<Usage>,
+
+    /// What every call to a model costs, in dollars.
+    pub cost: Bounds<f64>,
+}
+
+/// What an agent is expected to take in a run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentPlan {
+    pub name: String,
+    pub model: String,
+    pub tools: Vec<String>,
+
+    /// The number of times the agent replies.
+    pub turns: Bounds<usize>,
+
+    /// The tokens of the agent's calls to its model.
+    pub usage: Bounds<Usage>,
+
+    /// What the agent's calls cost, in dollars.
+    pub cost: Bounds<f64>,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} to {} turns, {} to {} tokens, ${:.4} to ${:.4}",
+            self.turns.low,
+            self.turns.high,
+            total(self.usage.low),
+            total(self.usage.high),
+            self.cost.low,
+            self.cost.high
+        )?;
+        for agent in &self.agents {
+            write!(
+                f,
+                "  {} ({}): {} to {} turns, ${:.4} to ${:.4}",
+                agent.name,
+                agent.model,
+                agent.turns.low,
+                agent.turns.high,
+                agent.cost.low,
+                agent.cost.high
+            )?;
+            if !agent.tools.is_empty() {
+                write!(f, ", tools: {}", agent.tools.join(", "))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the tokens of `usage`, sent and received.
+fn total(usage: Usage) -> usize {
+    usage.prompt_tokens + usage.completion_tokens
+}
+
+/// Describes a workflow's conversation, for dry runs. The agents take
+/// turns in the order they're added, like a chat's participants.
+///
+/// Usage:
+/// ```
+/// # use autogen_rs::{chat::{ChatOutcome, TerminationReason}, workflow::{AgentSpec, MemoryStore, Template, WorkflowRunner}};
+/// let template = Template::new()
+///     .with_agent(AgentSpec::new("researcher", "gpt-4o").with_tools(["search"]))
+///     .with_agent(AgentSpec::new("writer", "gpt-4o-mini").with_reply_tokens(100, 1000))
+///     .with_turns(2, 6);
+/// let runner = WorkflowRunner::new(MemoryStore::new()).with_workflow(
+///     "report",
+///     template.workflow(|task: String| async move {
+///         // the conversation the template describes
+///         ChatOutcome {
+///             transcript: Vec::new(),
+///             reason: TerminationReason::Completed,
+///             variables: Default::default(),
+///         }
+///     }),
+/// );
+///
+/// let plan = runner.dry_run("report", "Summarize this week's incidents.")?;
+/// assert_eq!((plan.turns.low, plan.turns.high), (2, 6));
+/// assert!(plan.cost.low < plan.cost.high);
+/// println!("{plan}");
+/// # anyhow::Ok(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Template {
+    agents: Vec<AgentSpec>,
+    turns: (usize, usize),
+    prices: PriceTable,
+    tokenizers: TokenizerRegistry,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Self {
+            agents: Vec::new(),
+            turns: (1, DEFAULT_MAX_TURNS),
+            prices: PriceTable::default(),
+            tokenizers: TokenizerRegistry::default(),
+        }
+    }
+}
+
+impl Template {
+    /// Create a template with no agents, taking from one to
+    /// [`DEFAULT_MAX_TURNS`] turns.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add an agent. Agents take turns in the order they're added.
+    pub fn with_agent(mut self, agent: AgentSpec) -> Self {
+        self.agents.push(agent);
+        self
+    }
+
+    /// Expect the conversation to take from `min` to `max` replies.
+    pub fn with_turns(mut self, min: usize, max: usize) -> Self {
+        self.turns = (min, max.max(min));
+        self
+    }
+
+    /// Price calls with `prices` instead of the default table.
+    pub fn with_prices(mut self, prices: PriceTable) -> Self {
+        self.prices = prices;
+        self
+    }
+
+    /// Count tokens with `tokenizers` instead of the default ones.
+    pub fn with_tokenizers(mut self, tokenizers: TokenizerRegistry) -> Self {
+        self.tokenizers = tokenizers;
+        self
+    }
+
+    /// Returns `workflow`, described by the template.
+    pub fn workflow<W: Workflow>(self, workflow: W) -> Templated<W> {
+        Templated {
+            template: self,
+            workflow,
+        }
+    }
+
+    /// Returns the plan of a run for `task`, without running it. The low
+    /// end has the fewest turns with the shortest replies; the high end the
+    /// most turns with the longest.
+    pub fn dry_run(&self, task: &str) -> Plan {
+        let low = self.walk(task, self.turns.0, |agent| agent.reply_tokens.0);
+        let high = self.walk(task, self.turns.1, |agent| agent.reply_tokens.1);
+        let agents = self
+            .agents
+            .iter()
+            .zip(low.iter().zip(&high))
+            .map(|(agent, (low, high))| AgentPlan {
+                name: agent.name.clone(),
+                model: agent.model.clone(),
+                tools: agent.tools.clone(),
+                turns: Bounds {
+                    low: low.0,
+                    high: high.0,
+                },
+                usage: Bounds {
+                    low: low.1,
+                    high: high.1,
+                },
+                cost: Bounds {
+                    low: self.prices.cost(&agent.model, low.1),
+                    high: self.prices.cost(&agent.model, high.1),
+                },
+            })
+            .collect::<Vec<_>>();
+        let sum = |usages: &[(usize, Usage)]| Usage {
+            prompt_tokens: usages.iter().map(|(_, usage)| usage.prompt_tokens).sum(),
+            completion_tokens: usages
+                .iter()
+                .map(|(_, usage)| usage.completion_tokens)
+                .sum(),
+        };
+        Plan {
+            task: task.to_string(),
+            turns: Bounds {
+                low: agents.iter().map(|agent| agent.turns.low).sum(),
+                high: agents.iter().map(|agent| agent.turns.high).sum(),
+            },
+            usage: Bounds {
+                low: sum(&low),
+                high: sum(&high),
+            },
+            cost: Bounds {
+                low: agents.iter().map(|agent| agent.cost.low).sum(),
+                high: agents.iter().map(|agent| agent.cost.high).sum(),
+            },
+            agents,
+        }
+    }
+
+    /// Walks a conversation about `task` of `turns` replies, each taking
+    /// `reply_tokens` of its agent's tokens. Returns each agent's turns and
+    /// usage.
+    fn walk(
+        &self,
+        task: &str,
+        turns: usize,
+        reply_tokens: impl Fn(&AgentSpec) -> usize,
+    ) -> Vec<(usize, Usage)> {
+        let mut usages = vec![(0, Usage::default()); self.agents.len()];
+        // the replies so far, which every later call sends again
+        let mut history = 0;
+        let speakers = self.agents.iter().enumerate().cycle().take(turns);
+        for (index, agent) in speakers {
+            let opening = agent
+                .system_prompt
+                .iter()
+                .map(|prompt| HistoryMessage::new(Role::System, prompt))
+                .chain([HistoryMessage::new(Role::User, task)])
+                .collect::<Vec<_>>();
+            let prompt = self.tokenizers.count_messages(&agent.model, &opening)
+                + history
+                + agent.tools.len() * TOOL_DEFINITION_TOKENS;
+            let reply = reply_tokens(agent);
+            let (count, usage) = &mut usages[index];
+            *count += 1;
+            usage.prompt_tokens += prompt;
+            usage.completion_tokens += reply;
+            history += reply;
+        }
+        usages
+    }
+}
+
+/// A workflow described by a [`Template`], so it can be dry run.
+#[derive(Debug, Clone)]
+pub struct Templated<W> {
+    template: Template,
+    workflow: W,
+}
+
+impl<W: Workflow> Workflow for Templated<W> {
+    fn run(&self, task: String) -> WorkflowFuture {
+        self.workflow.run(task)
+    }
+
+    fn run_with(&self, task: String, checkpoint: Checkpoint) -> WorkflowFuture {
+        self.workflow.run_with(task, checkpoint)
+    }
+
+    fn template(&self) -> Option<&Template> {
+        Some(&self.template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{llm::usage::Pricing, tokenizer::Estimate},
+        anyhow::Result,
+    };
+
+    #[test]
+    fn test_dry_run() -> Result<()> {
+        let pricing = Pricing::new(1.0, 2.0);
+        let template = Template::new()
+            .with_agent(AgentSpec::new("a", "model").with_reply_tokens(10, 20))
+            .with_agent(
+                AgentSpec::new("b", "model")
+                    .with_tools(["search"])
+                    .with_reply_tokens(30, 40),
+            )
+            .with_turns(1, 3)
+            .with_prices(PriceTable::new().with_price("model", pricing))
+            .with_tokenizers(TokenizerRegistry::new().with_fallback(Estimate::default()));
+        let plan = template.dry_run("task");
+        let opening = TokenizerRegistry::new()
+            .with_fallback(Estimate::default())
+            .count_messages("model", &[HistoryMessage::new(Role::User, "task")]);
+
+        // the low end: a replies once
+        assert_eq!((plan.turns.low, plan.turns.high), (1, 3));
+        let low = Usage {
+            prompt_tokens: opening,
+            completion_tokens: 10,
+        };
+        assert_eq!(plan.agents[0].usage.low, low);
+        assert_eq!(plan.agents[1].turns.low, 0);
+        assert_eq!(plan.cost.low, pricing.cost(low));
+
+        // the high end: a, b, then a again, each sending the replies so far
+        let a = Usage {
+            prompt_tokens: opening + (opening + 20 + 40),
+            completion_tokens: 40,
+        };
+        let b = Usage {
+            prompt_tokens: opening + 20 + TOOL_DEFINITION_TOKENS,
+            completion_tokens: 40,
+        };
+        assert_eq!(
+            (plan.agents[0].usage.high, plan.agents[1].usage.high),
+            (a, b)
+        );
+        assert_eq!(plan.cost.high, pricing.cost(a) + pricing.cost(b));
+        assert!(plan.to_string().contains("b (model): 0 to 1 turns"));
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+//! A runner's queue of tasks. Tasks wait for one of the runner's slots in
+//! order of [`Priority`], oldest first within a priority. When every slot is
+//! taken and a task outranks one that's running, the runner asks the running
+//! one to park at its next [`Checkpoint`], which frees its slot for the
+//! urgent task. The parked run waits in the queue like any other task and
+//! carries on once it gets a slot again.
+
+use {
+    super::{Error, Task},
+    serde::{Deserialize, Serialize},
+    std::{
+        cmp::Ordering,
+        collections::{BinaryHeap, HashMap},
+        sync::{
+            atomic::{self, AtomicBool, AtomicUsize},
+            Arc, Mutex,
+        },
+    },
+    tokio::sync::{oneshot, Notify},
+};
+
+/// How urgent a task is.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+
+    /// Parks lower-priority runs at their next checkpoint if there's no slot
+    /// free, like any priority above theirs.
+    Urgent,
+}
+
+/// Something waiting for a slot.
+#[derive(Debug)]
+enum Waiting {
+    /// A task that hasn't started.
+    Task(Task),
+
+    /// A run parked at a checkpoint, resumed by sending on `resume`.
+    Parked {
+        park: Arc<AtomicBool>,
+        resume: oneshot::Sender<()>,
+    },
+}
+
+/// An entry in the queue. Runs keep the sequence number of their task, so
+/// a parked run goes ahead of tasks of its priority submitted after it.
+#[derive(Debug)]
+struct Entry {
+    priority: Priority,
+    sequence: u64,
+    waiting: Waiting,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // the heap pops the greatest: the most urgent, then the oldest
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// A run holding a slot.
+#[derive(Debug)]
+struct Running {
+    priority: Priority,
+
+    /// Set to ask the run to park at its next checkpoint.
+    park: Arc<AtomicBool>,
+}
+
+#[derive(Debug)]
+struct State {
+    waiting: BinaryHeap<Entry>,
+
+    /// Runs holding a slot, by sequence number.
+    running: HashMap<u64, Running>,
+
+    /// The number of slots free.
+    free: usize,
+
+    /// The sequence number of the next task.
+    sequence: u64,
+
+    /// Whether the queue no longer takes tasks.
+    closed: bool,
+}
+
+/// A runner's queue of tasks, and its slots.
+#[derive(Debug)]
+pub(crate) struct Queue {
+    state: Mutex<State>,
+
+    /// Wakes the dispatcher when a task is queued, a slot is freed or the
+    /// queue is closed.
+    changed: Notify,
+}
+
+impl Queue {
+    /// Create an empty queue with `slots` slots.
+    pub(crate) fn new(slots: usize) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(State {
+                waiting: BinaryHeap::new(),
+                running: HashMap::new(),
+                free: slots,
+                sequence: 0,
+                closed: false,
+            }),
+            changed: Notify::new(),
+        })
+    }
+
+    /// Queue `task`. Fails if the queue is closed.
+    pub(crate) fn push(&self, task: Task, priority: Priority) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(Error::Stopped);
+        }
+        let sequence = state.sequence;
+        state.sequence += 1;
+        state.waiting.push(Entry {
+            priority,
+            sequence,
+            waiting: Waiting::Task(task),
+        });
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Take no more tasks. Tasks already queued still run.
+    pub(crate) fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_one();
+    }
+
+    /// Waits for the next task to get a slot, resuming parked runs that get
+    /// one along the way. Returns `None` once the queue is closed and every
+    /// task has run.
+    pub(crate) async fn next(self: &Arc<Self>) -> Option<(Task, Priority, Checkpoint)> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(next) = self.dispatch(&mut state) {
+                    return Some(next);
+                }
+                if state.closed && state.waiting.is_empty() && state.running.is_empty() {
+                    return None;
+                }
+            }
+            self.changed.notified().await;
+        }
+    }
+
+    /// Hands out free slots, and asks a run to park if a more urgent task
+    /// is waiting for one.
+    fn dispatch(self: &Arc<Self>, state: &mut State) -> Option<(Task, Priority, Checkpoint)> {
+        while state.free > 0 {
+            let Some(entry) = state.waiting.pop() else {
+                break;
+            };
+            let park = match entry.waiting {
+                Waiting::Task(_) => Arc::new(AtomicBool::new(false)),
+                Waiting::Parked { ref park, .. } => park.clone(),
+            };
+            let running = Running {
+                priority: entry.priority,
+                park: park.clone(),
+            };
+            match entry.waiting {
+                Waiting::Task(task) => {
+                    state.free -= 1;
+                    state.running.insert(entry.sequence, running);
+                    let checkpoint = Checkpoint(Some(Arc::new(Slot {
+                        queue: self.clone(),
+                        sequence: entry.sequence,
+                        park,
+                        parked: AtomicUsize::new(0),
+                    })));
+                    return Some((task, entry.priority, checkpoint));
+                }
+                // a run that went away while parked doesn't need its slot
+                Waiting::Parked { resume, .. } => {
+                    if resume.send(()).is_ok() {
+                        state.free -= 1;
+                        state.running.insert(entry.sequence, running);
+                    }
+                }
+            }
+        }
+
+        let urgent = state.waiting.peek()?.priority;
+        let parking = state
+            .running
+            .values()
+            .any(|run| run.park.load(atomic::Ordering::Relaxed));
+        if state.free == 0 && !parking {
+            let lowest = state.running.values().min_by_key(|run| run.priority);
+            if let Some(run) = lowest.filter(|run| run.priority < urgent) {
+                run.park.store(true, atomic::Ordering::Relaxed);
+            }
+        }
+        None
+    }
+}
+
+/// A run's hold on its slot. The slot is freed when the run finishes.
+#[derive(Debug)]
+struct Slot {
+    queue: Arc<Queue>,
+    sequence: u64,
+    park: Arc<AtomicBool>,
+
+    /// The number of times the run was parked.
+    parked: AtomicUsize,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        if state.running.remove(&self.sequence).is_some() {
+            state.free += 1;
+        }
+        self.queue.changed.notify_one();
+    }
+}
+
+/// A place in a workflow where it's safe to park the run for a more urgent
+/// task. Given to [`Preemptible`](super::Preemptible) workflows; the default
+/// checkpoint never parks.
+#[derive(Debug, Clone, Default)]
+pub struct Checkpoint(Option<Arc<Slot>>);
+
+impl Checkpoint {
+    /// Returns whether the runner asked the run to park.
+    pub fn is_requested(&self) -> bool {
+        self.0
+            .as_ref()
+            .is_some_and(|slot| slot.park.load(atomic::Ordering::Relaxed))
+    }
+
+    /// Parks the run if the runner asked it to, giving up its slot until it
+    /// gets one back. Returns whether the run was parked, e.g. so it can
+    /// refresh anything that may have changed meanwhile.
+    pub async fn check(&self) -> bool {
+        let Some(slot) = self.0.as_ref().filter(|_| self.is_requested()) else {
+            return false;
+        };
+        let (resume, resumed) = oneshot::channel();
+        {
+            let mut state = slot.queue.state.lock().unwrap();
+            let Some(running) = state.running.remove(&slot.sequence) else {
+                return false;
+            };
+            running.park.store(false, atomic::Ordering::Relaxed);
+            state.free += 1;
+            state.waiting.push(Entry {
+                priority: running.priority,
+                sequence: slot.sequence,
+                waiting: Waiting::Parked {
+                    park: running.park,
+                    resume,
+                },
+            });
+        }
+        slot.queue.changed.notify_one();
+        slot.parked.fetch_add(1, atomic::Ordering::Relaxed);
+        tracing::info!(run = slot.sequence, "parking run for a more urgent task");
+        // the queue outlives the run, so it always resumes it
+        let _ = resumed.await;
+        tracing::info!(run = slot.sequence, "resuming parked run");
+        true
+    }
+
+    /// Returns the number of times the run was parked.
+    pub(crate) fn parked(&self) -> usize {
+        self.0
+            .as_ref()
+            .map_or(0, |slot| slot.parked.load(atomic::Ordering::Relaxed))
+    }
+}